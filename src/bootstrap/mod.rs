@@ -0,0 +1,328 @@
+// src/bootstrap/mod.rs
+
+//! Building a root filesystem for a container image
+//!
+//! [`bootstrap`] fills an empty directory from repositories, the way
+//! `debootstrap` or `dnf --installroot` would: it creates a database inside
+//! the root, adds the repositories of a distribution preset (or those given
+//! instead), syncs them and installs the requested packages with the
+//! dependencies they need as one changeset. Container defaults apply:
+//! - documentation, man pages and locales are recorded but not deployed,
+//!   through the same exclusion globs as `install --exclude`
+//! - scriptlets only run when a runner is passed
+//!
+//! The [`Manifest`] it returns lists each package installed with the URL
+//! and checksum it came from, so an image can be audited or built again.
+//!
+//! ```no_run
+//! use conary::bootstrap::{self, BootstrapOptions};
+//!
+//! let options = BootstrapOptions::new("/build/rootfs", vec!["bash".to_string()]).distro("fedora");
+//! let result = bootstrap::bootstrap(&options)?;
+//! println!("{}", serde_json::to_string_pretty(&result.manifest).unwrap());
+//! # Ok::<(), conary::Error>(())
+//! ```
+
+use crate::db::{self, models::RepositoryPackage};
+use crate::error::{Error, Result};
+use crate::installer::{InstallOptions, InstallReport, Installer};
+use crate::paths::Layout;
+use crate::plan::{Operation, PlanOptions, Planner, Request, Source};
+use crate::repository::{self, ChecksumType, DefaultSet, Distro};
+use crate::scriptlet::ScriptletRunner;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Database location inside the root being built
+pub const DB_PATH: &str = "var/lib/conary/conary.db";
+
+/// Paths left out of container roots unless asked for
+pub const CONTAINER_EXCLUDES: &[&str] = &[
+    "/usr/share/doc/*",
+    "/usr/share/man/*",
+    "/usr/share/info/*",
+    "/usr/share/locale/*",
+];
+
+/// Names of the distribution presets
+///
+/// Each adds the distribution's default repositories (see
+/// [`repository::DEFAULT_REPOSITORIES`]), without multilib ones.
+pub fn distros() -> impl Iterator<Item = &'static str> {
+    Distro::ALL.into_iter().map(|distro| distro.as_str())
+}
+
+/// What to build and from where
+#[derive(Clone)]
+pub struct BootstrapOptions<'a> {
+    /// Directory to fill; it may exist but must not hold a database yet
+    pub root: PathBuf,
+    /// Distribution preset whose repositories are added
+    pub distro: Option<String>,
+    /// Repositories as name and URL, added instead of the preset's
+    pub repositories: Vec<(String, String)>,
+    /// Packages to install, by name
+    pub packages: Vec<String>,
+    /// Globs of paths to record but not deploy
+    pub exclude: Vec<String>,
+    /// Runs package scriptlets; `None` skips them
+    pub scripts: Option<&'a dyn ScriptletRunner>,
+}
+
+impl<'a> BootstrapOptions<'a> {
+    /// Install `packages` into `root` with the container defaults
+    pub fn new(root: impl Into<PathBuf>, packages: Vec<String>) -> Self {
+        Self {
+            root: root.into(),
+            distro: None,
+            repositories: Vec::new(),
+            packages,
+            exclude: CONTAINER_EXCLUDES
+                .iter()
+                .map(|glob| glob.to_string())
+                .collect(),
+            scripts: None,
+        }
+    }
+
+    /// Add the repositories of the `distro` preset
+    pub fn distro(mut self, distro: &str) -> Self {
+        self.distro = Some(distro.to_string());
+        self
+    }
+
+    /// Add this repository; once any is given, the preset's are not added
+    pub fn repository(mut self, name: &str, url: &str) -> Self {
+        self.repositories.push((name.to_string(), url.to_string()));
+        self
+    }
+
+    /// Run package scriptlets with `runner`
+    pub fn scripts(mut self, runner: &'a dyn ScriptletRunner) -> Self {
+        self.scripts = Some(runner);
+        self
+    }
+
+    /// The repositories to add, as name, URL and priority
+    fn repositories(&self) -> Result<Vec<(String, String, i32)>> {
+        if !self.repositories.is_empty() {
+            return Ok(self
+                .repositories
+                .iter()
+                .map(|(name, url)| (name.clone(), url.clone(), 0))
+                .collect());
+        }
+        let Some(distro) = &self.distro else {
+            return Err(Error::InitError(
+                "Bootstrap needs a distribution or at least one repository".to_string(),
+            ));
+        };
+        let distro: Distro = distro.parse().map_err(|_| {
+            Error::NotFoundError(format!(
+                "Unknown distribution '{}'; known: {}",
+                distro,
+                distros().collect::<Vec<_>>().join(", ")
+            ))
+        })?;
+        Ok(DefaultSet::Distro(distro)
+            .repositories()
+            .into_iter()
+            .filter(|repo| !repo.multilib)
+            .map(|repo| (repo.name.to_string(), repo.url.to_string(), repo.priority))
+            .collect())
+    }
+}
+
+/// What a bootstrap installed
+#[derive(Debug, Clone)]
+pub struct BootstrapResult {
+    pub report: InstallReport,
+    pub manifest: Manifest,
+}
+
+/// Record of how a root was built
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub distro: Option<String>,
+    pub repositories: Vec<ManifestRepository>,
+    /// Packages asked for, before dependencies
+    pub requested: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Whether scriptlets ran
+    pub scripts: bool,
+    /// Every package installed, by name
+    pub packages: Vec<ManifestPackage>,
+}
+
+/// A repository a root was built from
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestRepository {
+    pub name: String,
+    pub url: String,
+}
+
+/// A package installed into a root, and the file it came from
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestPackage {
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+    /// Pulled in for another package rather than requested
+    pub dependency: bool,
+    pub repository: String,
+    pub url: String,
+    pub checksum: String,
+    pub checksum_type: ChecksumType,
+}
+
+/// Build a root as `options` describe
+///
+/// Fails before anything is installed when the root already has a
+/// database, when a repository cannot be synced, and when a package or a
+/// dependency cannot be found. The database, and the CAS next to it, stay
+/// in the root at [`DB_PATH`], so the result can be updated with `conary`
+/// like any other installation.
+pub fn bootstrap(options: &BootstrapOptions) -> Result<BootstrapResult> {
+    if options.packages.is_empty() {
+        return Err(Error::NotFoundError("No packages to bootstrap".to_string()));
+    }
+    let repositories = options.repositories()?;
+    let db_path = options.root.join(DB_PATH);
+    if db_path.exists() {
+        return Err(Error::ConflictError(format!(
+            "{} already has a Conary database at {}",
+            options.root.display(),
+            db_path.display()
+        )));
+    }
+    let db_path = path_str(&db_path)?;
+
+    info!(
+        "Bootstrapping {} into {}",
+        options.packages.join(", "),
+        options.root.display()
+    );
+    db::init(db_path)?;
+    let mut conn = db::open(db_path)?;
+    let layout = Layout::load(&conn, db_path)?.with_root(&options.root)?;
+    let _lock = layout.lock()?;
+
+    let mut manifest = Manifest {
+        distro: options.distro.clone(),
+        repositories: Vec::new(),
+        requested: options.packages.clone(),
+        exclude: options.exclude.clone(),
+        scripts: options.scripts.is_some(),
+        packages: Vec::new(),
+    };
+    for (name, url, priority) in repositories {
+        let mut repo =
+            repository::add_repository(&conn, name.clone(), url.clone(), true, priority)?;
+        repository::sync_repository(&mut conn, &mut repo, true, None).map_err(|e| {
+            Error::DownloadError(format!("Failed to sync repository {}: {}", name, e))
+        })?;
+        manifest.repositories.push(ManifestRepository { name, url });
+    }
+
+    let mut installer = Installer::for_layout(&mut conn, &layout)?;
+    let mut planner = Planner::new(installer.conn(), PlanOptions::default());
+    for package in &options.packages {
+        planner.request(Request::Install {
+            name: package.clone(),
+            version: None,
+        });
+    }
+    let plan = planner.plan()?;
+
+    for operation in &plan.operations {
+        let Operation::Install {
+            package,
+            source:
+                Source::Repository {
+                    repository,
+                    package_id,
+                    ..
+                },
+            dependency,
+            ..
+        } = operation
+        else {
+            continue;
+        };
+        let found =
+            RepositoryPackage::find_by_id(installer.conn(), *package_id)?.ok_or_else(|| {
+                Error::NotFoundError(format!("Repository package {} is gone", package_id))
+            })?;
+        manifest.packages.push(ManifestPackage {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            architecture: package.architecture.clone(),
+            dependency: *dependency,
+            repository: repository.clone(),
+            url: found.download_url,
+            checksum: found.checksum,
+            checksum_type: found.checksum_type,
+        });
+    }
+    manifest.packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let install_options = InstallOptions {
+        scripts: options.scripts,
+        exclude: &options.exclude,
+        ..Default::default()
+    };
+    let report = installer
+        .execute(plan, &install_options)?
+        .installed
+        .ok_or_else(|| Error::NotFoundError("No packages to install".to_string()))?;
+    Ok(BootstrapResult { report, manifest })
+}
+
+/// `path` as a string, for the database functions
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        Error::InitError(format!(
+            "Database path {} is not valid UTF-8",
+            path.display()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repositories_come_from_preset_or_options() {
+        let options = BootstrapOptions::new("/tmp/root", vec!["bash".to_string()]);
+        assert!(options.repositories().is_err());
+
+        let arch = options.clone().distro("arch").repositories().unwrap();
+        let names: Vec<&str> = arch.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(names, ["arch-core", "arch-extra"]);
+
+        let err = options
+            .clone()
+            .distro("gentoo")
+            .repositories()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("gentoo") && err.contains("arch, fedora, ubuntu"),
+            "{}",
+            err
+        );
+
+        // Given repositories replace the preset
+        let local = options
+            .distro("fedora")
+            .repository("local", "file:///srv/repo")
+            .repositories()
+            .unwrap();
+        assert_eq!(
+            local,
+            [("local".to_string(), "file:///srv/repo".to_string(), 0)]
+        );
+    }
+}
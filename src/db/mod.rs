@@ -9,7 +9,7 @@
 //! - CRUD operations for troves, changesets, files, etc.
 
 pub mod models;
-pub mod schema;
+pub(crate) mod schema;
 
 use crate::error::{Error, Result};
 use rusqlite::Connection;
@@ -57,7 +57,10 @@ pub fn init(db_path: &str) -> Result<()> {
     Ok(())
 }
 
-/// Open an existing Conary database
+/// Open an existing Conary database for a command that changes it
+///
+/// Pending schema migrations are applied, so a database written by an older
+/// conary is brought up to date before it is used.
 ///
 /// # Arguments
 ///
@@ -73,17 +76,64 @@ pub fn open(db_path: &str) -> Result<Connection> {
 
     let conn = Connection::open(db_path)?;
 
-    // Set pragmas
+    // Set pragmas; WAL lets read-only commands run while this one writes
     conn.execute_batch(
         "
+        PRAGMA journal_mode = WAL;
         PRAGMA foreign_keys = ON;
         PRAGMA busy_timeout = 5000;
         ",
     )?;
+    schema::migrate(&conn)?;
 
     Ok(conn)
 }
 
+/// Open an existing Conary database for a command that only reads it
+///
+/// Nothing is written: the connection is `query_only` and migrations are not
+/// run, which keeps queries and shell completion cheap. A database whose
+/// schema is older than this conary expects is refused, since the queries
+/// are written against the current schema; any command that changes the
+/// database, or `conary init`, migrates it.
+///
+/// The connection never takes the operation lock and reads from one
+/// snapshot: it holds a read transaction until it is dropped, so every query
+/// sees the database as of the first one, even while an install or update
+/// commits changesets. Writers record each changeset in one transaction, so
+/// a snapshot never holds a package without its files.
+pub fn open_read_only(db_path: &str) -> Result<Connection> {
+    if !Path::new(db_path).exists() {
+        return Err(Error::DatabaseNotFound(db_path.to_string()));
+    }
+
+    let conn = Connection::open(db_path)?;
+    prepare_read_only(&conn, db_path)?;
+    Ok(conn)
+}
+
+fn prepare_read_only(conn: &Connection, db_path: &str) -> Result<()> {
+    conn.execute_batch(
+        "
+        PRAGMA query_only = ON;
+        PRAGMA busy_timeout = 5000;
+        BEGIN;
+        ",
+    )?;
+
+    let version = schema::stored_version(conn)?;
+    if version < schema::SCHEMA_VERSION {
+        return Err(Error::InitError(format!(
+            "{} has schema version {}, older than version {} this conary reads; run 'conary init --db-path {}' to migrate it",
+            db_path,
+            version,
+            schema::SCHEMA_VERSION,
+            db_path
+        )));
+    }
+    Ok(())
+}
+
 /// Execute a function within a transaction
 ///
 /// If the function returns Ok, the transaction is committed.
@@ -138,6 +188,109 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_read_only_open_writes_nothing() {
+        use crate::db::models::{Trove, TroveType};
+        use rusqlite::hooks::{AuthAction, Authorization};
+        use std::sync::{Arc, Mutex};
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        init(db_path).unwrap();
+        Trove::new("bash".to_string(), "5.2".to_string(), TroveType::Package)
+            .insert(&open(db_path).unwrap())
+            .unwrap();
+
+        // Statements prepared and any actions that would write, as SQLite
+        // authorizes them
+        let seen: Arc<Mutex<(usize, Vec<String>)>> = Arc::default();
+        let conn = Connection::open(db_path).unwrap();
+        let log = Arc::clone(&seen);
+        conn.authorizer(Some(move |ctx: rusqlite::hooks::AuthContext<'_>| {
+            let mut log = log.lock().unwrap();
+            match ctx.action {
+                AuthAction::Select | AuthAction::Pragma { .. } => log.0 += 1,
+                AuthAction::Read { .. }
+                | AuthAction::Function { .. }
+                | AuthAction::Transaction { .. } => {}
+                other => log.1.push(format!("{:?}", other)),
+            }
+            Authorization::Allow
+        }));
+
+        prepare_read_only(&conn, db_path).unwrap();
+        let names: Vec<String> = Trove::list_all(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|trove| trove.name)
+            .collect();
+        assert_eq!(names, ["bash"]);
+
+        let (statements, writes) = seen.lock().unwrap().clone();
+        assert!(writes.is_empty(), "{:?}", writes);
+        assert!(statements <= 4, "{} statements", statements);
+        assert!(conn.execute("DELETE FROM troves", []).is_err());
+    }
+
+    #[test]
+    fn test_read_only_open_reads_one_snapshot() {
+        use crate::db::models::{Trove, TroveType};
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        init(db_path).unwrap();
+        let writer = open(db_path).unwrap();
+        Trove::new("bash".to_string(), "5.2".to_string(), TroveType::Package)
+            .insert(&writer)
+            .unwrap();
+
+        let reader = open_read_only(db_path).unwrap();
+        assert_eq!(Trove::list_all(&reader).unwrap().len(), 1);
+
+        // Commits made after the first query stay invisible to the reader
+        // and do not wait for it
+        Trove::new("zsh".to_string(), "5.9".to_string(), TroveType::Package)
+            .insert(&writer)
+            .unwrap();
+        assert_eq!(Trove::list_all(&reader).unwrap().len(), 1);
+        assert_eq!(
+            Trove::list_all(&open_read_only(db_path).unwrap())
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_read_only_open_refuses_old_schema() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        init(db_path).unwrap();
+        // As left by a conary from before package locks
+        Connection::open(db_path)
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE package_locks; DROP TABLE repository_advisories; DROP TABLE verify_findings;
+                 DROP TABLE repository_pins; DROP TABLE shared_files;
+                 ALTER TABLE repositories DROP COLUMN components;
+                 ALTER TABLE repositories DROP COLUMN architectures;
+                 ALTER TABLE troves DROP COLUMN installed_size;
+                 ALTER TABLE repository_packages DROP COLUMN checksum_type;
+                 ALTER TABLE troves DROP COLUMN epoch;
+                 ALTER TABLE troves DROP COLUMN format;
+                 ALTER TABLE repository_packages DROP COLUMN epoch;
+                 DELETE FROM schema_version WHERE version > 30;",
+            )
+            .unwrap();
+
+        let err = open_read_only(db_path).unwrap_err();
+        assert!(err.to_string().contains("schema version 30"), "{}", err);
+
+        // A command that writes migrates it again
+        open(db_path).unwrap();
+        open_read_only(db_path).unwrap();
+    }
+
     #[test]
     fn test_open_nonexistent_database() {
         let result = open("/nonexistent/path/db.sqlite");
@@ -10,7 +10,7 @@ use rusqlite::Connection;
 use tracing::{debug, info};
 
 /// Current schema version
-pub const SCHEMA_VERSION: i32 = 5;
+pub const SCHEMA_VERSION: i32 = 47;
 
 /// Initialize the schema version tracking table
 fn init_schema_version(conn: &Connection) -> Result<()> {
@@ -39,6 +39,23 @@ pub fn get_schema_version(conn: &Connection) -> Result<i32> {
     Ok(version)
 }
 
+/// The schema version of a database, without creating the tracking table
+///
+/// A database without the table is at version 0.
+pub fn stored_version(conn: &Connection) -> Result<i32> {
+    match conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+        row.get::<_, Option<i32>>(0)
+    }) {
+        Ok(version) => Ok(version.unwrap_or(0)),
+        Err(rusqlite::Error::SqliteFailure(_, Some(message)))
+            if message.contains("no such table") =>
+        {
+            Ok(0)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 /// Set the schema version
 fn set_schema_version(conn: &Connection, version: i32) -> Result<()> {
     conn.execute(
@@ -51,10 +68,10 @@ fn set_schema_version(conn: &Connection, version: i32) -> Result<()> {
 /// Apply all pending migrations to bring the database up to date
 pub fn migrate(conn: &Connection) -> Result<()> {
     let current_version = get_schema_version(conn)?;
-    info!("Current schema version: {}", current_version);
+    debug!("Current schema version: {}", current_version);
 
     if current_version >= SCHEMA_VERSION {
-        info!("Schema is up to date");
+        debug!("Schema is up to date");
         return Ok(());
     }
 
@@ -80,6 +97,48 @@ fn apply_migration(conn: &Connection, version: i32) -> Result<()> {
         3 => migrate_v3(conn),
         4 => migrate_v4(conn),
         5 => migrate_v5(conn),
+        6 => migrate_v6(conn),
+        7 => migrate_v7(conn),
+        8 => migrate_v8(conn),
+        9 => migrate_v9(conn),
+        10 => migrate_v10(conn),
+        11 => migrate_v11(conn),
+        12 => migrate_v12(conn),
+        13 => migrate_v13(conn),
+        14 => migrate_v14(conn),
+        15 => migrate_v15(conn),
+        16 => migrate_v16(conn),
+        17 => migrate_v17(conn),
+        18 => migrate_v18(conn),
+        19 => migrate_v19(conn),
+        20 => migrate_v20(conn),
+        21 => migrate_v21(conn),
+        22 => migrate_v22(conn),
+        23 => migrate_v23(conn),
+        24 => migrate_v24(conn),
+        25 => migrate_v25(conn),
+        26 => migrate_v26(conn),
+        27 => migrate_v27(conn),
+        28 => migrate_v28(conn),
+        29 => migrate_v29(conn),
+        30 => migrate_v30(conn),
+        31 => migrate_v31(conn),
+        32 => migrate_v32(conn),
+        33 => migrate_v33(conn),
+        34 => migrate_v34(conn),
+        35 => migrate_v35(conn),
+        36 => migrate_v36(conn),
+        37 => migrate_v37(conn),
+        38 => migrate_v38(conn),
+        39 => migrate_v39(conn),
+        40 => migrate_v40(conn),
+        41 => migrate_v41(conn),
+        42 => migrate_v42(conn),
+        43 => migrate_v43(conn),
+        44 => migrate_v44(conn),
+        45 => migrate_v45(conn),
+        46 => migrate_v46(conn),
+        47 => migrate_v47(conn),
         _ => panic!("Unknown migration version: {}", version),
     }
 }
@@ -364,109 +423,1523 @@ fn migrate_v5(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+/// Schema Version 6: Add cumulative operation statistics
+///
+/// Adds a single event table that the stats module appends to:
+/// - operation_stats: One row per recorded counter increment (bytes
+///   downloaded, packages installed, cache hits, ...), optionally tagged
+///   with the repository it came from
+fn migrate_v6(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 6");
 
-    fn create_test_db() -> (NamedTempFile, Connection) {
-        let temp_file = NamedTempFile::new().unwrap();
-        let conn = Connection::open(temp_file.path()).unwrap();
-        (temp_file, conn)
-    }
+    conn.execute_batch(
+        "
+        -- Operation statistics: Append-only counter increments
+        CREATE TABLE operation_stats (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            metric TEXT NOT NULL,
+            repository TEXT,
+            amount INTEGER NOT NULL,
+            recorded_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
 
-    #[test]
-    fn test_schema_version_tracking() {
-        let (_temp, conn) = create_test_db();
+        CREATE INDEX idx_operation_stats_metric ON operation_stats(metric);
+        CREATE INDEX idx_operation_stats_recorded ON operation_stats(recorded_at);
+        ",
+    )?;
 
-        // Initial version should be 0
-        let version = get_schema_version(&conn).unwrap();
-        assert_eq!(version, 0);
+    info!("Schema version 6 applied successfully");
+    Ok(())
+}
 
-        // Set version to 1
-        set_schema_version(&conn, 1).unwrap();
-        let version = get_schema_version(&conn).unwrap();
-        assert_eq!(version, 1);
-    }
+/// Schema Version 7: Add changeset batches
+///
+/// Bulk operations (such as reconcile) produce one changeset per package;
+/// a shared batch id lets them be listed and reasoned about together.
+fn migrate_v7(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 7");
 
-    #[test]
-    fn test_migrate_creates_all_tables() {
-        let (_temp, conn) = create_test_db();
+    conn.execute_batch(
+        "
+        ALTER TABLE changesets ADD COLUMN batch_id TEXT;
+        CREATE INDEX idx_changesets_batch ON changesets(batch_id);
+        ",
+    )?;
 
-        // Run migration
-        migrate(&conn).unwrap();
+    info!("Schema version 7 applied successfully");
+    Ok(())
+}
 
-        // Verify all tables exist
-        let tables: Vec<String> = conn
-            .prepare("SELECT name FROM sqlite_master WHERE type='table' ORDER BY name")
-            .unwrap()
-            .query_map([], |row| row.get(0))
-            .unwrap()
-            .collect::<std::result::Result<Vec<_>, _>>()
-            .unwrap();
+/// Schema Version 8: Add package scriptlets
+///
+/// Stores the maintainer scripts shipped with each installed trove so
+/// removal can run them later, and records the exit code of every
+/// scriptlet run against the changeset it belonged to.
+fn migrate_v8(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 8");
 
-        assert!(tables.contains(&"troves".to_string()));
-        assert!(tables.contains(&"changesets".to_string()));
-        assert!(tables.contains(&"files".to_string()));
-        assert!(tables.contains(&"flavors".to_string()));
-        assert!(tables.contains(&"provenance".to_string()));
-        assert!(tables.contains(&"dependencies".to_string()));
-        assert!(tables.contains(&"schema_version".to_string()));
-    }
+    conn.execute_batch(
+        "
+        CREATE TABLE scriptlets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            trove_id INTEGER NOT NULL,
+            phase TEXT NOT NULL CHECK(phase IN ('pre-install', 'post-install', 'pre-remove', 'post-remove')),
+            interpreter TEXT NOT NULL,
+            body TEXT NOT NULL,
+            format TEXT NOT NULL,
+            FOREIGN KEY (trove_id) REFERENCES troves(id) ON DELETE CASCADE
+        );
 
-    #[test]
-    fn test_migrate_is_idempotent() {
-        let (_temp, conn) = create_test_db();
+        CREATE INDEX idx_scriptlets_trove ON scriptlets(trove_id);
 
-        // Run migration twice
-        migrate(&conn).unwrap();
-        let version1 = get_schema_version(&conn).unwrap();
+        CREATE TABLE scriptlet_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            changeset_id INTEGER NOT NULL,
+            package TEXT NOT NULL,
+            phase TEXT NOT NULL,
+            exit_code INTEGER NOT NULL,
+            ran_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (changeset_id) REFERENCES changesets(id) ON DELETE CASCADE
+        );
 
-        migrate(&conn).unwrap();
-        let version2 = get_schema_version(&conn).unwrap();
+        CREATE INDEX idx_scriptlet_runs_changeset ON scriptlet_runs(changeset_id);
+        ",
+    )?;
 
-        assert_eq!(version1, version2);
-        assert_eq!(version1, SCHEMA_VERSION);
-    }
+    info!("Schema version 8 applied successfully");
+    Ok(())
+}
 
-    #[test]
-    fn test_troves_table_constraints() {
-        let (_temp, conn) = create_test_db();
-        migrate(&conn).unwrap();
+/// Schema Version 9: Track file types and link targets
+///
+/// Symlinks and hardlinks are recorded with their target instead of being
+/// flattened into regular files.
+fn migrate_v9(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 9");
 
-        // Insert a valid trove
-        conn.execute(
-            "INSERT INTO troves (name, version, type, architecture) VALUES (?1, ?2, ?3, ?4)",
-            ["test-package", "1.0.0", "package", "x86_64"],
-        )
-        .unwrap();
+    conn.execute_batch(
+        "
+        ALTER TABLE files ADD COLUMN file_type TEXT NOT NULL DEFAULT 'regular'
+            CHECK(file_type IN ('regular', 'symlink', 'hardlink'));
+        ALTER TABLE files ADD COLUMN link_target TEXT;
+        ",
+    )?;
 
-        // Try to insert duplicate - should fail due to UNIQUE constraint
-        let result = conn.execute(
-            "INSERT INTO troves (name, version, type, architecture) VALUES (?1, ?2, ?3, ?4)",
-            ["test-package", "1.0.0", "package", "x86_64"],
+    info!("Schema version 9 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 10: Normalize repository package dependencies
+///
+/// Moves dependency lists out of the JSON `repository_packages.dependencies`
+/// column into one row per dependency, so metapackages with thousands of
+/// dependencies no longer produce multi-megabyte rows. The old column is
+/// left in place (always NULL) since SQLite cannot drop it portably.
+fn migrate_v10(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 10");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE repository_package_dependencies (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            package_id INTEGER NOT NULL,
+            dependency TEXT NOT NULL,
+            FOREIGN KEY (package_id) REFERENCES repository_packages(id) ON DELETE CASCADE
         );
-        assert!(result.is_err());
-    }
 
-    #[test]
-    fn test_foreign_key_constraints() {
-        let (_temp, conn) = create_test_db();
-        conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
-        migrate(&conn).unwrap();
+        CREATE INDEX idx_repo_package_deps_package ON repository_package_dependencies(package_id);
 
-        // Try to insert a file without a trove - should fail
-        let result = conn.execute(
-            "INSERT INTO files (path, sha256_hash, size, permissions, trove_id)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            [
-                "/usr/bin/test",
-                "abc123",
-                "1024",
-                "755",
-                "999", // Non-existent trove_id
-            ],
+        INSERT INTO repository_package_dependencies (package_id, dependency)
+            SELECT rp.id, je.value
+            FROM repository_packages rp, json_each(rp.dependencies) je
+            WHERE rp.dependencies IS NOT NULL AND json_valid(rp.dependencies);
+
+        UPDATE repository_packages SET dependencies = NULL;
+        ",
+    )?;
+
+    info!("Schema version 10 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 11: Config file tracking
+///
+/// Flags config files so upgrades keep local edits, and adds the
+/// `config-kept` file_history action recorded when that happens. SQLite
+/// cannot alter a CHECK constraint, so file_history is rebuilt.
+fn migrate_v11(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 11");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE files ADD COLUMN is_config INTEGER NOT NULL DEFAULT 0;
+
+        CREATE TABLE file_history_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            changeset_id INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            sha256_hash TEXT,
+            action TEXT NOT NULL CHECK(action IN ('add', 'modify', 'delete', 'config-kept')),
+            previous_hash TEXT,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (changeset_id) REFERENCES changesets(id) ON DELETE CASCADE,
+            FOREIGN KEY (sha256_hash) REFERENCES file_contents(sha256_hash),
+            FOREIGN KEY (previous_hash) REFERENCES file_contents(sha256_hash)
         );
-        assert!(result.is_err());
+
+        INSERT INTO file_history_new (id, changeset_id, path, sha256_hash, action, previous_hash, created_at)
+            SELECT id, changeset_id, path, sha256_hash, action, previous_hash, created_at FROM file_history;
+
+        DROP TABLE file_history;
+        ALTER TABLE file_history_new RENAME TO file_history;
+
+        CREATE INDEX idx_file_history_changeset ON file_history(changeset_id);
+        CREATE INDEX idx_file_history_path ON file_history(path);
+        ",
+    )?;
+
+    info!("Schema version 11 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 12: Installation settings
+///
+/// Adds a key/value settings table, used for example to keep the CAS
+/// objects directory on a different filesystem than the database.
+fn migrate_v12(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 12");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        ",
+    )?;
+
+    info!("Schema version 12 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 13: Install reasons
+///
+/// Records whether a trove was requested explicitly or pulled in as a
+/// dependency, so dependencies nothing needs anymore can be autoremoved.
+/// Existing troves are treated as explicit.
+fn migrate_v13(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 13");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE troves ADD COLUMN install_reason TEXT NOT NULL DEFAULT 'explicit'
+            CHECK(install_reason IN ('explicit', 'dependency'));
+        ",
+    )?;
+
+    info!("Schema version 13 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 14: Changeset size accounting
+///
+/// Records how many bytes each changeset downloaded and how much it grew or
+/// shrank the installed files. Existing changesets keep NULL ("n/a").
+fn migrate_v14(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 14");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE changesets ADD COLUMN downloaded_bytes INTEGER;
+        ALTER TABLE changesets ADD COLUMN installed_size_delta INTEGER;
+        ",
+    )?;
+
+    info!("Schema version 14 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 15: Percent-encoded file paths
+///
+/// Paths are now stored with non-UTF-8 bytes and `%` written as `%XX` (see
+/// `paths`). Existing rows were stored lossily and never contain escapes,
+/// so escaping their `%` signs is enough to keep them decoding to the same
+/// path.
+fn migrate_v15(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 15");
+
+    conn.execute_batch(
+        "
+        UPDATE files SET path = replace(path, '%', '%25') WHERE instr(path, '%') > 0;
+        UPDATE files SET link_target = replace(link_target, '%', '%25')
+            WHERE instr(link_target, '%') > 0;
+        UPDATE file_history SET path = replace(path, '%', '%25') WHERE instr(path, '%') > 0;
+        ",
+    )?;
+
+    info!("Schema version 15 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 16: Scriptlet runner tracking
+///
+/// Records which runner ("direct" or "sandbox") launched each scriptlet.
+/// Runs recorded before this version keep NULL.
+fn migrate_v16(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 16");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE scriptlet_runs ADD COLUMN backend TEXT;
+        ",
+    )?;
+
+    info!("Schema version 16 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 17: Record troves removed by changesets
+///
+/// Troves are deleted when removed, replaced by an upgrade or rolled back,
+/// which left removal changesets pointing at nothing. Each removal now
+/// keeps the name, version and architecture of the trove it took off.
+fn migrate_v17(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 17");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE changeset_removed_troves (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            changeset_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            version TEXT NOT NULL,
+            architecture TEXT,
+            installed_by_changeset_id INTEGER,
+            reason TEXT NOT NULL CHECK(reason IN ('removed', 'replaced', 'rolled_back')),
+            FOREIGN KEY (changeset_id) REFERENCES changesets(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX idx_changeset_removed_troves_changeset ON changeset_removed_troves(changeset_id);
+        CREATE INDEX idx_changeset_removed_troves_name ON changeset_removed_troves(name);
+        ",
+    )?;
+
+    info!("Schema version 17 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 18: Provides for virtual package resolution
+///
+/// Records the capabilities (virtual names, sonames) that repository
+/// packages and installed troves provide, so a dependency on a capability
+/// can be matched to the package that supplies it.
+fn migrate_v18(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 18");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE repository_provides (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            package_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            version TEXT,
+            FOREIGN KEY (package_id) REFERENCES repository_packages(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX idx_repository_provides_package ON repository_provides(package_id);
+        CREATE INDEX idx_repository_provides_name ON repository_provides(name);
+
+        CREATE TABLE provides (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            trove_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            version TEXT,
+            FOREIGN KEY (trove_id) REFERENCES troves(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX idx_provides_trove ON provides(trove_id);
+        CREATE INDEX idx_provides_name ON provides(name);
+        ",
+    )?;
+
+    info!("Schema version 18 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 19: Files shipped by repository packages
+///
+/// Repository metadata lists the files a package owns (Fedora's primary.xml
+/// carries the ones under /bin, /sbin, /usr/bin and /etc) so that file
+/// dependencies such as "/bin/sh" can be resolved before the package is
+/// downloaded.
+fn migrate_v19(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 19");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE repository_package_files (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            package_id INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            FOREIGN KEY (package_id) REFERENCES repository_packages(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX idx_repository_package_files_package ON repository_package_files(package_id);
+        CREATE INDEX idx_repository_package_files_path ON repository_package_files(path);
+        ",
+    )?;
+
+    info!("Schema version 19 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 20: Conflicts and replaces of installed troves
+///
+/// A trove's conflicts keep other packages from being installed next to
+/// it; its replaces name packages it supersedes. Both come from package
+/// metadata such as Arch's `conflict` and `replaces` entries.
+fn migrate_v20(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 20");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE trove_relations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            trove_id INTEGER NOT NULL,
+            kind TEXT NOT NULL CHECK(kind IN ('conflicts', 'replaces')),
+            name TEXT NOT NULL,
+            version_constraint TEXT,
+            FOREIGN KEY (trove_id) REFERENCES troves(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX idx_trove_relations_trove ON trove_relations(trove_id);
+        CREATE INDEX idx_trove_relations_name ON trove_relations(name);
+        ",
+    )?;
+
+    info!("Schema version 20 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 21: Repository scope
+///
+/// Explicit-only repositories are used when an install names them with
+/// --repo, never by dependency resolution. Existing repositories keep full
+/// scope.
+fn migrate_v21(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 21");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE repositories ADD COLUMN scope TEXT NOT NULL DEFAULT 'full'
+            CHECK(scope IN ('full', 'explicit-only'));
+        ",
+    )?;
+
+    info!("Schema version 21 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 22: Protected changesets
+///
+/// Protected changesets, such as the initial provisioning of a system, are
+/// refused by rollback unless forced. Existing changesets are unprotected.
+fn migrate_v22(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 22");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE changesets ADD COLUMN protected INTEGER NOT NULL DEFAULT 0;
+        ",
+    )?;
+
+    info!("Schema version 22 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 23: Repository metadata validators
+///
+/// The ETag, Last-Modified and SHA-256 of the metadata index seen by the last
+/// sync, so later syncs can skip repositories whose metadata is unchanged.
+fn migrate_v23(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 23");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE repositories ADD COLUMN metadata_etag TEXT;
+        ALTER TABLE repositories ADD COLUMN metadata_last_modified TEXT;
+        ALTER TABLE repositories ADD COLUMN metadata_checksum TEXT;
+        ",
+    )?;
+
+    info!("Schema version 23 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 24: Changeset phase timings
+///
+/// The JSON timing report of the install that created a changeset, so slow
+/// installs can be profiled after the fact.
+fn migrate_v24(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 24");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE changesets ADD COLUMN timings TEXT;
+        ",
+    )?;
+
+    info!("Schema version 24 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 25: Repository-claimed versions
+///
+/// When a downloaded package's header disagrees with the repository
+/// metadata, the trove keeps the header version and the provenance keeps
+/// the version the repository advertised, so updates do not chase it.
+fn migrate_v25(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 25");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE provenance ADD COLUMN repository_version TEXT;
+        ",
+    )?;
+
+    info!("Schema version 25 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 26: Trove origins
+///
+/// The repository and URL a trove was downloaded from, so a broken build
+/// can be traced to its mirror and updates can stay with the repository a
+/// package came from. Both are NULL for local-file installs; removing the
+/// repository keeps the URL.
+fn migrate_v26(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 26");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE troves ADD COLUMN origin_repository_id INTEGER REFERENCES repositories(id) ON DELETE SET NULL;
+        ALTER TABLE troves ADD COLUMN origin_url TEXT;
+        ",
+    )?;
+
+    info!("Schema version 26 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 27: Install options of each trove
+///
+/// Records, as JSON, the install-time choices that left files of a package
+/// undeployed, such as exclusion globs.
+fn migrate_v27(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 27");
+
+    conn.execute("ALTER TABLE troves ADD COLUMN install_options TEXT", [])?;
+
+    info!("Schema version 27 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 28: Repository download helpers
+///
+/// A repository may name an external command that downloads its packages,
+/// and optionally its metadata, instead of the built-in HTTP client. When
+/// the helper fails, `helper_fallback` decides whether the built-in client
+/// is tried or the download fails.
+fn migrate_v28(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 28");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE repositories ADD COLUMN download_helper TEXT;
+        ALTER TABLE repositories ADD COLUMN helper_metadata INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE repositories ADD COLUMN helper_fallback INTEGER NOT NULL DEFAULT 0;
+        ",
+    )?;
+
+    info!("Schema version 28 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 29: Repository URL policy
+///
+/// `prefer_https` upgrades a repository's http:// metadata and package URLs
+/// to https://. `allow_cross_host` decides whether packages whose URL points
+/// away from the repository's host may be downloaded; it defaults to allowed,
+/// as mirrors listed in metadata commonly do.
+fn migrate_v29(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 29");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE repositories ADD COLUMN prefer_https INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE repositories ADD COLUMN allow_cross_host INTEGER NOT NULL DEFAULT 1;
+        ",
+    )?;
+
+    info!("Schema version 29 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 30: Repository sync filters
+///
+/// `sync_include` and `sync_exclude` hold newline-separated globs matched
+/// against package names (or `name.arch`) while a sync stores the package
+/// index; NULL means no filter.
+fn migrate_v30(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 30");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE repositories ADD COLUMN sync_include TEXT;
+        ALTER TABLE repositories ADD COLUMN sync_exclude TEXT;
+        ",
+    )?;
+
+    info!("Schema version 30 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 31: Package locks
+///
+/// A locked package is held at its installed version: updates skip it and
+/// installing another version of it is refused. Locks are kept by name, so
+/// they outlive the trove they were taken on.
+fn migrate_v31(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 31");
+
+    conn.execute(
+        "CREATE TABLE package_locks (
+            name TEXT PRIMARY KEY,
+            locked_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+
+    info!("Schema version 31 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 32: Repository advisories
+///
+/// Update advisories a repository publishes (Fedora's updateinfo.xml), one
+/// row per package version an advisory covers. Rows are linked by package
+/// name and version rather than to a repository package, and replaced with
+/// the package index on every sync. `cves` holds newline-separated CVE
+/// identifiers.
+fn migrate_v32(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 32");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE repository_advisories (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repository_id INTEGER NOT NULL,
+            advisory_id TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            severity TEXT,
+            cves TEXT,
+            package_name TEXT NOT NULL,
+            package_version TEXT NOT NULL,
+            FOREIGN KEY (repository_id) REFERENCES repositories(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX idx_repository_advisories_package
+            ON repository_advisories(package_name, package_version);
+        CREATE INDEX idx_repository_advisories_repository ON repository_advisories(repository_id);
+        ",
+    )?;
+
+    info!("Schema version 32 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 33: Debian components and architectures
+///
+/// `components` and `architectures` hold the newline-separated components
+/// and architectures a Debian repository is synced for; NULL reads every
+/// component its Release file lists, for the host architecture.
+fn migrate_v33(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 33");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE repositories ADD COLUMN components TEXT;
+        ALTER TABLE repositories ADD COLUMN architectures TEXT;
+        ",
+    )?;
+
+    info!("Schema version 33 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 34: Verify findings
+///
+/// The files the last `verify` of each package found modified, missing,
+/// drifted or unrepairable, so `update` can refuse to overwrite local
+/// changes. `install_changeset_id` is the changeset that had installed the
+/// package when verify ran; findings from before the package's current
+/// install no longer apply.
+fn migrate_v34(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 34");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE verify_findings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            package TEXT NOT NULL,
+            path TEXT NOT NULL,
+            status TEXT NOT NULL,
+            install_changeset_id INTEGER,
+            found_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE(package, path)
+        );
+        ",
+    )?;
+
+    info!("Schema version 34 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 35: Directory and file swaps in the file history
+///
+/// An upgrade can ship a file where the old version had a directory, or
+/// the reverse. The file_history actions `dir-to-file` and `dir-saved`
+/// record a directory removed or moved aside for a file, and `file-to-dir`
+/// a file removed for a directory, so rollback can put them back. As in
+/// version 11, file_history is rebuilt to change its CHECK constraint.
+fn migrate_v35(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 35");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE file_history_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            changeset_id INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            sha256_hash TEXT,
+            action TEXT NOT NULL CHECK(action IN ('add', 'modify', 'delete', 'config-kept', 'dir-to-file', 'dir-saved', 'file-to-dir')),
+            previous_hash TEXT,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (changeset_id) REFERENCES changesets(id) ON DELETE CASCADE,
+            FOREIGN KEY (sha256_hash) REFERENCES file_contents(sha256_hash),
+            FOREIGN KEY (previous_hash) REFERENCES file_contents(sha256_hash)
+        );
+
+        INSERT INTO file_history_new (id, changeset_id, path, sha256_hash, action, previous_hash, created_at)
+            SELECT id, changeset_id, path, sha256_hash, action, previous_hash, created_at FROM file_history;
+
+        DROP TABLE file_history;
+        ALTER TABLE file_history_new RENAME TO file_history;
+
+        CREATE INDEX idx_file_history_changeset ON file_history(changeset_id);
+        CREATE INDEX idx_file_history_path ON file_history(path);
+        ",
+    )?;
+
+    info!("Schema version 35 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 36: Installed size per trove
+///
+/// The total size of a trove's files, recorded when it is installed so
+/// disk usage can be listed by package without summing every file.
+/// Existing troves get the sum of their recorded files.
+fn migrate_v36(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 36");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE troves ADD COLUMN installed_size INTEGER NOT NULL DEFAULT 0;
+        UPDATE troves SET installed_size = (SELECT COALESCE(SUM(size), 0) FROM files WHERE files.trove_id = troves.id);
+        ",
+    )?;
+
+    info!("Schema version 36 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 37: Checksum algorithm of repository packages
+///
+/// Metadata may publish SHA-512 or MD5 digests instead of SHA-256, and
+/// downloads have to be checked with the algorithm the checksum was made
+/// with. Packages synced before this were all taken as SHA-256.
+fn migrate_v37(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 37");
+
+    conn.execute_batch(
+        "ALTER TABLE repository_packages ADD COLUMN checksum_type TEXT NOT NULL DEFAULT 'sha256';",
+    )?;
+
+    info!("Schema version 37 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 38: Staged changesets
+///
+/// With staged deployment a changeset is recorded with the status `staged`
+/// and its files wait in a pending tree until `apply-pending` moves them
+/// into place. The changesets table is rebuilt to change its CHECK
+/// constraint. Foreign keys are off meanwhile: dropping the old table
+/// would otherwise cascade into the file history and everything else that
+/// refers to a changeset.
+fn migrate_v38(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 38");
+
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let rebuilt = conn.execute_batch(
+        "
+        CREATE TABLE changesets_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            description TEXT NOT NULL,
+            status TEXT NOT NULL CHECK(status IN ('pending', 'applied', 'staged', 'rolled_back')),
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            applied_at TEXT,
+            rolled_back_at TEXT,
+            reversed_by_changeset_id INTEGER REFERENCES changesets(id) ON DELETE SET NULL,
+            batch_id TEXT,
+            downloaded_bytes INTEGER,
+            installed_size_delta INTEGER,
+            protected INTEGER NOT NULL DEFAULT 0,
+            timings TEXT
+        );
+
+        INSERT INTO changesets_new (id, description, status, created_at, applied_at, rolled_back_at,
+                                    reversed_by_changeset_id, batch_id, downloaded_bytes, installed_size_delta,
+                                    protected, timings)
+            SELECT id, description, status, created_at, applied_at, rolled_back_at, reversed_by_changeset_id,
+                   batch_id, downloaded_bytes, installed_size_delta, protected, timings
+            FROM changesets;
+
+        DROP TABLE changesets;
+        ALTER TABLE changesets_new RENAME TO changesets;
+
+        CREATE INDEX idx_changesets_status ON changesets(status);
+        CREATE INDEX idx_changesets_created_at ON changesets(created_at);
+        CREATE INDEX idx_changesets_batch ON changesets(batch_id);
+        ",
+    );
+    if foreign_keys {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    }
+    rebuilt?;
+
+    info!("Schema version 38 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 39: Directories owned by packages
+///
+/// Directories are recorded as files of type `directory` with no hash, so
+/// verify can check their mode and removal can clean them up. Packages
+/// share directories like /usr/bin, so paths are only unique among the
+/// other file types. The files table is rebuilt to change its constraints;
+/// nothing refers to it.
+fn migrate_v39(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 39");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE files_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            sha256_hash TEXT,
+            size INTEGER NOT NULL,
+            permissions INTEGER NOT NULL,
+            owner TEXT,
+            group_name TEXT,
+            trove_id INTEGER NOT NULL,
+            installed_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            file_type TEXT NOT NULL DEFAULT 'regular'
+                CHECK(file_type IN ('regular', 'symlink', 'hardlink', 'directory')),
+            link_target TEXT,
+            is_config INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (trove_id) REFERENCES troves(id) ON DELETE CASCADE
+        );
+
+        INSERT INTO files_new (id, path, sha256_hash, size, permissions, owner, group_name, trove_id, installed_at,
+                               file_type, link_target, is_config)
+            SELECT id, path, sha256_hash, size, permissions, owner, group_name, trove_id, installed_at,
+                   file_type, link_target, is_config
+            FROM files;
+
+        DROP TABLE files;
+        ALTER TABLE files_new RENAME TO files;
+
+        CREATE INDEX idx_files_path ON files(path);
+        CREATE UNIQUE INDEX idx_files_unique_path ON files(path) WHERE file_type != 'directory';
+        CREATE INDEX idx_files_trove_id ON files(trove_id);
+        CREATE INDEX idx_files_sha256 ON files(sha256_hash);
+        ",
+    )?;
+
+    info!("Schema version 39 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 40: Canonical file_contents rows
+///
+/// Content rows were written by several call sites, each building the
+/// content path itself. Existing rows are normalized: hashes are trimmed
+/// and lowercased, rows left sharing a hash are merged, sizes become
+/// integers and content paths are recomputed from the hash. The hashes
+/// other tables record are normalized the same way. The rebuilt table only
+/// accepts lowercase hashes and integer sizes, so rows added through
+/// `FileContents` stay that way. Foreign keys are off during the rebuild,
+/// as in v38.
+fn migrate_v40(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 40");
+
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let rebuilt = conn.execute_batch(
+        "
+        CREATE TABLE file_contents_new (
+            sha256_hash TEXT PRIMARY KEY CHECK(sha256_hash = lower(sha256_hash)),
+            content_path TEXT NOT NULL,
+            size INTEGER NOT NULL CHECK(typeof(size) = 'integer' AND size >= 0),
+            stored_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+
+        INSERT INTO file_contents_new (sha256_hash, content_path, size, stored_at)
+            SELECT lower(trim(sha256_hash)),
+                   'objects/' || substr(lower(trim(sha256_hash)), 1, 2) || '/' || substr(lower(trim(sha256_hash)), 3),
+                   max(0, MAX(CAST(size AS INTEGER))),
+                   COALESCE(MIN(stored_at), CURRENT_TIMESTAMP)
+            FROM file_contents
+            GROUP BY lower(trim(sha256_hash));
+
+        DROP TABLE file_contents;
+        ALTER TABLE file_contents_new RENAME TO file_contents;
+
+        CREATE INDEX idx_file_contents_stored_at ON file_contents(stored_at);
+
+        UPDATE files SET sha256_hash = lower(trim(sha256_hash)) WHERE sha256_hash != lower(trim(sha256_hash));
+        UPDATE file_history SET sha256_hash = lower(trim(sha256_hash)) WHERE sha256_hash != lower(trim(sha256_hash));
+        UPDATE file_history SET previous_hash = lower(trim(previous_hash))
+            WHERE previous_hash != lower(trim(previous_hash));
+        UPDATE package_deltas SET from_hash = lower(trim(from_hash)), to_hash = lower(trim(to_hash))
+            WHERE from_hash != lower(trim(from_hash)) OR to_hash != lower(trim(to_hash));
+        ",
+    );
+    if foreign_keys {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    }
+    rebuilt?;
+
+    info!("Schema version 40 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 41: Epochs of troves and repository packages
+///
+/// Versions kept their epoch only as an `epoch:` prefix, and troves
+/// installed from local RPM files had none at all, so a package could look
+/// older than the repository's copy of itself. The epoch is now a column of
+/// its own, filled from the prefix of existing versions; a `0:` prefix is
+/// dropped, since versions only show an epoch above zero.
+fn migrate_v41(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 41");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE troves ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE repository_packages ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0;
+        ",
+    )?;
+
+    for table in ["troves", "repository_packages"] {
+        // An epoch prefix is a run of digits before the first colon
+        conn.execute_batch(&format!(
+            "
+            UPDATE {table} SET epoch = CAST(substr(version, 1, instr(version, ':') - 1) AS INTEGER)
+                WHERE instr(version, ':') > 1
+                  AND substr(version, 1, instr(version, ':') - 1) NOT GLOB '*[^0-9]*';
+            UPDATE {table} SET version = substr(version, instr(version, ':') + 1)
+                WHERE epoch = 0 AND instr(version, ':') > 1
+                  AND substr(version, 1, instr(version, ':') - 1) NOT GLOB '*[^0-9]*';
+            "
+        ))?;
+    }
+
+    info!("Schema version 41 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 42: Delta hashes without foreign keys
+///
+/// A package delta names the hashes of whole package files: the base it
+/// applies to, found in the CAS, and the package it rebuilds. Those are
+/// never file_contents rows, which track the files packages install, so
+/// the foreign keys made every repository delta fail to insert. The table
+/// is rebuilt without them, with foreign keys off as in v38.
+fn migrate_v42(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 42");
+
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let rebuilt = conn.execute_batch(
+        "
+        CREATE TABLE package_deltas_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            package_name TEXT NOT NULL,
+            from_version TEXT NOT NULL,
+            to_version TEXT NOT NULL,
+            from_hash TEXT NOT NULL,
+            to_hash TEXT NOT NULL,
+            delta_url TEXT NOT NULL,
+            delta_size INTEGER NOT NULL,
+            delta_checksum TEXT NOT NULL,
+            full_size INTEGER NOT NULL,
+            compression_ratio REAL NOT NULL,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+
+        INSERT INTO package_deltas_new SELECT * FROM package_deltas;
+        DROP TABLE package_deltas;
+        ALTER TABLE package_deltas_new RENAME TO package_deltas;
+
+        CREATE INDEX idx_package_deltas_package ON package_deltas(package_name);
+        CREATE INDEX idx_package_deltas_from_hash ON package_deltas(from_hash);
+        CREATE INDEX idx_package_deltas_to_hash ON package_deltas(to_hash);
+        CREATE UNIQUE INDEX idx_package_deltas_transition ON package_deltas(package_name, from_version, to_version);
+        ",
+    );
+    if foreign_keys {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    }
+    rebuilt?;
+
+    info!("Schema version 42 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 43: Extended attributes of files
+///
+/// Packages give some files capabilities or other extended attributes,
+/// which have to be set again when a file is redeployed and are checked by
+/// `verify --attrs`. They are kept as a JSON object with hex values; files
+/// without any have NULL.
+fn migrate_v43(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 43");
+
+    conn.execute_batch("ALTER TABLE files ADD COLUMN xattrs TEXT;")?;
+
+    info!("Schema version 43 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 44: Repository pins
+///
+/// Repository priority orders whole repositories; a pin overrides it for
+/// the packages matching a glob, preferring one repository for them or
+/// forbidding one. See `RepositoryPin`.
+fn migrate_v44(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 44");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE repository_pins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            pattern TEXT NOT NULL,
+            repository TEXT NOT NULL,
+            action TEXT NOT NULL CHECK(action IN ('prefer', 'forbid')),
+            priority INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE(pattern, repository)
+        );
+        ",
+    )?;
+
+    info!("Schema version 44 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 45: Package format of troves
+///
+/// Dependency and conflict checks order an installed trove's version the
+/// way its format does. Troves installed from a repository take the format
+/// of their download URL; local installs from before this stay unknown and
+/// are ordered like RPMs.
+fn migrate_v45(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 45");
+
+    conn.execute_batch(
+        "
+        ALTER TABLE troves ADD COLUMN format TEXT;
+        UPDATE troves SET format = CASE
+            WHEN origin_url LIKE '%.deb' THEN 'deb'
+            WHEN origin_url LIKE '%.pkg.tar%' THEN 'arch'
+            WHEN origin_url LIKE '%.rpm' THEN 'rpm'
+        END;
+        ",
+    )?;
+
+    info!("Schema version 45 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 46: Paths installonly versions share
+///
+/// A path has one owner in `files`, the version installed last. The other
+/// versions shipping the same file are listed here, so removing the owner
+/// hands the path to one of them instead of deleting it.
+fn migrate_v46(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 46");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE shared_files (
+            path TEXT NOT NULL,
+            trove_id INTEGER NOT NULL,
+            PRIMARY KEY (path, trove_id),
+            FOREIGN KEY (trove_id) REFERENCES troves(id) ON DELETE CASCADE
+        );
+        CREATE INDEX idx_shared_files_trove_id ON shared_files(trove_id);
+        ",
+    )?;
+
+    info!("Schema version 46 applied successfully");
+    Ok(())
+}
+
+/// Schema Version 47: Directory modes tightened in the file history
+///
+/// Packages sharing a directory get the strictest mode any of them
+/// declares. The file_history action `dir-mode` records an existing
+/// directory whose mode a changeset tightened. As in version 35,
+/// file_history is rebuilt to change its CHECK constraint.
+fn migrate_v47(conn: &Connection) -> Result<()> {
+    debug!("Migrating to schema version 47");
+
+    conn.execute_batch(
+        "
+        CREATE TABLE file_history_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            changeset_id INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            sha256_hash TEXT,
+            action TEXT NOT NULL CHECK(action IN ('add', 'modify', 'delete', 'config-kept', 'dir-to-file', 'dir-saved', 'file-to-dir', 'dir-mode')),
+            previous_hash TEXT,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (changeset_id) REFERENCES changesets(id) ON DELETE CASCADE,
+            FOREIGN KEY (sha256_hash) REFERENCES file_contents(sha256_hash),
+            FOREIGN KEY (previous_hash) REFERENCES file_contents(sha256_hash)
+        );
+
+        INSERT INTO file_history_new (id, changeset_id, path, sha256_hash, action, previous_hash, created_at)
+            SELECT id, changeset_id, path, sha256_hash, action, previous_hash, created_at FROM file_history;
+
+        DROP TABLE file_history;
+        ALTER TABLE file_history_new RENAME TO file_history;
+
+        CREATE INDEX idx_file_history_changeset ON file_history(changeset_id);
+        CREATE INDEX idx_file_history_path ON file_history(path);
+        ",
+    )?;
+
+    info!("Schema version 47 applied successfully");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn create_test_db() -> (NamedTempFile, Connection) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        (temp_file, conn)
+    }
+
+    #[test]
+    fn test_schema_version_tracking() {
+        let (_temp, conn) = create_test_db();
+
+        // Initial version should be 0
+        let version = get_schema_version(&conn).unwrap();
+        assert_eq!(version, 0);
+
+        // Set version to 1
+        set_schema_version(&conn, 1).unwrap();
+        let version = get_schema_version(&conn).unwrap();
+        assert_eq!(version, 1);
+    }
+
+    #[test]
+    fn test_migrate_creates_all_tables() {
+        let (_temp, conn) = create_test_db();
+
+        // Run migration
+        migrate(&conn).unwrap();
+
+        // Verify all tables exist
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type='table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert!(tables.contains(&"troves".to_string()));
+        assert!(tables.contains(&"changesets".to_string()));
+        assert!(tables.contains(&"files".to_string()));
+        assert!(tables.contains(&"flavors".to_string()));
+        assert!(tables.contains(&"provenance".to_string()));
+        assert!(tables.contains(&"dependencies".to_string()));
+        assert!(tables.contains(&"schema_version".to_string()));
+    }
+
+    #[test]
+    fn test_migrate_is_idempotent() {
+        let (_temp, conn) = create_test_db();
+
+        // Run migration twice
+        migrate(&conn).unwrap();
+        let version1 = get_schema_version(&conn).unwrap();
+
+        migrate(&conn).unwrap();
+        let version2 = get_schema_version(&conn).unwrap();
+
+        assert_eq!(version1, version2);
+        assert_eq!(version1, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_troves_table_constraints() {
+        let (_temp, conn) = create_test_db();
+        migrate(&conn).unwrap();
+
+        // Insert a valid trove
+        conn.execute(
+            "INSERT INTO troves (name, version, type, architecture) VALUES (?1, ?2, ?3, ?4)",
+            ["test-package", "1.0.0", "package", "x86_64"],
+        )
+        .unwrap();
+
+        // Try to insert duplicate - should fail due to UNIQUE constraint
+        let result = conn.execute(
+            "INSERT INTO troves (name, version, type, architecture) VALUES (?1, ?2, ?3, ?4)",
+            ["test-package", "1.0.0", "package", "x86_64"],
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_foreign_key_constraints() {
+        let (_temp, conn) = create_test_db();
+        conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
+        migrate(&conn).unwrap();
+
+        // Try to insert a file without a trove - should fail
+        let result = conn.execute(
+            "INSERT INTO files (path, sha256_hash, size, permissions, trove_id)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            [
+                "/usr/bin/test",
+                "abc123",
+                "1024",
+                "755",
+                "999", // Non-existent trove_id
+            ],
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_migrate_v10_moves_dependency_json() {
+        let (_temp, conn) = create_test_db();
+        init_schema_version(&conn).unwrap();
+        for version in 1..10 {
+            apply_migration(&conn, version).unwrap();
+        }
+
+        conn.execute_batch(
+            "INSERT INTO repositories (name, url) VALUES ('repo', 'https://example.com');
+             INSERT INTO repository_packages (repository_id, name, version, checksum, size, download_url, dependencies)
+                 VALUES (1, 'meta', '1.0', 'abc', 1, 'https://example.com/meta.rpm', '[\"glibc\", \"bash\"]');
+             INSERT INTO repository_packages (repository_id, name, version, checksum, size, download_url, dependencies)
+                 VALUES (1, 'broken', '1.0', 'def', 1, 'https://example.com/broken.rpm', 'not json');",
+        )
+        .unwrap();
+
+        apply_migration(&conn, 10).unwrap();
+
+        let deps: Vec<String> = conn
+            .prepare("SELECT dependency FROM repository_package_dependencies ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(deps, vec!["glibc", "bash"]);
+
+        let leftover: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM repository_packages WHERE dependencies IS NOT NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(leftover, 0);
+    }
+
+    #[test]
+    fn test_migrate_v36_backfills_installed_size() {
+        let (_temp, conn) = create_test_db();
+        init_schema_version(&conn).unwrap();
+        for version in 1..36 {
+            apply_migration(&conn, version).unwrap();
+        }
+
+        conn.execute_batch(
+            "INSERT INTO troves (name, version, type) VALUES ('tool', '1.0', 'package'), ('empty', '1.0', 'package');
+             INSERT INTO file_contents (sha256_hash, content_path, size) VALUES ('aaaa', 'objects/aa/aa', 10);
+             INSERT INTO files (path, sha256_hash, size, permissions, trove_id)
+                 VALUES ('/usr/bin/tool', 'aaaa', 10, 493, 1), ('/usr/bin/tool2', 'aaaa', 10, 493, 1);",
+        )
+        .unwrap();
+
+        apply_migration(&conn, 36).unwrap();
+
+        let sizes: Vec<(String, i64)> = conn
+            .prepare("SELECT name, installed_size FROM troves ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            sizes,
+            vec![("tool".to_string(), 20), ("empty".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn test_migrate_v38_keeps_rows_referring_to_changesets() {
+        let (_temp, conn) = create_test_db();
+        conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
+        init_schema_version(&conn).unwrap();
+        for version in 1..38 {
+            apply_migration(&conn, version).unwrap();
+        }
+
+        conn.execute_batch(
+            "INSERT INTO changesets (description, status, protected) VALUES ('Install tool-1.0', 'applied', 1);
+             INSERT INTO file_history (changeset_id, path, action) VALUES (1, '/usr/bin/tool', 'add');",
+        )
+        .unwrap();
+        assert!(
+            conn.execute(
+                "INSERT INTO changesets (description, status) VALUES ('x', 'staged')",
+                []
+            )
+            .is_err()
+        );
+
+        apply_migration(&conn, 38).unwrap();
+
+        let history: i64 = conn
+            .query_row("SELECT COUNT(*) FROM file_history", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(history, 1);
+        let protected: bool = conn
+            .query_row("SELECT protected FROM changesets WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(protected);
+        conn.execute(
+            "INSERT INTO changesets (description, status) VALUES ('Install tool-2.0', 'staged')",
+            [],
+        )
+        .unwrap();
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+    }
+
+    #[test]
+    fn test_migrate_v39_shares_directories_between_troves() {
+        let (_temp, conn) = create_test_db();
+        init_schema_version(&conn).unwrap();
+        for version in 1..39 {
+            apply_migration(&conn, version).unwrap();
+        }
+
+        conn.execute_batch(
+            "INSERT INTO troves (name, version, type) VALUES ('a', '1.0', 'package'), ('b', '1.0', 'package');
+             INSERT INTO files (path, sha256_hash, size, permissions, trove_id, is_config)
+                 VALUES ('/etc/a.conf', 'aaaa', 3, 420, 1, 1);",
+        )
+        .unwrap();
+
+        apply_migration(&conn, 39).unwrap();
+
+        let is_config: bool = conn
+            .query_row(
+                "SELECT is_config FROM files WHERE path = '/etc/a.conf'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(is_config);
+        for trove_id in [1, 2] {
+            conn.execute(
+                "INSERT INTO files (path, size, permissions, trove_id, file_type) VALUES ('/var/lib/a', 0, 448, ?1, 'directory')",
+                [trove_id],
+            )
+            .unwrap();
+        }
+        // Only directories are shared
+        assert!(
+            conn.execute("INSERT INTO files (path, sha256_hash, size, permissions, trove_id) VALUES ('/etc/a.conf', 'bbbb', 3, 420, 2)", [])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_migrate_v40_normalizes_file_contents() {
+        let (_temp, conn) = create_test_db();
+        init_schema_version(&conn).unwrap();
+        for version in 1..40 {
+            apply_migration(&conn, version).unwrap();
+        }
+
+        let hash = "ab".repeat(32);
+        conn.execute_batch(&format!(
+            "INSERT INTO file_contents (sha256_hash, content_path, size, stored_at)
+                 VALUES ('{upper}', '{upper}', '1024 bytes', '2024-01-02 00:00:00'),
+                        (' {hash} ', 'objects/{hash}', 1024, '2024-01-01 00:00:00'),
+                        ('cdcd', 'cdcd', '3000000000', '2024-01-03 00:00:00');
+             INSERT INTO troves (name, version, type) VALUES ('a', '1.0', 'package');
+             INSERT INTO files (path, sha256_hash, size, permissions, trove_id) VALUES ('/usr/bin/a', '{upper}', 1024, 493, 1);",
+            upper = hash.to_uppercase(),
+        ))
+        .unwrap();
+
+        apply_migration(&conn, 40).unwrap();
+
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM file_contents", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+        let (path, kind, stored_at): (String, String, String) = conn
+            .query_row(
+                "SELECT content_path, typeof(size), stored_at FROM file_contents WHERE sha256_hash = ?1",
+                [&hash],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(path, format!("objects/ab/{}", &hash[2..]));
+        assert_eq!(kind, "integer");
+        assert_eq!(stored_at, "2024-01-01 00:00:00");
+        let file_hash: String = conn
+            .query_row(
+                "SELECT sha256_hash FROM files WHERE path = '/usr/bin/a'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(file_hash, hash);
+        // Sizes stored as text are summed as the numbers they spell
+        let total: i64 = conn
+            .query_row("SELECT SUM(size) FROM file_contents", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(total, 3_000_001_024);
+
+        assert!(
+            conn.execute("INSERT INTO file_contents (sha256_hash, content_path, size) VALUES ('EFEF', 'x', 1)", [])
+                .is_err()
+        );
+        assert!(
+            conn.execute("INSERT INTO file_contents (sha256_hash, content_path, size) VALUES ('efef', 'x', '1 KiB')", [])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_migrate_v41_splits_epochs() {
+        let (_temp, conn) = create_test_db();
+        init_schema_version(&conn).unwrap();
+        for version in 1..41 {
+            apply_migration(&conn, version).unwrap();
+        }
+
+        conn.execute_batch(
+            "INSERT INTO troves (name, version, type)
+                 VALUES ('a', '1:2.3-4.fc43', 'package'), ('b', '0:1.0-1', 'package'), ('c', '1.0-1', 'package');
+             INSERT INTO repositories (name, url) VALUES ('fedora', 'https://example.com');
+             INSERT INTO repository_packages (repository_id, name, version, checksum, size, download_url)
+                 VALUES (1, 'a', '2:2.3-5.fc43', 'abc', 1, 'https://example.com/a.rpm');",
+        )
+        .unwrap();
+
+        apply_migration(&conn, 41).unwrap();
+
+        let troves: Vec<(String, i64)> = conn
+            .prepare("SELECT version, epoch FROM troves ORDER BY name")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            troves,
+            vec![
+                ("1:2.3-4.fc43".to_string(), 1),
+                ("1.0-1".to_string(), 0),
+                ("1.0-1".to_string(), 0)
+            ]
+        );
+        let epoch: i64 = conn
+            .query_row("SELECT epoch FROM repository_packages", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(epoch, 2);
+    }
+
+    #[test]
+    fn test_migrate_v42_accepts_deltas_of_package_files() {
+        let (_temp, conn) = create_test_db();
+        conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
+        init_schema_version(&conn).unwrap();
+        for version in 1..42 {
+            apply_migration(&conn, version).unwrap();
+        }
+        let (from, to) = ("ab".repeat(32), "cd".repeat(32));
+        let insert = format!(
+            "INSERT INTO package_deltas (package_name, from_version, to_version, from_hash, to_hash, delta_url,
+                                         delta_size, delta_checksum, full_size, compression_ratio)
+                 VALUES ('hello', '1.0', '1.1', '{from}', '{to}', 'https://example.com/hello.delta', 10, 'ef', 100, 0.1)"
+        );
+        assert!(conn.execute_batch(&insert).is_err());
+
+        apply_migration(&conn, 42).unwrap();
+        conn.execute_batch(&insert).unwrap();
+        let from_hash: String = conn
+            .query_row(
+                "SELECT from_hash FROM package_deltas WHERE package_name = 'hello'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(from_hash, from);
+        let indexes: i64 = conn
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'package_deltas'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(indexes, 4);
     }
 }
@@ -20,9 +20,22 @@
 //! ```
 //!
 //! This provides excellent compression for similar files (e.g., updated binaries).
+//!
+//! # Package Deltas
+//!
+//! [`DeltaGenerator::generate_package_deltas`] diffs every changed file between
+//! two versions of a package and writes the deltas with a [`DeltaManifest`]
+//! whose entries follow the repository `DeltaInfo` schema, so a plain HTTP
+//! directory can serve them.
 
+use crate::db::models::{FileEntry, FileType, Trove};
 use crate::error::{Error, Result};
 use crate::filesystem::CasStore;
+use crate::packages::PackageFormat;
+use crate::repository::DeltaInfo;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
@@ -79,6 +92,81 @@ impl DeltaMetrics {
     }
 }
 
+/// The regular files of one version of a package, by path, with their CAS hashes
+#[derive(Debug, Clone, Default)]
+pub struct VersionFiles {
+    pub version: String,
+    pub files: BTreeMap<String, String>,
+}
+
+impl VersionFiles {
+    /// The files of `name` at `version` if that version is installed
+    pub fn installed(conn: &Connection, name: &str, version: &str) -> Result<Option<Self>> {
+        let Some(trove) = Trove::find_by_name(conn, name)?
+            .into_iter()
+            .find(|trove| trove.version == version)
+        else {
+            return Ok(None);
+        };
+        let Some(trove_id) = trove.id else {
+            return Ok(None);
+        };
+        let files = FileEntry::find_by_trove(conn, trove_id)?
+            .into_iter()
+            .filter(|file| file.file_type == FileType::Regular)
+            .filter_map(|file| Some((file.path, file.sha256_hash?)))
+            .collect();
+        Ok(Some(Self {
+            version: trove.version,
+            files,
+        }))
+    }
+
+    /// The files of a package file, storing their content in `cas`
+    pub fn from_package(cas: &CasStore, package: &dyn PackageFormat) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for file in package.extract_file_contents()? {
+            if file.file_type == FileType::Regular {
+                files.insert(file.path, cas.store(&file.content)?);
+            }
+        }
+        Ok(Self {
+            version: package.version().to_string(),
+            files,
+        })
+    }
+}
+
+/// The delta for one file, as listed in a [`DeltaManifest`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileDelta {
+    pub path: String,
+    /// SHA-256 of the file the delta reconstructs
+    pub to_hash: String,
+    #[serde(flatten)]
+    pub delta: DeltaInfo,
+}
+
+/// The file deltas between two versions of a package
+///
+/// Each `delta_url` is relative to the directory holding the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaManifest {
+    pub package: String,
+    pub from_version: String,
+    pub to_version: String,
+    pub files: Vec<FileDelta>,
+    /// Changed files whose delta saved too little to be worth serving
+    pub skipped: Vec<String>,
+}
+
+impl DeltaManifest {
+    /// The file name the manifest is written under
+    pub fn file_name(package: &str, from_version: &str, to_version: &str) -> String {
+        format!("{}-{}-to-{}.json", package, from_version, to_version)
+    }
+}
+
 /// Delta generator using zstd dictionary compression
 pub struct DeltaGenerator {
     cas: CasStore,
@@ -126,12 +214,10 @@ impl DeltaGenerator {
         let delta = self.compress_with_dictionary(&new_content, &old_content)?;
 
         // Write delta to output file
-        let mut file = File::create(output_path).map_err(|e| {
-            Error::IoError(format!("Failed to create delta file: {}", e))
-        })?;
-        file.write_all(&delta).map_err(|e| {
-            Error::IoError(format!("Failed to write delta file: {}", e))
-        })?;
+        let mut file = File::create(output_path)
+            .map_err(|e| Error::IoError(format!("Failed to create delta file: {}", e)))?;
+        file.write_all(&delta)
+            .map_err(|e| Error::IoError(format!("Failed to write delta file: {}", e)))?;
 
         // Calculate metrics
         let metrics = DeltaMetrics::new(
@@ -150,6 +236,76 @@ impl DeltaGenerator {
         Ok(metrics)
     }
 
+    /// Generate deltas for every file that changed between two versions of a package
+    ///
+    /// Files present in both versions with different content get a delta in
+    /// `output_dir`, named after the two hashes; the manifest listing them is
+    /// written next to them under [`DeltaManifest::file_name`]. Added and
+    /// removed files have nothing to diff against and are left out.
+    pub fn generate_package_deltas(
+        &self,
+        package: &str,
+        old: &VersionFiles,
+        new: &VersionFiles,
+        output_dir: &Path,
+    ) -> Result<DeltaManifest> {
+        std::fs::create_dir_all(output_dir).map_err(|e| {
+            Error::IoError(format!("Failed to create {}: {}", output_dir.display(), e))
+        })?;
+
+        let mut manifest = DeltaManifest {
+            package: package.to_string(),
+            from_version: old.version.clone(),
+            to_version: new.version.clone(),
+            files: Vec::new(),
+            skipped: Vec::new(),
+        };
+        for (path, new_hash) in &new.files {
+            let Some(old_hash) = old.files.get(path).filter(|old_hash| *old_hash != new_hash)
+            else {
+                continue;
+            };
+            let delta_name = format!("{}-{}.delta", old_hash, new_hash);
+            let delta_path = output_dir.join(&delta_name);
+            let metrics = self.generate_delta(old_hash, new_hash, &delta_path)?;
+            if !metrics.is_worthwhile() {
+                std::fs::remove_file(&delta_path)?;
+                manifest.skipped.push(path.clone());
+                continue;
+            }
+
+            let delta = std::fs::read(&delta_path)?;
+            manifest.files.push(FileDelta {
+                path: path.clone(),
+                to_hash: new_hash.clone(),
+                delta: DeltaInfo {
+                    from_version: old.version.clone(),
+                    from_hash: old_hash.clone(),
+                    delta_url: delta_name,
+                    delta_size: metrics.delta_size as i64,
+                    delta_checksum: CasStore::compute_hash(&delta),
+                    compression_ratio: metrics.compression_ratio,
+                },
+            });
+        }
+
+        let manifest_path = output_dir.join(DeltaManifest::file_name(
+            package,
+            &old.version,
+            &new.version,
+        ));
+        let json = serde_json::to_string_pretty(&manifest)
+            .map_err(|e| Error::IoError(format!("Failed to serialize delta manifest: {}", e)))?;
+        std::fs::write(&manifest_path, json).map_err(|e| {
+            Error::IoError(format!(
+                "Failed to write {}: {}",
+                manifest_path.display(),
+                e
+            ))
+        })?;
+        Ok(manifest)
+    }
+
     /// Compress data using dictionary compression
     fn compress_with_dictionary(&self, data: &[u8], dictionary: &[u8]) -> Result<Vec<u8>> {
         // Create encoder dictionary from old version (copied for 'static lifetime)
@@ -210,13 +366,12 @@ impl DeltaApplier {
         debug!("Old version retrieved: {} bytes", old_content.len());
 
         // Read delta file
-        let mut delta_file = File::open(delta_path).map_err(|e| {
-            Error::IoError(format!("Failed to open delta file: {}", e))
-        })?;
+        let mut delta_file = File::open(delta_path)
+            .map_err(|e| Error::IoError(format!("Failed to open delta file: {}", e)))?;
         let mut delta = Vec::new();
-        delta_file.read_to_end(&mut delta).map_err(|e| {
-            Error::IoError(format!("Failed to read delta file: {}", e))
-        })?;
+        delta_file
+            .read_to_end(&mut delta)
+            .map_err(|e| Error::IoError(format!("Failed to read delta file: {}", e)))?;
 
         debug!("Delta loaded: {} bytes", delta.len());
 
@@ -422,4 +577,68 @@ mod tests {
 
         assert_eq!(result_hash, new_hash);
     }
+
+    #[test]
+    fn test_package_deltas_round_trip() {
+        let (temp, cas) = create_test_cas();
+        let output = temp.path().join("deltas");
+
+        let binary_v1 = "fn main() { println!(\"version 1.0\"); }".repeat(100);
+        let binary_v2 = "fn main() { println!(\"version 1.1\"); }".repeat(100);
+        let version = |version: &str, files: &[(&str, &[u8])]| VersionFiles {
+            version: version.to_string(),
+            files: files
+                .iter()
+                .map(|(path, content)| (path.to_string(), cas.store(content).unwrap()))
+                .collect(),
+        };
+        let old = version(
+            "1.0-1",
+            &[
+                ("/usr/bin/hello", binary_v1.as_bytes()),
+                ("/usr/share/doc/hello/README", b"unchanged"),
+                ("/usr/share/hello/old.txt", b"removed"),
+            ],
+        );
+        let new = version(
+            "1.1-1",
+            &[
+                ("/usr/bin/hello", binary_v2.as_bytes()),
+                ("/usr/share/doc/hello/README", b"unchanged"),
+                ("/usr/share/hello/new.txt", b"added"),
+            ],
+        );
+
+        let generator = DeltaGenerator::new(temp.path()).unwrap();
+        let manifest = generator
+            .generate_package_deltas("hello", &old, &new, &output)
+            .unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert!(manifest.skipped.is_empty());
+
+        let entry = &manifest.files[0];
+        assert_eq!(entry.path, "/usr/bin/hello");
+        assert_eq!(entry.delta.from_version, "1.0-1");
+        assert_eq!(entry.delta.from_hash, old.files["/usr/bin/hello"]);
+
+        let delta_path = output.join(&entry.delta.delta_url);
+        assert_eq!(
+            CasStore::compute_hash(&std::fs::read(&delta_path).unwrap()),
+            entry.delta.delta_checksum
+        );
+        let applier = DeltaApplier::new(temp.path()).unwrap();
+        let result_hash = applier
+            .apply_delta(&entry.delta.from_hash, &delta_path, &entry.to_hash)
+            .unwrap();
+        assert_eq!(result_hash, new.files["/usr/bin/hello"]);
+
+        // The manifest entries read back as repository delta info
+        let json = std::fs::read_to_string(
+            output.join(DeltaManifest::file_name("hello", "1.0-1", "1.1-1")),
+        )
+        .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let info: DeltaInfo = serde_json::from_value(value["files"][0].clone()).unwrap();
+        assert_eq!(info.delta_url, entry.delta.delta_url);
+    }
 }
@@ -33,6 +33,14 @@ pub enum Error {
     #[error("Conflict: {0}")]
     ConflictError(String),
 
+    /// A package ships a path another package owns, or an untracked file
+    /// is in the way when `owner` is None
+    #[error("File conflict: {path} is {}", match owner {
+        Some(owner) => format!("owned by package {}", owner),
+        None => "in the way but not tracked by any package".to_string(),
+    })]
+    FileConflict { path: String, owner: Option<String> },
+
     /// Resource not found
     #[error("Not found: {0}")]
     NotFoundError(String),
@@ -45,14 +53,97 @@ pub enum Error {
     #[error("Parse error: {0}")]
     ParseError(String),
 
+    /// A package file that is corrupt or not what its format expects
+    #[error("Failed to parse {} package: {reason}", format.as_str())]
+    PackageParse {
+        format: crate::packages::PackageFormatType,
+        reason: String,
+    },
+
+    /// Writing a file into the install root failed
+    #[error("Failed to deploy {path}: {source}")]
+    Deployment {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
     /// Delta operation error
     #[error("Delta operation failed: {0}")]
     DeltaError(String),
 
+    /// Package scriptlet error
+    #[error("Scriptlet failed: {0}")]
+    ScriptletError(String),
+
+    /// A package or dependency could not be resolved
+    #[error("{0}")]
+    ResolutionError(Box<crate::repository::ResolutionError>),
+
+    /// An installed package's dependency would no longer be satisfied
+    #[error("{required_by} requires {name} {constraint}")]
+    DependencyUnresolved {
+        name: String,
+        constraint: String,
+        required_by: String,
+    },
+
     /// GPG signature verification failed
     #[error("GPG verification failed: {0}")]
     GpgVerificationFailed(String),
 }
 
+impl Error {
+    /// Whether the operation may succeed if run again unchanged
+    ///
+    /// True for network failures, a database another process holds locked,
+    /// and interrupted or timed out I/O.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::DownloadError(_) => true,
+            Error::Database(rusqlite::Error::SqliteFailure(e, _)) => {
+                matches!(
+                    e.code,
+                    rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                )
+            }
+            Error::Io(e) | Error::Deployment { source: e, .. } => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
+
+    /// Process exit code the CLI reports this error with
+    ///
+    /// | Code | Meaning                                    |
+    /// |------|--------------------------------------------|
+    /// | 1    | any other failure                          |
+    /// | 3    | package, changeset or file not found       |
+    /// | 4    | conflict with installed packages or files  |
+    /// | 5    | dependency could not be resolved           |
+    /// | 6    | checksum or signature verification failed  |
+    /// | 7    | package or metadata could not be parsed    |
+    /// | 8    | package scriptlet failed                   |
+    /// | 9    | download failed                            |
+    ///
+    /// Code 2 is left to argument errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::NotFoundError(_) | Error::DatabaseNotFound(_) => 3,
+            Error::ConflictError(_) | Error::FileConflict { .. } => 4,
+            Error::ResolutionError(_) | Error::DependencyUnresolved { .. } => 5,
+            Error::ChecksumMismatch { .. } | Error::GpgVerificationFailed(_) => 6,
+            Error::ParseError(_) | Error::PackageParse { .. } => 7,
+            Error::ScriptletError(_) => 8,
+            Error::DownloadError(_) => 9,
+            _ => 1,
+        }
+    }
+}
+
 /// Result type alias using Conary's Error type
 pub type Result<T> = std::result::Result<T, Error>;
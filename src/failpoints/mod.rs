@@ -0,0 +1,65 @@
+// src/failpoints/mod.rs
+
+//! Named fault-injection points for crash-safety tests
+//!
+//! Code marks the boundaries where a failure or crash leaves interesting
+//! state behind with [`fail_point!`]. Builds with the `failpoints` feature,
+//! and the crate's own unit tests, evaluate each point against a registry;
+//! all other builds expand the macro to nothing, so release binaries carry
+//! no trace of it.
+//!
+//! Points are configured through `CONARY_FAILPOINTS`, read once per process:
+//!
+//! ```text
+//! CONARY_FAILPOINTS="install::after-record=abort;download::verify=2*off->return"
+//! ```
+//!
+//! Each point takes a list of actions joined by `->`. An action may be
+//! prefixed with `N*` to apply to the next N hits only; the last action
+//! without a count applies to every later hit. The actions are:
+//!
+//! - `off`: carry on as if the point was not there
+//! - `return` or `return(message)`: fail the operation with the error the point describes
+//! - `panic` or `panic(message)`: panic at the point
+//! - `abort`: end the process without unwinding, as a crash or kill would
+//! - `sleep(ms)`: wait, to widen a race window
+//!
+//! The available points:
+//!
+//! | Point | Where | `return` fails with |
+//! |-------|-------|---------------------|
+//! | `install::before-record` | before an install changeset is recorded | I/O error |
+//! | `install::after-record` | after the changeset is committed, before any file is deployed | I/O error |
+//! | `deploy::rename` | before each file of a batch is renamed into the root | deployment error |
+//! | `cas::commit` | after objects are written, before they are renamed into the CAS | I/O error |
+//! | `download::body` | after each chunk of a download is written | interrupted read, retried |
+//! | `download::verify` | before a downloaded package's checksum is checked | checksum mismatch |
+//! | `sync::store` | after a repository's packages are stored, before the sync commits | database busy |
+//!
+//! Unit tests configure points with `configure` instead; those apply to
+//! the configuring thread only, so tests running in parallel do not trip
+//! each other's points.
+
+/// Evaluate a named failpoint, returning early when it is set to `return`
+///
+/// `$error` turns the failure message into the error the enclosing function
+/// returns; without it the point fails with [`Error::IoError`](crate::Error::IoError).
+macro_rules! fail_point {
+    ($name:expr) => {
+        fail_point!($name, |message: String| $crate::Error::IoError(message))
+    };
+    ($name:expr, $error:expr) => {
+        #[cfg(any(test, feature = "failpoints"))]
+        if let Some(message) = $crate::failpoints::eval($name) {
+            return Err(($error)(message).into());
+        }
+    };
+}
+
+#[cfg(any(test, feature = "failpoints"))]
+mod registry;
+
+#[cfg(test)]
+pub(crate) use registry::configure;
+#[cfg(any(test, feature = "failpoints"))]
+pub(crate) use registry::eval;
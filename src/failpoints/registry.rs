@@ -0,0 +1,246 @@
+// src/failpoints/registry.rs
+
+//! The failpoint registry, compiled into builds that evaluate points
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+
+/// Name of the environment variable the points are read from
+pub(crate) const FAILPOINTS_ENV: &str = "CONARY_FAILPOINTS";
+
+/// What a point does when it is hit
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Off,
+    Return(Option<String>),
+    Panic(Option<String>),
+    Abort,
+    Sleep(u64),
+}
+
+/// An action, for the next `count` hits or every hit when None
+#[derive(Debug, Clone, PartialEq)]
+struct Task {
+    count: Option<u32>,
+    action: Action,
+}
+
+type Registry = HashMap<String, VecDeque<Task>>;
+
+thread_local! {
+    static CONFIGURED: RefCell<Registry> = RefCell::new(HashMap::new());
+}
+
+/// Points configured through the environment, shared by all threads
+fn from_env() -> &'static Mutex<Registry> {
+    static FROM_ENV: OnceLock<Mutex<Registry>> = OnceLock::new();
+    FROM_ENV.get_or_init(|| {
+        let registry = match std::env::var(FAILPOINTS_ENV) {
+            Ok(spec) => parse_registry(&spec)
+                .unwrap_or_else(|e| panic!("Invalid {}: {}", FAILPOINTS_ENV, e)),
+            Err(_) => HashMap::new(),
+        };
+        Mutex::new(registry)
+    })
+}
+
+/// Parse `name=actions` pairs separated by `;`
+fn parse_registry(spec: &str) -> Result<Registry, String> {
+    let mut registry = HashMap::new();
+    for entry in spec
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (name, actions) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not of the form name=actions", entry))?;
+        registry.insert(name.trim().to_string(), parse_tasks(actions)?);
+    }
+    Ok(registry)
+}
+
+/// Parse an action list such as `2*off->return(disk full)`
+fn parse_tasks(actions: &str) -> Result<VecDeque<Task>, String> {
+    actions
+        .split("->")
+        .map(|task| {
+            let task = task.trim();
+            let (count, action) = match task.split_once('*') {
+                Some((count, action)) => {
+                    let count = count
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid count in '{}'", task))?;
+                    (Some(count), action.trim())
+                }
+                None => (None, task),
+            };
+            let (kind, arg) = match action.split_once('(') {
+                Some((kind, rest)) => {
+                    let arg = rest
+                        .strip_suffix(')')
+                        .ok_or_else(|| format!("Unclosed argument in '{}'", task))?;
+                    (kind, Some(arg.to_string()))
+                }
+                None => (action, None),
+            };
+            let action = match (kind, arg) {
+                ("off", None) => Action::Off,
+                ("return", arg) => Action::Return(arg),
+                ("panic", arg) => Action::Panic(arg),
+                ("abort", None) => Action::Abort,
+                ("sleep", Some(ms)) => Action::Sleep(
+                    ms.parse()
+                        .map_err(|_| format!("Invalid delay in '{}'", task))?,
+                ),
+                _ => return Err(format!("Unknown action '{}'", action)),
+            };
+            Ok(Task { count, action })
+        })
+        .collect()
+}
+
+/// Take the action for one hit of a point, dropping tasks that ran out
+fn next_action(tasks: &mut VecDeque<Task>) -> Option<Action> {
+    let task = tasks.front_mut()?;
+    let action = task.action.clone();
+    if let Some(count) = &mut task.count {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            tasks.pop_front();
+        }
+    }
+    Some(action)
+}
+
+/// Hit the point `name`, returning the failure message when it is to fail
+///
+/// Used through [`fail_point!`].
+pub(crate) fn eval(name: &str) -> Option<String> {
+    let configured =
+        CONFIGURED.with(|points| points.borrow_mut().get_mut(name).and_then(next_action));
+    let action = match configured {
+        Some(action) => action,
+        None => from_env()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(name)
+            .and_then(next_action)?,
+    };
+
+    match action {
+        Action::Off => None,
+        Action::Return(message) => {
+            Some(message.unwrap_or_else(|| format!("Injected failure at {}", name)))
+        }
+        Action::Panic(message) => panic!(
+            "{}",
+            message.unwrap_or_else(|| format!("Injected panic at {}", name))
+        ),
+        Action::Abort => {
+            eprintln!("Aborting at failpoint {}", name);
+            std::process::abort()
+        }
+        Action::Sleep(ms) => {
+            std::thread::sleep(std::time::Duration::from_millis(ms));
+            None
+        }
+    }
+}
+
+/// Configure the point `name` for the current thread until the guard is dropped
+#[cfg(test)]
+pub(crate) fn configure(name: &str, actions: &str) -> FailGuard {
+    let tasks =
+        parse_tasks(actions).unwrap_or_else(|e| panic!("Invalid actions for {}: {}", name, e));
+    CONFIGURED.with(|points| points.borrow_mut().insert(name.to_string(), tasks));
+    FailGuard {
+        name: name.to_string(),
+    }
+}
+
+/// Clears a point configured with [`configure`] when dropped
+#[cfg(test)]
+pub(crate) struct FailGuard {
+    name: String,
+}
+
+#[cfg(test)]
+impl Drop for FailGuard {
+    fn drop(&mut self) {
+        CONFIGURED.with(|points| points.borrow_mut().remove(&self.name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_actions() {
+        let registry =
+            parse_registry("a=return; b = 2*off->return(disk full)->panic;c=sleep(5)").unwrap();
+        assert_eq!(
+            registry["a"],
+            [Task {
+                count: None,
+                action: Action::Return(None)
+            }]
+        );
+        assert_eq!(
+            registry["b"],
+            [
+                Task {
+                    count: Some(2),
+                    action: Action::Off
+                },
+                Task {
+                    count: None,
+                    action: Action::Return(Some("disk full".to_string()))
+                },
+                Task {
+                    count: None,
+                    action: Action::Panic(None)
+                },
+            ]
+        );
+        assert_eq!(
+            registry["c"],
+            [Task {
+                count: None,
+                action: Action::Sleep(5)
+            }]
+        );
+
+        assert!(parse_registry("a").is_err());
+        assert!(parse_registry("a=explode").is_err());
+        assert!(parse_registry("a=x*return").is_err());
+        assert!(parse_registry("a=return(oops").is_err());
+    }
+
+    #[test]
+    fn test_counted_actions_run_out() {
+        let _guard = configure("test::counted", "2*off->1*return(third)->off");
+        let hits: Vec<_> = (0..5).map(|_| eval("test::counted")).collect();
+        assert_eq!(hits, [None, None, Some("third".to_string()), None, None]);
+    }
+
+    #[test]
+    fn test_configured_points_are_per_thread_and_scoped() {
+        fn guarded() -> crate::Result<()> {
+            fail_point!("test::scoped");
+            Ok(())
+        }
+
+        {
+            let _guard = configure("test::scoped", "return");
+            assert!(
+                matches!(guarded(), Err(crate::Error::IoError(message)) if message.contains("test::scoped"))
+            );
+            assert!(std::thread::spawn(guarded).join().unwrap().is_ok());
+        }
+        assert!(guarded().is_ok());
+    }
+}
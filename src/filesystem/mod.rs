@@ -6,17 +6,87 @@
 //! similar to git's object storage. Files are stored by their SHA-256
 //! hash, enabling deduplication and efficient rollback support.
 
-use crate::error::Result;
+use crate::db::models::{FileEntry, FileType};
+use crate::error::{Error, Result};
+use crate::packages::xattrs::Xattrs;
+use crate::paths::{decode_path, encode_path, resolve_under_root, under_root};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use tracing::{debug, info};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info, warn};
+
+/// Suffix for the new version of a config file that was modified locally
+pub const CONFIG_NEW_SUFFIX: &str = ".conarynew";
+
+/// Suffix a directory is moved aside to when a package ships a file in its place
+pub const SAVED_DIR_SUFFIX: &str = ".conary-saved";
+
+/// Suffix a replaced file is linked to until the batch replacing it is in place
+const BACKUP_SUFFIX: &str = ".conary-bak";
+
+/// Content written to the CAS but not yet durable or visible under its hash
+///
+/// Dropping it without [`CasStore::commit_staged`] discards the content.
+#[derive(Debug)]
+pub struct StagedObject {
+    temp: tempfile::TempPath,
+    path: PathBuf,
+}
+
+/// Default bytes of content [`CasStore::stage_reader`] keeps in memory
+///
+/// Larger content spills to a temporary file. Overridden by the
+/// `max_extraction_buffer_bytes` setting (see `crate::resources`).
+pub const DEFAULT_BUFFER_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Copy `reader` into `writer` in fixed-size chunks, hashing on the way
+///
+/// Returns the SHA-256 hash and the number of bytes copied.
+fn copy_hashing(reader: &mut dyn Read, writer: &mut dyn Write) -> std::io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let size = copy_hashing_into(&mut hasher, reader, writer)?;
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Copy `reader` into `writer` like [`copy_hashing`], feeding an existing hasher
+fn copy_hashing_into(
+    hasher: &mut Sha256,
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+) -> std::io::Result<u64> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+        size += n as u64;
+    }
+    Ok(size)
+}
+
+/// Content read by [`CasStore::stage_reader`]
+enum Incoming {
+    /// Small enough to stay in memory
+    Memory(Vec<u8>),
+    /// Outgrew the buffer limit and went to a temporary file
+    Spilled(tempfile::NamedTempFile),
+}
 
 /// Content-addressable storage manager
 pub struct CasStore {
     /// Root directory for object storage (e.g., /var/lib/conary/objects)
     objects_dir: PathBuf,
+    /// Bytes of staged content held in memory before spilling to disk
+    buffer_limit: usize,
 }
 
 impl CasStore {
@@ -30,7 +100,16 @@ impl CasStore {
             debug!("Created CAS objects directory: {:?}", objects_dir);
         }
 
-        Ok(Self { objects_dir })
+        Ok(Self {
+            objects_dir,
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+        })
+    }
+
+    /// Keep at most `bytes` of a file's content in memory while staging it
+    pub fn with_buffer_limit(mut self, bytes: usize) -> Self {
+        self.buffer_limit = bytes;
+        self
     }
 
     /// Store file content in CAS and return its SHA-256 hash
@@ -60,6 +139,7 @@ impl CasStore {
         let mut file = fs::File::create(&temp_path)?;
         file.write_all(content)?;
         file.sync_all()?;
+        fail_point!("cas::commit");
 
         // Atomic rename
         fs::rename(&temp_path, &path)?;
@@ -68,8 +148,157 @@ impl CasStore {
         Ok(hash)
     }
 
+    /// Store many contents at once, returning their hashes in order
+    ///
+    /// Objects are written in parallel and made durable with one filesystem
+    /// sync before any of them is renamed into place, instead of one fsync
+    /// per object. Duplicate contents are written once.
+    pub fn store_batch(&self, contents: &[&[u8]]) -> Result<Vec<String>> {
+        use rayon::prelude::*;
+
+        let hashes: Vec<String> = contents
+            .par_iter()
+            .map(|content| Self::compute_hash(content))
+            .collect();
+
+        let mut pending: HashMap<&str, &[u8]> = HashMap::new();
+        for (hash, content) in hashes.iter().zip(contents) {
+            if !self.exists(hash) {
+                pending.entry(hash).or_insert(content);
+            }
+        }
+        let pending: Vec<_> = pending.into_iter().collect();
+
+        let staged = pending
+            .par_iter()
+            .map(|(hash, content)| {
+                let path = self.hash_to_path(hash);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let temp_path = path.with_extension("tmp");
+                fs::write(&temp_path, content)?;
+                Ok((temp_path, path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        sync_all_in(
+            &self.objects_dir,
+            staged.iter().map(|(temp_path, _)| temp_path.as_path()),
+        )?;
+        fail_point!("cas::commit");
+        for (temp_path, path) in &staged {
+            fs::rename(temp_path, path)?;
+        }
+
+        debug!(
+            "Stored {} new objects in CAS ({} requested)",
+            staged.len(),
+            contents.len()
+        );
+        Ok(hashes)
+    }
+
+    /// Write content read from `reader` to a temporary object, hashing it on the way
+    ///
+    /// Content up to the buffer limit is hashed in memory and only written
+    /// when the CAS lacks it; anything larger spills to a temporary file, so
+    /// the content never has to fit in memory. Returns its hash and size,
+    /// along with the object to pass to [`commit_staged`](Self::commit_staged),
+    /// or None when the CAS already holds the content.
+    pub fn stage_reader(
+        &self,
+        reader: &mut dyn Read,
+    ) -> Result<(String, u64, Option<StagedObject>)> {
+        let (hash, size, incoming) = self.read_incoming(reader)?;
+
+        let path = self.hash_to_path(&hash);
+        if path.exists() {
+            return Ok((hash, size, None));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temp = match incoming {
+            Incoming::Spilled(temp) => temp,
+            Incoming::Memory(content) => {
+                let mut temp = self.incoming_file()?;
+                temp.write_all(&content)?;
+                temp
+            }
+        };
+        Ok((
+            hash,
+            size,
+            Some(StagedObject {
+                temp: temp.into_temp_path(),
+                path,
+            }),
+        ))
+    }
+
+    /// Read and hash content, spilling to a temporary file past the buffer limit
+    fn read_incoming(&self, reader: &mut dyn Read) -> Result<(String, u64, Incoming)> {
+        let mut hasher = Sha256::new();
+        let mut buffered = Vec::new();
+        reader
+            .take(self.buffer_limit as u64 + 1)
+            .read_to_end(&mut buffered)?;
+        hasher.update(&buffered);
+
+        if buffered.len() <= self.buffer_limit {
+            let size = buffered.len() as u64;
+            return Ok((
+                format!("{:x}", hasher.finalize()),
+                size,
+                Incoming::Memory(buffered),
+            ));
+        }
+
+        let mut temp = self.incoming_file()?;
+        temp.write_all(&buffered)?;
+        let rest = copy_hashing_into(&mut hasher, reader, temp.as_file_mut())?;
+        let size = buffered.len() as u64 + rest;
+        Ok((
+            format!("{:x}", hasher.finalize()),
+            size,
+            Incoming::Spilled(temp),
+        ))
+    }
+
+    /// Create a temporary file for incoming content next to the objects
+    fn incoming_file(&self) -> Result<tempfile::NamedTempFile> {
+        Ok(tempfile::Builder::new()
+            .prefix(".incoming-")
+            .tempfile_in(&self.objects_dir)?)
+    }
+
+    /// Make staged objects durable with one filesystem sync and move them into place
+    pub fn commit_staged(&self, staged: Vec<StagedObject>) -> Result<()> {
+        sync_all_in(&self.objects_dir, staged.iter().map(|object| &*object.temp))?;
+        fail_point!("cas::commit");
+        let count = staged.len();
+        for object in staged {
+            object.temp.persist(&object.path).map_err(|e| e.error)?;
+        }
+
+        debug!("Committed {} staged objects to CAS", count);
+        Ok(())
+    }
+
     /// Retrieve file content from CAS by hash
     pub fn retrieve(&self, hash: &str) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.retrieve_into(hash, &mut content)?;
+        Ok(content)
+    }
+
+    /// Copy file content from CAS into `writer` without holding it in memory
+    ///
+    /// The hash is verified once everything is written; on a mismatch the
+    /// caller must discard what it received. Returns the content size.
+    pub fn retrieve_into(&self, hash: &str, writer: &mut dyn Write) -> Result<u64> {
         let path = self.hash_to_path(hash);
 
         if !path.exists() {
@@ -80,23 +309,35 @@ impl CasStore {
         }
 
         let mut file = fs::File::open(&path)?;
-        let mut content = Vec::new();
-        file.read_to_end(&mut content)?;
+        let (computed_hash, size) = copy_hashing(&mut file, writer)?;
 
         // Verify hash
-        let computed_hash = Self::compute_hash(&content);
         if computed_hash != hash {
             return Err(crate::Error::Io(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!(
-                    "Hash mismatch: expected {}, got {}",
-                    hash, computed_hash
-                ),
+                format!("Hash mismatch: expected {}, got {}", hash, computed_hash),
             )));
         }
 
-        debug!("Retrieved content from CAS: {} ({} bytes)", hash, content.len());
-        Ok(content)
+        debug!("Retrieved content from CAS: {} ({} bytes)", hash, size);
+        Ok(size)
+    }
+
+    /// Copy file content from CAS into `writer` without verifying its hash
+    ///
+    /// Only for content hashed on its way into the CAS by the same caller,
+    /// such as objects an install has just staged; anything else should go
+    /// through [`retrieve_into`](Self::retrieve_into). Returns the content size.
+    pub fn copy_into(&self, hash: &str, writer: &mut dyn Write) -> Result<u64> {
+        let path = self.hash_to_path(hash);
+        let mut file = fs::File::open(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => crate::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Content not found in CAS: {}", hash),
+            )),
+            _ => crate::Error::Io(e),
+        })?;
+        Ok(std::io::copy(&mut file, writer)?)
     }
 
     /// Check if content with given hash exists in CAS
@@ -104,6 +345,28 @@ impl CasStore {
         self.hash_to_path(hash).exists()
     }
 
+    /// Size on disk of a stored object, or None if it is not stored
+    pub fn object_size(&self, hash: &str) -> Option<u64> {
+        fs::metadata(self.hash_to_path(hash))
+            .ok()
+            .map(|meta| meta.len())
+    }
+
+    /// Delete a stored object
+    ///
+    /// Returns the bytes freed; an object that is already gone frees none.
+    pub fn remove(&self, hash: &str) -> Result<u64> {
+        let path = self.hash_to_path(hash);
+        let size = match fs::metadata(&path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        fs::remove_file(&path)?;
+        debug!("Removed object from CAS: {} ({} bytes)", hash, size);
+        Ok(size)
+    }
+
     /// Get the filesystem path for a given hash
     ///
     /// Path format: objects/{first2}/{remaining}
@@ -124,18 +387,219 @@ impl CasStore {
         format!("{:x}", hasher.finalize())
     }
 
+    /// Compute SHA-256 hash of content read from `reader`, without storing it
+    pub fn compute_hash_reader(reader: &mut dyn Read) -> Result<String> {
+        let (hash, _) = copy_hashing(reader, &mut std::io::sink())?;
+        Ok(hash)
+    }
+
     /// Get the objects directory path
     pub fn objects_dir(&self) -> &Path {
         &self.objects_dir
     }
+
+    /// List the hashes of all stored objects
+    pub fn list_hashes(&self) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+
+        for prefix in fs::read_dir(&self.objects_dir)? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            let prefix_name = prefix.file_name().to_string_lossy().to_string();
+
+            for object in fs::read_dir(prefix.path())? {
+                let object = object?;
+                let name = object.file_name().to_string_lossy().to_string();
+                // Skip leftovers of interrupted writes
+                if object.file_type()?.is_file() && !name.ends_with(".tmp") {
+                    hashes.push(format!("{}{}", prefix_name, name));
+                }
+            }
+        }
+
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    /// Copy every object into another store, verifying each one
+    ///
+    /// Objects are read back through `retrieve`, so corrupt objects fail the
+    /// copy instead of being carried over. Returns the number of objects copied.
+    pub fn copy_to(&self, dest: &CasStore) -> Result<usize> {
+        let hashes = self.list_hashes()?;
+
+        for hash in &hashes {
+            let content = self.retrieve(hash)?;
+            let stored = dest.store(&content)?;
+            if stored != *hash || dest.retrieve(hash)?.len() != content.len() {
+                return Err(Error::ChecksumMismatch {
+                    expected: hash.clone(),
+                    actual: stored,
+                });
+            }
+        }
+
+        info!(
+            "Copied {} objects from {:?} to {:?}",
+            hashes.len(),
+            self.objects_dir,
+            dest.objects_dir
+        );
+        Ok(hashes.len())
+    }
+}
+
+/// Flush freshly written files under `dir` to disk
+///
+/// On Linux this is a single syncfs of the filesystem holding `dir`;
+/// elsewhere each file is synced in turn.
+fn sync_all_in<'a>(dir: &Path, files: impl Iterator<Item = &'a Path>) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let _ = files;
+        let dir = fs::File::open(dir)?;
+        // SAFETY: syncfs only inspects the descriptor, which `dir` keeps open
+        if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = dir;
+        for file in files {
+            fs::File::open(file)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Files of a [`FileDeployer::deploy_files`] batch on their way into place
+#[derive(Default)]
+struct DeployBatch {
+    /// Directories missing before the batch, created for its files
+    created_dirs: std::collections::BTreeSet<PathBuf>,
+    /// Temporary path, destination and bytes verified of each staged file
+    staged: Vec<(PathBuf, PathBuf, u64)>,
+    /// Where the file each rename replaced was linked to, in rename order
+    renamed: Vec<Option<PathBuf>>,
+}
+
+impl DeployBatch {
+    /// Put the root back as it was before the batch
+    ///
+    /// Renamed files are undone last to first, restoring what they
+    /// replaced; files not renamed yet are discarded. Failures are only
+    /// logged, so as much as possible is put back.
+    fn undo(&self) {
+        for (temp_path, _, _) in &self.staged[self.renamed.len()..] {
+            let _ = fs::remove_file(temp_path);
+        }
+        for ((_, target_path, _), backup) in self.staged.iter().zip(&self.renamed).rev() {
+            let undone = match backup {
+                Some(backup) => fs::rename(backup, target_path),
+                None => fs::remove_file(target_path),
+            };
+            if let Err(e) = undone {
+                warn!("Could not put back {}: {}", target_path.display(), e);
+            }
+        }
+        // Deepest first, so each directory is empty by the time it is removed
+        for dir in self.created_dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
 }
 
 /// File deployment manager
+///
+/// Every path it writes, moves or removes goes through
+/// [`resolve_under_root`], so a package cannot reach outside the install
+/// root with `..` or through a symlink.
 pub struct FileDeployer {
     /// CAS store for file contents
     cas: CasStore,
     /// Install root directory (e.g., "/" or "/tmp/conary-root")
     install_root: PathBuf,
+    /// Set once the "not running as root" warning has been shown
+    ownership_warned: AtomicBool,
+    /// Set once a warning about skipped extended attributes has been shown
+    xattrs_warned: AtomicBool,
+}
+
+/// Namespace of extended attributes any file owner may set
+const USER_XATTR_PREFIX: &str = "user.";
+
+/// Look up a user or group id by name in a passwd-style file
+///
+/// Numeric names are taken as ids directly and `root` is always 0, so
+/// lookups work before the install root has an /etc/passwd.
+fn lookup_id(db_file: &Path, name: &str) -> Option<u32> {
+    if name == "root" {
+        return Some(0);
+    }
+    if let Ok(id) = name.parse() {
+        return Some(id);
+    }
+
+    let content = fs::read_to_string(db_file).ok()?;
+    content.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+/// Remove `dir` bottom-up; fails at the first entry that is not a directory
+fn remove_empty_tree(dir: &Path) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_tree(&entry.path())?;
+        }
+    }
+    fs::remove_dir(dir)
+}
+
+/// A path next to `path`, its file name followed by `suffix`
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Move a staged file of a batch to its destination
+fn rename_staged(temp_path: &Path, target_path: &Path, path: &str) -> Result<()> {
+    fail_point!("deploy::rename", |message| deploying(path)(
+        std::io::Error::other(message)
+    ));
+    fs::rename(temp_path, target_path).map_err(deploying(path))
+}
+
+/// Attribute an I/O failure writing `path` into the install root to it
+fn deploying(path: &str) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    move |source| Error::Deployment {
+        path: path.to_string(),
+        source,
+    }
+}
+
+/// Whether the process can change file ownership
+fn running_as_root() -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: geteuid has no preconditions and cannot fail
+        unsafe { libc::geteuid() == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
 }
 
 impl FileDeployer {
@@ -150,7 +614,18 @@ impl FileDeployer {
             debug!("Created install root: {:?}", install_root);
         }
 
-        Ok(Self { cas, install_root })
+        Ok(Self {
+            cas,
+            install_root,
+            ownership_warned: AtomicBool::new(false),
+            xattrs_warned: AtomicBool::new(false),
+        })
+    }
+
+    /// Limit the memory its CAS uses per staged file (see [`CasStore::with_buffer_limit`])
+    pub fn with_buffer_limit(mut self, bytes: usize) -> Self {
+        self.cas = self.cas.with_buffer_limit(bytes);
+        self
     }
 
     /// Deploy a file from CAS to the filesystem
@@ -158,55 +633,477 @@ impl FileDeployer {
     /// - Retrieves content from CAS by hash
     /// - Writes to install_root + path
     /// - Sets permissions (ownership requires root)
-    pub fn deploy_file(
+    pub fn deploy_file(&self, path: &str, hash: &str, permissions: u32) -> Result<()> {
+        let (temp_path, target_path, _) = self.stage_file(path, hash, permissions, true)?;
+        fs::File::open(&temp_path)
+            .and_then(|file| file.sync_all())
+            .map_err(deploying(path))?;
+
+        // Atomic rename
+        fs::rename(&temp_path, &target_path).map_err(deploying(path))?;
+
+        info!(
+            "Deployed file: {} (hash: {}, mode: {:o})",
+            path, hash, permissions
+        );
+        Ok(())
+    }
+
+    /// Deploy many regular files from CAS, given as (path, hash, permissions, verify)
+    ///
+    /// Like [`deploy_file`](Self::deploy_file), but in two phases: every
+    /// file is first written in parallel to a temporary name next to its
+    /// destination and the batch synced to disk, then each is renamed into
+    /// place. A file being replaced is hard-linked aside first, so if any
+    /// rename fails those already done are undone, the directories created
+    /// for the batch are removed and the root is left as it was. Content is
+    /// checked against its hash only for files with `verify` set; an object
+    /// the caller hashed on its way into the CAS need not be read twice.
+    /// Returns the bytes hashed to verify content.
+    pub fn deploy_files(&self, files: &[(&str, &str, u32, bool)]) -> Result<u64> {
+        use rayon::prelude::*;
+
+        let mut batch = DeployBatch::default();
+        for (path, _, _, _) in files {
+            let mut dir = under_root(&self.install_root, path)
+                .parent()
+                .map(Path::to_path_buf);
+            while let Some(missing) =
+                dir.filter(|dir| *dir != self.install_root && fs::symlink_metadata(dir).is_err())
+            {
+                dir = missing.parent().map(Path::to_path_buf);
+                if !batch.created_dirs.insert(missing) {
+                    break;
+                }
+            }
+        }
+
+        let results: Vec<_> = files
+            .par_iter()
+            .map(|&(path, hash, permissions, verify)| {
+                self.stage_file(path, hash, permissions, verify)
+            })
+            .collect();
+        let mut failed = None;
+        for result in results {
+            match result {
+                Ok((temp_path, target_path, verified)) => {
+                    batch.staged.push((temp_path, target_path, verified))
+                }
+                Err(e) => {
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        let synced = match failed {
+            Some(e) => Err(e),
+            None => sync_all_in(
+                &self.install_root,
+                batch
+                    .staged
+                    .iter()
+                    .map(|(temp_path, _, _)| temp_path.as_path()),
+            ),
+        };
+        if let Err(e) = synced {
+            batch.undo();
+            return Err(e);
+        }
+
+        for ((temp_path, target_path, _), (path, _, _, _)) in batch.staged.iter().zip(files) {
+            match self.replace_file(temp_path, target_path, path) {
+                Ok(backup) => batch.renamed.push(backup),
+                Err(e) => {
+                    batch.undo();
+                    return Err(e);
+                }
+            }
+        }
+        for backup in batch.renamed.iter().flatten() {
+            if let Err(e) = fs::remove_file(backup) {
+                warn!("Could not remove {}: {}", backup.display(), e);
+            }
+        }
+
+        info!("Deployed {} files", batch.staged.len());
+        Ok(batch.staged.iter().map(|(_, _, verified)| verified).sum())
+    }
+
+    /// Rename a staged file over its destination, keeping what it replaces
+    ///
+    /// Anything but a directory already at the destination is hard-linked
+    /// to a backup name first, which is returned so the caller can put it
+    /// back or drop it.
+    fn replace_file(
+        &self,
+        temp_path: &Path,
+        target_path: &Path,
+        path: &str,
+    ) -> Result<Option<PathBuf>> {
+        let backup = match fs::symlink_metadata(target_path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                let backup = sibling_path(target_path, BACKUP_SUFFIX);
+                if fs::symlink_metadata(&backup).is_ok() {
+                    fs::remove_file(&backup).map_err(deploying(path))?;
+                }
+                fs::hard_link(target_path, &backup).map_err(deploying(path))?;
+                Some(backup)
+            }
+            _ => None,
+        };
+
+        if let Err(e) = rename_staged(temp_path, target_path, path) {
+            if let Some(backup) = &backup {
+                let _ = fs::remove_file(backup);
+            }
+            return Err(e);
+        }
+        Ok(backup)
+    }
+
+    /// Write a file's content next to its destination, not yet synced
+    ///
+    /// The temporary name carries the content hash, so files of one batch
+    /// never share one. Returns the temporary path, the destination and the
+    /// bytes hashed to verify the content, which is none unless `verify` is
+    /// set.
+    fn stage_file(
         &self,
         path: &str,
         hash: &str,
         permissions: u32,
-    ) -> Result<()> {
-        // Retrieve content from CAS
-        let content = self.cas.retrieve(hash)?;
-
+        verify: bool,
+    ) -> Result<(PathBuf, PathBuf, u64)> {
         // Compute target path
-        let target_path = self.install_root.join(path.trim_start_matches('/'));
+        let target_path = resolve_under_root(&self.install_root, path)?;
 
         // Create parent directories
         if let Some(parent) = target_path.parent() {
-            fs::create_dir_all(parent)?;
+            fs::create_dir_all(parent).map_err(deploying(path))?;
         }
 
-        // Write atomically
-        let temp_path = target_path.with_extension("conary-tmp");
-        let mut file = fs::File::create(&temp_path)?;
-        file.write_all(&content)?;
-        file.sync_all()?;
+        // Copy content from CAS
+        let temp_path = sibling_path(
+            &target_path,
+            &format!(".{}.conary-tmp", &hash[..hash.len().min(16)]),
+        );
+        let mut file =
+            std::io::BufWriter::new(fs::File::create(&temp_path).map_err(deploying(path))?);
+        let copied = match verify {
+            true => self.cas.retrieve_into(hash, &mut file),
+            false => self.cas.copy_into(hash, &mut file),
+        };
+        let verified = match copied.and_then(|size| Ok(file.flush().map(|()| size)?)) {
+            Ok(size) if verify => size,
+            Ok(_) => 0,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
 
         // Set permissions
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
             let perms = fs::Permissions::from_mode(permissions);
-            fs::set_permissions(&temp_path, perms)?;
+            fs::set_permissions(&temp_path, perms).map_err(deploying(path))?;
         }
 
-        // Atomic rename
-        fs::rename(&temp_path, &target_path)?;
+        Ok((temp_path, target_path, verified))
+    }
+
+    /// Create a symlink at the target path (the link target is not resolved)
+    pub fn deploy_symlink(&self, path: &str, link_target: &str) -> Result<()> {
+        let target_path = resolve_under_root(&self.install_root, path)?;
+
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).map_err(deploying(path))?;
+        }
+
+        // Create next to the destination and rename over it, like regular files
+        let temp_path = target_path.with_extension("conary-tmp");
+        if fs::symlink_metadata(&temp_path).is_ok() {
+            fs::remove_file(&temp_path).map_err(deploying(path))?;
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(decode_path(link_target), &temp_path)
+            .map_err(deploying(path))?;
+        #[cfg(not(unix))]
+        return Err(Error::IoError(format!(
+            "Symlinks are not supported on this platform: {}",
+            path
+        )));
+
+        fs::rename(&temp_path, &target_path).map_err(deploying(path))?;
+
+        info!("Deployed symlink: {} -> {}", path, link_target);
+        Ok(())
+    }
+
+    /// Create a hardlink at the target path to another path in the install root
+    pub fn deploy_hardlink(&self, path: &str, link_target: &str) -> Result<()> {
+        let target_path = resolve_under_root(&self.install_root, path)?;
+        let source_path = resolve_under_root(&self.install_root, link_target)?;
+
+        if !source_path.exists() {
+            return Err(Error::IoError(format!(
+                "Cannot hardlink {} to missing file {}",
+                path, link_target
+            )));
+        }
+
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).map_err(deploying(path))?;
+        }
+
+        let temp_path = target_path.with_extension("conary-tmp");
+        if fs::symlink_metadata(&temp_path).is_ok() {
+            fs::remove_file(&temp_path).map_err(deploying(path))?;
+        }
+        fs::hard_link(&source_path, &temp_path).map_err(deploying(path))?;
+        fs::rename(&temp_path, &target_path).map_err(deploying(path))?;
 
-        info!("Deployed file: {} (hash: {}, mode: {:o})", path, hash, permissions);
+        info!("Deployed hardlink: {} => {}", path, link_target);
+        Ok(())
+    }
+
+    /// Create a directory a package owns, or give an existing one its mode
+    ///
+    /// A symlink already at the path, such as merged-usr's `/lib`, is left
+    /// alone, as is the directory it points to.
+    pub fn deploy_dir(&self, path: &str, permissions: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let target_path = resolve_under_root(&self.install_root, path)?;
+        match fs::symlink_metadata(&target_path) {
+            Ok(metadata) if metadata.is_symlink() => {
+                debug!("Leaving symlink {} in place of its directory", path);
+                return Ok(());
+            }
+            Ok(metadata) if !metadata.is_dir() => {
+                return Err(Error::IoError(format!(
+                    "Cannot create directory {}: a file is in the way",
+                    path
+                )));
+            }
+            _ => fs::create_dir_all(&target_path).map_err(deploying(path))?,
+        }
+        fs::set_permissions(
+            &target_path,
+            fs::Permissions::from_mode(permissions & 0o7777),
+        )
+        .map_err(deploying(path))?;
+
+        debug!(
+            "Deployed directory: {} ({:04o})",
+            path,
+            permissions & 0o7777
+        );
+        Ok(())
+    }
+
+    /// Mode of the directory at `path`, or None if there is no directory there
+    ///
+    /// A symlink in its place is not followed.
+    pub fn dir_mode(&self, path: &str) -> Option<u32> {
+        use std::os::unix::fs::MetadataExt;
+
+        fs::symlink_metadata(under_root(&self.install_root, path))
+            .ok()
+            .filter(|metadata| metadata.is_dir())
+            .map(|metadata| metadata.mode() & 0o7777)
+    }
+
+    /// Deploy a file according to its type
+    ///
+    /// Regular files come from the CAS; symlinks and hardlinks are created
+    /// from `link_target`. Hardlinks must be deployed after the file they
+    /// point to. Directories are created empty and `hash` is not used.
+    pub fn deploy(
+        &self,
+        path: &str,
+        hash: &str,
+        permissions: u32,
+        file_type: FileType,
+        link_target: Option<&str>,
+    ) -> Result<()> {
+        match (file_type, link_target) {
+            (FileType::Regular, _) => self.deploy_file(path, hash, permissions),
+            (FileType::Symlink, Some(target)) => self.deploy_symlink(path, target),
+            (FileType::Hardlink, Some(target)) => self.deploy_hardlink(path, target),
+            (FileType::Directory, _) => self.deploy_dir(path, permissions),
+            (_, None) => Err(Error::IoError(format!(
+                "Cannot deploy {} {} without a link target",
+                file_type.as_str(),
+                path
+            ))),
+        }
+    }
+
+    /// Deploy a tracked file according to its type, with its recorded ownership
+    pub fn deploy_entry(&self, file: &FileEntry) -> Result<()> {
+        self.deploy(
+            &file.path,
+            file.sha256_hash.as_deref().unwrap_or_default(),
+            file.permissions as u32,
+            file.file_type,
+            file.link_target.as_deref(),
+        )?;
+        self.apply_ownership(
+            &file.path,
+            file.permissions as u32,
+            file.file_type,
+            file.owner.as_deref(),
+            file.group_name.as_deref(),
+        )?;
+        self.apply_xattrs(&file.path, file.file_type, &file.xattrs)
+    }
+
+    /// Resolve a user name against the install root's /etc/passwd
+    fn resolve_user(&self, name: &str) -> Option<u32> {
+        lookup_id(&self.install_root.join("etc/passwd"), name)
+    }
+
+    /// Resolve a group name against the install root's /etc/group
+    fn resolve_group(&self, name: &str) -> Option<u32> {
+        lookup_id(&self.install_root.join("etc/group"), name)
+    }
+
+    /// Apply the owner and group from package metadata to a deployed file
+    ///
+    /// Changing ownership needs root; otherwise this is skipped with a
+    /// warning (shown once per deployer). Regular files get their mode
+    /// re-applied afterwards because chown clears setuid/setgid bits.
+    /// Symlinks are changed themselves, not their targets.
+    pub fn apply_ownership(
+        &self,
+        path: &str,
+        permissions: u32,
+        file_type: FileType,
+        owner: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<()> {
+        if owner.is_none() && group.is_none() {
+            return Ok(());
+        }
+
+        if !running_as_root() {
+            if !self.ownership_warned.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Not running as root, file ownership from package metadata will not be applied"
+                );
+            }
+            return Ok(());
+        }
+
+        let uid = owner.and_then(|name| {
+            let uid = self.resolve_user(name);
+            if uid.is_none() {
+                warn!(
+                    "Unknown user '{}' for {}, leaving owner unchanged",
+                    name, path
+                );
+            }
+            uid
+        });
+        let gid = group.and_then(|name| {
+            let gid = self.resolve_group(name);
+            if gid.is_none() {
+                warn!(
+                    "Unknown group '{}' for {}, leaving group unchanged",
+                    name, path
+                );
+            }
+            gid
+        });
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let target_path = resolve_under_root(&self.install_root, path)?;
+            std::os::unix::fs::lchown(&target_path, uid, gid)?;
+            if file_type == FileType::Regular {
+                fs::set_permissions(&target_path, fs::Permissions::from_mode(permissions))?;
+            }
+            debug!("Set ownership of {} to {:?}:{:?}", path, uid, gid);
+        }
+
+        Ok(())
+    }
+
+    /// Whether this process can set the extended attribute `name`
+    fn can_set_xattr(name: &str) -> bool {
+        name.starts_with(USER_XATTR_PREFIX) || running_as_root()
+    }
+
+    /// Set the extended attributes from package metadata on a deployed file
+    ///
+    /// Attributes outside the `user.` namespace, file capabilities among
+    /// them, need root; otherwise they are skipped with a warning, as are
+    /// all attributes on a filesystem without xattr support (one warning
+    /// per deployer). Must run after [`apply_ownership`](Self::apply_ownership),
+    /// because chown clears file capabilities. Symlinks cannot carry them.
+    pub fn apply_xattrs(&self, path: &str, file_type: FileType, xattrs: &Xattrs) -> Result<()> {
+        if xattrs.is_empty() || file_type == FileType::Symlink {
+            return Ok(());
+        }
+
+        let target_path = resolve_under_root(&self.install_root, path)?;
+        for (name, value) in xattrs {
+            if !Self::can_set_xattr(name) {
+                if !self.xattrs_warned.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "Not running as root, file capabilities and other system extended attributes will not be applied"
+                    );
+                }
+                continue;
+            }
+            match xattr::set(&target_path, name, value) {
+                Ok(()) => debug!("Set extended attribute {} on {}", name, path),
+                Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+                    if !self.xattrs_warned.swap(true, Ordering::Relaxed) {
+                        warn!(
+                            "The filesystem of {} does not support extended attributes, they will not be applied",
+                            path
+                        );
+                    }
+                    return Ok(());
+                }
+                Err(source) => {
+                    return Err(Error::Deployment {
+                        path: path.to_string(),
+                        source,
+                    });
+                }
+            }
+        }
         Ok(())
     }
 
     /// Check if a file exists at the target path
+    ///
+    /// Symlinks count as present even when their target does not exist.
     pub fn file_exists(&self, path: &str) -> bool {
-        let target_path = self.install_root.join(path.trim_start_matches('/'));
-        target_path.exists()
+        let target_path = under_root(&self.install_root, path);
+        fs::symlink_metadata(target_path).is_ok()
+    }
+
+    /// Check if a regular file, not a link or directory, is at the target path
+    pub fn is_regular_file(&self, path: &str) -> bool {
+        let target_path = under_root(&self.install_root, path);
+        fs::symlink_metadata(target_path).is_ok_and(|metadata| metadata.is_file())
     }
 
     /// Remove a file from the filesystem
     pub fn remove_file(&self, path: &str) -> Result<()> {
-        let target_path = self.install_root.join(path.trim_start_matches('/'));
+        let target_path = resolve_under_root(&self.install_root, path)?;
 
-        if target_path.exists() {
+        if fs::symlink_metadata(&target_path).is_ok() {
             fs::remove_file(&target_path)?;
             info!("Removed file: {}", path);
         } else {
@@ -216,26 +1113,198 @@ impl FileDeployer {
         Ok(())
     }
 
+    /// Remove a directory and the directories below it, none of which may hold anything else
+    pub fn remove_empty_tree(&self, path: &str) -> Result<()> {
+        remove_empty_tree(&resolve_under_root(&self.install_root, path)?)?;
+        info!("Removed directory: {}", path);
+        Ok(())
+    }
+
+    /// Remove a directory if it is empty, returning whether it was removed
+    ///
+    /// Anything but a directory at the path is left alone.
+    pub fn remove_empty_dir(&self, path: &str) -> Result<bool> {
+        let target_path = resolve_under_root(&self.install_root, path)?;
+        if !fs::symlink_metadata(&target_path).is_ok_and(|metadata| metadata.is_dir()) {
+            return Ok(false);
+        }
+        match fs::remove_dir(&target_path) {
+            Ok(()) => {
+                info!("Removed directory: {}", path);
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Create a directory and any missing parents
+    pub fn create_dir(&self, path: &str) -> Result<()> {
+        fs::create_dir_all(resolve_under_root(&self.install_root, path)?)?;
+        debug!("Created directory: {}", path);
+        Ok(())
+    }
+
+    /// Move whatever is at `from` to `to`, which must not exist yet
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let target_path = resolve_under_root(&self.install_root, to)?;
+        if fs::symlink_metadata(&target_path).is_ok() {
+            return Err(Error::IoError(format!(
+                "Cannot move {} to {}: it already exists",
+                from, to
+            )));
+        }
+        fs::rename(resolve_under_root(&self.install_root, from)?, target_path)?;
+        info!("Moved {} to {}", from, to);
+        Ok(())
+    }
+
     /// Verify a file's hash matches expected
+    ///
+    /// For a symlink on disk, the hash of its target string is compared, which
+    /// is how symlinks are recorded in the database.
     pub fn verify_file(&self, path: &str, expected_hash: &str) -> Result<bool> {
-        let target_path = self.install_root.join(path.trim_start_matches('/'));
+        let target_path = under_root(&self.install_root, path);
 
-        if !target_path.exists() {
+        let Ok(metadata) = fs::symlink_metadata(&target_path) else {
             return Ok(false);
+        };
+
+        if metadata.file_type().is_symlink() {
+            let link_target = fs::read_link(&target_path)?;
+            let actual_hash = CasStore::compute_hash(encode_path(&link_target).as_bytes());
+            return Ok(actual_hash == expected_hash);
         }
 
         let mut file = fs::File::open(&target_path)?;
-        let mut content = Vec::new();
-        file.read_to_end(&mut content)?;
-
-        let actual_hash = CasStore::compute_hash(&content);
+        let (actual_hash, _) = copy_hashing(&mut file, &mut std::io::sink())?;
         Ok(actual_hash == expected_hash)
     }
 
+    /// Verify a tracked file against its recorded hash
+    ///
+    /// A directory has no content, so its mode stands in for the hash. A
+    /// symlink in its place, like merged-usr's `/lib`, is left alone by the
+    /// deployer and passes.
+    pub fn verify_entry(&self, file: &FileEntry) -> Result<bool> {
+        use std::os::unix::fs::MetadataExt;
+
+        match (file.file_type, &file.sha256_hash) {
+            (FileType::Directory, _) => Ok(fs::symlink_metadata(under_root(
+                &self.install_root,
+                &file.path,
+            ))
+            .is_ok_and(|metadata| {
+                metadata.is_symlink()
+                    || (metadata.is_dir()
+                        && metadata.mode() & 0o7777 == file.permissions as u32 & 0o7777)
+            })),
+            (_, Some(hash)) => self.verify_file(&file.path, hash),
+            (_, None) => Ok(false),
+        }
+    }
+
+    /// Whether a tracked file can be deployed again from the CAS
+    ///
+    /// Directories need no content.
+    pub fn can_redeploy(&self, file: &FileEntry) -> bool {
+        file.sha256_hash
+            .as_deref()
+            .is_none_or(|hash| self.cas.exists(hash))
+    }
+
+    /// Whether a config file on disk has local edits an upgrade should keep
+    ///
+    /// True when the file exists, is not already the new version, and does
+    /// not match the previously installed version. With no previous version
+    /// recorded, any differing file on disk counts as a local edit.
+    pub fn config_modified(
+        &self,
+        path: &str,
+        old_hash: Option<&str>,
+        new_hash: &str,
+    ) -> Result<bool> {
+        if !self.file_exists(path) || self.verify_file(path, new_hash)? {
+            return Ok(false);
+        }
+
+        match old_hash {
+            Some(old_hash) => Ok(!self.verify_file(path, old_hash)?),
+            None => Ok(true),
+        }
+    }
+
+    /// Store the current on-disk content of a file in the CAS
+    ///
+    /// Used to keep a copy of files that are about to be kept or replaced.
+    /// Returns the content hash and size.
+    pub fn store_existing(&self, path: &str) -> Result<(String, i64)> {
+        let target_path = under_root(&self.install_root, path);
+        let content = fs::read(&target_path)?;
+        Ok((self.cas.store(&content)?, content.len() as i64))
+    }
+
+    /// Compare a file's mode, owner, group and extended attributes against its recorded metadata
+    ///
+    /// Returns a description of each attribute that differs. Owners and
+    /// groups that cannot be resolved in the install root are not compared,
+    /// and neither are symlink modes. Extended attributes the deployer would
+    /// have skipped are not compared either.
+    #[cfg(unix)]
+    pub fn verify_attributes(&self, file: &FileEntry) -> Result<Vec<String>> {
+        use std::os::unix::fs::MetadataExt;
+
+        let target_path = under_root(&self.install_root, &file.path);
+        let metadata = fs::symlink_metadata(&target_path)?;
+        let mut drift = Vec::new();
+
+        if !metadata.file_type().is_symlink() {
+            let expected = file.permissions as u32 & 0o7777;
+            let actual = metadata.mode() & 0o7777;
+            if actual != expected {
+                drift.push(format!("mode {:04o} (expected {:04o})", actual, expected));
+            }
+        }
+
+        if let Some(owner) = &file.owner
+            && let Some(uid) = self.resolve_user(owner)
+            && metadata.uid() != uid
+        {
+            drift.push(format!("owner {} (expected {})", metadata.uid(), owner));
+        }
+
+        if let Some(group) = &file.group_name
+            && let Some(gid) = self.resolve_group(group)
+            && metadata.gid() != gid
+        {
+            drift.push(format!("group {} (expected {})", metadata.gid(), group));
+        }
+
+        // Attributes this process could not have set, or cannot read here, are not compared
+        for (name, expected) in &file.xattrs {
+            if !Self::can_set_xattr(name) || metadata.file_type().is_symlink() {
+                continue;
+            }
+            match xattr::get(&target_path, name) {
+                Ok(Some(actual)) if actual == *expected => {}
+                Ok(Some(_)) => drift.push(format!("xattr {} changed", name)),
+                Ok(None) => drift.push(format!("xattr {} missing", name)),
+                Err(_) => {}
+            }
+        }
+
+        Ok(drift)
+    }
+
     /// Get CAS store
     pub fn cas(&self) -> &CasStore {
         &self.cas
     }
+
+    /// Get the install root files are deployed under
+    pub fn install_root(&self) -> &Path {
+        &self.install_root
+    }
 }
 
 #[cfg(test)]
@@ -282,6 +1351,210 @@ mod tests {
         assert!(cas.exists(&hash1));
     }
 
+    #[test]
+    fn test_store_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let cas = CasStore::new(temp_dir.path()).unwrap();
+        let existing = cas.store(b"already stored").unwrap();
+
+        let contents: [&[u8]; 4] = [b"one", b"two", b"one", b"already stored"];
+        let hashes = cas.store_batch(&contents).unwrap();
+
+        assert_eq!(hashes.len(), 4);
+        assert_eq!(hashes[0], hashes[2]);
+        assert_eq!(hashes[3], existing);
+        for (hash, content) in hashes.iter().zip(contents) {
+            assert_eq!(cas.retrieve(hash).unwrap(), content);
+        }
+        assert_eq!(cas.list_hashes().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_stage_reader_and_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let cas = CasStore::new(temp_dir.path()).unwrap();
+        let existing = cas.store(b"already stored").unwrap();
+
+        let (hash, size, staged) = cas.stage_reader(&mut &b"streamed content"[..]).unwrap();
+        assert_eq!(hash, CasStore::compute_hash(b"streamed content"));
+        assert_eq!(size, 16);
+        assert!(
+            !cas.exists(&hash),
+            "staged objects are not visible before commit"
+        );
+
+        // Content already in the CAS is not staged again
+        let (again, _, none) = cas.stage_reader(&mut &b"already stored"[..]).unwrap();
+        assert_eq!(again, existing);
+        assert!(none.is_none());
+
+        // Dropping a staged object discards it
+        let (dropped, _, discarded) = cas.stage_reader(&mut &b"discarded"[..]).unwrap();
+        drop(discarded);
+
+        cas.commit_staged(staged.into_iter().collect()).unwrap();
+        assert_eq!(cas.retrieve(&hash).unwrap(), b"streamed content");
+        assert!(!cas.exists(&dropped));
+        assert_eq!(cas.list_hashes().unwrap().len(), 2);
+        let leftovers = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".incoming-")
+            })
+            .count();
+        assert_eq!(leftovers, 0, "no temporary files are left behind");
+    }
+
+    #[test]
+    fn test_stage_reader_spills_past_buffer_limit() {
+        let temp_dir = TempDir::new().unwrap();
+        let cas = CasStore::new(temp_dir.path())
+            .unwrap()
+            .with_buffer_limit(1024 * 1024);
+        let large: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let (hash, size, incoming) = cas.read_incoming(&mut large.as_slice()).unwrap();
+        assert_eq!(hash, CasStore::compute_hash(&large));
+        assert_eq!(size, large.len() as u64);
+        assert!(matches!(incoming, Incoming::Spilled(_)));
+
+        // Content at the limit stays in memory
+        let small = &large[..1024 * 1024];
+        let (_, _, incoming) = cas.read_incoming(&mut &small[..]).unwrap();
+        assert!(matches!(incoming, Incoming::Memory(ref content) if content.len() == small.len()));
+
+        let (hash, _, staged) = cas.stage_reader(&mut large.as_slice()).unwrap();
+        cas.commit_staged(staged.into_iter().collect()).unwrap();
+        assert_eq!(cas.retrieve(&hash).unwrap(), large);
+
+        // A buffered cache hit never touches the disk
+        let (small_hash, _, staged) = cas.stage_reader(&mut &small[..]).unwrap();
+        cas.commit_staged(staged.into_iter().collect()).unwrap();
+        let (again, _, none) = cas.stage_reader(&mut &small[..]).unwrap();
+        assert_eq!(again, small_hash);
+        assert!(none.is_none());
+    }
+
+    #[test]
+    fn test_file_deployer_deploy_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let install_root = temp_dir.path().join("root");
+        let deployer = FileDeployer::new(&temp_dir.path().join("objects"), &install_root).unwrap();
+
+        let tool = deployer.cas().store(b"tool").unwrap();
+        let conf = deployer.cas().store(b"conf").unwrap();
+        let verified = deployer
+            .deploy_files(&[
+                ("/usr/bin/tool", &tool, 0o755, false),
+                ("/etc/tool.conf", &conf, 0o644, true),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            fs::read(install_root.join("usr/bin/tool")).unwrap(),
+            b"tool"
+        );
+        assert_eq!(
+            fs::read(install_root.join("etc/tool.conf")).unwrap(),
+            b"conf"
+        );
+        assert!(!install_root.join("usr/bin/tool.conary-tmp").exists());
+        // Only the file asked to be verified was hashed again
+        assert_eq!(verified, 4);
+
+        // A corrupted object is caught when verified, and copied as is otherwise
+        fs::write(deployer.cas().hash_to_path(&conf), b"oops").unwrap();
+        assert!(
+            deployer
+                .deploy_files(&[("/etc/tool.conf", &conf, 0o644, true)])
+                .is_err()
+        );
+        assert_eq!(
+            fs::read(install_root.join("etc/tool.conf")).unwrap(),
+            b"conf"
+        );
+        assert!(!install_root.join("etc/tool.conary-tmp").exists());
+        deployer
+            .deploy_files(&[("/etc/tool.conf", &conf, 0o644, false)])
+            .unwrap();
+        assert_eq!(
+            fs::read(install_root.join("etc/tool.conf")).unwrap(),
+            b"oops"
+        );
+    }
+
+    #[test]
+    fn test_file_deployer_deploy_files_undoes_a_failed_batch() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let install_root = temp_dir.path().join("root");
+        let deployer = FileDeployer::new(&temp_dir.path().join("objects"), &install_root).unwrap();
+        fs::create_dir_all(install_root.join("usr/bin")).unwrap();
+        fs::write(install_root.join("usr/bin/tool"), b"tool v1").unwrap();
+        fs::set_permissions(
+            install_root.join("usr/bin/tool"),
+            fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("tool", install_root.join("usr/bin/alias")).unwrap();
+
+        let tool = deployer.cas().store(b"tool v2").unwrap();
+        let alias = deployer.cas().store(b"alias v2").unwrap();
+        let extra = deployer.cas().store(b"extra").unwrap();
+        let batch = [
+            ("/usr/bin/tool", tool.as_str(), 0o755, false),
+            ("/usr/lib/tool/plugin.so", extra.as_str(), 0o644, false),
+            ("/usr/bin/alias", alias.as_str(), 0o755, false),
+            ("/usr/bin/extra", extra.as_str(), 0o755, false),
+        ];
+
+        // The last rename fails; those done before it are put back and the
+        // directories created for the batch go
+        let failpoint = crate::failpoints::configure("deploy::rename", "3*off->return");
+        assert!(matches!(
+            deployer.deploy_files(&batch),
+            Err(Error::Deployment { .. })
+        ));
+        assert_eq!(
+            fs::read(install_root.join("usr/bin/tool")).unwrap(),
+            b"tool v1"
+        );
+        let mode = fs::metadata(install_root.join("usr/bin/tool"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o750);
+        assert_eq!(
+            fs::read_link(install_root.join("usr/bin/alias")).unwrap(),
+            Path::new("tool")
+        );
+        let mut names: Vec<_> = fs::read_dir(install_root.join("usr/bin"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["alias", "tool"]);
+        assert!(!install_root.join("usr/lib").exists());
+        drop(failpoint);
+
+        // Once the batch is in place the files it replaced are not kept
+        deployer.deploy_files(&batch).unwrap();
+        assert_eq!(
+            fs::read(install_root.join("usr/bin/alias")).unwrap(),
+            b"alias v2"
+        );
+        assert_eq!(
+            fs::read_dir(install_root.join("usr/bin")).unwrap().count(),
+            3
+        );
+    }
+
     #[test]
     fn test_hash_to_path() {
         let temp_dir = TempDir::new().unwrap();
@@ -316,7 +1589,9 @@ mod tests {
         let hash = deployer.cas().store(content).unwrap();
 
         // Deploy file
-        deployer.deploy_file("/usr/bin/test.sh", &hash, 0o755).unwrap();
+        deployer
+            .deploy_file("/usr/bin/test.sh", &hash, 0o755)
+            .unwrap();
 
         // Verify file exists
         assert!(deployer.file_exists("/usr/bin/test.sh"));
@@ -370,7 +1645,9 @@ mod tests {
         // Store and deploy
         let content = b"to be removed";
         let hash = deployer.cas().store(content).unwrap();
-        deployer.deploy_file("/remove_me.txt", &hash, 0o644).unwrap();
+        deployer
+            .deploy_file("/remove_me.txt", &hash, 0o644)
+            .unwrap();
 
         assert!(deployer.file_exists("/remove_me.txt"));
 
@@ -378,4 +1655,138 @@ mod tests {
         deployer.remove_file("/remove_me.txt").unwrap();
         assert!(!deployer.file_exists("/remove_me.txt"));
     }
+
+    #[test]
+    fn test_file_deployer_links() {
+        let temp_dir = TempDir::new().unwrap();
+        let install_root = temp_dir.path().join("root");
+        let objects_dir = temp_dir.path().join("objects");
+
+        let deployer = FileDeployer::new(&objects_dir, &install_root).unwrap();
+
+        let hash = deployer.cas().store(b"real binary").unwrap();
+        deployer.deploy_file("/usr/bin/real", &hash, 0o755).unwrap();
+
+        // Dangling symlinks are deployed as-is and still count as present
+        deployer.deploy_symlink("/usr/bin/alias", "real").unwrap();
+        deployer
+            .deploy_symlink("/usr/bin/dangling", "/nowhere")
+            .unwrap();
+        assert_eq!(
+            fs::read_link(install_root.join("usr/bin/alias")).unwrap(),
+            Path::new("real")
+        );
+        assert!(deployer.file_exists("/usr/bin/dangling"));
+
+        // Symlinks verify against the hash of their target
+        let target_hash = CasStore::compute_hash(b"real");
+        assert!(
+            deployer
+                .verify_file("/usr/bin/alias", &target_hash)
+                .unwrap()
+        );
+        assert!(!deployer.verify_file("/usr/bin/alias", &hash).unwrap());
+
+        deployer
+            .deploy_hardlink("/usr/bin/copy", "/usr/bin/real")
+            .unwrap();
+        assert!(deployer.verify_file("/usr/bin/copy", &hash).unwrap());
+        assert!(
+            deployer
+                .deploy_hardlink("/usr/bin/broken", "/usr/bin/missing")
+                .is_err()
+        );
+
+        deployer.remove_file("/usr/bin/dangling").unwrap();
+        assert!(!deployer.file_exists("/usr/bin/dangling"));
+    }
+
+    #[test]
+    fn test_file_deployer_dir_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let install_root = temp_dir.path().join("root");
+        let deployer = FileDeployer::new(&temp_dir.path().join("objects"), &install_root).unwrap();
+
+        assert_eq!(deployer.dir_mode("/var/lib/foo"), None);
+        deployer.deploy_dir("/var/lib/foo", 0o040750).unwrap();
+        assert_eq!(deployer.dir_mode("/var/lib/foo"), Some(0o750));
+
+        // Files and symlinks are not directories, even one pointing at a directory
+        let hash = deployer.cas().store(b"data").unwrap();
+        deployer
+            .deploy_file("/var/lib/foo/data", &hash, 0o644)
+            .unwrap();
+        deployer.deploy_symlink("/var/lib/bar", "foo").unwrap();
+        assert_eq!(deployer.dir_mode("/var/lib/foo/data"), None);
+        assert_eq!(deployer.dir_mode("/var/lib/bar"), None);
+    }
+
+    #[test]
+    fn test_lookup_id() {
+        let temp_dir = TempDir::new().unwrap();
+        let passwd = temp_dir.path().join("passwd");
+        fs::write(
+            &passwd,
+            "root:x:0:0::/root:/bin/sh\nsvc:x:4242:4242::/:/bin/false\n",
+        )
+        .unwrap();
+
+        assert_eq!(lookup_id(&passwd, "svc"), Some(4242));
+        assert_eq!(lookup_id(&passwd, "1000"), Some(1000));
+        assert_eq!(lookup_id(&passwd, "missing"), None);
+        // root resolves even without a passwd file
+        assert_eq!(lookup_id(&temp_dir.path().join("none"), "root"), Some(0));
+    }
+
+    #[test]
+    fn test_file_deployer_ownership() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let temp_dir = TempDir::new().unwrap();
+        let install_root = temp_dir.path().join("root");
+        let objects_dir = temp_dir.path().join("objects");
+        fs::create_dir_all(install_root.join("etc")).unwrap();
+        fs::write(
+            install_root.join("etc/passwd"),
+            "svc:x:4242:4242::/:/bin/false\n",
+        )
+        .unwrap();
+        fs::write(install_root.join("etc/group"), "svc:x:4243:\n").unwrap();
+
+        let deployer = FileDeployer::new(&objects_dir, &install_root).unwrap();
+        let hash = deployer.cas().store(b"setuid helper").unwrap();
+        deployer
+            .deploy_file("/usr/bin/helper", &hash, 0o4755)
+            .unwrap();
+        deployer
+            .apply_ownership(
+                "/usr/bin/helper",
+                0o4755,
+                FileType::Regular,
+                Some("svc"),
+                Some("svc"),
+            )
+            .unwrap();
+
+        let mut entry = FileEntry::new("/usr/bin/helper".to_string(), hash, 13, 0o4755, 1);
+        entry.owner = Some("svc".to_string());
+        entry.group_name = Some("svc".to_string());
+
+        let target = install_root.join("usr/bin/helper");
+        if running_as_root() {
+            // The setuid bit survives the chown
+            let metadata = fs::metadata(&target).unwrap();
+            assert_eq!(metadata.uid(), 4242);
+            assert_eq!(metadata.gid(), 4243);
+            assert_eq!(metadata.mode() & 0o7777, 0o4755);
+            assert!(deployer.verify_attributes(&entry).unwrap().is_empty());
+        } else {
+            let drift = deployer.verify_attributes(&entry).unwrap();
+            assert!(drift.iter().any(|d| d.starts_with("owner")));
+        }
+
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o755)).unwrap();
+        let drift = deployer.verify_attributes(&entry).unwrap();
+        assert!(drift.contains(&"mode 0755 (expected 4755)".to_string()));
+    }
 }
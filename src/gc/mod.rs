@@ -0,0 +1,252 @@
+// src/gc/mod.rs
+
+//! Garbage collection of CAS objects nothing refers to any more
+//!
+//! Upgrades and removals leave the old content in the CAS. An object is
+//! still needed when:
+//! - an installed file has it (the `files` table)
+//! - a changeset created within the retention window recorded it in
+//!   `file_history`, so rollback of recent changesets keeps working
+//! - a package delta was computed against it
+//!
+//! Everything else is deleted from disk and from `file_contents`, including
+//! objects left on disk without a `file_contents` row. History rows of older
+//! changesets keep their paths and actions but lose the hash of content that
+//! was collected. The window is `gc_retention_days` (30 days by default).
+//!
+//! The database side runs in one transaction and the objects are deleted only
+//! after it commits, so an interrupted run leaves at most unreferenced objects
+//! behind for the next one. Callers hold the installation lock for the whole
+//! run so an install cannot add references while it is in progress.
+
+use crate::db::models::Setting;
+use crate::error::{Error, Result};
+use crate::filesystem::CasStore;
+use rusqlite::Connection;
+use std::collections::{BTreeSet, HashSet};
+use tracing::{debug, info};
+
+/// Setting holding the days of changeset history whose content is kept
+pub const GC_RETENTION_SETTING: &str = "gc_retention_days";
+
+/// Days of history kept when `gc_retention_days` is unset
+pub const DEFAULT_RETENTION_DAYS: u32 = 30;
+
+/// Options controlling a garbage collection run
+#[derive(Debug, Clone, Default)]
+pub struct GcOptions {
+    /// Keep content recorded by changesets created within this many days
+    pub retention_days: u32,
+    /// Report what would be removed without removing anything
+    pub dry_run: bool,
+}
+
+/// Outcome of a garbage collection run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Objects still referenced
+    pub kept: usize,
+    /// Objects deleted (or, on a dry run, that would be)
+    pub removed: usize,
+    /// Bytes of the deleted objects
+    pub bytes_reclaimed: u64,
+}
+
+/// The retention window configured for `conn`
+pub fn retention_days(conn: &Connection) -> Result<u32> {
+    match Setting::get(conn, GC_RETENTION_SETTING)? {
+        Some(value) => parse_retention(&value),
+        None => Ok(DEFAULT_RETENTION_DAYS),
+    }
+}
+
+/// Parse a retention window given in days
+pub fn parse_retention(value: &str) -> Result<u32> {
+    value.trim().parse().map_err(|_| {
+        Error::ParseError(format!(
+            "{} must be a number of days, got '{}'",
+            GC_RETENTION_SETTING, value
+        ))
+    })
+}
+
+/// Delete the CAS objects nothing refers to
+pub fn collect_garbage(
+    conn: &mut Connection,
+    cas: &CasStore,
+    options: &GcOptions,
+) -> Result<GcReport> {
+    let cutoff = format!("-{} days", options.retention_days);
+
+    let garbage = crate::db::transaction(conn, |tx| {
+        let referenced = referenced_hashes(tx, &cutoff)?;
+
+        let mut stored: BTreeSet<String> = cas.list_hashes()?.into_iter().collect();
+        let mut stmt = tx.prepare("SELECT sha256_hash FROM file_contents")?;
+        for hash in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            stored.insert(hash?);
+        }
+        drop(stmt);
+
+        let garbage: Vec<String> = stored
+            .into_iter()
+            .filter(|hash| !referenced.contains(hash))
+            .collect();
+        debug!(
+            "{} objects referenced, {} unreferenced",
+            referenced.len(),
+            garbage.len()
+        );
+
+        if !options.dry_run {
+            for hash in &garbage {
+                // Older history only points at collected content by hash
+                tx.execute(
+                    "UPDATE file_history SET sha256_hash = NULL WHERE sha256_hash = ?1",
+                    [hash],
+                )?;
+                tx.execute(
+                    "UPDATE file_history SET previous_hash = NULL WHERE previous_hash = ?1",
+                    [hash],
+                )?;
+                tx.execute("DELETE FROM file_contents WHERE sha256_hash = ?1", [hash])?;
+            }
+        }
+        Ok((referenced.len(), garbage))
+    })?;
+
+    let (kept, garbage) = garbage;
+    let mut report = GcReport {
+        kept,
+        removed: garbage.len(),
+        bytes_reclaimed: 0,
+    };
+    for hash in &garbage {
+        report.bytes_reclaimed += if options.dry_run {
+            cas.object_size(hash).unwrap_or(0)
+        } else {
+            cas.remove(hash)?
+        };
+    }
+
+    if !options.dry_run {
+        info!(
+            "Collected {} CAS objects ({} bytes)",
+            report.removed, report.bytes_reclaimed
+        );
+    }
+    Ok(report)
+}
+
+/// Hashes that must stay in the CAS
+fn referenced_hashes(conn: &Connection, cutoff: &str) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT sha256_hash FROM files WHERE sha256_hash IS NOT NULL
+         UNION SELECT fh.sha256_hash FROM file_history fh JOIN changesets c ON c.id = fh.changeset_id
+             WHERE fh.sha256_hash IS NOT NULL AND c.created_at >= datetime('now', ?1)
+         UNION SELECT fh.previous_hash FROM file_history fh JOIN changesets c ON c.id = fh.changeset_id
+             WHERE fh.previous_hash IS NOT NULL AND c.created_at >= datetime('now', ?1)
+         UNION SELECT from_hash FROM package_deltas
+         UNION SELECT to_hash FROM package_deltas",
+    )?;
+    let hashes = stmt
+        .query_map([cutoff], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::db::models::FileContents;
+
+    fn setup() -> (tempfile::TempDir, Connection, CasStore) {
+        let temp = tempfile::tempdir().unwrap();
+        let db_path = temp.path().join("conary.db");
+        db::init(db_path.to_str().unwrap()).unwrap();
+        let conn = db::open(db_path.to_str().unwrap()).unwrap();
+        let cas = CasStore::new(temp.path().join("objects")).unwrap();
+        (temp, conn, cas)
+    }
+
+    fn record(conn: &Connection, cas: &CasStore, content: &[u8]) -> String {
+        let hash = cas.store(content).unwrap();
+        FileContents::new(hash.clone(), content.len() as i64)
+            .insert(conn)
+            .unwrap();
+        hash
+    }
+
+    /// A changeset of `age_days` ago that added `hash` at `path`
+    fn history(conn: &Connection, age_days: u32, path: &str, hash: &str) {
+        conn.execute(
+            "INSERT INTO changesets (description, status, created_at)
+             VALUES ('test', 'applied', datetime('now', ?1))",
+            [format!("-{} days", age_days)],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO file_history (changeset_id, path, sha256_hash, action) VALUES (?1, ?2, ?3, 'add')",
+            rusqlite::params![conn.last_insert_rowid(), path, hash],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_gc_keeps_recent_history_and_removes_the_rest() {
+        let (_temp, mut conn, cas) = setup();
+        let old = record(&conn, &cas, b"old version");
+        let recent = record(&conn, &cas, b"recent version");
+        let stray = cas.store(b"never recorded").unwrap();
+        history(&conn, 90, "/usr/bin/tool", &old);
+        history(&conn, 1, "/usr/bin/tool", &recent);
+
+        let options = GcOptions {
+            retention_days: 30,
+            dry_run: true,
+        };
+        let report = collect_garbage(&mut conn, &cas, &options).unwrap();
+        assert_eq!(
+            (report.kept, report.removed, report.bytes_reclaimed),
+            (1, 2, 25)
+        );
+        assert!(cas.exists(&old) && cas.exists(&stray));
+
+        let options = GcOptions {
+            dry_run: false,
+            ..options
+        };
+        let report = collect_garbage(&mut conn, &cas, &options).unwrap();
+        assert_eq!((report.removed, report.bytes_reclaimed), (2, 25));
+        assert!(!cas.exists(&old) && !cas.exists(&stray) && cas.exists(&recent));
+
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM file_contents", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+        let cleared: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM file_history WHERE sha256_hash IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(cleared, 1);
+
+        // Nothing left to collect
+        assert_eq!(
+            collect_garbage(&mut conn, &cas, &options).unwrap().removed,
+            0
+        );
+    }
+
+    #[test]
+    fn test_retention_setting() {
+        let (_temp, conn, _cas) = setup();
+        assert_eq!(retention_days(&conn).unwrap(), DEFAULT_RETENTION_DAYS);
+        Setting::set(&conn, GC_RETENTION_SETTING, "7").unwrap();
+        assert_eq!(retention_days(&conn).unwrap(), 7);
+        assert!(parse_retention("a week").is_err());
+    }
+}
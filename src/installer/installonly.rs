@@ -0,0 +1,143 @@
+// src/installer/installonly.rs
+
+//! Packages kept in several versions side by side
+//!
+//! A kernel is not upgraded in place: the new version goes in next to the
+//! running one, so the system can still boot an older kernel when a new one
+//! fails. Packages named in `installonly_packages` are handled that way. An
+//! upgrade of one installs the new version without removing the old trove,
+//! and once it is in, the oldest versions beyond `installonly_limit` are
+//! removed. Versions installed side by side may ship the same path only with
+//! the same content; the version installed last owns it.
+
+use crate::db::models::Setting;
+use crate::error::{Error, Result};
+use rusqlite::Connection;
+
+/// Setting listing the packages kept side by side, comma separated
+pub const INSTALLONLY_SETTING: &str = "installonly_packages";
+
+/// Setting capping how many versions of each such package stay installed
+pub const INSTALLONLY_LIMIT_SETTING: &str = "installonly_limit";
+
+/// Packages kept side by side when `installonly_packages` is unset
+pub const DEFAULT_INSTALLONLY: [&str; 3] = ["kernel", "kernel-core", "kernel-modules"];
+
+/// Versions kept when `installonly_limit` is unset
+pub const DEFAULT_INSTALLONLY_LIMIT: usize = 3;
+
+/// Every installonly setting, in the order `config-get` lists them
+pub const SETTINGS: [&str; 2] = [INSTALLONLY_SETTING, INSTALLONLY_LIMIT_SETTING];
+
+/// Which packages are installed side by side, and how many versions stay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallOnly {
+    pub packages: Vec<String>,
+    /// Versions of each package kept, the newly installed ones included
+    pub limit: usize,
+}
+
+impl Default for InstallOnly {
+    fn default() -> Self {
+        Self {
+            packages: DEFAULT_INSTALLONLY
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            limit: DEFAULT_INSTALLONLY_LIMIT,
+        }
+    }
+}
+
+impl InstallOnly {
+    /// Load the installonly settings, using defaults for unset keys
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut installonly = Self::default();
+        for key in SETTINGS {
+            if let Some(value) = Setting::get(conn, key)? {
+                installonly.apply(key, &value)?;
+            }
+        }
+        Ok(installonly)
+    }
+
+    /// Check that `value` is valid for the installonly setting `key`
+    ///
+    /// Returns false when `key` is not an installonly setting.
+    pub fn validate(key: &str, value: &str) -> Result<bool> {
+        if !SETTINGS.contains(&key) {
+            return Ok(false);
+        }
+        Self::default().apply(key, value)?;
+        Ok(true)
+    }
+
+    /// Default value of an installonly setting, as `config-get` shows it
+    pub fn default_value(key: &str) -> Option<String> {
+        match key {
+            INSTALLONLY_SETTING => Some(DEFAULT_INSTALLONLY.join(",")),
+            INSTALLONLY_LIMIT_SETTING => Some(DEFAULT_INSTALLONLY_LIMIT.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Whether versions of `name` are installed side by side
+    pub fn includes(&self, name: &str) -> bool {
+        self.packages.iter().any(|package| package == name)
+    }
+
+    /// Set the field for `key` from its stored value
+    fn apply(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            INSTALLONLY_SETTING => {
+                self.packages = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            INSTALLONLY_LIMIT_SETTING => {
+                self.limit = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|&limit| limit > 0)
+                    .ok_or_else(|| {
+                        Error::ParseError(format!(
+                            "{} must be a number of versions above 0, got '{}'",
+                            key, value
+                        ))
+                    })?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema;
+
+    #[test]
+    fn test_installonly_settings() {
+        let conn = Connection::open_in_memory().unwrap();
+        schema::migrate(&conn).unwrap();
+        let installonly = InstallOnly::load(&conn).unwrap();
+        assert!(installonly.includes("kernel-core"));
+        assert!(!installonly.includes("kernel-headers"));
+        assert_eq!(installonly.limit, DEFAULT_INSTALLONLY_LIMIT);
+
+        Setting::set(&conn, INSTALLONLY_SETTING, "kernel, kernel-debug,").unwrap();
+        Setting::set(&conn, INSTALLONLY_LIMIT_SETTING, "2").unwrap();
+        let installonly = InstallOnly::load(&conn).unwrap();
+        assert_eq!(installonly.packages, vec!["kernel", "kernel-debug"]);
+        assert_eq!(installonly.limit, 2);
+
+        assert!(InstallOnly::validate(INSTALLONLY_LIMIT_SETTING, "0").is_err());
+        assert!(InstallOnly::validate(INSTALLONLY_SETTING, "").unwrap());
+        assert!(!InstallOnly::validate("max_threads", "4").unwrap());
+    }
+}
@@ -0,0 +1,1430 @@
+// src/installer/mod.rs
+
+//! Installing, upgrading and removing packages
+//!
+//! An `Installer` ties a database connection to an objects directory and an
+//! install root and runs whole operations against them: conflict checks,
+//! checking that target paths fit the filesystems they land on, storing
+//! payloads in the CAS, recording the changeset, deploying files and running
+//! scriptlets. Each operation is one changeset and returns a typed
+//! report of what it did.
+//!
+//! Taking the operation lock is left to the caller, as is any user
+//! interaction such as progress display or confirmation.
+//!
+//! ```no_run
+//! use conary::{InstallOptions, Installer};
+//! use std::path::Path;
+//!
+//! let mut conn = conary::db::open("/var/lib/conary/conary.db")?;
+//! let mut installer = Installer::new(&mut conn, Path::new("/var/lib/conary/objects"), Path::new("/"))?;
+//! let report = installer.install_file(Path::new("hello-1.0-1-x86_64.pkg.tar.zst"), &InstallOptions::default())?;
+//! println!("changeset {}: {} files", report.changeset_id, report.files_deployed);
+//! # Ok::<(), conary::Error>(())
+//! ```
+
+mod installonly;
+mod payload;
+mod prepared;
+mod transitions;
+mod update_plan;
+
+pub use installonly::{INSTALLONLY_LIMIT_SETTING, INSTALLONLY_SETTING, InstallOnly};
+pub use payload::trove_file_size;
+use payload::{delete_unowned_files, record_deleted_files, trove_files_to_delete};
+pub(crate) use payload::{remove_unowned_dirs, trove_dirs};
+pub use prepared::PreparedInstall;
+pub use update_plan::{UpdatePlan, UpdatePlanEntry, UpdateRequest, plan_updates};
+
+use crate::db;
+use crate::db::models::{
+    Changeset, ChangesetStatus, DependencyEntry, FileEntry, InstallReason, PackageLock, Provenance,
+    RelationEntry, RemovalReason, RemovedTrove, Repository, RepositoryPackage, RepositoryScope,
+    Trove,
+};
+use crate::error::{Error, Result};
+use crate::filesystem::FileDeployer;
+use crate::packages::traits::ScriptletPhase;
+use crate::packages::{PackageFormat, PackageFormatType};
+use crate::paths::{self, Layout, MountTable, PathLimits, PathViolation};
+use crate::plan::{Plan, PlanOptions, Planner, Request, Source};
+use crate::repository::{self, PackageWithRepo, SelectionOptions};
+use crate::resources::ResourceLimits;
+use crate::scriptlet::{self, Operation, ScriptletContext, ScriptletRunner};
+use crate::stats;
+use crate::timing::{Instrument, TimingReport};
+use crate::version::{self, Version, VersionConstraint};
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tracing::{debug, info, warn};
+
+/// How packages are installed
+#[derive(Debug, Clone, Copy)]
+pub struct InstallOptions<'a> {
+    /// Runs package scriptlets; `None` skips them
+    pub scripts: Option<&'a dyn ScriptletRunner>,
+    /// Allow replacing an installed package with an older version
+    pub allow_downgrade: bool,
+    /// Globs of paths to record but not deploy; when empty, an upgrade
+    /// keeps the exclusions of the trove it replaces
+    pub exclude: &'a [String],
+    /// Install another version of a locked package, lifting its lock
+    pub force_unlock: bool,
+    /// Let an upgrade ship a file where a directory holds files the old
+    /// version does not own, moving the directory to `<path>.conary-saved`
+    pub allow_dir_replace: bool,
+}
+
+impl Default for InstallOptions<'_> {
+    fn default() -> Self {
+        Self {
+            scripts: Some(&scriptlet::DirectRunner),
+            allow_downgrade: false,
+            exclude: &[],
+            force_unlock: false,
+            allow_dir_replace: false,
+        }
+    }
+}
+
+/// How packages are removed
+#[derive(Debug, Clone, Copy)]
+pub struct RemoveOptions<'a> {
+    /// Runs package scriptlets; `None` skips them
+    pub scripts: Option<&'a dyn ScriptletRunner>,
+}
+
+impl Default for RemoveOptions<'_> {
+    fn default() -> Self {
+        Self {
+            scripts: Some(&scriptlet::DirectRunner),
+        }
+    }
+}
+
+/// A package installed by an `Installer` operation
+#[derive(Debug, Clone)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+    pub reason: InstallReason,
+    /// Number of files deployed
+    pub files: usize,
+    /// Number of files recorded but left out by exclusion globs
+    pub excluded: usize,
+    pub dependencies: usize,
+    /// The trove this package took the place of
+    pub replaced: Option<Trove>,
+    /// Config files that kept their local edits; the new version of each
+    /// was written next to it with the `.conarynew` suffix
+    pub kept_configs: Vec<String>,
+    /// Directories moved aside with the `.conary-saved` suffix to make room
+    /// for a file of the new version
+    pub saved_dirs: Vec<String>,
+}
+
+/// Outcome of an install or upgrade
+#[derive(Debug, Clone)]
+pub struct InstallReport {
+    pub changeset_id: i64,
+    /// Installed packages, in the order they were recorded
+    pub packages: Vec<InstalledPackage>,
+    pub files_deployed: u64,
+    pub bytes_written: u64,
+    /// The files went to the changeset's pending tree, for `apply-pending`
+    /// to move into place, instead of the install root
+    pub staged: bool,
+    pub timings: TimingReport,
+    /// Older versions of installonly packages removed to stay within
+    /// `installonly_limit`, as a changeset of their own
+    pub pruned: Option<RemoveReport>,
+}
+
+/// A trove removed by an `Installer` operation
+#[derive(Debug, Clone)]
+pub struct RemovedPackage {
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+    /// Number of files the trove owned
+    pub files: usize,
+}
+
+/// Outcome of a removal
+#[derive(Debug, Clone)]
+pub struct RemoveReport {
+    pub changeset_id: i64,
+    pub removed: Vec<RemovedPackage>,
+}
+
+/// Outcome of carrying out a plan
+#[derive(Debug, Clone, Default)]
+pub struct PlanReport {
+    /// The changeset installing and upgrading packages, if the plan had any
+    pub installed: Option<InstallReport>,
+    /// The changeset removing packages, if the plan had any
+    pub removed: Option<RemoveReport>,
+}
+
+/// Runs install, upgrade and removal operations against one installation
+pub struct Installer<'a> {
+    conn: &'a mut Connection,
+    deployer: FileDeployer,
+    root: PathBuf,
+    temp_dir: PathBuf,
+    limits: ResourceLimits,
+    path_limits: PathLimits,
+    installonly: InstallOnly,
+    mounts: MountTable,
+    instrument: Instrument,
+    /// Scratch directories of downloaded packages, kept until the installer is dropped
+    downloads: Vec<TempDir>,
+    /// Directory of pending trees when changesets are staged rather than deployed
+    staging: Option<PathBuf>,
+}
+
+impl<'a> Installer<'a> {
+    /// An installer storing content in `objects_dir` and deploying under `root`
+    ///
+    /// Downloads go to the system temp directory; see `with_temp_dir`.
+    pub fn new(conn: &'a mut Connection, objects_dir: &Path, root: &Path) -> Result<Self> {
+        let limits = ResourceLimits::load(conn)?;
+        let path_limits = PathLimits::load(conn)?;
+        let installonly = InstallOnly::load(conn)?;
+        let deployer = FileDeployer::new(objects_dir, root)?
+            .with_buffer_limit(limits.max_extraction_buffer_bytes);
+        Ok(Self {
+            conn,
+            deployer,
+            root: root.to_path_buf(),
+            temp_dir: std::env::temp_dir(),
+            limits,
+            path_limits,
+            installonly,
+            mounts: MountTable::load(),
+            instrument: Instrument::new(),
+            downloads: Vec::new(),
+            staging: None,
+        })
+    }
+
+    /// An installer for the objects directory, install root and temp directory of `layout`
+    ///
+    /// Installs are staged in the layout's pending directory when its
+    /// `staged_deploy` setting is on.
+    pub fn for_layout(conn: &'a mut Connection, layout: &Layout) -> Result<Self> {
+        let installer = Self::new(conn, layout.objects_dir(), layout.install_root())?
+            .with_temp_dir(layout.temp_dir());
+        Ok(match layout.staged_deploy() {
+            true => installer.with_staging(layout.pending_dir()),
+            false => installer,
+        })
+    }
+
+    /// Stage installs under `dir` instead of deploying them
+    ///
+    /// Each changeset is recorded as staged and its files are written to
+    /// `<dir>/<changeset id>`, leaving the install root untouched until
+    /// [`staged::apply_pending`](crate::staged::apply_pending) moves them
+    /// into place. Scriptlets are not run for staged changesets.
+    pub fn with_staging(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.staging = Some(dir.into());
+        self
+    }
+
+    /// Download packages into scratch directories under `dir`
+    pub fn with_temp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = dir.into();
+        self
+    }
+
+    /// Judge target filesystems by `mounts` instead of the running system's mount table
+    pub fn with_mounts(mut self, mounts: MountTable) -> Self {
+        self.mounts = mounts;
+        self
+    }
+
+    /// The database connection, for queries between operations
+    pub fn conn(&self) -> &Connection {
+        self.conn
+    }
+
+    pub fn deployer(&self) -> &FileDeployer {
+        &self.deployer
+    }
+
+    /// Parse a package file for `install_batch`
+    ///
+    /// Parsing counts towards the timings of the next batch.
+    pub fn prepare(&self, package_path: &Path) -> Result<PreparedInstall> {
+        PreparedInstall::open(package_path, &self.instrument)
+    }
+
+    /// Install a package file as its own changeset
+    ///
+    /// An installed older version of the same package and architecture is
+    /// upgraded; the same version, or a newer one without
+    /// `allow_downgrade`, is refused. An installonly package goes in next to
+    /// its installed versions instead.
+    pub fn install_file(
+        &mut self,
+        package_path: &Path,
+        opts: &InstallOptions,
+    ) -> Result<InstallReport> {
+        let prepared = self.prepare(package_path)?;
+        self.install_batch(vec![prepared], opts)
+    }
+
+    /// Replace the installed trove `old` with a package file
+    ///
+    /// Unlike `install_file` no version check is made, and the package
+    /// keeps the install reason of `old`, as when `update` replaces a trove.
+    pub fn upgrade(
+        &mut self,
+        package_path: &Path,
+        old: &Trove,
+        opts: &InstallOptions,
+    ) -> Result<InstallReport> {
+        let mut prepared = self.prepare(package_path)?;
+        prepared.replace(old);
+        self.install_batch(vec![prepared], opts)
+    }
+
+    /// Download and install the best repository package called `name`,
+    /// together with the dependencies it is missing
+    pub fn install_from_repo(
+        &mut self,
+        name: &str,
+        selection: &SelectionOptions,
+        opts: &InstallOptions,
+    ) -> Result<InstallReport> {
+        let options = PlanOptions {
+            repository: selection.repository.clone(),
+            architecture: selection.architecture.clone(),
+            ..Default::default()
+        };
+        let mut planner = Planner::new(self.conn, options);
+        planner.request(Request::Install {
+            name: name.to_string(),
+            version: selection.version.clone(),
+        });
+        let plan = planner.plan()?;
+        self.execute(plan, opts)?
+            .installed
+            .ok_or_else(|| Error::NotFoundError("No packages to install".to_string()))
+    }
+
+    /// Download repository packages and parse them for `install_batch`
+    ///
+    /// The first `dependency_count` packages are marked as installed for
+    /// dependencies. `progress` gets the index of a package with the bytes
+    /// received and expected so far. A package with a delta from a version
+    /// still in the CAS is rebuilt from it rather than downloaded whole. The
+    /// files stay on disk until the installer is dropped.
+    pub fn download(
+        &mut self,
+        packages: &[(String, PackageWithRepo)],
+        dependency_count: usize,
+        progress: Option<&(dyn Fn(usize, u64, Option<u64>) + Sync)>,
+    ) -> Result<Vec<PreparedInstall>> {
+        std::fs::create_dir_all(&self.temp_dir)?;
+        let total: u64 = packages
+            .iter()
+            .map(|(_, pkg)| pkg.package.size as u64)
+            .sum();
+        self.limits.check_tmp_quota(&self.temp_dir, total)?;
+        let dir = TempDir::new_in(&self.temp_dir)?;
+        let downloaded = repository::download_dependencies(
+            self.conn,
+            packages,
+            self.deployer.cas(),
+            dir.path(),
+            &self.limits,
+            progress,
+        )?;
+
+        let mut batch = Vec::with_capacity(downloaded.len());
+        for (i, ((_, pkg), (_, fetched))) in packages.iter().zip(&downloaded).enumerate() {
+            stats::record_download(
+                self.conn,
+                pkg.package.repository_id,
+                fetched.downloaded_bytes,
+            )?;
+            match &fetched.delta {
+                Some(delta) => info!(
+                    "Rebuilt package from the delta of {}: {}",
+                    delta.from_version,
+                    fetched.path.display()
+                ),
+                None => info!("Downloaded package to: {}", fetched.path.display()),
+            }
+
+            let mut prepared = self.prepare(&fetched.path)?;
+            prepared.downloaded_for(&pkg.package);
+            prepared.set_downloaded_bytes(fetched.downloaded_bytes);
+            if i < dependency_count {
+                prepared.set_reason(InstallReason::Dependency);
+            }
+            batch.push(prepared);
+        }
+        self.downloads.push(dir);
+        Ok(batch)
+    }
+
+    /// Download the repository packages of `plan` and parse them
+    ///
+    /// `progress` is reported as in `download`. Packages already parsed are
+    /// skipped, so `execute` need not fetch anything afterwards.
+    pub fn download_plan(
+        &mut self,
+        plan: &mut Plan,
+        progress: Option<&(dyn Fn(usize, u64, Option<u64>) + Sync)>,
+    ) -> Result<()> {
+        let mut indices = Vec::new();
+        let mut packages = Vec::new();
+        for (index, operation) in plan.operations.iter().enumerate() {
+            let crate::plan::Operation::Install {
+                source:
+                    Source::Repository {
+                        package_id,
+                        requested_as,
+                        ..
+                    },
+                ..
+            } = operation
+            else {
+                continue;
+            };
+            if plan.parsed(index).is_some() {
+                continue;
+            }
+            let package =
+                RepositoryPackage::find_by_id(self.conn, *package_id)?.ok_or_else(|| {
+                    Error::NotFoundError(format!("Repository package {} is gone", package_id))
+                })?;
+            let repository =
+                Repository::find_by_id(self.conn, package.repository_id)?.ok_or_else(|| {
+                    Error::NotFoundError(format!(
+                        "Repository of {} {} is gone",
+                        package.name, package.version
+                    ))
+                })?;
+            indices.push(index);
+            packages.push((
+                requested_as.clone(),
+                PackageWithRepo::new(package, repository),
+            ));
+        }
+        if packages.is_empty() {
+            return Ok(());
+        }
+
+        for (index, prepared) in indices
+            .into_iter()
+            .zip(self.download(&packages, 0, progress)?)
+        {
+            plan.set_parsed(index, prepared);
+        }
+        Ok(())
+    }
+
+    /// Carry out a plan drawn up by a [`Planner`](crate::plan::Planner)
+    ///
+    /// Packages not fetched by `download_plan` yet are downloaded first.
+    /// Everything the plan installs or upgrades then goes in as one
+    /// changeset, as in `install_batch`, and the packages it removes come
+    /// off in a second one. A plan with conflicts is refused.
+    pub fn execute(&mut self, mut plan: Plan, opts: &InstallOptions) -> Result<PlanReport> {
+        if !plan.conflicts.is_empty() {
+            let listed: Vec<String> = plan
+                .conflicts
+                .iter()
+                .map(|conflict| format!("  {}", conflict.to_string().trim_end()))
+                .collect();
+            return Err(Error::ConflictError(format!(
+                "The plan would break dependencies:\n{}",
+                listed.join("\n")
+            )));
+        }
+        self.download_plan(&mut plan, None)?;
+
+        let mut batch = Vec::new();
+        let mut removals = Vec::new();
+        for (index, operation) in std::mem::take(&mut plan.operations).into_iter().enumerate() {
+            match operation {
+                crate::plan::Operation::Install {
+                    source,
+                    dependency,
+                    replaces,
+                    ..
+                } => {
+                    let mut prepared = match (plan.take_parsed(index), source) {
+                        (Some(prepared), _) => prepared,
+                        (None, Source::File { path }) => self.prepare(&path)?,
+                        (None, Source::Repository { requested_as, .. }) => {
+                            return Err(Error::DownloadError(format!(
+                                "{} was not downloaded",
+                                requested_as
+                            )));
+                        }
+                    };
+                    if dependency {
+                        prepared.set_reason(InstallReason::Dependency);
+                    }
+                    if let Some(old) = replaces {
+                        let trove =
+                            Trove::find_by_id(self.conn, old.trove_id)?.ok_or_else(|| {
+                                Error::NotFoundError(format!(
+                                    "Package '{}' is not installed",
+                                    old.name
+                                ))
+                            })?;
+                        prepared.replace(&trove);
+                    }
+                    batch.push(prepared);
+                }
+                crate::plan::Operation::Remove { trove } => {
+                    removals.push(Trove::find_by_id(self.conn, trove.trove_id)?.ok_or_else(
+                        || {
+                            Error::NotFoundError(format!(
+                                "Package '{}' is not installed",
+                                trove.name
+                            ))
+                        },
+                    )?);
+                }
+            }
+        }
+
+        let installed = if batch.is_empty() {
+            None
+        } else {
+            Some(self.install_batch(batch, opts)?)
+        };
+        let removed = if removals.is_empty() {
+            None
+        } else {
+            let description = match removals.as_slice() {
+                [trove] => format!("Remove {}-{}", trove.name, trove.version),
+                _ => format!("Remove {} package(s)", removals.len()),
+            };
+            Some(self.remove_troves(
+                &removals,
+                &description,
+                &RemoveOptions {
+                    scripts: opts.scripts,
+                },
+            )?)
+        };
+        Ok(PlanReport { installed, removed })
+    }
+
+    /// Install prepared packages as one changeset
+    ///
+    /// Explicitly requested packages that do not replace a trove yet are
+    /// checked against installed versions as in `install_file`. Every
+    /// package is checked and extracted before any is recorded, so a
+    /// conflict anywhere leaves the installation untouched. Another version
+    /// of a locked package is refused unless `force_unlock` is set.
+    ///
+    /// Installonly packages never replace a trove, even one chosen with
+    /// `PreparedInstall::replace`; the oldest of their versions beyond
+    /// `installonly_limit` are removed once the batch is in.
+    pub fn install_batch(
+        &mut self,
+        mut batch: Vec<PreparedInstall>,
+        opts: &InstallOptions,
+    ) -> Result<InstallReport> {
+        if batch.is_empty() {
+            return Err(Error::NotFoundError("No packages to install".to_string()));
+        }
+        let instrument = std::mem::take(&mut self.instrument);
+
+        // Pre-transaction validation and upgrade detection
+        for prepared in &mut batch {
+            let package = prepared.package();
+            if self.installonly.includes(package.name()) {
+                let same = |trove: &Trove| {
+                    trove.version == package.version()
+                        && trove.architecture.as_deref() == package.architecture()
+                };
+                if Trove::find_by_name(self.conn, package.name())?
+                    .iter()
+                    .any(same)
+                {
+                    return Err(already_installed(
+                        package.name(),
+                        package.version(),
+                        package.architecture(),
+                    ));
+                }
+                prepared.old_trove = None;
+                prepared.is_downgrade = false;
+                prepared.alongside = true;
+                continue;
+            }
+            if prepared.reason() != InstallReason::Explicit || prepared.old_trove().is_some() {
+                continue;
+            }
+            let package = prepared.package();
+            let existing = Trove::find_by_name(self.conn, package.name())?;
+            if let Some((old, is_downgrade)) = find_trove_to_replace(
+                &existing,
+                package.name(),
+                package.version(),
+                package.architecture(),
+                prepared.format(),
+                opts.allow_downgrade,
+            )? {
+                prepared.old_trove = Some(old);
+                prepared.is_downgrade = is_downgrade;
+            }
+        }
+
+        for prepared in &mut batch {
+            let exclude = match prepared.old_trove() {
+                Some(old) if opts.exclude.is_empty() => old.install_modifiers.excluded.clone(),
+                _ => opts.exclude.to_vec(),
+            };
+            prepared.exclude(&exclude);
+        }
+        let unlocking = self.check_locks(&batch, opts.force_unlock)?;
+        self.check_paths(&batch)?;
+        for prepared in &mut batch {
+            prepared.plan_transitions(self.conn, &self.deployer, opts.allow_dir_replace)?;
+            if self.staging.is_some() {
+                prepared.refuse_transitions()?;
+            }
+        }
+        for prepared in &mut batch {
+            prepared.stage(self.conn, &self.deployer, &instrument)?;
+        }
+        let (changeset_id, kept_configs) = prepared::apply_installs(
+            self.conn,
+            &self.deployer,
+            &self.root,
+            &batch,
+            opts.scripts,
+            &instrument,
+            self.staging.as_deref(),
+        )?;
+        for name in unlocking {
+            PackageLock::unlock(self.conn, &name)?;
+            info!("Unlocked {}", name);
+        }
+        let pruned = self.prune_installonly(&batch, changeset_id, opts)?;
+
+        let packages: Vec<InstalledPackage> = batch
+            .iter()
+            .zip(kept_configs)
+            .map(|(prepared, kept)| {
+                let package = prepared.package();
+                let mut kept_configs: Vec<String> = kept.into_iter().collect();
+                kept_configs.sort();
+                InstalledPackage {
+                    name: package.name().to_string(),
+                    version: package.version().to_string(),
+                    architecture: package.architecture().map(str::to_string),
+                    reason: prepared.reason(),
+                    files: prepared.deployed_files().count(),
+                    excluded: package
+                        .files()
+                        .iter()
+                        .filter(|file| prepared.modifiers().excludes(&file.path))
+                        .count(),
+                    dependencies: package.dependencies().len(),
+                    replaced: prepared.old_trove().cloned(),
+                    kept_configs,
+                    saved_dirs: prepared.saved_dirs(),
+                }
+            })
+            .collect();
+        let (files_deployed, bytes_written) = batch
+            .iter()
+            .flat_map(|prepared| prepared.deployed_files())
+            .fold((0, 0), |(files, bytes), file| {
+                (files + 1, bytes + file.size as u64)
+            });
+        Ok(InstallReport {
+            changeset_id,
+            packages,
+            files_deployed,
+            bytes_written,
+            staged: self.staging.is_some(),
+            timings: instrument.report(),
+            pruned,
+        })
+    }
+
+    /// Remove the oldest versions of the installonly packages of `batch` beyond the limit
+    ///
+    /// Versions installed by `changeset_id` always stay. Returns `None`
+    /// when no package has too many versions.
+    fn prune_installonly(
+        &mut self,
+        batch: &[PreparedInstall],
+        changeset_id: i64,
+        opts: &InstallOptions,
+    ) -> Result<Option<RemoveReport>> {
+        let mut pruned = Vec::new();
+        for prepared in batch.iter().filter(|prepared| prepared.alongside) {
+            let package = prepared.package();
+            let mut versions: Vec<Trove> = Trove::find_by_name(self.conn, package.name())?
+                .into_iter()
+                .filter(|trove| trove.architecture.as_deref() == package.architecture())
+                .collect();
+            let excess = versions.len().saturating_sub(self.installonly.limit);
+            let scheme = version::VersionScheme::for_format(prepared.format());
+            versions.retain(|trove| trove.installed_by_changeset_id != Some(changeset_id));
+            versions.sort_by(|a, b| version::compare_versions(scheme, &a.version, &b.version));
+            pruned.extend(versions.into_iter().take(excess));
+        }
+        if pruned.is_empty() {
+            return Ok(None);
+        }
+
+        for trove in &pruned {
+            info!(
+                "Removing {} {} beyond the installonly limit of {}",
+                trove.name, trove.version, self.installonly.limit
+            );
+        }
+        let description = match pruned.as_slice() {
+            [trove] => format!(
+                "Remove {}-{} (installonly limit)",
+                trove.name, trove.version
+            ),
+            _ => format!("Remove {} package(s) (installonly limit)", pruned.len()),
+        };
+        self.remove_troves(
+            &pruned,
+            &description,
+            &RemoveOptions {
+                scripts: opts.scripts,
+            },
+        )
+        .map(Some)
+    }
+
+    /// Refuse a batch that would change the version of a locked package
+    ///
+    /// With `force_unlock`, returns the locks to lift once the batch is installed.
+    fn check_locks(&self, batch: &[PreparedInstall], force_unlock: bool) -> Result<Vec<String>> {
+        let mut unlocking = Vec::new();
+        for prepared in batch {
+            let package = prepared.package();
+            if !PackageLock::is_locked(self.conn, package.name())? {
+                continue;
+            }
+            let Some(locked) = Trove::find_by_name(self.conn, package.name())?
+                .into_iter()
+                .find(|trove| trove.version != package.version())
+            else {
+                continue;
+            };
+            if !force_unlock {
+                return Err(Error::ConflictError(format!(
+                    "{} is locked at version {}; use --force-unlock to install {} anyway, or 'conary unlock {}'",
+                    locked.name,
+                    locked.version,
+                    package.version(),
+                    locked.name
+                )));
+            }
+            unlocking.push(locked.name);
+        }
+        Ok(unlocking)
+    }
+
+    /// Refuse a batch with any path the target filesystem cannot hold, listing them all
+    ///
+    /// A path climbing out of the root with `..` is refused first, excluded
+    /// or not, since only a broken or malicious package ships one.
+    fn check_paths(&self, batch: &[PreparedInstall]) -> Result<()> {
+        for prepared in batch {
+            let package = prepared.package();
+            if let Some(file) = package
+                .files()
+                .iter()
+                .find(|file| paths::escapes_root(&file.path))
+            {
+                return Err(Error::IoError(format!(
+                    "Refusing to install {}: {} leads outside the install root",
+                    package.name(),
+                    file.path
+                )));
+            }
+        }
+
+        let violations: Vec<PathViolation> = batch
+            .iter()
+            .flat_map(|prepared| {
+                let package = prepared.package();
+                package
+                    .files()
+                    .iter()
+                    .filter(|file| !prepared.modifiers().excludes(&file.path))
+                    .filter_map(|file| {
+                        self.path_limits
+                            .check(&self.mounts, &self.root, package.name(), &file.path)
+                    })
+            })
+            .collect();
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        let listed: Vec<String> = violations
+            .iter()
+            .map(|violation| format!("  {}", violation))
+            .collect();
+        Err(Error::IoError(format!(
+            "{} path(s) cannot be deployed to the target filesystem:\n{}",
+            violations.len(),
+            listed.join("\n")
+        )))
+    }
+
+    /// Remove the installed package `name`, or its installed `version`
+    ///
+    /// Refused when several versions are installed and no `version` picks
+    /// one, or when installed packages depend on it. Packages depending on
+    /// a version removed from several are left to the versions that stay.
+    pub fn remove(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+        opts: &RemoveOptions,
+    ) -> Result<RemoveReport> {
+        let trove = trove_to_remove(self.conn, name, version)?;
+
+        let others_stay = Trove::find_by_name(self.conn, name)?.len() > 1;
+        let breaking = if others_stay {
+            Vec::new()
+        } else {
+            crate::resolver::Resolver::new(self.conn)?.check_removal(name)?
+        };
+        if !breaking.is_empty() {
+            return Err(Error::ConflictError(format!(
+                "Cannot remove '{}': {} package(s) depend on it ({}); see 'conary whatbreaks {}'",
+                name,
+                breaking.len(),
+                breaking.join(", "),
+                name
+            )));
+        }
+
+        let description = format!("Remove {}-{}", trove.name, trove.version);
+        self.remove_troves(&[trove], &description, opts)
+    }
+
+    /// Remove the packages installed as dependencies that nothing needs any more
+    ///
+    /// Returns `None` when there is nothing to remove.
+    pub fn autoremove(&mut self, opts: &RemoveOptions) -> Result<Option<RemoveReport>> {
+        let unneeded = crate::resolver::find_unneeded_dependencies(self.conn)?;
+        if unneeded.is_empty() {
+            return Ok(None);
+        }
+        let description = format!("Autoremove {} package(s)", unneeded.len());
+        self.remove_troves(&unneeded, &description, opts).map(Some)
+    }
+
+    /// Remove troves as one changeset, with their scriptlets and files
+    ///
+    /// Locally edited config files stay in place, and so do directories
+    /// another trove owns or that still hold anything.
+    fn remove_troves(
+        &mut self,
+        troves: &[Trove],
+        description: &str,
+        opts: &RemoveOptions,
+    ) -> Result<RemoveReport> {
+        // Scriptlets are stored with each trove and must be loaded before it is deleted
+        let mut scriptlets = Vec::new();
+        let mut removed = Vec::new();
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        for trove in troves {
+            let trove_id = trove
+                .id
+                .ok_or_else(|| Error::NotFoundError(format!("Trove {} has no ID", trove.name)))?;
+            scriptlets.push(scriptlet::load(self.conn, trove_id)?);
+            files.extend(trove_files_to_delete(self.conn, &self.deployer, trove)?);
+            dirs.extend(trove_dirs(self.conn, trove)?);
+            removed.push(RemovedPackage {
+                name: trove.name.clone(),
+                version: trove.version.clone(),
+                architecture: trove.architecture.clone(),
+                files: FileEntry::find_by_trove(self.conn, trove_id)?.len(),
+            });
+        }
+        let runner = opts.scripts.unwrap_or(&scriptlet::DirectRunner);
+        let contexts: Vec<_> = troves
+            .iter()
+            .zip(&scriptlets)
+            .map(|(trove, loaded)| {
+                loaded.as_ref().map(|(format, _)| ScriptletContext {
+                    root: &self.root,
+                    runner,
+                    format: *format,
+                    operation: Operation::Remove,
+                    version: &trove.version,
+                    other_version: None,
+                })
+            })
+            .collect();
+
+        // A failing pre-remove scriptlet aborts the whole changeset
+        let mut outcomes = Vec::new();
+        if opts.scripts.is_some() {
+            for ((trove, loaded), ctx) in troves.iter().zip(&scriptlets).zip(&contexts) {
+                if let (Some((_, trove_scriptlets)), Some(ctx)) = (loaded, ctx)
+                    && let Err(e) = scriptlet::run_checked(
+                        trove_scriptlets,
+                        ScriptletPhase::PreRemove,
+                        ctx,
+                        &trove.name,
+                        &mut outcomes,
+                    )
+                {
+                    scriptlet::record_aborted(self.conn, description, &outcomes)?;
+                    return Err(e);
+                }
+            }
+        }
+
+        let changeset_id = db::transaction(self.conn, |tx| {
+            let mut changeset = Changeset::new(description.to_string());
+            let changeset_id = changeset.insert(tx)?;
+            scriptlet::record_outcomes(tx, changeset_id, &outcomes)?;
+
+            for trove in troves {
+                let removed_size = trove_file_size(tx, Some(trove))?;
+                Changeset::add_sizes(tx, changeset_id, 0, -removed_size)?;
+                // Files are cascade-deleted with the trove
+                RemovedTrove::new(changeset_id, trove, RemovalReason::Removed).insert(tx)?;
+                // Files another installed version ships too stay, owned by it
+                FileEntry::hand_back_shared(tx, trove.id.unwrap())?;
+                Trove::delete(tx, trove.id.unwrap())?;
+            }
+            record_deleted_files(tx, changeset_id, &files)?;
+            stats::record(
+                tx,
+                stats::Metric::PackagesRemoved,
+                None,
+                troves.len() as i64,
+            )?;
+
+            changeset.update_status(tx, ChangesetStatus::Applied)?;
+            Ok(changeset_id)
+        })?;
+
+        // Files go first, so the directories nothing owns any more can go once empty
+        if self.staging.is_none() {
+            delete_unowned_files(self.conn, &self.deployer, &files)?;
+            dirs.sort_by(|a, b| b.cmp(a));
+            dirs.dedup();
+            remove_unowned_dirs(self.conn, &self.deployer, &dirs)?;
+        }
+
+        if opts.scripts.is_some() {
+            let mut post_outcomes = Vec::new();
+            for ((trove, loaded), ctx) in troves.iter().zip(&scriptlets).zip(&contexts) {
+                if let (Some((_, trove_scriptlets)), Some(ctx)) = (loaded, ctx) {
+                    scriptlet::run_checked(
+                        trove_scriptlets,
+                        ScriptletPhase::PostRemove,
+                        ctx,
+                        &trove.name,
+                        &mut post_outcomes,
+                    )?;
+                }
+            }
+            scriptlet::record_outcomes(self.conn, changeset_id, &post_outcomes)?;
+        }
+
+        Ok(RemoveReport {
+            changeset_id,
+            removed,
+        })
+    }
+}
+
+/// Decide which installed trove, if any, a new package version replaces
+///
+/// Only troves with the same architecture are considered. Installing the
+/// exact same version is always an error; installing an older version is an
+/// error unless `allow_downgrade` is set. Versions are ordered the way
+/// `format` orders them. Returns the trove to replace and whether the
+/// replacement is a downgrade.
+pub fn find_trove_to_replace(
+    existing: &[Trove],
+    name: &str,
+    version: &str,
+    architecture: Option<&str>,
+    format: PackageFormatType,
+    allow_downgrade: bool,
+) -> Result<Option<(Trove, bool)>> {
+    let mut replaced = None;
+
+    for trove in existing {
+        // Only compare packages with same architecture
+        if trove.architecture.as_deref() != architecture {
+            continue;
+        }
+
+        if trove.version == version {
+            return Err(already_installed(name, version, architecture));
+        }
+
+        // Compare versions
+        match (
+            Version::parse_for_format(format, &trove.version),
+            Version::parse_for_format(format, version),
+        ) {
+            (Ok(existing_ver), Ok(new_ver)) => {
+                if new_ver > existing_ver {
+                    // This is an upgrade
+                    info!(
+                        "Upgrading {} from version {} to {}",
+                        name, trove.version, version
+                    );
+                    replaced = Some((trove.clone(), false));
+                } else if allow_downgrade {
+                    warn!(
+                        "DOWNGRADING {} from version {} to {}; the older version may lack fixes or read newer data incorrectly",
+                        name, trove.version, version
+                    );
+                    replaced = Some((trove.clone(), true));
+                } else {
+                    // Trying to install older version
+                    return Err(Error::ConflictError(format!(
+                        "Cannot downgrade package {} from version {} to {} (use --allow-downgrade)",
+                        name, trove.version, version
+                    )));
+                }
+            }
+            _ => {
+                // Version parsing failed - allow installation but warn
+                warn!(
+                    "Could not compare versions {} and {}",
+                    trove.version, version
+                );
+            }
+        }
+    }
+
+    Ok(replaced)
+}
+
+/// The error for installing a version of a package that is already installed
+fn already_installed(name: &str, version: &str, architecture: Option<&str>) -> Error {
+    Error::ConflictError(format!(
+        "Package {} version {} ({}) is already installed",
+        name,
+        version,
+        architecture.unwrap_or("no-arch")
+    ))
+}
+
+/// The installed trove removing `name` takes off
+///
+/// That is the only trove called `name`, or with `version` the one installed
+/// as that version. Several matching troves are refused.
+pub(crate) fn trove_to_remove(
+    conn: &Connection,
+    name: &str,
+    version: Option<&str>,
+) -> Result<Trove> {
+    let mut troves = Trove::find_by_name(conn, name)?;
+    if troves.is_empty() {
+        return Err(Error::NotFoundError(format!(
+            "Package '{}' is not installed",
+            name
+        )));
+    }
+    if let Some(version) = version {
+        let mut matching = Vec::new();
+        for trove in troves {
+            if installed_as(conn, &trove, version)? {
+                matching.push(trove);
+            }
+        }
+        if matching.is_empty() {
+            return Err(Error::NotFoundError(format!(
+                "Package '{}' version {} is not installed",
+                name, version
+            )));
+        }
+        troves = matching;
+    }
+    if troves.len() > 1 {
+        let versions: Vec<String> = troves
+            .iter()
+            .map(|trove| {
+                format!(
+                    "{} ({})",
+                    trove.version,
+                    trove.architecture.as_deref().unwrap_or("no-arch")
+                )
+            })
+            .collect();
+        return Err(Error::ConflictError(format!(
+            "Multiple versions of '{}' are installed: {}; pick one with --version",
+            name,
+            versions.join(", ")
+        )));
+    }
+    Ok(troves.remove(0))
+}
+
+/// Whether an installed trove is `version`
+///
+/// A package whose header disagreed with its repository metadata is
+/// recorded under the header version; the version the repository
+/// advertised, kept in its provenance, counts as well.
+pub fn installed_as(conn: &Connection, trove: &Trove, version: &str) -> Result<bool> {
+    if trove.version == version {
+        return Ok(true);
+    }
+    let Some(trove_id) = trove.id else {
+        return Ok(false);
+    };
+    let provenance = Provenance::find_by_trove(conn, trove_id)?;
+    Ok(provenance
+        .and_then(|provenance| provenance.repository_version)
+        .as_deref()
+        == Some(version))
+}
+
+/// Installed troves with a newer version available in a repository
+///
+/// Only `repo` is searched when given; otherwise a trove stays with the
+/// repository it came from as long as that repository still carries the
+/// package, and falls back to every enabled repository that is not
+/// explicit-only. Among packages of the same architecture the
+/// highest-priority repository wins, then the newest version by the
+/// ordering of the package's format. A trove is only updated to a version
+/// newer than the installed one that it is not already installed as. The
+/// versions of an installonly package are taken together: one update at
+/// most, from the newest of them, to a version none is installed as.
+pub fn available_updates(
+    conn: &Connection,
+    installed: &[Trove],
+    repo: Option<&str>,
+) -> Result<Vec<(Trove, RepositoryPackage)>> {
+    let repo_id = match repo {
+        Some(name) => Some(
+            Repository::find_by_name(conn, name)?
+                .and_then(|repo| repo.id)
+                .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))?,
+        ),
+        None => None,
+    };
+    let repositories: Vec<Repository> = Repository::list_all(conn)?;
+    let repository = |id: i64| repositories.iter().find(|repo| repo.id == Some(id));
+
+    let installonly = InstallOnly::load(conn)?;
+
+    let mut updates: Vec<(Trove, RepositoryPackage)> = Vec::new();
+    'troves: for trove in installed {
+        let same_package =
+            |other: &Trove| other.name == trove.name && other.architecture == trove.architecture;
+        let versions: Vec<&Trove> = if installonly.includes(&trove.name) {
+            if updates.iter().any(|(updated, _)| same_package(updated)) {
+                continue;
+            }
+            installed
+                .iter()
+                .filter(|other| same_package(other))
+                .collect()
+        } else {
+            vec![trove]
+        };
+
+        let mut candidates = RepositoryPackage::find_by_name(conn, &trove.name)?;
+        candidates.retain(|repo_pkg| {
+            repo_pkg.architecture == trove.architecture || repo_pkg.architecture.is_none()
+        });
+        match (repo_id, trove.origin_repository_id) {
+            (Some(repo_id), _) => candidates.retain(|repo_pkg| repo_pkg.repository_id == repo_id),
+            (None, Some(origin))
+                if candidates
+                    .iter()
+                    .any(|repo_pkg| repo_pkg.repository_id == origin) =>
+            {
+                candidates.retain(|repo_pkg| repo_pkg.repository_id == origin);
+            }
+            _ => candidates.retain(|repo_pkg| {
+                repository(repo_pkg.repository_id)
+                    .is_some_and(|repo| repo.enabled && repo.scope != RepositoryScope::ExplicitOnly)
+            }),
+        }
+
+        let newest = candidates.into_iter().max_by(|a, b| {
+            let (repo_a, repo_b) = (repository(a.repository_id), repository(b.repository_id));
+            let priority = |repo: Option<&Repository>| repo.map_or(i32::MIN, |repo| repo.priority);
+            priority(repo_a)
+                .cmp(&priority(repo_b))
+                .then_with(|| version::compare_versions(a.version_scheme(), &a.version, &b.version))
+                .then_with(|| {
+                    repo_b
+                        .map(|repo| &repo.name)
+                        .cmp(&repo_a.map(|repo| &repo.name))
+                })
+        });
+        let Some(repo_pkg) = newest else {
+            continue;
+        };
+
+        let scheme = repo_pkg.version_scheme();
+        let mut trove = trove;
+        for installed_version in versions {
+            if installed_as(conn, installed_version, &repo_pkg.version)? {
+                continue 'troves;
+            }
+            if version::compare_versions(scheme, &installed_version.version, &trove.version).is_gt()
+            {
+                trove = installed_version;
+            }
+        }
+        if version::compare_versions(repo_pkg.version_scheme(), &repo_pkg.version, &trove.version)
+            .is_le()
+        {
+            debug!(
+                "Newest {} in the repositories is {}, not newer than installed {}",
+                trove.name, repo_pkg.version, trove.version
+            );
+            continue;
+        }
+        info!(
+            "Update available: {} {} -> {}",
+            trove.name, trove.version, repo_pkg.version
+        );
+        updates.push((trove.clone(), repo_pkg));
+    }
+    Ok(updates)
+}
+
+/// Whether `version` falls under an optional constraint like ">=2.0"
+///
+/// The version is ordered by `scheme`, that of the package it belongs to.
+/// A constraint that cannot be parsed is taken to match, so an odd entry
+/// errs on the side of reporting a conflict.
+pub fn constraint_matches(
+    constraint: Option<&str>,
+    scheme: version::VersionScheme,
+    version: &str,
+) -> bool {
+    let Some(constraint) = constraint else {
+        return true;
+    };
+    match VersionConstraint::parse(constraint) {
+        Ok(constraint) => constraint.satisfies(scheme, version),
+        Err(_) => true,
+    }
+}
+
+/// Refuse a package that conflicts with an installed trove, in either direction
+///
+/// Other versions of the same package and `replacing` (the trove an update
+/// replaces) never count as conflicts. `format` is that of `package`. A
+/// directory the package shares with an installed trove conflicts when the
+/// two declare different owners or groups for it.
+fn check_package_conflicts(
+    conn: &Connection,
+    package: &dyn PackageFormat,
+    format: PackageFormatType,
+    replacing: Option<&Trove>,
+) -> Result<()> {
+    let exempt = |trove: &Trove| {
+        trove.name == package.name() || replacing.is_some_and(|old| old.id == trove.id)
+    };
+    let scheme = version::VersionScheme::for_format(format);
+
+    // Conflicts the new package declares, by name or provided capability
+    for conflict in package.conflicts() {
+        for trove in DependencyEntry::find_providers(conn, &conflict.name)? {
+            if !exempt(&trove)
+                && constraint_matches(
+                    conflict.version.as_deref(),
+                    trove.version_scheme(),
+                    &trove.version,
+                )
+            {
+                return Err(Error::ConflictError(format!(
+                    "{} {} conflicts with installed package {} {}",
+                    package.name(),
+                    package.version(),
+                    trove.name,
+                    trove.version
+                )));
+            }
+        }
+    }
+
+    // Conflicts installed troves declare against the new package or what it provides
+    let names =
+        std::iter::once(package.name()).chain(package.provides().iter().map(|p| p.name.as_str()));
+    for name in names {
+        for relation in RelationEntry::find_by_name(conn, "conflicts", name)? {
+            let Some(trove) = Trove::find_by_id(conn, relation.trove_id)? else {
+                continue;
+            };
+            if !exempt(&trove)
+                && constraint_matches(
+                    relation.version_constraint.as_deref(),
+                    scheme,
+                    package.version(),
+                )
+            {
+                return Err(Error::ConflictError(format!(
+                    "Installed package {} {} conflicts with {} {}",
+                    trove.name,
+                    trove.version,
+                    package.name(),
+                    package.version()
+                )));
+            }
+        }
+    }
+
+    // Modes of shared directories are reconciled, but owners must agree
+    let differs = |declared: &Option<String>, recorded: &Option<String>| matches!((declared, recorded), (Some(declared), Some(recorded)) if declared != recorded);
+    for dir in package.files().iter().filter(|file| file.is_dir) {
+        for existing in FileEntry::find_dirs_by_path(conn, &dir.path)? {
+            let Some(trove) = Trove::find_by_id(conn, existing.trove_id)? else {
+                continue;
+            };
+            if !exempt(&trove)
+                && (differs(&dir.owner, &existing.owner)
+                    || differs(&dir.group, &existing.group_name))
+            {
+                return Err(Error::ConflictError(format!(
+                    "{} {} declares directory {} as {}:{}, but installed package {} {} owns it as {}:{}",
+                    package.name(),
+                    package.version(),
+                    dir.path,
+                    dir.owner.as_deref().unwrap_or("-"),
+                    dir.group.as_deref().unwrap_or("-"),
+                    trove.name,
+                    trove.version,
+                    existing.owner.as_deref().unwrap_or("-"),
+                    existing.group_name.as_deref().unwrap_or("-")
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::TroveType;
+
+    #[test]
+    fn test_find_trove_to_replace_downgrade() {
+        let mut installed =
+            Trove::new("hello".to_string(), "2.0-1".to_string(), TroveType::Package);
+        installed.architecture = Some("x86_64".to_string());
+        let existing = vec![installed];
+
+        // Upgrades replace the installed trove
+        let (trove, downgrade) = find_trove_to_replace(
+            &existing,
+            "hello",
+            "2.1-1",
+            Some("x86_64"),
+            PackageFormatType::Rpm,
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(trove.version, "2.0-1");
+        assert!(!downgrade);
+
+        // Downgrades are refused unless allowed
+        let err = find_trove_to_replace(
+            &existing,
+            "hello",
+            "1.9-1",
+            Some("x86_64"),
+            PackageFormatType::Rpm,
+            false,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Cannot downgrade"));
+        let (_, downgrade) = find_trove_to_replace(
+            &existing,
+            "hello",
+            "1.9-1",
+            Some("x86_64"),
+            PackageFormatType::Rpm,
+            true,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(downgrade);
+
+        // The same version is refused even when downgrades are allowed
+        assert!(
+            find_trove_to_replace(
+                &existing,
+                "hello",
+                "2.0-1",
+                Some("x86_64"),
+                PackageFormatType::Rpm,
+                true
+            )
+            .is_err()
+        );
+
+        // Other architectures are installed side by side
+        assert!(
+            find_trove_to_replace(
+                &existing,
+                "hello",
+                "1.9-1",
+                Some("i686"),
+                PackageFormatType::Rpm,
+                false
+            )
+            .unwrap()
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_constraint_matches_in_package_scheme() {
+        use version::VersionScheme;
+
+        assert!(constraint_matches(None, VersionScheme::Arch, "1.0a"));
+        assert!(constraint_matches(
+            Some(">=1.0"),
+            VersionScheme::Rpm,
+            "1.0a"
+        ));
+        assert!(!constraint_matches(
+            Some(">=1.0"),
+            VersionScheme::Arch,
+            "1.0a"
+        ));
+        assert!(constraint_matches(
+            Some("<< 2.0"),
+            VersionScheme::Debian,
+            "2.0~beta1-1"
+        ));
+        assert!(!constraint_matches(
+            Some(">> 2.0"),
+            VersionScheme::Debian,
+            "2.0~beta1-1"
+        ));
+        // Unparseable constraints match
+        assert!(constraint_matches(Some(">="), VersionScheme::Debian, "1.0"));
+    }
+}
@@ -0,0 +1,534 @@
+// src/installer/payload.rs
+
+//! Package payloads on their way to disk
+//!
+//! A payload is streamed into the CAS while it is extracted, recorded as
+//! content, file and history rows inside the changeset transaction, and
+//! deployed from the CAS once the transaction has committed. Directories
+//! are recorded as files without content and created first.
+
+use crate::db::models::{FileContents, InstallModifiers};
+use crate::filesystem::FileDeployer;
+use crate::packages::PackageFormat;
+use crate::packages::traits::{FileStream, PayloadFile};
+use crate::timing::{Instrument, Phase};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use tracing::warn;
+
+/// Total size of the files of a trove about to be replaced or removed
+pub fn trove_file_size(
+    conn: &rusqlite::Connection,
+    trove: Option<&crate::db::models::Trove>,
+) -> crate::Result<i64> {
+    let Some(trove_id) = trove.and_then(|trove| trove.id) else {
+        return Ok(0);
+    };
+
+    Ok(crate::db::models::FileEntry::find_by_trove(conn, trove_id)?
+        .iter()
+        .map(|file| file.size)
+        .sum())
+}
+
+/// Stored hashes of the files of a trove about to be replaced, keyed by path
+pub(super) fn old_file_hashes(
+    conn: &rusqlite::Connection,
+    old_trove: Option<&crate::db::models::Trove>,
+) -> crate::Result<HashMap<String, String>> {
+    let Some(old_id) = old_trove.and_then(|old| old.id) else {
+        return Ok(HashMap::new());
+    };
+
+    Ok(crate::db::models::FileEntry::find_by_trove(conn, old_id)?
+        .into_iter()
+        .filter_map(|file| Some((file.path, file.sha256_hash?)))
+        .collect())
+}
+
+/// Directories owned by a trove, deepest first
+pub(crate) fn trove_dirs(
+    conn: &rusqlite::Connection,
+    trove: &crate::db::models::Trove,
+) -> crate::Result<Vec<String>> {
+    let Some(trove_id) = trove.id else {
+        return Ok(Vec::new());
+    };
+
+    let mut dirs: Vec<String> = crate::db::models::FileEntry::find_by_trove(conn, trove_id)?
+        .into_iter()
+        .filter(|file| file.file_type == crate::db::models::FileType::Directory)
+        .map(|file| file.path)
+        .collect();
+    dirs.sort_by(|a, b| b.cmp(a));
+    Ok(dirs)
+}
+
+/// Remove the directories of removed troves that are empty and that no installed trove owns
+///
+/// `dirs` come from [`trove_dirs`], deepest first, so a directory emptied
+/// by removing the one inside it goes too. A directory that cannot be
+/// removed is left in place with a warning.
+pub(crate) fn remove_unowned_dirs(
+    conn: &rusqlite::Connection,
+    deployer: &FileDeployer,
+    dirs: &[String],
+) -> crate::Result<()> {
+    for dir in dirs {
+        if crate::db::models::FileEntry::find_by_path(conn, dir)?.is_some() {
+            continue;
+        }
+        if let Err(e) = deployer.remove_empty_dir(dir) {
+            warn!("Leaving directory {} in place: {}", dir, e);
+        }
+    }
+    Ok(())
+}
+
+/// The files of a trove being removed that go from the root with it
+///
+/// Directories are left to [`remove_unowned_dirs`] and files the install
+/// excluded were never deployed. A config file edited since it was
+/// installed stays in place, with a warning, as the user's copy.
+pub(crate) fn trove_files_to_delete(
+    conn: &rusqlite::Connection,
+    deployer: &FileDeployer,
+    trove: &crate::db::models::Trove,
+) -> crate::Result<Vec<crate::db::models::FileEntry>> {
+    let Some(trove_id) = trove.id else {
+        return Ok(Vec::new());
+    };
+
+    let mut files = Vec::new();
+    for file in crate::db::models::FileEntry::find_by_trove(conn, trove_id)? {
+        if file.file_type == crate::db::models::FileType::Directory
+            || trove.install_modifiers.excludes(&file.path)
+        {
+            continue;
+        }
+        if let (true, Some(hash)) = (file.is_config, file.sha256_hash.as_deref())
+            && deployer.is_regular_file(&file.path)
+            && !deployer.verify_file(&file.path, hash)?
+        {
+            warn!(
+                "Keeping {}: it was edited since {} was installed",
+                file.path, trove.name
+            );
+            continue;
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Record the files a removal deletes in the changeset's history
+///
+/// Called once the troves are deleted; paths an installed trove took over
+/// are not deleted and not recorded. The deleted content is kept as the
+/// previous hash, so garbage collection holds on to it for the retention
+/// period like replaced content.
+pub(crate) fn record_deleted_files(
+    tx: &rusqlite::Connection,
+    changeset_id: i64,
+    files: &[crate::db::models::FileEntry],
+) -> crate::Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO file_history (changeset_id, path, sha256_hash, action, previous_hash) VALUES (?1, ?2, NULL, 'delete', ?3)",
+    )?;
+    for file in files {
+        if crate::db::models::FileEntry::find_by_path(tx, &file.path)?.is_some() {
+            continue;
+        }
+        stmt.execute(rusqlite::params![
+            changeset_id,
+            &file.path,
+            &file.sha256_hash
+        ])?;
+    }
+    Ok(())
+}
+
+/// Delete the files of removed troves from the root
+///
+/// `files` come from [`trove_files_to_delete`]. A path an installed trove
+/// owns is left alone, and a file that cannot be deleted stays in place
+/// with a warning.
+pub(crate) fn delete_unowned_files(
+    conn: &rusqlite::Connection,
+    deployer: &FileDeployer,
+    files: &[crate::db::models::FileEntry],
+) -> crate::Result<()> {
+    for file in files {
+        if crate::db::models::FileEntry::find_by_path(conn, &file.path)?.is_some() {
+            continue;
+        }
+        if let Err(e) = deployer.remove_file(&file.path) {
+            warn!("Leaving {} in place: {}", file.path, e);
+        }
+    }
+    Ok(())
+}
+
+/// Record a file in the changeset's history, deciding how a config file is handled
+///
+/// A config file edited locally since the previous version was installed
+/// is kept: its current content is saved to the CAS and the decision is
+/// recorded as `config-kept`. Returns true in that case, meaning the new
+/// version must be deployed next to it instead of over it. Any other file
+/// already on disk is recorded as `modify` with the hash of the content
+/// it replaces.
+fn record_file_history(
+    tx: &rusqlite::Connection,
+    deployer: &FileDeployer,
+    changeset_id: i64,
+    file: &PayloadFile,
+    hash: &str,
+    old_hashes: &HashMap<String, String>,
+) -> crate::Result<bool> {
+    let old_hash = old_hashes.get(&file.path).map(String::as_str);
+    if file.is_config && deployer.config_modified(&file.path, old_hash, hash)? {
+        let (local_hash, local_size) = deployer.store_existing(&file.path)?;
+        FileContents::new(local_hash.clone(), local_size).insert(tx)?;
+        tx.execute(
+            "INSERT INTO file_history (changeset_id, path, sha256_hash, action, previous_hash) VALUES (?1, ?2, ?3, 'config-kept', ?4)",
+            rusqlite::params![changeset_id, &file.path, hash, &local_hash],
+        )?;
+        return Ok(true);
+    }
+
+    // A regular file being replaced is saved to the CAS first, so a failed
+    // deployment or a later rollback can put it back
+    let (action, previous_hash) = match deployer.file_exists(&file.path) {
+        true if deployer.is_regular_file(&file.path) => {
+            let (previous_hash, previous_size) = deployer.store_existing(&file.path)?;
+            FileContents::new(previous_hash.clone(), previous_size).insert(tx)?;
+            ("modify", Some(previous_hash))
+        }
+        true => ("modify", None),
+        false => ("add", None),
+    };
+    tx.prepare_cached(
+        "INSERT INTO file_history (changeset_id, path, sha256_hash, action, previous_hash) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(rusqlite::params![changeset_id, &file.path, hash, action, previous_hash])?;
+    Ok(false)
+}
+
+/// Content of an extracted file once it is in the CAS
+pub(super) struct StoredContent {
+    /// None for directories, which have no content
+    pub(super) hash: Option<String>,
+    /// Already in the CAS, or shipped earlier in the same package
+    pub(super) cache_hit: bool,
+}
+
+/// Stream a package's payload into the CAS
+///
+/// Each file is hashed while it is written to a staged object, so only file
+/// metadata stays in memory. The staged objects are synced together and moved
+/// into place once the whole payload has been read. Hardlinks take the
+/// content of the earlier file they point to.
+pub(super) fn store_package_contents(
+    cas: &crate::filesystem::CasStore,
+    package: &dyn PackageFormat,
+    instrument: &Instrument,
+) -> crate::Result<(Vec<PayloadFile>, Vec<StoredContent>)> {
+    use crate::db::models::FileType;
+    use crate::filesystem::StagedObject;
+
+    let mut files: Vec<PayloadFile> = Vec::new();
+    let mut stored: Vec<StoredContent> = Vec::new();
+    let mut by_path: HashMap<String, usize> = HashMap::new();
+    let mut staged: HashMap<String, StagedObject> = HashMap::new();
+
+    // Time spent reading the payload counts as extraction, the rest of
+    // staging as hashing
+    let started = std::time::Instant::now();
+    let mut hashing = std::time::Duration::ZERO;
+    let (mut hashed_files, mut hashed_bytes) = (0, 0);
+
+    let mut stage = |reader: &mut dyn io::Read| -> crate::Result<StoredContent> {
+        let stage_start = std::time::Instant::now();
+        let mut reader = TimedReader {
+            inner: reader,
+            elapsed: std::time::Duration::ZERO,
+        };
+        let (hash, size, object) = cas.stage_reader(&mut reader)?;
+        hashing += stage_start.elapsed().saturating_sub(reader.elapsed);
+        hashed_files += 1;
+        hashed_bytes += size;
+        // Content already in the CAS, or staged earlier in the same package
+        let cache_hit = match object {
+            Some(object) if !staged.contains_key(&hash) => {
+                staged.insert(hash.clone(), object);
+                false
+            }
+            _ => true,
+        };
+        Ok(StoredContent {
+            hash: Some(hash),
+            cache_hit,
+        })
+    };
+
+    package.extract_files(&mut |FileStream { file, content }| {
+        let content = match (file.file_type, &file.link_target) {
+            (FileType::Hardlink, Some(target)) => {
+                let &i = by_path.get(target).ok_or_else(|| {
+                    crate::Error::ParseError(format!(
+                        "Hardlink {} points to unknown file {}",
+                        file.path, target
+                    ))
+                })?;
+                StoredContent {
+                    hash: stored[i].hash.clone(),
+                    cache_hit: true,
+                }
+            }
+            // A symlink's identity is its target, see ExtractedFile::cas_content
+            (FileType::Symlink, Some(target)) => stage(&mut target.as_bytes())?,
+            (FileType::Directory, _) => StoredContent {
+                hash: None,
+                cache_hit: false,
+            },
+            _ => stage(content)?,
+        };
+        by_path.insert(file.path.clone(), files.len());
+        files.push(file);
+        stored.push(content);
+        Ok(())
+    })?;
+
+    cas.commit_staged(staged.into_values().collect())?;
+
+    instrument.add_duration(Phase::Hash, hashing);
+    instrument.add_duration(Phase::Extract, started.elapsed().saturating_sub(hashing));
+    instrument.count(Phase::Hash, hashed_files, hashed_bytes);
+    instrument.count(
+        Phase::Extract,
+        files.len() as u64,
+        files.iter().map(|file| file.size as u64).sum(),
+    );
+    Ok((files, stored))
+}
+
+/// A reader that adds up the time spent in `read`
+struct TimedReader<'a> {
+    inner: &'a mut dyn io::Read,
+    elapsed: std::time::Duration,
+}
+
+impl io::Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = std::time::Instant::now();
+        let result = self.inner.read(buf);
+        self.elapsed += start.elapsed();
+        result
+    }
+}
+
+/// Bytes of the extracted files whose content the CAS already had
+pub(super) fn cache_hit_bytes(files: &[PayloadFile], stored: &[StoredContent]) -> i64 {
+    files
+        .iter()
+        .zip(stored)
+        .filter(|(_, content)| content.cache_hit)
+        .map(|(file, _)| file.size)
+        .sum()
+}
+
+/// Record the content, file and history rows of a newly installed trove
+///
+/// Each table gets one prepared statement for the whole package. Returns
+/// the config files whose local edits are kept.
+pub(super) fn record_installed_files(
+    tx: &rusqlite::Connection,
+    deployer: &FileDeployer,
+    changeset_id: i64,
+    trove_id: i64,
+    files: &[PayloadFile],
+    stored: &[StoredContent],
+    old_hashes: &HashMap<String, String>,
+) -> crate::Result<HashSet<String>> {
+    for (file, content) in files.iter().zip(stored) {
+        if let Some(hash) = &content.hash {
+            FileContents::new(hash.clone(), file.size).insert(tx)?;
+        }
+    }
+
+    let entries: Vec<_> = files
+        .iter()
+        .zip(stored)
+        .map(|(file, content)| {
+            let mut entry = match &content.hash {
+                Some(hash) => crate::db::models::FileEntry::new(
+                    file.path.clone(),
+                    hash.clone(),
+                    file.size,
+                    file.mode,
+                    trove_id,
+                ),
+                None => {
+                    crate::db::models::FileEntry::directory(file.path.clone(), file.mode, trove_id)
+                }
+            };
+            entry.file_type = file.file_type;
+            entry.link_target = file.link_target.clone();
+            entry.owner = file.owner.clone();
+            entry.group_name = file.group.clone();
+            entry.is_config = file.is_config;
+            entry.xattrs = file.xattrs.clone();
+            entry
+        })
+        .collect();
+    crate::db::models::FileEntry::insert_batch(tx, &entries)?;
+
+    // Track in file history; locally edited config files stay in place.
+    // Directories are cleaned up by ownership instead, see remove_unowned_dirs
+    let mut kept_configs = HashSet::new();
+    for (file, content) in files.iter().zip(stored) {
+        let Some(hash) = &content.hash else {
+            continue;
+        };
+        if record_file_history(tx, deployer, changeset_id, file, hash, old_hashes)? {
+            kept_configs.insert(file.path.clone());
+        }
+    }
+    Ok(kept_configs)
+}
+
+/// Work out the mode each directory of a package is deployed with
+///
+/// A directory shared with other troves gets the strictest mode any of its
+/// owners declares, see [`FileEntry::reconciled_dir_mode`]. Taking access
+/// away from an existing directory is warned about and recorded as
+/// `dir-mode` in the file history of `changeset_id`. Excluded directories
+/// are left out.
+pub(super) fn reconcile_dir_modes(
+    conn: &rusqlite::Connection,
+    deployer: &FileDeployer,
+    changeset_id: i64,
+    files: &[PayloadFile],
+    modifiers: &InstallModifiers,
+) -> crate::Result<HashMap<String, u32>> {
+    use crate::db::models::{FileEntry, FileType};
+
+    let mut modes = HashMap::new();
+    for file in files
+        .iter()
+        .filter(|file| file.file_type == FileType::Directory && !modifiers.excludes(&file.path))
+    {
+        let mode =
+            FileEntry::reconciled_dir_mode(conn, &file.path)?.unwrap_or(file.mode) as u32 & 0o7777;
+        if let Some(current) = deployer.dir_mode(&file.path)
+            && current & !mode & 0o777 != 0
+        {
+            warn!(
+                "Tightening {} from {:04o} to {:04o}, the strictest mode its packages declare",
+                file.path, current, mode
+            );
+            conn.execute(
+                "INSERT INTO file_history (changeset_id, path, action) VALUES (?1, ?2, 'dir-mode')",
+                rusqlite::params![changeset_id, file.path],
+            )?;
+        }
+        modes.insert(file.path.clone(), mode);
+    }
+    Ok(modes)
+}
+
+/// Deploy extracted package files to the filesystem
+///
+/// Directories are created first with their mode from `dir_modes`, and
+/// hardlinks last so the files they point to already exist.
+/// Config files in `kept_configs` keep their local edits; the new version is
+/// written next to them with the `.conarynew` suffix. Files excluded by
+/// `modifiers` are skipped.
+///
+/// Content this install put in the CAS was hashed as it was staged and is
+/// copied out as is; only cache hits, whose objects were there before, are
+/// hashed again to verify them. Returns the files and bytes verified.
+pub(super) fn deploy_extracted_files(
+    deployer: &FileDeployer,
+    extracted_files: &[PayloadFile],
+    stored: &[StoredContent],
+    kept_configs: &HashSet<String>,
+    dir_modes: &HashMap<String, u32>,
+    modifiers: &InstallModifiers,
+) -> crate::Result<(u64, u64)> {
+    use crate::db::models::FileType;
+
+    let targets: Vec<Option<String>> = extracted_files
+        .iter()
+        .map(|file| {
+            if modifiers.excludes(&file.path) {
+                None
+            } else if kept_configs.contains(&file.path) {
+                Some(format!(
+                    "{}{}",
+                    file.path,
+                    crate::filesystem::CONFIG_NEW_SUFFIX
+                ))
+            } else {
+                Some(file.path.clone())
+            }
+        })
+        .collect();
+    let deployed = || {
+        extracted_files
+            .iter()
+            .zip(stored)
+            .zip(&targets)
+            .filter_map(|((file, content), path)| Some((file, content, path.as_deref()?)))
+    };
+
+    for (file, _, path) in deployed().filter(|(file, _, _)| file.file_type == FileType::Directory) {
+        deployer.deploy_dir(
+            path,
+            dir_modes
+                .get(&file.path)
+                .copied()
+                .unwrap_or(file.mode as u32),
+        )?;
+    }
+
+    // Regular files go out in one batch, then links in the order tar gave them
+    let regular: Vec<_> = deployed()
+        .filter_map(
+            |(file, content, path)| match (file.file_type, &content.hash) {
+                (FileType::Regular, Some(hash)) => {
+                    Some((path, hash.as_str(), file.mode as u32, content.cache_hit))
+                }
+                _ => None,
+            },
+        )
+        .collect();
+    let verified_files = regular.iter().filter(|(_, _, _, verify)| *verify).count() as u64;
+    let verified_bytes = deployer.deploy_files(&regular)?;
+
+    let mut links: Vec<_> = deployed()
+        .filter(|(file, _, _)| matches!(file.file_type, FileType::Symlink | FileType::Hardlink))
+        .collect();
+    links.sort_by_key(|(file, _, _)| file.file_type == FileType::Hardlink);
+    for (file, content, path) in links {
+        deployer.deploy(
+            path,
+            content.hash.as_deref().unwrap_or_default(),
+            file.mode as u32,
+            file.file_type,
+            file.link_target.as_deref(),
+        )?;
+    }
+
+    for (file, _, path) in deployed() {
+        deployer.apply_ownership(
+            path,
+            file.mode as u32,
+            file.file_type,
+            file.owner.as_deref(),
+            file.group.as_deref(),
+        )?;
+        deployer.apply_xattrs(path, file.file_type, &file.xattrs)?;
+    }
+    Ok((verified_files, verified_bytes))
+}
@@ -0,0 +1,793 @@
+// src/installer/prepared.rs
+
+//! Packages staged for a changeset
+//!
+//! A `PreparedInstall` carries a parsed package from the moment it is opened
+//! until its trove is recorded. `apply_installs` records a whole batch of
+//! them in one changeset and deploys their files afterwards.
+
+use super::check_package_conflicts;
+use super::payload::{
+    StoredContent, cache_hit_bytes, deploy_extracted_files, old_file_hashes, reconcile_dir_modes,
+    record_installed_files, store_package_contents, trove_file_size,
+};
+use super::transitions::{self, Transition};
+use crate::db;
+use crate::db::models::{
+    Changeset, ChangesetStatus, DependencyEntry, FileEntry, FileType, InstallModifiers,
+    InstallReason, Provenance, ProvideEntry, RelationEntry, RemovalReason, RemovedTrove,
+    RepositoryPackage, Trove,
+};
+use crate::error::{Error, Result};
+use crate::filesystem::FileDeployer;
+use crate::packages::traits::{DependencyType, PayloadFile, Scriptlet, ScriptletPhase};
+use crate::packages::{self, PackageFormat, PackageFormatType, detect_package_format};
+use crate::scriptlet::{self, Operation, ScriptletContext, ScriptletRunner};
+use crate::stats;
+use crate::timing::{Instrument, Phase};
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Store a package's conflicts and replaces for its installed trove
+fn record_relations(tx: &Connection, trove_id: i64, package: &dyn PackageFormat) -> Result<()> {
+    let relations = [
+        ("conflicts", package.conflicts()),
+        ("replaces", package.replaces()),
+    ];
+    for (kind, entries) in relations {
+        for entry in entries {
+            RelationEntry::new(
+                trove_id,
+                kind.to_string(),
+                entry.name.clone(),
+                entry.version.clone(),
+            )
+            .insert(tx)?;
+        }
+    }
+    Ok(())
+}
+
+/// A parsed package on its way into a changeset
+///
+/// `Installer::prepare` only parses the package; the caller may then pick
+/// the trove it replaces and why it is installed. Staging runs the conflict
+/// checks and streams the payload into the CAS, so a whole batch can fail
+/// before anything is recorded or deployed.
+pub struct PreparedInstall {
+    path: PathBuf,
+    package: Box<dyn PackageFormat>,
+    format: PackageFormatType,
+    pub(super) old_trove: Option<Trove>,
+    pub(super) is_downgrade: bool,
+    /// Installed next to the other versions of an installonly package
+    pub(super) alongside: bool,
+    reason: InstallReason,
+    downloaded_bytes: i64,
+    files: Vec<PayloadFile>,
+    stored: Vec<StoredContent>,
+    /// Scriptlets of the version being replaced, loaded before its trove is deleted
+    old_scriptlets: Option<(PackageFormatType, Vec<Scriptlet>)>,
+    /// Version the repository advertised, when the package header disagrees
+    repository_version: Option<String>,
+    /// Repository ID and URL the package was downloaded from
+    origin: Option<(i64, String)>,
+    /// Files left undeployed, recorded with the trove
+    modifiers: InstallModifiers,
+    /// Paths changing between a directory and a file on the way from the old version
+    transitions: Vec<Transition>,
+}
+
+impl std::fmt::Debug for PreparedInstall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PreparedInstall")
+            .field("path", &self.path)
+            .field("name", &self.package.name())
+            .field("version", &self.package.version())
+            .field("reason", &self.reason)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PreparedInstall {
+    /// Parse a package file, auto-detecting its format
+    pub(super) fn open(package_path: &Path, instrument: &Instrument) -> Result<Self> {
+        let format = detect_package_format(&package_path.to_string_lossy())?;
+        let package = instrument.time(Phase::Parse, || packages::open_package(package_path))?;
+        let package_size = std::fs::metadata(package_path).map_or(0, |meta| meta.len());
+        instrument.count(Phase::Parse, package.files().len() as u64, package_size);
+
+        info!(
+            "Parsed package: {} version {} ({} files, {} dependencies)",
+            package.name(),
+            package.version(),
+            package.files().len(),
+            package.dependencies().len()
+        );
+
+        Ok(Self {
+            path: package_path.to_path_buf(),
+            package,
+            format,
+            old_trove: None,
+            is_downgrade: false,
+            alongside: false,
+            reason: InstallReason::Explicit,
+            downloaded_bytes: 0,
+            files: Vec::new(),
+            stored: Vec::new(),
+            old_scriptlets: None,
+            repository_version: None,
+            origin: None,
+            modifiers: InstallModifiers::default(),
+            transitions: Vec::new(),
+        })
+    }
+
+    /// The package file it was parsed from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The parsed package
+    pub fn package(&self) -> &dyn PackageFormat {
+        self.package.as_ref()
+    }
+
+    /// The format of the package file
+    pub fn format(&self) -> PackageFormatType {
+        self.format
+    }
+
+    /// The installed trove this package takes the place of, if any
+    pub fn old_trove(&self) -> Option<&Trove> {
+        self.old_trove.as_ref()
+    }
+
+    pub fn reason(&self) -> InstallReason {
+        self.reason
+    }
+
+    /// Record the package as installed for `reason` (explicit by default)
+    pub fn set_reason(&mut self, reason: InstallReason) {
+        self.reason = reason;
+    }
+
+    /// Replace `old` with this package, keeping the reason `old` was installed for
+    ///
+    /// No version check is made; `Installer::install_batch` only looks for
+    /// a trove to replace when none was chosen here.
+    pub fn replace(&mut self, old: &Trove) {
+        self.reason = old.install_reason;
+        self.old_trove = Some(old.clone());
+        self.is_downgrade = false;
+    }
+
+    /// Record but do not deploy the files matching any of `patterns`
+    ///
+    /// Only the globs that match a file of the package are kept with the trove.
+    pub fn exclude(&mut self, patterns: &[String]) {
+        let package = &self.package;
+        self.modifiers.excluded = patterns
+            .iter()
+            .filter(|pattern| {
+                package
+                    .files()
+                    .iter()
+                    .any(|file| crate::query::glob_match(pattern, &file.path))
+            })
+            .cloned()
+            .collect();
+    }
+
+    /// Count the size of the downloaded package file towards the changeset
+    pub fn set_downloaded_bytes(&mut self, bytes: i64) {
+        self.downloaded_bytes = bytes;
+    }
+
+    /// Refuse the package if it conflicts with an installed trove
+    pub fn check_conflicts(&self, conn: &Connection) -> Result<()> {
+        check_package_conflicts(
+            conn,
+            self.package.as_ref(),
+            self.format,
+            self.old_trove.as_ref(),
+        )
+    }
+
+    /// Note the repository entry this package was downloaded for
+    ///
+    /// The trove records the repository and URL as its origin. Rebuilds and
+    /// stale mirrors can also leave the metadata a release behind the
+    /// package itself: the header version is installed, and the advertised
+    /// one is kept so that updates do not keep offering it.
+    pub fn downloaded_for(&mut self, selected: &RepositoryPackage) {
+        self.origin = Some((selected.repository_id, selected.download_url.clone()));
+
+        let package = &self.package;
+        let arch_differs = matches!(
+            (package.architecture(), selected.architecture.as_deref()),
+            (Some(parsed), Some(advertised)) if parsed != advertised
+        );
+        if package.name() == selected.name && package.version() == selected.version && !arch_differs
+        {
+            return;
+        }
+
+        warn!(
+            "Repository lists {} {} ({}) but the downloaded package is {} {} ({}); installing the package's own version",
+            selected.name,
+            selected.version,
+            selected.architecture.as_deref().unwrap_or("none"),
+            package.name(),
+            package.version(),
+            package.architecture().unwrap_or("none")
+        );
+        if package.version() != selected.version {
+            self.repository_version = Some(selected.version.clone());
+        }
+    }
+
+    /// Find the paths an upgrade changes between a directory and a file
+    ///
+    /// Nothing on disk changes until the package is deployed; a directory
+    /// holding files the old version does not own is refused unless
+    /// `allow_dir_replace` is set.
+    pub(super) fn plan_transitions(
+        &mut self,
+        conn: &Connection,
+        deployer: &FileDeployer,
+        allow_dir_replace: bool,
+    ) -> Result<()> {
+        if self.old_trove.is_none() {
+            return Ok(());
+        }
+        let old_hashes = old_file_hashes(conn, self.old_trove.as_ref())?;
+        self.transitions = transitions::plan_transitions(
+            deployer,
+            self.package.as_ref(),
+            &self.modifiers,
+            &old_hashes,
+            allow_dir_replace,
+        )?;
+        Ok(())
+    }
+
+    /// Refuse an upgrade that swaps a directory and a file, which cannot be staged
+    ///
+    /// Moving a pending tree into place only adds and replaces files.
+    pub(super) fn refuse_transitions(&self) -> Result<()> {
+        match self.transitions.first() {
+            Some(transition) => Err(Error::ConflictError(format!(
+                "{} {} changes {} between a directory and a file, which cannot be staged; \
+                 install it with staged_deploy off",
+                self.package.name(),
+                self.package.version(),
+                transition.path()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Directories moved aside to make room for a file of this package
+    pub(super) fn saved_dirs(&self) -> Vec<String> {
+        self.transitions
+            .iter()
+            .filter_map(Transition::saved_path)
+            .collect()
+    }
+
+    /// Check the package against installed troves and store its payload in the CAS
+    pub(super) fn stage(
+        &mut self,
+        conn: &Connection,
+        deployer: &FileDeployer,
+        instrument: &Instrument,
+    ) -> Result<()> {
+        self.check_conflicts(conn)?;
+
+        self.old_scriptlets = match self.old_trove.as_ref().and_then(|old| old.id) {
+            Some(old_id) => scriptlet::load(conn, old_id)?,
+            None => None,
+        };
+
+        // Stream file contents into the CAS so the transaction only writes rows
+        info!("Extracting file contents from {}...", self.package.name());
+        let (files, stored) =
+            store_package_contents(deployer.cas(), self.package.as_ref(), instrument)?;
+        info!("Extracted {} files", files.len());
+        self.files = files;
+        self.stored = stored;
+        Ok(())
+    }
+
+    /// Files extracted by staging that are deployed, leaving out exclusions
+    pub(super) fn deployed_files(&self) -> impl Iterator<Item = &PayloadFile> {
+        self.files
+            .iter()
+            .filter(|file| !self.modifiers.excludes(&file.path))
+    }
+
+    pub(super) fn modifiers(&self) -> &InstallModifiers {
+        &self.modifiers
+    }
+
+    /// Changeset description when this package is installed on its own
+    fn description(&self) -> String {
+        let description = self.action();
+        match &self.repository_version {
+            Some(claimed) => format!("{} (repository version {})", description, claimed),
+            None => description,
+        }
+    }
+
+    fn action(&self) -> String {
+        let package = &self.package;
+        match &self.old_trove {
+            Some(old) if old.name != package.name() => {
+                format!(
+                    "Replace {} {} with {}-{}",
+                    old.name,
+                    old.version,
+                    package.name(),
+                    package.version()
+                )
+            }
+            Some(old) => format!(
+                "{} {} from {} to {}",
+                if self.is_downgrade {
+                    "Downgrade"
+                } else {
+                    "Upgrade"
+                },
+                package.name(),
+                old.version,
+                package.version()
+            ),
+            None => format!("Install {}-{}", package.name(), package.version()),
+        }
+    }
+
+    /// Scriptlet context for the new version
+    fn new_ctx<'a>(
+        &'a self,
+        root: &'a Path,
+        runner: &'a dyn ScriptletRunner,
+    ) -> ScriptletContext<'a> {
+        ScriptletContext {
+            root,
+            runner,
+            format: self.format,
+            operation: if self.old_trove.is_some() {
+                Operation::Upgrade
+            } else {
+                Operation::Install
+            },
+            version: self.package.version(),
+            other_version: self.old_trove.as_ref().map(|old| old.version.as_str()),
+        }
+    }
+
+    /// Scriptlets of the replaced version with their context, if it had any
+    fn old_ctx<'a>(
+        &'a self,
+        root: &'a Path,
+        runner: &'a dyn ScriptletRunner,
+    ) -> Option<(&'a [Scriptlet], ScriptletContext<'a>)> {
+        let ((old_format, scriptlets), old) =
+            self.old_scriptlets.as_ref().zip(self.old_trove.as_ref())?;
+        Some((
+            scriptlets.as_slice(),
+            ScriptletContext {
+                root,
+                runner,
+                format: *old_format,
+                operation: Operation::Upgrade,
+                version: &old.version,
+                other_version: Some(self.package.version()),
+            },
+        ))
+    }
+
+    /// Record this package's trove and files under `changeset_id`
+    ///
+    /// `claimed` maps the paths recorded so far in the changeset to their
+    /// package, so two packages of one batch cannot ship the same file.
+    /// Returns the config files whose local edits are kept.
+    fn record<'a>(
+        &'a self,
+        tx: &Connection,
+        deployer: &FileDeployer,
+        changeset_id: i64,
+        claimed: &mut HashMap<&'a str, &'a str>,
+    ) -> Result<HashSet<String>> {
+        let package = self.package.as_ref();
+        let old_trove = self.old_trove.as_ref();
+
+        // Hashes of the version being replaced tell local config edits apart
+        let old_hashes = old_file_hashes(tx, old_trove)?;
+        let old_size = trove_file_size(tx, old_trove)?;
+
+        // If upgrading, remove the old trove first
+        if let Some(old) = old_trove
+            && let Some(old_id) = old.id
+        {
+            info!("Removing old version {} before upgrade", old.version);
+            RemovedTrove::new(changeset_id, old, RemovalReason::Replaced).insert(tx)?;
+            Trove::delete(tx, old_id)?;
+        }
+
+        // Packages recorded earlier in the same changeset count as installed
+        check_package_conflicts(tx, package, self.format, old_trove)?;
+
+        // Convert to Trove and associate with changeset
+        let mut trove = package.to_trove();
+        trove.installed_by_changeset_id = Some(changeset_id);
+        trove.install_reason = self.reason;
+        if let Some((repository_id, url)) = &self.origin {
+            trove.origin_repository_id = Some(*repository_id);
+            trove.origin_url = Some(url.clone());
+        }
+        trove.install_modifiers = self.modifiers.clone();
+        let new_size: i64 = self.files.iter().map(|file| file.size).sum();
+        trove.installed_size = new_size;
+        let trove_id = trove.insert(tx)?;
+        if let Some(claimed) = &self.repository_version {
+            let mut provenance = Provenance::new(trove_id);
+            provenance.repository_version = Some(claimed.clone());
+            provenance.insert(tx)?;
+        }
+
+        // Check every file for conflicts before recording any
+        let mut shared = Vec::new();
+        for (file, content) in self.files.iter().zip(&self.stored) {
+            // Directories are shared; only a file another package owns there conflicts
+            if file.file_type == FileType::Directory {
+                if let Some(existing) = FileEntry::find_by_path(tx, &file.path)?
+                    && existing.file_type != FileType::Directory
+                    && let Some(owner) = Trove::find_by_id(tx, existing.trove_id)?
+                    && owner.name != package.name()
+                {
+                    return Err(Error::FileConflict {
+                        path: file.path.clone(),
+                        owner: Some(owner.name),
+                    });
+                }
+                continue;
+            }
+
+            if let Some(other) = claimed.insert(file.path.as_str(), package.name()) {
+                return Err(Error::FileConflict {
+                    path: file.path.clone(),
+                    owner: Some(other.to_string()),
+                });
+            }
+
+            // Versions side by side share a path only with the same content; the new one takes it over
+            if self.alongside
+                && let Some(existing) = FileEntry::find_by_path(tx, &file.path)?
+                && let Some(owner) = Trove::find_by_id(tx, existing.trove_id)?
+                && owner.name == package.name()
+            {
+                if existing.sha256_hash != content.hash || existing.link_target != file.link_target
+                {
+                    return Err(Error::FileConflict {
+                        path: file.path.clone(),
+                        owner: Some(format!("{} {}", owner.name, owner.version)),
+                    });
+                }
+                shared.push((file.path.as_str(), existing.trove_id));
+                continue;
+            }
+
+            // Conflict detection (skip if upgrading same package); excluded files are not written
+            if deployer.file_exists(&file.path) && !self.modifiers.excludes(&file.path) {
+                if let Some(existing) = FileEntry::find_by_path(tx, &file.path)? {
+                    let owner_trove = Trove::find_by_id(tx, existing.trove_id)?;
+                    if let Some(owner) = owner_trove
+                        && owner.name != package.name()
+                    {
+                        return Err(Error::FileConflict {
+                            path: file.path.clone(),
+                            owner: Some(owner.name),
+                        });
+                    }
+                } else if old_trove.is_none() {
+                    // Only error on orphans for fresh installs, not upgrades
+                    return Err(Error::FileConflict {
+                        path: file.path.clone(),
+                        owner: None,
+                    });
+                }
+            }
+        }
+
+        // The earlier version gets the path back if the new one goes first
+        for (path, owner_id) in shared {
+            FileEntry::record_sharer(tx, path, owner_id)?;
+            FileEntry::delete(tx, path)?;
+        }
+
+        // Record content, file and history rows in one pass
+        let kept_configs = record_installed_files(
+            tx,
+            deployer,
+            changeset_id,
+            trove_id,
+            &self.files,
+            &self.stored,
+            &old_hashes,
+        )?;
+        for transition in &self.transitions {
+            transition.record(tx, changeset_id)?;
+        }
+        let cache_hit_bytes = cache_hit_bytes(&self.files, &self.stored);
+
+        Changeset::add_sizes(tx, changeset_id, self.downloaded_bytes, new_size - old_size)?;
+
+        // Record operation statistics
+        let op_metric = if old_trove.is_some() {
+            stats::Metric::PackagesUpdated
+        } else {
+            stats::Metric::PackagesInstalled
+        };
+        stats::record(tx, op_metric, None, 1)?;
+        stats::record(tx, stats::Metric::CacheHitBytes, None, cache_hit_bytes)?;
+
+        // Store dependencies in database
+        for dep in package.dependencies() {
+            let dep_type_str = match dep.dep_type {
+                DependencyType::Runtime => "runtime",
+                DependencyType::Build => "build",
+                DependencyType::Optional => "optional",
+            };
+
+            let mut dep_entry = DependencyEntry::new(
+                trove_id,
+                dep.name.clone(),
+                dep.version.clone(),
+                dep_type_str.to_string(),
+                None,
+            );
+            dep_entry.insert(tx)?;
+        }
+
+        // Store provided capabilities so dependents can resolve against them
+        for provide in package.provides() {
+            ProvideEntry::new(trove_id, provide.name.clone(), provide.version.clone())
+                .insert(tx)?;
+        }
+        record_relations(tx, trove_id, package)?;
+
+        // Keep scriptlets so removal can run them later
+        scriptlet::store(tx, trove_id, self.format, package.scriptlets())?;
+
+        Ok(kept_configs)
+    }
+}
+
+/// Undo a changeset whose files could not all be deployed
+///
+/// The changeset is rolled back as `conary rollback` would: the files it
+/// added go and the files it replaced get their earlier content back from
+/// the CAS, which recording saved there. Files the deployer already put
+/// back are left alone, and nothing on disk counts as a local change, since
+/// the changeset never finished deploying. If the rollback fails too, the
+/// changeset stays applied for `verify --repair` to finish.
+fn roll_back_failed(conn: &mut Connection, deployer: &FileDeployer, changeset_id: i64) {
+    use crate::rollback::{FileAction, RollbackPlan};
+
+    let rolled_back =
+        RollbackPlan::build(conn, deployer, changeset_id, true).and_then(|mut plan| {
+            plan.files.retain(|file| match &file.action {
+                FileAction::Restore { hash } => {
+                    !deployer.verify_file(&file.path, hash).unwrap_or(false)
+                }
+                _ => true,
+            });
+            for file in &mut plan.files {
+                file.modified = false;
+            }
+            plan.execute(conn, deployer)
+        });
+    match rolled_back {
+        Ok(_) => warn!(
+            "Deployment failed; changeset {} was rolled back",
+            changeset_id
+        ),
+        Err(e) => warn!(
+            "Deployment failed and changeset {} could not be rolled back: {}; run 'conary verify --repair'",
+            changeset_id, e
+        ),
+    }
+}
+
+/// Apply a batch of staged packages as a single changeset
+///
+/// All troves are recorded in one transaction, so a conflict in any package
+/// leaves the database untouched and nothing is deployed; rolling back the
+/// changeset removes every package of the batch. The phase timings collected
+/// by `instrument` are stored with the changeset. With `staging`, the
+/// changeset is recorded as staged, its files go to its pending tree under
+/// that directory and no scriptlets run. A deployment that fails partway
+/// is undone and its changeset rolled back. Returns the changeset ID and, per
+/// package, the config files whose local edits were kept.
+pub(super) fn apply_installs(
+    conn: &mut Connection,
+    deployer: &FileDeployer,
+    root: &Path,
+    batch: &[PreparedInstall],
+    scripts: Option<&dyn ScriptletRunner>,
+    instrument: &Instrument,
+    staging: Option<&Path>,
+) -> Result<(i64, Vec<HashSet<String>>)> {
+    let has_scriptlets = batch.iter().any(|prepared| {
+        !prepared.package.scriptlets().is_empty() || prepared.old_scriptlets.is_some()
+    });
+    if staging.is_some() && scripts.is_some() && has_scriptlets {
+        warn!("Scriptlets are not run for staged changesets");
+    }
+    let run_scripts = scripts.is_some() && staging.is_none();
+    let runner = scripts.unwrap_or(&scriptlet::DirectRunner);
+
+    let changeset_desc = match batch {
+        [single] => single.description(),
+        _ => format!(
+            "Install {} packages: {}",
+            batch.len(),
+            batch
+                .iter()
+                .map(|prepared| match &prepared.repository_version {
+                    Some(claimed) => format!(
+                        "{}-{} (repository version {})",
+                        prepared.package.name(),
+                        prepared.package.version(),
+                        claimed
+                    ),
+                    None => format!("{}-{}", prepared.package.name(), prepared.package.version()),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    // Pre-phase scriptlets run before anything is touched; a failure aborts the changeset
+    let mut outcomes = Vec::new();
+    if run_scripts {
+        let pre = batch.iter().try_for_each(|prepared| {
+            let name = prepared.package.name();
+            if let Some((scriptlets, ctx)) = prepared.old_ctx(root, runner) {
+                scriptlet::run_checked(
+                    scriptlets,
+                    ScriptletPhase::PreRemove,
+                    &ctx,
+                    name,
+                    &mut outcomes,
+                )?;
+            }
+            let ctx = prepared.new_ctx(root, runner);
+            scriptlet::run_checked(
+                prepared.package.scriptlets(),
+                ScriptletPhase::PreInstall,
+                &ctx,
+                name,
+                &mut outcomes,
+            )
+        });
+        if let Err(e) = pre {
+            scriptlet::record_aborted(conn, &changeset_desc, &outcomes)?;
+            return Err(e);
+        }
+    }
+
+    // Perform installation within a changeset transaction
+    fail_point!("install::before-record");
+    let record_start = std::time::Instant::now();
+    let (changeset_id, kept_configs) = db::transaction(conn, |tx| {
+        let mut changeset = Changeset::new(changeset_desc.clone());
+        let changeset_id = changeset.insert(tx)?;
+        scriptlet::record_outcomes(tx, changeset_id, &outcomes)?;
+
+        let mut claimed = HashMap::new();
+        let kept_configs = batch
+            .iter()
+            .map(|prepared| prepared.record(tx, deployer, changeset_id, &mut claimed))
+            .collect::<Result<Vec<_>>>()?;
+
+        let status = if staging.is_some() {
+            ChangesetStatus::Staged
+        } else {
+            ChangesetStatus::Applied
+        };
+        changeset.update_status(tx, status)?;
+
+        Ok((changeset_id, kept_configs))
+    })?;
+    instrument.add_duration(Phase::Record, record_start.elapsed());
+    for prepared in batch {
+        instrument.count(Phase::Record, prepared.files.len() as u64, 0);
+    }
+    fail_point!("install::after-record");
+
+    // Deploy files to filesystem (outside transaction for safety)
+    let pending;
+    let deployer = match staging {
+        Some(dir) => {
+            let tree = crate::staged::pending_tree(dir, changeset_id);
+            pending = FileDeployer::new(deployer.cas().objects_dir(), tree.as_path())?;
+            &pending
+        }
+        None => deployer,
+    };
+    let deployed = batch
+        .iter()
+        .zip(&kept_configs)
+        .try_for_each(|(prepared, kept)| {
+            info!("Deploying files of {}...", prepared.package.name());
+            let (verified_files, verified_bytes) = instrument.time(Phase::Deploy, || {
+                for transition in &prepared.transitions {
+                    transition.apply(deployer)?;
+                }
+                let dir_modes = reconcile_dir_modes(
+                    conn,
+                    deployer,
+                    changeset_id,
+                    &prepared.files,
+                    &prepared.modifiers,
+                )?;
+                deploy_extracted_files(
+                    deployer,
+                    &prepared.files,
+                    &prepared.stored,
+                    kept,
+                    &dir_modes,
+                    &prepared.modifiers,
+                )
+            })?;
+            // Verifying content already in the CAS hashes it again; the time is part of deploying
+            instrument.count(Phase::Hash, verified_files, verified_bytes);
+            let deployed = prepared.deployed_files().count();
+            info!("Successfully deployed {} files", deployed);
+
+            let bytes = prepared.deployed_files().map(|file| file.size as u64).sum();
+            instrument.count(Phase::Deploy, deployed as u64, bytes);
+            Ok::<_, Error>(())
+        });
+    if let Err(e) = deployed {
+        if staging.is_none() {
+            roll_back_failed(conn, deployer, changeset_id);
+        }
+        return Err(e);
+    }
+
+    // Post-phase scriptlets run once the files are in place
+    if run_scripts {
+        let mut post_outcomes = Vec::new();
+        for prepared in batch {
+            let name = prepared.package.name();
+            if let Some((scriptlets, ctx)) = prepared.old_ctx(root, runner) {
+                scriptlet::run_checked(
+                    scriptlets,
+                    ScriptletPhase::PostRemove,
+                    &ctx,
+                    name,
+                    &mut post_outcomes,
+                )?;
+            }
+            let ctx = prepared.new_ctx(root, runner);
+            scriptlet::run_checked(
+                prepared.package.scriptlets(),
+                ScriptletPhase::PostInstall,
+                &ctx,
+                name,
+                &mut post_outcomes,
+            )?;
+        }
+        scriptlet::record_outcomes(conn, changeset_id, &post_outcomes)?;
+    }
+
+    Changeset::set_timings(conn, changeset_id, &instrument.report())?;
+    Ok((changeset_id, kept_configs))
+}
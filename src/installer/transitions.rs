@@ -0,0 +1,229 @@
+// src/installer/transitions.rs
+
+//! Paths that change between a directory and a file across versions
+//!
+//! Packages record their files but not always the directories holding
+//! them, so directories are often implicit: a version shipping
+//! `/usr/lib/foo/a` has a directory at `/usr/lib/foo`, and the next one
+//! may ship `/usr/lib/foo` itself as a file or symlink.
+//! The reverse happens too. Neither can be deployed over what is on disk,
+//! so [`plan_transitions`] works out before an upgrade is recorded which
+//! paths change type:
+//! - a directory holding nothing but files of the version being replaced
+//!   is removed together with those files
+//! - a directory holding anything else is only replaced when asked to,
+//!   and is moved to `<path>.conary-saved` rather than deleted
+//! - a file of the version being replaced where the new version needs a
+//!   directory is removed
+//!
+//! Each transition is recorded in the changeset's file history, so a
+//! rollback can put the directory or file back.
+
+use crate::db::models::InstallModifiers;
+use crate::error::{Error, Result};
+use crate::filesystem::{FileDeployer, SAVED_DIR_SUFFIX};
+use crate::packages::PackageFormat;
+use crate::paths::{encode_path, under_root};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+/// A path that has to change type before a package can be deployed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Transition {
+    /// A directory where the package ships a file or link
+    DirectoryToFile {
+        path: String,
+        /// Files of the replaced version below it, removed with it
+        owned: Vec<String>,
+        /// Something else is below it too, so it is moved aside instead
+        save: bool,
+    },
+    /// A file or link of the replaced version where the package needs a directory
+    FileToDirectory { path: String, hash: String },
+}
+
+impl Transition {
+    /// The path changing type
+    pub(super) fn path(&self) -> &str {
+        match self {
+            Transition::DirectoryToFile { path, .. } | Transition::FileToDirectory { path, .. } => {
+                path
+            }
+        }
+    }
+
+    /// Where a directory moved aside ends up, if this transition moves one
+    pub(super) fn saved_path(&self) -> Option<String> {
+        match self {
+            Transition::DirectoryToFile {
+                path, save: true, ..
+            } => Some(format!("{}{}", path, SAVED_DIR_SUFFIX)),
+            _ => None,
+        }
+    }
+
+    /// Record the transition in the file history of `changeset_id`
+    ///
+    /// `dir-to-file` is a directory that was removed, `dir-saved` one that
+    /// was moved aside and `file-to-dir` a file removed for a directory,
+    /// keeping its content as the previous hash.
+    pub(super) fn record(&self, tx: &rusqlite::Connection, changeset_id: i64) -> Result<()> {
+        let (path, action, previous_hash) = match self {
+            Transition::DirectoryToFile { path, save, .. } => {
+                (path, if *save { "dir-saved" } else { "dir-to-file" }, None)
+            }
+            Transition::FileToDirectory { path, hash } => (path, "file-to-dir", Some(hash)),
+        };
+        tx.execute(
+            "INSERT INTO file_history (changeset_id, path, action, previous_hash) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![changeset_id, path, action, previous_hash],
+        )?;
+        Ok(())
+    }
+
+    /// Make room on disk for the new version
+    pub(super) fn apply(&self, deployer: &FileDeployer) -> Result<()> {
+        match self {
+            Transition::DirectoryToFile { path, owned, save } => {
+                for file in owned {
+                    deployer.remove_file(file)?;
+                }
+                if *save {
+                    deployer.rename(path, &format!("{}{}", path, SAVED_DIR_SUFFIX))?;
+                } else {
+                    deployer.remove_empty_tree(path)?;
+                }
+                info!("Replaced directory {} with a file", path);
+            }
+            Transition::FileToDirectory { path, .. } => {
+                deployer.remove_file(path)?;
+                info!("Replaced file {} with a directory", path);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find the paths of `package` that change type from what is on disk
+///
+/// `old_hashes` are the files of the version being replaced. A directory
+/// holding anything else is refused unless `allow_dir_replace` is set, as
+/// is a path needed as a directory that is a file the replaced version
+/// does not own. Symlinks to directories, such as `/lib` on merged-usr
+/// systems, are left alone. Excluded files are not deployed, so they are
+/// not looked at.
+pub(super) fn plan_transitions(
+    deployer: &FileDeployer,
+    package: &dyn PackageFormat,
+    modifiers: &InstallModifiers,
+    old_hashes: &HashMap<String, String>,
+    allow_dir_replace: bool,
+) -> Result<Vec<Transition>> {
+    let root = deployer.install_root();
+    let mut transitions = Vec::new();
+
+    for file in package
+        .files()
+        .iter()
+        .filter(|file| !modifiers.excludes(&file.path))
+    {
+        // Parents that are files now; a directory the package owns counts as one
+        let dir = if file.is_dir {
+            file.path.as_str()
+        } else {
+            file.path.rsplit_once('/').map_or("", |(dir, _)| dir)
+        };
+        let mut parent = String::new();
+        for component in dir.split('/').filter(|component| !component.is_empty()) {
+            parent = format!("{}/{}", parent, component);
+            let Ok(metadata) = std::fs::symlink_metadata(under_root(root, &parent)) else {
+                break;
+            };
+            if metadata.is_dir() {
+                continue;
+            }
+            match old_hashes.get(&parent) {
+                Some(hash) => {
+                    let transition = Transition::FileToDirectory {
+                        path: parent.clone(),
+                        hash: hash.clone(),
+                    };
+                    if !transitions.contains(&transition) {
+                        transitions.push(transition);
+                    }
+                }
+                None if metadata.is_symlink() && under_root(root, &parent).is_dir() => continue,
+                None => {
+                    return Err(Error::ConflictError(format!(
+                        "{} needs {} to be a directory for {}, but it is a file no version being replaced owns",
+                        package.name(),
+                        parent,
+                        file.path
+                    )));
+                }
+            }
+            break;
+        }
+
+        // The path itself is a directory now
+        let target = under_root(root, &file.path);
+        if file.is_dir
+            || !std::fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_dir())
+        {
+            continue;
+        }
+        let mut owned = Vec::new();
+        let save = holds_other_files(&target, &file.path, old_hashes, &mut owned)?;
+        if save {
+            let saved = format!("{}{}", file.path, SAVED_DIR_SUFFIX);
+            if !allow_dir_replace {
+                return Err(Error::ConflictError(format!(
+                    "{} ships {} as a file, but it is a directory holding files no version being replaced owns; \
+                     rerun with --allow-dir-replace to move them to {}",
+                    package.name(),
+                    file.path,
+                    saved
+                )));
+            }
+            if deployer.file_exists(&saved) {
+                return Err(Error::ConflictError(format!(
+                    "Cannot move {} aside for {}: {} already exists",
+                    file.path,
+                    package.name(),
+                    saved
+                )));
+            }
+        }
+        transitions.push(Transition::DirectoryToFile {
+            path: file.path.clone(),
+            owned,
+            save,
+        });
+    }
+    Ok(transitions)
+}
+
+/// Collect the files below `dir` (stored as `path`) that are in `old_hashes`
+///
+/// Returns whether anything else is there besides directories.
+fn holds_other_files(
+    dir: &Path,
+    path: &str,
+    old_hashes: &HashMap<String, String>,
+    owned: &mut Vec<String>,
+) -> Result<bool> {
+    let mut other = false;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let child = format!("{}/{}", path, encode_path(Path::new(&entry.file_name())));
+        if entry.file_type()?.is_dir() {
+            other |= holds_other_files(&entry.path(), &child, old_hashes, owned)?;
+        } else if old_hashes.contains_key(&child) {
+            owned.push(child);
+        } else {
+            other = true;
+        }
+    }
+    Ok(other)
+}
@@ -0,0 +1,371 @@
+// src/installer/update_plan.rs
+
+//! Working out what an update will do
+//!
+//! [`plan_updates`] finds the version each installed package would move to,
+//! the way [`available_updates`](super::available_updates) picks it, and
+//! then applies what `conary update` honours besides: packages taking over
+//! others through `replaces`, package locks and `--security`. Each entry
+//! carries the delta the update tries before the full package, so
+//! `update --dry-run` can show what would be downloaded from the metadata
+//! in the database alone.
+
+use super::{InstallOnly, available_updates, constraint_matches};
+use crate::db::models::{
+    PackageDelta, PackageLock, Repository, RepositoryAdvisory, RepositoryPackage, Trove,
+};
+use crate::error::Result;
+use crate::version;
+use rusqlite::Connection;
+use serde::Serialize;
+use tracing::info;
+
+/// Which packages an update covers and how it picks their new versions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpdateRequest<'a> {
+    /// Only update this package; every installed package when `None`
+    pub package: Option<&'a str>,
+    /// Update from this repository instead of the one each package came from
+    pub repo: Option<&'a str>,
+    /// Let packages declaring `replaces` take over the packages they replace
+    pub allow_replaces: bool,
+    /// Only take new versions a security advisory covers
+    pub security: bool,
+}
+
+/// One installed package and the version an update moves it to
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdatePlanEntry {
+    pub installed: Trove,
+    /// The new version; a package of another name when it replaces the
+    /// installed one
+    pub candidate: RepositoryPackage,
+    /// Name of the repository the candidate comes from
+    pub repository: String,
+    /// Delta from the installed version, tried before the full package
+    pub delta: Option<PackageDelta>,
+    /// Security advisories covering the candidate, as "id" or "id, severity",
+    /// when the update was asked for security fixes only
+    pub advisories: Vec<String>,
+    /// The candidate goes in next to the installed version, which stays,
+    /// as the package is installonly
+    pub alongside: bool,
+}
+
+impl UpdatePlanEntry {
+    /// Whether the candidate takes over the installed package through `replaces`
+    pub fn replaces(&self) -> bool {
+        self.installed.name != self.candidate.name
+    }
+
+    /// Bytes the update downloads for this package, the delta when there is one
+    pub fn download_size(&self) -> i64 {
+        self.delta
+            .as_ref()
+            .map_or(self.candidate.size, |delta| delta.delta_size)
+    }
+
+    /// Bytes the delta saves over downloading the full package
+    pub fn delta_savings(&self) -> i64 {
+        self.delta
+            .as_ref()
+            .map_or(0, |delta| (self.candidate.size - delta.delta_size).max(0))
+    }
+
+    /// The line `check-update` prints: `name installed -> candidate repository`
+    ///
+    /// A replacing candidate is given with its name.
+    pub fn check_line(&self) -> String {
+        let candidate = match self.replaces() {
+            true => format!("{} {}", self.candidate.name, self.candidate.version),
+            false => self.candidate.version.clone(),
+        };
+        format!(
+            "{} {} -> {} {}",
+            self.installed.name, self.installed.version, candidate, self.repository
+        )
+    }
+}
+
+/// What an update will do, before anything is downloaded
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdatePlan {
+    /// Installed packages the update looked at
+    pub checked: usize,
+    pub entries: Vec<UpdatePlanEntry>,
+    /// Updates held back by a package lock, as installed and candidate
+    pub locked: Vec<(Trove, RepositoryPackage)>,
+    /// Packages that would replace an installed one, not taken without
+    /// `allow_replaces`, as installed and replacement
+    pub replacements: Vec<(Trove, RepositoryPackage)>,
+    /// Updates left out because no security advisory covers them
+    pub not_security: usize,
+}
+
+impl UpdatePlan {
+    /// Bytes the whole update downloads
+    pub fn download_size(&self) -> i64 {
+        self.entries
+            .iter()
+            .map(UpdatePlanEntry::download_size)
+            .sum()
+    }
+
+    /// Bytes deltas save over downloading every full package
+    pub fn delta_savings(&self) -> i64 {
+        self.entries
+            .iter()
+            .map(UpdatePlanEntry::delta_savings)
+            .sum()
+    }
+}
+
+/// Work out the packages `request` updates and what each update downloads
+///
+/// Nothing is changed and nothing is fetched; the plan is drawn from the
+/// repository metadata already synced.
+pub fn plan_updates(conn: &Connection, request: &UpdateRequest) -> Result<UpdatePlan> {
+    let installed = match request.package {
+        Some(name) => Trove::find_by_name(conn, name)?,
+        None => Trove::list_all(conn)?,
+    };
+    let mut plan = UpdatePlan {
+        checked: installed.len(),
+        ..Default::default()
+    };
+    let installonly = InstallOnly::load(conn)?;
+    let mut updates = available_updates(conn, &installed, request.repo)?;
+
+    // A package declaring `replaces` can take over an installed trove
+    for trove in &installed {
+        if updates.iter().any(|(updated, _)| updated.id == trove.id) {
+            continue;
+        }
+
+        for replacement in RepositoryPackage::find_replacing(conn, &trove.name)? {
+            let applies = replacement.relations("replaces").iter().any(|entry| {
+                let (name, constraint) = version::split_constraint(entry);
+                name == trove.name
+                    && constraint_matches(constraint, trove.version_scheme(), &trove.version)
+            });
+            if !applies || !Trove::find_by_name(conn, &replacement.name)?.is_empty() {
+                continue;
+            }
+
+            if request.allow_replaces {
+                info!(
+                    "{} {} replaces {} {}",
+                    replacement.name, replacement.version, trove.name, trove.version
+                );
+                updates.push((trove.clone(), replacement));
+            } else {
+                plan.replacements.push((trove.clone(), replacement));
+            }
+            break;
+        }
+    }
+
+    for (trove, candidate) in updates {
+        // Locked packages stay at their installed version
+        if PackageLock::is_locked(conn, &trove.name)? {
+            plan.locked.push((trove, candidate));
+            continue;
+        }
+
+        let mut advisories = Vec::new();
+        if request.security {
+            advisories = RepositoryAdvisory::find_for_package(
+                conn,
+                candidate.repository_id,
+                &candidate.name,
+                &candidate.version,
+            )?
+            .into_iter()
+            .filter(RepositoryAdvisory::is_security)
+            .map(|advisory| match advisory.severity {
+                Some(severity) => format!("{}, {}", advisory.advisory_id, severity),
+                None => advisory.advisory_id,
+            })
+            .collect();
+            if advisories.is_empty() {
+                plan.not_security += 1;
+                continue;
+            }
+        }
+
+        let repository = Repository::find_by_id(conn, candidate.repository_id)?
+            .map(|repo| repo.name)
+            .unwrap_or_default();
+        let delta =
+            PackageDelta::find_delta(conn, &trove.name, &trove.version, &candidate.version)?;
+        let alongside = installonly.includes(&candidate.name);
+        plan.entries.push(UpdatePlanEntry {
+            installed: trove,
+            candidate,
+            repository,
+            delta,
+            advisories,
+            alongside,
+        });
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::db::models::{FileContents, TroveType};
+
+    #[test]
+    fn test_plan_updates_counts_downloads_and_deltas() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("conary.db");
+        db::init(db_path.to_str().unwrap()).unwrap();
+        let conn = db::open(db_path.to_str().unwrap()).unwrap();
+
+        let repo_id = crate::repository::add_repository(
+            &conn,
+            "local".to_string(),
+            "https://example.com".to_string(),
+            true,
+            0,
+        )
+        .unwrap()
+        .id
+        .unwrap();
+        for (name, size) in [
+            ("hello", 4_000_000),
+            ("tool", 1_000),
+            ("kernel", 90_000_000),
+        ] {
+            Trove::new(name.to_string(), "1.0-1".to_string(), TroveType::Package)
+                .insert(&conn)
+                .unwrap();
+            let url = format!("https://example.com/{}-2.0-1.pkg.tar.zst", name);
+            RepositoryPackage::new(
+                repo_id,
+                name.to_string(),
+                "2.0-1".to_string(),
+                "0".repeat(64),
+                size,
+                url,
+            )
+            .insert(&conn)
+            .unwrap();
+        }
+        PackageLock::lock(&conn, "kernel").unwrap();
+        for hash in ["0".repeat(64), "1".repeat(64)] {
+            FileContents::new(hash, 0).insert(&conn).unwrap();
+        }
+        let mut delta = PackageDelta::new(
+            "hello".to_string(),
+            "1.0-1".to_string(),
+            "2.0-1".to_string(),
+            "1".repeat(64),
+            "0".repeat(64),
+            "https://example.com/hello.delta".to_string(),
+            500_000,
+            "2".repeat(64),
+            4_000_000,
+        );
+        delta.insert(&conn).unwrap();
+
+        let plan = plan_updates(&conn, &UpdateRequest::default()).unwrap();
+        assert_eq!(plan.checked, 3);
+        let names: Vec<&str> = plan
+            .entries
+            .iter()
+            .map(|entry| entry.installed.name.as_str())
+            .collect();
+        assert_eq!(names, ["hello", "tool"]);
+        assert_eq!(plan.locked[0].0.name, "kernel");
+        assert_eq!(plan.entries[0].repository, "local");
+        assert_eq!(plan.entries[0].download_size(), 500_000);
+        assert_eq!(
+            plan.entries[1].delta.as_ref().map(|delta| delta.delta_size),
+            None
+        );
+        assert_eq!(plan.download_size(), 501_000);
+        assert_eq!(plan.delta_savings(), 3_500_000);
+        assert_eq!(plan.entries[1].check_line(), "tool 1.0-1 -> 2.0-1 local");
+
+        // Nothing covers these with a security advisory
+        let plan = plan_updates(
+            &conn,
+            &UpdateRequest {
+                security: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(plan.entries.is_empty());
+        assert_eq!(plan.not_security, 2);
+
+        let plan = plan_updates(
+            &conn,
+            &UpdateRequest {
+                package: Some("tool"),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!((plan.checked, plan.entries.len()), (1, 1));
+    }
+
+    #[test]
+    fn test_plan_updates_installonly_once_from_newest() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::db::schema::migrate(&conn).unwrap();
+        let repo_id = crate::repository::add_repository(
+            &conn,
+            "local".to_string(),
+            "https://example.com".to_string(),
+            true,
+            0,
+        )
+        .unwrap()
+        .id
+        .unwrap();
+        for version in ["6.1-1", "6.2-1"] {
+            Trove::new(
+                "kernel".to_string(),
+                version.to_string(),
+                TroveType::Package,
+            )
+            .insert(&conn)
+            .unwrap();
+        }
+        let url = "https://example.com/kernel-6.3-1.pkg.tar.zst".to_string();
+        RepositoryPackage::new(
+            repo_id,
+            "kernel".to_string(),
+            "6.3-1".to_string(),
+            "0".repeat(64),
+            1,
+            url,
+        )
+        .insert(&conn)
+        .unwrap();
+
+        let plan = plan_updates(&conn, &UpdateRequest::default()).unwrap();
+        assert_eq!(plan.entries.len(), 1);
+        assert_eq!(plan.entries[0].installed.version, "6.2-1");
+        assert!(plan.entries[0].alongside);
+
+        // Nothing to do once any version is the candidate
+        Trove::new(
+            "kernel".to_string(),
+            "6.3-1".to_string(),
+            TroveType::Package,
+        )
+        .insert(&conn)
+        .unwrap();
+        assert!(
+            plan_updates(&conn, &UpdateRequest::default())
+                .unwrap()
+                .entries
+                .is_empty()
+        );
+    }
+}
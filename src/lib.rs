@@ -12,14 +12,58 @@
 //! - Troves: Hierarchical package units (packages, components, collections)
 //! - Flavors: Build-time variations tracked in metadata
 //! - File-level tracking: SHA-256 hashes, delta updates, conflict detection
+//!
+//! # Public API
+//!
+//! The items re-exported at the crate root are the supported surface and
+//! follow semver. The modules stay public because the `conary` binary is
+//! built on them, but anything reached only through a module path may change
+//! in a minor release. Modules that exist purely to serve the binary are
+//! hidden from the documentation.
 
+pub mod bootstrap;
 pub mod db;
 pub mod delta;
 mod error;
+#[macro_use]
+mod failpoints;
 pub mod filesystem;
+pub mod gc;
+pub mod installer;
 pub mod packages;
+pub mod paths;
+pub mod plan;
+pub mod query;
+pub mod reconcile;
 pub mod repository;
 pub mod resolver;
+pub mod resources;
+pub mod rollback;
+#[doc(hidden)]
+pub mod running;
+#[doc(hidden)]
+pub mod scriptlet;
+pub mod staged;
+#[doc(hidden)]
+pub mod stats;
+#[doc(hidden)]
+pub mod summary;
+pub mod timing;
 pub mod version;
 
+pub use db::models::{
+    Changeset, ChangesetStatus, DependencyEntry, FileEntry, FileType, InstallReason, Repository,
+    RepositoryPackage, Trove, TroveType,
+};
 pub use error::{Error, Result};
+pub use filesystem::{CasStore, FileDeployer};
+pub use installer::{
+    InstallOptions, InstallReport, Installer, PlanReport, RemoveOptions, RemoveReport,
+};
+pub use packages::{PackageFormat, PackageFormatType, detect_package_format, open_package};
+pub use paths::Layout;
+pub use plan::{Plan, Planner};
+pub use reconcile::{ReconcileOptions, ReconcileReport, reconcile};
+pub use repository::{add_repository, remove_repository, search_packages, sync_repository};
+pub use resolver::{ResolutionPlan, Resolver};
+pub use version::{ArchVersion, DebVersion, RpmVersion, VersionConstraint};
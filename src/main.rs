@@ -2,36 +2,27 @@
 
 use anyhow::Result;
 use clap::{CommandFactory, Parser, Subcommand};
-use clap_complete::{generate, Shell};
-use conary::db::models::{DeltaStats, PackageDelta};
-use conary::delta::DeltaApplier;
-use conary::packages::arch::ArchPackage;
-use conary::packages::deb::DebPackage;
-use conary::packages::rpm::RpmPackage;
-use conary::packages::traits::DependencyType;
+use clap_complete::{Shell, generate};
+use conary::db::models::{DeltaStats, InstallReason, RepositoryScope};
 use conary::packages::PackageFormat;
-use conary::repository::{self, PackageSelector, SelectionOptions};
-use conary::version::RpmVersion;
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::{Path, PathBuf};
-use tempfile::TempDir;
+use conary::packages::traits::FileStream;
+use conary::plan::{Operation, PlanOptions, Planner, Request, Source};
+use conary::repository::{self, Companion};
+use conary::resources::ResourceLimits;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
 use tracing::{info, warn};
 
-/// Package format types
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum PackageFormatType {
-    Rpm,
-    Deb,
-    Arch,
-}
-
 #[derive(Parser)]
 #[command(name = "conary")]
 #[command(author, version, about = "Modern package manager with atomic operations and rollback", long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Don't show download progress; check-update prints nothing
+    #[arg(short, long, global = true)]
+    quiet: bool,
 }
 
 #[derive(Subcommand)]
@@ -41,11 +32,24 @@ enum Commands {
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
+        /// Store CAS objects here instead of next to the database
+        #[arg(long)]
+        objects_dir: Option<String>,
+        /// Run package scriptlets in a bubblewrap sandbox by default
+        #[arg(long)]
+        sandbox_scripts: bool,
+        /// Add the default repositories of arch, fedora, ubuntu or all (default: the host's, from /etc/os-release)
+        #[arg(long, value_name = "DISTRO", num_args = 0..=1)]
+        with_defaults: Option<Option<String>>,
+        /// Create only the database, with no repositories (the default)
+        #[arg(long, conflicts_with = "with_defaults")]
+        empty: bool,
     },
     /// Install a package from file or repository
     Install {
-        /// Package file path or package name
-        package: String,
+        /// Package file paths or package names, installed as one changeset
+        #[arg(required = true)]
+        packages: Vec<String>,
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
@@ -58,31 +62,255 @@ enum Commands {
         /// Specific repository to use
         #[arg(long)]
         repo: Option<String>,
+        /// Install builds for this architecture instead of the host's, e.g. i686
+        #[arg(long, value_name = "ARCH")]
+        arch: Option<String>,
         /// Dry run - show what would be installed without installing
         #[arg(long)]
         dry_run: bool,
+        /// Do not run package scriptlets
+        #[arg(long = "noscripts")]
+        no_scripts: bool,
+        /// Run package scriptlets in a bubblewrap sandbox
+        #[arg(long)]
+        sandbox_scripts: bool,
+        /// Allow replacing an installed package with an older version
+        #[arg(long)]
+        allow_downgrade: bool,
+        /// Install another version of a locked package, lifting the lock
+        #[arg(long)]
+        force_unlock: bool,
+        /// Let an upgrade replace a directory holding other files with a file,
+        /// moving the directory to <path>.conary-saved
+        #[arg(long)]
+        allow_dir_replace: bool,
+        /// Succeed without changes when the exact version is already installed
+        #[arg(long)]
+        idempotent: bool,
+        /// With --idempotent, check the installed files before skipping
+        #[arg(long, requires = "idempotent")]
+        verify_existing: bool,
+        /// Record but do not deploy files matching this glob (repeatable)
+        #[arg(long = "exclude", value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Also install the debug symbol packages of the requested packages
+        #[arg(long)]
+        with_debuginfo: bool,
+        /// Also install the documentation packages of the requested packages
+        #[arg(long)]
+        with_docs: bool,
+        /// Print how long each install phase took
+        #[arg(long)]
+        timings: bool,
+        /// Print why a package or dependency could not be resolved as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Build a root filesystem for a container image from repositories
+    Bootstrap {
+        /// Directory to fill; gets its own database under var/lib/conary
+        #[arg(short, long)]
+        root: String,
+        /// Distribution whose default repositories are used (arch, fedora, ubuntu)
+        #[arg(long, required_unless_present = "repos")]
+        distro: Option<String>,
+        /// Packages to install, comma-separated
+        #[arg(long, value_delimiter = ',', required = true)]
+        packages: Vec<String>,
+        /// Use this repository instead of the distribution's, as NAME=URL (repeatable)
+        #[arg(long = "repo", value_name = "NAME=URL")]
+        repos: Vec<String>,
+        /// Run package scriptlets (skipped by default)
+        #[arg(long)]
+        with_scripts: bool,
+        /// Deploy documentation, man pages and locales too
+        #[arg(long)]
+        keep_docs: bool,
+        /// Record but do not deploy files matching this glob as well (repeatable)
+        #[arg(long = "exclude", value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Write the manifest of installed packages to this JSON file
+        #[arg(long)]
+        manifest: Option<String>,
     },
     /// Remove an installed package
     Remove {
         /// Package name to remove
         package_name: String,
+        /// Remove this installed version, when several are installed
+        #[arg(long)]
+        version: Option<String>,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Install root directory (default: /)
+        #[arg(short, long, default_value = "/")]
+        root: String,
+        /// Do not run package scriptlets
+        #[arg(long = "noscripts")]
+        no_scripts: bool,
+        /// Run package scriptlets in a bubblewrap sandbox
+        #[arg(long)]
+        sandbox_scripts: bool,
+    },
+    /// Remove packages installed as dependencies that are no longer needed
+    Autoremove {
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Install root directory (default: /)
+        #[arg(short, long, default_value = "/")]
+        root: String,
+        /// List the packages that would be removed without removing them
+        #[arg(long)]
+        dry_run: bool,
+        /// Do not run package scriptlets
+        #[arg(long = "noscripts")]
+        no_scripts: bool,
+        /// Run package scriptlets in a bubblewrap sandbox
+        #[arg(long)]
+        sandbox_scripts: bool,
+    },
+    /// Mark an installed package as explicitly installed so autoremove keeps it
+    MarkExplicit {
+        /// Package name
+        package_name: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// Lock a package at its installed version so updates skip it
+    Lock {
+        /// Package name
+        package_name: String,
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
     },
+    /// Lift the lock on a package
+    Unlock {
+        /// Package name
+        package_name: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// List locked packages
+    Locks {
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prefer or forbid repositories for packages matching a pattern
+    Pin {
+        #[command(subcommand)]
+        action: PinCommand,
+    },
     /// Query installed packages
     Query {
-        /// Package name pattern (optional, shows all if omitted)
+        /// Package name pattern: a glob (`lib*`) or a substring (optional, shows all if omitted)
         pattern: Option<String>,
+        /// Only show packages installed by this changeset
+        #[arg(long, value_name = "CHANGESET", conflicts_with = "requires_file")]
+        installed_by: Option<i64>,
+        /// Only show packages for this architecture
+        #[arg(long, conflicts_with = "requires_file")]
+        arch: Option<String>,
+        /// Show what a changeset installed, removed and changed on disk
+        #[arg(long, value_name = "ID", conflicts_with_all = ["pattern", "installed_by", "arch", "requires_file"])]
+        changeset: Option<i64>,
+        /// List every changeset that affected the package named by the pattern, oldest first
+        #[arg(long, requires = "pattern", conflicts_with_all = ["installed_by", "arch", "requires_file"])]
+        show_changesets: bool,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Show installed packages that require this file path (exact or glob)
+        #[arg(long, conflicts_with = "pattern")]
+        requires_file: Option<String>,
+        /// Also match the shebang interpreters of installed executables
+        #[arg(long, requires = "requires_file")]
+        scan_shebangs: bool,
+        /// Install root to read executables from (default: /)
+        #[arg(short, long, default_value = "/")]
+        root: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List installed packages by disk usage, largest first
+    Du {
+        /// Show only the largest N packages; the totals still cover all
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Unpack a package file into a directory without installing it
+    Extract {
+        /// Path to the package file (RPM, DEB or Arch)
+        package: String,
+        /// Directory to unpack into; created if missing
+        #[arg(required_unless_present = "list")]
+        dir: Option<String>,
+        /// Only print the files of the package, with size and mode
+        #[arg(long, conflicts_with = "dir")]
+        list: bool,
+    },
+    /// Show everything known about a package
+    Info {
+        /// Package name
+        package_name: String,
+        /// Show the versions available from repositories instead of the installed one
+        #[arg(long)]
+        remote: bool,
+        /// Also check the installed files against their recorded hashes
+        #[arg(long, conflicts_with = "remote")]
+        verify: bool,
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
+        /// Install root to verify files under (default: /)
+        #[arg(short, long, default_value = "/")]
+        root: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
     },
     /// Show changeset history
     History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
         /// Database path (default: /var/lib/conary/conary.db)
-        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        #[arg(
+            short,
+            long,
+            global = true,
+            default_value = "/var/lib/conary/conary.db"
+        )]
         db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+        /// Show only the newest N changesets
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Show only changesets created on or after this date (YYYY-MM-DD or "YYYY-MM-DD HH:MM:SS", UTC)
+        #[arg(long)]
+        since: Option<String>,
+        /// Show only changesets with this status (applied, staged, rolled_back, pending)
+        #[arg(long)]
+        status: Option<String>,
+        /// Show the packages and files changed by one changeset
+        #[arg(long, value_name = "ID", conflicts_with_all = ["limit", "since", "status"])]
+        show: Option<i64>,
     },
     /// Rollback a changeset
     Rollback {
@@ -94,6 +322,16 @@ enum Commands {
         /// Install root directory (default: /)
         #[arg(short, long, default_value = "/")]
         root: String,
+        /// Roll back the changeset even if it is protected
+        #[arg(long)]
+        force: bool,
+        /// Show what the rollback would do, including local changes it would
+        /// discard, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the --dry-run plan as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
     },
     /// Verify installed files match their stored hashes
     Verify {
@@ -105,6 +343,60 @@ enum Commands {
         /// Install root directory (default: /)
         #[arg(short, long, default_value = "/")]
         root: String,
+        /// Also check mode, owner, group and extended attributes against package metadata
+        #[arg(long)]
+        attrs: bool,
+        /// Treat locally modified config files as verification failures
+        #[arg(long)]
+        strict_config: bool,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+        /// Compare the installed package with its original package file
+        #[arg(long, value_name = "PKG", conflicts_with = "package")]
+        package_file: Option<String>,
+        /// Restore files that fail verification from the CAS
+        #[arg(long, conflicts_with = "package_file")]
+        repair: bool,
+        /// Check and repair files excluded at install time like any other
+        #[arg(long, conflicts_with = "package_file")]
+        include_excluded: bool,
+        /// List only files that did not verify clean (implied by --porcelain)
+        #[arg(long)]
+        changed_only: bool,
+        /// Stable tab-separated output for scripts: status, path, package, detail
+        #[arg(long, conflicts_with_all = ["json", "repair"])]
+        porcelain: bool,
+        /// List regular files under PREFIX (default /usr) that no package owns
+        #[arg(
+            long,
+            value_name = "PREFIX",
+            num_args = 0..=1,
+            default_missing_value = "/usr",
+            conflicts_with_all = ["package", "package_file", "repair", "attrs"]
+        )]
+        orphans: Option<String>,
+        /// With --orphans, skip paths matching this glob, such as '/usr/local/*' (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "orphans")]
+        exclude: Vec<String>,
+    },
+    /// Revalidate every installed package and repair drift between the DB, CAS and root
+    Reconcile {
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Install root directory (default: /)
+        #[arg(short, long, default_value = "/")]
+        root: String,
+        /// Re-download packages whose content is missing from the CAS
+        #[arg(long)]
+        download_missing: bool,
+        /// Show what would be repaired without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Also restore files excluded when the package was installed
+        #[arg(long)]
+        include_excluded: bool,
     },
     /// Show dependencies of a package
     Depends {
@@ -113,6 +405,9 @@ enum Commands {
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
     },
     /// Show reverse dependencies (what depends on this package)
     Rdepends {
@@ -121,6 +416,9 @@ enum Commands {
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
     },
     /// Show what packages would break if this package is removed
     Whatbreaks {
@@ -151,6 +449,45 @@ enum Commands {
         /// Disable repository after adding
         #[arg(long)]
         disabled: bool,
+        /// Which operations may use it: full, or explicit-only for installs naming it with --repo
+        #[arg(long, default_value = "full")]
+        scope: String,
+        /// Download packages by running this command with the URL, destination and SHA-256
+        #[arg(long, value_name = "COMMAND")]
+        download_helper: Option<String>,
+        /// Fetch metadata through the download helper too
+        #[arg(long, requires = "download_helper")]
+        helper_metadata: bool,
+        /// Use the built-in client when the download helper fails
+        #[arg(long, requires = "download_helper")]
+        helper_fallback: bool,
+        /// Fetch http:// metadata and packages over https://
+        #[arg(long)]
+        prefer_https: bool,
+        /// Refuse package downloads from hosts other than the repository's
+        #[arg(long)]
+        no_cross_host: bool,
+        /// Debian component to sync, e.g. universe (repeatable; default: all the Release file lists)
+        #[arg(long = "component", value_name = "COMPONENT")]
+        components: Vec<String>,
+        /// Debian architecture to sync, e.g. arm64 (repeatable; default: the host's)
+        #[arg(long = "arch", value_name = "ARCH")]
+        architectures: Vec<String>,
+        /// Only store packages whose name or name.arch matches, e.g. 'kernel*' (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        sync_include: Vec<String>,
+        /// Do not store packages whose name or name.arch matches, e.g. '*-debuginfo' (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        sync_exclude: Vec<String>,
+        /// Record that the repository's metadata is not GPG-checked
+        #[arg(long)]
+        no_gpg_check: bool,
+        /// URL of the key the repository signs with
+        #[arg(long, value_name = "URL")]
+        gpg_key: Option<String>,
+        /// Seconds before synced metadata is fetched again
+        #[arg(long, value_name = "SECONDS", default_value = "3600")]
+        metadata_expire: i32,
     },
     /// List repositories
     RepoList {
@@ -160,6 +497,21 @@ enum Commands {
         /// Show all repositories (including disabled)
         #[arg(short, long)]
         all: bool,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+        /// Show the repo-add command that recreates each repository
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Print the configured repositories for another system
+    RepoExport {
+        /// Print a shell script of repo-add commands, one per repository
+        #[arg(long, required = true)]
+        as_commands: bool,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
     },
     /// Remove a repository
     RepoRemove {
@@ -185,6 +537,69 @@ enum Commands {
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
     },
+    /// Change settings of a repository
+    RepoModify {
+        /// Repository name
+        name: String,
+        /// Which operations may use it: full, or explicit-only for installs naming it with --repo
+        #[arg(
+            long,
+            required_unless_present_any = [
+                "download_helper", "no_download_helper", "prefer_https", "allow_cross_host",
+                "sync_include", "sync_exclude", "no_sync_filter",
+            ]
+        )]
+        scope: Option<String>,
+        /// Download packages by running this command with the URL, destination and SHA-256
+        #[arg(long, value_name = "COMMAND", conflicts_with = "no_download_helper")]
+        download_helper: Option<String>,
+        /// Fetch metadata through the download helper too
+        #[arg(long, requires = "download_helper")]
+        helper_metadata: bool,
+        /// Use the built-in client when the download helper fails
+        #[arg(long, requires = "download_helper")]
+        helper_fallback: bool,
+        /// Go back to downloading with the built-in client
+        #[arg(long)]
+        no_download_helper: bool,
+        /// Fetch http:// metadata and packages over https://
+        #[arg(long, value_name = "BOOL")]
+        prefer_https: Option<bool>,
+        /// Allow package downloads from hosts other than the repository's
+        #[arg(long, value_name = "BOOL")]
+        allow_cross_host: Option<bool>,
+        /// Only store packages whose name or name.arch matches, e.g. 'kernel*' (repeatable; replaces the list)
+        #[arg(long, value_name = "PATTERN", conflicts_with = "no_sync_filter")]
+        sync_include: Vec<String>,
+        /// Do not store packages whose name or name.arch matches, e.g. '*-debuginfo' (repeatable; replaces the list)
+        #[arg(long, value_name = "PATTERN", conflicts_with = "no_sync_filter")]
+        sync_exclude: Vec<String>,
+        /// Store every package again
+        #[arg(long)]
+        no_sync_filter: bool,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// Show and edit the order repositories are preferred in
+    ///
+    /// Repositories are ordered by priority, highest first; equal priorities
+    /// are ordered by name. All edits are applied in one transaction, --set
+    /// before --move.
+    RepoPriority {
+        /// Set a priority, e.g. --set fedora-43=95 (repeatable)
+        #[arg(long = "set", value_name = "NAME=PRIORITY")]
+        set: Vec<String>,
+        /// Move a repository, e.g. --move local before fedora-43 (repeatable)
+        #[arg(long = "move", num_args = 3, value_names = ["NAME", "before|after", "OTHER"])]
+        moves: Vec<String>,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
     /// Synchronize repository metadata
     RepoSync {
         /// Repository name (syncs all if omitted)
@@ -192,17 +607,29 @@ enum Commands {
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
-        /// Force sync even if metadata hasn't expired
+        /// Force a full sync even if metadata hasn't expired or changed
         #[arg(short, long)]
         force: bool,
     },
     /// Search for packages in repositories
     Search {
-        /// Search pattern
+        /// Search pattern, matched against names and descriptions
         pattern: String,
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+        /// Only search this repository
+        #[arg(long)]
+        repo: Option<String>,
+        /// Mark packages that are already installed
+        #[arg(long)]
+        installed: bool,
+        /// Read the pattern as a regular expression
+        #[arg(long)]
+        regex: bool,
     },
     /// Update installed packages from repositories
     Update {
@@ -214,762 +641,2449 @@ enum Commands {
         /// Install root directory (default: /)
         #[arg(short, long, default_value = "/")]
         root: String,
+        /// Do not run package scriptlets
+        #[arg(long = "noscripts")]
+        no_scripts: bool,
+        /// Run package scriptlets in a bubblewrap sandbox
+        #[arg(long)]
+        sandbox_scripts: bool,
+        /// Check for running processes using files being replaced (warn, confirm or abort)
+        #[arg(long, value_name = "MODE", num_args = 0..=1, default_missing_value = "warn")]
+        check_running: Option<String>,
+        /// Let packages that declare `replaces` take over the packages they replace
+        #[arg(long)]
+        allow_replaces: bool,
+        /// Let an update replace a directory holding other files with a file,
+        /// moving the directory to <path>.conary-saved
+        #[arg(long)]
+        allow_dir_replace: bool,
+        /// Print how long each install phase took, per package
+        #[arg(long)]
+        timings: bool,
+        /// Update from this repository instead of the one each package came from
+        #[arg(long)]
+        repo: Option<String>,
+        /// List the packages that would be updated without updating them
+        #[arg(long)]
+        dry_run: bool,
+        /// End with a summary of what was done, how long it took and every warning logged
+        #[arg(long)]
+        summary: bool,
+        /// Only update packages whose new version a security advisory covers
+        #[arg(long)]
+        security: bool,
+        /// Update packages even where verify found their files changed locally
+        #[arg(long)]
+        overwrite_modified: bool,
+    },
+    /// Check whether updates are available, without downloading anything
+    ///
+    /// Prints one line per update, `name installed -> candidate repository`,
+    /// from the repository metadata already synced. Exits 0 when everything
+    /// is current, 100 when updates are available and 1 on error; with
+    /// --quiet only the exit code tells.
+    CheckUpdate {
+        /// Package name (checks all if omitted)
+        package: Option<String>,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Check against this repository instead of the one each package came from
+        #[arg(long)]
+        repo: Option<String>,
+        /// Only count new versions a security advisory covers
+        #[arg(long)]
+        security: bool,
     },
     /// Show delta update statistics
     DeltaStats {
         /// Database path (default: /var/lib/conary/conary.db)
         #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
         db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Generate file deltas between two versions of a package
+    ///
+    /// Each version is read from the package files given with --package-file,
+    /// or else from the installed package. The deltas and a manifest listing
+    /// them are written to the output directory, ready to be served over HTTP.
+    DeltaGenerate {
+        /// Package name
+        package: String,
+        /// Version to generate deltas from
+        old_version: String,
+        /// Version to generate deltas to
+        new_version: String,
+        /// Package file providing a version that is not installed (repeatable)
+        #[arg(long = "package-file")]
+        package_files: Vec<String>,
+        /// Directory to write the deltas and manifest to
+        #[arg(short, long)]
+        output: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// Show cumulative bandwidth and operation statistics
+    Stats {
+        #[command(subcommand)]
+        action: Option<StatsAction>,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(
+            short,
+            long,
+            global = true,
+            default_value = "/var/lib/conary/conary.db"
+        )]
+        db_path: String,
+        /// Only include activity within this window (e.g. 30d, 12h, 2w)
+        #[arg(long)]
+        since: Option<String>,
+        /// Break totals down by repository
+        #[arg(long)]
+        by_repo: bool,
+    },
+    /// Move the CAS objects directory to a new location
+    CasRelocate {
+        /// New objects directory (absolute path)
+        new_dir: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// Install debug symbols for installed packages
+    Debuginfo {
+        #[command(subcommand)]
+        action: DebuginfoAction,
+    },
+    /// Inspect content stored in the CAS
+    Cas {
+        #[command(subcommand)]
+        action: CasAction,
+    },
+    /// Look for configurations likely to surprise
+    Doctor {
+        #[command(subcommand)]
+        check: DoctorCheck,
+    },
+    /// Move the files of staged changesets into the root and mark them applied
+    ApplyPending {
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Install root directory (default: /)
+        #[arg(short, long, default_value = "/")]
+        root: String,
+        /// List the staged changesets without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Set a configuration value (resource limits, scriptlet_sandbox, staged_deploy, installonly_packages)
+    ConfigSet {
+        /// Setting name, e.g. max_parallel_downloads
+        key: String,
+        /// New value; sizes accept K, M and G suffixes
+        value: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// Show configuration values, or all of them when no key is given
+    ConfigGet {
+        /// Setting name
+        key: Option<String>,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// Reset a configuration value to its default
+    ConfigUnset {
+        /// Setting name
+        key: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
     },
 }
 
-/// Detect package format from file extension and magic bytes
-fn detect_package_format(path: &str) -> Result<PackageFormatType> {
-    // First try file extension
-    if path.ends_with(".rpm") {
-        return Ok(PackageFormatType::Rpm);
-    } else if path.ends_with(".deb") {
-        return Ok(PackageFormatType::Deb);
-    } else if path.ends_with(".pkg.tar.zst") || path.ends_with(".pkg.tar.xz") {
-        return Ok(PackageFormatType::Arch);
-    }
-
-    // Fallback to magic bytes detection
-    let mut file = File::open(path)?;
-    let mut magic = [0u8; 8];
-    file.read_exact(&mut magic)?;
-
-    // RPM magic: 0xED 0xAB 0xEE 0xDB (first 4 bytes)
-    if magic[0..4] == [0xED, 0xAB, 0xEE, 0xDB] {
-        return Ok(PackageFormatType::Rpm);
-    }
-
-    // DEB magic: "!<arch>\n" (ar archive format)
-    if magic[0..7] == *b"!<arch>" {
-        return Ok(PackageFormatType::Deb);
-    }
-
-    // Arch packages are compressed tar archives
-    // Check for zstd magic: 0x28 0xB5 0x2F 0xFD
-    if magic[0..4] == [0x28, 0xB5, 0x2F, 0xFD] {
-        return Ok(PackageFormatType::Arch);
-    }
-
-    // Check for xz magic: 0xFD 0x37 0x7A 0x58 0x5A 0x00
-    if magic[0..6] == [0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00] {
-        return Ok(PackageFormatType::Arch);
-    }
+#[derive(Subcommand)]
+enum HistoryAction {
+    /// Protect a changeset from being rolled back without --force
+    Protect {
+        /// Changeset ID to protect
+        changeset_id: i64,
+    },
+    /// Remove the protection from a changeset
+    Unprotect {
+        /// Changeset ID to unprotect
+        changeset_id: i64,
+    },
+}
 
-    Err(anyhow::anyhow!(
-        "Unable to detect package format for: {}",
-        path
-    ))
+#[derive(Subcommand)]
+enum StatsAction {
+    /// Reset cumulative bandwidth and operation statistics
+    Reset,
 }
 
-/// Install a package from a file path
-///
-/// This function handles the core installation logic and can be used by both
-/// Install and Update commands.
-///
-/// # Arguments
-/// * `package_path` - Path to the package file
-/// * `conn` - Database connection (must be mutable for transactions)
-/// * `root` - Install root directory
-/// * `old_trove` - Optional existing trove to upgrade (None for fresh install)
-fn install_package_from_file(
-    package_path: &Path,
-    conn: &mut rusqlite::Connection,
-    root: &str,
-    old_trove: Option<&conary::db::models::Trove>,
-) -> Result<()> {
-    // Auto-detect package format
-    let format = detect_package_format(package_path.to_str().unwrap())?;
-    info!("Detected package format: {:?}", format);
-
-    // Parse the package based on format
-    let package: Box<dyn PackageFormat> = match format {
-        PackageFormatType::Rpm => Box::new(RpmPackage::parse(package_path.to_str().unwrap())?),
-        PackageFormatType::Deb => Box::new(DebPackage::parse(package_path.to_str().unwrap())?),
-        PackageFormatType::Arch => Box::new(ArchPackage::parse(package_path.to_str().unwrap())?),
-    };
+#[derive(Subcommand)]
+enum PinCommand {
+    /// Take packages matching a pattern from a repository, or never from it with --forbid
+    Add {
+        /// Package name glob, e.g. bash or lib32-*
+        pattern: String,
+        /// Repository name
+        repository: String,
+        /// Keep matching packages out of the repository unless it is named with --repo
+        #[arg(long)]
+        forbid: bool,
+        /// Pins with a higher priority are consulted first
+        #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+        priority: i32,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+    /// List pins in the order they are consulted
+    List {
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove the pin of a pattern on a repository
+    Remove {
+        /// Package name glob the pin was added with
+        pattern: String,
+        /// Repository name
+        repository: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+}
 
-    info!(
-        "Parsed package: {} version {} ({} files, {} dependencies)",
-        package.name(),
-        package.version(),
-        package.files().len(),
-        package.dependencies().len()
-    );
+#[derive(Subcommand)]
+enum DebuginfoAction {
+    /// Install the debug symbol package of an installed package, at its installed version
+    Install {
+        /// Installed package name
+        package: String,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Install root directory (default: /)
+        #[arg(short, long, default_value = "/")]
+        root: String,
+        /// Show what would be installed without installing
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
 
-    // Extract file contents from package
-    info!("Extracting file contents from package...");
-    let extracted_files = package.extract_file_contents()?;
-    info!("Extracted {} files", extracted_files.len());
-
-    // Initialize CAS and file deployer
-    let db_dir = std::env::var("CONARY_DB_DIR").unwrap_or_else(|_| "/var/lib/conary".to_string());
-    let objects_dir = PathBuf::from(&db_dir).join("objects");
-    let install_root = PathBuf::from(root);
-    let deployer = conary::filesystem::FileDeployer::new(&objects_dir, &install_root)?;
-
-    // Perform installation within a changeset transaction
-    conary::db::transaction(conn, |tx| {
-        // Create changeset for this installation
-        let changeset_desc = if let Some(old) = old_trove {
-            format!(
-                "Upgrade {} from {} to {}",
-                package.name(),
-                old.version,
-                package.version()
-            )
+#[derive(Subcommand)]
+enum CasAction {
+    /// Report content installed at more than one path and what dedupe would save
+    Analyze {
+        /// Number of duplicated blobs to list
+        #[arg(long, default_value = "10")]
+        top: usize,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete CAS objects no installed file or recent changeset refers to
+    Gc {
+        /// Only report what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+        /// Keep content of changesets from this many days (default: gc_retention_days)
+        #[arg(long)]
+        keep_days: Option<u32>,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DoctorCheck {
+    /// List package names offered by enabled repositories of different formats, and which one wins
+    Repos {
+        /// Number of names to list
+        #[arg(long, default_value = "50")]
+        limit: usize,
+        /// Number of names to skip, for the next page
+        #[arg(long, default_value = "0")]
+        offset: usize,
+        /// Database path (default: /var/lib/conary/conary.db)
+        #[arg(short, long, default_value = "/var/lib/conary/conary.db")]
+        db_path: String,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Settings managed through config-set, in the order config-get lists them
+const CONFIG_KEYS: [&str; 12] = [
+    conary::resources::MAX_PARALLEL_DOWNLOADS_SETTING,
+    conary::resources::MAX_EXTRACTION_BUFFER_SETTING,
+    conary::resources::TMP_QUOTA_SETTING,
+    conary::resources::MAX_THREADS_SETTING,
+    conary::paths::MAX_FILENAME_SETTING,
+    conary::paths::MAX_PATH_SETTING,
+    conary::paths::FAT_NAMES_SETTING,
+    conary::scriptlet::SANDBOX_SETTING,
+    conary::gc::GC_RETENTION_SETTING,
+    conary::paths::STAGED_DEPLOY_SETTING,
+    conary::installer::INSTALLONLY_SETTING,
+    conary::installer::INSTALLONLY_LIMIT_SETTING,
+];
+
+/// Check that `value` is acceptable for the config setting `key`
+fn validate_config(key: &str, value: &str) -> Result<()> {
+    if key == conary::paths::OBJECTS_PATH_SETTING {
+        return Err(anyhow::anyhow!(
+            "{} is managed by 'conary cas-relocate', which also moves the objects",
+            key
+        ));
+    }
+    if key == conary::scriptlet::SANDBOX_SETTING || key == conary::paths::STAGED_DEPLOY_SETTING {
+        if value != "true" && value != "false" {
+            return Err(anyhow::anyhow!("{} must be 'true' or 'false'", key));
+        }
+        return Ok(());
+    }
+    if key == conary::gc::GC_RETENTION_SETTING {
+        conary::gc::parse_retention(value)?;
+        return Ok(());
+    }
+    if conary::paths::PathLimits::validate(key, value)? {
+        return Ok(());
+    }
+    if conary::installer::InstallOnly::validate(key, value)? {
+        return Ok(());
+    }
+    if !ResourceLimits::validate(key, value)? {
+        return Err(anyhow::anyhow!(
+            "Unknown setting '{}' (known: {})",
+            key,
+            CONFIG_KEYS.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Value shown for a config setting that has not been set
+fn config_default(key: &str) -> String {
+    if key == conary::scriptlet::SANDBOX_SETTING || key == conary::paths::STAGED_DEPLOY_SETTING {
+        return "false".to_string();
+    }
+    if key == conary::gc::GC_RETENTION_SETTING {
+        return conary::gc::DEFAULT_RETENTION_DAYS.to_string();
+    }
+    ResourceLimits::default_value(key)
+        .or_else(|| conary::paths::PathLimits::default_value(key))
+        .or_else(|| conary::installer::InstallOnly::default_value(key))
+        .unwrap_or_else(|| "unset".to_string())
+}
+
+/// Turn an error from package selection or dependency resolution into the
+/// command's error, printing a resolution failure as JSON first with `json`
+fn resolution_failure(err: conary::Error, json: bool) -> anyhow::Error {
+    if json && let conary::Error::ResolutionError(failure) = &err {
+        match serde_json::to_string_pretty(failure) {
+            Ok(rendered) => println!("{}", rendered),
+            Err(e) => warn!("Could not render the resolution failure as JSON: {}", e),
+        }
+    }
+    err.into()
+}
+
+/// Refuse a transaction that leaves a requirement of an affected package unmet
+///
+/// `operation` names the transaction in the report, such as "Update".
+fn refuse_conflicts(conflicts: &[conary::resolver::Conflict], operation: &str) -> Result<()> {
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let report: Vec<String> = conflicts
+        .iter()
+        .map(|conflict| format!("  {}", conflict.to_string().trim_end()))
+        .collect();
+    Err(anyhow::anyhow!(
+        "{} would break dependencies, nothing was changed:\n{}",
+        operation,
+        report.join("\n")
+    ))
+}
+
+/// Report a requested package the plan left out as already installed
+///
+/// Backs `install --idempotent`. With `verify` set the installed files are
+/// checked against their recorded hashes first, and a mismatch is an error
+/// rather than a silent no-op; config files are skipped since local edits
+/// are expected.
+fn already_installed(
+    conn: &rusqlite::Connection,
+    layout: &conary::paths::Layout,
+    package: &conary::plan::PlannedPackage,
+    verify: bool,
+) -> Result<()> {
+    let (name, version) = (&package.name, &package.version);
+    let mut found = None;
+    for trove in conary::db::models::Trove::find_by_name(conn, name)? {
+        if trove.architecture == package.architecture
+            && conary::installer::installed_as(conn, &trove, version)?
+        {
+            found = Some(trove);
+            break;
+        }
+    }
+    let Some(trove) = found else {
+        return Ok(());
+    };
+
+    if verify && let Some(trove_id) = trove.id {
+        let deployer =
+            conary::filesystem::FileDeployer::new(layout.objects_dir(), layout.install_root())?;
+        let mut mismatched = 0;
+        for file in conary::db::models::FileEntry::find_by_trove(conn, trove_id)? {
+            if file.is_config || trove.install_modifiers.excludes(&file.path) {
+                continue;
+            }
+            if !deployer.verify_entry(&file).unwrap_or(false) {
+                warn!("{} of {} does not match its recorded hash", file.path, name);
+                mismatched += 1;
+            }
+        }
+        if mismatched > 0 {
+            return Err(anyhow::anyhow!(
+                "{} {} is installed but {} of its files do not match (run 'conary verify --repair {}')",
+                name,
+                version,
+                mismatched,
+                name
+            ));
+        }
+    }
+
+    println!("{} {} already installed, nothing to do", name, version);
+    Ok(())
+}
+
+/// A `--since` date as the timestamp changesets are stored with
+fn parse_since(since: &str) -> Result<String> {
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    if let Ok(at) = NaiveDateTime::parse_from_str(since, "%Y-%m-%d %H:%M:%S") {
+        return Ok(at.format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+    let date = NaiveDate::parse_from_str(since, "%Y-%m-%d").map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid --since date '{}': expected YYYY-MM-DD or \"YYYY-MM-DD HH:MM:SS\"",
+            since
+        )
+    })?;
+    Ok(date.format("%Y-%m-%d 00:00:00").to_string())
+}
+
+/// When a changeset was applied, rolled back or created, in that order of preference
+fn changeset_timestamp(changeset: &conary::db::models::Changeset) -> &str {
+    changeset
+        .applied_at
+        .as_ref()
+        .or(changeset.rolled_back_at.as_ref())
+        .or(changeset.created_at.as_ref())
+        .map(|s| s.as_str())
+        .unwrap_or("pending")
+}
+
+fn package_action_label(action: conary::query::PackageAction) -> &'static str {
+    use conary::query::PackageAction;
+
+    match action {
+        PackageAction::Installed => "installed",
+        PackageAction::Removed => "removed",
+        PackageAction::Replaced => "replaced",
+        PackageAction::RolledBack => "rolled back",
+    }
+}
+
+/// Name, version and architecture of a package version
+fn trove_label(change: &conary::query::TroveChange) -> String {
+    match &change.architecture {
+        Some(arch) => format!("{} {} [{}]", change.name, change.version, arch),
+        None => format!("{} {}", change.name, change.version),
+    }
+}
+
+/// Print what a changeset installed, removed and touched on disk
+fn print_changeset_report(report: &conary::query::ChangesetReport) {
+    let changeset = &report.changeset;
+    println!(
+        "Changeset {}: {} ({:?}, {})",
+        changeset.id.unwrap(),
+        changeset.description,
+        changeset.status,
+        changeset_timestamp(changeset)
+    );
+
+    if report.troves.is_empty() {
+        println!("  No packages recorded");
+    }
+    for change in &report.troves {
+        println!(
+            "  {:<11} {}",
+            package_action_label(change.action),
+            trove_label(change)
+        );
+    }
+
+    // File actions grouped by kind; anything unexpected is listed last as recorded
+    let groups = [
+        ("add", "Added"),
+        ("modify", "Modified"),
+        ("delete", "Removed"),
+        ("config-kept", "Config kept"),
+        ("dir-to-file", "Directories replaced by files"),
+        ("dir-saved", "Directories moved aside for files"),
+        ("file-to-dir", "Files replaced by directories"),
+        ("dir-mode", "Directory modes tightened"),
+    ];
+    for (action, heading) in groups {
+        let files: Vec<&str> = report
+            .files
+            .iter()
+            .filter(|file| file.action == action)
+            .map(|file| file.path.as_str())
+            .collect();
+        if !files.is_empty() {
+            println!("\n{} ({}):", heading, files.len());
+            for path in files {
+                println!("  {}", path);
+            }
+        }
+    }
+    let other: Vec<_> = report
+        .files
+        .iter()
+        .filter(|file| !groups.iter().any(|(action, _)| *action == file.action))
+        .collect();
+    if !other.is_empty() {
+        println!("\nOther:");
+        for file in other {
+            println!("  {:<11} {}", file.action, file.path);
+        }
+    }
+    println!(
+        "\nTotal: {} package(s), {} file(s)",
+        report.troves.len(),
+        report.files.len()
+    );
+}
+
+/// Point out an install that was staged rather than deployed
+fn print_staged(report: &conary::InstallReport) {
+    if report.staged {
+        println!(
+            "Changeset {} is staged; run 'conary apply-pending' to move its files into place",
+            report.changeset_id
+        );
+    }
+}
+
+/// Report the versions of installonly packages removed to stay within the limit
+fn print_pruned(report: &conary::InstallReport) {
+    for removed in report.pruned.iter().flat_map(|pruned| &pruned.removed) {
+        println!(
+            "Removed package: {} version {} (beyond {})",
+            removed.name,
+            removed.version,
+            conary::installer::INSTALLONLY_LIMIT_SETTING
+        );
+    }
+}
+
+/// Report directories an upgrade moved aside to make room for a file
+fn print_saved_dirs(package: &conary::installer::InstalledPackage) {
+    for saved in &package.saved_dirs {
+        let path = saved
+            .strip_suffix(conary::filesystem::SAVED_DIR_SUFFIX)
+            .unwrap_or(saved);
+        println!(
+            "{} {} ships {} as a file; the directory there was moved to {}",
+            package.name, package.version, path, saved
+        );
+    }
+}
+
+/// Print what a rollback would do, with local changes it discards flagged
+fn print_rollback_plan(plan: &conary::rollback::RollbackPlan) {
+    use conary::rollback::FileAction;
+
+    println!(
+        "Rollback of changeset {} ({})",
+        plan.changeset_id, plan.description
+    );
+    println!("\nPackages to remove ({}):", plan.troves.len());
+    for trove in &plan.troves {
+        println!("  {} {}", trove.name, trove.version);
+    }
+    if plan.staged {
+        println!(
+            "\nThe changeset is staged; its pending files are discarded and the root is left as it is"
+        );
+    }
+
+    let clobber = |file: &conary::rollback::PlannedFile| {
+        if file.modified {
+            "  WILL CLOBBER local changes"
         } else {
-            format!("Install {}-{}", package.name(), package.version())
-        };
-        let mut changeset = conary::db::models::Changeset::new(changeset_desc);
-        let changeset_id = changeset.insert(tx)?;
-
-        // If upgrading, remove the old trove first
-        if let Some(old) = old_trove {
-            if let Some(old_id) = old.id {
-                info!("Removing old version {} before upgrade", old.version);
-                conary::db::models::Trove::delete(tx, old_id)?;
-            }
-        }
-
-        // Convert to Trove and associate with changeset
-        let mut trove = package.to_trove();
-        trove.installed_by_changeset_id = Some(changeset_id);
-        let trove_id = trove.insert(tx)?;
-
-        // Process each file: conflict check, store in CAS, deploy, track in DB
-        for file in &extracted_files {
-            // Conflict detection (skip if upgrading same package)
-            if deployer.file_exists(&file.path) {
-                if let Some(existing) = conary::db::models::FileEntry::find_by_path(tx, &file.path)? {
-                    let owner_trove = conary::db::models::Trove::find_by_id(tx, existing.trove_id)?;
-                    if let Some(owner) = owner_trove {
-                        if owner.name != package.name() {
-                            return Err(conary::Error::InitError(format!(
-                                "File conflict: {} is owned by package {}",
-                                file.path, owner.name
-                            )));
-                        }
-                    }
-                } else if old_trove.is_none() {
-                    // Only error on orphans for fresh installs, not upgrades
-                    return Err(conary::Error::InitError(format!(
-                        "File conflict: {} exists but is not tracked by any package",
-                        file.path
-                    )));
+            ""
+        }
+    };
+    let heading = |action: &FileAction| match action {
+        FileAction::Remove => "Files to remove",
+        FileAction::Restore { .. } => "Files to restore",
+        FileAction::RemoveConfigNew => "Unused new config versions to remove",
+        FileAction::RestoreDirectory { .. } => "Directories to restore in place of files",
+        FileAction::RestoreFile { .. } => "Files to restore in place of directories",
+    };
+    let groups = [
+        "Files to remove",
+        "Files to restore",
+        "Unused new config versions to remove",
+        "Directories to restore in place of files",
+        "Files to restore in place of directories",
+    ];
+    for group in groups {
+        let files: Vec<_> = plan
+            .files
+            .iter()
+            .filter(|file| heading(&file.action) == group)
+            .collect();
+        if files.is_empty() {
+            continue;
+        }
+        println!("\n{} ({}):", group, files.len());
+        for file in files {
+            match &file.action {
+                FileAction::Restore { hash } => println!(
+                    "  {} -> {}{}",
+                    file.path,
+                    &hash[..12.min(hash.len())],
+                    clobber(file)
+                ),
+                FileAction::RestoreDirectory { saved: Some(saved) } => {
+                    println!("  {} (from {})", file.path, saved)
                 }
+                _ => println!("  {}{}", file.path, clobber(file)),
             }
+        }
+    }
 
-            // Store content in CAS
-            let hash = deployer.cas().store(&file.content)?;
+    if !plan.stranded.is_empty() {
+        println!("\nDependencies left unsatisfied ({}):", plan.stranded.len());
+        for dependent in &plan.stranded {
+            println!("  {} needs {}", dependent.package, dependent.needs);
+        }
+    }
 
-            // Store file content metadata in database
-            tx.execute(
-                "INSERT OR IGNORE INTO file_contents (sha256_hash, content_path, size) VALUES (?1, ?2, ?3)",
-                [&hash, &format!("objects/{}/{}", &hash[0..2], &hash[2..]), &file.size.to_string()],
-            )?;
+    let clobbered = plan.clobbered().count();
+    if clobbered > 0 {
+        println!(
+            "\nWARNING: {} file(s) changed since the changeset; their local changes will be lost",
+            clobbered
+        );
+    }
+}
+
+/// Report running processes that use files about to be replaced
+///
+/// Depending on `mode`, only warns, asks for confirmation on stdin, or fails.
+fn check_running_processes(paths: &[String], mode: conary::running::RunningCheck) -> Result<()> {
+    use conary::running::{ProcFs, ProcessScan, RunningCheck};
+
+    let procfs = ProcFs::default();
+    let in_use = ProcessScan::new(&procfs).users_of(paths)?;
+    if in_use.is_empty() {
+        return Ok(());
+    }
 
-            // Store file metadata in database
-            let mut file_entry = conary::db::models::FileEntry::new(
-                file.path.clone(),
-                hash.clone(),
-                file.size,
-                file.mode,
-                trove_id,
+    println!("The following running processes use files that will be replaced:");
+    for usage in &in_use {
+        let unit = usage
+            .process
+            .unit
+            .as_deref()
+            .map(|unit| format!(" [{}]", unit))
+            .unwrap_or_default();
+        println!(
+            "  {} {}{}: {}",
+            usage.process.pid,
+            usage.process.comm,
+            unit,
+            usage.files.join(", ")
+        );
+    }
+
+    match mode {
+        RunningCheck::Warn => {
+            warn!(
+                "{} running process(es) may need a restart after the update",
+                in_use.len()
             );
-            file_entry.insert(tx)?;
+            Ok(())
+        }
+        RunningCheck::Confirm => {
+            print!("Continue with the update? [y/N] ");
+            io::Write::flush(&mut io::stdout())?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if matches!(answer.trim(), "y" | "Y" | "yes") {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("Update cancelled"))
+            }
+        }
+        RunningCheck::Abort => Err(anyhow::anyhow!(
+            "{} running process(es) use files that would be replaced",
+            in_use.len()
+        )),
+    }
+}
 
-            // Track in file history
-            let action = if deployer.file_exists(&file.path) { "modify" } else { "add" };
-            tx.execute(
-                "INSERT INTO file_history (changeset_id, path, sha256_hash, action) VALUES (?1, ?2, ?3, ?4)",
-                [&changeset_id.to_string(), &file.path, &hash, action],
-            )?;
+/// Outcome of verifying one installed file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum VerifyStatus {
+    Ok,
+    Modified,
+    Missing,
+    /// Content matches but ownership or mode drifted
+    Attrs,
+    /// A config file edited locally
+    Config,
+    /// Never deployed because the install excluded it
+    Excluded,
+    /// Shipped by the package file but not recorded in the database
+    Untracked,
+    /// Recorded in the database but not shipped by the package file
+    NotInPackage,
+    /// Database hash or mode differs from the package file
+    DbMismatch,
+    /// Failed verification and was restored from the CAS
+    Repaired,
+    /// Failed verification and its content is gone from the CAS
+    Unrepairable,
+    /// Installed by a staged changeset and checked in its pending tree;
+    /// details describe what is wrong with the pending copy, if anything
+    Staged,
+    /// Found under the --orphans prefix but owned by no package
+    Orphan,
+}
+
+impl VerifyStatus {
+    /// Name of the finding stored for `update`, for the statuses that are
+    /// local changes an update would overwrite
+    fn finding(self) -> Option<&'static str> {
+        match self {
+            VerifyStatus::Modified => Some("modified"),
+            VerifyStatus::Missing => Some("missing"),
+            VerifyStatus::Attrs => Some("attrs"),
+            VerifyStatus::Unrepairable => Some("unrepairable"),
+            _ => None,
         }
+    }
+}
 
-        // Store dependencies in database
-        for dep in package.dependencies() {
-            let dep_type_str = match dep.dep_type {
-                DependencyType::Runtime => "runtime",
-                DependencyType::Build => "build",
-                DependencyType::Optional => "optional",
-            };
+/// A file as shipped by a package file
+struct ManifestEntry {
+    /// None for directories
+    sha256: Option<String>,
+    mode: i32,
+}
 
-            let mut dep_entry = conary::db::models::DependencyEntry::new(
-                trove_id,
-                dep.name.clone(),
-                dep.version.clone(),
-                dep_type_str.to_string(),
-                None,
-            );
-            dep_entry.insert(tx)?;
+/// Hash every file a package ships, keyed by path
+///
+/// Hashes follow the CAS conventions: a symlink hashes its target and a
+/// hardlink takes the hash of the file it points to. Directories have none.
+fn package_manifest(
+    package: &dyn PackageFormat,
+) -> conary::Result<std::collections::BTreeMap<String, ManifestEntry>> {
+    use conary::db::models::FileType;
+    use conary::filesystem::CasStore;
+
+    let mut manifest = std::collections::BTreeMap::new();
+    package.extract_files(&mut |FileStream { file, content }| {
+        let sha256 = match (file.file_type, &file.link_target) {
+            (FileType::Hardlink, Some(target)) => manifest
+                .get(target)
+                .map(|entry: &ManifestEntry| entry.sha256.clone())
+                .ok_or_else(|| {
+                    conary::Error::ParseError(format!(
+                        "Hardlink {} points to unknown file {}",
+                        file.path, target
+                    ))
+                })?,
+            (FileType::Symlink, Some(target)) => Some(CasStore::compute_hash(target.as_bytes())),
+            (FileType::Directory, _) => None,
+            _ => Some(CasStore::compute_hash_reader(content)?),
+        };
+        manifest.insert(
+            file.path,
+            ManifestEntry {
+                sha256,
+                mode: file.mode,
+            },
+        );
+        Ok(())
+    })?;
+    Ok(manifest)
+}
+
+/// Compare an installed trove with the package file it came from
+///
+/// Every path in either the package or the database gets one result.
+/// Files the database does not track are tracking drift; rows for files the
+/// package does not ship, or whose hash or mode differ from it, are metadata
+/// drift; files on disk that differ from the package are content mismatches.
+fn verify_against_package(
+    conn: &rusqlite::Connection,
+    deployer: &conary::filesystem::FileDeployer,
+    trove: &conary::db::models::Trove,
+    package: &dyn PackageFormat,
+) -> Result<Vec<VerifyResult>> {
+    let manifest = package_manifest(package)?;
+    let recorded: HashMap<String, conary::db::models::FileEntry> =
+        conary::db::models::FileEntry::find_by_trove(conn, trove.id.unwrap())?
+            .into_iter()
+            .map(|file| (file.path.clone(), file))
+            .collect();
+
+    let result = |path: &str, status: VerifyStatus, details: Vec<String>| VerifyResult {
+        path: path.to_string(),
+        package: trove.name.clone(),
+        status,
+        details,
+    };
+
+    let mut results = Vec::new();
+    for (path, entry) in &manifest {
+        let mut details = Vec::new();
+        if let Some(file) = recorded.get(path) {
+            if file.sha256_hash != entry.sha256 {
+                details.push(format!(
+                    "database hash {} (package {})",
+                    file.sha256_hash.as_deref().unwrap_or("none"),
+                    entry.sha256.as_deref().unwrap_or("none")
+                ));
+            }
+            if file.permissions & 0o7777 != entry.mode & 0o7777 {
+                details.push(format!(
+                    "database mode {:04o} (package {:04o})",
+                    file.permissions & 0o7777,
+                    entry.mode & 0o7777
+                ));
+            }
+            if !details.is_empty() {
+                results.push(result(path, VerifyStatus::DbMismatch, details));
+                continue;
+            }
+        } else {
+            results.push(result(path, VerifyStatus::Untracked, Vec::new()));
+            continue;
         }
 
-        // Mark changeset as applied
-        changeset.update_status(tx, conary::db::models::ChangesetStatus::Applied)?;
+        // The row matches the package, so the file on disk is checked against both
+        let file = &recorded[path];
+        if !deployer.file_exists(path) {
+            results.push(result(path, VerifyStatus::Missing, Vec::new()));
+        } else if !deployer.verify_entry(file)? {
+            results.push(result(path, VerifyStatus::Modified, Vec::new()));
+        } else {
+            let drift = deployer.verify_attributes(file)?;
+            let status = if drift.is_empty() {
+                VerifyStatus::Ok
+            } else {
+                VerifyStatus::Attrs
+            };
+            results.push(result(path, status, drift));
+        }
+    }
+
+    let mut extra: Vec<&String> = recorded
+        .keys()
+        .filter(|path| !manifest.contains_key(*path))
+        .collect();
+    extra.sort();
+    for path in extra {
+        results.push(result(path, VerifyStatus::NotInPackage, Vec::new()));
+    }
+
+    Ok(results)
+}
+
+/// Verification result for one file, as emitted by `verify --json`
+#[derive(Debug, Clone, serde::Serialize)]
+struct VerifyResult {
+    path: String,
+    package: String,
+    status: VerifyStatus,
+    details: Vec<String>,
+}
+
+/// Verification found problems; `verify --porcelain` exits 1 for these
+#[derive(Debug)]
+struct VerifyFindings;
+
+impl std::fmt::Display for VerifyFindings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Verification failed")
+    }
+}
+
+impl std::error::Error for VerifyFindings {}
+
+/// Updates are available; `check-update` exits 100 for these, as dnf does
+#[derive(Debug)]
+struct UpdatesAvailable;
+
+impl std::fmt::Display for UpdatesAvailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Updates are available")
+    }
+}
 
+impl std::error::Error for UpdatesAvailable {}
+
+/// List the files under `prefix` that no package owns, as `verify --orphans`
+///
+/// Orphans are printed as the walk finds them; the JSON document is written
+/// the same way, its summary last. Any orphan fails verification.
+fn verify_orphans(
+    conn: &rusqlite::Connection,
+    root: &Path,
+    prefix: &str,
+    exclude: &[String],
+    json: bool,
+    porcelain: bool,
+) -> Result<()> {
+    let prefix = format!("/{}", prefix.trim_matches('/'));
+    if json {
+        print!("{{\n  \"orphans\": [");
+    }
+    let mut listed = 0;
+    let summary = conary::query::find_orphans(conn, root, &prefix, exclude, |orphan| {
+        if porcelain {
+            let result = VerifyResult {
+                path: orphan.path.clone(),
+                package: String::new(),
+                status: VerifyStatus::Orphan,
+                details: vec![orphan.size.to_string()],
+            };
+            for line in porcelain_lines(&result) {
+                println!("{}", line);
+            }
+        } else if json {
+            print!(
+                "{}\n    {}",
+                if listed == 0 { "" } else { "," },
+                serde_json::json!(orphan)
+            );
+        } else {
+            println!(
+                "ORPHAN: {} ({} bytes, modified {})",
+                orphan.path,
+                orphan.size,
+                orphan.modified.as_deref().unwrap_or("unknown")
+            );
+        }
+        listed += 1;
         Ok(())
     })?;
 
-    // Deploy files to filesystem (outside transaction for safety)
-    info!("Deploying files to filesystem...");
-    for file in &extracted_files {
-        let hash = conary::filesystem::CasStore::compute_hash(&file.content);
-        deployer.deploy_file(&file.path, &hash, file.mode as u32)?;
+    if json {
+        let summary = serde_json::to_string_pretty(&summary)?.replace('\n', "\n  ");
+        println!(
+            "{}  ],\n  \"summary\": {}\n}}",
+            if listed == 0 { "" } else { "\n" },
+            summary
+        );
+    } else if !porcelain {
+        println!("\nOrphan scan of {}:", prefix);
+        println!("  Tracked: {} files", summary.tracked);
+        println!("  Orphaned: {} files", summary.orphaned);
+        println!("  Excluded: {} paths", summary.excluded);
+    }
+
+    if summary.orphaned > 0 {
+        return Err(VerifyFindings.into());
+    }
+    Ok(())
+}
+
+/// Render a verification result as `verify --porcelain` (v1) lines
+///
+/// The format is a stable interface for scripts and configuration
+/// management; it will not change within v1. Each problem is one line of
+/// four tab-separated fields, `<status>\t<path>\t<package>\t<detail>`, with
+/// an empty detail when there is nothing to add. A tab, newline or
+/// backslash in a field is written as `\t`, `\n` or `\\`. Status codes:
+///
+/// - `M` content modified (config files only with --strict-config)
+/// - `D` missing
+/// - `P` permissions differ; the detail is `mode <actual> (expected <mode>)`
+/// - `O` owner or group differs; one line each
+/// - `A` extended attribute missing or changed, such as file capabilities;
+///   one line each
+/// - `U` untracked: shipped by the --package-file but not recorded
+/// - `X` excluded at install time and not deployed
+/// - `C` config file edited locally
+/// - `N` recorded but not shipped by the --package-file
+/// - `?` owned by no package (--orphans); the detail is the size in bytes
+/// - `H` recorded hash or mode differs from the --package-file
+///
+/// Clean files produce no line. `X` and `C` lines are informational; any
+/// other line is a finding. The exit status is 0 without findings, 1 with
+/// findings and 2 or more when verification could not run.
+fn porcelain_lines(result: &VerifyResult) -> Vec<String> {
+    let escape = |field: &str| {
+        field
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+    };
+    let line = |code: char, detail: &str| {
+        format!(
+            "{}\t{}\t{}\t{}",
+            code,
+            escape(&result.path),
+            escape(&result.package),
+            escape(detail)
+        )
+    };
+    let joined = result.details.join(", ");
+
+    match result.status {
+        VerifyStatus::Ok | VerifyStatus::Repaired => Vec::new(),
+        VerifyStatus::Modified => vec![line('M', &joined)],
+        VerifyStatus::Missing | VerifyStatus::Unrepairable => vec![line('D', &joined)],
+        VerifyStatus::Attrs => result
+            .details
+            .iter()
+            .map(|detail| {
+                let code = match detail.split(' ').next() {
+                    Some("mode") => 'P',
+                    Some("xattr") => 'A',
+                    _ => 'O',
+                };
+                line(code, detail)
+            })
+            .collect(),
+        VerifyStatus::Excluded => vec![line('X', &joined)],
+        VerifyStatus::Config => vec![line('C', &joined)],
+        VerifyStatus::Untracked => vec![line('U', &joined)],
+        VerifyStatus::NotInPackage => vec![line('N', &joined)],
+        VerifyStatus::DbMismatch => vec![line('H', &joined)],
+        VerifyStatus::Staged => vec![line('S', &joined)],
+        VerifyStatus::Orphan => vec![line('?', &joined)],
+    }
+}
+
+/// Redeploy the files that failed verification from the CAS
+///
+/// `files` and `results` line up index by index. Restored files are marked
+/// Repaired and recorded in a "Repair <pkg>" changeset per package; files
+/// whose content is no longer in the CAS are marked Unrepairable.
+fn repair_files(
+    conn: &mut rusqlite::Connection,
+    deployer: &conary::filesystem::FileDeployer,
+    files: &[(conary::db::models::FileEntry, String)],
+    results: &mut [VerifyResult],
+) -> Result<()> {
+    use conary::db::models::FileType;
+
+    // Hardlinks last, so the files they point to are back first
+    let mut failed: Vec<usize> = (0..results.len())
+        .filter(|&i| {
+            matches!(
+                results[i].status,
+                VerifyStatus::Modified | VerifyStatus::Missing | VerifyStatus::Attrs
+            )
+        })
+        .collect();
+    failed.sort_by_key(|&i| files[i].0.file_type == FileType::Hardlink);
+
+    let mut restored: Vec<(&str, &str, &str)> = Vec::new();
+    for i in failed {
+        let (file, pkg_name) = &files[i];
+        if !deployer.can_redeploy(file) {
+            results[i].status = VerifyStatus::Unrepairable;
+            results[i].details = vec!["content missing from CAS".to_string()];
+            continue;
+        }
+        let action = if results[i].status == VerifyStatus::Missing {
+            "add"
+        } else {
+            "modify"
+        };
+        deployer.deploy_entry(file)?;
+        results[i].status = VerifyStatus::Repaired;
+        restored.push((pkg_name.as_str(), file.path.as_str(), action));
+        info!("Repaired {}", file.path);
+    }
+
+    let hashes: HashMap<&str, Option<&str>> = files
+        .iter()
+        .map(|(file, _)| (file.path.as_str(), file.sha256_hash.as_deref()))
+        .collect();
+    let mut by_package: std::collections::BTreeMap<&str, Vec<(&str, &str)>> =
+        std::collections::BTreeMap::new();
+    for (pkg_name, path, action) in restored {
+        by_package.entry(pkg_name).or_default().push((path, action));
+    }
+    for (pkg_name, repairs) in by_package {
+        conary::db::transaction(conn, |tx| {
+            let mut changeset = conary::db::models::Changeset::new(format!(
+                "Repair {} ({} files restored)",
+                pkg_name,
+                repairs.len()
+            ));
+            let changeset_id = changeset.insert(tx)?;
+
+            for &(path, action) in &repairs {
+                tx.execute(
+                    "INSERT INTO file_history (changeset_id, path, sha256_hash, action) VALUES (?1, ?2, ?3, ?4)",
+                    rusqlite::params![changeset_id, path, hashes[path], action],
+                )?;
+            }
+
+            changeset.update_status(tx, conary::db::models::ChangesetStatus::Applied)?;
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Progress line for one or more downloads
+///
+/// Downloads running in parallel share a single line on stderr that shows
+/// their combined total and is redrawn in place. Nothing is drawn with
+/// --quiet or when stderr is not a terminal.
+struct DownloadProgress {
+    label: String,
+    enabled: bool,
+    state: std::sync::Mutex<ProgressState>,
+}
+
+struct ProgressState {
+    /// Bytes received and expected size of each download
+    downloads: Vec<(u64, Option<u64>)>,
+    last_draw: Option<std::time::Instant>,
+}
+
+impl DownloadProgress {
+    /// Minimum time between redraws
+    const REDRAW_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+    /// A progress line for `downloads` downloads, reported by index
+    fn new(label: impl Into<String>, downloads: usize, quiet: bool) -> Self {
+        Self {
+            label: label.into(),
+            enabled: !quiet && io::IsTerminal::is_terminal(&io::stderr()),
+            state: std::sync::Mutex::new(ProgressState {
+                downloads: vec![(0, None); downloads],
+                last_draw: None,
+            }),
+        }
+    }
+
+    /// Record the progress of one download and redraw if it is time to
+    fn update(&self, index: usize, received: u64, total: Option<u64>) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.downloads[index] = (received, total);
+
+        let now = std::time::Instant::now();
+        let finished = total.is_some_and(|total| received >= total);
+        if !finished
+            && state
+                .last_draw
+                .is_some_and(|last| now - last < Self::REDRAW_INTERVAL)
+        {
+            return;
+        }
+        state.last_draw = Some(now);
+
+        let received: u64 = state.downloads.iter().map(|(received, _)| received).sum();
+        let totals: Option<u64> = state.downloads.iter().map(|(_, total)| *total).sum();
+        let line = match totals {
+            Some(total) if total > 0 => format!(
+                "{}: {} / {} ({}%)",
+                self.label,
+                format_size(Some(received as i64), false),
+                format_size(Some(total as i64), false),
+                received.min(total) * 100 / total
+            ),
+            _ => format!(
+                "{}: {}",
+                self.label,
+                format_size(Some(received as i64), false)
+            ),
+        };
+        eprint!("\r\x1b[2K{}", line);
+    }
+
+    /// Callback for the single download of a one-download progress line
+    fn reporter(&self) -> impl Fn(u64, Option<u64>) + Sync + '_ {
+        move |received, total| self.update(0, received, total)
+    }
+
+    /// Clear the progress line
+    fn finish(&self) {
+        if self.enabled && self.state.lock().unwrap().last_draw.is_some() {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// A repository's download helper and its policy, for listings
+fn describe_download_helper(repo: &conary::db::models::Repository) -> Option<String> {
+    let command = repo.download_helper.as_ref()?;
+    let scope = if repo.helper_metadata {
+        "packages and metadata"
+    } else {
+        "packages"
+    };
+    let on_failure = if repo.helper_fallback {
+        "falls back to HTTP"
+    } else {
+        "no fallback"
+    };
+    Some(format!("{} ({}, {})", command, scope, on_failure))
+}
+
+/// Describe a repository's URL policy, if it differs from the default
+fn describe_url_policy(repo: &conary::db::models::Repository) -> Option<String> {
+    match (repo.prefer_https, repo.allow_cross_host) {
+        (false, true) => None,
+        (true, true) => Some("https preferred".to_string()),
+        (false, false) => Some("repository host only".to_string()),
+        (true, false) => Some("https preferred, repository host only".to_string()),
+    }
+}
+
+/// Describe a repository's sync filter, if it has one
+fn describe_sync_filter(repo: &conary::db::models::Repository) -> Option<String> {
+    let mut parts = Vec::new();
+    if !repo.sync_include.is_empty() {
+        parts.push(format!("include {}", repo.sync_include.join(", ")));
+    }
+    if !repo.sync_exclude.is_empty() {
+        parts.push(format!("exclude {}", repo.sync_exclude.join(", ")));
+    }
+    (!parts.is_empty()).then(|| parts.join("; "))
+}
+
+/// The advisories of an update entry, as printed after it
+fn advisory_note(entry: &conary::installer::UpdatePlanEntry) -> String {
+    if entry.advisories.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", entry.advisories.join("; "))
+    }
+}
+
+/// Print the table `update --dry-run` shows, with the download totals
+fn print_update_plan(plan: &conary::installer::UpdatePlan) {
+    let header = [
+        "NAME",
+        "INSTALLED",
+        "CANDIDATE",
+        "REPOSITORY",
+        "DOWNLOAD",
+        "DELTA",
+    ]
+    .map(str::to_string);
+    let rows: Vec<[String; 6]> = plan
+        .entries
+        .iter()
+        .map(|entry| {
+            let candidate = if entry.replaces() {
+                format!(
+                    "{} {} (replaces)",
+                    entry.candidate.name, entry.candidate.version
+                )
+            } else if entry.alongside {
+                format!("{} (alongside)", entry.candidate.version)
+            } else {
+                entry.candidate.version.clone()
+            };
+            let delta = match entry.delta {
+                Some(_) => format!(
+                    "yes, saves {}",
+                    format_size(Some(entry.delta_savings()), false)
+                ),
+                None => "no".to_string(),
+            };
+            [
+                entry.installed.name.clone(),
+                entry.installed.version.clone(),
+                candidate,
+                entry.repository.clone(),
+                format_size(Some(entry.download_size()), false),
+                delta,
+            ]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|column| column.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |row: &[String; 6], note: &str| {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("  {}{}", cells.join("  ").trim_end(), note);
+    };
+    print_row(&header, "");
+    for (row, entry) in rows.iter().zip(&plan.entries) {
+        print_row(row, &advisory_note(entry));
+    }
+
+    let download = format_size(Some(plan.download_size()), false);
+    match plan.delta_savings() {
+        0 => println!("Total download: {}", download),
+        saved => println!(
+            "Total download: {} ({} saved by deltas)",
+            download,
+            format_size(Some(saved), false)
+        ),
+    }
+}
+
+/// Print an installed package for `conary info`
+///
+/// `failures` lists the files that failed verification, when it ran.
+fn print_installed_info(info: &conary::query::InstalledInfo, failures: Option<&[String]>) {
+    let trove = &info.trove;
+    let field = |label: &str, value: &str| println!("{:<14}: {}", label, value);
+
+    field("Name", &trove.name);
+    field("Version", &trove.version);
+    field(
+        "Architecture",
+        trove.architecture.as_deref().unwrap_or("none"),
+    );
+    field("Description", trove.description.as_deref().unwrap_or(""));
+    field(
+        "Installed",
+        trove.installed_at.as_deref().unwrap_or("unknown"),
+    );
+    field("Reason", trove.install_reason.as_str());
+    match (&info.origin_repository, &trove.origin_url) {
+        (Some(repo), Some(url)) => field("Repository", &format!("{} ({})", repo, url)),
+        (None, Some(url)) => field("Repository", &format!("removed repository ({})", url)),
+        _ => field("Repository", "local file"),
+    }
+    match &info.changeset {
+        Some(changeset) => field(
+            "Changeset",
+            &format!(
+                "{} ({})",
+                changeset.id.unwrap_or_default(),
+                changeset.description
+            ),
+        ),
+        None => field("Changeset", "unknown"),
+    }
+    field(
+        "Files",
+        &format!(
+            "{} ({})",
+            info.file_count,
+            format_size(Some(info.total_size), false)
+        ),
+    );
+    if !trove.install_modifiers.is_empty() {
+        field("Excluded", &trove.install_modifiers.excluded.join(", "));
+    }
+
+    let list = |items: Vec<String>| {
+        if items.is_empty() {
+            "none".to_string()
+        } else {
+            items.join(", ")
+        }
+    };
+    let dependencies = info
+        .dependencies
+        .iter()
+        .map(|dep| match &dep.version_constraint {
+            Some(constraint) => format!("{} {}", dep.depends_on_name, constraint),
+            None => dep.depends_on_name.clone(),
+        })
+        .collect();
+    field("Depends on", &list(dependencies));
+    let provides = info
+        .provides
+        .iter()
+        .map(|provide| match &provide.version {
+            Some(version) => format!("{} = {}", provide.name, version),
+            None => provide.name.clone(),
+        })
+        .collect();
+    field("Provides", &list(provides));
+    let flavors = info
+        .flavors
+        .iter()
+        .map(|flavor| format!("{}={}", flavor.key, flavor.value))
+        .collect();
+    field("Flavors", &list(flavors));
+
+    match &info.provenance {
+        Some(provenance) => {
+            let entries = [
+                ("Source", &provenance.source_url),
+                ("Branch", &provenance.source_branch),
+                ("Commit", &provenance.source_commit),
+                ("Build host", &provenance.build_host),
+                ("Build time", &provenance.build_time),
+                ("Builder", &provenance.builder),
+            ];
+            for (label, value) in entries {
+                if let Some(value) = value {
+                    field(label, value);
+                }
+            }
+        }
+        None => field("Provenance", "none recorded"),
+    }
+
+    match failures {
+        Some([]) => field("Verification", "all files match"),
+        Some(failed) => {
+            field(
+                "Verification",
+                &format!("{} files fail verification", failed.len()),
+            );
+            for path in failed {
+                println!("  {}", path);
+            }
+        }
+        None => {}
     }
-    info!("Successfully deployed {} files", extracted_files.len());
+}
+
+/// Print a repository package for `conary info --remote`
+fn print_available_info(info: &conary::query::AvailableInfo) {
+    let package = &info.package;
+    let field = |label: &str, value: &str| println!("{:<14}: {}", label, value);
+
+    field("Name", &package.name);
+    field("Version", &package.version);
+    field(
+        "Architecture",
+        package.architecture.as_deref().unwrap_or("none"),
+    );
+    field("Description", package.description.as_deref().unwrap_or(""));
+    field(
+        "Repository",
+        info.repository.as_deref().unwrap_or("unknown"),
+    );
+    field("Download URL", &package.download_url);
+    field("Size", &format_size(Some(package.size), false));
+    field(
+        "Checksum",
+        &format!("{} ({})", package.checksum, package.checksum_type.as_str()),
+    );
+    field(
+        "Depends on",
+        &if info.dependencies.is_empty() {
+            "none".to_string()
+        } else {
+            info.dependencies.join(", ")
+        },
+    );
+}
+
+/// Print the phase timings stored with a changeset
+fn print_timings(conn: &rusqlite::Connection, changeset_id: i64) -> Result<()> {
+    let Some(report) =
+        conary::db::models::Changeset::find_by_id(conn, changeset_id)?.and_then(|cs| cs.timings)
+    else {
+        println!("No timings recorded for changeset {}", changeset_id);
+        return Ok(());
+    };
+
+    println!("Timings ({:.1} ms total):", report.total_ms);
+    for timing in &report.phases {
+        println!(
+            "  {:<8} {:>9.1} ms  {:>6} files  {:>10}",
+            timing.phase.as_str(),
+            timing.duration_ms,
+            timing.files,
+            format_size(Some(timing.bytes as i64), false)
+        );
+    }
+    println!(
+        "  {:<8} {:>9.1} ms",
+        "other",
+        (report.total_ms - report.phases_ms()).max(0.0)
+    );
+    Ok(())
+}
+
+/// Format a byte count for display, "n/a" when it was not recorded
+fn format_size(bytes: Option<i64>, signed: bool) -> String {
+    let Some(bytes) = bytes else {
+        return "n/a".to_string();
+    };
+
+    let sign = if bytes < 0 {
+        "-"
+    } else if signed {
+        "+"
+    } else {
+        ""
+    };
+    let abs = bytes.unsigned_abs() as f64;
+    if abs >= 1_048_576.0 {
+        format!("{}{:.1} MB", sign, abs / 1_048_576.0)
+    } else if abs >= 1024.0 {
+        format!("{}{:.1} KB", sign, abs / 1024.0)
+    } else {
+        format!("{}{} B", sign, abs)
+    }
+}
+
+/// The files of `name` at `version`, from a matching package file or the installed package
+fn delta_version_files(
+    conn: &rusqlite::Connection,
+    cas: &conary::filesystem::CasStore,
+    name: &str,
+    version: &str,
+    package_files: &[String],
+) -> Result<conary::delta::VersionFiles> {
+    for package_file in package_files {
+        let package = conary::packages::open_package(Path::new(package_file))?;
+        if package.name() == name && package.version() == version {
+            return Ok(conary::delta::VersionFiles::from_package(
+                cas,
+                package.as_ref(),
+            )?);
+        }
+    }
+    conary::delta::VersionFiles::installed(conn, name, version)?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} {} is not installed and no --package-file provides it",
+            name,
+            version
+        )
+    })
+}
 
-    Ok(())
+/// Print a block of cumulative statistics
+fn print_stats_totals(totals: &conary::stats::StatsTotals) {
+    let mb = |bytes: i64| bytes as f64 / 1_048_576.0;
+    println!("  Downloaded: {:.2} MB", mb(totals.bytes_downloaded));
+    println!("  Metadata sync: {:.2} MB", mb(totals.sync_bytes));
+    println!("  CAS cache hits: {:.2} MB", mb(totals.cache_hit_bytes));
+    println!("  Packages installed: {}", totals.packages_installed);
+    println!("  Packages updated: {}", totals.packages_updated);
+    println!("  Packages removed: {}", totals.packages_removed);
 }
 
-fn main() -> Result<()> {
+/// Warnings logged during the run, for `update --summary`
+static WARNINGS: std::sync::OnceLock<conary::summary::WarningCollector> =
+    std::sync::OnceLock::new();
+
+fn main() -> std::process::ExitCode {
+    use tracing_subscriber::layer::{Layer, SubscriberExt};
+    use tracing_subscriber::util::SubscriberInitExt;
+
     // Initialize tracing subscriber for logging
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                // Keep stdout for command output so --json stays parseable
+                .with_writer(std::io::stderr)
+                .with_filter(env_filter),
+        )
+        // Warnings are collected whatever RUST_LOG says, so the summary never misses one
+        .with(
+            WARNINGS
+                .get_or_init(Default::default)
+                .clone()
+                .with_filter(tracing::level_filters::LevelFilter::WARN),
         )
         .init();
 
     let cli = Cli::parse();
+    // verify --porcelain promises 1 for findings and 2 for errors
+    let porcelain = matches!(
+        cli.command,
+        Some(Commands::Verify {
+            porcelain: true,
+            ..
+        })
+    );
+    // check-update promises 100 for available updates and 1 for errors
+    let check_update = matches!(cli.command, Some(Commands::CheckUpdate { .. }));
+
+    match run(cli) {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) if porcelain && e.is::<VerifyFindings>() => std::process::ExitCode::from(1),
+        Err(e) if check_update && e.is::<UpdatesAvailable>() => std::process::ExitCode::from(100),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            // Library errors carry their own exit code, see conary::Error::exit_code
+            let error = e
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<conary::Error>());
+            if error.is_some_and(conary::Error::is_retryable) {
+                eprintln!("This may be temporary; running the command again may succeed.");
+            }
+            std::process::ExitCode::from(match error {
+                _ if porcelain => 2,
+                _ if check_update => 1,
+                Some(error) => error.exit_code(),
+                None => 1,
+            })
+        }
+    }
+}
+
+/// Run the parsed command
+fn run(cli: Cli) -> Result<()> {
+    let quiet = cli.quiet;
 
     match cli.command {
-        Some(Commands::Init { db_path }) => {
+        Some(Commands::Init {
+            db_path,
+            objects_dir,
+            sandbox_scripts,
+            with_defaults,
+            empty: _,
+        }) => {
+            // Checked before anything is created, so a bad name leaves no database behind
+            let defaults = match with_defaults {
+                None => None,
+                Some(Some(distro)) => Some(
+                    distro
+                        .parse::<conary::repository::DefaultSet>()
+                        .map_err(|e| anyhow::anyhow!(e))?,
+                ),
+                Some(None) => Some(conary::repository::DefaultSet::Distro(
+                    conary::repository::Distro::detect()?,
+                )),
+            };
+
             info!("Initializing Conary database at: {}", db_path);
             conary::db::init(&db_path)?;
             println!("Database initialized successfully at: {}", db_path);
 
-            // Add default repositories for major distributions
             let conn = conary::db::open(&db_path)?;
 
-            info!("Adding default repositories...");
-
-            // Arch Linux core repository (priority 100)
-            match conary::repository::add_repository(
-                &conn,
-                "arch-core".to_string(),
-                "https://geo.mirror.pkgbuild.com/core/os/x86_64".to_string(),
-                true,
-                100,
-            ) {
-                Ok(_) => println!("  Added: arch-core (Arch Linux)"),
-                Err(e) => eprintln!("  Warning: Could not add arch-core: {}", e),
-            }
-
-            // Arch Linux extra repository (priority 95)
-            match conary::repository::add_repository(
-                &conn,
-                "arch-extra".to_string(),
-                "https://geo.mirror.pkgbuild.com/extra/os/x86_64".to_string(),
-                true,
-                95,
-            ) {
-                Ok(_) => println!("  Added: arch-extra (Arch Linux)"),
-                Err(e) => eprintln!("  Warning: Could not add arch-extra: {}", e),
+            if let Some(objects_dir) = objects_dir {
+                if !Path::new(&objects_dir).is_absolute() {
+                    return Err(anyhow::anyhow!(
+                        "Objects directory must be an absolute path: {}",
+                        objects_dir
+                    ));
+                }
+                conary::db::models::Setting::set(
+                    &conn,
+                    conary::paths::OBJECTS_PATH_SETTING,
+                    &objects_dir,
+                )?;
+                conary::filesystem::CasStore::new(&objects_dir)?;
+                println!("CAS objects will be stored in: {}", objects_dir);
             }
 
-            // Fedora 43 Everything repository (priority 90)
-            match conary::repository::add_repository(
-                &conn,
-                "fedora-43".to_string(),
-                "https://dl.fedoraproject.org/pub/fedora/linux/releases/43/Everything/x86_64/os".to_string(),
-                true,
-                90,
-            ) {
-                Ok(_) => println!("  Added: fedora-43 (Fedora 43)"),
-                Err(e) => eprintln!("  Warning: Could not add fedora-43: {}", e),
+            if sandbox_scripts {
+                conary::db::models::Setting::set(
+                    &conn,
+                    conary::scriptlet::SANDBOX_SETTING,
+                    "true",
+                )?;
+                println!("Package scriptlets will run in a bubblewrap sandbox");
             }
 
-            // Arch Linux multilib repository (priority 85)
-            match conary::repository::add_repository(
-                &conn,
-                "arch-multilib".to_string(),
-                "https://geo.mirror.pkgbuild.com/multilib/os/x86_64".to_string(),
-                true,
-                85,
-            ) {
-                Ok(_) => println!("  Added: arch-multilib (Arch Linux)"),
-                Err(e) => eprintln!("  Warning: Could not add arch-multilib: {}", e),
-            }
+            let Some(defaults) = defaults else {
+                println!(
+                    "No repositories added. Use 'conary repo-add' or 'conary init --with-defaults' to add some."
+                );
+                return Ok(());
+            };
 
-            // Ubuntu 24.04 LTS main repository (priority 80)
-            match conary::repository::add_repository(
-                &conn,
-                "ubuntu-noble".to_string(),
-                "http://archive.ubuntu.com/ubuntu".to_string(),
-                true,
-                80,
-            ) {
-                Ok(_) => println!("  Added: ubuntu-noble (Ubuntu 24.04 LTS)"),
-                Err(e) => eprintln!("  Warning: Could not add ubuntu-noble: {}", e),
+            info!("Adding default repositories...");
+            for default in defaults.repositories() {
+                match conary::repository::add_repository(
+                    &conn,
+                    default.name.to_string(),
+                    default.url.to_string(),
+                    true,
+                    default.priority,
+                ) {
+                    Ok(_) => println!("  Added: {} ({})", default.name, default.label),
+                    Err(e) => eprintln!("  Warning: Could not add {}: {}", default.name, e),
+                }
             }
 
             println!("\nDefault repositories added. Use 'conary repo-sync' to download metadata.");
             Ok(())
         }
         Some(Commands::Install {
-            package,
+            packages,
             db_path,
             root,
             version,
             repo,
+            arch,
             dry_run,
+            no_scripts,
+            sandbox_scripts,
+            allow_downgrade,
+            force_unlock,
+            allow_dir_replace,
+            idempotent,
+            verify_existing,
+            exclude,
+            with_debuginfo,
+            with_docs,
+            timings,
+            json,
         }) => {
-            info!("Installing package: {}", package);
-
-            // Detect if this is a file path or package name
-            let package_path = if Path::new(&package).exists() {
-                // It's a file path - use directly
-                info!("Installing from local file: {}", package);
-                PathBuf::from(&package)
-            } else {
-                // It's a package name - search repositories and download
-                info!("Searching repositories for package: {}", package);
+            info!("Installing packages: {}", packages.join(", "));
 
-                // Open database connection for repository search
-                let conn = conary::db::open(&db_path)?;
+            // Open database connection
+            let mut conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = if dry_run { None } else { Some(layout.lock()?) };
+            let runner = conary::scriptlet::select_runner(&conn, sandbox_scripts)?;
+            ResourceLimits::load(&conn)?.configure_thread_pool();
+            let mut installer = conary::Installer::for_layout(&mut conn, &layout)?;
+            let install_options = conary::InstallOptions {
+                scripts: (!no_scripts).then_some(runner.as_ref()),
+                allow_downgrade,
+                exclude: &exclude,
+                force_unlock,
+                allow_dir_replace,
+            };
 
-                // Build selection options
-                let options = SelectionOptions {
-                    version: version.clone(),
-                    repository: repo.clone(),
-                    architecture: None, // Use system architecture
-                };
+            // Local files are parsed right away; names are looked up in the repositories
+            let companions = [
+                (with_debuginfo, Companion::Debuginfo),
+                (with_docs, Companion::Docs),
+            ];
+            let options = PlanOptions {
+                repository: repo.clone(),
+                architecture: arch, // None selects the host architecture
+                skip_installed: idempotent,
+                companions: companions
+                    .into_iter()
+                    .filter(|(with, _)| *with)
+                    .map(|(_, companion)| companion)
+                    .collect(),
+                ..Default::default()
+            };
+            let mut planner = Planner::new(installer.conn(), options);
+            for package in &packages {
+                if Path::new(package).exists() {
+                    info!("Installing from local file: {}", package);
+                    planner.install_file(installer.prepare(Path::new(package))?);
+                } else {
+                    info!("Searching repositories for package: {}", package);
+                    planner.request(Request::Install {
+                        name: package.clone(),
+                        version: version.clone(),
+                    });
+                }
+            }
+            let mut plan = planner.plan().map_err(|e| resolution_failure(e, json))?;
+            for package in &plan.unchanged {
+                already_installed(installer.conn(), &layout, package, verify_existing)?;
+            }
+            for warning in &plan.warnings {
+                eprintln!("Warning: {}", warning);
+            }
 
-                // Find best matching package
-                let pkg_with_repo = PackageSelector::find_best_package(&conn, &package, &options)?;
+            // Everything requested is already installed (only with --idempotent)
+            if plan.operations.is_empty() {
+                return Ok(());
+            }
 
-                info!(
-                    "Found package {} {} in repository {} (priority {})",
-                    pkg_with_repo.package.name,
-                    pkg_with_repo.package.version,
-                    pkg_with_repo.repository.name,
-                    pkg_with_repo.repository.priority
-                );
+            if plan.requirements_checked > 0 {
+                println!("Checking dependencies...");
+                let dependencies: Vec<&Operation> = plan.dependencies().collect();
+                if dependencies.is_empty() {
+                    println!("All dependencies already satisfied");
+                } else {
+                    println!(
+                        "{} {} missing dependencies:",
+                        if dry_run {
+                            "Would install"
+                        } else {
+                            "Installing"
+                        },
+                        dependencies.len()
+                    );
+                    for operation in dependencies {
+                        if let Operation::Install {
+                            package,
+                            source:
+                                Source::Repository {
+                                    requested_as,
+                                    resolved_for,
+                                    ..
+                                },
+                            ..
+                        } = operation
+                        {
+                            match resolved_for {
+                                Some(arch) => println!(
+                                    "  {} ({}, for {})",
+                                    requested_as, package.version, arch
+                                ),
+                                None => println!("  {} ({})", requested_as, package.version),
+                            }
+                        }
+                    }
+                }
+            }
 
-                // Download package to temp directory
-                let temp_dir = TempDir::new()?;
-                let download_path = repository::download_package(&pkg_with_repo.package, temp_dir.path())?;
+            // The whole set is checked against what stays installed before anything is fetched
+            refuse_conflicts(&plan.conflicts, "Install")?;
 
-                info!("Downloaded package to: {}", download_path.display());
-                download_path
-            };
+            // If dry-run, show what would be installed and exit
+            if dry_run {
+                for (index, operation) in plan.operations.iter().enumerate() {
+                    let Operation::Install {
+                        package,
+                        source,
+                        dependency: false,
+                        ..
+                    } = operation
+                    else {
+                        continue;
+                    };
+                    match (source, plan.parsed(index)) {
+                        (Source::Repository { repository, .. }, _) => println!(
+                            "\nWould install package: {} version {} (from {})",
+                            package.name, package.version, repository
+                        ),
+                        (Source::File { .. }, Some(prepared)) => {
+                            let package = prepared.package();
+                            println!(
+                                "\nWould install package: {} version {}",
+                                package.name(),
+                                package.version()
+                            );
+                            println!(
+                                "  Architecture: {}",
+                                package.architecture().unwrap_or("none")
+                            );
+                            println!("  Files: {}", package.files().len());
+                            println!("  Dependencies: {}", package.dependencies().len());
+                        }
+                        (Source::File { .. }, None) => {}
+                    }
+                }
+                println!("\nDry run complete. No changes made.");
+                return Ok(());
+            }
 
-            // Auto-detect package format
-            let format = detect_package_format(package_path.to_str().unwrap())?;
-            info!("Detected package format: {:?}", format);
+            // Dependencies and requested packages are downloaded together; dependencies go first
+            if !plan.downloads.is_empty() {
+                let label = format!("Downloading {} package(s)", plan.downloads.len());
+                let progress = DownloadProgress::new(label, plan.downloads.len(), quiet);
+                let report = |index, received, total| progress.update(index, received, total);
+                let fetched = installer.download_plan(&mut plan, Some(&report));
+                progress.finish();
+                fetched.map_err(|e| anyhow::anyhow!("Failed to download packages: {}", e))?;
+            }
 
-            // Parse the package based on format
-            let rpm = match format {
-                PackageFormatType::Rpm => RpmPackage::parse(package_path.to_str().unwrap())?,
-                PackageFormatType::Deb => {
-                    return Err(anyhow::anyhow!("DEB format not yet implemented"));
+            let Some(report) = installer.execute(plan, &install_options)?.installed else {
+                return Ok(());
+            };
+            for package in &report.packages {
+                for path in &package.kept_configs {
+                    println!(
+                        "Keeping modified config file {}, new version saved as {}{}",
+                        path,
+                        path,
+                        conary::filesystem::CONFIG_NEW_SUFFIX
+                    );
                 }
-                PackageFormatType::Arch => {
-                    return Err(anyhow::anyhow!("Arch format not yet implemented"));
+                print_saved_dirs(package);
+                println!(
+                    "Installed package: {} version {}",
+                    package.name, package.version
+                );
+                println!(
+                    "  Architecture: {}",
+                    package.architecture.as_deref().unwrap_or("none")
+                );
+                println!("  Files: {}", package.files);
+                if package.excluded > 0 {
+                    println!("  Excluded: {} files", package.excluded);
                 }
-            };
+                println!("  Dependencies: {}", package.dependencies);
+            }
+            print_staged(&report);
+            print_pruned(&report);
+            if timings {
+                print_timings(installer.conn(), report.changeset_id)?;
+            }
 
-            info!(
-                "Parsed package: {} version {} ({} files, {} dependencies)",
-                rpm.name(),
-                rpm.version(),
-                rpm.files().len(),
-                rpm.dependencies().len()
+            Ok(())
+        }
+        Some(Commands::Bootstrap {
+            root,
+            distro,
+            packages,
+            repos,
+            with_scripts,
+            keep_docs,
+            exclude,
+            manifest,
+        }) => {
+            let mut options = conary::bootstrap::BootstrapOptions::new(&root, packages);
+            options.distro = distro;
+            for repo in &repos {
+                let (name, url) = repo.split_once('=').ok_or_else(|| {
+                    anyhow::anyhow!("Repository must be given as NAME=URL: {}", repo)
+                })?;
+                options = options.repository(name, url);
+            }
+            if keep_docs {
+                options.exclude.clear();
+            }
+            options.exclude.extend(exclude);
+            if with_scripts {
+                options = options.scripts(&conary::scriptlet::DirectRunner);
+            }
+
+            let result = conary::bootstrap::bootstrap(&options)?;
+            for repository in &result.manifest.repositories {
+                println!(
+                    "Synced repository: {} ({})",
+                    repository.name, repository.url
+                );
+            }
+            let excluded: usize = result
+                .report
+                .packages
+                .iter()
+                .map(|package| package.excluded)
+                .sum();
+            println!(
+                "Installed {} package(s) into {}: {} files, {}",
+                result.report.packages.len(),
+                root,
+                result.report.files_deployed,
+                format_size(Some(result.report.bytes_written as i64), false)
             );
+            if excluded > 0 {
+                println!("  Excluded: {} files", excluded);
+            }
+            if let Some(path) = manifest {
+                std::fs::write(
+                    &path,
+                    serde_json::to_string_pretty(&result.manifest)? + "\n",
+                )?;
+                println!("Manifest written to {}", path);
+            }
+            Ok(())
+        }
+        Some(Commands::Remove {
+            package_name,
+            version,
+            db_path,
+            root,
+            no_scripts,
+            sandbox_scripts,
+        }) => {
+            info!("Removing package: {}", package_name);
 
             // Open database connection
             let mut conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = layout.lock()?;
+            let runner = conary::scriptlet::select_runner(&conn, sandbox_scripts)?;
+
+            let mut installer = conary::Installer::for_layout(&mut conn, &layout)?;
+            let report = installer.remove(
+                &package_name,
+                version.as_deref(),
+                &conary::RemoveOptions {
+                    scripts: (!no_scripts).then_some(runner.as_ref()),
+                },
+            )?;
 
-            // Auto-resolve and install dependencies
-            let dep_names: Vec<String> = rpm.dependencies()
-                .iter()
-                .map(|d| d.name.clone())
-                .collect();
+            for removed in &report.removed {
+                println!(
+                    "Removed package: {} version {}",
+                    removed.name, removed.version
+                );
+                println!(
+                    "  Architecture: {}",
+                    removed.architecture.as_deref().unwrap_or("none")
+                );
+                println!("  Files removed: {}", removed.files);
+            }
 
-            if !dep_names.is_empty() {
-                info!("Resolving {} dependencies transitively...", dep_names.len());
-                println!("Checking dependencies for {}...", rpm.name());
-
-                // Use transitive resolver with max depth of 10
-                match repository::resolve_dependencies_transitive(&conn, &dep_names, 10) {
-                    Ok(to_download) => {
-                        if !to_download.is_empty() {
-                            if dry_run {
-                                println!("Would install {} missing dependencies:", to_download.len());
-                                for (dep_name, pkg) in &to_download {
-                                    println!("  {} ({})", dep_name, pkg.package.version);
-                                }
-                            } else {
-                                println!("Installing {} missing dependencies:", to_download.len());
-                                for (dep_name, pkg) in &to_download {
-                                    println!("  {} ({})", dep_name, pkg.package.version);
-                                }
-                            }
+            Ok(())
+        }
+        Some(Commands::Autoremove {
+            db_path,
+            root,
+            dry_run,
+            no_scripts,
+            sandbox_scripts,
+        }) => {
+            info!("Looking for dependencies that are no longer needed");
 
-                            // Skip download/install if dry-run
-                            if !dry_run {
-                                // Download all dependencies
-                                let temp_dir = TempDir::new()?;
-                                match repository::download_dependencies(&to_download, temp_dir.path()) {
-                                    Ok(downloaded) => {
-                                        // Install each dependency in order
-                                        for (dep_name, dep_path) in downloaded {
-                                            info!("Installing dependency: {}", dep_name);
-                                            println!("Installing dependency: {}", dep_name);
-
-                                            if let Err(e) = install_package_from_file(
-                                                &dep_path,
-                                                &mut conn,
-                                                &root,
-                                                None, // No upgrade for dependencies
-                                            ) {
-                                                return Err(anyhow::anyhow!(
-                                                    "Failed to install dependency {}: {}",
-                                                    dep_name,
-                                                    e
-                                                ));
-                                            }
-                                            println!("  ✓ Installed {}", dep_name);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        return Err(anyhow::anyhow!(
-                                            "Failed to download dependencies: {}",
-                                            e
-                                        ));
-                                    }
-                                }
-                            }
-                        } else {
-                            println!("All dependencies already satisfied");
-                        }
-                    }
-                    Err(e) => {
-                        return Err(anyhow::anyhow!("Dependency resolution failed: {}", e));
-                    }
-                }
+            let mut conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = if dry_run { None } else { Some(layout.lock()?) };
+
+            let unneeded = conary::resolver::find_unneeded_dependencies(&conn)?;
+            if unneeded.is_empty() {
+                println!("No unneeded packages to remove");
+                return Ok(());
             }
 
-            // If dry-run, show what would be installed and exit
+            println!(
+                "{} {} package(s) no longer needed:",
+                if dry_run { "Would remove" } else { "Removing" },
+                unneeded.len()
+            );
+            for trove in &unneeded {
+                println!("  {} {}", trove.name, trove.version);
+            }
             if dry_run {
-                println!("\nWould install package: {} version {}", rpm.name(), rpm.version());
-                println!("  Architecture: {}", rpm.architecture().unwrap_or("none"));
-                println!("  Files: {}", rpm.files().len());
-                println!("  Dependencies: {}", rpm.dependencies().len());
                 println!("\nDry run complete. No changes made.");
                 return Ok(());
             }
 
-            // Pre-transaction validation and upgrade detection
-            let existing = conary::db::models::Trove::find_by_name(&conn, rpm.name())?;
-            let mut old_trove_to_upgrade: Option<conary::db::models::Trove> = None;
+            let runner = conary::scriptlet::select_runner(&conn, sandbox_scripts)?;
+            let mut installer = conary::Installer::for_layout(&mut conn, &layout)?;
+            let report = installer.autoremove(&conary::RemoveOptions {
+                scripts: (!no_scripts).then_some(runner.as_ref()),
+            })?;
 
-            for trove in &existing {
-                // Only compare packages with same architecture
-                if trove.architecture == rpm.architecture().map(|s| s.to_string()) {
-                    if trove.version == rpm.version() {
-                        // Same version already installed
-                        return Err(anyhow::anyhow!(
-                            "Package {} version {} ({}) is already installed",
-                            rpm.name(),
-                            rpm.version(),
-                            rpm.architecture().unwrap_or("no-arch")
-                        ));
-                    }
+            println!(
+                "Removed {} package(s)",
+                report.map_or(0, |report| report.removed.len())
+            );
+            Ok(())
+        }
+        Some(Commands::MarkExplicit {
+            package_name,
+            db_path,
+        }) => {
+            let conn = conary::db::open(&db_path)?;
 
-                    // Compare versions
-                    match (RpmVersion::parse(&trove.version), RpmVersion::parse(rpm.version())) {
-                        (Ok(existing_ver), Ok(new_ver)) => {
-                            if new_ver > existing_ver {
-                                // This is an upgrade
-                                info!(
-                                    "Upgrading {} from version {} to {}",
-                                    rpm.name(),
-                                    trove.version,
-                                    rpm.version()
-                                );
-                                old_trove_to_upgrade = Some(trove.clone());
-                            } else {
-                                // Trying to install older version
-                                return Err(anyhow::anyhow!(
-                                    "Cannot downgrade package {} from version {} to {}",
-                                    rpm.name(),
-                                    trove.version,
-                                    rpm.version()
-                                ));
-                            }
-                        }
-                        _ => {
-                            // Version parsing failed - allow installation but warn
-                            warn!(
-                                "Could not compare versions {} and {}",
-                                trove.version,
-                                rpm.version()
-                            );
-                        }
-                    }
-                }
+            let troves = conary::db::models::Trove::find_by_name(&conn, &package_name)?;
+            if troves.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Package '{}' is not installed",
+                    package_name
+                ));
             }
 
-            // Extract file contents from RPM
-            info!("Extracting file contents from package...");
-            let extracted_files = rpm.extract_file_contents()?;
-            info!("Extracted {} files", extracted_files.len());
-
-            // Initialize CAS and file deployer
-            let objects_dir = std::path::Path::new(&db_path)
-                .parent()
-                .unwrap_or_else(|| std::path::Path::new("."))
-                .join("objects");
-            let install_root = std::path::PathBuf::from(&root);
-            let deployer = conary::filesystem::FileDeployer::new(&objects_dir, &install_root)?;
+            for trove in &troves {
+                conary::db::models::Trove::set_install_reason(
+                    &conn,
+                    trove.id.unwrap(),
+                    InstallReason::Explicit,
+                )?;
+            }
 
-            // Perform installation within a changeset transaction
-            let _changeset_id = conary::db::transaction(&mut conn, |tx| {
-                // Create changeset for this installation
-                let changeset_desc = if let Some(ref old_trove) = old_trove_to_upgrade {
-                    format!(
-                        "Upgrade {} from {} to {}",
-                        rpm.name(),
-                        old_trove.version,
-                        rpm.version()
-                    )
-                } else {
-                    format!("Install {}-{}", rpm.name(), rpm.version())
-                };
-                let mut changeset = conary::db::models::Changeset::new(changeset_desc);
-                let changeset_id = changeset.insert(tx)?;
-
-                // If upgrading, remove the old trove first
-                if let Some(old_trove) = old_trove_to_upgrade {
-                    if let Some(old_id) = old_trove.id {
-                        info!("Removing old version {} before upgrade", old_trove.version);
-                        // Delete old trove (CASCADE will handle files and dependencies)
-                        conary::db::models::Trove::delete(tx, old_id)?;
-                    }
-                }
+            println!("Marked {} as explicitly installed", package_name);
+            Ok(())
+        }
+        Some(Commands::Lock {
+            package_name,
+            db_path,
+        }) => {
+            let conn = conary::db::open(&db_path)?;
 
-                // Convert to Trove and associate with changeset
-                let mut trove = rpm.to_trove();
-                trove.installed_by_changeset_id = Some(changeset_id);
-                let trove_id = trove.insert(tx)?;
-
-                // Process each file: conflict check, store in CAS, deploy, track in DB
-                for file in &extracted_files {
-                    // Conflict detection
-                    if deployer.file_exists(&file.path) {
-                        // Check if file is tracked in database
-                        if let Some(existing) = conary::db::models::FileEntry::find_by_path(tx, &file.path)? {
-                            // File exists and is tracked - check ownership
-                            let owner_trove = conary::db::models::Trove::find_by_id(tx, existing.trove_id)?;
-                            if let Some(owner) = owner_trove
-                                && owner.name != rpm.name() {
-                                return Err(conary::Error::InitError(format!(
-                                    "File conflict: {} is owned by package {}",
-                                    file.path, owner.name
-                                )));
-                            }
-                            // Same package owns it - this is an update, allow
-                        } else {
-                            // File exists but not tracked - orphan file
-                            return Err(conary::Error::InitError(format!(
-                                "File conflict: {} exists but is not tracked by any package",
-                                file.path
-                            )));
-                        }
-                    }
+            let troves = conary::db::models::Trove::find_by_name(&conn, &package_name)?;
+            if troves.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Package '{}' is not installed",
+                    package_name
+                ));
+            }
 
-                    // Store content in CAS
-                    let hash = deployer.cas().store(&file.content)?;
+            if conary::db::models::PackageLock::lock(&conn, &package_name)? {
+                let versions: Vec<&str> =
+                    troves.iter().map(|trove| trove.version.as_str()).collect();
+                println!("Locked {} at {}", package_name, versions.join(", "));
+            } else {
+                println!("{} is already locked", package_name);
+            }
+            Ok(())
+        }
+        Some(Commands::Unlock {
+            package_name,
+            db_path,
+        }) => {
+            let conn = conary::db::open(&db_path)?;
 
-                    // Store file content metadata in database
-                    tx.execute(
-                        "INSERT OR IGNORE INTO file_contents (sha256_hash, content_path, size) VALUES (?1, ?2, ?3)",
-                        [&hash, &format!("objects/{}/{}", &hash[0..2], &hash[2..]), &file.size.to_string()],
-                    )?;
+            if !conary::db::models::PackageLock::unlock(&conn, &package_name)? {
+                return Err(anyhow::anyhow!("Package '{}' is not locked", package_name));
+            }
+            println!("Unlocked {}", package_name);
+            Ok(())
+        }
+        Some(Commands::Locks { db_path, json }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            let locks = conary::db::models::PackageLock::list_all(&conn)?;
 
-                    // Store file metadata in database
-                    let mut file_entry = conary::db::models::FileEntry::new(
-                        file.path.clone(),
-                        hash.clone(),
-                        file.size,
-                        file.mode,
-                        trove_id,
-                    );
-                    file_entry.insert(tx)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&locks)?);
+                return Ok(());
+            }
 
-                    // Track in file history
-                    let action = if deployer.file_exists(&file.path) { "modify" } else { "add" };
-                    tx.execute(
-                        "INSERT INTO file_history (changeset_id, path, sha256_hash, action) VALUES (?1, ?2, ?3, ?4)",
-                        [&changeset_id.to_string(), &file.path, &hash, action],
-                    )?;
-                }
+            if locks.is_empty() {
+                println!("No packages are locked.");
+                return Ok(());
+            }
+            println!("Locked packages:");
+            for lock in &locks {
+                let versions: Vec<String> =
+                    conary::db::models::Trove::find_by_name(&conn, &lock.name)?
+                        .into_iter()
+                        .map(|trove| trove.version)
+                        .collect();
+                let held = if versions.is_empty() {
+                    "not installed".to_string()
+                } else {
+                    versions.join(", ")
+                };
+                println!(
+                    "  {} {} (since {})",
+                    lock.name,
+                    held,
+                    lock.locked_at.as_deref().unwrap_or("unknown")
+                );
+            }
+            Ok(())
+        }
+        Some(Commands::Pin {
+            action:
+                PinCommand::Add {
+                    pattern,
+                    repository,
+                    forbid,
+                    priority,
+                    db_path,
+                },
+        }) => {
+            use conary::db::models::{PinAction, RepositoryPin};
 
-                // Store dependencies in database
-                for dep in rpm.dependencies() {
-                    let dep_type_str = match dep.dep_type {
-                        DependencyType::Runtime => "runtime",
-                        DependencyType::Build => "build",
-                        DependencyType::Optional => "optional",
-                    };
+            let conn = conary::db::open(&db_path)?;
+            if conary::db::models::Repository::find_by_name(&conn, &repository)?.is_none() {
+                return Err(anyhow::anyhow!("Repository '{}' not found", repository));
+            }
 
-                    let mut dep_entry = conary::db::models::DependencyEntry::new(
-                        trove_id,
-                        dep.name.clone(),
-                        dep.version.clone(),
-                        dep_type_str.to_string(),
-                        None, // version_constraint parsing comes later
-                    );
-                    dep_entry.insert(tx)?;
-                }
+            let action = if forbid {
+                PinAction::Forbid
+            } else {
+                PinAction::Prefer
+            };
+            let mut pin = RepositoryPin::new(pattern, repository, action, priority);
+            let added = pin.save(&conn)?;
+            println!(
+                "{} pin: {} {} from {} (priority {})",
+                if added { "Added" } else { "Updated" },
+                pin.action.as_str(),
+                pin.pattern,
+                pin.repository,
+                pin.priority
+            );
+            Ok(())
+        }
+        Some(Commands::Pin {
+            action: PinCommand::List { db_path, json },
+        }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            let pins = conary::db::models::RepositoryPin::list_all(&conn)?;
 
-                // Mark changeset as applied
-                changeset.update_status(tx, conary::db::models::ChangesetStatus::Applied)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&pins)?);
+                return Ok(());
+            }
 
-                Ok(changeset_id)
-            })?;
+            if pins.is_empty() {
+                println!("No repository pins.");
+                return Ok(());
+            }
+            println!("Repository pins, consulted in this order:");
+            for pin in &pins {
+                let known =
+                    conary::db::models::Repository::find_by_name(&conn, &pin.repository)?.is_some();
+                println!(
+                    "  {:<7} {} from {} (priority {}){}",
+                    pin.action.as_str(),
+                    pin.pattern,
+                    pin.repository,
+                    pin.priority,
+                    if known {
+                        ""
+                    } else {
+                        " [repository not configured]"
+                    }
+                );
+            }
+            Ok(())
+        }
+        Some(Commands::Pin {
+            action:
+                PinCommand::Remove {
+                    pattern,
+                    repository,
+                    db_path,
+                },
+        }) => {
+            let conn = conary::db::open(&db_path)?;
 
-            // Deploy files to filesystem (outside transaction for safety)
-            info!("Deploying files to filesystem...");
-            for file in &extracted_files {
-                let hash = conary::filesystem::CasStore::compute_hash(&file.content);
-                deployer.deploy_file(&file.path, &hash, file.mode as u32)?;
+            if !conary::db::models::RepositoryPin::remove(&conn, &pattern, &repository)? {
+                return Err(anyhow::anyhow!(
+                    "No pin of '{}' on repository '{}'",
+                    pattern,
+                    repository
+                ));
             }
-            info!("Successfully deployed {} files", extracted_files.len());
+            println!("Removed pin of {} on {}", pattern, repository);
+            Ok(())
+        }
+        Some(Commands::Du {
+            limit,
+            db_path,
+            json,
+        }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            let mut report = conary::query::disk_usage(&conn)?;
+            let hidden = limit.map_or(0, |limit| report.packages.len().saturating_sub(limit));
+            report.packages.truncate(report.packages.len() - hidden);
 
-            println!("Installed package: {} version {}", rpm.name(), rpm.version());
-            println!("  Architecture: {}", rpm.architecture().unwrap_or("none"));
-            println!("  Files: {}", rpm.files().len());
-            println!("  Dependencies: {}", rpm.dependencies().len());
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
 
-            // Show provenance info if available
-            if let Some(source_rpm) = rpm.source_rpm() {
-                println!("  Source RPM: {}", source_rpm);
+            if report.packages.is_empty() {
+                println!("No packages installed");
+                return Ok(());
+            }
+            println!("{:>10}  PACKAGE", "SIZE");
+            for package in &report.packages {
+                println!(
+                    "{:>10}  {} {}",
+                    format_size(Some(package.installed_size), false),
+                    package.name,
+                    package.version
+                );
             }
-            if let Some(vendor) = rpm.vendor() {
-                println!("  Vendor: {}", vendor);
+            if hidden > 0 {
+                println!("{:>10}  ({} smaller package(s) not shown)", "", hidden);
             }
+            println!(
+                "\nTotal: {} in {} package(s)",
+                format_size(Some(report.total_size), false),
+                report.packages.len() + hidden
+            );
+            println!(
+                "Unique CAS content: {} (files with the same content are stored once)",
+                format_size(Some(report.unique_cas_bytes), false)
+            );
+            Ok(())
+        }
+        Some(Commands::Extract {
+            package,
+            dir,
+            list: _,
+        }) => {
+            let package = conary::packages::open_package(Path::new(&package))?;
+            let Some(dir) = dir else {
+                println!("{:>6}  {:>10}  PATH", "MODE", "SIZE");
+                for file in package.files() {
+                    println!(
+                        "{:06o}  {:>10}  {}",
+                        file.mode & 0o177777,
+                        file.size,
+                        file.path
+                    );
+                }
+                return Ok(());
+            };
 
+            let summary = conary::packages::extract::extract_to(package.as_ref(), Path::new(&dir))?;
+            println!(
+                "Extracted {} {} into {}: {} file(s), {} symlink(s), {} hardlink(s), {} directories, {}",
+                package.name(),
+                package.version(),
+                dir,
+                summary.files,
+                summary.symlinks,
+                summary.hardlinks,
+                summary.directories,
+                format_size(Some(summary.bytes as i64), false)
+            );
             Ok(())
         }
-        Some(Commands::Remove {
-            package_name,
+        Some(Commands::Query {
+            pattern,
+            installed_by,
+            arch,
+            changeset,
+            show_changesets,
             db_path,
+            requires_file,
+            scan_shebangs,
+            root,
+            json,
         }) => {
-            info!("Removing package: {}", package_name);
+            let conn = conary::db::open_read_only(&db_path)?;
 
-            // Open database connection
-            let mut conn = conary::db::open(&db_path)?;
+            if let Some(path) = requires_file {
+                let requirements = conary::query::packages_requiring_file(
+                    &conn,
+                    Path::new(&root),
+                    &path,
+                    scan_shebangs,
+                )?;
 
-            // Find the package to remove
-            let troves = conary::db::models::Trove::find_by_name(&conn, &package_name)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&requirements)?);
+                } else if requirements.is_empty() {
+                    println!("No installed packages require {}", path);
+                } else {
+                    println!("Packages requiring {}:", path);
+                    for req in &requirements {
+                        match &req.source {
+                            conary::query::RequirementSource::Declared { dependency } => {
+                                println!(
+                                    "  {} {} (requires {})",
+                                    req.package, req.version, dependency
+                                );
+                            }
+                            conary::query::RequirementSource::Shebang { file, interpreter } => {
+                                println!(
+                                    "  {} {} ({} runs {})",
+                                    req.package, req.version, file, interpreter
+                                );
+                            }
+                        }
+                    }
+                    println!("\nTotal: {} requirement(s)", requirements.len());
+                }
 
-            if troves.is_empty() {
-                return Err(anyhow::anyhow!("Package '{}' is not installed", package_name));
+                return Ok(());
             }
 
-            if troves.len() > 1 {
-                println!("Multiple versions of '{}' found:", package_name);
-                for trove in &troves {
-                    println!("  - version {}", trove.version);
+            if let Some(changeset_id) = changeset {
+                let report = conary::query::changeset_report(&conn, changeset_id)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print_changeset_report(&report);
                 }
-                return Err(anyhow::anyhow!(
-                    "Please specify version (future enhancement)"
-                ));
+                return Ok(());
             }
 
-            let trove = &troves[0];
-            let trove_id = trove.id.unwrap();
-
-            // Check for reverse dependencies
-            let resolver = conary::resolver::Resolver::new(&conn)?;
-            let breaking = resolver.check_removal(&package_name)?;
-
-            if !breaking.is_empty() {
-                println!(
-                    "WARNING: Removing '{}' would break the following packages:",
-                    package_name
-                );
-                for pkg in &breaking {
-                    println!("  {}", pkg);
+            if show_changesets {
+                let name = pattern.unwrap_or_default();
+                let events = conary::query::package_changesets(&conn, &name)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&events)?);
+                } else if events.is_empty() {
+                    println!("No changesets recorded for {}", name);
+                } else {
+                    println!("Changesets affecting {}:", name);
+                    for event in &events {
+                        println!(
+                            "  [{}] {} - {}: {} {}",
+                            event.changeset.id.unwrap(),
+                            changeset_timestamp(&event.changeset),
+                            event.changeset.description,
+                            package_action_label(event.change.action),
+                            trove_label(&event.change)
+                        );
+                    }
+                    println!("\nTotal: {} event(s)", events.len());
                 }
-                println!("\nRefusing to remove package with dependencies.");
-                println!("Use 'conary whatbreaks {}' for more information.", package_name);
-                return Err(anyhow::anyhow!(
-                    "Cannot remove '{}': {} packages depend on it",
-                    package_name,
-                    breaking.len()
-                ));
+                return Ok(());
             }
 
-            // Count files before removal for reporting
-            let file_count = conary::db::models::FileEntry::find_by_trove(&conn, trove_id)?.len();
-
-            // Perform removal within a changeset transaction
-            conary::db::transaction(&mut conn, |tx| {
-                // Create changeset for this removal
-                let mut changeset = conary::db::models::Changeset::new(format!(
-                    "Remove {}-{}",
-                    trove.name, trove.version
-                ));
-                changeset.insert(tx)?;
-
-                // Delete the trove (files will be cascade-deleted due to foreign key)
-                conary::db::models::Trove::delete(tx, trove_id)?;
-
-                // Mark changeset as applied
-                changeset.update_status(tx, conary::db::models::ChangesetStatus::Applied)?;
-
-                Ok(())
-            })?;
-
-            // TODO: Actually delete files from filesystem (Phase 6)
-
-            println!("Removed package: {} version {}", trove.name, trove.version);
-            println!("  Architecture: {}", trove.architecture.as_deref().unwrap_or("none"));
-            println!("  Files removed: {}", file_count);
-
-            Ok(())
-        }
-        Some(Commands::Query { pattern, db_path }) => {
-            let conn = conary::db::open(&db_path)?;
-
             // Get all troves or filter by pattern
-            let troves = if let Some(pattern) = pattern {
-                conary::db::models::Trove::find_by_name(&conn, &pattern)?
-            } else {
-                // Get all troves
-                let mut stmt = conn.prepare("SELECT id, name, version, type, architecture, description, installed_at, installed_by_changeset_id FROM troves ORDER BY name, version")?;
-                let rows = stmt.query_map([], |row| {
-                    Ok(conary::db::models::Trove {
-                        id: Some(row.get(0)?),
-                        name: row.get(1)?,
-                        version: row.get(2)?,
-                        trove_type: row.get::<_, String>(3)?.parse().unwrap(),
-                        architecture: row.get(4)?,
-                        description: row.get(5)?,
-                        installed_at: row.get(6)?,
-                        installed_by_changeset_id: row.get(7)?,
-                    })
-                })?;
-                rows.collect::<rusqlite::Result<Vec<_>>>()?
+            let mut troves = match pattern {
+                Some(pattern) => conary::db::models::Trove::search(&conn, &pattern)?,
+                None => conary::db::models::Trove::list_all(&conn)?,
             };
+            troves.retain(|trove| {
+                installed_by.is_none_or(|id| trove.installed_by_changeset_id == Some(id))
+                    && arch
+                        .as_ref()
+                        .is_none_or(|arch| trove.architecture.as_ref() == Some(arch))
+            });
+            troves.sort_by(|a, b| {
+                (&a.name, &a.version, &a.architecture).cmp(&(&b.name, &b.version, &b.architecture))
+            });
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&troves)?);
+                return Ok(());
+            }
 
             if troves.is_empty() {
                 println!("No packages found.");
             } else {
+                let repo_names: HashMap<i64, String> =
+                    conary::db::models::Repository::list_all(&conn)?
+                        .into_iter()
+                        .filter_map(|repo| Some((repo.id?, repo.name)))
+                        .collect();
                 println!("Installed packages:");
                 for trove in &troves {
-                    print!("  {} {} ({:?})", trove.name, trove.version, trove.trove_type);
+                    print!(
+                        "  {} {} ({:?})",
+                        trove.name, trove.version, trove.trove_type
+                    );
                     if let Some(arch) = &trove.architecture {
                         print!(" [{}]", arch);
                     }
+                    if let Some(repo) = trove
+                        .origin_repository_id
+                        .and_then(|id| repo_names.get(&id))
+                    {
+                        print!(" from {}", repo);
+                    }
                     println!();
                 }
                 println!("\nTotal: {} package(s)", troves.len());
@@ -977,31 +3091,99 @@ fn main() -> Result<()> {
 
             Ok(())
         }
-        Some(Commands::History { db_path }) => {
-            let conn = conary::db::open(&db_path)?;
+        Some(Commands::History {
+            action,
+            db_path,
+            json,
+            limit,
+            since,
+            status,
+            show,
+        }) => {
+            let conn = if action.is_some() {
+                conary::db::open(&db_path)?
+            } else {
+                conary::db::open_read_only(&db_path)?
+            };
+
+            if let Some(action) = action {
+                let (changeset_id, protected) = match action {
+                    HistoryAction::Protect { changeset_id } => (changeset_id, true),
+                    HistoryAction::Unprotect { changeset_id } => (changeset_id, false),
+                };
+                conary::db::models::Changeset::set_protected(&conn, changeset_id, protected)?;
+                if protected {
+                    println!("Changeset {} is now protected", changeset_id);
+                } else {
+                    println!("Changeset {} is no longer protected", changeset_id);
+                }
+                return Ok(());
+            }
 
-            let changesets = conary::db::models::Changeset::list_all(&conn)?;
+            if let Some(changeset_id) = show {
+                let report = conary::query::changeset_report(&conn, changeset_id)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print_changeset_report(&report);
+                }
+                return Ok(());
+            }
+
+            let filter = conary::db::models::ChangesetFilter {
+                status: status
+                    .map(|status| status.parse().map_err(|e: String| anyhow::anyhow!(e)))
+                    .transpose()?,
+                since: since.as_deref().map(parse_since).transpose()?,
+                limit,
+            };
+            let filtered =
+                filter.status.is_some() || filter.since.is_some() || filter.limit.is_some();
+            let changesets = conary::db::models::Changeset::list(&conn, &filter)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&changesets)?);
+                return Ok(());
+            }
 
             if changesets.is_empty() {
-                println!("No changeset history.");
+                println!(
+                    "{}",
+                    if filtered {
+                        "No matching changesets."
+                    } else {
+                        "No changeset history."
+                    }
+                );
             } else {
                 println!("Changeset history:");
                 for changeset in &changesets {
-                    let timestamp = changeset
-                        .applied_at
-                        .as_ref()
-                        .or(changeset.rolled_back_at.as_ref())
-                        .or(changeset.created_at.as_ref())
-                        .map(|s| s.as_str())
-                        .unwrap_or("pending");
-
                     println!(
-                        "  [{}] {} - {} ({:?})",
+                        "  [{}] {} - {} ({:?}{}) downloaded {}, size {}",
                         changeset.id.unwrap(),
-                        timestamp,
+                        changeset_timestamp(changeset),
                         changeset.description,
-                        changeset.status
+                        changeset.status,
+                        if changeset.protected {
+                            ", protected"
+                        } else {
+                            ""
+                        },
+                        format_size(changeset.downloaded_bytes, false),
+                        format_size(changeset.installed_size_delta, true)
                     );
+                    for run in conary::db::models::ScriptletRun::find_by_changeset(
+                        &conn,
+                        changeset.id.unwrap(),
+                    )? {
+                        println!(
+                            "      {} scriptlet of {} exited with {} ({})",
+                            run.phase,
+                            run.package,
+                            run.exit_code,
+                            run.backend.as_deref().unwrap_or("unknown runner")
+                        );
+                    }
                 }
                 println!("\nTotal: {} changeset(s)", changesets.len());
             }
@@ -1012,219 +3194,617 @@ fn main() -> Result<()> {
             changeset_id,
             db_path,
             root,
+            force,
+            dry_run,
+            json,
         }) => {
             info!("Rolling back changeset: {}", changeset_id);
 
             let mut conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = layout.lock()?;
 
             // Initialize file deployer for filesystem operations
-            let objects_dir = std::path::Path::new(&db_path)
-                .parent()
-                .unwrap_or_else(|| std::path::Path::new("."))
-                .join("objects");
-            let install_root = std::path::PathBuf::from(&root);
+            let objects_dir = layout.objects_dir().to_path_buf();
+            let install_root = layout.install_root().to_path_buf();
             let deployer = conary::filesystem::FileDeployer::new(&objects_dir, &install_root)?;
 
-            // Find the changeset to rollback
-            let changeset = conary::db::models::Changeset::find_by_id(&conn, changeset_id)?
-                .ok_or_else(|| anyhow::anyhow!("Changeset {} not found", changeset_id))?;
+            let plan =
+                conary::rollback::RollbackPlan::build(&conn, &deployer, changeset_id, force)?;
+            if dry_run {
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&plan)?);
+                } else {
+                    print_rollback_plan(&plan);
+                }
+                return Ok(());
+            }
 
-            // Check if already rolled back
-            if changeset.status == conary::db::models::ChangesetStatus::RolledBack {
-                return Err(anyhow::anyhow!(
-                    "Changeset {} is already rolled back",
-                    changeset_id
-                ));
+            plan.execute(&mut conn, &deployer)?;
+            if plan.staged {
+                conary::staged::discard(&layout, changeset_id)?;
+            }
+            for trove in &plan.troves {
+                println!("Removed {} version {}", trove.name, trove.version);
+            }
+            println!(
+                "Rollback complete. Changeset {} has been reversed.",
+                changeset_id
+            );
+            if plan.staged {
+                println!("  Discarded its pending files; nothing was deployed yet");
+            } else {
+                println!("  Removed {} files from filesystem", plan.files.len());
             }
 
-            // Check if not yet applied
-            if changeset.status == conary::db::models::ChangesetStatus::Pending {
-                return Err(anyhow::anyhow!(
-                    "Cannot rollback pending changeset {}",
-                    changeset_id
-                ));
+            Ok(())
+        }
+        Some(Commands::Verify {
+            package,
+            db_path,
+            root,
+            attrs,
+            strict_config,
+            json,
+            package_file,
+            repair,
+            include_excluded,
+            changed_only,
+            porcelain,
+            orphans,
+            exclude,
+        }) => {
+            info!("Verifying installed files...");
+            // Listings meant for a program rather than a terminal
+            let machine = json || porcelain;
+
+            let mut conn = conary::db::open(&db_path)?;
+
+            // Initialize file deployer for verification
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            if let Some(prefix) = orphans {
+                return verify_orphans(
+                    &conn,
+                    layout.install_root(),
+                    &prefix,
+                    &exclude,
+                    json,
+                    porcelain,
+                );
             }
+            let deployer =
+                conary::filesystem::FileDeployer::new(layout.objects_dir(), layout.install_root())?;
+
+            if let Some(package_file) = package_file {
+                let package = conary::packages::open_package(Path::new(&package_file))?;
+                let trove = conary::db::models::Trove::find_by_name(&conn, package.name())?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Package '{}' is not installed", package.name())
+                    })?;
+                if trove.version != package.version() && !machine {
+                    println!(
+                        "Note: installed {} is version {}, package file is {}",
+                        trove.name,
+                        trove.version,
+                        package.version()
+                    );
+                }
 
-            // Query file history for this changeset before the transaction
-            let files_to_rollback: Vec<(String, String)> = {
-                let mut stmt = conn.prepare(
-                    "SELECT path, action FROM file_history WHERE changeset_id = ?1"
-                )?;
-                let rows = stmt.query_map([changeset_id], |row| {
-                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-                })?;
-                rows.collect::<rusqlite::Result<Vec<_>>>()?
+                let results = verify_against_package(&conn, &deployer, &trove, package.as_ref())?;
+                let count =
+                    |status: VerifyStatus| results.iter().filter(|r| r.status == status).count();
+                let tracking = count(VerifyStatus::Untracked);
+                let metadata = count(VerifyStatus::NotInPackage) + count(VerifyStatus::DbMismatch);
+                let content = count(VerifyStatus::Modified)
+                    + count(VerifyStatus::Missing)
+                    + count(VerifyStatus::Attrs);
+
+                if porcelain {
+                    for line in results.iter().flat_map(porcelain_lines) {
+                        println!("{}", line);
+                    }
+                } else if json {
+                    let listed: Vec<&VerifyResult> = results
+                        .iter()
+                        .filter(|r| !changed_only || r.status != VerifyStatus::Ok)
+                        .collect();
+                    let report = serde_json::json!({
+                        "package": trove.name,
+                        "installed_version": trove.version,
+                        "package_version": package.version(),
+                        "files": listed,
+                        "summary": {
+                            "ok": count(VerifyStatus::Ok),
+                            "untracked": count(VerifyStatus::Untracked),
+                            "not_in_package": count(VerifyStatus::NotInPackage),
+                            "db_mismatch": count(VerifyStatus::DbMismatch),
+                            "modified": count(VerifyStatus::Modified),
+                            "missing": count(VerifyStatus::Missing),
+                            "attribute_drift": count(VerifyStatus::Attrs),
+                            "total": results.len(),
+                        },
+                    });
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    for r in results.iter().filter(|r| r.status != VerifyStatus::Ok) {
+                        let label = match r.status {
+                            VerifyStatus::Untracked => "UNTRACKED",
+                            VerifyStatus::NotInPackage => "NOT IN PACKAGE",
+                            VerifyStatus::DbMismatch => "DB MISMATCH",
+                            VerifyStatus::Modified => "MODIFIED",
+                            VerifyStatus::Missing => "MISSING",
+                            _ => "ATTRS",
+                        };
+                        if r.details.is_empty() {
+                            println!("{}: {}", label, r.path);
+                        } else {
+                            println!("{}: {}: {}", label, r.path, r.details.join(", "));
+                        }
+                    }
+
+                    println!("\nVerification of {} against {}:", trove.name, package_file);
+                    println!("  OK: {} files", count(VerifyStatus::Ok));
+                    println!("  Tracking drift: {} files", tracking);
+                    println!("  Metadata drift: {} files", metadata);
+                    println!("  Content mismatch: {} files", content);
+                }
+
+                if tracking + metadata + content > 0 {
+                    return Err(VerifyFindings.into());
+                }
+                return Ok(());
+            }
+
+            // Get troves whose files to verify
+            let troves = if let Some(pkg_name) = package {
+                // Verify specific package
+                let troves = conary::db::models::Trove::find_by_name(&conn, &pkg_name)?;
+                if troves.is_empty() {
+                    return Err(anyhow::anyhow!("Package '{}' is not installed", pkg_name));
+                }
+                troves
+            } else {
+                // Verify all installed files
+                conary::db::models::Trove::list_all(&conn)?
             };
 
-            // Perform rollback in a transaction
+            let mut files = Vec::new();
+            let mut excluded = Vec::new();
+            // Files of staged changesets are checked in their pending tree, by index into pending_trees
+            let mut pending_trees = Vec::new();
+            let mut pending = Vec::new();
+            for trove in &troves {
+                let tree = match conary::staged::pending_tree_of(&conn, &layout, trove)? {
+                    Some(tree) => {
+                        pending_trees.push(conary::filesystem::FileDeployer::new(
+                            layout.objects_dir(),
+                            &tree,
+                        )?);
+                        Some(pending_trees.len() - 1)
+                    }
+                    None => None,
+                };
+                let mut trove_files =
+                    conary::db::models::FileEntry::find_by_trove(&conn, trove.id.unwrap())?;
+                trove_files.sort_by(|a, b| a.path.cmp(&b.path));
+                for mut file in trove_files {
+                    // A shared directory is expected to have the strictest mode of its owners
+                    if file.file_type == conary::db::models::FileType::Directory
+                        && let Some(mode) =
+                            conary::db::models::FileEntry::reconciled_dir_mode(&conn, &file.path)?
+                    {
+                        file.permissions = mode;
+                    }
+                    excluded
+                        .push(!include_excluded && trove.install_modifiers.excludes(&file.path));
+                    pending.push(tree);
+                    files.push((file, trove.name.clone()));
+                }
+            }
+
+            if files.is_empty() && !machine {
+                println!("No files to verify");
+                return Ok(());
+            }
+
+            // Verify each file
+            let mut results = Vec::new();
+
+            for (((file, pkg_name), &excluded), tree) in files.iter().zip(&excluded).zip(&pending) {
+                let path = &file.path;
+                if let Some(tree) = tree.map(|index| &pending_trees[index]) {
+                    let (status, details) = match tree.verify_entry(file) {
+                        Ok(false) | Err(_) if excluded && !tree.file_exists(path) => {
+                            (VerifyStatus::Excluded, Vec::new())
+                        }
+                        Ok(false) | Err(_) if !tree.file_exists(path) => (
+                            VerifyStatus::Staged,
+                            vec!["missing from the pending tree".to_string()],
+                        ),
+                        Ok(true) if attrs => (VerifyStatus::Staged, tree.verify_attributes(file)?),
+                        Ok(true) => (VerifyStatus::Staged, Vec::new()),
+                        _ => (
+                            VerifyStatus::Staged,
+                            vec!["modified in the pending tree".to_string()],
+                        ),
+                    };
+                    if !details.is_empty() && !machine {
+                        println!(
+                            "STAGED: {} (from {}): {}",
+                            path,
+                            pkg_name,
+                            details.join(", ")
+                        );
+                    }
+                    results.push(VerifyResult {
+                        path: path.clone(),
+                        package: pkg_name.clone(),
+                        status,
+                        details,
+                    });
+                    continue;
+                }
+                let (status, details) = match deployer.verify_entry(file) {
+                    Ok(false) | Err(_) if excluded && !deployer.file_exists(path) => {
+                        // Left out at install time, not lost
+                        info!("EXCLUDED: {} (from {})", path, pkg_name);
+                        (VerifyStatus::Excluded, Vec::new())
+                    }
+                    Ok(true) => {
+                        let drift = if attrs {
+                            deployer.verify_attributes(file)?
+                        } else {
+                            Vec::new()
+                        };
+                        if drift.is_empty() {
+                            info!("OK: {} (from {})", path, pkg_name);
+                            (VerifyStatus::Ok, drift)
+                        } else {
+                            if !machine {
+                                println!(
+                                    "ATTRS: {} (from {}): {}",
+                                    path,
+                                    pkg_name,
+                                    drift.join(", ")
+                                );
+                            }
+                            (VerifyStatus::Attrs, drift)
+                        }
+                    }
+                    Ok(false) if !deployer.file_exists(path) => {
+                        if !machine {
+                            println!("MISSING: {} (from {})", path, pkg_name);
+                        }
+                        (VerifyStatus::Missing, Vec::new())
+                    }
+                    Ok(false) if file.is_config && !strict_config => {
+                        // Config files are expected to be edited
+                        if !machine {
+                            println!("CONFIG: {} (from {}) modified locally", path, pkg_name);
+                        }
+                        (VerifyStatus::Config, Vec::new())
+                    }
+                    Ok(false) => {
+                        if !machine {
+                            println!("MODIFIED: {} (from {})", path, pkg_name);
+                        }
+                        (VerifyStatus::Modified, Vec::new())
+                    }
+                    Err(_) => {
+                        if !machine {
+                            println!("MISSING: {} (from {})", path, pkg_name);
+                        }
+                        (VerifyStatus::Missing, Vec::new())
+                    }
+                };
+                results.push(VerifyResult {
+                    path: path.clone(),
+                    package: pkg_name.clone(),
+                    status,
+                    details,
+                });
+            }
+
+            if repair {
+                let _lock = layout.lock()?;
+                repair_files(&mut conn, &deployer, &files, &mut results)?;
+                if !machine {
+                    for r in &results {
+                        match r.status {
+                            VerifyStatus::Repaired => {
+                                println!("REPAIRED: {} (from {})", r.path, r.package)
+                            }
+                            VerifyStatus::Unrepairable => {
+                                println!(
+                                    "UNREPAIRABLE: {} (from {}): {}",
+                                    r.path,
+                                    r.package,
+                                    r.details.join(", ")
+                                )
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            // Remember the findings, so update can refuse to overwrite them
             conary::db::transaction(&mut conn, |tx| {
-                // Find all troves installed by this changeset
-                let troves = {
-                    let mut stmt = tx.prepare(
-                        "SELECT id, name, version, type, architecture, description, installed_at, installed_by_changeset_id
-                         FROM troves WHERE installed_by_changeset_id = ?1",
-                    )?;
-                    let rows = stmt.query_map([changeset_id], |row| {
-                        Ok(conary::db::models::Trove {
-                            id: Some(row.get(0)?),
-                            name: row.get(1)?,
-                            version: row.get(2)?,
-                            trove_type: row.get::<_, String>(3)?.parse().unwrap(),
-                            architecture: row.get(4)?,
-                            description: row.get(5)?,
-                            installed_at: row.get(6)?,
-                            installed_by_changeset_id: row.get(7)?,
+                for trove in &troves {
+                    let findings: Vec<(String, String)> = results
+                        .iter()
+                        .filter(|r| r.package == trove.name)
+                        .filter_map(|r| {
+                            r.status
+                                .finding()
+                                .map(|status| (r.path.clone(), status.to_string()))
                         })
-                    })?;
-                    rows.collect::<rusqlite::Result<Vec<_>>>()?
-                };
+                        .collect();
+                    conary::db::models::VerifyFinding::record(tx, trove, &findings)?;
+                }
+                Ok(())
+            })?;
 
-                if troves.is_empty() {
-                    return Err(conary::Error::InitError(
-                        "No troves found for this changeset. Cannot rollback Remove operations yet.".to_string()
-                    ));
+            let count =
+                |status: VerifyStatus| results.iter().filter(|r| r.status == status).count();
+            let (ok_count, modified_count, missing_count) = (
+                count(VerifyStatus::Ok),
+                count(VerifyStatus::Modified),
+                count(VerifyStatus::Missing),
+            );
+            let (attr_count, config_count) =
+                (count(VerifyStatus::Attrs), count(VerifyStatus::Config));
+            let excluded_count = count(VerifyStatus::Excluded);
+            let (repaired_count, unrepairable_count) = (
+                count(VerifyStatus::Repaired),
+                count(VerifyStatus::Unrepairable),
+            );
+            let staged_count = count(VerifyStatus::Staged);
+            let staged_broken = results
+                .iter()
+                .filter(|r| r.status == VerifyStatus::Staged && !r.details.is_empty())
+                .count();
+            let failed = modified_count > 0
+                || missing_count > 0
+                || attr_count > 0
+                || unrepairable_count > 0
+                || staged_broken > 0;
+            if porcelain {
+                for line in results.iter().flat_map(porcelain_lines) {
+                    println!("{}", line);
+                }
+                if failed {
+                    return Err(VerifyFindings.into());
                 }
+                return Ok(());
+            }
+            if json {
+                let listed: Vec<&VerifyResult> = results
+                    .iter()
+                    .filter(|r| !changed_only || r.status != VerifyStatus::Ok)
+                    .collect();
+                let report = serde_json::json!({
+                    "files": listed,
+                    "summary": {
+                        "ok": ok_count,
+                        "modified": modified_count,
+                        "missing": missing_count,
+                        "config_modified": config_count,
+                        "excluded": excluded_count,
+                        "attribute_drift": attr_count,
+                        "repaired": repaired_count,
+                        "unrepairable": unrepairable_count,
+                        "staged": staged_count,
+                        "staged_broken": staged_broken,
+                        "total": files.len(),
+                    },
+                });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                if failed {
+                    return Err(VerifyFindings.into());
+                }
+                return Ok(());
+            }
+
+            // Print summary
+            println!("\nVerification summary:");
+            println!("  OK: {} files", ok_count);
+            println!("  Modified: {} files", modified_count);
+            println!("  Missing: {} files", missing_count);
+            if config_count > 0 {
+                println!("  Modified config: {} files", config_count);
+            }
+            if excluded_count > 0 {
+                println!("  Excluded: {} files", excluded_count);
+            }
+            if staged_count > 0 {
+                println!(
+                    "  Staged: {} files ({} broken), waiting for 'conary apply-pending'",
+                    staged_count, staged_broken
+                );
+            }
+            if attrs {
+                println!("  Attribute drift: {} files", attr_count);
+            }
+            if repair {
+                println!("  Repaired: {} files", repaired_count);
+                println!("  Unrepairable: {} files", unrepairable_count);
+            }
+            println!("  Total: {} files", files.len());
+
+            if failed {
+                return Err(VerifyFindings.into());
+            }
+
+            Ok(())
+        }
+        Some(Commands::Reconcile {
+            db_path,
+            root,
+            download_missing,
+            dry_run,
+            include_excluded,
+        }) => {
+            info!("Reconciling installed packages against {}", root);
 
-                // Create a new changeset for the rollback operation
-                let mut rollback_changeset = conary::db::models::Changeset::new(format!(
-                    "Rollback of changeset {} ({})",
-                    changeset_id, changeset.description
-                ));
-                let rollback_changeset_id = rollback_changeset.insert(tx)?;
+            let mut conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = if dry_run { None } else { Some(layout.lock()?) };
+            let objects_dir = layout.objects_dir().to_path_buf();
+
+            let options = conary::reconcile::ReconcileOptions {
+                download_missing,
+                dry_run,
+                include_excluded,
+            };
+            let report = conary::reconcile::reconcile(
+                &mut conn,
+                &objects_dir,
+                layout.install_root(),
+                &options,
+            )?;
 
-                // Delete all troves that were installed by the original changeset
-                for trove in &troves {
-                    conary::db::models::Trove::delete(tx, trove.id.unwrap())?;
-                    println!("Removed {} version {}", trove.name, trove.version);
+            for pkg in report.packages.iter().filter(|p| !p.repairs.is_empty()) {
+                println!("{} {}:", pkg.name, pkg.version);
+                for repair in &pkg.repairs {
+                    let problem = match repair.problem {
+                        conary::reconcile::FileProblem::Missing => "missing",
+                        conary::reconcile::FileProblem::Modified => "modified",
+                    };
+                    let action = match &repair.resolution {
+                        conary::reconcile::Resolution::Redeployed => {
+                            "redeployed from CAS".to_string()
+                        }
+                        conary::reconcile::Resolution::Redownloaded => {
+                            "restored from re-downloaded package".to_string()
+                        }
+                        conary::reconcile::Resolution::Irrecoverable(reason) => {
+                            format!("IRRECOVERABLE: {}", reason)
+                        }
+                    };
+                    println!("  {} ({}): {}", repair.path, problem, action);
                 }
+            }
 
-                // Mark the rollback changeset as applied
-                rollback_changeset.update_status(tx, conary::db::models::ChangesetStatus::Applied)?;
-
-                // Mark the original changeset as rolled back and link to rollback changeset
-                tx.execute(
-                    "UPDATE changesets
-                     SET status = 'rolled_back',
-                         rolled_back_at = CURRENT_TIMESTAMP,
-                         reversed_by_changeset_id = ?1
-                     WHERE id = ?2",
-                    [rollback_changeset_id, changeset_id],
-                )?;
-
-                Ok(troves.len())
-            })?;
-
-            // Remove files from filesystem (outside transaction)
-            info!("Removing files from filesystem...");
-            for (path, action) in &files_to_rollback {
-                if action == "add" || action == "modify" {
-                    deployer.remove_file(path)?;
-                    info!("Removed file: {}", path);
-                }
+            println!(
+                "\nReconcile summary{}:",
+                if dry_run { " (dry run)" } else { "" }
+            );
+            println!("  Packages checked: {}", report.packages.len());
+            println!("  Files checked: {}", report.files_checked());
+            println!("  Files repaired: {}", report.files_repaired());
+            println!("  Files irrecoverable: {}", report.files_irrecoverable());
+            if !dry_run && report.files_repaired() > 0 {
+                println!("  Batch: {}", report.batch_id);
             }
 
-            println!("Rollback complete. Changeset {} has been reversed.", changeset_id);
-            println!("  Removed {} files from filesystem", files_to_rollback.len());
+            if report.files_irrecoverable() > 0 {
+                return Err(anyhow::anyhow!(
+                    "{} files could not be restored",
+                    report.files_irrecoverable()
+                ));
+            }
 
             Ok(())
         }
-        Some(Commands::Verify {
-            package,
+        Some(Commands::Info {
+            package_name,
+            remote,
+            verify,
             db_path,
             root,
+            json,
         }) => {
-            info!("Verifying installed files...");
-
-            let conn = conary::db::open(&db_path)?;
-
-            // Initialize file deployer for verification
-            let objects_dir = std::path::Path::new(&db_path)
-                .parent()
-                .unwrap_or_else(|| std::path::Path::new("."))
-                .join("objects");
-            let install_root = std::path::PathBuf::from(&root);
-            let deployer = conary::filesystem::FileDeployer::new(&objects_dir, &install_root)?;
-
-            // Get files to verify
-            let files: Vec<(String, String, String)> = if let Some(pkg_name) = package {
-                // Verify specific package
-                let troves = conary::db::models::Trove::find_by_name(&conn, &pkg_name)?;
-                if troves.is_empty() {
-                    return Err(anyhow::anyhow!("Package '{}' is not installed", pkg_name));
+            let conn = conary::db::open_read_only(&db_path)?;
+
+            if remote {
+                let available = conary::query::available_info(&conn, &package_name)?;
+                if available.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "Package '{}' not found in any repository",
+                        package_name
+                    ));
                 }
-
-                let mut all_files = Vec::new();
-                for trove in &troves {
-                    let trove_files = conary::db::models::FileEntry::find_by_trove(&conn, trove.id.unwrap())?;
-                    for file in trove_files {
-                        all_files.push((file.path, file.sha256_hash, trove.name.clone()));
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&available)?);
+                } else {
+                    for (i, info) in available.iter().enumerate() {
+                        if i > 0 {
+                            println!();
+                        }
+                        print_available_info(info);
                     }
                 }
-                all_files
-            } else {
-                // Verify all installed files
-                let mut stmt = conn.prepare(
-                    "SELECT f.path, f.sha256_hash, t.name FROM files f
-                     JOIN troves t ON f.trove_id = t.id
-                     ORDER BY t.name, f.path"
-                )?;
-                let rows = stmt.query_map([], |row| {
-                    Ok((
-                        row.get::<_, String>(0)?,
-                        row.get::<_, String>(1)?,
-                        row.get::<_, String>(2)?,
-                    ))
-                })?;
-                rows.collect::<rusqlite::Result<Vec<_>>>()?
-            };
-
-            if files.is_empty() {
-                println!("No files to verify");
                 return Ok(());
             }
 
-            // Verify each file
-            let mut ok_count = 0;
-            let mut modified_count = 0;
-            let mut missing_count = 0;
+            let installed = conary::query::installed_info(&conn, &package_name)?;
+            if installed.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Package '{}' is not installed (use --remote for repository packages)",
+                    package_name
+                ));
+            }
 
-            for (path, expected_hash, pkg_name) in &files {
-                match deployer.verify_file(path, expected_hash) {
-                    Ok(true) => {
-                        ok_count += 1;
-                        info!("OK: {} (from {})", path, pkg_name);
-                    }
-                    Ok(false) => {
-                        modified_count += 1;
-                        println!("MODIFIED: {} (from {})", path, pkg_name);
-                    }
-                    Err(_) => {
-                        missing_count += 1;
-                        println!("MISSING: {} (from {})", path, pkg_name);
-                    }
+            // Files whose content no longer matches, per installed version
+            let mut failures = Vec::new();
+            if verify {
+                let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+                let deployer = conary::filesystem::FileDeployer::new(
+                    layout.objects_dir(),
+                    layout.install_root(),
+                )?;
+                for info in &installed {
+                    let files = conary::db::models::FileEntry::find_by_trove(
+                        &conn,
+                        info.trove.id.unwrap(),
+                    )?;
+                    let failed: Vec<String> = files
+                        .into_iter()
+                        .filter(|file| {
+                            !file.is_config && !info.trove.install_modifiers.excludes(&file.path)
+                        })
+                        .filter(|file| !deployer.verify_entry(file).unwrap_or(false))
+                        .map(|file| file.path)
+                        .collect();
+                    failures.push(failed);
                 }
             }
 
-            // Print summary
-            println!("\nVerification summary:");
-            println!("  OK: {} files", ok_count);
-            println!("  Modified: {} files", modified_count);
-            println!("  Missing: {} files", missing_count);
-            println!("  Total: {} files", files.len());
-
-            if modified_count > 0 || missing_count > 0 {
-                return Err(anyhow::anyhow!("Verification failed"));
+            if json {
+                let entries = installed
+                    .iter()
+                    .enumerate()
+                    .map(|(i, info)| {
+                        let mut entry = serde_json::to_value(info)?;
+                        if let Some(failed) = failures.get(i) {
+                            entry["verification_failures"] = serde_json::to_value(failed)?;
+                        }
+                        Ok(entry)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+                return Ok(());
             }
 
+            for (i, info) in installed.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print_installed_info(info, failures.get(i).map(Vec::as_slice));
+            }
             Ok(())
         }
         Some(Commands::Depends {
             package_name,
             db_path,
+            json,
         }) => {
             info!("Showing dependencies for package: {}", package_name);
 
-            let conn = conary::db::open(&db_path)?;
+            let conn = conary::db::open_read_only(&db_path)?;
 
             // Find the trove
             let troves = conary::db::models::Trove::find_by_name(&conn, &package_name)?;
@@ -1233,12 +3813,17 @@ fn main() -> Result<()> {
                 .ok_or_else(|| anyhow::anyhow!("Package '{}' not found", package_name))?;
 
             // Get dependencies
-            let deps = conary::db::models::DependencyEntry::find_by_trove(
-                &conn,
-                trove.id.unwrap(),
-            )?;
-
-            if deps.is_empty() {
+            let deps =
+                conary::db::models::DependencyEntry::find_by_trove(&conn, trove.id.unwrap())?;
+
+            if json {
+                let report = serde_json::json!({
+                    "package": trove.name,
+                    "version": trove.version,
+                    "dependencies": deps,
+                });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if deps.is_empty() {
                 println!("Package '{}' has no dependencies", package_name);
             } else {
                 println!("Dependencies for package '{}':", package_name);
@@ -1259,19 +3844,35 @@ fn main() -> Result<()> {
         Some(Commands::Rdepends {
             package_name,
             db_path,
+            json,
         }) => {
-            info!(
-                "Showing reverse dependencies for package: {}",
-                package_name
-            );
+            info!("Showing reverse dependencies for package: {}", package_name);
 
-            let conn = conary::db::open(&db_path)?;
+            let conn = conary::db::open_read_only(&db_path)?;
 
             // Find packages that depend on this one
             let dependents =
                 conary::db::models::DependencyEntry::find_dependents(&conn, &package_name)?;
 
-            if dependents.is_empty() {
+            if json {
+                let mut entries = Vec::new();
+                for dep in &dependents {
+                    if let Some(trove) = conary::db::models::Trove::find_by_id(&conn, dep.trove_id)?
+                    {
+                        entries.push(serde_json::json!({
+                            "package": trove.name,
+                            "version": trove.version,
+                            "dependency_type": dep.dependency_type,
+                            "version_constraint": dep.version_constraint,
+                        }));
+                    }
+                }
+                let report = serde_json::json!({
+                    "package": package_name,
+                    "dependents": entries,
+                });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if dependents.is_empty() {
                 println!(
                     "No packages depend on '{}' (or package not installed)",
                     package_name
@@ -1303,7 +3904,7 @@ fn main() -> Result<()> {
                 package_name
             );
 
-            let conn = conary::db::open(&db_path)?;
+            let conn = conary::db::open_read_only(&db_path)?;
 
             // Check if package exists
             let troves = conary::db::models::Trove::find_by_name(&conn, &package_name)?;
@@ -1347,30 +3948,119 @@ fn main() -> Result<()> {
             db_path,
             priority,
             disabled,
+            scope,
+            download_helper,
+            helper_metadata,
+            helper_fallback,
+            prefer_https,
+            no_cross_host,
+            components,
+            architectures,
+            sync_include,
+            sync_exclude,
+            no_gpg_check,
+            gpg_key,
+            metadata_expire,
         }) => {
             info!("Adding repository: {} ({})", name, url);
+            let scope: RepositoryScope = scope.parse().map_err(|e: String| anyhow::anyhow!(e))?;
 
             let conn = conary::db::open(&db_path)?;
-            let repo = conary::repository::add_repository(&conn, name.clone(), url.clone(), !disabled, priority)?;
+            let mut repo = conary::repository::add_repository(
+                &conn,
+                name.clone(),
+                url.clone(),
+                !disabled,
+                priority,
+            )?;
+            if scope != repo.scope {
+                conary::repository::set_repository_scope(&conn, &name, scope)?;
+                repo.scope = scope;
+            }
+            if let Some(command) = &download_helper {
+                repo = conary::repository::set_download_helper(
+                    &conn,
+                    &name,
+                    Some(command),
+                    helper_metadata,
+                    helper_fallback,
+                )?;
+            }
+            if prefer_https || no_cross_host {
+                repo = conary::repository::set_url_policy(
+                    &conn,
+                    &name,
+                    Some(prefer_https),
+                    Some(!no_cross_host),
+                )?;
+            }
+            if !components.is_empty() || !architectures.is_empty() {
+                repo = conary::repository::set_debian_sources(
+                    &conn,
+                    &name,
+                    components,
+                    architectures,
+                )?;
+            }
+            if !sync_include.is_empty() || !sync_exclude.is_empty() {
+                repo = conary::repository::set_sync_filter(
+                    &conn,
+                    &name,
+                    Some(sync_include),
+                    Some(sync_exclude),
+                )?;
+            }
+            if no_gpg_check || gpg_key.is_some() || metadata_expire != repo.metadata_expire {
+                repo = conary::repository::set_metadata_policy(
+                    &conn,
+                    &name,
+                    !no_gpg_check,
+                    gpg_key,
+                    metadata_expire,
+                )?;
+            }
 
             println!("Added repository: {}", repo.name);
             println!("  URL: {}", repo.url);
             println!("  Enabled: {}", repo.enabled);
             println!("  Priority: {}", repo.priority);
+            println!("  Scope: {}", repo.scope.as_str());
+            if let Some(helper) = describe_download_helper(&repo) {
+                println!("  Download helper: {}", helper);
+            }
+            if let Some(policy) = describe_url_policy(&repo) {
+                println!("  URLs: {}", policy);
+            }
+            if !repo.components.is_empty() {
+                println!("  Components: {}", repo.components.join(", "));
+            }
+            if !repo.architectures.is_empty() {
+                println!("  Architectures: {}", repo.architectures.join(", "));
+            }
+            if let Some(filter) = describe_sync_filter(&repo) {
+                println!("  Sync filter: {}", filter);
+            }
 
             Ok(())
         }
-        Some(Commands::RepoList { db_path, all }) => {
+        Some(Commands::RepoList {
+            db_path,
+            all,
+            json,
+            verbose,
+        }) => {
             info!("Listing repositories");
 
-            let conn = conary::db::open(&db_path)?;
+            let conn = conary::db::open_read_only(&db_path)?;
             let repos = if all {
                 conary::db::models::Repository::list_all(&conn)?
             } else {
                 conary::db::models::Repository::list_enabled(&conn)?
             };
 
-            if repos.is_empty() {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&repos)?);
+            } else if repos.is_empty() {
                 println!("No repositories configured");
             } else {
                 println!("Repositories:");
@@ -1380,13 +4070,46 @@ fn main() -> Result<()> {
                         Some(ts) => format!("synced {}", ts),
                         None => "never synced".to_string(),
                     };
-                    println!("  {} {} (priority: {}, {})", enabled_mark, repo.name, repo.priority, sync_status);
+                    let scope = match repo.scope {
+                        RepositoryScope::Full => String::new(),
+                        scope => format!(", {}", scope.as_str()),
+                    };
+                    println!(
+                        "  {} {} (priority: {}{}, {})",
+                        enabled_mark, repo.name, repo.priority, scope, sync_status
+                    );
                     println!("      {}", repo.url);
+                    if let Some(helper) = describe_download_helper(&repo) {
+                        println!("      download helper: {}", helper);
+                    }
+                    if let Some(policy) = describe_url_policy(&repo) {
+                        println!("      urls: {}", policy);
+                    }
+                    if let Some(filter) = describe_sync_filter(&repo) {
+                        println!("      sync filter: {}", filter);
+                    }
+                    if verbose {
+                        println!("      {}", repo.to_add_command());
+                    }
                 }
             }
 
             Ok(())
         }
+        Some(Commands::RepoExport {
+            as_commands: _,
+            db_path,
+        }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            println!("#!/bin/sh");
+            println!("# Repositories exported by conary repo-export");
+            println!("set -e");
+            for repo in conary::db::models::Repository::list_all(&conn)? {
+                println!("{}", repo.to_add_command());
+            }
+
+            Ok(())
+        }
         Some(Commands::RepoRemove { name, db_path }) => {
             info!("Removing repository: {}", name);
 
@@ -1417,6 +4140,142 @@ fn main() -> Result<()> {
 
             Ok(())
         }
+        Some(Commands::RepoModify {
+            name,
+            scope,
+            download_helper,
+            helper_metadata,
+            helper_fallback,
+            no_download_helper,
+            prefer_https,
+            allow_cross_host,
+            sync_include,
+            sync_exclude,
+            no_sync_filter,
+            db_path,
+        }) => {
+            info!("Modifying repository: {}", name);
+            let scope: Option<RepositoryScope> = scope
+                .map(|scope| scope.parse().map_err(|e: String| anyhow::anyhow!(e)))
+                .transpose()?;
+
+            let conn = conary::db::open(&db_path)?;
+            if let Some(scope) = scope {
+                conary::repository::set_repository_scope(&conn, &name, scope)?;
+                println!("Repository {} scope: {}", name, scope.as_str());
+            }
+            if download_helper.is_some() || no_download_helper {
+                let repo = conary::repository::set_download_helper(
+                    &conn,
+                    &name,
+                    download_helper.as_deref(),
+                    helper_metadata,
+                    helper_fallback,
+                )?;
+                match describe_download_helper(&repo) {
+                    Some(helper) => println!("Repository {} download helper: {}", name, helper),
+                    None => println!("Repository {} downloads with the built-in client", name),
+                }
+            }
+            if prefer_https.is_some() || allow_cross_host.is_some() {
+                let repo = conary::repository::set_url_policy(
+                    &conn,
+                    &name,
+                    prefer_https,
+                    allow_cross_host,
+                )?;
+                println!(
+                    "Repository {} URLs: {}",
+                    name,
+                    describe_url_policy(&repo).unwrap_or_else(|| "as given, any host".to_string())
+                );
+            }
+            if !sync_include.is_empty() || !sync_exclude.is_empty() || no_sync_filter {
+                let keep = |patterns: Vec<String>| {
+                    (no_sync_filter || !patterns.is_empty()).then_some(patterns)
+                };
+                let repo = conary::repository::set_sync_filter(
+                    &conn,
+                    &name,
+                    keep(sync_include),
+                    keep(sync_exclude),
+                )?;
+                println!(
+                    "Repository {} sync filter: {}",
+                    name,
+                    describe_sync_filter(&repo)
+                        .unwrap_or_else(|| "none, every package is stored".to_string())
+                );
+                println!("  Takes effect on the next 'conary repo-sync {}'", name);
+            }
+
+            Ok(())
+        }
+        Some(Commands::RepoPriority {
+            set,
+            moves,
+            db_path,
+            json,
+        }) => {
+            let mut edits = Vec::new();
+            for entry in &set {
+                let (name, priority) = entry.split_once('=').ok_or_else(|| {
+                    anyhow::anyhow!("Invalid --set '{}': expected NAME=PRIORITY", entry)
+                })?;
+                let priority = priority.trim().parse().map_err(|_| {
+                    anyhow::anyhow!("Invalid priority '{}' for repository {}", priority, name)
+                })?;
+                edits.push(conary::repository::PriorityEdit::Set {
+                    name: name.trim().to_string(),
+                    priority,
+                });
+            }
+            for args in moves.chunks(3) {
+                let before = match args[1].as_str() {
+                    "before" => true,
+                    "after" => false,
+                    other => {
+                        return Err(anyhow::anyhow!(
+                            "Invalid --move position '{}': expected before or after",
+                            other
+                        ));
+                    }
+                };
+                edits.push(conary::repository::PriorityEdit::Move {
+                    name: args[0].clone(),
+                    before,
+                    other: args[2].clone(),
+                });
+            }
+
+            let mut conn = conary::db::open(&db_path)?;
+            let repos = if edits.is_empty() {
+                conary::db::models::Repository::list_all(&conn)?
+            } else {
+                info!("Applying {} repository priority edit(s)", edits.len());
+                conary::repository::edit_priorities(&mut conn, &edits)?
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&repos)?);
+            } else if repos.is_empty() {
+                println!("No repositories configured");
+            } else {
+                println!("Repository order:");
+                for (i, repo) in repos.iter().enumerate() {
+                    let disabled = if repo.enabled { "" } else { ", disabled" };
+                    println!(
+                        "  {}. {} (priority {}{})",
+                        i + 1,
+                        repo.name,
+                        repo.priority,
+                        disabled
+                    );
+                }
+            }
+
+            Ok(())
+        }
         Some(Commands::RepoSync {
             name,
             db_path,
@@ -1424,7 +4283,7 @@ fn main() -> Result<()> {
         }) => {
             info!("Synchronizing repository metadata");
 
-            let conn = conary::db::open(&db_path)?;
+            let mut conn = conary::db::open(&db_path)?;
 
             let repos_to_sync = if let Some(repo_name) = name {
                 // Sync specific repository
@@ -1452,47 +4311,97 @@ fn main() -> Result<()> {
                 return Ok(());
             }
 
-            // Parallel sync using rayon - each thread gets own database connection
-            use rayon::prelude::*;
-            let results: Vec<(String, conary::Result<usize>)> = repos_needing_sync
-                .par_iter()
-                .map(|repo| {
-                    println!("Syncing repository: {} ...", repo.name);
-
-                    // Each thread needs its own connection for SQLite safety
-                    let sync_result = (|| -> conary::Result<usize> {
-                        let conn = conary::db::open(&db_path)?;
-                        let mut repo_mut = repo.clone();
-                        conary::repository::sync_repository(&conn, &mut repo_mut)
-                    })();
-
-                    (repo.name.clone(), sync_result)
-                })
-                .collect();
+            // Metadata is fetched in parallel, bounded by max_parallel_downloads on
+            // constrained machines, while this connection stores each repository in turn
+            let limits = ResourceLimits::load(&conn)?;
+            limits.configure_thread_pool();
+            for repo in &repos_needing_sync {
+                println!("Syncing repository: {} ...", repo.name);
+            }
+            let progress =
+                DownloadProgress::new("Syncing metadata", repos_needing_sync.len(), quiet);
+            let report = |index, received, total| progress.update(index, received, total);
+            let results = conary::repository::sync_repositories(
+                &mut conn,
+                &repos_needing_sync,
+                force,
+                &limits,
+                Some(&report),
+            );
+            progress.finish();
 
             // Report all results after parallel sync completes
             for (name, result) in results {
                 match result {
-                    Ok(count) => println!("  ✓ Synchronized {} packages from {}", count, name),
+                    Ok(conary::repository::SyncOutcome::Updated {
+                        packages,
+                        skipped,
+                        changes,
+                    }) => {
+                        let mut counts = format!(
+                            "{} added, {} removed, {} updated",
+                            changes.added, changes.removed, changes.updated
+                        );
+                        if skipped > 0 {
+                            counts.push_str(&format!(", {} skipped by sync filter", skipped));
+                        }
+                        println!(
+                            "  ✓ Synchronized {} packages from {} ({})",
+                            packages, name, counts
+                        )
+                    }
+                    Ok(conary::repository::SyncOutcome::Unchanged) => {
+                        println!("  ✓ {} is unchanged", name)
+                    }
                     Err(e) => println!("  ✗ Failed to sync {}: {}", name, e),
                 }
             }
 
             Ok(())
         }
-        Some(Commands::Search { pattern, db_path }) => {
+        Some(Commands::Search {
+            pattern,
+            db_path,
+            json,
+            repo,
+            installed,
+            regex,
+        }) => {
             info!("Searching for packages matching: {}", pattern);
 
-            let conn = conary::db::open(&db_path)?;
-            let packages = conary::repository::search_packages(&conn, &pattern)?;
+            let conn = conary::db::open_read_only(&db_path)?;
+            let options = conary::repository::SearchOptions {
+                repository: repo,
+                regex,
+            };
+            let hits = conary::repository::search_packages(&conn, &pattern, &options)?;
 
-            if packages.is_empty() {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&hits)?);
+            } else if hits.is_empty() {
                 println!("No packages found matching '{}'", pattern);
             } else {
-                println!("Found {} packages matching '{}':", packages.len(), pattern);
-                for pkg in packages {
+                println!("Found {} packages matching '{}':", hits.len(), pattern);
+                for hit in hits {
+                    let pkg = &hit.package;
                     let arch_str = pkg.architecture.as_deref().unwrap_or("noarch");
-                    println!("  {} {} ({})", pkg.name, pkg.version, arch_str);
+                    let mut line = format!(
+                        "  {} {} ({}) from {} (priority {})",
+                        pkg.name, pkg.version, arch_str, hit.repository, hit.repository_priority
+                    );
+                    if hit.repository_scope == RepositoryScope::ExplicitOnly {
+                        line.push_str(&format!(" [explicit-only: --repo {}]", hit.repository));
+                    }
+                    if installed {
+                        match &hit.installed_version {
+                            Some(version) if *version == pkg.version => {
+                                line.push_str(" [installed]")
+                            }
+                            Some(version) => line.push_str(&format!(" [installed: {}]", version)),
+                            None => {}
+                        }
+                    }
+                    println!("{}", line);
                     if let Some(desc) = &pkg.description {
                         println!("      {}", desc);
                     }
@@ -1505,76 +4414,174 @@ fn main() -> Result<()> {
             package,
             db_path,
             root,
+            no_scripts,
+            sandbox_scripts,
+            check_running,
+            allow_replaces,
+            allow_dir_replace,
+            timings,
+            repo,
+            dry_run,
+            summary,
+            security,
+            overwrite_modified,
         }) => {
             info!("Checking for package updates");
+            let mut report = conary::summary::OperationSummary::start();
+            let check_running = check_running
+                .map(|mode| mode.parse::<conary::running::RunningCheck>())
+                .transpose()
+                .map_err(|e| anyhow::anyhow!(e))?;
 
             let mut conn = conary::db::open(&db_path)?;
 
             // Initialize paths
-            let objects_dir = Path::new(&db_path)
-                .parent()
-                .unwrap_or_else(|| Path::new("."))
-                .join("objects");
-            let temp_dir = Path::new(&db_path)
-                .parent()
-                .unwrap_or_else(|| Path::new("."))
-                .join("tmp");
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = if dry_run { None } else { Some(layout.lock()?) };
+            let objects_dir = layout.objects_dir().to_path_buf();
+            let temp_dir = layout.temp_dir();
             std::fs::create_dir_all(&temp_dir)?;
-            let _install_root = std::path::PathBuf::from(&root);
-
-            // Get installed packages to check for updates
-            let installed_troves = if let Some(pkg_name) = package {
-                conary::db::models::Trove::find_by_name(&conn, &pkg_name)?
-            } else {
-                // Get all installed packages
-                let mut stmt = conn.prepare("SELECT id, name, version, type, architecture, description, installed_at, installed_by_changeset_id FROM troves ORDER BY name")?;
-                let rows = stmt.query_map([], |row| {
-                    Ok(conary::db::models::Trove {
-                        id: Some(row.get(0)?),
-                        name: row.get(1)?,
-                        version: row.get(2)?,
-                        trove_type: row.get::<_, String>(3)?.parse().unwrap(),
-                        architecture: row.get(4)?,
-                        description: row.get(5)?,
-                        installed_at: row.get(6)?,
-                        installed_by_changeset_id: row.get(7)?,
-                    })
-                })?;
-                rows.collect::<rusqlite::Result<Vec<_>>>()?
+            let install_root = layout.install_root().to_path_buf();
+            let runner = conary::scriptlet::select_runner(&conn, sandbox_scripts)?;
+            let install_options = conary::InstallOptions {
+                scripts: (!no_scripts).then_some(runner.as_ref()),
+                allow_downgrade: false,
+                exclude: &[],
+                force_unlock: false,
+                allow_dir_replace,
             };
-
-            if installed_troves.is_empty() {
+            let limits = ResourceLimits::load(&conn)?;
+            limits.configure_thread_pool();
+
+            let options = PlanOptions {
+                repository: repo.clone(),
+                allow_replaces,
+                security,
+                ..Default::default()
+            };
+            let mut planner = Planner::new(&conn, options);
+            planner.request(Request::Upgrade {
+                name: package.clone(),
+            });
+            let plan = planner.plan()?;
+            if plan.updates.checked == 0 {
                 println!("No packages to update");
                 return Ok(());
             }
+            for warning in &plan.warnings {
+                println!("{}", warning);
+            }
 
-            // Find available updates
-            let mut updates_available = Vec::new();
-            for trove in &installed_troves {
-                // Search for newer versions in repositories
-                let repo_packages = conary::db::models::RepositoryPackage::find_by_name(&conn, &trove.name)?;
-
-                for repo_pkg in repo_packages {
-                    // Simple version comparison (exact match for now)
-                    if repo_pkg.version != trove.version {
-                        // Check if architecture matches
-                        if repo_pkg.architecture == trove.architecture || repo_pkg.architecture.is_none() {
-                            info!("Update available: {} {} -> {}", trove.name, trove.version, repo_pkg.version);
-                            updates_available.push((trove.clone(), repo_pkg));
-                            break; // Take first available update
-                        }
+            let updates = &plan.updates;
+            if updates.entries.is_empty() {
+                if security && updates.not_security > 0 {
+                    println!(
+                        "No security updates; {} other update(s) available",
+                        updates.not_security
+                    );
+                } else if !updates.locked.is_empty() {
+                    println!(
+                        "No updates besides {} locked package(s)",
+                        updates.locked.len()
+                    );
+                } else {
+                    println!("All packages are up to date");
+                }
+                return Ok(());
+            }
+
+            println!(
+                "Found {} package(s) with updates available:",
+                updates.entries.len()
+            );
+            if dry_run {
+                print_update_plan(updates);
+            } else {
+                for entry in &updates.entries {
+                    let (trove, repo_pkg) = (&entry.installed, &entry.candidate);
+                    let note = advisory_note(entry);
+                    if entry.replaces() {
+                        println!(
+                            "  {} {} -> {} {} (replaces){}",
+                            trove.name, trove.version, repo_pkg.name, repo_pkg.version, note
+                        );
+                    } else if entry.alongside {
+                        println!(
+                            "  {} {} + {} (installed alongside){}",
+                            trove.name, trove.version, repo_pkg.version, note
+                        );
+                    } else {
+                        println!(
+                            "  {} {} -> {}{}",
+                            trove.name, trove.version, repo_pkg.version, note
+                        );
                     }
                 }
             }
+            refuse_conflicts(&plan.conflicts, "Update")?;
+            // Installonly packages keep the installed version, files and all
+            let kept: Vec<i64> = plan
+                .updates
+                .entries
+                .iter()
+                .filter(|entry| entry.alongside)
+                .filter_map(|entry| entry.installed.id)
+                .collect();
+            let updates_available: Vec<(
+                conary::db::models::Trove,
+                conary::db::models::RepositoryPackage,
+            )> = plan
+                .updates
+                .entries
+                .into_iter()
+                .map(|entry| (entry.installed, entry.candidate))
+                .collect();
+
+            // Local changes a verify found would be overwritten
+            let mut modified = Vec::new();
+            for (trove, _) in &updates_available {
+                for finding in conary::db::models::VerifyFinding::unresolved(&conn, trove)? {
+                    modified.push(format!(
+                        "  {} ({}, {})",
+                        finding.path, finding.package, finding.status
+                    ));
+                }
+            }
+            if !modified.is_empty() && !overwrite_modified {
+                let listing = modified.join("\n");
+                if !dry_run {
+                    return Err(anyhow::anyhow!(
+                        "verify found local changes the update would overwrite:\n{}\n\
+                         Rerun with --overwrite-modified to replace them, or run verify again once they are resolved",
+                        listing
+                    ));
+                }
+                println!(
+                    "\nverify found local changes the update would overwrite (needs --overwrite-modified):\n{}",
+                    listing
+                );
+            }
 
-            if updates_available.is_empty() {
-                println!("All packages are up to date");
+            if dry_run {
+                println!("\nDry run complete. No changes made.");
                 return Ok(());
             }
 
-            println!("Found {} package(s) with updates available:", updates_available.len());
-            for (trove, repo_pkg) in &updates_available {
-                println!("  {} {} -> {}", trove.name, trove.version, repo_pkg.version);
+            // Every file of an updated trove is replaced or removed
+            if let Some(mode) = check_running {
+                let mut replaced = Vec::new();
+                for (trove, _) in updates_available
+                    .iter()
+                    .filter(|(trove, _)| !kept.contains(&trove.id.unwrap()))
+                {
+                    let files =
+                        conary::db::models::FileEntry::find_by_trove(&conn, trove.id.unwrap())?;
+                    for file in files {
+                        let full_path = conary::paths::under_root(&install_root, &file.path);
+                        replaced.push(full_path.to_string_lossy().to_string());
+                    }
+                }
+                check_running_processes(&replaced, mode)?;
             }
 
             // Initialize delta tracking
@@ -1582,114 +4589,131 @@ fn main() -> Result<()> {
             let mut deltas_applied = 0i32;
             let mut full_downloads = 0i32;
             let mut delta_failures = 0i32;
+            let cas = conary::filesystem::CasStore::new(&objects_dir)?;
 
             // Create changeset for the update operation
             let changeset_id = conary::db::transaction(&mut conn, |tx| {
-                let mut changeset = conary::db::models::Changeset::new(
-                    format!("Update {} package(s)", updates_available.len())
-                );
+                let mut changeset = conary::db::models::Changeset::new(format!(
+                    "Update {} package(s)",
+                    updates_available.len()
+                ));
                 changeset.insert(tx)
             })?;
+            report.changesets.push(changeset_id);
 
             // Process each update
             for (installed_trove, repo_pkg) in updates_available {
-                println!("\nUpdating {} ...", installed_trove.name);
-
-                // Try delta update first
-                let mut delta_success = false;
-
-                // Check if delta is available from current to new version
-                if let Ok(Some(delta_info)) = PackageDelta::find_delta(
-                    &conn,
-                    &installed_trove.name,
-                    &installed_trove.version,
-                    &repo_pkg.version,
-                ) {
-                    println!(
-                        "  Delta available: {} bytes ({:.1}% of full size)",
-                        delta_info.delta_size,
-                        delta_info.compression_ratio * 100.0
-                    );
-
-                    // Try to download and apply delta
-                    let delta_path = temp_dir.join(format!(
-                        "{}-{}-to-{}.delta",
-                        installed_trove.name, installed_trove.version, repo_pkg.version
-                    ));
-
-                    match repository::download_delta(
-                        &repository::DeltaInfo {
-                            from_version: delta_info.from_version,
-                            from_hash: delta_info.from_hash.clone(),
-                            delta_url: delta_info.delta_url,
-                            delta_size: delta_info.delta_size,
-                            delta_checksum: delta_info.delta_checksum,
-                            compression_ratio: delta_info.compression_ratio,
-                        },
-                        &installed_trove.name,
-                        &repo_pkg.version,
-                        &temp_dir,
-                    ) {
-                        Ok(_) => {
-                            // Apply delta
-                            let applier = DeltaApplier::new(&objects_dir)?;
-
-                            match applier.apply_delta(
-                                &delta_info.from_hash,
-                                &delta_path,
-                                &delta_info.to_hash,
-                            ) {
-                                Ok(_) => {
-                                    println!("  ✓ Delta applied successfully");
-                                    delta_success = true;
-                                    deltas_applied += 1;
-
-                                    // Calculate bandwidth saved
-                                    let saved = repo_pkg.size - delta_info.delta_size;
-                                    total_bytes_saved += saved;
-                                }
-                                Err(e) => {
-                                    warn!("  Delta application failed: {}", e);
-                                    delta_failures += 1;
-                                }
-                            }
+                println!("\nUpdating {} ...", installed_trove.name);
+                let repository =
+                    conary::db::models::Repository::find_by_id(&conn, repo_pkg.repository_id)?
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Repository of {} {} is gone",
+                                repo_pkg.name,
+                                repo_pkg.version
+                            )
+                        })?;
+
+                // Rebuild from a delta when the CAS still has a base for one
+                let deltas = repository::usable_deltas(&conn, &cas, &repo_pkg)?;
+                if let Some(delta) = deltas.first() {
+                    println!(
+                        "  Delta available from {}: {} bytes ({:.1}% of full size)",
+                        delta.from_version,
+                        delta.delta_size,
+                        delta.compression_ratio * 100.0
+                    );
+                }
 
-                            // Clean up delta file
-                            let _ = std::fs::remove_file(delta_path);
-                        }
-                        Err(e) => {
-                            warn!("  Delta download failed: {}", e);
-                            delta_failures += 1;
-                        }
+                let progress =
+                    DownloadProgress::new(format!("Downloading {}", repo_pkg.name), 1, quiet);
+                let fetched = limits
+                    .check_tmp_quota(&temp_dir, repo_pkg.size as u64)
+                    .and_then(|()| {
+                        repository::fetch_package(
+                            &repository,
+                            &repo_pkg,
+                            &deltas,
+                            &cas,
+                            &temp_dir,
+                            Some(&progress.reporter()),
+                        )
+                    });
+                progress.finish();
+                let fetched = match fetched {
+                    Ok(fetched) => fetched,
+                    Err(e) => {
+                        delta_failures += deltas.len() as i32;
+                        warn!("  Full download failed: {}", e);
+                        report.failed += 1;
+                        continue;
                     }
+                };
+                delta_failures += fetched.delta_failures;
+                if fetched.delta.is_some() {
+                    println!("  ✓ Delta applied successfully");
+                    deltas_applied += 1;
+                    total_bytes_saved += fetched.bytes_saved();
+                    report.delta_bytes_saved += fetched.bytes_saved() as u64;
+                } else {
+                    println!("  ✓ Downloaded {} bytes", fetched.downloaded_bytes);
+                    full_downloads += 1;
                 }
-
-                // Fall back to full download if delta didn't work
-                if !delta_success {
-                    println!("  Downloading full package...");
-
-                    match repository::download_package(&repo_pkg, &temp_dir) {
-                        Ok(pkg_path) => {
-                            println!("  ✓ Downloaded {} bytes", repo_pkg.size);
-                            full_downloads += 1;
-
-                            // Parse and install the downloaded package
-                            if let Err(e) = install_package_from_file(&pkg_path, &mut conn, &root, Some(&installed_trove)) {
-                                warn!("  Package installation failed: {}", e);
-                                let _ = std::fs::remove_file(pkg_path);
-                                continue;
-                            }
-
-                            println!("  ✓ Package installed successfully");
-                            // Clean up downloaded file
-                            let _ = std::fs::remove_file(pkg_path);
-                        }
-                        Err(e) => {
-                            warn!("  Full download failed: {}", e);
-                            continue;
+                let pkg_path = fetched.path;
+                let pkg_size = fetched.downloaded_bytes;
+                report.downloaded_bytes += pkg_size as u64;
+                conary::stats::record_download(&conn, repo_pkg.repository_id, pkg_size)?;
+                conary::db::models::Changeset::add_sizes(&conn, changeset_id, pkg_size, 0)?;
+
+                // Parse and install the downloaded package
+                let installed =
+                    conary::Installer::for_layout(&mut conn, &layout).and_then(|mut installer| {
+                        let mut prepared = installer.prepare(&pkg_path)?;
+                        prepared.downloaded_for(&repo_pkg);
+                        prepared.replace(&installed_trove);
+                        installer.install_batch(vec![prepared], &install_options)
+                    });
+                match installed {
+                    Ok(installed) => {
+                        installed.packages.iter().for_each(print_saved_dirs);
+                        print_staged(&installed);
+                        print_pruned(&installed);
+                        let pkg_changeset_id = installed.changeset_id;
+                        report.upgraded += 1;
+                        report.changesets.push(pkg_changeset_id);
+                        report.add_timings(&installed.timings);
+                        // The update changeset sums up the size changes of each package
+                        let size_delta =
+                            conary::db::models::Changeset::find_by_id(&conn, pkg_changeset_id)?
+                                .and_then(|cs| cs.installed_size_delta)
+                                .unwrap_or(0);
+                        conary::db::models::Changeset::add_sizes(
+                            &conn,
+                            pkg_changeset_id,
+                            pkg_size,
+                            0,
+                        )?;
+                        conary::db::models::Changeset::add_sizes(
+                            &conn,
+                            changeset_id,
+                            0,
+                            size_delta,
+                        )?;
+                        if timings {
+                            print_timings(&conn, pkg_changeset_id)?;
                         }
                     }
+                    Err(e) => {
+                        warn!("  Package installation failed: {}", e);
+                        report.failed += 1;
+                        let _ = std::fs::remove_file(pkg_path);
+                        continue;
+                    }
                 }
+
+                println!("  ✓ Package installed successfully");
+                // Clean up downloaded file
+                let _ = std::fs::remove_file(pkg_path);
             }
 
             // Store delta statistics
@@ -1703,12 +4727,23 @@ fn main() -> Result<()> {
 
                 // Mark changeset as applied
                 let mut changeset = conary::db::models::Changeset::find_by_id(tx, changeset_id)?
-                    .ok_or_else(|| conary::Error::NotFoundError("Changeset not found".to_string()))?;
+                    .ok_or_else(|| {
+                        conary::Error::NotFoundError("Changeset not found".to_string())
+                    })?;
                 changeset.update_status(tx, conary::db::models::ChangesetStatus::Applied)?;
 
                 Ok(())
             })?;
 
+            if summary {
+                let warnings = WARNINGS
+                    .get()
+                    .map(|collector| collector.take())
+                    .unwrap_or_default();
+                print!("\n{}", report.render(&warnings));
+                return Ok(());
+            }
+
             // Print summary
             println!("\n=== Update Summary ===");
             println!("Delta updates: {}", deltas_applied);
@@ -1721,10 +4756,34 @@ fn main() -> Result<()> {
 
             Ok(())
         }
-        Some(Commands::DeltaStats { db_path }) => {
+        Some(Commands::CheckUpdate {
+            package,
+            db_path,
+            repo,
+            security,
+        }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            let request = conary::installer::UpdateRequest {
+                package: package.as_deref(),
+                repo: repo.as_deref(),
+                allow_replaces: false,
+                security,
+            };
+            let plan = conary::installer::plan_updates(&conn, &request)?;
+            if plan.entries.is_empty() {
+                return Ok(());
+            }
+            if !quiet {
+                for entry in &plan.entries {
+                    println!("{}", entry.check_line());
+                }
+            }
+            Err(UpdatesAvailable.into())
+        }
+        Some(Commands::DeltaStats { db_path, json }) => {
             info!("Showing delta update statistics");
 
-            let conn = conary::db::open(&db_path)?;
+            let conn = conary::db::open_read_only(&db_path)?;
 
             // Get total statistics across all changesets
             let total_stats = DeltaStats::get_total_stats(&conn)?;
@@ -1733,7 +4792,7 @@ fn main() -> Result<()> {
             let all_stats = {
                 let mut stmt = conn.prepare(
                     "SELECT id, changeset_id, total_bytes_saved, deltas_applied, full_downloads, delta_failures, created_at
-                     FROM delta_stats ORDER BY created_at DESC"
+                     FROM delta_stats ORDER BY created_at DESC, id DESC"
                 )?;
                 let rows = stmt.query_map([], |row| {
                     Ok(DeltaStats {
@@ -1749,6 +4808,15 @@ fn main() -> Result<()> {
                 rows.collect::<rusqlite::Result<Vec<_>>>()?
             };
 
+            if json {
+                let report = serde_json::json!({
+                    "total": total_stats,
+                    "operations": all_stats,
+                });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
+
             if all_stats.is_empty() {
                 println!("No delta statistics available");
                 println!("Run 'conary update' to start tracking delta usage");
@@ -1768,7 +4836,8 @@ fn main() -> Result<()> {
             // Calculate success rate
             let total_updates = total_stats.deltas_applied + total_stats.full_downloads;
             if total_updates > 0 {
-                let success_rate = (total_stats.deltas_applied as f64 / total_updates as f64) * 100.0;
+                let success_rate =
+                    (total_stats.deltas_applied as f64 / total_updates as f64) * 100.0;
                 println!("  Delta success rate: {:.1}%", success_rate);
             }
 
@@ -1800,6 +4869,435 @@ fn main() -> Result<()> {
 
             Ok(())
         }
+        Some(Commands::DeltaGenerate {
+            package,
+            old_version,
+            new_version,
+            package_files,
+            output,
+            db_path,
+        }) => {
+            let conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?;
+            let cas = conary::filesystem::CasStore::new(layout.objects_dir())?;
+
+            let old = delta_version_files(&conn, &cas, &package, &old_version, &package_files)?;
+            let new = delta_version_files(&conn, &cas, &package, &new_version, &package_files)?;
+            let generator = conary::delta::DeltaGenerator::new(layout.objects_dir())?;
+            let manifest =
+                generator.generate_package_deltas(&package, &old, &new, Path::new(&output))?;
+
+            let delta_bytes: i64 = manifest
+                .files
+                .iter()
+                .map(|file| file.delta.delta_size)
+                .sum();
+            println!(
+                "Generated {} delta(s) for {} {} -> {} ({})",
+                manifest.files.len(),
+                package,
+                old_version,
+                new_version,
+                format_size(Some(delta_bytes), false)
+            );
+            for path in &manifest.skipped {
+                println!("  skipped {}: delta not smaller than the file", path);
+            }
+            println!(
+                "Manifest: {}",
+                Path::new(&output)
+                    .join(conary::delta::DeltaManifest::file_name(
+                        &package,
+                        &old_version,
+                        &new_version
+                    ))
+                    .display()
+            );
+            Ok(())
+        }
+        Some(Commands::Stats {
+            action: Some(StatsAction::Reset),
+            db_path,
+            ..
+        }) => {
+            info!("Resetting operation statistics");
+
+            let conn = conary::db::open(&db_path)?;
+            let removed = conary::stats::reset(&conn)?;
+
+            println!("Reset operation statistics ({} records removed)", removed);
+            Ok(())
+        }
+        Some(Commands::Stats {
+            action: None,
+            db_path,
+            since,
+            by_repo,
+        }) => {
+            info!("Showing operation statistics");
+
+            let conn = conary::db::open_read_only(&db_path)?;
+            let since_ts = since
+                .as_deref()
+                .map(conary::stats::parse_since)
+                .transpose()?;
+
+            match &since {
+                Some(window) => println!("=== Operation Statistics (last {}) ===\n", window),
+                None => println!("=== Operation Statistics ===\n"),
+            }
+
+            if by_repo {
+                let by_repo = conary::stats::totals_by_repo(&conn, since_ts.as_deref())?;
+                if by_repo.is_empty() {
+                    println!("No statistics recorded");
+                    return Ok(());
+                }
+                for (idx, (repo_name, totals)) in by_repo.iter().enumerate() {
+                    if idx > 0 {
+                        println!();
+                    }
+                    println!("{}:", repo_name);
+                    print_stats_totals(totals);
+                }
+            } else {
+                let totals = conary::stats::totals(&conn, since_ts.as_deref())?;
+                print_stats_totals(&totals);
+            }
+
+            Ok(())
+        }
+        Some(Commands::Debuginfo {
+            action:
+                DebuginfoAction::Install {
+                    package,
+                    db_path,
+                    root,
+                    dry_run,
+                },
+        }) => {
+            let mut conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = if dry_run { None } else { Some(layout.lock()?) };
+            let mut installer = conary::Installer::for_layout(&mut conn, &layout)?;
+
+            let mut planner = Planner::new(
+                installer.conn(),
+                PlanOptions {
+                    skip_installed: true,
+                    ..Default::default()
+                },
+            );
+            planner.request(Request::Companion {
+                name: package.clone(),
+                companion: Companion::Debuginfo,
+            });
+            let mut plan = planner.plan()?;
+            for package in &plan.unchanged {
+                println!("{} {} is already installed", package.name, package.version);
+            }
+            refuse_conflicts(&plan.conflicts, "Install")?;
+            if plan.operations.is_empty() {
+                return Ok(());
+            }
+            if dry_run {
+                for operation in &plan.operations {
+                    if let Operation::Install {
+                        package,
+                        source: Source::Repository { repository, .. },
+                        ..
+                    } = operation
+                    {
+                        println!(
+                            "Would install package: {} version {} (from {})",
+                            package.name, package.version, repository
+                        );
+                    }
+                }
+                println!("\nDry run complete. No changes made.");
+                return Ok(());
+            }
+
+            let label = format!("Downloading {} package(s)", plan.downloads.len());
+            let progress = DownloadProgress::new(label, plan.downloads.len(), quiet);
+            let report = |index, received, total| progress.update(index, received, total);
+            let fetched = installer.download_plan(&mut plan, Some(&report));
+            progress.finish();
+            fetched.map_err(|e| anyhow::anyhow!("Failed to download packages: {}", e))?;
+
+            let runner = conary::scriptlet::select_runner(installer.conn(), false)?;
+            let options = conary::InstallOptions {
+                scripts: Some(runner.as_ref()),
+                ..Default::default()
+            };
+            if let Some(report) = installer.execute(plan, &options)?.installed {
+                for package in &report.packages {
+                    println!(
+                        "Installed package: {} version {}",
+                        package.name, package.version
+                    );
+                }
+                print_staged(&report);
+                print_pruned(&report);
+            }
+            Ok(())
+        }
+        Some(Commands::Cas {
+            action: CasAction::Analyze { top, db_path, json },
+        }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            let report = conary::query::duplicate_content(&conn, top)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
+
+            if report.duplicated_blobs == 0 {
+                println!("No duplicate content installed");
+            } else {
+                println!(
+                    "{} duplicated blobs, {} extra copies",
+                    report.duplicated_blobs, report.duplicate_files
+                );
+            }
+            println!(
+                "Hardlink savings: {}",
+                format_size(Some(report.hardlink_savings), false)
+            );
+            println!(
+                "Single-store savings: {}",
+                format_size(Some(report.single_store_savings), false)
+            );
+
+            if !report.top.is_empty() {
+                println!("\nTop duplicated blobs:");
+                for blob in &report.top {
+                    println!(
+                        "  {}  {} x {} copies, {} wasted",
+                        &blob.sha256_hash[..blob.sha256_hash.len().min(12)],
+                        format_size(Some(blob.size), false),
+                        blob.paths.len(),
+                        format_size(Some(blob.wasted_bytes), false)
+                    );
+                    for path in &blob.paths {
+                        println!("      {} ({})", path.path, path.package);
+                    }
+                }
+            }
+
+            if !report.by_package.is_empty() {
+                println!("\nBy package:");
+                for package in &report.by_package {
+                    println!(
+                        "  {}: {} files, {}",
+                        package.package,
+                        package.duplicate_files,
+                        format_size(Some(package.wasted_bytes), false)
+                    );
+                }
+            }
+            Ok(())
+        }
+        Some(Commands::Doctor {
+            check:
+                DoctorCheck::Repos {
+                    limit,
+                    offset,
+                    db_path,
+                    json,
+                },
+        }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            let report = conary::repository::OverlapReport::build(&conn, offset, limit)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
+
+            if report.total == 0 {
+                println!("No package name is offered by enabled repositories of different formats");
+                return Ok(());
+            }
+
+            println!(
+                "{} package name(s) offered by enabled repositories of different formats:",
+                report.total
+            );
+            for overlap in &report.overlaps {
+                println!("\n{}", overlap.name);
+                for offer in &overlap.offers {
+                    println!(
+                        "  {} (priority {}, {}): {} {}",
+                        offer.repository,
+                        offer.priority,
+                        offer.format,
+                        offer.version,
+                        offer.architecture.as_deref().unwrap_or("-")
+                    );
+                }
+                match &overlap.selected {
+                    Some(repository) => println!("  selected: {} ({})", repository, overlap.reason),
+                    None => println!("  selected: none ({})", overlap.reason),
+                }
+            }
+
+            let shown = report.offset + report.overlaps.len();
+            if shown < report.total {
+                println!(
+                    "\nShowing {}-{} of {}; use --offset {} for more",
+                    report.offset + 1,
+                    shown,
+                    report.total,
+                    shown
+                );
+            }
+            println!(
+                "\nWarning: versions from different distributions cannot be compared, and mixing their \
+                 packages on one system can break it. Choose which repository each package comes from with \
+                 'conary repo-priority --set NAME=PRIORITY', or keep a repository to installs naming it with \
+                 'conary repo-modify NAME --scope explicit-only'."
+            );
+            Ok(())
+        }
+        Some(Commands::Cas {
+            action:
+                CasAction::Gc {
+                    dry_run,
+                    keep_days,
+                    db_path,
+                },
+        }) => {
+            let mut conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?;
+            let _lock = layout.lock()?;
+
+            let options = conary::gc::GcOptions {
+                retention_days: match keep_days {
+                    Some(days) => days,
+                    None => conary::gc::retention_days(&conn)?,
+                },
+                dry_run,
+            };
+            let cas = conary::CasStore::new(layout.objects_dir())?;
+            let report = conary::gc::collect_garbage(&mut conn, &cas, &options)?;
+
+            let reclaimed = format_size(Some(report.bytes_reclaimed as i64), false);
+            if dry_run {
+                println!(
+                    "Would remove {} unreferenced objects, reclaiming {}",
+                    report.removed, reclaimed
+                );
+            } else {
+                println!(
+                    "Removed {} unreferenced objects, reclaimed {}",
+                    report.removed, reclaimed
+                );
+            }
+            println!(
+                "Kept {} objects referenced by installed files or changesets of the last {} days",
+                report.kept, options.retention_days
+            );
+            Ok(())
+        }
+        Some(Commands::CasRelocate { new_dir, db_path }) => {
+            info!("Relocating CAS objects to {}", new_dir);
+
+            let conn = conary::db::open(&db_path)?;
+            let mut layout = conary::paths::Layout::load(&conn, &db_path)?;
+            let _lock = layout.lock()?;
+
+            let old_dir = layout.objects_dir().to_path_buf();
+            let moved = layout.relocate_objects(&conn, Path::new(&new_dir))?;
+
+            println!(
+                "Moved {} objects from {} to {}",
+                moved,
+                old_dir.display(),
+                layout.objects_dir().display()
+            );
+            Ok(())
+        }
+        Some(Commands::ApplyPending {
+            db_path,
+            root,
+            dry_run,
+        }) => {
+            let conn = conary::db::open(&db_path)?;
+            let layout = conary::paths::Layout::load(&conn, &db_path)?.with_root(&root)?;
+            let _lock = if dry_run { None } else { Some(layout.lock()?) };
+
+            if dry_run {
+                let staged = conary::staged::staged_changesets(&conn)?;
+                if staged.is_empty() {
+                    println!("No staged changesets");
+                }
+                for changeset in staged {
+                    println!(
+                        "Would apply changeset {}: {}",
+                        changeset.id.unwrap(),
+                        changeset.description
+                    );
+                }
+                return Ok(());
+            }
+
+            let applied = conary::staged::apply_pending(&conn, &layout)?;
+            if applied.is_empty() {
+                println!("No staged changesets");
+            }
+            for changeset in &applied {
+                println!(
+                    "Applied changeset {}: {} ({} files)",
+                    changeset.changeset_id, changeset.description, changeset.files
+                );
+            }
+            Ok(())
+        }
+        Some(Commands::ConfigSet {
+            key,
+            value,
+            db_path,
+        }) => {
+            validate_config(&key, &value)?;
+
+            let conn = conary::db::open(&db_path)?;
+            conary::db::models::Setting::set(&conn, &key, &value)?;
+            println!("{} = {}", key, value);
+            Ok(())
+        }
+        Some(Commands::ConfigGet { key, db_path }) => {
+            let conn = conary::db::open_read_only(&db_path)?;
+            let keys: Vec<&str> = match &key {
+                Some(key) => vec![key.as_str()],
+                None => CONFIG_KEYS.to_vec(),
+            };
+
+            for key in keys {
+                match conary::db::models::Setting::get(&conn, key)? {
+                    Some(value) => println!("{} = {}", key, value),
+                    None => println!("{} = {} (default)", key, config_default(key)),
+                }
+            }
+            Ok(())
+        }
+        Some(Commands::ConfigUnset { key, db_path }) => {
+            if key == conary::paths::OBJECTS_PATH_SETTING {
+                return Err(anyhow::anyhow!(
+                    "{} is managed by 'conary cas-relocate', which also moves the objects",
+                    key
+                ));
+            }
+
+            let conn = conary::db::open(&db_path)?;
+            if conary::db::models::Setting::delete(&conn, &key)? {
+                println!("{} reset to {}", key, config_default(&key));
+            } else {
+                println!("{} was not set", key);
+            }
+            Ok(())
+        }
         None => {
             // No command provided, show help
             println!("Conary Package Manager v{}", env!("CARGO_PKG_VERSION"));
@@ -1812,86 +5310,1038 @@ fn main() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// Install a package file as its own changeset through the library installer
+    ///
+    /// Upgrades keep the install reason of the trove they replace.
+    fn install_package_from_file(
+        package_path: &Path,
+        conn: &mut rusqlite::Connection,
+        root: &Path,
+        old_trove: Option<&conary::db::models::Trove>,
+        objects_dir: &Path,
+        scripts: Option<&dyn conary::scriptlet::ScriptletRunner>,
+        reason: InstallReason,
+    ) -> Result<i64> {
+        let mut installer = conary::Installer::new(conn, objects_dir, root)?;
+        let mut prepared = installer.prepare(package_path)?;
+        match old_trove {
+            Some(old) => prepared.replace(old),
+            None => prepared.set_reason(reason),
+        }
+        let options = conary::InstallOptions {
+            scripts,
+            allow_downgrade: false,
+            exclude: &[],
+            force_unlock: false,
+            allow_dir_replace: false,
+        };
+        Ok(installer
+            .install_batch(vec![prepared], &options)?
+            .changeset_id)
+    }
 
-    #[test]
-    fn test_detect_format_from_rpm_extension() {
-        // Create a temporary file with .rpm extension
-        let temp_file = tempfile::NamedTempFile::with_suffix(".rpm").unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    /// Build a minimal Arch package (.pkg.tar.zst) containing the given files
+    fn build_arch_fixture(
+        dir: &Path,
+        name: &str,
+        version: &str,
+        files: &[(&str, &[u8])],
+    ) -> PathBuf {
+        build_arch_fixture_with(dir, name, version, "", files)
+    }
 
-        // Write RPM magic bytes
-        std::fs::write(path, [0xED, 0xAB, 0xEE, 0xDB, 0, 0, 0, 0]).unwrap();
+    /// Like build_arch_fixture, with extra lines appended to .PKGINFO
+    fn build_arch_fixture_with(
+        dir: &Path,
+        name: &str,
+        version: &str,
+        pkginfo_extra: &str,
+        files: &[(&str, &[u8])],
+    ) -> PathBuf {
+        let pkg_path = dir.join(format!("{}-{}-x86_64.pkg.tar.zst", name, version));
+        let encoder = zstd::Encoder::new(File::create(&pkg_path).unwrap(), 3).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+
+        let pkginfo = format!(
+            "pkgname = {}\npkgver = {}\narch = x86_64\n{}",
+            name, version, pkginfo_extra
+        );
+        let mut header = tar::Header::new_gnu();
+        header.set_size(pkginfo.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, ".PKGINFO", pkginfo.as_bytes())
+            .unwrap();
+
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *content).unwrap();
+        }
 
-        let format = detect_package_format(path).unwrap();
-        assert_eq!(format, PackageFormatType::Rpm);
+        builder.into_inner().unwrap().finish().unwrap();
+        pkg_path
     }
 
     #[test]
-    fn test_detect_format_from_deb_extension() {
-        let temp_file = tempfile::NamedTempFile::with_suffix(".deb").unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_fixture_install_records_stats() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let content: &[u8] = b"#!/bin/sh\necho hello\n";
+        let pkg = build_arch_fixture(temp.path(), "hello", "1.0-1", &[("usr/bin/hello", content)]);
+        install_package_from_file(
+            &pkg,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        let totals = conary::stats::totals(&conn, None).unwrap();
+        assert_eq!(totals.packages_installed, 1);
+        assert_eq!(totals.packages_updated, 0);
+        assert_eq!(totals.cache_hit_bytes, 0);
+
+        // A second package shipping identical content hits the CAS
+        let pkg2 = build_arch_fixture(
+            temp.path(),
+            "hello-alias",
+            "1.0-1",
+            &[("usr/bin/hello-alias", content)],
+        );
+        install_package_from_file(
+            &pkg2,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        // Upgrading counts as an update, not an install
+        let old = conary::db::models::Trove::find_by_name(&conn, "hello")
+            .unwrap()
+            .remove(0);
+        let pkg3 = build_arch_fixture(
+            temp.path(),
+            "hello",
+            "1.1-1",
+            &[("usr/bin/hello", b"#!/bin/sh\necho hi\n")],
+        );
+        install_package_from_file(
+            &pkg3,
+            &mut conn,
+            &root,
+            Some(&old),
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
 
-        // Write DEB magic bytes
-        std::fs::write(path, b"!<arch>\n").unwrap();
+        let totals = conary::stats::totals(&conn, None).unwrap();
+        assert_eq!(totals.packages_installed, 2);
+        assert_eq!(totals.packages_updated, 1);
+        assert_eq!(totals.cache_hit_bytes, content.len() as i64);
+        assert_eq!(totals.bytes_downloaded, 0);
+    }
 
-        let format = detect_package_format(path).unwrap();
-        assert_eq!(format, PackageFormatType::Deb);
+    #[test]
+    fn test_failing_pre_install_aborts_before_deploy() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let install: &[u8] = b"pre_install() {\n  exit 1\n}\n";
+        let pkg = build_arch_fixture(
+            temp.path(),
+            "adduser",
+            "1.0-1",
+            &[(".INSTALL", install), ("usr/bin/adduser", b"binary")],
+        );
+
+        let result = install_package_from_file(
+            &pkg,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        );
+        assert!(result.is_err());
+        assert!(!root.join("usr/bin/adduser").exists());
+        assert!(
+            conary::db::models::Trove::find_by_name(&conn, "adduser")
+                .unwrap()
+                .is_empty()
+        );
+
+        let changesets = conary::db::models::Changeset::list_all(&conn).unwrap();
+        assert_eq!(changesets.len(), 1);
+        assert_eq!(
+            changesets[0].status,
+            conary::db::models::ChangesetStatus::RolledBack
+        );
+
+        // With --noscripts the same package installs and its scriptlet is kept for later
+        install_package_from_file(
+            &pkg,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        assert!(root.join("usr/bin/adduser").exists());
+
+        let trove = conary::db::models::Trove::find_by_name(&conn, "adduser")
+            .unwrap()
+            .remove(0);
+        let stored =
+            conary::db::models::ScriptletEntry::find_by_trove(&conn, trove.id.unwrap()).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].phase, "pre-install");
+        assert_eq!(stored[0].format, "arch");
     }
 
     #[test]
-    fn test_detect_format_from_arch_extension() {
-        let temp_file = tempfile::NamedTempFile::with_suffix(".pkg.tar.zst").unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_fixture_symlinks_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        // Package with a regular file, a relative symlink and a hardlink to it
+        let pkg_path = temp.path().join("coreutils-9.0-1-x86_64.pkg.tar.zst");
+        let encoder = zstd::Encoder::new(File::create(&pkg_path).unwrap(), 3).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+
+        let pkginfo = "pkgname = coreutils\npkgver = 9.0-1\narch = x86_64\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(pkginfo.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, ".PKGINFO", pkginfo.as_bytes())
+            .unwrap();
+
+        let content: &[u8] = b"multicall binary";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o755);
+        header.set_username("root").unwrap();
+        header.set_groupname("wheel").unwrap();
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "usr/bin/coreutils", content)
+            .unwrap();
+
+        for (entry_type, path, target) in [
+            (tar::EntryType::Symlink, "usr/bin/ls", "coreutils"),
+            (tar::EntryType::Link, "usr/bin/cat", "usr/bin/coreutils"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            header.set_mode(0o777);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        install_package_from_file(
+            &pkg_path,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        let ls = root.join("usr/bin/ls");
+        assert!(
+            std::fs::symlink_metadata(&ls)
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        assert_eq!(std::fs::read_link(&ls).unwrap(), Path::new("coreutils"));
+        assert_eq!(std::fs::read(root.join("usr/bin/cat")).unwrap(), content);
+
+        let link = conary::db::models::FileEntry::find_by_path(&conn, "/usr/bin/ls")
+            .unwrap()
+            .unwrap();
+        assert_eq!(link.file_type, conary::db::models::FileType::Symlink);
+        assert_eq!(link.link_target.as_deref(), Some("coreutils"));
+        let hardlink = conary::db::models::FileEntry::find_by_path(&conn, "/usr/bin/cat")
+            .unwrap()
+            .unwrap();
+        assert_eq!(hardlink.file_type, conary::db::models::FileType::Hardlink);
+        assert_eq!(hardlink.link_target.as_deref(), Some("/usr/bin/coreutils"));
+
+        let binary = conary::db::models::FileEntry::find_by_path(&conn, "/usr/bin/coreutils")
+            .unwrap()
+            .unwrap();
+        assert_eq!(binary.owner.as_deref(), Some("root"));
+        assert_eq!(binary.group_name.as_deref(), Some("wheel"));
+
+        // Every tracked file verifies, and a repointed symlink does not
+        let deployer = conary::filesystem::FileDeployer::new(&objects_dir, &root).unwrap();
+        for path in ["/usr/bin/coreutils", "/usr/bin/ls", "/usr/bin/cat"] {
+            let file = conary::db::models::FileEntry::find_by_path(&conn, path)
+                .unwrap()
+                .unwrap();
+            assert!(
+                deployer.verify_entry(&file).unwrap(),
+                "{} should verify",
+                path
+            );
+        }
 
-        // Write zstd magic bytes
-        std::fs::write(path, [0x28, 0xB5, 0x2F, 0xFD, 0, 0, 0, 0]).unwrap();
+        std::fs::remove_file(&ls).unwrap();
+        std::os::unix::fs::symlink("/usr/bin/evil", &ls).unwrap();
+        assert!(!deployer.verify_entry(&link).unwrap());
+    }
 
-        let format = detect_package_format(path).unwrap();
-        assert_eq!(format, PackageFormatType::Arch);
+    #[test]
+    fn test_upgrade_keeps_modified_config() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+        let backup = "backup = etc/app.conf\nbackup = etc/other.conf\n";
+
+        let v1 = build_arch_fixture_with(
+            temp.path(),
+            "app",
+            "1.0-1",
+            backup,
+            &[
+                ("etc/app.conf", b"port=80\n"),
+                ("etc/other.conf", b"a=1\n"),
+                ("usr/bin/app", b"v1"),
+            ],
+        );
+        install_package_from_file(
+            &v1,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        let config = conary::db::models::FileEntry::find_by_path(&conn, "/etc/app.conf")
+            .unwrap()
+            .unwrap();
+        assert!(config.is_config);
+
+        // The user edits one config file and leaves the other alone
+        std::fs::write(root.join("etc/app.conf"), b"port=8080\n").unwrap();
+
+        let v2 = build_arch_fixture_with(
+            temp.path(),
+            "app",
+            "1.1-1",
+            backup,
+            &[
+                ("etc/app.conf", b"port=80\nworkers=4\n"),
+                ("etc/other.conf", b"a=2\n"),
+                ("usr/bin/app", b"v2"),
+            ],
+        );
+        let old = conary::db::models::Trove::find_by_name(&conn, "app")
+            .unwrap()
+            .remove(0);
+        install_package_from_file(
+            &v2,
+            &mut conn,
+            &root,
+            Some(&old),
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read(root.join("etc/app.conf")).unwrap(),
+            b"port=8080\n"
+        );
+        assert_eq!(
+            std::fs::read(root.join("etc/app.conf.conarynew")).unwrap(),
+            b"port=80\nworkers=4\n"
+        );
+        assert_eq!(
+            std::fs::read(root.join("etc/other.conf")).unwrap(),
+            b"a=2\n"
+        );
+        assert!(!root.join("etc/other.conf.conarynew").exists());
+        assert_eq!(std::fs::read(root.join("usr/bin/app")).unwrap(), b"v2");
+
+        // The decision is recorded, with the local edits saved in the CAS
+        let (action, previous): (String, String) = conn
+            .query_row(
+                "SELECT action, previous_hash FROM file_history WHERE path = '/etc/app.conf' ORDER BY id DESC LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(action, "config-kept");
+        let cas = conary::filesystem::CasStore::new(&objects_dir).unwrap();
+        assert_eq!(cas.retrieve(&previous).unwrap(), b"port=8080\n");
     }
 
     #[test]
-    fn test_detect_format_from_rpm_magic_bytes() {
-        // Test fallback to magic bytes when extension is not recognized
-        let temp_file = tempfile::NamedTempFile::with_suffix(".unknown").unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_remove_deletes_files_and_keeps_edited_config() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let package = build_arch_fixture_with(
+            temp.path(),
+            "app",
+            "1.0-1",
+            "backup = etc/app.conf\nbackup = etc/other.conf\n",
+            &[
+                ("etc/app.conf", b"port=80\n"),
+                ("etc/other.conf", b"a=1\n"),
+                ("usr/bin/app", b"v1"),
+            ],
+        );
+        install_package_from_file(
+            &package,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        std::fs::write(root.join("etc/app.conf"), b"port=8080\n").unwrap();
+
+        let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+        let report = installer
+            .remove("app", None, &conary::RemoveOptions { scripts: None })
+            .unwrap();
+        assert!(!root.join("usr/bin/app").exists());
+        assert!(!root.join("etc/other.conf").exists());
+        assert_eq!(
+            std::fs::read(root.join("etc/app.conf")).unwrap(),
+            b"port=8080\n"
+        );
+
+        // The deletions are recorded with the content that was removed
+        let mut stmt = conn
+            .prepare("SELECT path, previous_hash FROM file_history WHERE changeset_id = ?1 AND action = 'delete' ORDER BY path")
+            .unwrap();
+        let deleted: Vec<(String, String)> = stmt
+            .query_map([report.changeset_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let paths: Vec<&str> = deleted.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/etc/other.conf", "/usr/bin/app"]);
+        let cas = conary::filesystem::CasStore::new(&objects_dir).unwrap();
+        assert_eq!(cas.retrieve(&deleted[1].1).unwrap(), b"v1");
+    }
 
-        // Write RPM magic bytes
-        std::fs::write(path, [0xED, 0xAB, 0xEE, 0xDB, 0, 0, 0, 0]).unwrap();
+    #[test]
+    fn test_upgrade_keeps_install_reason() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let v1 = build_arch_fixture(
+            temp.path(),
+            "libfoo",
+            "1.0-1",
+            &[("usr/lib/libfoo.so", b"v1")],
+        );
+        install_package_from_file(
+            &v1,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Dependency,
+        )
+        .unwrap();
+        assert_eq!(
+            conary::resolver::find_unneeded_dependencies(&conn)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let old = conary::db::models::Trove::find_by_name(&conn, "libfoo")
+            .unwrap()
+            .remove(0);
+        let v2 = build_arch_fixture(
+            temp.path(),
+            "libfoo",
+            "1.1-1",
+            &[("usr/lib/libfoo.so", b"v2")],
+        );
+        install_package_from_file(
+            &v2,
+            &mut conn,
+            &root,
+            Some(&old),
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        let upgraded = conary::db::models::Trove::find_by_name(&conn, "libfoo")
+            .unwrap()
+            .remove(0);
+        assert_eq!(upgraded.version, "1.1-1");
+        assert_eq!(upgraded.install_reason, InstallReason::Dependency);
+
+        conary::db::models::Trove::set_install_reason(
+            &conn,
+            upgraded.id.unwrap(),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        assert!(
+            conary::resolver::find_unneeded_dependencies(&conn)
+                .unwrap()
+                .is_empty()
+        );
+    }
 
-        let format = detect_package_format(path).unwrap();
-        assert_eq!(format, PackageFormatType::Rpm);
+    #[test]
+    fn test_changeset_size_deltas() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let v1 = build_arch_fixture(
+            temp.path(),
+            "sized",
+            "1.0-1",
+            &[
+                ("usr/share/sized/a", b"0123456789"),
+                ("usr/share/sized/b", b"01234"),
+            ],
+        );
+        let installed = install_package_from_file(
+            &v1,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        let changeset = conary::db::models::Changeset::find_by_id(&conn, installed)
+            .unwrap()
+            .unwrap();
+        assert_eq!(changeset.downloaded_bytes, Some(0));
+        assert_eq!(changeset.installed_size_delta, Some(15));
+
+        // Replacing both files with a single smaller one shrinks the install
+        let old = conary::db::models::Trove::find_by_name(&conn, "sized")
+            .unwrap()
+            .remove(0);
+        let v2 = build_arch_fixture(
+            temp.path(),
+            "sized",
+            "1.1-1",
+            &[("usr/share/sized/a", b"0123")],
+        );
+        let upgraded = install_package_from_file(
+            &v2,
+            &mut conn,
+            &root,
+            Some(&old),
+            &objects_dir,
+            Some(&conary::scriptlet::DirectRunner),
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        conary::db::models::Changeset::add_sizes(&conn, upgraded, 2048, 0).unwrap();
+        let changeset = conary::db::models::Changeset::find_by_id(&conn, upgraded)
+            .unwrap()
+            .unwrap();
+        assert_eq!(changeset.downloaded_bytes, Some(2048));
+        assert_eq!(changeset.installed_size_delta, Some(-11));
+
+        assert_eq!(format_size(changeset.downloaded_bytes, false), "2.0 KB");
+        assert_eq!(format_size(changeset.installed_size_delta, true), "-11 B");
+        assert_eq!(format_size(None, true), "n/a");
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_detect_format_from_deb_magic_bytes() {
-        let temp_file = tempfile::NamedTempFile::with_suffix(".unknown").unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_non_utf8_path_under_symlinked_root() {
+        use conary::db::models::{FileEntry, Trove};
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let db_path = db_path.to_str().unwrap();
+        conary::db::init(db_path).unwrap();
+        let mut conn = conary::db::open(db_path).unwrap();
+
+        // roots/current -> roots/v42
+        let real_root = temp.path().join("roots/v42");
+        std::fs::create_dir_all(&real_root).unwrap();
+        std::os::unix::fs::symlink(&real_root, temp.path().join("roots/current")).unwrap();
+        let layout = conary::paths::Layout::load(&conn, db_path)
+            .unwrap()
+            .with_root(temp.path().join("roots/current"))
+            .unwrap();
+
+        // An Arch package shipping a Latin-1 file name
+        let raw_name = OsStr::from_bytes(b"usr/share/caf\xe9.txt");
+        let pkg_path = temp.path().join("latin-1.0-1-x86_64.pkg.tar.zst");
+        let encoder = zstd::Encoder::new(File::create(&pkg_path).unwrap(), 3).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+        let entries: [(&OsStr, &[u8]); 2] = [
+            (
+                OsStr::new(".PKGINFO"),
+                b"pkgname = latin\npkgver = 1.0-1\narch = x86_64\n",
+            ),
+            (raw_name, b"bonjour\n"),
+        ];
+        for (path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, content).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        install_package_from_file(
+            &pkg_path,
+            &mut conn,
+            layout.install_root(),
+            None,
+            layout.objects_dir(),
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        // The database keeps the encoded name; the file lands under the real root
+        let trove = Trove::find_by_name(&conn, "latin").unwrap().remove(0);
+        let files = FileEntry::find_by_trove(&conn, trove.id.unwrap()).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "/usr/share/caf%E9.txt");
+        let on_disk = real_root.join(raw_name);
+        assert_eq!(std::fs::read(&on_disk).unwrap(), b"bonjour\n");
+
+        // Verify and removal resolve the same file through the symlinked root
+        let deployer =
+            conary::filesystem::FileDeployer::new(layout.objects_dir(), layout.install_root())
+                .unwrap();
+        assert!(deployer.verify_entry(&files[0]).unwrap());
+        deployer.remove_file(&files[0].path).unwrap();
+        assert!(!on_disk.exists());
+    }
 
-        // Write DEB magic bytes (ar archive)
-        std::fs::write(path, b"!<arch>\n").unwrap();
+    #[test]
+    fn test_upgrade_records_replaced_trove() {
+        use conary::query::PackageAction;
+
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let pkg = build_arch_fixture(temp.path(), "hello", "1.0-1", &[("usr/bin/hello", b"v1")]);
+        let install = install_package_from_file(
+            &pkg,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        let old = conary::db::models::Trove::find_by_name(&conn, "hello")
+            .unwrap()
+            .remove(0);
+        let pkg = build_arch_fixture(temp.path(), "hello", "1.1-1", &[("usr/bin/hello", b"v2")]);
+        let upgrade = install_package_from_file(
+            &pkg,
+            &mut conn,
+            &root,
+            Some(&old),
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        let report = conary::query::changeset_report(&conn, upgrade).unwrap();
+        let actions: Vec<_> = report
+            .troves
+            .iter()
+            .map(|t| (t.version.as_str(), t.action))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("1.0-1", PackageAction::Replaced),
+                ("1.1-1", PackageAction::Installed)
+            ]
+        );
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].action, "modify");
+
+        let events = conary::query::package_changesets(&conn, "hello").unwrap();
+        let ids: Vec<_> = events.iter().map(|e| e.changeset.id.unwrap()).collect();
+        assert_eq!(ids, [install, upgrade, upgrade]);
+    }
 
-        let format = detect_package_format(path).unwrap();
-        assert_eq!(format, PackageFormatType::Deb);
+    #[test]
+    fn test_installed_provides_satisfy_dependencies() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let pkg = build_arch_fixture_with(
+            temp.path(),
+            "bash",
+            "5.2-1",
+            "provides = sh\nprovides = libreadline.so=8-64\n",
+            &[("usr/bin/bash", b"shell")],
+        );
+        install_package_from_file(
+            &pkg,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        let trove = conary::db::models::Trove::find_by_name(&conn, "bash")
+            .unwrap()
+            .remove(0);
+        let provides =
+            conary::db::models::ProvideEntry::find_by_trove(&conn, trove.id.unwrap()).unwrap();
+        let provides: Vec<_> = provides
+            .iter()
+            .map(|p| (p.name.as_str(), p.version.as_deref()))
+            .collect();
+        assert_eq!(provides, [("sh", None), ("libreadline.so", Some("8-64"))]);
+
+        // Nothing left to fetch for a dependency on the virtual name
+        let resolved =
+            repository::resolve_dependencies_transitive(&conn, &["sh".to_string()], 5).unwrap();
+        assert!(resolved.is_empty());
     }
 
     #[test]
-    fn test_detect_format_unknown() {
-        let temp_file = tempfile::NamedTempFile::with_suffix(".unknown").unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_validate_config() {
+        assert!(validate_config("max_parallel_downloads", "2").is_ok());
+        assert!(validate_config("max_extraction_buffer_bytes", "1M").is_ok());
+        assert!(validate_config("scriptlet_sandbox", "true").is_ok());
+
+        assert!(validate_config("max_parallel_downloads", "0").is_err());
+        assert!(validate_config("scriptlet_sandbox", "yes").is_err());
+        assert!(validate_config("objects_path", "/srv/objects").is_err());
+        assert!(validate_config("no_such_setting", "1").is_err());
+    }
 
-        // Write random bytes that don't match any format
-        std::fs::write(path, [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]).unwrap();
+    #[test]
+    fn test_package_providing_and_conflicting_is_refused() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let gnu = build_arch_fixture_with(
+            temp.path(),
+            "gnu-netcat",
+            "0.7.1-9",
+            "provides = netcat\n",
+            &[("usr/bin/netcat-gnu", b"gnu")],
+        );
+        let openbsd = build_arch_fixture_with(
+            temp.path(),
+            "openbsd-netcat",
+            "1.226_1-1",
+            "provides = netcat\nconflict = netcat\nreplaces = gnu-netcat\n",
+            &[("usr/bin/netcat-openbsd", b"openbsd")],
+        );
+        install_package_from_file(
+            &gnu,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        // The conflict is matched through gnu-netcat's provide, not its own
+        let err = install_package_from_file(
+            &openbsd,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("openbsd-netcat") && err.contains("gnu-netcat"),
+            "{}",
+            err
+        );
+        assert!(
+            conary::db::models::Trove::find_by_name(&conn, "openbsd-netcat")
+                .unwrap()
+                .is_empty()
+        );
+
+        // Replacing the installed trove is allowed, as update does for `replaces`
+        let old = conary::db::models::Trove::find_by_name(&conn, "gnu-netcat")
+            .unwrap()
+            .remove(0);
+        let changeset_id = install_package_from_file(
+            &openbsd,
+            &mut conn,
+            &root,
+            Some(&old),
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+        let changeset = conary::db::models::Changeset::find_by_id(&conn, changeset_id)
+            .unwrap()
+            .unwrap();
+        assert!(
+            changeset.description.starts_with("Replace gnu-netcat"),
+            "{}",
+            changeset.description
+        );
+        assert!(
+            conary::db::models::Trove::find_by_name(&conn, "gnu-netcat")
+                .unwrap()
+                .is_empty()
+        );
+
+        let trove = conary::db::models::Trove::find_by_name(&conn, "openbsd-netcat")
+            .unwrap()
+            .remove(0);
+        let relations =
+            conary::db::models::RelationEntry::find_by_trove(&conn, trove.id.unwrap()).unwrap();
+        let relations: Vec<_> = relations
+            .iter()
+            .map(|r| (r.kind.as_str(), r.name.as_str()))
+            .collect();
+        assert_eq!(
+            relations,
+            [("conflicts", "netcat"), ("replaces", "gnu-netcat")]
+        );
+
+        // Its stored conflict now keeps another netcat provider out
+        let err = install_package_from_file(
+            &gnu,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("Installed package openbsd-netcat"), "{}", err);
+    }
 
-        let result = detect_package_format(path);
-        assert!(result.is_err());
+    #[test]
+    fn test_verify_against_package_file() {
+        let temp = TempDir::new().unwrap();
+        let db_path = temp.path().join("conary.db");
+        let objects_dir = temp.path().join("objects");
+        let root = temp.path().join("root");
+        conary::db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = conary::db::open(db_path.to_str().unwrap()).unwrap();
+
+        let pkg = build_arch_fixture(
+            temp.path(),
+            "audit",
+            "1.0-1",
+            &[
+                ("usr/bin/audit", b"binary"),
+                ("usr/share/audit/a", b"a"),
+                ("usr/share/audit/b", b"b"),
+            ],
+        );
+        install_package_from_file(
+            &pkg,
+            &mut conn,
+            &root,
+            None,
+            &objects_dir,
+            None,
+            InstallReason::Explicit,
+        )
+        .unwrap();
+
+        let package = conary::packages::open_package(&pkg).unwrap();
+        let trove = conary::db::models::Trove::find_by_name(&conn, "audit")
+            .unwrap()
+            .remove(0);
+        let deployer = conary::filesystem::FileDeployer::new(&objects_dir, &root).unwrap();
+        let statuses = |conn: &rusqlite::Connection| -> Vec<(String, VerifyStatus)> {
+            verify_against_package(conn, &deployer, &trove, package.as_ref())
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.path, r.status))
+                .collect()
+        };
+        assert!(
+            statuses(&conn)
+                .iter()
+                .all(|(_, status)| *status == VerifyStatus::Ok)
+        );
+
+        // Content drift on disk, a dropped row and a rewritten row
+        std::fs::write(root.join("usr/share/audit/a"), b"tampered").unwrap();
+        conn.execute("DELETE FROM files WHERE path = '/usr/share/audit/b'", [])
+            .unwrap();
+        conn.execute(
+            "UPDATE files SET sha256_hash = 'bogus' WHERE path = '/usr/bin/audit'",
+            [],
+        )
+        .unwrap();
+
+        assert_eq!(
+            statuses(&conn),
+            vec![
+                ("/usr/bin/audit".to_string(), VerifyStatus::DbMismatch),
+                ("/usr/share/audit/a".to_string(), VerifyStatus::Modified),
+                ("/usr/share/audit/b".to_string(), VerifyStatus::Untracked),
+            ]
+        );
     }
 
     #[test]
-    fn test_package_format_type_equality() {
-        assert_eq!(PackageFormatType::Rpm, PackageFormatType::Rpm);
-        assert_eq!(PackageFormatType::Deb, PackageFormatType::Deb);
-        assert_eq!(PackageFormatType::Arch, PackageFormatType::Arch);
-        assert_ne!(PackageFormatType::Rpm, PackageFormatType::Deb);
+    fn test_porcelain_lines() {
+        let result = |path: &str, status: VerifyStatus, details: &[&str]| VerifyResult {
+            path: path.to_string(),
+            package: "tool".to_string(),
+            status,
+            details: details.iter().map(|d| d.to_string()).collect(),
+        };
+        let lines: Vec<String> = [
+            result("/usr/bin/tool", VerifyStatus::Ok, &[]),
+            result("/etc/tool.conf", VerifyStatus::Config, &[]),
+            result("/usr/bin/back\\slash", VerifyStatus::Modified, &[]),
+            result("/usr/bin/tab\there", VerifyStatus::Missing, &[]),
+            result(
+                "/usr/lib/tool.so",
+                VerifyStatus::Attrs,
+                &[
+                    "mode 0600 (expected 0644)",
+                    "owner 1000 (expected root)",
+                    "group 10 (expected root)",
+                ],
+            ),
+            result(
+                "/usr/bin/ping",
+                VerifyStatus::Attrs,
+                &["xattr security.capability missing"],
+            ),
+            result("/usr/share/doc/tool", VerifyStatus::Excluded, &[]),
+            result("/usr/bin/extra", VerifyStatus::Untracked, &[]),
+            result("/usr/bin/gone", VerifyStatus::NotInPackage, &[]),
+            result("/usr/bin/tool2", VerifyStatus::DbMismatch, &["hash"]),
+        ]
+        .iter()
+        .flat_map(porcelain_lines)
+        .collect();
+
+        // Porcelain v1 is frozen: this must never change
+        assert_eq!(
+            lines,
+            vec![
+                "C\t/etc/tool.conf\ttool\t",
+                "M\t/usr/bin/back\\\\slash\ttool\t",
+                "D\t/usr/bin/tab\\there\ttool\t",
+                "P\t/usr/lib/tool.so\ttool\tmode 0600 (expected 0644)",
+                "O\t/usr/lib/tool.so\ttool\towner 1000 (expected root)",
+                "O\t/usr/lib/tool.so\ttool\tgroup 10 (expected root)",
+                "A\t/usr/bin/ping\ttool\txattr security.capability missing",
+                "X\t/usr/share/doc/tool\ttool\t",
+                "U\t/usr/bin/extra\ttool\t",
+                "N\t/usr/bin/gone\ttool\t",
+                "H\t/usr/bin/tool2\ttool\thash",
+            ]
+        );
     }
 }
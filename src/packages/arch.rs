@@ -6,7 +6,12 @@
 
 use crate::db::models::{Trove, TroveType};
 use crate::error::{Error, Result};
-use crate::packages::traits::{Dependency, DependencyType, ExtractedFile, PackageFile, PackageFormat};
+use crate::packages::PackageFormatType;
+use crate::packages::traits::{
+    Dependency, DependencyType, FileStream, PackageFile, PackageFormat, Provide, Scriptlet,
+    ScriptletPhase,
+};
+use crate::scriptlet::shell_function_defined;
 use flate2::read::GzDecoder;
 use std::fs::File;
 use std::io::Read;
@@ -15,6 +20,9 @@ use tar::Archive;
 use tracing::debug;
 use xz2::read::XzDecoder;
 
+/// Format named in this parser's errors
+const FORMAT: PackageFormatType = PackageFormatType::Arch;
+
 /// Arch Linux package representation
 pub struct ArchPackage {
     package_path: PathBuf,
@@ -24,6 +32,10 @@ pub struct ArchPackage {
     description: Option<String>,
     files: Vec<PackageFile>,
     dependencies: Vec<Dependency>,
+    provides: Vec<Provide>,
+    conflicts: Vec<Dependency>,
+    replaces: Vec<Dependency>,
+    scriptlets: Vec<Scriptlet>,
     // Additional Arch-specific metadata
     url: Option<String>,
     licenses: Vec<String>,
@@ -42,7 +54,7 @@ impl ArchPackage {
         } else if path.ends_with(".pkg.tar.gz") {
             Ok(CompressionFormat::Gzip)
         } else {
-            Err(Error::InitError(format!(
+            Err(FORMAT.parse_error(format!(
                 "Unsupported Arch package format: {}. Expected .pkg.tar.zst, .pkg.tar.xz, or .pkg.tar.gz",
                 path
             )))
@@ -52,14 +64,15 @@ impl ArchPackage {
     /// Open and decompress the package archive
     fn open_archive(path: &str) -> Result<Archive<Box<dyn Read>>> {
         let file = File::open(path)
-            .map_err(|e| Error::InitError(format!("Failed to open package file: {}", e)))?;
+            .map_err(|e| Error::IoError(format!("Failed to open package file: {}", e)))?;
 
         let compression = Self::detect_compression(path)?;
 
         let reader: Box<dyn Read> = match compression {
             CompressionFormat::Zstd => {
-                let decoder = zstd::Decoder::new(file)
-                    .map_err(|e| Error::InitError(format!("Failed to create zstd decoder: {}", e)))?;
+                let decoder = zstd::Decoder::new(file).map_err(|e| {
+                    FORMAT.parse_error(format!("Failed to create zstd decoder: {}", e))
+                })?;
                 Box::new(decoder)
             }
             CompressionFormat::Xz => {
@@ -106,6 +119,12 @@ impl ArchPackage {
                     "depend" => info.dependencies.push(value.to_string()),
                     "optdepend" => info.optional_deps.push(value.to_string()),
                     "makedepend" => info.make_deps.push(value.to_string()),
+                    "provides" => info.provides.push(value.to_string()),
+                    "conflict" | "conflicts" => info.conflicts.push(value.to_string()),
+                    "replaces" => info.replaces.push(value.to_string()),
+                    "backup" => info
+                        .backup
+                        .push(format!("/{}", value.trim_start_matches('/'))),
                     _ => {} // Ignore unknown keys
                 }
             }
@@ -119,48 +138,89 @@ impl ArchPackage {
         let mut archive = Self::open_archive(path)?;
         let mut files = Vec::new();
 
-        for entry in archive.entries()
-            .map_err(|e| Error::InitError(format!("Failed to read archive entries: {}", e)))?
+        for entry in archive
+            .entries()
+            .map_err(|e| FORMAT.parse_error(format!("Failed to read archive entries: {}", e)))?
         {
-            let entry = entry
-                .map_err(|e| Error::InitError(format!("Failed to read archive entry: {}", e)))?;
+            let mut entry = entry
+                .map_err(|e| FORMAT.parse_error(format!("Failed to read archive entry: {}", e)))?;
 
-            let entry_path = entry.path()
-                .map_err(|e| Error::InitError(format!("Failed to get entry path: {}", e)))?
-                .to_string_lossy()
-                .to_string();
+            let entry_path = entry
+                .path()
+                .map_err(|e| FORMAT.parse_error(format!("Failed to get entry path: {}", e)))?;
+            let entry_path = crate::paths::encode_path(&entry_path);
 
             // Skip .PKGINFO, .MTREE, .BUILDINFO, and .INSTALL files
             if entry_path == ".PKGINFO"
                 || entry_path == ".MTREE"
                 || entry_path == ".BUILDINFO"
-                || entry_path == ".INSTALL" {
+                || entry_path == ".INSTALL"
+            {
                 continue;
             }
 
-            // Skip directories
-            if entry.header().entry_type().is_dir() {
+            // The archive root is not owned by the package
+            let is_dir = entry.header().entry_type().is_dir();
+            let entry_path = entry_path.trim_end_matches('/');
+            if is_dir && entry_path.trim_start_matches('.').is_empty() {
                 continue;
             }
 
-            let size = entry.header().size()
-                .map_err(|e| Error::InitError(format!("Failed to get file size: {}", e)))?;
+            let size = entry
+                .header()
+                .size()
+                .map_err(|e| FORMAT.parse_error(format!("Failed to get file size: {}", e)))?;
 
-            let mode = entry.header().mode()
-                .map_err(|e| Error::InitError(format!("Failed to get file mode: {}", e)))?;
+            let mode = crate::packages::tar_mode(FORMAT, entry.header())?;
+
+            let (owner, group) = crate::packages::tar_ownership(entry.header());
+            let xattrs = crate::packages::tar_xattrs(FORMAT, &mut entry)?;
 
             files.push(PackageFile {
                 path: format!("/{}", entry_path), // Ensure absolute path
-                size: size as i64,
-                mode: mode as i32,
+                size: if is_dir { 0 } else { size as i64 },
+                mode,
                 sha256: None, // We'll compute this during extraction if needed
+                owner,
+                group,
+                is_config: false,
+                is_dir,
+                xattrs,
             });
         }
 
         Ok(files)
     }
 
+    /// Build scriptlets from an .INSTALL file
+    ///
+    /// An .INSTALL file is a bash script defining hook functions. Each phase
+    /// carries the whole file; the function to call is picked at run time
+    /// since upgrades use pre_upgrade/post_upgrade instead of the install hooks.
+    fn parse_install_script(content: &str) -> Vec<Scriptlet> {
+        let phases: [(ScriptletPhase, &[&str]); 4] = [
+            (ScriptletPhase::PreInstall, &["pre_install", "pre_upgrade"]),
+            (
+                ScriptletPhase::PostInstall,
+                &["post_install", "post_upgrade"],
+            ),
+            (ScriptletPhase::PreRemove, &["pre_remove"]),
+            (ScriptletPhase::PostRemove, &["post_remove"]),
+        ];
+
+        phases
+            .into_iter()
+            .filter(|(_, functions)| functions.iter().any(|f| shell_function_defined(content, f)))
+            .map(|(phase, _)| Scriptlet {
+                phase,
+                interpreter: "/bin/bash".to_string(),
+                body: content.to_string(),
+            })
+            .collect()
+    }
+
     /// Parse dependencies from strings like "glibc>=2.34" or "package: description"
+    #[allow(clippy::unnecessary_filter_map, clippy::manual_pattern_char_comparison)]
     fn parse_dependencies(deps: &[String], dep_type: DependencyType) -> Vec<Dependency> {
         deps.iter()
             .filter_map(|dep| {
@@ -176,12 +236,13 @@ impl ArchPackage {
                 };
 
                 // Parse version constraint (e.g., "glibc>=2.34")
-                let (pkg_name, version) = if let Some(pos) = name.find(|c| c == '>' || c == '<' || c == '=') {
-                    let (n, v) = name.split_at(pos);
-                    (n.trim(), Some(v.trim().to_string()))
-                } else {
-                    (name, None)
-                };
+                let (pkg_name, version) =
+                    if let Some(pos) = name.find(|c| c == '>' || c == '<' || c == '=') {
+                        let (n, v) = name.split_at(pos);
+                        (n.trim(), Some(v.trim().to_string()))
+                    } else {
+                        (name, None)
+                    };
 
                 Some(Dependency {
                     name: pkg_name.to_string(),
@@ -192,6 +253,23 @@ impl ArchPackage {
             })
             .collect()
     }
+
+    /// Parse provides like "sh" or "libreadline.so=8-64"
+    fn parse_provides(provides: &[String]) -> Vec<Provide> {
+        provides
+            .iter()
+            .map(|provide| match provide.split_once('=') {
+                Some((name, version)) => Provide {
+                    name: name.trim().to_string(),
+                    version: Some(version.trim().to_string()),
+                },
+                None => Provide {
+                    name: provide.clone(),
+                    version: None,
+                },
+            })
+            .collect()
+    }
 }
 
 /// Package compression format
@@ -217,56 +295,94 @@ struct PkgInfo {
     dependencies: Vec<String>,
     optional_deps: Vec<String>,
     make_deps: Vec<String>,
+    provides: Vec<String>,
+    conflicts: Vec<String>,
+    replaces: Vec<String>,
+    /// Config files (absolute paths) kept across upgrades
+    backup: Vec<String>,
 }
 
 impl PackageFormat for ArchPackage {
     fn parse(path: &str) -> Result<Self> {
         debug!("Parsing Arch package: {}", path);
 
-        // Open archive and find .PKGINFO
+        // Open archive and find .PKGINFO and .INSTALL
         let mut archive = Self::open_archive(path)?;
         let mut pkginfo_content = None;
+        let mut install_content = None;
 
-        for entry in archive.entries()
-            .map_err(|e| Error::InitError(format!("Failed to read archive: {}", e)))?
+        for entry in archive
+            .entries()
+            .map_err(|e| FORMAT.parse_error(format!("Failed to read archive: {}", e)))?
         {
-            let mut entry = entry
-                .map_err(|e| Error::InitError(format!("Failed to read entry: {}", e)))?;
+            let mut entry =
+                entry.map_err(|e| FORMAT.parse_error(format!("Failed to read entry: {}", e)))?;
 
-            let entry_path = entry.path()
-                .map_err(|e| Error::InitError(format!("Failed to get entry path: {}", e)))?
-                .to_string_lossy()
-                .to_string();
+            let entry_path = entry
+                .path()
+                .map_err(|e| FORMAT.parse_error(format!("Failed to get entry path: {}", e)))?;
+            let entry_path = crate::paths::encode_path(&entry_path);
 
             if entry_path == ".PKGINFO" {
                 let mut content = String::new();
-                entry.read_to_string(&mut content)
-                    .map_err(|e| Error::InitError(format!("Failed to read .PKGINFO: {}", e)))?;
+                entry
+                    .read_to_string(&mut content)
+                    .map_err(|e| FORMAT.parse_error(format!("Failed to read .PKGINFO: {}", e)))?;
                 pkginfo_content = Some(content);
+            } else if entry_path == ".INSTALL" {
+                let mut content = String::new();
+                entry
+                    .read_to_string(&mut content)
+                    .map_err(|e| FORMAT.parse_error(format!("Failed to read .INSTALL: {}", e)))?;
+                install_content = Some(content);
+            }
+
+            if pkginfo_content.is_some() && install_content.is_some() {
                 break;
             }
         }
 
         let pkginfo_content = pkginfo_content
-            .ok_or_else(|| Error::InitError("No .PKGINFO file found in package".to_string()))?;
+            .ok_or_else(|| FORMAT.parse_error("No .PKGINFO file found in package".to_string()))?;
 
         // Parse .PKGINFO
         let pkginfo = Self::parse_pkginfo(&pkginfo_content)?;
 
-        let name = pkginfo.name
-            .ok_or_else(|| Error::InitError("Package name not found in .PKGINFO".to_string()))?;
+        let name = pkginfo
+            .name
+            .ok_or_else(|| FORMAT.parse_error("Package name not found in .PKGINFO".to_string()))?;
 
-        let version = pkginfo.version
-            .ok_or_else(|| Error::InitError("Package version not found in .PKGINFO".to_string()))?;
+        let version = pkginfo.version.ok_or_else(|| {
+            FORMAT.parse_error("Package version not found in .PKGINFO".to_string())
+        })?;
 
-        // Extract file list
-        let files = Self::extract_file_list(path)?;
+        // Extract file list, flagging backup entries as config files
+        let mut files = Self::extract_file_list(path)?;
+        for file in &mut files {
+            file.is_config = pkginfo.backup.contains(&file.path);
+        }
+        let scriptlets = install_content
+            .as_deref()
+            .map(Self::parse_install_script)
+            .unwrap_or_default();
 
         // Parse dependencies
         let mut dependencies = Vec::new();
-        dependencies.extend(Self::parse_dependencies(&pkginfo.dependencies, DependencyType::Runtime));
-        dependencies.extend(Self::parse_dependencies(&pkginfo.optional_deps, DependencyType::Optional));
-        dependencies.extend(Self::parse_dependencies(&pkginfo.make_deps, DependencyType::Build));
+        dependencies.extend(Self::parse_dependencies(
+            &pkginfo.dependencies,
+            DependencyType::Runtime,
+        ));
+        dependencies.extend(Self::parse_dependencies(
+            &pkginfo.optional_deps,
+            DependencyType::Optional,
+        ));
+        dependencies.extend(Self::parse_dependencies(
+            &pkginfo.make_deps,
+            DependencyType::Build,
+        ));
+        let provides = Self::parse_provides(&pkginfo.provides);
+        let conflicts = Self::parse_dependencies(&pkginfo.conflicts, DependencyType::Runtime);
+        let replaces = Self::parse_dependencies(&pkginfo.replaces, DependencyType::Runtime);
 
         debug!(
             "Parsed Arch package: {} version {} ({} files, {} dependencies)",
@@ -284,6 +400,10 @@ impl PackageFormat for ArchPackage {
             description: pkginfo.description,
             files,
             dependencies,
+            provides,
+            conflicts,
+            replaces,
+            scriptlets,
             url: pkginfo.url,
             licenses: pkginfo.licenses,
             groups: pkginfo.groups,
@@ -316,64 +436,37 @@ impl PackageFormat for ArchPackage {
         &self.dependencies
     }
 
-    fn extract_file_contents(&self) -> Result<Vec<ExtractedFile>> {
-        debug!("Extracting file contents from Arch package: {:?}", self.package_path);
-
-        let mut archive = Self::open_archive(self.package_path.to_str().unwrap())?;
-        let mut extracted_files = Vec::new();
-
-        for entry in archive.entries()
-            .map_err(|e| Error::InitError(format!("Failed to read archive: {}", e)))?
-        {
-            let mut entry = entry
-                .map_err(|e| Error::InitError(format!("Failed to read entry: {}", e)))?;
-
-            let entry_path = entry.path()
-                .map_err(|e| Error::InitError(format!("Failed to get entry path: {}", e)))?
-                .to_string_lossy()
-                .to_string();
-
-            // Skip metadata files
-            if entry_path == ".PKGINFO"
-                || entry_path == ".MTREE"
-                || entry_path == ".BUILDINFO"
-                || entry_path == ".INSTALL" {
-                continue;
-            }
+    fn provides(&self) -> &[Provide] {
+        &self.provides
+    }
 
-            // Skip directories
-            if entry.header().entry_type().is_dir() {
-                continue;
-            }
+    fn conflicts(&self) -> &[Dependency] {
+        &self.conflicts
+    }
 
-            let size = entry.header().size()
-                .map_err(|e| Error::InitError(format!("Failed to get file size: {}", e)))?;
+    fn replaces(&self) -> &[Dependency] {
+        &self.replaces
+    }
 
-            let mode = entry.header().mode()
-                .map_err(|e| Error::InitError(format!("Failed to get file mode: {}", e)))?;
+    fn scriptlets(&self) -> &[Scriptlet] {
+        &self.scriptlets
+    }
 
-            // Read file content
-            let mut content = Vec::new();
-            entry.read_to_end(&mut content)
-                .map_err(|e| Error::InitError(format!("Failed to read file content: {}", e)))?;
+    fn extract_files(&self, sink: &mut dyn FnMut(FileStream) -> Result<()>) -> Result<()> {
+        debug!(
+            "Extracting file contents from Arch package: {:?}",
+            self.package_path
+        );
 
-            // Compute SHA-256
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.update(&content);
-            let hash = format!("{:x}", hasher.finalize());
+        let mut archive = Self::open_archive(self.package_path.to_str().unwrap())?;
 
-            extracted_files.push(ExtractedFile {
-                path: format!("/{}", entry_path), // Ensure absolute path
-                content,
-                size: size as i64,
-                mode: mode as i32,
-                sha256: Some(hash),
-            });
-        }
+        // Skip metadata files
+        let skip = [".PKGINFO", ".MTREE", ".BUILDINFO", ".INSTALL"];
+        let count =
+            crate::packages::stream_tar_payload(FORMAT, &mut archive, &self.files, &skip, sink)?;
 
-        debug!("Extracted {} files from Arch package", extracted_files.len());
-        Ok(extracted_files)
+        debug!("Extracted {} files from Arch package", count);
+        Ok(())
     }
 
     fn to_trove(&self) -> Trove {
@@ -386,6 +479,7 @@ impl PackageFormat for ArchPackage {
         trove.architecture = self.architecture().map(|s| s.to_string());
         trove.description = self.description().map(|s| s.to_string());
 
+        trove.format = Some(FORMAT);
         trove
     }
 }
@@ -448,7 +542,13 @@ mod tests {
             ArchPackage::detect_compression("test.pkg.tar.gz"),
             Ok(CompressionFormat::Gzip)
         ));
-        assert!(ArchPackage::detect_compression("test.rpm").is_err());
+        assert!(matches!(
+            ArchPackage::detect_compression("test.rpm"),
+            Err(Error::PackageParse {
+                format: PackageFormatType::Arch,
+                ..
+            })
+        ));
     }
 
     #[test]
@@ -466,6 +566,10 @@ depend = glibc>=2.34
 depend = zlib
 optdepend = python: for scripts
 makedepend = gcc
+backup = etc/test.conf
+provides = test-api=1
+conflict = test-package-git
+replaces = old-test<1.0
 "#;
 
         let info = ArchPackage::parse_pkginfo(content).unwrap();
@@ -477,14 +581,18 @@ makedepend = gcc
         assert_eq!(info.dependencies.len(), 2);
         assert_eq!(info.optional_deps.len(), 1);
         assert_eq!(info.make_deps.len(), 1);
+        assert_eq!(info.backup, vec!["/etc/test.conf".to_string()]);
+        assert_eq!(info.provides, vec!["test-api=1".to_string()]);
+        assert_eq!(info.conflicts, vec!["test-package-git".to_string()]);
+
+        let replaces = ArchPackage::parse_dependencies(&info.replaces, DependencyType::Runtime);
+        assert_eq!(replaces[0].name, "old-test");
+        assert_eq!(replaces[0].version.as_deref(), Some("<1.0"));
     }
 
     #[test]
     fn test_dependency_parsing() {
-        let deps = vec![
-            "glibc>=2.34".to_string(),
-            "zlib".to_string(),
-        ];
+        let deps = vec!["glibc>=2.34".to_string(), "zlib".to_string()];
 
         let parsed = ArchPackage::parse_dependencies(&deps, DependencyType::Runtime);
         assert_eq!(parsed.len(), 2);
@@ -494,6 +602,18 @@ makedepend = gcc
         assert_eq!(parsed[1].version, None);
     }
 
+    #[test]
+    fn test_provides_parsing() {
+        let provides = vec!["sh".to_string(), "libreadline.so=8-64".to_string()];
+
+        let parsed = ArchPackage::parse_provides(&provides);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].name, "sh");
+        assert_eq!(parsed[0].version, None);
+        assert_eq!(parsed[1].name, "libreadline.so");
+        assert_eq!(parsed[1].version, Some("8-64".to_string()));
+    }
+
     #[test]
     fn test_optional_dependency_parsing() {
         let deps = vec![
@@ -504,8 +624,25 @@ makedepend = gcc
         let parsed = ArchPackage::parse_dependencies(&deps, DependencyType::Optional);
         assert_eq!(parsed.len(), 2);
         assert_eq!(parsed[0].name, "python");
-        assert_eq!(parsed[0].description, Some("for running scripts".to_string()));
+        assert_eq!(
+            parsed[0].description,
+            Some("for running scripts".to_string())
+        );
         assert_eq!(parsed[1].name, "ruby");
         assert_eq!(parsed[1].description, None);
     }
+
+    #[test]
+    fn test_install_script_parsing() {
+        let content = "post_install() {\n  useradd foo\n}\n\npost_upgrade() {\n  post_install\n}\n\npre_remove() {\n  userdel foo\n}\n";
+
+        let scriptlets = ArchPackage::parse_install_script(content);
+        let phases: Vec<_> = scriptlets.iter().map(|s| s.phase).collect();
+        assert_eq!(
+            phases,
+            vec![ScriptletPhase::PostInstall, ScriptletPhase::PreRemove]
+        );
+        assert_eq!(scriptlets[0].interpreter, "/bin/bash");
+        assert_eq!(scriptlets[0].body, content);
+    }
 }
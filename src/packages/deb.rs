@@ -6,7 +6,11 @@
 
 use crate::db::models::{Trove, TroveType};
 use crate::error::{Error, Result};
-use crate::packages::traits::{Dependency, DependencyType, ExtractedFile, PackageFile, PackageFormat};
+use crate::packages::PackageFormatType;
+use crate::packages::traits::{
+    Dependency, DependencyType, FileStream, PackageFile, PackageFormat, Provide, Scriptlet,
+    ScriptletPhase,
+};
 use flate2::read::GzDecoder;
 use std::fs::File;
 use std::io::Read;
@@ -15,6 +19,9 @@ use tar::Archive;
 use tracing::debug;
 use xz2::read::XzDecoder;
 
+/// Format named in this parser's errors
+const FORMAT: PackageFormatType = PackageFormatType::Deb;
+
 /// Debian package representation
 pub struct DebPackage {
     package_path: PathBuf,
@@ -24,6 +31,8 @@ pub struct DebPackage {
     description: Option<String>,
     files: Vec<PackageFile>,
     dependencies: Vec<Dependency>,
+    provides: Vec<Provide>,
+    scriptlets: Vec<Scriptlet>,
     // Additional Debian-specific metadata
     maintainer: Option<String>,
     section: Option<String>,
@@ -86,6 +95,7 @@ impl DebPackage {
             "Recommends" => info.recommends = Self::parse_dependency_list(value),
             "Suggests" => info.suggests = Self::parse_dependency_list(value),
             "Build-Depends" => info.build_depends = Self::parse_dependency_list(value),
+            "Provides" => info.provides = Self::parse_dependency_list(value),
             _ => {} // Ignore unknown fields
         }
     }
@@ -99,6 +109,7 @@ impl DebPackage {
     }
 
     /// Parse a single dependency string into name and version constraint
+    #[allow(clippy::collapsible_if)]
     fn parse_single_dependency(dep: &str) -> (String, Option<String>) {
         // Handle alternatives (foo | bar)
         let dep = dep.split('|').next().unwrap_or(dep).trim();
@@ -118,13 +129,13 @@ impl DebPackage {
     /// Extract file from AR archive by name
     fn extract_ar_file(path: &str, filename: &str) -> Result<Vec<u8>> {
         let file = File::open(path)
-            .map_err(|e| Error::InitError(format!("Failed to open DEB file: {}", e)))?;
+            .map_err(|e| Error::IoError(format!("Failed to open DEB file: {}", e)))?;
 
         let mut archive = ar::Archive::new(file);
 
         while let Some(entry) = archive.next_entry() {
-            let mut entry = entry
-                .map_err(|e| Error::InitError(format!("Failed to read AR entry: {}", e)))?;
+            let mut entry =
+                entry.map_err(|e| FORMAT.parse_error(format!("Failed to read AR entry: {}", e)))?;
 
             let entry_name = String::from_utf8_lossy(entry.header().identifier()).to_string();
 
@@ -132,21 +143,23 @@ impl DebPackage {
                 let mut content = Vec::new();
                 entry
                     .read_to_end(&mut content)
-                    .map_err(|e| Error::InitError(format!("Failed to read AR file: {}", e)))?;
+                    .map_err(|e| FORMAT.parse_error(format!("Failed to read AR file: {}", e)))?;
                 return Ok(content);
             }
         }
 
-        Err(Error::InitError(format!(
-            "File {} not found in DEB archive",
-            filename
-        )))
+        Err(FORMAT.parse_error(format!("File {} not found in DEB archive", filename)))
     }
 
     /// Decompress and extract control.tar.* to get control file
     fn extract_control_file(path: &str) -> Result<String> {
         // Try different compression formats
-        for ext in &["control.tar.gz", "control.tar.xz", "control.tar.zst", "control.tar"] {
+        for ext in &[
+            "control.tar.gz",
+            "control.tar.xz",
+            "control.tar.zst",
+            "control.tar",
+        ] {
             if let Ok(tar_data) = Self::extract_ar_file(path, ext) {
                 // Decompress based on extension
                 let reader: Box<dyn Read> = if ext.ends_with(".gz") {
@@ -154,10 +167,9 @@ impl DebPackage {
                 } else if ext.ends_with(".xz") {
                     Box::new(XzDecoder::new(&tar_data[..]))
                 } else if ext.ends_with(".zst") {
-                    Box::new(
-                        zstd::Decoder::new(&tar_data[..])
-                            .map_err(|e| Error::InitError(format!("Failed to create zstd decoder: {}", e)))?,
-                    )
+                    Box::new(zstd::Decoder::new(&tar_data[..]).map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to create zstd decoder: {}", e))
+                    })?)
                 } else {
                     Box::new(&tar_data[..])
                 };
@@ -165,31 +177,131 @@ impl DebPackage {
                 let mut archive = Archive::new(reader);
 
                 // Find control file in tar
-                for entry in archive.entries()
-                    .map_err(|e| Error::InitError(format!("Failed to read control.tar: {}", e)))?
+                for entry in archive
+                    .entries()
+                    .map_err(|e| FORMAT.parse_error(format!("Failed to read control.tar: {}", e)))?
                 {
                     let mut entry = entry
-                        .map_err(|e| Error::InitError(format!("Failed to read entry: {}", e)))?;
+                        .map_err(|e| FORMAT.parse_error(format!("Failed to read entry: {}", e)))?;
 
-                    let entry_path = entry.path()
-                        .map_err(|e| Error::InitError(format!("Failed to get entry path: {}", e)))?
-                        .to_string_lossy()
-                        .to_string();
+                    let entry_path = entry.path().map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to get entry path: {}", e))
+                    })?;
+                    let entry_path = crate::paths::encode_path(&entry_path);
 
                     if entry_path == "./control" || entry_path == "control" {
                         let mut content = String::new();
-                        entry
-                            .read_to_string(&mut content)
-                            .map_err(|e| Error::InitError(format!("Failed to read control file: {}", e)))?;
+                        entry.read_to_string(&mut content).map_err(|e| {
+                            FORMAT.parse_error(format!("Failed to read control file: {}", e))
+                        })?;
                         return Ok(content);
                     }
                 }
             }
         }
 
-        Err(Error::InitError(
-            "Could not find or extract control file from DEB package".to_string(),
-        ))
+        Err(FORMAT
+            .parse_error("Could not find or extract control file from DEB package".to_string()))
+    }
+
+    /// Extract maintainer scripts (preinst, postinst, prerm, postrm) and the
+    /// conffiles list from control.tar.*
+    fn extract_control_members(path: &str) -> Result<(Vec<Scriptlet>, Vec<String>)> {
+        for ext in &[
+            "control.tar.gz",
+            "control.tar.xz",
+            "control.tar.zst",
+            "control.tar",
+        ] {
+            if let Ok(tar_data) = Self::extract_ar_file(path, ext) {
+                // Decompress based on extension
+                let reader: Box<dyn Read> = if ext.ends_with(".gz") {
+                    Box::new(GzDecoder::new(&tar_data[..]))
+                } else if ext.ends_with(".xz") {
+                    Box::new(XzDecoder::new(&tar_data[..]))
+                } else if ext.ends_with(".zst") {
+                    Box::new(zstd::Decoder::new(&tar_data[..]).map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to create zstd decoder: {}", e))
+                    })?)
+                } else {
+                    Box::new(&tar_data[..])
+                };
+
+                let mut archive = Archive::new(reader);
+                let mut scriptlets = Vec::new();
+                let mut conffiles = Vec::new();
+
+                for entry in archive
+                    .entries()
+                    .map_err(|e| FORMAT.parse_error(format!("Failed to read control.tar: {}", e)))?
+                {
+                    let mut entry = entry
+                        .map_err(|e| FORMAT.parse_error(format!("Failed to read entry: {}", e)))?;
+
+                    let entry_path = entry.path().map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to get entry path: {}", e))
+                    })?;
+                    let entry_path = crate::paths::encode_path(&entry_path);
+
+                    let member = entry_path.trim_start_matches("./");
+                    if member == "conffiles" {
+                        let mut content = String::new();
+                        entry.read_to_string(&mut content).map_err(|e| {
+                            FORMAT.parse_error(format!("Failed to read conffiles: {}", e))
+                        })?;
+                        conffiles = Self::parse_conffiles(&content);
+                        continue;
+                    }
+
+                    let phase = match member {
+                        "preinst" => ScriptletPhase::PreInstall,
+                        "postinst" => ScriptletPhase::PostInstall,
+                        "prerm" => ScriptletPhase::PreRemove,
+                        "postrm" => ScriptletPhase::PostRemove,
+                        _ => continue,
+                    };
+
+                    let mut body = String::new();
+                    entry.read_to_string(&mut body).map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to read {}: {}", entry_path, e))
+                    })?;
+
+                    scriptlets.push(Scriptlet {
+                        phase,
+                        interpreter: Self::script_interpreter(&body),
+                        body,
+                    });
+                }
+
+                return Ok((scriptlets, conffiles));
+            }
+        }
+
+        Err(FORMAT
+            .parse_error("Could not find or extract control.tar from DEB package".to_string()))
+    }
+
+    /// Parse a conffiles list: one absolute path per line
+    ///
+    /// Lines carrying flags (such as `remove-on-upgrade /etc/foo`) describe
+    /// files that are no longer shipped, so they are skipped.
+    fn parse_conffiles(content: &str) -> Vec<String> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with('/'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Determine the interpreter of a maintainer script from its shebang line
+    fn script_interpreter(body: &str) -> String {
+        body.lines()
+            .next()
+            .and_then(|line| line.strip_prefix("#!"))
+            .map(|interpreter| interpreter.trim().to_string())
+            .filter(|interpreter| !interpreter.is_empty())
+            .unwrap_or_else(|| "/bin/sh".to_string())
     }
 
     /// Extract file list from data.tar.*
@@ -203,10 +315,9 @@ impl DebPackage {
                 } else if ext.ends_with(".xz") {
                     Box::new(XzDecoder::new(&tar_data[..]))
                 } else if ext.ends_with(".zst") {
-                    Box::new(
-                        zstd::Decoder::new(&tar_data[..])
-                            .map_err(|e| Error::InitError(format!("Failed to create zstd decoder: {}", e)))?,
-                    )
+                    Box::new(zstd::Decoder::new(&tar_data[..]).map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to create zstd decoder: {}", e))
+                    })?)
                 } else {
                     Box::new(&tar_data[..])
                 };
@@ -214,33 +325,44 @@ impl DebPackage {
                 let mut archive = Archive::new(reader);
                 let mut files = Vec::new();
 
-                for entry in archive.entries()
-                    .map_err(|e| Error::InitError(format!("Failed to read data.tar: {}", e)))?
+                for entry in archive
+                    .entries()
+                    .map_err(|e| FORMAT.parse_error(format!("Failed to read data.tar: {}", e)))?
                 {
-                    let entry = entry
-                        .map_err(|e| Error::InitError(format!("Failed to read entry: {}", e)))?;
+                    let mut entry = entry
+                        .map_err(|e| FORMAT.parse_error(format!("Failed to read entry: {}", e)))?;
 
-                    let entry_path = entry.path()
-                        .map_err(|e| Error::InitError(format!("Failed to get entry path: {}", e)))?
-                        .to_string_lossy()
-                        .to_string();
+                    let entry_path = entry.path().map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to get entry path: {}", e))
+                    })?;
+                    let entry_path = crate::paths::encode_path(&entry_path);
 
-                    // Skip directories
-                    if entry.header().entry_type().is_dir() {
+                    // The archive root is not owned by the package
+                    let is_dir = entry.header().entry_type().is_dir();
+                    let entry_path = entry_path.trim_end_matches('/');
+                    if is_dir && entry_path.trim_start_matches('.').is_empty() {
                         continue;
                     }
 
-                    let size = entry.header().size()
-                        .map_err(|e| Error::InitError(format!("Failed to get file size: {}", e)))?;
+                    let size = entry.header().size().map_err(|e| {
+                        FORMAT.parse_error(format!("Failed to get file size: {}", e))
+                    })?;
 
-                    let mode = entry.header().mode()
-                        .map_err(|e| Error::InitError(format!("Failed to get file mode: {}", e)))?;
+                    let mode = crate::packages::tar_mode(FORMAT, entry.header())?;
+
+                    let (owner, group) = crate::packages::tar_ownership(entry.header());
+                    let xattrs = crate::packages::tar_xattrs(FORMAT, &mut entry)?;
 
                     files.push(PackageFile {
                         path: format!("/{}", entry_path.trim_start_matches("./")),
-                        size: size as i64,
-                        mode: mode as i32,
+                        size: if is_dir { 0 } else { size as i64 },
+                        mode,
                         sha256: None,
+                        owner,
+                        group,
+                        is_config: false,
+                        is_dir,
+                        xattrs,
                     });
                 }
 
@@ -248,9 +370,7 @@ impl DebPackage {
             }
         }
 
-        Err(Error::InitError(
-            "Could not find or extract data.tar from DEB package".to_string(),
-        ))
+        Err(FORMAT.parse_error("Could not find or extract data.tar from DEB package".to_string()))
     }
 
     /// Convert dependency list to Dependency structs
@@ -267,6 +387,20 @@ impl DebPackage {
             })
             .collect()
     }
+
+    /// Convert Provides entries, e.g. "mail-transport-agent" or "libfoo-abi (= 2)"
+    fn convert_provides(provides: &[String]) -> Vec<Provide> {
+        provides
+            .iter()
+            .map(|provide| {
+                let (name, constraint) = Self::parse_single_dependency(provide);
+                let version = constraint
+                    .map(|c| c.trim_start_matches('=').trim().to_string())
+                    .filter(|v| !v.is_empty());
+                Provide { name, version }
+            })
+            .collect()
+    }
 }
 
 /// Parsed control file metadata
@@ -285,6 +419,7 @@ struct ControlInfo {
     recommends: Vec<String>,
     suggests: Vec<String>,
     build_depends: Vec<String>,
+    provides: Vec<String>,
 }
 
 impl PackageFormat for DebPackage {
@@ -295,23 +430,40 @@ impl PackageFormat for DebPackage {
         let control_content = Self::extract_control_file(path)?;
         let control = Self::parse_control(&control_content)?;
 
-        let name = control
-            .name
-            .ok_or_else(|| Error::InitError("Package name not found in control file".to_string()))?;
+        let name = control.name.ok_or_else(|| {
+            FORMAT.parse_error("Package name not found in control file".to_string())
+        })?;
 
-        let version = control
-            .version
-            .ok_or_else(|| Error::InitError("Package version not found in control file".to_string()))?;
+        let version = control.version.ok_or_else(|| {
+            FORMAT.parse_error("Package version not found in control file".to_string())
+        })?;
 
-        // Extract file list
-        let files = Self::extract_file_list(path)?;
+        // Extract file list, maintainer scripts and config files
+        let mut files = Self::extract_file_list(path)?;
+        let (scriptlets, conffiles) = Self::extract_control_members(path)?;
+        for file in &mut files {
+            file.is_config = conffiles.contains(&file.path);
+        }
 
         // Convert dependencies
         let mut dependencies = Vec::new();
-        dependencies.extend(Self::convert_dependencies(&control.dependencies, DependencyType::Runtime));
-        dependencies.extend(Self::convert_dependencies(&control.recommends, DependencyType::Optional));
-        dependencies.extend(Self::convert_dependencies(&control.suggests, DependencyType::Optional));
-        dependencies.extend(Self::convert_dependencies(&control.build_depends, DependencyType::Build));
+        dependencies.extend(Self::convert_dependencies(
+            &control.dependencies,
+            DependencyType::Runtime,
+        ));
+        dependencies.extend(Self::convert_dependencies(
+            &control.recommends,
+            DependencyType::Optional,
+        ));
+        dependencies.extend(Self::convert_dependencies(
+            &control.suggests,
+            DependencyType::Optional,
+        ));
+        dependencies.extend(Self::convert_dependencies(
+            &control.build_depends,
+            DependencyType::Build,
+        ));
+        let provides = Self::convert_provides(&control.provides);
 
         debug!(
             "Parsed DEB package: {} version {} ({} files, {} dependencies)",
@@ -329,6 +481,8 @@ impl PackageFormat for DebPackage {
             description: control.description,
             files,
             dependencies,
+            provides,
+            scriptlets,
             maintainer: control.maintainer,
             section: control.section,
             priority: control.priority,
@@ -361,80 +515,56 @@ impl PackageFormat for DebPackage {
         &self.dependencies
     }
 
-    fn extract_file_contents(&self) -> Result<Vec<ExtractedFile>> {
-        debug!("Extracting file contents from Debian package: {:?}", self.package_path);
-
-        // Try different compression formats
-        for ext in &["data.tar.gz", "data.tar.xz", "data.tar.zst", "data.tar"] {
-            if let Ok(tar_data) = Self::extract_ar_file(self.package_path.to_str().unwrap(), ext) {
-                // Decompress based on extension
-                let reader: Box<dyn Read> = if ext.ends_with(".gz") {
-                    Box::new(GzDecoder::new(&tar_data[..]))
-                } else if ext.ends_with(".xz") {
-                    Box::new(XzDecoder::new(&tar_data[..]))
-                } else if ext.ends_with(".zst") {
-                    Box::new(
-                        zstd::Decoder::new(&tar_data[..])
-                            .map_err(|e| Error::InitError(format!("Failed to create zstd decoder: {}", e)))?,
-                    )
-                } else {
-                    Box::new(&tar_data[..])
-                };
-
-                let mut archive = Archive::new(reader);
-                let mut extracted_files = Vec::new();
-
-                for entry in archive.entries()
-                    .map_err(|e| Error::InitError(format!("Failed to read data.tar: {}", e)))?
-                {
-                    let mut entry = entry
-                        .map_err(|e| Error::InitError(format!("Failed to read entry: {}", e)))?;
-
-                    let entry_path = entry.path()
-                        .map_err(|e| Error::InitError(format!("Failed to get entry path: {}", e)))?
-                        .to_string_lossy()
-                        .to_string();
-
-                    // Skip directories
-                    if entry.header().entry_type().is_dir() {
-                        continue;
-                    }
-
-                    let size = entry.header().size()
-                        .map_err(|e| Error::InitError(format!("Failed to get file size: {}", e)))?;
+    fn provides(&self) -> &[Provide] {
+        &self.provides
+    }
 
-                    let mode = entry.header().mode()
-                        .map_err(|e| Error::InitError(format!("Failed to get file mode: {}", e)))?;
+    fn scriptlets(&self) -> &[Scriptlet] {
+        &self.scriptlets
+    }
 
-                    // Read file content
-                    let mut content = Vec::new();
-                    entry
-                        .read_to_end(&mut content)
-                        .map_err(|e| Error::InitError(format!("Failed to read file content: {}", e)))?;
+    fn extract_files(&self, sink: &mut dyn FnMut(FileStream) -> Result<()>) -> Result<()> {
+        debug!(
+            "Extracting file contents from Debian package: {:?}",
+            self.package_path
+        );
 
-                    // Compute SHA-256
-                    use sha2::{Digest, Sha256};
-                    let mut hasher = Sha256::new();
-                    hasher.update(&content);
-                    let hash = format!("{:x}", hasher.finalize());
+        let file = File::open(&self.package_path)
+            .map_err(|e| Error::IoError(format!("Failed to open DEB file: {}", e)))?;
+        let mut archive = ar::Archive::new(file);
 
-                    extracted_files.push(ExtractedFile {
-                        path: format!("/{}", entry_path.trim_start_matches("./")),
-                        content,
-                        size: size as i64,
-                        mode: mode as i32,
-                        sha256: Some(hash),
-                    });
-                }
+        // Decompress data.tar straight out of the ar member
+        while let Some(entry) = archive.next_entry() {
+            let entry =
+                entry.map_err(|e| FORMAT.parse_error(format!("Failed to read AR entry: {}", e)))?;
 
-                debug!("Extracted {} files from DEB package", extracted_files.len());
-                return Ok(extracted_files);
+            let entry_name = String::from_utf8_lossy(entry.header().identifier()).to_string();
+            if !entry_name.starts_with("data.tar") {
+                continue;
             }
+
+            let reader: Box<dyn Read> = if entry_name.ends_with(".gz") {
+                Box::new(GzDecoder::new(entry))
+            } else if entry_name.ends_with(".xz") {
+                Box::new(XzDecoder::new(entry))
+            } else if entry_name.ends_with(".zst") {
+                Box::new(zstd::Decoder::new(entry).map_err(|e| {
+                    FORMAT.parse_error(format!("Failed to create zstd decoder: {}", e))
+                })?)
+            } else if entry_name == "data.tar" {
+                Box::new(entry)
+            } else {
+                return Err(FORMAT.parse_error(format!("Unsupported DEB payload: {}", entry_name)));
+            };
+
+            let mut payload = Archive::new(reader);
+            let count =
+                crate::packages::stream_tar_payload(FORMAT, &mut payload, &self.files, &[], sink)?;
+            debug!("Extracted {} files from DEB package", count);
+            return Ok(());
         }
 
-        Err(Error::InitError(
-            "Could not find or extract data.tar from DEB package".to_string(),
-        ))
+        Err(FORMAT.parse_error("Could not find or extract data.tar from DEB package".to_string()))
     }
 
     fn to_trove(&self) -> Trove {
@@ -447,6 +577,7 @@ impl PackageFormat for DebPackage {
         trove.architecture = self.architecture().map(|s| s.to_string());
         trove.description = self.description().map(|s| s.to_string());
 
+        trove.format = Some(FORMAT);
         trove
     }
 }
@@ -517,7 +648,10 @@ Recommends: python3
         assert_eq!(control.version, Some("1.0.0-1".to_string()));
         assert_eq!(control.architecture, Some("amd64".to_string()));
         assert_eq!(control.description, Some("A test package".to_string()));
-        assert_eq!(control.maintainer, Some("Test User <test@example.com>".to_string()));
+        assert_eq!(
+            control.maintainer,
+            Some("Test User <test@example.com>".to_string())
+        );
         assert_eq!(control.section, Some("utils".to_string()));
         assert_eq!(control.priority, Some("optional".to_string()));
         assert_eq!(control.homepage, Some("https://example.com".to_string()));
@@ -551,4 +685,41 @@ Recommends: python3
         assert_eq!(name, "python3");
         assert_eq!(version, None);
     }
+
+    #[test]
+    fn test_provides_parsing() {
+        let provides = DebPackage::parse_dependency_list("mail-transport-agent, libfoo-abi (= 2)");
+        let parsed = DebPackage::convert_provides(&provides);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].name, "mail-transport-agent");
+        assert_eq!(parsed[0].version, None);
+        assert_eq!(parsed[1].name, "libfoo-abi");
+        assert_eq!(parsed[1].version, Some("2".to_string()));
+    }
+
+    #[test]
+    fn test_script_interpreter() {
+        assert_eq!(
+            DebPackage::script_interpreter("#!/bin/sh -e\nset -e\n"),
+            "/bin/sh -e"
+        );
+        assert_eq!(
+            DebPackage::script_interpreter("#! /usr/bin/perl\n"),
+            "/usr/bin/perl"
+        );
+        assert_eq!(DebPackage::script_interpreter("set -e\n"), "/bin/sh");
+    }
+
+    #[test]
+    fn test_conffiles_parsing() {
+        let content =
+            "/etc/app/app.conf\n/etc/default/app\nremove-on-upgrade /etc/app/old.conf\n\n";
+        assert_eq!(
+            DebPackage::parse_conffiles(content),
+            vec![
+                "/etc/app/app.conf".to_string(),
+                "/etc/default/app".to_string()
+            ]
+        );
+    }
 }
@@ -0,0 +1,87 @@
+// src/packages/extract.rs
+
+//! Unpacking a package into a directory
+//!
+//! [`extract_to`] writes a package's payload under a directory without
+//! touching a database, for looking inside packages and for assembling
+//! images by hand. Regular files and directories keep their permission
+//! bits, symlinks are recreated with their targets unchanged and hardlinks
+//! are linked to the extracted file they name. Paths are resolved the way
+//! the deployer resolves them (see [`resolve_under_root`]), so a payload
+//! climbing out of the directory with `..` is refused and one writing
+//! through a symlink it shipped stays inside the directory.
+
+use super::PackageFormat;
+use super::traits::FileStream;
+use crate::db::models::FileType;
+use crate::error::{Error, Result};
+use crate::paths::{decode_path, resolve_under_root};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use tracing::debug;
+
+/// What `extract_to` wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtractSummary {
+    pub files: usize,
+    pub symlinks: usize,
+    pub hardlinks: usize,
+    pub directories: usize,
+    /// Bytes of regular file content written
+    pub bytes: u64,
+}
+
+/// Write every file of `package` under `dest`, creating it if needed
+///
+/// Files already in `dest` are replaced. Ownership is not applied.
+pub fn extract_to(package: &dyn PackageFormat, dest: &Path) -> Result<ExtractSummary> {
+    fs::create_dir_all(dest)?;
+    let mut summary = ExtractSummary::default();
+
+    package.extract_files(&mut |FileStream { file, content }| {
+        let target = resolve_under_root(dest, &file.path)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Never write through whatever is there now
+        if fs::symlink_metadata(&target).is_ok_and(|metadata| !metadata.is_dir()) {
+            fs::remove_file(&target)?;
+        }
+
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = fs::Permissions::from_mode(file.mode as u32 & 0o7777);
+
+        match (file.file_type, file.link_target.as_deref()) {
+            (FileType::Symlink, Some(link)) => {
+                std::os::unix::fs::symlink(decode_path(link), &target)?;
+                summary.symlinks += 1;
+            }
+            (FileType::Hardlink, Some(link)) => {
+                fs::hard_link(resolve_under_root(dest, link)?, &target)?;
+                summary.hardlinks += 1;
+            }
+            (FileType::Regular, _) => {
+                let mut out = File::create(&target)?;
+                summary.bytes += io::copy(content, &mut out)?;
+                fs::set_permissions(&target, permissions)?;
+                summary.files += 1;
+            }
+            (FileType::Directory, _) => {
+                fs::create_dir_all(&target)?;
+                fs::set_permissions(&target, permissions)?;
+                summary.directories += 1;
+            }
+            (_, None) => {
+                return Err(Error::ParseError(format!(
+                    "{} is a link without a target",
+                    file.path
+                )));
+            }
+        }
+        debug!("Extracted {}", file.path);
+        Ok(())
+    })?;
+
+    Ok(summary)
+}
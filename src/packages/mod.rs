@@ -7,7 +7,393 @@
 
 pub mod arch;
 pub mod deb;
+pub mod extract;
 pub mod rpm;
 pub mod traits;
+pub mod xattrs;
 
 pub use traits::PackageFormat;
+
+use crate::db::models::FileType;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use tar::EntryType;
+use tracing::warn;
+use traits::{FileStream, PackageFile, PayloadFile};
+
+/// Package format types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageFormatType {
+    Rpm,
+    Deb,
+    Arch,
+}
+
+impl PackageFormatType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            PackageFormatType::Rpm => "rpm",
+            PackageFormatType::Deb => "deb",
+            PackageFormatType::Arch => "arch",
+        }
+    }
+
+    /// A failure to read a package of this format
+    pub(crate) fn parse_error(self, reason: String) -> Error {
+        Error::PackageParse {
+            format: self,
+            reason,
+        }
+    }
+}
+
+impl FromStr for PackageFormatType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rpm" => Ok(PackageFormatType::Rpm),
+            "deb" => Ok(PackageFormatType::Deb),
+            "arch" => Ok(PackageFormatType::Arch),
+            _ => Err(format!("Invalid package format: {}", s)),
+        }
+    }
+}
+
+/// Detect package format from file extension and magic bytes
+pub fn detect_package_format(path: &str) -> Result<PackageFormatType> {
+    // First try file extension
+    if path.ends_with(".rpm") {
+        return Ok(PackageFormatType::Rpm);
+    } else if path.ends_with(".deb") {
+        return Ok(PackageFormatType::Deb);
+    } else if path.ends_with(".pkg.tar.zst") || path.ends_with(".pkg.tar.xz") {
+        return Ok(PackageFormatType::Arch);
+    }
+
+    // Fallback to magic bytes detection
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+
+    // RPM magic: 0xED 0xAB 0xEE 0xDB (first 4 bytes)
+    if magic[0..4] == [0xED, 0xAB, 0xEE, 0xDB] {
+        return Ok(PackageFormatType::Rpm);
+    }
+
+    // DEB magic: "!<arch>\n" (ar archive format)
+    if magic[0..7] == *b"!<arch>" {
+        return Ok(PackageFormatType::Deb);
+    }
+
+    // Arch packages are compressed tar archives
+    // Check for zstd magic: 0x28 0xB5 0x2F 0xFD
+    if magic[0..4] == [0x28, 0xB5, 0x2F, 0xFD] {
+        return Ok(PackageFormatType::Arch);
+    }
+
+    // Check for xz magic: 0xFD 0x37 0x7A 0x58 0x5A 0x00
+    if magic[0..6] == [0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00] {
+        return Ok(PackageFormatType::Arch);
+    }
+
+    Err(Error::ParseError(format!(
+        "Unable to detect package format for: {}",
+        path
+    )))
+}
+
+/// Detect the format of a package file and parse it
+pub fn open_package(path: &Path) -> Result<Box<dyn PackageFormat>> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| Error::ParseError(format!("Invalid package path: {}", path.display())))?;
+
+    let package: Box<dyn PackageFormat> = match detect_package_format(path_str)? {
+        PackageFormatType::Rpm => Box::new(rpm::RpmPackage::parse(path_str)?),
+        PackageFormatType::Deb => Box::new(deb::DebPackage::parse(path_str)?),
+        PackageFormatType::Arch => Box::new(arch::ArchPackage::parse(path_str)?),
+    };
+    Ok(package)
+}
+
+/// Normalize a path from a package payload archive to an absolute path
+///
+/// Directory entries lose their trailing slash; the archive root becomes `/`.
+pub(crate) fn payload_path(path: &str) -> String {
+    format!(
+        "/{}",
+        path.trim_start_matches("./")
+            .trim_start_matches('/')
+            .trim_end_matches('/')
+    )
+}
+
+/// Mode of a tar entry, with the file type bits an RPM header would carry
+///
+/// Tar headers only hold the permission bits and keep the type apart, so
+/// they are put back together to match RPM modes and `st_mode`.
+pub(crate) fn tar_mode(format: PackageFormatType, header: &tar::Header) -> Result<i32> {
+    let mode = header
+        .mode()
+        .map_err(|e| format.parse_error(format!("Failed to get file mode: {}", e)))?;
+    let file_type = match header.entry_type() {
+        EntryType::Directory => 0o040000,
+        EntryType::Symlink => 0o120000,
+        _ => 0o100000,
+    };
+    Ok((mode & 0o7777 | file_type) as i32)
+}
+
+/// Paths of the config files in a package's file list
+pub(crate) fn config_paths(files: &[PackageFile]) -> HashSet<&str> {
+    files
+        .iter()
+        .filter(|f| f.is_config)
+        .map(|f| f.path.as_str())
+        .collect()
+}
+
+/// Read the owning user and group of a tar entry
+///
+/// Names are preferred; archives without names (plain ustar from some build
+/// tools) fall back to the numeric uid/gid as a string.
+pub(crate) fn tar_ownership(header: &tar::Header) -> (Option<String>, Option<String>) {
+    let owner = match header.username() {
+        Ok(Some(name)) if !name.is_empty() => Some(name.to_string()),
+        _ => header.uid().ok().map(|uid| uid.to_string()),
+    };
+    let group = match header.groupname() {
+        Ok(Some(name)) if !name.is_empty() => Some(name.to_string()),
+        _ => header.gid().ok().map(|gid| gid.to_string()),
+    };
+    (owner, group)
+}
+
+/// Stream the files of a tar-based package payload to `sink`
+///
+/// Entries whose archive path is listed in `skip` (package metadata) are
+/// left out. Returns the number of files streamed.
+pub(crate) fn stream_tar_payload<R: Read>(
+    format: PackageFormatType,
+    archive: &mut tar::Archive<R>,
+    files: &[PackageFile],
+    skip: &[&str],
+    sink: &mut dyn FnMut(FileStream) -> Result<()>,
+) -> Result<usize> {
+    let config_paths = config_paths(files);
+    let mut streamed = 0;
+
+    for entry in archive
+        .entries()
+        .map_err(|e| format.parse_error(format!("Failed to read payload: {}", e)))?
+    {
+        let mut entry =
+            entry.map_err(|e| format.parse_error(format!("Failed to read entry: {}", e)))?;
+
+        let entry_path = entry
+            .path()
+            .map_err(|e| format.parse_error(format!("Failed to get entry path: {}", e)))?;
+        let entry_path = crate::paths::encode_path(&entry_path);
+        if skip.contains(&entry_path.as_str()) {
+            continue;
+        }
+
+        let path = payload_path(&entry_path);
+        let Some(mut file) = tar_entry_file(format, &mut entry, path)? else {
+            continue;
+        };
+        file.is_config = config_paths.contains(file.path.as_str());
+
+        if file.file_type == FileType::Regular {
+            sink(FileStream {
+                file,
+                content: &mut entry,
+            })?;
+        } else {
+            sink(FileStream {
+                file,
+                content: &mut std::io::empty(),
+            })?;
+        }
+        streamed += 1;
+    }
+
+    Ok(streamed)
+}
+
+/// Describe an entry of a tar-based package payload
+///
+/// Symlinks record their target. Hardlinks record the path they point to,
+/// since tar always stores the link after its target. Directories are
+/// tracked, except the archive root. Device nodes are not and yield None.
+fn tar_entry_file<R: Read>(
+    format: PackageFormatType,
+    entry: &mut tar::Entry<R>,
+    path: String,
+) -> Result<Option<PayloadFile>> {
+    let size = entry
+        .header()
+        .size()
+        .map_err(|e| format.parse_error(format!("Failed to get file size: {}", e)))?;
+    let mode = tar_mode(format, entry.header())?;
+    let (owner, group) = tar_ownership(entry.header());
+
+    let link_name = || -> Result<String> {
+        entry
+            .link_name()
+            .map_err(|e| {
+                format.parse_error(format!("Failed to read link target of {}: {}", path, e))
+            })?
+            .map(|target| crate::paths::encode_path(&target))
+            .ok_or_else(|| format.parse_error(format!("Link {} has no target", path)))
+    };
+
+    let (file_type, link_target) = match entry.header().entry_type() {
+        EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+            (FileType::Regular, None)
+        }
+        EntryType::Symlink => (FileType::Symlink, Some(link_name()?)),
+        EntryType::Link => (FileType::Hardlink, Some(payload_path(&link_name()?))),
+        EntryType::Directory if path == "/" => return Ok(None),
+        EntryType::Directory => (FileType::Directory, None),
+        other => {
+            warn!("Skipping unsupported file type {:?}: {}", other, path);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(PayloadFile {
+        path,
+        size: if file_type == FileType::Directory {
+            0
+        } else {
+            size as i64
+        },
+        mode,
+        sha256: None,
+        file_type,
+        link_target,
+        owner,
+        group,
+        is_config: false,
+        xattrs: tar_xattrs(format, entry)?,
+    }))
+}
+
+/// Extended attributes of a tar entry, from its `SCHILY.xattr.` PAX records
+pub(crate) fn tar_xattrs<R: Read>(
+    format: PackageFormatType,
+    entry: &mut tar::Entry<R>,
+) -> Result<xattrs::Xattrs> {
+    let mut found = xattrs::Xattrs::new();
+    let Some(extensions) = entry
+        .pax_extensions()
+        .map_err(|e| format.parse_error(format!("Failed to read PAX header: {}", e)))?
+    else {
+        return Ok(found);
+    };
+    for extension in extensions {
+        let extension = extension
+            .map_err(|e| format.parse_error(format!("Failed to read PAX header: {}", e)))?;
+        if let Ok(key) = extension.key()
+            && let Some(name) = key.strip_prefix(xattrs::PAX_XATTR_PREFIX)
+        {
+            found.insert(name.to_string(), extension.value_bytes().to_vec());
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format_from_rpm_extension() {
+        // Create a temporary file with .rpm extension
+        let temp_file = tempfile::NamedTempFile::with_suffix(".rpm").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        // Write RPM magic bytes
+        std::fs::write(path, [0xED, 0xAB, 0xEE, 0xDB, 0, 0, 0, 0]).unwrap();
+
+        let format = detect_package_format(path).unwrap();
+        assert_eq!(format, PackageFormatType::Rpm);
+    }
+
+    #[test]
+    fn test_detect_format_from_deb_extension() {
+        let temp_file = tempfile::NamedTempFile::with_suffix(".deb").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        // Write DEB magic bytes
+        std::fs::write(path, b"!<arch>\n").unwrap();
+
+        let format = detect_package_format(path).unwrap();
+        assert_eq!(format, PackageFormatType::Deb);
+    }
+
+    #[test]
+    fn test_detect_format_from_arch_extension() {
+        let temp_file = tempfile::NamedTempFile::with_suffix(".pkg.tar.zst").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        // Write zstd magic bytes
+        std::fs::write(path, [0x28, 0xB5, 0x2F, 0xFD, 0, 0, 0, 0]).unwrap();
+
+        let format = detect_package_format(path).unwrap();
+        assert_eq!(format, PackageFormatType::Arch);
+    }
+
+    #[test]
+    fn test_detect_format_from_rpm_magic_bytes() {
+        // Test fallback to magic bytes when extension is not recognized
+        let temp_file = tempfile::NamedTempFile::with_suffix(".unknown").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        // Write RPM magic bytes
+        std::fs::write(path, [0xED, 0xAB, 0xEE, 0xDB, 0, 0, 0, 0]).unwrap();
+
+        let format = detect_package_format(path).unwrap();
+        assert_eq!(format, PackageFormatType::Rpm);
+    }
+
+    #[test]
+    fn test_detect_format_from_deb_magic_bytes() {
+        let temp_file = tempfile::NamedTempFile::with_suffix(".unknown").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        // Write DEB magic bytes (ar archive)
+        std::fs::write(path, b"!<arch>\n").unwrap();
+
+        let format = detect_package_format(path).unwrap();
+        assert_eq!(format, PackageFormatType::Deb);
+    }
+
+    #[test]
+    fn test_detect_format_unknown() {
+        let temp_file = tempfile::NamedTempFile::with_suffix(".unknown").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        // Write random bytes that don't match any format
+        std::fs::write(path, [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]).unwrap();
+
+        let result = detect_package_format(path);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_package_format_type_equality() {
+        assert_eq!(PackageFormatType::Rpm, PackageFormatType::Rpm);
+        assert_eq!(PackageFormatType::Deb, PackageFormatType::Deb);
+        assert_eq!(PackageFormatType::Arch, PackageFormatType::Arch);
+        assert_ne!(PackageFormatType::Rpm, PackageFormatType::Deb);
+    }
+}
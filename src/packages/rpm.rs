@@ -2,24 +2,37 @@
 
 //! RPM package format parser
 
-use crate::db::models::{Trove, TroveType};
+use crate::db::models::{FileType, Trove, TroveType};
 use crate::error::{Error, Result};
-use crate::packages::traits::{Dependency, DependencyType, ExtractedFile, PackageFile, PackageFormat};
+use crate::packages::PackageFormatType;
+use crate::packages::traits::{
+    Dependency, DependencyType, FileStream, PackageFile, PackageFormat, PayloadFile, Provide,
+    Scriptlet, ScriptletPhase,
+};
+use crate::packages::xattrs::{CAPABILITY_XATTR, Xattrs, capability_xattr};
+use crate::version::RpmVersion;
 use rpm::Package;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::PathBuf;
-use tracing::debug;
+use tracing::{debug, warn};
+
+/// Format named in this parser's errors
+const FORMAT: PackageFormatType = PackageFormatType::Rpm;
 
 /// RPM package representation
 pub struct RpmPackage {
     package_path: PathBuf,
     name: String,
+    /// Full `[epoch:]version-release`, the form repository metadata uses
     version: String,
+    epoch: u64,
     architecture: Option<String>,
     description: Option<String>,
     files: Vec<PackageFile>,
     dependencies: Vec<Dependency>,
+    provides: Vec<Provide>,
+    scriptlets: Vec<Scriptlet>,
     // Provenance information
     source_rpm: Option<String>,
     build_host: Option<String>,
@@ -38,12 +51,29 @@ impl RpmPackage {
             for entry in file_entries {
                 // FileDigest can be formatted as hex string
                 let sha256 = entry.digest.as_ref().map(|d| format!("{}", d));
+                // Directories the package owns, listed with %dir or as a whole
+                let is_dir = matches!(entry.mode, rpm::FileMode::Dir { .. });
+                let mut xattrs = Xattrs::new();
+                if let Some(caps) = entry.caps.as_deref().filter(|caps| !caps.is_empty()) {
+                    match capability_xattr(caps) {
+                        Ok(Some(value)) => {
+                            xattrs.insert(CAPABILITY_XATTR.to_string(), value);
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!("Ignoring capabilities of {}: {}", entry.path.display(), e),
+                    }
+                }
 
                 files.push(PackageFile {
-                    path: entry.path.to_string_lossy().to_string(),
-                    size: entry.size as i64,
+                    path: crate::paths::encode_path(&entry.path),
+                    size: if is_dir { 0 } else { entry.size as i64 },
                     mode: entry.mode.raw_mode() as i32,
-                    sha256,
+                    sha256: sha256.filter(|_| !is_dir),
+                    owner: Some(entry.ownership.user.clone()),
+                    group: Some(entry.ownership.group.clone()),
+                    is_config: entry.flags.contains(rpm::FileFlags::CONFIG),
+                    is_dir,
+                    xattrs,
                 });
             }
         }
@@ -58,8 +88,8 @@ impl RpmPackage {
         // Extract runtime dependencies (Requires)
         if let Ok(requires) = pkg.metadata.get_requires() {
             for req in requires {
-                // Skip rpmlib dependencies and file paths
-                if req.name.starts_with("rpmlib(") || req.name.starts_with('/') {
+                // Skip rpmlib dependencies; file paths are resolved by file owner
+                if req.name.starts_with("rpmlib(") {
                     continue;
                 }
 
@@ -80,6 +110,68 @@ impl RpmPackage {
 
         deps
     }
+
+    /// Extract the capabilities an RPM package provides (Provides)
+    fn extract_provides(pkg: &Package) -> Vec<Provide> {
+        pkg.metadata
+            .get_provides()
+            .map(|provides| {
+                provides
+                    .into_iter()
+                    .map(|provide| Provide {
+                        name: provide.name.to_string(),
+                        version: (!provide.version.is_empty()).then(|| provide.version.to_string()),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Extract %pre/%post/%preun/%postun scriptlets from RPM package
+    fn extract_scriptlets(pkg: &Package) -> Vec<Scriptlet> {
+        let scripts = [
+            (
+                ScriptletPhase::PreInstall,
+                pkg.metadata.get_pre_install_script(),
+            ),
+            (
+                ScriptletPhase::PostInstall,
+                pkg.metadata.get_post_install_script(),
+            ),
+            (
+                ScriptletPhase::PreRemove,
+                pkg.metadata.get_pre_uninstall_script(),
+            ),
+            (
+                ScriptletPhase::PostRemove,
+                pkg.metadata.get_post_uninstall_script(),
+            ),
+        ];
+
+        scripts
+            .into_iter()
+            .filter_map(|(phase, script)| {
+                let script = script.ok()?;
+                // RPM defaults to /bin/sh when no interpreter is recorded
+                let interpreter = script
+                    .program
+                    .filter(|program| !program.is_empty())
+                    .map(|program| program.join(" "))
+                    .unwrap_or_else(|| "/bin/sh".to_string());
+
+                // An interpreter-only scriptlet (e.g. "%post -p /sbin/ldconfig") has no body
+                if script.script.trim().is_empty() && interpreter == "/bin/sh" {
+                    return None;
+                }
+
+                Some(Scriptlet {
+                    phase,
+                    interpreter,
+                    body: script.script,
+                })
+            })
+            .collect()
+    }
 }
 
 impl PackageFormat for RpmPackage {
@@ -87,25 +179,32 @@ impl PackageFormat for RpmPackage {
         debug!("Parsing RPM package: {}", path);
 
         let file = File::open(path)
-            .map_err(|e| Error::InitError(format!("Failed to open RPM file: {}", e)))?;
+            .map_err(|e| Error::IoError(format!("Failed to open RPM file: {}", e)))?;
 
         let mut buf_reader = BufReader::new(file);
 
         let pkg = Package::parse(&mut buf_reader)
-            .map_err(|e| Error::InitError(format!("Failed to parse RPM: {}", e)))?;
+            .map_err(|e| FORMAT.parse_error(format!("Failed to parse RPM: {}", e)))?;
 
         // Extract basic metadata
         let name = pkg
             .metadata
             .get_name()
-            .map_err(|e| Error::InitError(format!("Failed to get package name: {}", e)))?
+            .map_err(|e| FORMAT.parse_error(format!("Failed to get package name: {}", e)))?
             .to_string();
 
-        let version = pkg
-            .metadata
-            .get_version()
-            .map_err(|e| Error::InitError(format!("Failed to get package version: {}", e)))?
-            .to_string();
+        // Packages without an epoch tag have epoch 0
+        let epoch = pkg.metadata.get_epoch().map_or(0, u64::from);
+        let version = RpmVersion {
+            epoch,
+            version: pkg
+                .metadata
+                .get_version()
+                .map_err(|e| FORMAT.parse_error(format!("Failed to get package version: {}", e)))?
+                .to_string(),
+            release: pkg.metadata.get_release().ok().map(|s| s.to_string()),
+        }
+        .to_string();
 
         let architecture = pkg.metadata.get_arch().ok().map(|s| s.to_string());
         let description = pkg.metadata.get_description().ok().map(|s| s.to_string());
@@ -119,23 +218,29 @@ impl PackageFormat for RpmPackage {
 
         let files = Self::extract_files(&pkg);
         let dependencies = Self::extract_dependencies(&pkg);
+        let provides = Self::extract_provides(&pkg);
+        let scriptlets = Self::extract_scriptlets(&pkg);
 
         debug!(
-            "Parsed RPM: {} version {} ({} files, {} dependencies)",
+            "Parsed RPM: {} version {} ({} files, {} dependencies, {} scriptlets)",
             name,
             version,
             files.len(),
-            dependencies.len()
+            dependencies.len(),
+            scriptlets.len()
         );
 
         Ok(Self {
             package_path: PathBuf::from(path),
             name,
             version,
+            epoch,
             architecture,
             description,
             files,
             dependencies,
+            provides,
+            scriptlets,
             source_rpm,
             build_host,
             vendor,
@@ -168,73 +273,148 @@ impl PackageFormat for RpmPackage {
         &self.dependencies
     }
 
-    fn extract_file_contents(&self) -> Result<Vec<ExtractedFile>> {
-        use std::process::Command;
+    fn provides(&self) -> &[Provide] {
+        &self.provides
+    }
+
+    fn scriptlets(&self) -> &[Scriptlet] {
+        &self.scriptlets
+    }
+
+    fn extract_files(&self, sink: &mut dyn FnMut(FileStream) -> Result<()>) -> Result<()> {
+        use std::process::{Command, Stdio};
         use tempfile::TempDir;
 
         debug!("Extracting file contents from RPM: {:?}", self.package_path);
 
+        // cpio would write these wherever they point
+        if let Some(file) = self
+            .files
+            .iter()
+            .find(|file| crate::paths::escapes_root(&file.path))
+        {
+            return Err(FORMAT.parse_error(format!(
+                "RPM payload path {} leads outside the package",
+                file.path
+            )));
+        }
+
         // Create temp directory for extraction
         let temp_dir = TempDir::new()
-            .map_err(|e| Error::InitError(format!("Failed to create temp dir: {}", e)))?;
+            .map_err(|e| Error::IoError(format!("Failed to create temp dir: {}", e)))?;
 
-        // Extract RPM to temp directory using rpm2cpio | cpio
-        // rpm2cpio package.rpm | cpio -idmv -D /tmp/extract
-        let rpm2cpio_output = Command::new("rpm2cpio")
+        // Extract RPM to temp directory, piping the cpio archive straight through
+        // rpm2cpio package.rpm | cpio -idm -D /tmp/extract
+        let mut rpm2cpio = Command::new("rpm2cpio")
             .arg(&self.package_path)
-            .output()
-            .map_err(|e| Error::InitError(format!("Failed to run rpm2cpio: {}. Is rpm2cpio installed?", e)))?;
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                Error::IoError(format!(
+                    "Failed to run rpm2cpio: {}. Is rpm2cpio installed?",
+                    e
+                ))
+            })?;
 
+        let cpio_status = Command::new("cpio")
+            .args(["-idm", "--quiet", "--no-absolute-filenames"])
+            .current_dir(temp_dir.path())
+            .stdin(rpm2cpio.stdout.take().unwrap())
+            .status()
+            .map_err(|e| {
+                Error::IoError(format!("Failed to run cpio: {}. Is cpio installed?", e))
+            })?;
+
+        let rpm2cpio_output = rpm2cpio
+            .wait_with_output()
+            .map_err(|e| Error::IoError(format!("Failed to run rpm2cpio: {}", e)))?;
         if !rpm2cpio_output.status.success() {
-            return Err(Error::InitError(format!(
+            return Err(FORMAT.parse_error(format!(
                 "rpm2cpio failed: {}",
                 String::from_utf8_lossy(&rpm2cpio_output.stderr)
             )));
         }
 
-        // Extract cpio archive
-        let cpio_status = Command::new("cpio")
-            .args(["-idm", "--quiet"])
-            .current_dir(temp_dir.path())
-            .stdin(std::process::Stdio::piped())
-            .spawn()
-            .and_then(|mut child| {
-                use std::io::Write;
-                child.stdin.as_mut().unwrap().write_all(&rpm2cpio_output.stdout)?;
-                child.wait()
-            })
-            .map_err(|e| Error::InitError(format!("Failed to run cpio: {}. Is cpio installed?", e)))?;
-
         if !cpio_status.success() {
-            return Err(Error::InitError("cpio extraction failed".to_string()));
+            return Err(FORMAT.parse_error("cpio extraction failed".to_string()));
         }
 
-        // Read extracted files
-        let mut extracted_files = Vec::new();
+        // Stream extracted files
+        let mut count = 0;
 
         for file_meta in &self.files {
-            let full_path = temp_dir.path().join(file_meta.path.trim_start_matches('/'));
+            let full_path = crate::paths::resolve_under_root(temp_dir.path(), &file_meta.path)?;
+
+            // Symlinks keep their target; check before is_file(), which follows links
+            let metadata = match std::fs::symlink_metadata(&full_path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let file = PayloadFile {
+                path: file_meta.path.clone(),
+                size: file_meta.size,
+                mode: file_meta.mode,
+                sha256: None,
+                file_type: FileType::Regular,
+                link_target: None,
+                owner: file_meta.owner.clone(),
+                group: file_meta.group.clone(),
+                is_config: file_meta.is_config,
+                xattrs: file_meta.xattrs.clone(),
+            };
+
+            if metadata.file_type().is_symlink() {
+                let target = std::fs::read_link(&full_path).map_err(|e| {
+                    FORMAT.parse_error(format!("Failed to read link {}: {}", file_meta.path, e))
+                })?;
+
+                let file = PayloadFile {
+                    file_type: FileType::Symlink,
+                    link_target: Some(crate::paths::encode_path(&target)),
+                    ..file
+                };
+                sink(FileStream {
+                    file,
+                    content: &mut std::io::empty(),
+                })?;
+                count += 1;
+                continue;
+            }
 
-            // Skip if not a regular file (directory, symlink, etc.)
-            if !full_path.is_file() {
+            if file_meta.is_dir && metadata.is_dir() {
+                let file = PayloadFile {
+                    file_type: FileType::Directory,
+                    ..file
+                };
+                sink(FileStream {
+                    file,
+                    content: &mut std::io::empty(),
+                })?;
+                count += 1;
                 continue;
             }
 
-            // Read file content
-            let content = std::fs::read(&full_path)
-                .map_err(|e| Error::InitError(format!("Failed to read {}: {}", file_meta.path, e)))?;
+            // Skip anything else that is not a regular file (device nodes)
+            if !metadata.is_file() {
+                continue;
+            }
 
-            extracted_files.push(ExtractedFile {
-                path: file_meta.path.clone(),
-                content,
-                size: file_meta.size,
-                mode: file_meta.mode,
-                sha256: file_meta.sha256.clone(),
-            });
+            let mut content = File::open(&full_path).map_err(|e| {
+                FORMAT.parse_error(format!("Failed to read {}: {}", file_meta.path, e))
+            })?;
+            sink(FileStream {
+                file: PayloadFile {
+                    sha256: file_meta.sha256.clone(),
+                    ..file
+                },
+                content: &mut content,
+            })?;
+            count += 1;
         }
 
-        debug!("Extracted {} files from RPM", extracted_files.len());
-        Ok(extracted_files)
+        debug!("Extracted {} files from RPM", count);
+        Ok(())
     }
 
     fn to_trove(&self) -> Trove {
@@ -247,11 +427,17 @@ impl PackageFormat for RpmPackage {
         trove.architecture = self.architecture().map(|s| s.to_string());
         trove.description = self.description().map(|s| s.to_string());
 
+        trove.format = Some(FORMAT);
         trove
     }
 }
 
 impl RpmPackage {
+    /// Epoch from the package header, 0 when it has none
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
     /// Get source RPM name (for provenance tracking)
     pub fn source_rpm(&self) -> Option<&str> {
         self.source_rpm.as_deref()
@@ -302,11 +488,14 @@ mod tests {
         let rpm = RpmPackage {
             package_path: PathBuf::from("/fake/path.rpm"),
             name: "test-package".to_string(),
-            version: "1.0.0".to_string(),
+            version: "1:1.0.0-1".to_string(),
+            epoch: 1,
             architecture: Some("x86_64".to_string()),
             description: Some("Test package".to_string()),
             files: vec![],
             dependencies: vec![],
+            provides: vec![],
+            scriptlets: vec![],
             source_rpm: Some("test-package-1.0.0.src.rpm".to_string()),
             build_host: Some("buildhost.example.com".to_string()),
             vendor: Some("Test Vendor".to_string()),
@@ -317,7 +506,8 @@ mod tests {
         let trove = rpm.to_trove();
 
         assert_eq!(trove.name, "test-package");
-        assert_eq!(trove.version, "1.0.0");
+        assert_eq!(trove.version, "1:1.0.0-1");
+        assert_eq!(trove.epoch, 1);
         assert_eq!(trove.architecture, Some("x86_64".to_string()));
         assert_eq!(trove.description, Some("Test package".to_string()));
     }
@@ -328,10 +518,13 @@ mod tests {
             package_path: PathBuf::from("/fake/test.rpm"),
             name: "test".to_string(),
             version: "1.0".to_string(),
+            epoch: 0,
             architecture: None,
             description: None,
             files: vec![],
             dependencies: vec![],
+            provides: vec![],
+            scriptlets: vec![],
             source_rpm: Some("test-1.0.src.rpm".to_string()),
             build_host: Some("builder".to_string()),
             vendor: Some("Vendor".to_string()),
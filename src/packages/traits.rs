@@ -2,8 +2,12 @@
 
 //! Common traits for package format parsers
 
-use crate::db::models::Trove;
-use crate::error::Result;
+use crate::db::models::{FileType, Trove};
+use crate::error::{Error, Result};
+use crate::packages::xattrs::Xattrs;
+use std::collections::HashMap;
+use std::io::Read;
+use std::str::FromStr;
 
 /// Metadata about a file within a package
 #[derive(Debug, Clone)]
@@ -12,9 +16,23 @@ pub struct PackageFile {
     pub size: i64,
     pub mode: i32,
     pub sha256: Option<String>,
+    /// Owning user name from the package metadata
+    pub owner: Option<String>,
+    /// Owning group name from the package metadata
+    pub group: Option<String>,
+    /// Config file whose local edits are kept on upgrade
+    pub is_config: bool,
+    /// A directory the package owns, with no content and no hash
+    pub is_dir: bool,
+    /// Extended attributes to set on the file, such as its capabilities
+    pub xattrs: Xattrs,
 }
 
 /// A file extracted from a package with its content
+///
+/// Symlinks carry their target in `link_target` and no content. Hardlinks
+/// carry the packaged path they point to along with that file's content.
+/// Directories have neither content nor a hash.
 #[derive(Debug, Clone)]
 pub struct ExtractedFile {
     pub path: String,
@@ -22,6 +40,53 @@ pub struct ExtractedFile {
     pub size: i64,
     pub mode: i32,
     pub sha256: Option<String>,
+    pub file_type: FileType,
+    pub link_target: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub is_config: bool,
+    pub xattrs: Xattrs,
+}
+
+impl ExtractedFile {
+    /// Bytes stored in the CAS for this file
+    ///
+    /// A symlink's identity is its target, so the target string is stored
+    /// and hashed in place of content.
+    pub fn cas_content(&self) -> &[u8] {
+        match (self.file_type, &self.link_target) {
+            (FileType::Symlink, Some(target)) => target.as_bytes(),
+            _ => &self.content,
+        }
+    }
+}
+
+/// Metadata of a file read from a package payload
+///
+/// Symlinks carry their target in `link_target`. Hardlinks carry the
+/// packaged path they point to, which always appears earlier in the payload.
+/// Directories come before the files inside them.
+#[derive(Debug, Clone)]
+pub struct PayloadFile {
+    pub path: String,
+    pub size: i64,
+    pub mode: i32,
+    pub sha256: Option<String>,
+    pub file_type: FileType,
+    pub link_target: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub is_config: bool,
+    pub xattrs: Xattrs,
+}
+
+/// A payload file handed to the sink of [`PackageFormat::extract_files`]
+///
+/// `content` reads a regular file's bytes straight from the payload and is
+/// only valid during the sink call. It is empty for links and directories.
+pub struct FileStream<'a> {
+    pub file: PayloadFile,
+    pub content: &'a mut dyn Read,
 }
 
 /// Dependency information
@@ -33,6 +98,13 @@ pub struct Dependency {
     pub description: Option<String>,
 }
 
+/// A capability the package provides, such as a virtual name or soname
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provide {
+    pub name: String,
+    pub version: Option<String>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DependencyType {
     Runtime,
@@ -40,6 +112,49 @@ pub enum DependencyType {
     Optional,
 }
 
+/// Point in the install/remove lifecycle at which a scriptlet runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScriptletPhase {
+    PreInstall,
+    PostInstall,
+    PreRemove,
+    PostRemove,
+}
+
+impl ScriptletPhase {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ScriptletPhase::PreInstall => "pre-install",
+            ScriptletPhase::PostInstall => "post-install",
+            ScriptletPhase::PreRemove => "pre-remove",
+            ScriptletPhase::PostRemove => "post-remove",
+        }
+    }
+}
+
+impl FromStr for ScriptletPhase {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pre-install" => Ok(ScriptletPhase::PreInstall),
+            "post-install" => Ok(ScriptletPhase::PostInstall),
+            "pre-remove" => Ok(ScriptletPhase::PreRemove),
+            "post-remove" => Ok(ScriptletPhase::PostRemove),
+            _ => Err(format!("Invalid scriptlet phase: {}", s)),
+        }
+    }
+}
+
+/// A maintainer script shipped inside a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scriptlet {
+    pub phase: ScriptletPhase,
+    /// Interpreter command line (e.g., "/bin/sh")
+    pub interpreter: String,
+    pub body: String,
+}
+
 /// Common interface for all package formats (RPM, DEB, Arch, etc.)
 pub trait PackageFormat {
     /// Parse a package file from the given path
@@ -65,11 +180,86 @@ pub trait PackageFormat {
     /// Get the list of dependencies
     fn dependencies(&self) -> &[Dependency];
 
+    /// Get the capabilities the package provides
+    fn provides(&self) -> &[Provide];
+
+    /// Get the packages this one cannot be installed alongside
+    ///
+    /// Entries carry a name and an optional version constraint, like
+    /// dependencies. Formats without conflict metadata return nothing.
+    fn conflicts(&self) -> &[Dependency] {
+        &[]
+    }
+
+    /// Get the packages this one supersedes and may remove on update
+    fn replaces(&self) -> &[Dependency] {
+        &[]
+    }
+
+    /// Get the maintainer scripts shipped with the package
+    fn scriptlets(&self) -> &[Scriptlet];
+
+    /// Stream the package payload, calling `sink` once per file in payload order
+    ///
+    /// Only the current file's content is readable at a time, so memory use
+    /// does not grow with the size of the package.
+    fn extract_files(&self, sink: &mut dyn FnMut(FileStream) -> Result<()>) -> Result<()>;
+
     /// Extract all file contents from the package
     ///
     /// Returns a vector of ExtractedFile containing file metadata and content.
-    /// This is used during package installation to get the actual file data.
-    fn extract_file_contents(&self) -> Result<Vec<ExtractedFile>>;
+    /// Built on [`extract_files`](Self::extract_files), but holds the whole
+    /// payload in memory; installation streams instead.
+    fn extract_file_contents(&self) -> Result<Vec<ExtractedFile>> {
+        let mut extracted: Vec<ExtractedFile> = Vec::new();
+        let mut by_path: HashMap<String, usize> = HashMap::new();
+
+        self.extract_files(&mut |FileStream { file, content }| {
+            let content = match (file.file_type, &file.link_target) {
+                (FileType::Hardlink, Some(target)) => by_path
+                    .get(target)
+                    .map(|&i| extracted[i].content.clone())
+                    .ok_or_else(|| {
+                        Error::ParseError(format!(
+                            "Hardlink {} points to unknown file {}",
+                            file.path, target
+                        ))
+                    })?,
+                _ => {
+                    let mut buffer = Vec::new();
+                    content.read_to_end(&mut buffer).map_err(|e| {
+                        Error::IoError(format!("Failed to read {}: {}", file.path, e))
+                    })?;
+                    buffer
+                }
+            };
+
+            let sha256 = match file.file_type {
+                FileType::Symlink | FileType::Directory => file.sha256,
+                _ => file
+                    .sha256
+                    .or_else(|| Some(crate::filesystem::CasStore::compute_hash(&content))),
+            };
+
+            by_path.insert(file.path.clone(), extracted.len());
+            extracted.push(ExtractedFile {
+                path: file.path,
+                content,
+                size: file.size,
+                mode: file.mode,
+                sha256,
+                file_type: file.file_type,
+                link_target: file.link_target,
+                owner: file.owner,
+                group: file.group,
+                is_config: file.is_config,
+                xattrs: file.xattrs,
+            });
+            Ok(())
+        })?;
+
+        Ok(extracted)
+    }
 
     /// Convert this package to a Trove representation
     fn to_trove(&self) -> Trove;
@@ -0,0 +1,262 @@
+// src/packages/xattrs.rs
+
+//! Extended attributes shipped with package files
+//!
+//! Packages can give a file extended attributes that have to be set when
+//! it is deployed. The common case is file capabilities, which let
+//! `ping` open raw sockets without being setuid root. RPM headers carry
+//! capabilities in the text form `setcap` takes (`cap_net_raw=ep`).
+//! Tar-based formats carry any attribute as a PAX `SCHILY.xattr.<name>`
+//! record. Both end up as a map from attribute name to raw value, and the
+//! capability text is encoded into the `security.capability` value the
+//! kernel reads.
+//!
+//! The files table keeps the map as a JSON object with hex-encoded values,
+//! since values such as capability sets are binary.
+
+use std::collections::BTreeMap;
+
+/// Extended attributes of a file, by name
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Attribute holding a file's capabilities
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Prefix of an extended attribute record in a PAX header
+pub const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Capability names in bit order, as `capability.h` numbers them
+const CAPABILITIES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// `VFS_CAP_REVISION_2`, the capability format for 64 capability bits
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+
+/// `VFS_CAP_FLAGS_EFFECTIVE`, raising permitted capabilities on exec
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+/// Encode capabilities in `setcap` text form as a `security.capability` value
+///
+/// The text is a list of clauses such as `cap_net_raw=ep` or
+/// `cap_net_admin,cap_net_raw+p`, applied in order: `=` sets the listed
+/// capabilities to exactly the given flags, `+` raises and `-` lowers
+/// them. An empty list or `all` names every capability. Returns None when
+/// no capability ends up set.
+pub fn capability_xattr(text: &str) -> std::result::Result<Option<Vec<u8>>, String> {
+    let all = (1u64 << CAPABILITIES.len()) - 1;
+    // Permitted, inheritable and effective sets
+    let mut sets = [0u64; 3];
+
+    for clause in text.split_whitespace() {
+        let op_at = clause.find(['=', '+', '-']).ok_or_else(|| {
+            format!(
+                "Invalid capabilities '{}': no operator in '{}'",
+                text, clause
+            )
+        })?;
+        let (names, actions) = clause.split_at(op_at);
+
+        let mask = if names.is_empty() || names.eq_ignore_ascii_case("all") {
+            all
+        } else {
+            names.split(',').try_fold(0u64, |mask, name| {
+                CAPABILITIES
+                    .iter()
+                    .position(|cap| cap.eq_ignore_ascii_case(name))
+                    .map(|bit| mask | 1 << bit)
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid capabilities '{}': unknown capability {}",
+                            text, name
+                        )
+                    })
+            })?
+        };
+
+        let mut chars = actions.chars().peekable();
+        while let Some(op) = chars.next() {
+            let mut flagged = [false; 3];
+            while let Some(&flag) = chars.peek()
+                && let Some(set) = "pie".find(flag)
+            {
+                flagged[set] = true;
+                chars.next();
+            }
+            for (set, flagged) in sets.iter_mut().zip(flagged) {
+                match op {
+                    '=' if flagged => *set |= mask,
+                    '=' => *set &= !mask,
+                    '+' if flagged => *set |= mask,
+                    '-' if flagged => *set &= !mask,
+                    '+' | '-' => {}
+                    other => {
+                        return Err(format!(
+                            "Invalid capabilities '{}': unexpected '{}'",
+                            text, other
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    let [permitted, inheritable, effective] = sets;
+    if permitted == 0 && inheritable == 0 {
+        return Ok(None);
+    }
+
+    // struct vfs_cap_data: magic, then permitted and inheritable words
+    // for capabilities 0-31 and 32-63, all little-endian
+    let magic = VFS_CAP_REVISION_2
+        | if effective != 0 {
+            VFS_CAP_FLAGS_EFFECTIVE
+        } else {
+            0
+        };
+    let words = [
+        magic,
+        permitted as u32,
+        inheritable as u32,
+        (permitted >> 32) as u32,
+        (inheritable >> 32) as u32,
+    ];
+    Ok(Some(
+        words.iter().flat_map(|word| word.to_le_bytes()).collect(),
+    ))
+}
+
+/// Serialize attributes for the files table; None when there are none
+pub fn to_json(xattrs: &Xattrs) -> Option<String> {
+    if xattrs.is_empty() {
+        return None;
+    }
+    let encoded: BTreeMap<&str, String> = xattrs
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str(),
+                value.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            )
+        })
+        .collect();
+    serde_json::to_string(&encoded).ok()
+}
+
+/// Read attributes serialized by [`to_json`]
+pub fn from_json(json: &str) -> std::result::Result<Xattrs, String> {
+    let encoded: BTreeMap<String, String> =
+        serde_json::from_str(json).map_err(|e| format!("Invalid extended attributes: {}", e))?;
+    encoded
+        .into_iter()
+        .map(|(name, hex)| {
+            let value = (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| format!("Invalid value of extended attribute {}", name))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_xattr() {
+        // cap_net_raw is bit 13
+        let value = capability_xattr("cap_net_raw=ep").unwrap().unwrap();
+        assert_eq!(
+            value,
+            [
+                1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]
+        );
+
+        // Permitted only, no effective flag; bits above 31 go in the second word
+        let value = capability_xattr("cap_net_admin,CAP_NET_RAW+p cap_bpf+p")
+            .unwrap()
+            .unwrap();
+        assert_eq!(&value[..4], &[0, 0, 0, 2]);
+        assert_eq!(
+            u32::from_le_bytes(value[4..8].try_into().unwrap()),
+            1 << 12 | 1 << 13
+        );
+        assert_eq!(
+            u32::from_le_bytes(value[12..16].try_into().unwrap()),
+            1 << (39 - 32)
+        );
+
+        // Later clauses lower what earlier ones raised
+        assert_eq!(capability_xattr("cap_kill+ep cap_kill-p").unwrap(), None);
+        assert_eq!(
+            capability_xattr("all=ep cap_kill=").unwrap().unwrap()[4..8],
+            [0xdf, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(capability_xattr("").unwrap(), None);
+
+        assert!(capability_xattr("cap_flying=ep").is_err());
+        assert!(capability_xattr("cap_kill").is_err());
+        assert!(capability_xattr("cap_kill=ex").is_err());
+    }
+
+    #[test]
+    fn test_xattrs_json_roundtrip() {
+        assert_eq!(to_json(&Xattrs::new()), None);
+
+        let mut xattrs = Xattrs::new();
+        xattrs.insert(CAPABILITY_XATTR.to_string(), vec![0, 1, 0xfe, 0xff]);
+        xattrs.insert("user.mime_type".to_string(), b"text/plain".to_vec());
+        let json = to_json(&xattrs).unwrap();
+        assert!(json.contains(r#""security.capability":"0001feff""#));
+        assert_eq!(from_json(&json).unwrap(), xattrs);
+
+        assert!(from_json(r#"{"user.a":"abc"}"#).is_err());
+        assert!(from_json("[]").is_err());
+    }
+}
@@ -0,0 +1,423 @@
+// src/paths/limits.rs
+
+//! Name and length limits of the filesystems packages are deployed to
+//!
+//! Before an install deploys anything, every target path is checked, so a
+//! path the filesystem cannot hold is reported up front with its package
+//! instead of failing halfway with a raw OS error:
+//! - `max_filename_length` - bytes in one path component (default 255)
+//! - `max_path_length` - bytes in the whole path under the install root
+//!   (default 4096)
+//! - `check_fat_names` - under a vfat mount, such as an EFI system partition,
+//!   also refuse names Windows cannot use: reserved device names (`CON`,
+//!   `NUL`, `COM1`, ...), the characters `<>:"\|?*`, and a trailing dot or
+//!   space (default true)
+//!
+//! The filesystem a path lands on is looked up in the mount table
+//! (`/proc/mounts`).
+
+use super::{path_bytes, under_root};
+use crate::db::models::Setting;
+use crate::error::{Error, Result};
+use rusqlite::Connection;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+/// Setting capping the bytes in one path component
+pub const MAX_FILENAME_SETTING: &str = "max_filename_length";
+
+/// Setting capping the bytes in a whole target path
+pub const MAX_PATH_SETTING: &str = "max_path_length";
+
+/// Setting turning the Windows name checks on vfat mounts on or off
+pub const FAT_NAMES_SETTING: &str = "check_fat_names";
+
+/// Every path limit setting, in the order `config-get` lists them
+pub const SETTINGS: [&str; 3] = [MAX_FILENAME_SETTING, MAX_PATH_SETTING, FAT_NAMES_SETTING];
+
+/// Filesystem types that get the Windows name checks
+const FAT_TYPES: [&str; 2] = ["vfat", "msdos"];
+
+/// Device names Windows reserves, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Path limits of an installation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathLimits {
+    pub max_filename_length: usize,
+    pub max_path_length: usize,
+    pub check_fat_names: bool,
+}
+
+impl Default for PathLimits {
+    fn default() -> Self {
+        Self {
+            max_filename_length: 255,
+            max_path_length: 4096,
+            check_fat_names: true,
+        }
+    }
+}
+
+/// A package path the target filesystem cannot hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathViolation {
+    pub package: String,
+    pub path: String,
+    pub problem: String,
+}
+
+impl fmt::Display for PathViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.package, self.path, self.problem)
+    }
+}
+
+impl PathLimits {
+    /// Load the limits from the settings table, using defaults for unset keys
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut limits = Self::default();
+        for key in SETTINGS {
+            if let Some(value) = Setting::get(conn, key)? {
+                limits.apply(key, &value)?;
+            }
+        }
+        Ok(limits)
+    }
+
+    /// Check that `value` is valid for the path limit setting `key`
+    ///
+    /// Returns false when `key` is not a path limit setting.
+    pub fn validate(key: &str, value: &str) -> Result<bool> {
+        if !SETTINGS.contains(&key) {
+            return Ok(false);
+        }
+        Self::default().apply(key, value)?;
+        Ok(true)
+    }
+
+    /// Default value of a path limit setting, as `config-get` shows it
+    pub fn default_value(key: &str) -> Option<String> {
+        let defaults = Self::default();
+        match key {
+            MAX_FILENAME_SETTING => Some(defaults.max_filename_length.to_string()),
+            MAX_PATH_SETTING => Some(defaults.max_path_length.to_string()),
+            FAT_NAMES_SETTING => Some(defaults.check_fat_names.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Set the field for `key` from its stored value
+    fn apply(&mut self, key: &str, value: &str) -> Result<()> {
+        let length = || match value.trim().parse::<usize>() {
+            Ok(length) if length > 0 => Ok(length),
+            _ => Err(Error::ParseError(format!(
+                "{} must be a positive number, got '{}'",
+                key, value
+            ))),
+        };
+        match key {
+            MAX_FILENAME_SETTING => self.max_filename_length = length()?,
+            MAX_PATH_SETTING => self.max_path_length = length()?,
+            FAT_NAMES_SETTING => {
+                self.check_fat_names = match value.trim() {
+                    "true" => true,
+                    "false" => false,
+                    _ => {
+                        return Err(Error::ParseError(format!(
+                            "{} must be 'true' or 'false', got '{}'",
+                            key, value
+                        )));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Why the stored package path `stored` cannot be deployed under `root`, if it cannot
+    pub fn check(
+        &self,
+        mounts: &MountTable,
+        root: &Path,
+        package: &str,
+        stored: &str,
+    ) -> Option<PathViolation> {
+        let problem = self.problem(mounts, &under_root(root, stored))?;
+        Some(PathViolation {
+            package: package.to_string(),
+            path: stored.to_string(),
+            problem,
+        })
+    }
+
+    fn problem(&self, mounts: &MountTable, target: &Path) -> Option<String> {
+        let length = path_bytes(target).len();
+        if length > self.max_path_length {
+            return Some(format!(
+                "path of {} bytes exceeds {} ({})",
+                length, MAX_PATH_SETTING, self.max_path_length
+            ));
+        }
+        for component in target.components() {
+            if let Component::Normal(name) = component
+                && name.len() > self.max_filename_length
+            {
+                return Some(format!(
+                    "name of {} bytes exceeds {} ({})",
+                    name.len(),
+                    MAX_FILENAME_SETTING,
+                    self.max_filename_length
+                ));
+            }
+        }
+
+        if !self.check_fat_names {
+            return None;
+        }
+        let (mount_point, fs_type) = mounts.mount_of(target)?;
+        if !FAT_TYPES.contains(&fs_type) {
+            return None;
+        }
+        let below = target.strip_prefix(mount_point).ok()?;
+        below.components().find_map(|component| match component {
+            Component::Normal(name) => fat_problem(&name.to_string_lossy()).map(|problem| {
+                format!(
+                    "{} on the {} filesystem at {}",
+                    problem,
+                    fs_type,
+                    mount_point.display()
+                )
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// Why Windows cannot use `name`, if it cannot
+fn fat_problem(name: &str) -> Option<String> {
+    if let Some(c) = name
+        .chars()
+        .find(|c| "<>:\"\\|?*".contains(*c) || c.is_control())
+    {
+        return Some(format!("'{}' contains {:?}", name, c));
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Some(format!("'{}' ends in a dot or space", name));
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return Some(format!("'{}' is a reserved device name", name));
+    }
+    None
+}
+
+/// Mounted filesystems and their types
+#[derive(Debug, Clone, Default)]
+pub struct MountTable {
+    mounts: Vec<(PathBuf, String)>,
+}
+
+impl MountTable {
+    /// The mounts of the running system, or none if `/proc/mounts` cannot be read
+    pub fn load() -> Self {
+        match std::fs::read_to_string("/proc/mounts") {
+            Ok(text) => Self::parse(&text),
+            Err(e) => {
+                debug!("Cannot read /proc/mounts: {}", e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Parse a table in `/proc/mounts` format
+    pub fn parse(text: &str) -> Self {
+        let mounts = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let mount_point = fields.nth(1)?;
+                let fs_type = fields.next()?;
+                Some((PathBuf::from(unescape(mount_point)), fs_type.to_string()))
+            })
+            .collect();
+        Self { mounts }
+    }
+
+    /// The mount point and filesystem type `path` lands on
+    ///
+    /// The deepest mount point wins; of several on the same point, the last.
+    pub fn mount_of(&self, path: &Path) -> Option<(&Path, &str)> {
+        let mut found: Option<&(PathBuf, String)> = None;
+        for mount in &self.mounts {
+            if path.starts_with(&mount.0)
+                && found
+                    .is_none_or(|best| mount.0.components().count() >= best.0.components().count())
+            {
+                found = Some(mount);
+            }
+        }
+        found.map(|(mount_point, fs_type)| (mount_point.as_path(), fs_type.as_str()))
+    }
+}
+
+/// Undo the octal escapes `/proc/mounts` uses for spaces, tabs and backslashes
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        match rest
+            .get(i + 1..i + 4)
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok())
+        {
+            Some(byte) => {
+                out.push(byte as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTS: &str = "\
+/dev/sda2 / ext4 rw,relatime 0 0
+/dev/sda1 /boot/efi vfat rw,fmask=0077 0 0
+/dev/sdb1 /mnt/usb\\040stick vfat rw 0 0
+";
+
+    #[test]
+    fn test_mount_table() {
+        let mounts = MountTable::parse(MOUNTS);
+        assert_eq!(
+            mounts.mount_of(Path::new("/usr/bin/bash")),
+            Some((Path::new("/"), "ext4"))
+        );
+        assert_eq!(
+            mounts.mount_of(Path::new("/boot/efi/EFI/BOOT")),
+            Some((Path::new("/boot/efi"), "vfat"))
+        );
+        assert_eq!(
+            mounts.mount_of(Path::new("/boot/efiboot")),
+            Some((Path::new("/"), "ext4"))
+        );
+        assert_eq!(
+            mounts.mount_of(Path::new("/mnt/usb stick/a")).unwrap().1,
+            "vfat"
+        );
+        assert_eq!(MountTable::default().mount_of(Path::new("/usr")), None);
+    }
+
+    #[test]
+    fn test_overlong_paths_are_rejected() {
+        let limits = PathLimits::default();
+        let mounts = MountTable::parse(MOUNTS);
+        let root = Path::new("/");
+        assert_eq!(limits.check(&mounts, root, "bash", "/usr/bin/bash"), None);
+
+        let long_name = format!("/usr/share/doc/{}", "a".repeat(256));
+        let violation = limits.check(&mounts, root, "docs", &long_name).unwrap();
+        assert_eq!(violation.package, "docs");
+        assert!(
+            violation
+                .problem
+                .contains("name of 256 bytes exceeds max_filename_length (255)"),
+            "{}",
+            violation
+        );
+
+        let deep = format!("/usr/share{}", "/directory".repeat(410));
+        let violation = limits.check(&mounts, root, "deep", &deep).unwrap();
+        assert!(
+            violation.problem.contains("exceeds max_path_length (4096)"),
+            "{}",
+            violation
+        );
+
+        // The root counts towards the total
+        let tight = PathLimits {
+            max_path_length: 20,
+            ..PathLimits::default()
+        };
+        assert_eq!(tight.check(&mounts, root, "bash", "/usr/bin/bash"), None);
+        assert!(
+            tight
+                .check(
+                    &mounts,
+                    Path::new("/var/roots/v42"),
+                    "bash",
+                    "/usr/bin/bash"
+                )
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_fat_names_only_on_vfat() {
+        let limits = PathLimits::default();
+        let mounts = MountTable::parse(MOUNTS);
+        let root = Path::new("/");
+
+        for (path, problem) in [
+            ("/boot/efi/EFI/con.cfg", "reserved device name"),
+            ("/boot/efi/EFI/LPT1", "reserved device name"),
+            ("/boot/efi/EFI/grub:2.cfg", "contains ':'"),
+            ("/boot/efi/EFI/trailing.", "ends in a dot or space"),
+        ] {
+            let violation = limits.check(&mounts, root, "grub", path).unwrap();
+            assert!(violation.problem.contains(problem), "{}", violation);
+            assert!(
+                violation.problem.contains("vfat filesystem at /boot/efi"),
+                "{}",
+                violation
+            );
+        }
+        assert_eq!(
+            limits.check(&mounts, root, "grub", "/boot/efi/EFI/BOOT/BOOTX64.EFI"),
+            None
+        );
+        assert_eq!(limits.check(&mounts, root, "grub", "/etc/grub:2.cfg"), None);
+        assert_eq!(
+            limits.check(&mounts, root, "coreutils", "/usr/share/man/con.1"),
+            None
+        );
+
+        let off = PathLimits {
+            check_fat_names: false,
+            ..PathLimits::default()
+        };
+        assert_eq!(
+            off.check(&mounts, root, "grub", "/boot/efi/EFI/con.cfg"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_path_limit_settings() {
+        assert!(PathLimits::validate(MAX_FILENAME_SETTING, "143").unwrap());
+        assert!(PathLimits::validate(MAX_PATH_SETTING, "0").is_err());
+        assert!(PathLimits::validate(FAT_NAMES_SETTING, "yes").is_err());
+        assert!(!PathLimits::validate("max_threads", "4").unwrap());
+        assert_eq!(
+            PathLimits::default_value(FAT_NAMES_SETTING).unwrap(),
+            "true"
+        );
+    }
+}
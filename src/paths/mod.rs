@@ -0,0 +1,530 @@
+// src/paths/mod.rs
+
+//! On-disk layout of a Conary installation
+//!
+//! Everything lives next to the database unless a setting says otherwise:
+//! - `<db dir>/objects` - CAS objects, overridden by the `objects_path` setting
+//! - `<db dir>/tmp` - scratch space for downloads and delta reconstruction
+//! - `<db dir>/conary.lock` - held by operations that change the system
+//! - `<db dir>/pending` - files of staged changesets, one tree per changeset,
+//!   when the `staged_deploy` setting is on (see [`crate::staged`])
+//!
+//! The install root is resolved once, when the layout is built, so symlinked
+//! roots (`/var/roots/current -> /var/roots/v42`) are followed the same way by
+//! every component that touches the root.
+//!
+//! # Stored paths
+//!
+//! File paths from packages are raw bytes and need not be valid UTF-8. The
+//! database stores them as text: valid UTF-8 is kept as is, while each byte
+//! that is not part of a valid UTF-8 sequence, and every `%`, is written as
+//! `%XX` (uppercase hex). Use [`encode_path`] when recording a path and
+//! [`decode_path`] or [`under_root`] when touching the filesystem.
+//!
+//! # Target filesystems
+//!
+//! [`PathLimits`] checks, before an install deploys anything, that every
+//! target path fits the name and length limits of the filesystem it lands
+//! on, with Windows name rules for vfat mounts found in the [`MountTable`].
+
+mod limits;
+
+pub use limits::{
+    FAT_NAMES_SETTING, MAX_FILENAME_SETTING, MAX_PATH_SETTING, MountTable, PathLimits,
+    PathViolation,
+};
+
+use crate::db::models::Setting;
+use crate::error::{Error, Result};
+use crate::filesystem::CasStore;
+use rusqlite::Connection;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
+
+/// Setting holding the absolute path of the CAS objects directory
+pub const OBJECTS_PATH_SETTING: &str = "objects_path";
+
+/// Setting that stages installs in the pending directory instead of deploying them
+pub const STAGED_DEPLOY_SETTING: &str = "staged_deploy";
+
+/// Name of the lock file in the data directory
+const LOCK_FILE: &str = "conary.lock";
+
+/// Resolved locations of the database, CAS, scratch directories and install root
+#[derive(Debug, Clone)]
+pub struct Layout {
+    data_dir: PathBuf,
+    objects_dir: PathBuf,
+    install_root: PathBuf,
+    staged_deploy: bool,
+}
+
+impl Layout {
+    /// Resolve the layout for the database at `db_path`
+    pub fn load(conn: &Connection, db_path: &str) -> Result<Self> {
+        let data_dir = Path::new(db_path)
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf();
+        let objects_dir = match Setting::get(conn, OBJECTS_PATH_SETTING)? {
+            Some(path) => PathBuf::from(path),
+            None => data_dir.join("objects"),
+        };
+        let staged_deploy = Setting::get(conn, STAGED_DEPLOY_SETTING)?.as_deref() == Some("true");
+
+        Ok(Self {
+            data_dir,
+            objects_dir,
+            install_root: PathBuf::from("/"),
+            staged_deploy,
+        })
+    }
+
+    /// Use `root` as the install root, creating it if needed
+    ///
+    /// The root is canonicalized here and nowhere else, so a symlinked root
+    /// resolves to the same directory for deployment, verification and
+    /// scriptlets.
+    pub fn with_root(mut self, root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref();
+        fs::create_dir_all(root)?;
+        self.install_root = fs::canonicalize(root)?;
+        Ok(self)
+    }
+
+    /// Directory holding the database
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// CAS objects directory
+    pub fn objects_dir(&self) -> &Path {
+        &self.objects_dir
+    }
+
+    /// Install root, canonicalized
+    pub fn install_root(&self) -> &Path {
+        &self.install_root
+    }
+
+    /// Scratch directory for downloads and delta reconstruction
+    pub fn temp_dir(&self) -> PathBuf {
+        self.data_dir.join("tmp")
+    }
+
+    /// Directory holding the pending tree of each staged changeset
+    pub fn pending_dir(&self) -> PathBuf {
+        self.data_dir.join("pending")
+    }
+
+    /// Whether installs are staged for `apply-pending` rather than deployed
+    pub fn staged_deploy(&self) -> bool {
+        self.staged_deploy
+    }
+
+    /// Take the operation lock, failing if another operation holds it
+    ///
+    /// The lock is released when the returned guard is dropped.
+    pub fn lock(&self) -> Result<OperationLock> {
+        let path = self.data_dir.join(LOCK_FILE);
+        let file = File::create(&path)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            // SAFETY: flock only inspects the descriptor, which `file` keeps open
+            let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+            if rc != 0 {
+                return Err(Error::ConflictError(format!(
+                    "another conary operation holds {}",
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(OperationLock { _file: file })
+    }
+
+    /// Move all CAS objects to `new_dir` and record the new location
+    ///
+    /// Every object is copied and verified before the setting changes, so an
+    /// interrupted relocation leaves the old store in use and intact. The old
+    /// directory is removed afterwards. Callers should hold the operation lock.
+    /// Returns the number of objects moved.
+    pub fn relocate_objects(&mut self, conn: &Connection, new_dir: &Path) -> Result<usize> {
+        if !new_dir.is_absolute() {
+            return Err(Error::InitError(format!(
+                "Objects directory must be an absolute path: {}",
+                new_dir.display()
+            )));
+        }
+
+        let old_store = CasStore::new(&self.objects_dir)?;
+        let new_store = CasStore::new(new_dir)?;
+        let old_dir = fs::canonicalize(&self.objects_dir)?;
+        let new_dir = fs::canonicalize(new_dir)?;
+        if old_dir.starts_with(&new_dir) || new_dir.starts_with(&old_dir) {
+            // Only removes the directory if it was just created empty
+            let _ = fs::remove_dir(&new_dir);
+            return Err(Error::ConflictError(format!(
+                "Cannot relocate objects from {} to {}: the directories overlap",
+                old_dir.display(),
+                new_dir.display()
+            )));
+        }
+
+        let moved = old_store.copy_to(&new_store)?;
+        Setting::set(conn, OBJECTS_PATH_SETTING, &new_dir.to_string_lossy())?;
+        info!("Objects directory moved to {}", new_dir.display());
+
+        if let Err(e) = fs::remove_dir_all(&old_dir) {
+            warn!(
+                "Could not remove old objects directory {}: {}",
+                old_dir.display(),
+                e
+            );
+        }
+
+        self.objects_dir = new_dir;
+        Ok(moved)
+    }
+}
+
+/// Guard for the operation lock, see [`Layout::lock`]
+pub struct OperationLock {
+    _file: File,
+}
+
+/// Encode a path for storage in the database, see [Stored paths](self#stored-paths)
+pub fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for chunk in path_bytes(path).utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '%' {
+                encoded.push_str("%25");
+            } else {
+                encoded.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Decode a path stored with [`encode_path`]
+///
+/// A `%` not followed by two hex digits is taken literally.
+pub fn decode_path(stored: &str) -> PathBuf {
+    let bytes = stored.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = stored.get(i + 1..i + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+        {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    path_from_bytes(decoded)
+}
+
+/// Location of a stored package path under an install root
+///
+/// The path is joined as it is; use [`resolve_under_root`] before writing.
+pub fn under_root(root: &Path, stored: &str) -> PathBuf {
+    root.join(decode_path(stored.trim_start_matches('/')))
+}
+
+/// Most symlinks followed while resolving one path, as with Linux's ELOOP
+const MAX_SYMLINKS: usize = 40;
+
+/// Whether a stored package path has components that climb out of any root
+pub fn escapes_root(stored: &str) -> bool {
+    decode_path(stored.trim_start_matches('/'))
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Location of a stored package path under an install root, to write to
+///
+/// Paths with `..` components are refused. Symlinks among the parent
+/// directories are followed the way they would be if `root` were the root
+/// directory: an absolute target starts over at `root`, and `..` goes no
+/// higher than it. Links like merged-usr's `/lib -> usr/lib` keep working,
+/// while one pointing outside the root, whether shipped by a package or
+/// already there, is never written through. The last component is not
+/// followed, since it is what gets replaced.
+pub fn resolve_under_root(root: &Path, stored: &str) -> Result<PathBuf> {
+    let outside = || {
+        Error::IoError(format!(
+            "Refusing {}: the path leads outside {}",
+            stored,
+            root.display()
+        ))
+    };
+    if escapes_root(stored) {
+        return Err(outside());
+    }
+    let relative = decode_path(stored.trim_start_matches('/'));
+    let mut components: Vec<OsString> = relative
+        .components()
+        .map(|c| c.as_os_str().to_os_string())
+        .collect();
+    components.retain(|component| component != ".");
+    let Some(last) = components.pop() else {
+        return Ok(root.to_path_buf());
+    };
+
+    // Components still to walk; `None` goes up one level
+    let mut pending: std::collections::VecDeque<Option<OsString>> =
+        components.into_iter().map(Some).collect();
+    let mut resolved = root.to_path_buf();
+    let mut depth = 0;
+    let mut followed = 0;
+    while let Some(next) = pending.pop_front() {
+        let Some(component) = next else {
+            if depth > 0 {
+                resolved.pop();
+                depth -= 1;
+            }
+            continue;
+        };
+        let candidate = resolved.join(&component);
+        if !fs::symlink_metadata(&candidate).is_ok_and(|metadata| metadata.is_symlink()) {
+            resolved = candidate;
+            depth += 1;
+            continue;
+        }
+
+        followed += 1;
+        if followed > MAX_SYMLINKS {
+            return Err(Error::IoError(format!(
+                "Refusing {}: too many levels of symbolic links",
+                stored
+            )));
+        }
+        let target = fs::read_link(&candidate)?;
+        if target.is_absolute() {
+            resolved = root.to_path_buf();
+            depth = 0;
+        }
+        for component in target.components().rev() {
+            match component {
+                Component::Normal(name) => pending.push_front(Some(name.to_os_string())),
+                Component::ParentDir => pending.push_front(None),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+    }
+    Ok(resolved.join(last))
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> &[u8] {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> &[u8] {
+    // Paths are Unicode on other platforms; anything else is replaced
+    path.to_str().unwrap_or_default().as_bytes()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn create_test_db(dir: &Path) -> (String, Connection) {
+        let db_path = dir.join("conary.db").to_string_lossy().to_string();
+        crate::db::init(&db_path).unwrap();
+        let conn = crate::db::open(&db_path).unwrap();
+        (db_path, conn)
+    }
+
+    #[test]
+    fn test_layout_defaults_next_to_database() {
+        let temp = TempDir::new().unwrap();
+        let (db_path, conn) = create_test_db(temp.path());
+
+        let layout = Layout::load(&conn, &db_path).unwrap();
+        assert_eq!(layout.objects_dir(), temp.path().join("objects"));
+        assert_eq!(layout.temp_dir(), temp.path().join("tmp"));
+
+        Setting::set(&conn, OBJECTS_PATH_SETTING, "/srv/conary-objects").unwrap();
+        let layout = Layout::load(&conn, &db_path).unwrap();
+        assert_eq!(layout.objects_dir(), Path::new("/srv/conary-objects"));
+    }
+
+    #[test]
+    fn test_relocate_populated_store() {
+        let temp = TempDir::new().unwrap();
+        let (db_path, conn) = create_test_db(temp.path());
+        let mut layout = Layout::load(&conn, &db_path).unwrap();
+
+        let store = CasStore::new(layout.objects_dir()).unwrap();
+        let hashes: Vec<String> = (0..20)
+            .map(|i| store.store(format!("object {}", i).as_bytes()).unwrap())
+            .collect();
+
+        let new_dir = temp.path().join("elsewhere/objects");
+        let moved = layout.relocate_objects(&conn, &new_dir).unwrap();
+        assert_eq!(moved, 20);
+        assert!(!temp.path().join("objects").exists());
+
+        // A freshly loaded layout reads from the new location
+        let layout = Layout::load(&conn, &db_path).unwrap();
+        assert_eq!(layout.objects_dir(), fs::canonicalize(&new_dir).unwrap());
+        let store = CasStore::new(layout.objects_dir()).unwrap();
+        for (i, hash) in hashes.iter().enumerate() {
+            assert_eq!(
+                store.retrieve(hash).unwrap(),
+                format!("object {}", i).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_relocate_refuses_overlapping_dirs() {
+        let temp = TempDir::new().unwrap();
+        let (db_path, conn) = create_test_db(temp.path());
+        let mut layout = Layout::load(&conn, &db_path).unwrap();
+
+        let nested = temp.path().join("objects/nested");
+        assert!(layout.relocate_objects(&conn, &nested).is_err());
+        assert!(
+            layout
+                .relocate_objects(&conn, Path::new("relative"))
+                .is_err()
+        );
+        assert_eq!(layout.objects_dir(), temp.path().join("objects"));
+    }
+
+    #[test]
+    fn test_path_encoding_round_trips() {
+        assert_eq!(encode_path(Path::new("/usr/bin/tool")), "/usr/bin/tool");
+        assert_eq!(encode_path(Path::new("/srv/100%")), "/srv/100%25");
+        assert_eq!(decode_path("/srv/100%25"), Path::new("/srv/100%"));
+        assert_eq!(decode_path("/srv/100%"), Path::new("/srv/100%"));
+        assert_eq!(
+            under_root(Path::new("/mnt/root"), "/usr/bin/tool"),
+            Path::new("/mnt/root/usr/bin/tool")
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let raw = Path::new(std::ffi::OsStr::from_bytes(b"/usr/share/caf\xe9 \xff%"));
+            let encoded = encode_path(raw);
+            assert_eq!(encoded, "/usr/share/caf%E9 %FF%25");
+            assert_eq!(decode_path(&encoded), raw);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_under_root_stays_inside() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("root");
+        let outside = temp.path().join("outside");
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::create_dir(&outside).unwrap();
+
+        assert_eq!(
+            resolve_under_root(&root, "/usr/bin/tool").unwrap(),
+            root.join("usr/bin/tool")
+        );
+        for path in ["/../escape", "/usr/../../etc/passwd", "usr/lib/.."] {
+            let err = resolve_under_root(&root, path).unwrap_err().to_string();
+            assert!(err.contains("leads outside"), "{}: {}", path, err);
+            assert!(escapes_root(path));
+        }
+
+        // Merged-usr links are followed inside the root
+        std::os::unix::fs::symlink("usr/lib", root.join("lib")).unwrap();
+        assert_eq!(
+            resolve_under_root(&root, "/lib/libc.so").unwrap(),
+            root.join("usr/lib/libc.so")
+        );
+
+        // Links pointing out of the root land inside it instead
+        std::os::unix::fs::symlink(&outside, root.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("../../../..", root.join("usr/lib/up")).unwrap();
+        let absolute = resolve_under_root(&root, "/absolute/file").unwrap();
+        assert!(
+            absolute.starts_with(&root) && absolute.ends_with("file"),
+            "{}",
+            absolute.display()
+        );
+        assert_eq!(
+            resolve_under_root(&root, "/usr/lib/up/etc/passwd").unwrap(),
+            root.join("etc/passwd")
+        );
+
+        // The last component is replaced, not followed
+        assert_eq!(
+            resolve_under_root(&root, "/absolute").unwrap(),
+            root.join("absolute")
+        );
+
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+        assert!(
+            resolve_under_root(&root, "/loop/file")
+                .unwrap_err()
+                .to_string()
+                .contains("too many levels")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_root_resolves_once() {
+        let temp = TempDir::new().unwrap();
+        let (db_path, conn) = create_test_db(temp.path());
+        let real = temp.path().join("v42");
+        fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink(&real, temp.path().join("current")).unwrap();
+
+        let layout = Layout::load(&conn, &db_path)
+            .unwrap()
+            .with_root(temp.path().join("current"))
+            .unwrap();
+        assert_eq!(layout.install_root(), fs::canonicalize(&real).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_is_exclusive() {
+        let temp = TempDir::new().unwrap();
+        let (db_path, conn) = create_test_db(temp.path());
+        let layout = Layout::load(&conn, &db_path).unwrap();
+
+        let guard = layout.lock().unwrap();
+        assert!(matches!(layout.lock(), Err(Error::ConflictError(_))));
+        drop(guard);
+        assert!(layout.lock().is_ok());
+    }
+}
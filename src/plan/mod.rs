@@ -0,0 +1,1012 @@
+// src/plan/mod.rs
+
+//! Working out a transaction before anything is changed
+//!
+//! Install, update, downgrade, cascading removal, snapshot restore and import
+//! all come down to the same steps: pick packages from the repositories,
+//! pull in what they are missing, put the result in order and check the
+//! installed set as it will be afterwards. A [`Planner`] takes the requests
+//! of one transaction and does those steps once. The [`Plan`] it returns
+//! lists the operations in the order they run, what has to be downloaded,
+//! the conflicts found and anything worth a warning.
+//!
+//! Plans serialize, so a dry run can print one as JSON and a journal or a
+//! lock can keep it; [`Installer::execute`](crate::Installer::execute)
+//! carries one out. `install` and `update` are planned this way.
+//!
+//! ```no_run
+//! use conary::plan::{PlanOptions, Planner, Request};
+//!
+//! let conn = conary::db::open("/var/lib/conary/conary.db")?;
+//! let mut planner = Planner::new(&conn, PlanOptions::default());
+//! planner.request(Request::Install { name: "nginx".to_string(), version: None });
+//! planner.request(Request::Remove { name: "httpd".to_string(), version: None });
+//! let plan = planner.plan()?;
+//! println!("{}", serde_json::to_string_pretty(&plan).unwrap());
+//! # Ok::<(), conary::Error>(())
+//! ```
+
+use crate::db::models::{Repository, RepositoryPackage, Trove};
+use crate::error::{Error, Result};
+use crate::installer::{self, PreparedInstall, UpdatePlan, UpdateRequest, plan_updates};
+use crate::repository::{self, Companion, PackageSelector, PackageWithRepo, SelectionOptions};
+use crate::resolver::{Conflict, ProposedChange, Resolver};
+use crate::version;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Most levels of dependencies followed from a requested package
+const MAX_DEPENDENCY_DEPTH: usize = 10;
+
+/// One thing asked of a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// Install the best repository package called `name`, or that version of it
+    Install {
+        name: String,
+        version: Option<String>,
+    },
+    /// Take the installed package `name` off, or that installed version of it
+    Remove {
+        name: String,
+        version: Option<String>,
+    },
+    /// Move installed packages to the newest version their repositories
+    /// carry; only `name` when given
+    Upgrade { name: Option<String> },
+    /// Install the `companion` package of the installed package `name`, at
+    /// the installed version
+    Companion { name: String, companion: Companion },
+}
+
+/// Policies a plan is drawn up under
+#[derive(Debug, Clone, Default)]
+pub struct PlanOptions {
+    /// Take packages from this repository only; dependencies the others do
+    /// not provide may still come from it when it is explicit-only
+    pub repository: Option<String>,
+    /// Architecture of requested packages; the host's when `None`
+    pub architecture: Option<String>,
+    /// Leave out requested packages whose version is already installed
+    pub skip_installed: bool,
+    /// Let upgrades switch to packages declaring `replaces`
+    pub allow_replaces: bool,
+    /// Only upgrade to versions a security advisory covers
+    pub security: bool,
+    /// Also install these companions of requested repository packages,
+    /// where their repositories have them
+    pub companions: Vec<Companion>,
+}
+
+/// A package by name, version and architecture
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedPackage {
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+}
+
+/// An installed trove a plan takes off or replaces
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedTrove {
+    pub trove_id: i64,
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+}
+
+/// Where a package being installed comes from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Source {
+    /// A package file on disk
+    File { path: PathBuf },
+    /// A package of a synced repository
+    Repository {
+        repository: String,
+        package_id: i64,
+        /// What it was asked for by: the requested name, or the name of the
+        /// requirement it satisfies for a dependency
+        requested_as: String,
+        /// Architecture it was picked for as a dependency, when not the host's
+        resolved_for: Option<String>,
+    },
+}
+
+/// One step of a plan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Operation {
+    /// Put a package on
+    Install {
+        #[serde(flatten)]
+        package: PlannedPackage,
+        source: Source,
+        /// Pulled in for another package rather than requested
+        dependency: bool,
+        /// The trove it takes the place of; without one, an installed older
+        /// version of the same package is still upgraded
+        replaces: Option<PlannedTrove>,
+    },
+    /// Take an installed trove off
+    Remove {
+        #[serde(flatten)]
+        trove: PlannedTrove,
+    },
+}
+
+/// A package file a plan fetches from a repository
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Download {
+    pub name: String,
+    pub version: String,
+    pub repository: String,
+    pub url: String,
+    /// Size of the full package
+    pub size: i64,
+    /// Size of the delta from the installed version, tried first
+    pub delta_size: Option<i64>,
+}
+
+/// Everything a transaction will do, worked out before it starts
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    pub requests: Vec<Request>,
+    /// In the order they run: dependencies, requested installs, upgrades
+    /// and then removals
+    pub operations: Vec<Operation>,
+    pub downloads: Vec<Download>,
+    /// Requirements the installed set would fail afterwards; a plan with
+    /// any is not executed
+    pub conflicts: Vec<Conflict>,
+    pub warnings: Vec<String>,
+    /// Requested packages left out because their version is installed
+    pub unchanged: Vec<PlannedPackage>,
+    /// Requirements looked up in the repositories, besides those the
+    /// requested packages meet themselves
+    pub requirements_checked: usize,
+    /// How upgrade requests picked their new versions
+    pub updates: UpdatePlan,
+    /// Parsed packages of install operations, by operation index
+    #[serde(skip)]
+    parsed: Vec<Option<PreparedInstall>>,
+}
+
+impl Plan {
+    /// The parsed package of the install operation at `index`, once it is on disk
+    pub fn parsed(&self, index: usize) -> Option<&PreparedInstall> {
+        self.parsed.get(index).and_then(Option::as_ref)
+    }
+
+    pub(crate) fn set_parsed(&mut self, index: usize, prepared: PreparedInstall) {
+        if self.parsed.len() <= index {
+            self.parsed.resize_with(index + 1, || None);
+        }
+        self.parsed[index] = Some(prepared);
+    }
+
+    pub(crate) fn take_parsed(&mut self, index: usize) -> Option<PreparedInstall> {
+        self.parsed.get_mut(index).and_then(Option::take)
+    }
+
+    /// Install operations pulled in as dependencies
+    pub fn dependencies(&self) -> impl Iterator<Item = &Operation> {
+        self.operations.iter().filter(|operation| {
+            matches!(
+                operation,
+                Operation::Install {
+                    dependency: true,
+                    ..
+                }
+            )
+        })
+    }
+}
+
+/// Draws up the plan of one transaction
+///
+/// Requests are added one by one and planned together, so dependencies are
+/// resolved for the whole set and a package removed by one request is seen
+/// missing by the others.
+pub struct Planner<'a> {
+    conn: &'a Connection,
+    options: PlanOptions,
+    requests: Vec<Request>,
+    files: Vec<PreparedInstall>,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(conn: &'a Connection, options: PlanOptions) -> Self {
+        Self {
+            conn,
+            options,
+            requests: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn request(&mut self, request: Request) -> &mut Self {
+        self.requests.push(request);
+        self
+    }
+
+    /// Install a package file parsed by `Installer::prepare`
+    pub fn install_file(&mut self, prepared: PreparedInstall) -> &mut Self {
+        self.files.push(prepared);
+        self
+    }
+
+    /// Work out the plan
+    ///
+    /// Fails when a requested package cannot be found or its dependencies
+    /// cannot be resolved, when a package is requested twice, and when a
+    /// package file conflicts with installed files. Requirements broken by
+    /// the result are not an error but the plan's `conflicts`.
+    pub fn plan(self) -> Result<Plan> {
+        let Self {
+            conn,
+            options,
+            requests,
+            files,
+        } = self;
+        let mut plan = Plan {
+            requests: requests.clone(),
+            ..Default::default()
+        };
+        let mut requested: HashSet<String> = HashSet::new();
+
+        let mut local = Vec::new();
+        for prepared in files {
+            let package = prepared.package();
+            let planned = PlannedPackage {
+                name: package.name().to_string(),
+                version: package.version().to_string(),
+                architecture: package.architecture().map(str::to_string),
+            };
+            claim(&mut requested, &planned.name)?;
+            if options.skip_installed && is_installed(conn, &planned)? {
+                plan.unchanged.push(planned);
+            } else {
+                local.push(prepared);
+            }
+        }
+
+        let mut from_repo: Vec<(String, PackageWithRepo)> = Vec::new();
+        let mut removals = Vec::new();
+        for request in &requests {
+            match request {
+                Request::Install { name, version } => {
+                    let selection = SelectionOptions {
+                        version: version.clone(),
+                        repository: options.repository.clone(),
+                        architecture: options.architecture.clone(),
+                    };
+                    let selected = PackageSelector::find_best_package(conn, name, &selection)?;
+                    info!(
+                        "Found package {} {} in repository {} (priority {})",
+                        selected.package.name,
+                        selected.package.version,
+                        selected.repository.name,
+                        selected.repository.priority
+                    );
+                    let planned = PlannedPackage {
+                        name: selected.package.name.clone(),
+                        version: selected.package.version.clone(),
+                        architecture: selected.package.architecture.clone(),
+                    };
+                    claim(&mut requested, &planned.name)?;
+                    if options.skip_installed && is_installed(conn, &planned)? {
+                        plan.unchanged.push(planned);
+                    } else {
+                        from_repo.push((name.clone(), selected));
+                    }
+                }
+                Request::Remove { name, version } => {
+                    claim(&mut requested, name)?;
+                    removals.push(installer::trove_to_remove(conn, name, version.as_deref())?);
+                }
+                Request::Upgrade { name } => {
+                    let updates = plan_updates(
+                        conn,
+                        &UpdateRequest {
+                            package: name.as_deref(),
+                            repo: options.repository.as_deref(),
+                            allow_replaces: options.allow_replaces,
+                            security: options.security,
+                        },
+                    )?;
+                    for entry in &updates.entries {
+                        claim(&mut requested, &entry.installed.name)?;
+                    }
+                    for (trove, replacement) in &updates.replacements {
+                        plan.warnings.push(format!(
+                            "{} {} replaces {}; rerun with --allow-replaces to switch",
+                            replacement.name, replacement.version, trove.name
+                        ));
+                    }
+                    for (trove, candidate) in &updates.locked {
+                        plan.warnings.push(format!(
+                            "{} {} -> {} skipped (locked)",
+                            trove.name, trove.version, candidate.version
+                        ));
+                    }
+                    plan.updates.checked += updates.checked;
+                    plan.updates.entries.extend(updates.entries);
+                    plan.updates.locked.extend(updates.locked);
+                    plan.updates.replacements.extend(updates.replacements);
+                    plan.updates.not_security += updates.not_security;
+                }
+                Request::Companion { name, companion } => {
+                    for found in installed_companions(conn, name, *companion)? {
+                        claim(&mut requested, &found.package.name)?;
+                        from_repo.push((found.package.name.clone(), found));
+                    }
+                }
+            }
+        }
+
+        // Companions of the requested packages, at their versions
+        let mut companions = Vec::new();
+        for (_, selected) in &from_repo {
+            for companion in &options.companions {
+                match PackageSelector::find_companion(conn, &selected.package, *companion)? {
+                    Some(found) => companions.push(found),
+                    None => plan.warnings.push(missing_companion(
+                        &selected.package,
+                        &selected.repository,
+                        *companion,
+                    )),
+                }
+            }
+        }
+        for found in companions {
+            if requested.contains(&found.package.name) {
+                continue;
+            }
+            let planned = PlannedPackage {
+                name: found.package.name.clone(),
+                version: found.package.version.clone(),
+                architecture: found.package.architecture.clone(),
+            };
+            claim(&mut requested, &planned.name)?;
+            if options.skip_installed && is_installed(conn, &planned)? {
+                plan.unchanged.push(planned);
+            } else {
+                from_repo.push((planned.name, found));
+            }
+        }
+
+        // Refuse conflicting packages before pulling in any dependencies
+        for prepared in &local {
+            prepared.check_conflicts(conn)?;
+        }
+
+        let dependencies =
+            resolve_dependencies(conn, &options, &requested, &local, &from_repo, &mut plan)?;
+
+        let mut changes = Vec::new();
+        let fetched = dependencies
+            .iter()
+            .map(|pkg| (pkg, true))
+            .chain(from_repo.iter().map(|pkg| (pkg, false)));
+        for ((requested_as, selected), dependency) in fetched {
+            changes.push(ProposedChange::install(
+                &selected.package.name,
+                &selected.package.version,
+                selected.package.version_scheme(),
+                selected.dependencies(conn)?,
+            ));
+            plan.downloads.push(Download {
+                name: selected.package.name.clone(),
+                version: selected.package.version.clone(),
+                repository: selected.repository.name.clone(),
+                url: selected.package.download_url.clone(),
+                size: selected.package.size,
+                delta_size: None,
+            });
+            plan.operations.push(Operation::Install {
+                package: PlannedPackage {
+                    name: selected.package.name.clone(),
+                    version: selected.package.version.clone(),
+                    architecture: selected.package.architecture.clone(),
+                },
+                source: Source::Repository {
+                    repository: selected.repository.name.clone(),
+                    package_id: selected.package.id.unwrap_or_default(),
+                    requested_as: requested_as.clone(),
+                    resolved_for: selected.resolved_for.clone(),
+                },
+                dependency,
+                replaces: None,
+            });
+        }
+
+        for prepared in local {
+            let package = prepared.package();
+            changes.push(ProposedChange::Install {
+                name: package.name().to_string(),
+                version: package.version().to_string(),
+                scheme: version::VersionScheme::for_format(prepared.format()),
+                requires: package
+                    .dependencies()
+                    .iter()
+                    .map(|dep| (dep.name.clone(), dep.version.clone()))
+                    .collect(),
+            });
+            plan.operations.push(Operation::Install {
+                package: PlannedPackage {
+                    name: package.name().to_string(),
+                    version: package.version().to_string(),
+                    architecture: package.architecture().map(str::to_string),
+                },
+                source: Source::File {
+                    path: prepared.path().to_path_buf(),
+                },
+                dependency: false,
+                replaces: None,
+            });
+            plan.set_parsed(plan.operations.len() - 1, prepared);
+        }
+
+        for entry in &plan.updates.entries {
+            let (trove, candidate) = (&entry.installed, &entry.candidate);
+            if entry.replaces() {
+                changes.push(ProposedChange::Remove {
+                    name: trove.name.clone(),
+                });
+            }
+            changes.push(ProposedChange::install(
+                &candidate.name,
+                &candidate.version,
+                candidate.version_scheme(),
+                &candidate.load_dependencies(conn)?,
+            ));
+            plan.downloads.push(Download {
+                name: candidate.name.clone(),
+                version: candidate.version.clone(),
+                repository: entry.repository.clone(),
+                url: candidate.download_url.clone(),
+                size: candidate.size,
+                delta_size: entry.delta.as_ref().map(|delta| delta.delta_size),
+            });
+            plan.operations.push(Operation::Install {
+                package: PlannedPackage {
+                    name: candidate.name.clone(),
+                    version: candidate.version.clone(),
+                    architecture: candidate.architecture.clone(),
+                },
+                source: Source::Repository {
+                    repository: entry.repository.clone(),
+                    package_id: candidate.id.unwrap_or_default(),
+                    requested_as: candidate.name.clone(),
+                    resolved_for: None,
+                },
+                dependency: false,
+                replaces: (!entry.alongside).then(|| PlannedTrove::from(trove)),
+            });
+        }
+
+        for trove in removals {
+            // Another installed version keeps meeting what the removed one did
+            if Trove::find_by_name(conn, &trove.name)?.len() == 1 {
+                changes.push(ProposedChange::Remove {
+                    name: trove.name.clone(),
+                });
+            }
+            plan.operations.push(Operation::Remove {
+                trove: PlannedTrove::from(&trove),
+            });
+        }
+
+        plan.conflicts = Resolver::new(conn)?.check_transaction(&changes);
+        Ok(plan)
+    }
+}
+
+impl From<&Trove> for PlannedTrove {
+    fn from(trove: &Trove) -> Self {
+        Self {
+            trove_id: trove.id.unwrap_or_default(),
+            name: trove.name.clone(),
+            version: trove.version.clone(),
+            architecture: trove.architecture.clone(),
+        }
+    }
+}
+
+/// Note `name` as requested, refusing it the second time
+fn claim(requested: &mut HashSet<String>, name: &str) -> Result<()> {
+    if !requested.insert(name.to_string()) {
+        return Err(Error::ConflictError(format!(
+            "Package {} is requested more than once",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Whether this exact name, version and architecture is installed
+fn is_installed(conn: &Connection, package: &PlannedPackage) -> Result<bool> {
+    for trove in Trove::find_by_name(conn, &package.name)? {
+        if trove.architecture == package.architecture
+            && installer::installed_as(conn, &trove, &package.version)?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The `companion` packages of each installed version of `name`
+///
+/// A version is matched with the repository package it was installed from,
+/// preferring the repository it came from, and its companion is taken from
+/// there. Fails when no installed version has one.
+fn installed_companions(
+    conn: &Connection,
+    name: &str,
+    companion: Companion,
+) -> Result<Vec<PackageWithRepo>> {
+    let troves = Trove::find_by_name(conn, name)?;
+    if troves.is_empty() {
+        return Err(Error::NotFoundError(format!(
+            "Package '{}' is not installed",
+            name
+        )));
+    }
+
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+    for trove in &troves {
+        let mut sources = Vec::new();
+        for pkg in RepositoryPackage::find_by_name(conn, name)? {
+            if pkg.architecture == trove.architecture
+                && installer::installed_as(conn, trove, &pkg.version)?
+            {
+                sources.push(pkg);
+            }
+        }
+        sources.sort_by_key(|pkg| Some(pkg.repository_id) != trove.origin_repository_id);
+        let Some(source) = sources.into_iter().next() else {
+            missing.push(format!(
+                "{} {} is in no synced repository",
+                name, trove.version
+            ));
+            continue;
+        };
+        match PackageSelector::find_companion(conn, &source, companion)? {
+            Some(companion) => found.push(companion),
+            None => {
+                let repo =
+                    Repository::find_by_id(conn, source.repository_id)?.ok_or_else(|| {
+                        Error::NotFoundError(format!(
+                            "Repository {} not found",
+                            source.repository_id
+                        ))
+                    })?;
+                missing.push(missing_companion(&source, &repo, companion));
+            }
+        }
+    }
+    if found.is_empty() {
+        return Err(Error::NotFoundError(missing.join("; ")));
+    }
+    Ok(found)
+}
+
+/// Why the `companion` of `package` from `repo` is not installed
+fn missing_companion(
+    package: &RepositoryPackage,
+    repo: &Repository,
+    companion: Companion,
+) -> String {
+    format!(
+        "No {} package for {} {} in {} (looked for {})",
+        companion,
+        package.name,
+        package.version,
+        repo.name,
+        companion
+            .names(package.version_scheme(), &package.name)
+            .join(", ")
+    )
+}
+
+/// Repository packages the requested installs are missing, with the
+/// requirement each satisfies
+///
+/// Requirements met by the requested set itself are left out. Each package's
+/// requirements are resolved for its own architecture, and a package needed
+/// by several is listed once.
+fn resolve_dependencies(
+    conn: &Connection,
+    options: &PlanOptions,
+    requested: &HashSet<String>,
+    local: &[PreparedInstall],
+    from_repo: &[(String, PackageWithRepo)],
+    plan: &mut Plan,
+) -> Result<Vec<(String, PackageWithRepo)>> {
+    let provided: HashSet<&str> = requested
+        .iter()
+        .map(String::as_str)
+        .chain(local.iter().flat_map(|prepared| {
+            prepared
+                .package()
+                .provides()
+                .iter()
+                .map(|p| p.name.as_str())
+        }))
+        .collect();
+
+    let mut dep_names: Vec<(String, Option<String>, Vec<String>)> = Vec::new();
+    for prepared in local {
+        let package = prepared.package();
+        let arch = PackageSelector::dependency_architecture(package.architecture(), None);
+        let names = package
+            .dependencies()
+            .iter()
+            .map(|d| d.name.clone())
+            .collect();
+        dep_names.push((package.name().to_string(), arch, names));
+    }
+    for (_, selected) in from_repo {
+        let arch = PackageSelector::dependency_architecture(
+            selected.package.architecture.as_deref(),
+            None,
+        );
+        dep_names.push((
+            selected.package.name.clone(),
+            arch,
+            selected.dependencies(conn)?.to_vec(),
+        ));
+    }
+    for (_, _, names) in &mut dep_names {
+        names.retain(|dep| !provided.contains(version::split_constraint(dep).0));
+        names.sort();
+        names.dedup();
+    }
+    dep_names.retain(|(_, _, names)| !names.is_empty());
+    plan.requirements_checked = dep_names.iter().map(|(_, _, names)| names.len()).sum();
+    if dep_names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    info!(
+        "Resolving {} dependencies transitively...",
+        plan.requirements_checked
+    );
+    let mut dependencies: Vec<(String, PackageWithRepo)> = Vec::new();
+    for (needed_by, arch, names) in &dep_names {
+        let resolved = repository::resolve_dependencies_transitive_in(
+            conn,
+            names,
+            MAX_DEPENDENCY_DEPTH,
+            options.repository.as_deref(),
+            arch.as_deref(),
+        )
+        .map_err(|e| repository::needed_by(e, needed_by))?;
+        // Packages can be needed by several of the requested ones
+        for (name, selected) in resolved {
+            if !dependencies
+                .iter()
+                .any(|(_, other)| other.package.id == selected.package.id)
+            {
+                dependencies.push((name, selected));
+            }
+        }
+    }
+    dependencies.retain(|(_, selected)| !requested.contains(&selected.package.name));
+    Ok(dependencies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::db::models::{DependencyEntry, Repository, RepositoryPackage, TroveType};
+
+    fn setup() -> (tempfile::TempDir, Connection) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("conary.db");
+        db::init(db_path.to_str().unwrap()).unwrap();
+        let conn = db::open(db_path.to_str().unwrap()).unwrap();
+
+        let repo_id = Repository::new("local".to_string(), "https://example.com".to_string())
+            .insert(&conn)
+            .unwrap();
+        let offer = |name: &str, version: &str, deps: &[&str]| {
+            let url = format!("https://example.com/{}-{}.pkg.tar.zst", name, version);
+            let mut pkg = RepositoryPackage::new(
+                repo_id,
+                name.to_string(),
+                version.to_string(),
+                "0".repeat(64),
+                100,
+                url,
+            );
+            pkg.insert(&conn).unwrap();
+            let deps: Vec<String> = deps.iter().map(|dep| dep.to_string()).collect();
+            pkg.insert_dependencies(&conn, &deps).unwrap();
+        };
+        offer("app", "2.0-1", &["libapp >= 1.0"]);
+        offer("libapp", "1.4-1", &[]);
+
+        let install = |name: &str, deps: &[&str]| {
+            let id = Trove::new(name.to_string(), "1.0-1".to_string(), TroveType::Package)
+                .insert(&conn)
+                .unwrap();
+            for dep in deps {
+                DependencyEntry::new(id, dep.to_string(), None, "runtime".to_string(), None)
+                    .insert(&conn)
+                    .unwrap();
+            }
+        };
+        install("legacy", &[]);
+        install("libold", &[]);
+        install("tool", &["libold"]);
+        (temp_dir, conn)
+    }
+
+    fn planned(conn: &Connection, requests: &[Request]) -> Result<Plan> {
+        let mut planner = Planner::new(conn, PlanOptions::default());
+        for request in requests {
+            planner.request(request.clone());
+        }
+        planner.plan()
+    }
+
+    #[test]
+    fn test_plan_mixed_install_and_remove() {
+        let (_temp_dir, conn) = setup();
+        let plan = planned(
+            &conn,
+            &[
+                Request::Install {
+                    name: "app".to_string(),
+                    version: None,
+                },
+                Request::Remove {
+                    name: "legacy".to_string(),
+                    version: None,
+                },
+            ],
+        )
+        .unwrap();
+
+        let steps: Vec<(&str, &str, bool)> = plan
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Install {
+                    package,
+                    dependency,
+                    ..
+                } => ("install", package.name.as_str(), *dependency),
+                Operation::Remove { trove } => ("remove", trove.name.as_str(), false),
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                ("install", "libapp", true),
+                ("install", "app", false),
+                ("remove", "legacy", false)
+            ]
+        );
+        let downloads: Vec<&str> = plan
+            .downloads
+            .iter()
+            .map(|download| download.name.as_str())
+            .collect();
+        assert_eq!(downloads, ["libapp", "app"]);
+        assert_eq!(plan.requirements_checked, 1);
+        assert!(plan.conflicts.is_empty(), "{:?}", plan.conflicts);
+        assert_eq!(plan.dependencies().count(), 1);
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["operations"][0]["operation"], "install");
+        assert_eq!(json["operations"][0]["source"]["requested_as"], "libapp");
+        assert_eq!(json["operations"][2]["operation"], "remove");
+        assert_eq!(json["requests"][1]["request"], "remove");
+    }
+
+    #[test]
+    fn test_plan_reports_removals_breaking_requirements() {
+        let (_temp_dir, conn) = setup();
+        let plan = planned(
+            &conn,
+            &[
+                Request::Remove {
+                    name: "libold".to_string(),
+                    version: None,
+                },
+                Request::Install {
+                    name: "libapp".to_string(),
+                    version: None,
+                },
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            plan.conflicts,
+            [Conflict::MissingPackage {
+                package: "libold".to_string(),
+                required_by: vec!["tool".to_string()]
+            }]
+        );
+
+        // Removing what depends on it as well leaves nothing broken
+        let plan = planned(
+            &conn,
+            &[
+                Request::Remove {
+                    name: "libold".to_string(),
+                    version: None,
+                },
+                Request::Remove {
+                    name: "tool".to_string(),
+                    version: None,
+                },
+            ],
+        )
+        .unwrap();
+        assert!(plan.conflicts.is_empty(), "{:?}", plan.conflicts);
+        assert_eq!(plan.operations.len(), 2);
+    }
+
+    #[test]
+    fn test_plan_adds_companions_at_matching_versions() {
+        let (_temp_dir, conn) = setup();
+        let repo_id = Repository::find_by_name(&conn, "local")
+            .unwrap()
+            .unwrap()
+            .id
+            .unwrap();
+        let offers = [
+            ("app-debug", "1.9-1"),
+            ("app-debug", "2.0-1"),
+            ("tool", "1.0-1"),
+            ("tool", "2.0-1"),
+            ("tool-debug", "1.0-1"),
+            ("tool-debug", "2.0-1"),
+        ];
+        for (name, version) in offers {
+            let url = format!("https://example.com/{}-{}.pkg.tar.zst", name, version);
+            RepositoryPackage::new(
+                repo_id,
+                name.to_string(),
+                version.to_string(),
+                "0".repeat(64),
+                100,
+                url,
+            )
+            .insert(&conn)
+            .unwrap();
+        }
+        let installs = |plan: &Plan| -> Vec<(String, String)> {
+            plan.operations
+                .iter()
+                .filter_map(|operation| match operation {
+                    Operation::Install { package, .. } => {
+                        Some((package.name.clone(), package.version.clone()))
+                    }
+                    Operation::Remove { .. } => None,
+                })
+                .collect()
+        };
+
+        let options = PlanOptions {
+            companions: vec![Companion::Debuginfo, Companion::Docs],
+            ..Default::default()
+        };
+        let mut planner = Planner::new(&conn, options);
+        planner.request(Request::Install {
+            name: "app".to_string(),
+            version: None,
+        });
+        let plan = planner.plan().unwrap();
+        let expected = [
+            ("libapp", "1.4-1"),
+            ("app", "2.0-1"),
+            ("app-debug", "2.0-1"),
+        ];
+        assert_eq!(
+            installs(&plan),
+            expected.map(|(name, version)| (name.to_string(), version.to_string()))
+        );
+        assert_eq!(
+            plan.warnings,
+            ["No documentation package for app 2.0-1 in local (looked for app-docs, app-doc)"]
+        );
+
+        // The debug symbols of what is installed, not of the newest version
+        let plan = planned(
+            &conn,
+            &[Request::Companion {
+                name: "tool".to_string(),
+                companion: Companion::Debuginfo,
+            }],
+        )
+        .unwrap();
+        assert_eq!(
+            installs(&plan),
+            [("tool-debug".to_string(), "1.0-1".to_string())]
+        );
+
+        let err = planned(
+            &conn,
+            &[Request::Companion {
+                name: "legacy".to_string(),
+                companion: Companion::Debuginfo,
+            }],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("legacy 1.0-1 is in no synced repository"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_plan_refuses_repeated_and_unknown_requests() {
+        let (_temp_dir, conn) = setup();
+        let err = planned(
+            &conn,
+            &[
+                Request::Install {
+                    name: "libapp".to_string(),
+                    version: None,
+                },
+                Request::Remove {
+                    name: "libapp".to_string(),
+                    version: None,
+                },
+            ],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("requested more than once"),
+            "{}",
+            err
+        );
+
+        let err = planned(
+            &conn,
+            &[Request::Remove {
+                name: "absent".to_string(),
+                version: None,
+            }],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("'absent' is not installed"),
+            "{}",
+            err
+        );
+
+        // An installed version is left alone, and so are its dependencies
+        Trove::new("app".to_string(), "2.0-1".to_string(), TroveType::Package)
+            .insert(&conn)
+            .unwrap();
+        let mut planner = Planner::new(
+            &conn,
+            PlanOptions {
+                skip_installed: true,
+                ..Default::default()
+            },
+        );
+        planner.request(Request::Install {
+            name: "app".to_string(),
+            version: None,
+        });
+        let plan = planner.plan().unwrap();
+        assert!(plan.operations.is_empty());
+        assert_eq!(plan.unchanged[0].name, "app");
+    }
+}
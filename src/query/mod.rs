@@ -0,0 +1,1118 @@
+// src/query/mod.rs
+
+//! Queries over the installed package database
+//!
+//! Answers questions that span several tables, such as which installed
+//! packages need a given file path. A package can need a path in two ways:
+//! - It declares a dependency on the path (RPM file requirements such as
+//!   `/usr/bin/python3`)
+//! - One of its executables names the path as its interpreter in a shebang
+//!   line (opt-in, since it reads files from the install root)
+//!
+//! It also reports changesets in terms of packages: what one changeset
+//! installed, removed and touched on disk, and which changesets ever
+//! affected a given package.
+//!
+//! It measures duplicate content: regular files installed at several paths
+//! with the same hash, and what deploying them as hardlinks would save.
+//!
+//! It finds orphans: regular files under the install root that no installed
+//! package owns, for adopting an existing system or cleaning up after a
+//! botched operation.
+//!
+//! Finally it gathers everything known about one package, installed or
+//! available from a repository, for `conary info`.
+
+use crate::db::models::{
+    Changeset, DependencyEntry, FileEntry, Flavor, Provenance, ProvideEntry, RemovalReason,
+    RemovedTrove, Repository, RepositoryPackage, Trove,
+};
+use crate::error::{Error, Result};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::mpsc::{SyncSender, sync_channel};
+use tracing::{debug, warn};
+
+/// Longest shebang line read when scanning executables
+const MAX_SHEBANG_LEN: u64 = 256;
+
+/// Walked files queued for lookup before the walk waits for the lookups
+const ORPHAN_QUEUE_LEN: usize = 1024;
+
+/// Why an installed package needs a file path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum RequirementSource {
+    /// The package declares a dependency on the path
+    Declared { dependency: String },
+    /// An executable in the package uses the path as its interpreter
+    Shebang { file: String, interpreter: String },
+}
+
+/// An installed package that needs a file path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileRequirement {
+    pub package: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub source: RequirementSource,
+}
+
+/// Match a path against a glob pattern
+///
+/// Supports `*` (any run of characters, including `/`) and `?` (any single
+/// character), matching SQLite's GLOB for those wildcards. A pattern without
+/// wildcards only matches the exact path.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last '*' absorb one more character and retry
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Read the interpreter named in a file's shebang line, if any
+///
+/// Only the first line is read, and at most `MAX_SHEBANG_LEN` bytes of it.
+fn read_shebang(path: &Path) -> Option<String> {
+    let file = File::open(path).ok()?;
+    let mut reader = BufReader::new(file.take(MAX_SHEBANG_LEN));
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line).ok()?;
+
+    let line = String::from_utf8_lossy(&line);
+    let interpreter = line.strip_prefix("#!")?.split_whitespace().next()?;
+    Some(interpreter.to_string())
+}
+
+/// Find installed packages that need a file path (exact path or glob)
+///
+/// Declared file dependencies are always checked. With `scan_shebangs`, the
+/// executable files of every installed trove are also read from `root` and
+/// their shebang interpreters matched against the pattern.
+pub fn packages_requiring_file(
+    conn: &Connection,
+    root: &Path,
+    path: &str,
+    scan_shebangs: bool,
+) -> Result<Vec<FileRequirement>> {
+    let mut requirements = Vec::new();
+
+    // Declared dependencies; GLOB covers exact matches for wildcard-free paths
+    let mut stmt = conn.prepare(
+        "SELECT t.name, t.version, d.depends_on_name
+         FROM dependencies d
+         JOIN troves t ON t.id = d.trove_id
+         WHERE d.depends_on_name = ?1 OR d.depends_on_name GLOB ?1
+         ORDER BY t.name, t.version",
+    )?;
+    let rows = stmt.query_map([path], |row| {
+        Ok(FileRequirement {
+            package: row.get(0)?,
+            version: row.get(1)?,
+            source: RequirementSource::Declared {
+                dependency: row.get(2)?,
+            },
+        })
+    })?;
+    for row in rows {
+        requirements.push(row?);
+    }
+
+    if scan_shebangs {
+        for trove in Trove::list_all(conn)? {
+            let Some(trove_id) = trove.id else {
+                continue;
+            };
+
+            for file in FileEntry::find_by_trove(conn, trove_id)? {
+                if file.permissions & 0o111 == 0 {
+                    continue;
+                }
+
+                let full_path = crate::paths::under_root(root, &file.path);
+                if let Some(interpreter) = read_shebang(&full_path)
+                    && glob_match(path, &interpreter)
+                {
+                    debug!("{} uses {} as its interpreter", file.path, interpreter);
+                    requirements.push(FileRequirement {
+                        package: trove.name.clone(),
+                        version: trove.version.clone(),
+                        source: RequirementSource::Shebang {
+                            file: file.path,
+                            interpreter,
+                        },
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(requirements)
+}
+
+/// What a changeset did to one version of a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageAction {
+    Installed,
+    Removed,
+    /// Taken off in favor of another version
+    Replaced,
+    RolledBack,
+}
+
+impl From<RemovalReason> for PackageAction {
+    fn from(reason: RemovalReason) -> Self {
+        match reason {
+            RemovalReason::Removed => PackageAction::Removed,
+            RemovalReason::Replaced => PackageAction::Replaced,
+            RemovalReason::RolledBack => PackageAction::RolledBack,
+        }
+    }
+}
+
+/// A package version a changeset put on or took off the system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TroveChange {
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+    pub action: PackageAction,
+}
+
+/// A file change recorded in a changeset's file history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    /// `add`, `modify`, `delete` or `config-kept`, or `dir-to-file`,
+    /// `dir-saved` and `file-to-dir` for directories and files swapped for
+    /// one another, and `dir-mode` for a directory whose mode was tightened
+    pub action: String,
+    pub sha256_hash: Option<String>,
+    pub previous_hash: Option<String>,
+}
+
+/// Everything recorded about one changeset
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangesetReport {
+    pub changeset: Changeset,
+    /// Removals first, then installs, each sorted by name
+    pub troves: Vec<TroveChange>,
+    pub files: Vec<FileChange>,
+}
+
+/// A changeset that affected a package, as listed by [`package_changesets`]
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageEvent {
+    pub changeset: Changeset,
+    #[serde(flatten)]
+    pub change: TroveChange,
+}
+
+fn removal_change(removed: RemovedTrove) -> TroveChange {
+    TroveChange {
+        name: removed.name,
+        version: removed.version,
+        architecture: removed.architecture,
+        action: removed.reason.into(),
+    }
+}
+
+fn install_change(name: String, version: String, architecture: Option<String>) -> TroveChange {
+    TroveChange {
+        name,
+        version,
+        architecture,
+        action: PackageAction::Installed,
+    }
+}
+
+/// Report the troves a changeset installed and removed, and the files it touched
+///
+/// Installed troves include those removed again by a later changeset.
+pub fn changeset_report(conn: &Connection, changeset_id: i64) -> Result<ChangesetReport> {
+    let changeset = Changeset::find_by_id(conn, changeset_id)?
+        .ok_or_else(|| Error::NotFoundError(format!("Changeset {} not found", changeset_id)))?;
+
+    let mut troves: Vec<TroveChange> = RemovedTrove::find_by_changeset(conn, changeset_id)?
+        .into_iter()
+        .map(removal_change)
+        .collect();
+
+    let mut installed: Vec<TroveChange> = Trove::find_by_changeset(conn, changeset_id)?
+        .into_iter()
+        .map(|trove| install_change(trove.name, trove.version, trove.architecture))
+        .chain(
+            RemovedTrove::find_by_installing_changeset(conn, changeset_id)?
+                .into_iter()
+                .map(|removed| install_change(removed.name, removed.version, removed.architecture)),
+        )
+        .collect();
+    installed.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    troves.extend(installed);
+
+    let mut stmt = conn.prepare(
+        "SELECT path, action, sha256_hash, previous_hash
+         FROM file_history WHERE changeset_id = ?1 ORDER BY path",
+    )?;
+    let files = stmt
+        .query_map([changeset_id], |row| {
+            Ok(FileChange {
+                path: row.get(0)?,
+                action: row.get(1)?,
+                sha256_hash: row.get(2)?,
+                previous_hash: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(ChangesetReport {
+        changeset,
+        troves,
+        files,
+    })
+}
+
+/// List every changeset that installed or removed a version of a package
+///
+/// Events are in changeset order; within one changeset (an upgrade) the
+/// removal comes before the install. Removals made before they were
+/// recorded (schema version 17) are not known.
+pub fn package_changesets(conn: &Connection, name: &str) -> Result<Vec<PackageEvent>> {
+    // (changeset, installs sort after removals, change)
+    let mut events: Vec<(i64, bool, TroveChange)> = Vec::new();
+
+    for trove in Trove::find_by_name(conn, name)? {
+        if let Some(changeset_id) = trove.installed_by_changeset_id {
+            events.push((
+                changeset_id,
+                true,
+                install_change(trove.name, trove.version, trove.architecture),
+            ));
+        }
+    }
+    for removed in RemovedTrove::find_by_name(conn, name)? {
+        if let Some(changeset_id) = removed.installed_by_changeset_id {
+            events.push((
+                changeset_id,
+                true,
+                install_change(
+                    removed.name.clone(),
+                    removed.version.clone(),
+                    removed.architecture.clone(),
+                ),
+            ));
+        }
+        events.push((removed.changeset_id, false, removal_change(removed)));
+    }
+    events.sort_by(|a, b| (a.0, a.1, &a.2.version).cmp(&(b.0, b.1, &b.2.version)));
+
+    let mut history = Vec::new();
+    for (changeset_id, _, change) in events {
+        if let Some(changeset) = Changeset::find_by_id(conn, changeset_id)? {
+            history.push(PackageEvent { changeset, change });
+        }
+    }
+
+    Ok(history)
+}
+
+/// An installed path shipping duplicated content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DuplicatePath {
+    pub path: String,
+    pub package: String,
+}
+
+/// Content installed at more than one path
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateBlob {
+    pub sha256_hash: String,
+    pub size: i64,
+    /// In install order; the first is counted as the original
+    pub paths: Vec<DuplicatePath>,
+    /// size × (copies − 1)
+    pub wasted_bytes: i64,
+}
+
+/// Extra copies of shared content installed by one package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageDuplicates {
+    pub package: String,
+    pub duplicate_files: usize,
+    pub wasted_bytes: i64,
+}
+
+/// Duplicate content across installed packages
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateReport {
+    /// Hashes installed at more than one path
+    pub duplicated_blobs: usize,
+    /// Installed copies beyond the first of each duplicated hash
+    pub duplicate_files: usize,
+    /// Bytes saved by hardlinking every copy of a hash to one file
+    pub hardlink_savings: i64,
+    /// Bytes saved by hardlinking installed files to their CAS objects
+    ///
+    /// Every regular file whose content is in the CAS, duplicated or not.
+    pub single_store_savings: i64,
+    /// The most wasteful blobs, largest waste first
+    pub top: Vec<DuplicateBlob>,
+    /// Packages by wasted bytes, largest first
+    pub by_package: Vec<PackageDuplicates>,
+}
+
+/// Find content shared by several installed regular files
+///
+/// Read-only. Empty files are ignored; `top` limits the blobs listed, not
+/// the totals. The first path installed with a hash counts as the original,
+/// and the packages of the other paths are charged for the extra copies.
+pub fn duplicate_content(conn: &Connection, top: usize) -> Result<DuplicateReport> {
+    let mut stmt = conn.prepare(
+        "SELECT f.sha256_hash, COALESCE(fc.size, f.size), f.path, t.name
+         FROM files f
+         JOIN troves t ON t.id = f.trove_id
+         LEFT JOIN file_contents fc ON fc.sha256_hash = f.sha256_hash
+         WHERE f.file_type = 'regular'
+           AND f.sha256_hash IN (
+               SELECT sha256_hash FROM files
+               WHERE file_type = 'regular' AND size > 0
+               GROUP BY sha256_hash HAVING COUNT(*) > 1
+           )
+         ORDER BY f.sha256_hash, f.id",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                DuplicatePath {
+                    path: row.get(2)?,
+                    package: row.get(3)?,
+                },
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let mut blobs: Vec<DuplicateBlob> = Vec::new();
+    for (hash, size, path) in rows {
+        match blobs.last_mut() {
+            Some(blob) if blob.sha256_hash == hash => {
+                blob.paths.push(path);
+                blob.wasted_bytes += size;
+            }
+            _ => blobs.push(DuplicateBlob {
+                sha256_hash: hash,
+                size,
+                paths: vec![path],
+                wasted_bytes: 0,
+            }),
+        }
+    }
+
+    let mut packages: std::collections::BTreeMap<&str, PackageDuplicates> =
+        std::collections::BTreeMap::new();
+    for blob in &blobs {
+        for copy in &blob.paths[1..] {
+            let entry = packages
+                .entry(&copy.package)
+                .or_insert_with(|| PackageDuplicates {
+                    package: copy.package.clone(),
+                    duplicate_files: 0,
+                    wasted_bytes: 0,
+                });
+            entry.duplicate_files += 1;
+            entry.wasted_bytes += blob.size;
+        }
+    }
+    let mut by_package: Vec<PackageDuplicates> = packages.into_values().collect();
+    by_package.sort_by(|a, b| {
+        b.wasted_bytes
+            .cmp(&a.wasted_bytes)
+            .then_with(|| a.package.cmp(&b.package))
+    });
+
+    let single_store_savings: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(f.size), 0)
+         FROM files f JOIN file_contents fc ON fc.sha256_hash = f.sha256_hash
+         WHERE f.file_type = 'regular'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    let duplicate_files = blobs.iter().map(|blob| blob.paths.len() - 1).sum();
+    let hardlink_savings = blobs.iter().map(|blob| blob.wasted_bytes).sum();
+    let duplicated_blobs = blobs.len();
+    blobs.sort_by(|a, b| {
+        b.wasted_bytes
+            .cmp(&a.wasted_bytes)
+            .then_with(|| a.sha256_hash.cmp(&b.sha256_hash))
+    });
+    blobs.truncate(top);
+    debug!(
+        "{} duplicated blobs waste {} bytes",
+        duplicated_blobs, hardlink_savings
+    );
+
+    Ok(DuplicateReport {
+        duplicated_blobs,
+        duplicate_files,
+        hardlink_savings,
+        single_store_savings,
+        top: blobs,
+        by_package,
+    })
+}
+
+/// Installed size of one package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageUsage {
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+    pub installed_size: i64,
+}
+
+/// Disk used by installed packages
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsageReport {
+    /// Largest first
+    pub packages: Vec<PackageUsage>,
+    /// Sum of the installed sizes of all packages
+    pub total_size: i64,
+    /// Bytes of distinct content behind the installed regular files
+    ///
+    /// Files sharing a hash are stored once in the CAS, so this can be
+    /// well below `total_size`.
+    pub unique_cas_bytes: i64,
+}
+
+/// List installed packages by the size of their files, largest first
+///
+/// Read-only. Each package counts the size its files had when it was
+/// installed, excluded files included.
+pub fn disk_usage(conn: &Connection) -> Result<DiskUsageReport> {
+    let mut packages: Vec<PackageUsage> = Trove::list_all(conn)?
+        .into_iter()
+        .map(|trove| PackageUsage {
+            name: trove.name,
+            version: trove.version,
+            architecture: trove.architecture,
+            installed_size: trove.installed_size,
+        })
+        .collect();
+    packages.sort_by(|a, b| {
+        b.installed_size
+            .cmp(&a.installed_size)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    let total_size = packages.iter().map(|package| package.installed_size).sum();
+
+    let unique_cas_bytes: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(size), 0) FROM (
+             SELECT fc.size FROM files f
+             JOIN file_contents fc ON fc.sha256_hash = f.sha256_hash
+             WHERE f.file_type = 'regular'
+             GROUP BY fc.sha256_hash
+         )",
+        [],
+        |row| row.get(0),
+    )?;
+
+    Ok(DiskUsageReport {
+        packages,
+        total_size,
+        unique_cas_bytes,
+    })
+}
+
+/// A regular file under the install root that no installed package owns
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OrphanFile {
+    pub path: String,
+    pub size: u64,
+    /// Modification time, UTC
+    pub modified: Option<String>,
+}
+
+/// What an orphan scan found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct OrphanSummary {
+    /// Regular files owned by an installed package
+    pub tracked: usize,
+    /// Regular files no package owns
+    pub orphaned: usize,
+    /// Files and directories skipped by an exclusion glob, each directory counted once
+    pub excluded: usize,
+}
+
+/// One entry of the filesystem walk behind [`find_orphans`]
+enum Walked {
+    File(OrphanFile),
+    Excluded,
+}
+
+/// Report the regular files under `prefix` in `root` that no package owns
+///
+/// `prefix` is a path as the package database records it, such as `/usr`.
+/// Entries matching one of the `exclude` globs (see [`glob_match`]) are
+/// skipped, a directory with everything under it. Symlinks are not followed
+/// and only regular files are looked up; a kept config file's `.conarynew`
+/// copy belongs to the package of the config file. The subdirectories of
+/// `prefix` are walked in parallel while the files found are looked up, and
+/// each orphan is passed to `report` as soon as it is known, in no
+/// particular order. Entries that cannot be read are logged and skipped.
+pub fn find_orphans(
+    conn: &Connection,
+    root: &Path,
+    prefix: &str,
+    exclude: &[String],
+    mut report: impl FnMut(&OrphanFile) -> Result<()>,
+) -> Result<OrphanSummary> {
+    let start = crate::paths::under_root(root, prefix);
+    if !start.is_dir() {
+        return Err(Error::NotFoundError(format!(
+            "No directory {} under {}",
+            prefix,
+            root.display()
+        )));
+    }
+
+    let (sender, walked) = sync_channel(ORPHAN_QUEUE_LEN);
+    std::thread::scope(|scope| {
+        scope.spawn(|| walk_for_orphans(root, &start, exclude, sender));
+
+        let mut summary = OrphanSummary::default();
+        for entry in walked {
+            let file = match entry {
+                Walked::Excluded => {
+                    summary.excluded += 1;
+                    continue;
+                }
+                Walked::File(file) => file,
+            };
+            let owner_path = file
+                .path
+                .strip_suffix(crate::filesystem::CONFIG_NEW_SUFFIX)
+                .unwrap_or(&file.path);
+            if FileEntry::find_by_path(conn, &file.path)?.is_some()
+                || FileEntry::find_by_path(conn, owner_path)?.is_some()
+            {
+                summary.tracked += 1;
+            } else {
+                summary.orphaned += 1;
+                report(&file)?;
+            }
+        }
+        Ok(summary)
+    })
+}
+
+/// Walk `start`, one subdirectory per task, sending what is found until the receiver hangs up
+fn walk_for_orphans(root: &Path, start: &Path, exclude: &[String], sender: SyncSender<Walked>) {
+    use rayon::prelude::*;
+
+    let children: Vec<_> = match std::fs::read_dir(start) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect(),
+        Err(e) => {
+            warn!("Cannot read {}: {}", start.display(), e);
+            return;
+        }
+    };
+    children.par_iter().for_each_with(sender, |sender, child| {
+        let mut walk = walkdir::WalkDir::new(child).into_iter();
+        while let Some(entry) = walk.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping unreadable entry: {}", e);
+                    continue;
+                }
+            };
+            let Ok(relative) = entry.path().strip_prefix(root) else {
+                continue;
+            };
+            let path = crate::paths::encode_path(&Path::new("/").join(relative));
+            let walked = if exclude.iter().any(|pattern| glob_match(pattern, &path)) {
+                if entry.file_type().is_dir() {
+                    walk.skip_current_dir();
+                }
+                Walked::Excluded
+            } else if entry.file_type().is_file() {
+                let metadata = match entry.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        warn!("Skipping {}: {}", path, e);
+                        continue;
+                    }
+                };
+                let modified = metadata.modified().ok().map(|time| {
+                    chrono::DateTime::<chrono::Utc>::from(time)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                });
+                Walked::File(OrphanFile {
+                    path,
+                    size: metadata.len(),
+                    modified,
+                })
+            } else {
+                continue;
+            };
+            if sender.send(walked).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Everything recorded about an installed package
+#[derive(Debug, Clone, Serialize)]
+pub struct InstalledInfo {
+    pub trove: Trove,
+    /// The changeset that installed this version
+    pub changeset: Option<Changeset>,
+    pub file_count: usize,
+    /// Bytes of the installed files
+    pub total_size: i64,
+    pub dependencies: Vec<DependencyEntry>,
+    pub provides: Vec<ProvideEntry>,
+    pub flavors: Vec<Flavor>,
+    pub provenance: Option<Provenance>,
+    /// Name of the repository the package was downloaded from
+    pub origin_repository: Option<String>,
+}
+
+/// A package available from a repository
+#[derive(Debug, Clone, Serialize)]
+pub struct AvailableInfo {
+    pub package: RepositoryPackage,
+    /// Name of the repository offering it
+    pub repository: Option<String>,
+    pub dependencies: Vec<String>,
+}
+
+/// Gather what is recorded about every installed version of a package
+///
+/// Empty when the package is not installed.
+pub fn installed_info(conn: &Connection, name: &str) -> Result<Vec<InstalledInfo>> {
+    let mut infos = Vec::new();
+    for trove in Trove::find_by_name(conn, name)? {
+        let trove_id = trove
+            .id
+            .ok_or_else(|| Error::NotFoundError(format!("Trove {} has no ID", trove.name)))?;
+        let changeset = match trove.installed_by_changeset_id {
+            Some(id) => Changeset::find_by_id(conn, id)?,
+            None => None,
+        };
+        let files = FileEntry::find_by_trove(conn, trove_id)?;
+        let origin_repository = match trove.origin_repository_id {
+            Some(id) => Repository::find_by_id(conn, id)?.map(|repo| repo.name),
+            None => None,
+        };
+
+        infos.push(InstalledInfo {
+            changeset,
+            file_count: files.len(),
+            total_size: files.iter().map(|file| file.size).sum(),
+            dependencies: DependencyEntry::find_by_trove(conn, trove_id)?,
+            provides: ProvideEntry::find_by_trove(conn, trove_id)?,
+            flavors: Flavor::find_by_trove(conn, trove_id)?,
+            provenance: Provenance::find_by_trove(conn, trove_id)?,
+            origin_repository,
+            trove,
+        });
+    }
+    Ok(infos)
+}
+
+/// Gather every version of a package the synced repositories offer
+///
+/// Empty when no repository has the package.
+pub fn available_info(conn: &Connection, name: &str) -> Result<Vec<AvailableInfo>> {
+    RepositoryPackage::find_by_name(conn, name)?
+        .into_iter()
+        .map(|package| {
+            Ok(AvailableInfo {
+                repository: Repository::find_by_id(conn, package.repository_id)?
+                    .map(|repo| repo.name),
+                dependencies: package.load_dependencies(conn)?,
+                package,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::{FileContents, TroveType};
+    use tempfile::NamedTempFile;
+
+    fn create_test_db() -> (NamedTempFile, Connection) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        crate::db::schema::migrate(&conn).unwrap();
+        (temp_file, conn)
+    }
+
+    fn add_trove(conn: &Connection, name: &str) -> i64 {
+        let mut trove = Trove::new(name.to_string(), "1.0".to_string(), TroveType::Package);
+        trove.insert(conn).unwrap()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/usr/bin/python3", "/usr/bin/python3"));
+        assert!(!glob_match("/usr/bin/python3", "/usr/bin/python3.12"));
+        assert!(glob_match("/usr/bin/python*", "/usr/bin/python3.12"));
+        assert!(glob_match("/usr/*/perl", "/usr/local/bin/perl"));
+        assert!(glob_match("/bin/?sh", "/bin/zsh"));
+        assert!(!glob_match("/bin/?sh", "/bin/sh"));
+    }
+
+    #[test]
+    fn test_declared_file_dependencies() {
+        let (_temp, conn) = create_test_db();
+
+        let tool = add_trove(&conn, "tool");
+        let other = add_trove(&conn, "other");
+        DependencyEntry::new(
+            tool,
+            "/usr/bin/python3".to_string(),
+            None,
+            "runtime".to_string(),
+            None,
+        )
+        .insert(&conn)
+        .unwrap();
+        DependencyEntry::new(
+            other,
+            "/usr/bin/python3.12".to_string(),
+            None,
+            "runtime".to_string(),
+            None,
+        )
+        .insert(&conn)
+        .unwrap();
+        DependencyEntry::new(
+            other,
+            "glibc".to_string(),
+            None,
+            "runtime".to_string(),
+            None,
+        )
+        .insert(&conn)
+        .unwrap();
+
+        let exact =
+            packages_requiring_file(&conn, Path::new("/"), "/usr/bin/python3", false).unwrap();
+        assert_eq!(exact.len(), 1);
+        assert_eq!(exact[0].package, "tool");
+        assert_eq!(
+            exact[0].source,
+            RequirementSource::Declared {
+                dependency: "/usr/bin/python3".to_string()
+            }
+        );
+
+        let glob =
+            packages_requiring_file(&conn, Path::new("/"), "/usr/bin/python*", false).unwrap();
+        let names: Vec<_> = glob.iter().map(|r| r.package.as_str()).collect();
+        assert_eq!(names, vec!["other", "tool"]);
+    }
+
+    #[test]
+    fn test_shebang_scan() {
+        let (_temp, conn) = create_test_db();
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        std::fs::write(
+            root.path().join("usr/bin/script"),
+            "#!/usr/bin/python3 -u\nprint('hi')\n",
+        )
+        .unwrap();
+        std::fs::write(root.path().join("usr/bin/data"), "#!/usr/bin/python3\n").unwrap();
+
+        let trove_id = add_trove(&conn, "scripts");
+        FileEntry::new(
+            "/usr/bin/script".to_string(),
+            "aa".repeat(32),
+            10,
+            0o755,
+            trove_id,
+        )
+        .insert(&conn)
+        .unwrap();
+        // Not executable, so not scanned
+        FileEntry::new(
+            "/usr/bin/data".to_string(),
+            "bb".repeat(32),
+            10,
+            0o644,
+            trove_id,
+        )
+        .insert(&conn)
+        .unwrap();
+
+        // Shebangs are only read when asked for
+        assert!(
+            packages_requiring_file(&conn, root.path(), "/usr/bin/python3", false)
+                .unwrap()
+                .is_empty()
+        );
+
+        let found = packages_requiring_file(&conn, root.path(), "/usr/bin/python3", true).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].package, "scripts");
+        assert_eq!(
+            found[0].source,
+            RequirementSource::Shebang {
+                file: "/usr/bin/script".to_string(),
+                interpreter: "/usr/bin/python3".to_string()
+            }
+        );
+
+        let json = serde_json::to_value(&found[0]).unwrap();
+        assert_eq!(json["source"], "shebang");
+        assert_eq!(json["file"], "/usr/bin/script");
+    }
+
+    #[test]
+    fn test_changeset_reports_across_upgrade_and_removal() {
+        let (_temp, conn) = create_test_db();
+
+        let install = Changeset::new("Install foo-1.0".to_string())
+            .insert(&conn)
+            .unwrap();
+        let mut old = Trove::new("foo".to_string(), "1.0".to_string(), TroveType::Package);
+        old.installed_by_changeset_id = Some(install);
+        let old_id = old.insert(&conn).unwrap();
+
+        let upgrade = Changeset::new("Upgrade foo from 1.0 to 1.1".to_string())
+            .insert(&conn)
+            .unwrap();
+        RemovedTrove::new(upgrade, &old, RemovalReason::Replaced)
+            .insert(&conn)
+            .unwrap();
+        Trove::delete(&conn, old_id).unwrap();
+        let mut new = Trove::new("foo".to_string(), "1.1".to_string(), TroveType::Package);
+        new.installed_by_changeset_id = Some(upgrade);
+        let new_id = new.insert(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO file_history (changeset_id, path, action) VALUES (?1, '/usr/bin/foo', 'modify')",
+            [upgrade],
+        )
+        .unwrap();
+
+        let remove = Changeset::new("Remove foo-1.1".to_string())
+            .insert(&conn)
+            .unwrap();
+        RemovedTrove::new(remove, &new, RemovalReason::Removed)
+            .insert(&conn)
+            .unwrap();
+        Trove::delete(&conn, new_id).unwrap();
+
+        let report = changeset_report(&conn, upgrade).unwrap();
+        let actions: Vec<_> = report
+            .troves
+            .iter()
+            .map(|t| (t.version.as_str(), t.action))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("1.0", PackageAction::Replaced),
+                ("1.1", PackageAction::Installed)
+            ]
+        );
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].path, "/usr/bin/foo");
+        assert_eq!(report.files[0].action, "modify");
+
+        // Installs are still reported once the trove is gone
+        let report = changeset_report(&conn, install).unwrap();
+        assert_eq!(report.troves.len(), 1);
+        assert_eq!(report.troves[0].action, PackageAction::Installed);
+
+        let events: Vec<_> = package_changesets(&conn, "foo")
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.changeset.id.unwrap(), e.change.version, e.change.action))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (install, "1.0".to_string(), PackageAction::Installed),
+                (upgrade, "1.0".to_string(), PackageAction::Replaced),
+                (upgrade, "1.1".to_string(), PackageAction::Installed),
+                (remove, "1.1".to_string(), PackageAction::Removed),
+            ]
+        );
+
+        assert!(package_changesets(&conn, "bar").unwrap().is_empty());
+        assert!(matches!(
+            changeset_report(&conn, 999),
+            Err(Error::NotFoundError(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_content_savings() {
+        let (_temp, conn) = create_test_db();
+        let base = add_trove(&conn, "base");
+        let extra = add_trove(&conn, "extra");
+
+        let add_file = |path: &str, hash: &str, size: i64, trove_id: i64| {
+            FileEntry::new(path.to_string(), hash.to_string(), size, 0o644, trove_id)
+                .insert(&conn)
+                .unwrap();
+        };
+        add_file("/usr/share/a1", "aaaa", 100, base);
+        add_file("/usr/share/a2", "aaaa", 100, extra);
+        add_file("/usr/share/a3", "aaaa", 100, extra);
+        add_file("/usr/share/b1", "bbbb", 10, base);
+        add_file("/usr/share/b2", "bbbb", 10, extra);
+        add_file("/usr/share/unique", "cccc", 50, base);
+        // Empty files share a hash but waste nothing
+        add_file("/usr/share/empty1", "eeee", 0, base);
+        add_file("/usr/share/empty2", "eeee", 0, extra);
+        // Symlinks are not copies of their content
+        let mut link = FileEntry::new(
+            "/usr/share/link".to_string(),
+            "aaaa".to_string(),
+            100,
+            0o777,
+            extra,
+        );
+        link.file_type = crate::db::models::FileType::Symlink;
+        link.link_target = Some("a1".to_string());
+        link.insert(&conn).unwrap();
+        for (hash, size) in [("aaaa", 100), ("bbbb", 10), ("cccc", 50), ("eeee", 0)] {
+            FileContents::new(hash.to_string(), size)
+                .insert(&conn)
+                .unwrap();
+        }
+
+        let report = duplicate_content(&conn, 1).unwrap();
+        assert_eq!(report.duplicated_blobs, 2);
+        assert_eq!(report.duplicate_files, 3);
+        assert_eq!(report.hardlink_savings, 100 * 2 + 10);
+        assert_eq!(report.single_store_savings, 100 * 3 + 10 * 2 + 50);
+
+        assert_eq!(report.top.len(), 1);
+        assert_eq!(report.top[0].sha256_hash, "aaaa");
+        assert_eq!(report.top[0].wasted_bytes, 200);
+        assert_eq!(report.top[0].paths[0].path, "/usr/share/a1");
+        assert_eq!(report.top[0].paths.len(), 3);
+
+        assert_eq!(
+            report.by_package,
+            vec![PackageDuplicates {
+                package: "extra".to_string(),
+                duplicate_files: 3,
+                wasted_bytes: 210,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let (_temp, conn) = create_test_db();
+        let root = tempfile::tempdir().unwrap();
+        let tool = add_trove(&conn, "tool");
+        for path in ["/usr/bin/tool", "/usr/lib/tool.conf"] {
+            FileEntry::new(path.to_string(), "aaaa".to_string(), 4, 0o644, tool)
+                .insert(&conn)
+                .unwrap();
+        }
+        for path in [
+            "usr/bin/tool",
+            "usr/bin/stray",
+            "usr/lib/tool.conf",
+            "usr/lib/tool.conf.conarynew",
+            "usr/lib/deep/nested/leftover",
+            "usr/local/bin/mine",
+            "usr/local/lib/mine.so",
+            "etc/outside",
+        ] {
+            let path = root.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"data").unwrap();
+        }
+        std::os::unix::fs::symlink("tool", root.path().join("usr/bin/link")).unwrap();
+
+        let mut orphans = Vec::new();
+        let summary = find_orphans(
+            &conn,
+            root.path(),
+            "/usr",
+            &["/usr/local".to_string()],
+            |orphan| {
+                orphans.push(orphan.clone());
+                Ok(())
+            },
+        )
+        .unwrap();
+        orphans.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths: Vec<&str> = orphans.iter().map(|orphan| orphan.path.as_str()).collect();
+        assert_eq!(paths, ["/usr/bin/stray", "/usr/lib/deep/nested/leftover"]);
+        assert_eq!(orphans[0].size, 4);
+        assert!(orphans[0].modified.is_some());
+        // /usr/local is skipped as a whole
+        assert_eq!(
+            summary,
+            OrphanSummary {
+                tracked: 3,
+                orphaned: 2,
+                excluded: 1
+            }
+        );
+
+        let summary = find_orphans(
+            &conn,
+            root.path(),
+            "/usr/lib",
+            &["*.so".to_string()],
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            OrphanSummary {
+                tracked: 2,
+                orphaned: 1,
+                excluded: 0
+            }
+        );
+        assert!(matches!(
+            find_orphans(&conn, root.path(), "/opt", &[], |_| Ok(())),
+            Err(Error::NotFoundError(_))
+        ));
+    }
+}
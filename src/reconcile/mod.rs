@@ -0,0 +1,516 @@
+// src/reconcile/mod.rs
+
+//! Full-system revalidation after a root or CAS restore
+//!
+//! When the install root and the database drift apart (a root restored from
+//! backup with a newer database, or the reverse), every tracked file needs to be
+//! checked and brought back in line. Reconciliation works per trove, in
+//! dependency order:
+//!
+//! 1. **Verify**: Compare each tracked file on disk against its recorded hash
+//! 2. **Repair**: Redeploy missing or modified files from the CAS (locally
+//!    edited config files are left alone)
+//! 3. **Reinstall**: If the CAS object itself is gone, re-download the package
+//!    from a repository that carries the same version and refill the CAS
+//!
+//! Anything that cannot be restored is reported as irrecoverable. Every trove
+//! that had files repaired gets its own changeset, and all changesets from one
+//! run share a batch id.
+
+use crate::db::models::{
+    Changeset, ChangesetStatus, DependencyEntry, FileContents, FileEntry, FileType, Repository,
+    RepositoryPackage, Trove,
+};
+use crate::error::{Error, Result};
+use crate::filesystem::FileDeployer;
+use crate::packages;
+use crate::repository;
+use crate::stats;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tempfile::TempDir;
+use tracing::{info, warn};
+
+/// Options controlling a reconcile run
+#[derive(Debug, Clone, Default)]
+pub struct ReconcileOptions {
+    /// Re-download packages whose CAS objects are missing
+    pub download_missing: bool,
+    /// Report what would be done without touching the filesystem or database
+    pub dry_run: bool,
+    /// Also restore files the trove was installed without
+    pub include_excluded: bool,
+}
+
+/// What was wrong with a tracked file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileProblem {
+    /// File is not present in the install root
+    Missing,
+    /// File content does not match the recorded hash
+    Modified,
+}
+
+/// How a broken file was (or would be) handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Content was redeployed from the CAS
+    Redeployed,
+    /// Package was re-downloaded to restore the CAS, then content redeployed
+    Redownloaded,
+    /// File could not be restored
+    Irrecoverable(String),
+}
+
+/// A single file repair
+#[derive(Debug, Clone)]
+pub struct FileRepair {
+    pub path: String,
+    pub problem: FileProblem,
+    pub resolution: Resolution,
+}
+
+/// Reconcile result for one trove
+#[derive(Debug, Clone)]
+pub struct PackageReconcile {
+    pub name: String,
+    pub version: String,
+    pub files_checked: usize,
+    pub repairs: Vec<FileRepair>,
+    /// Changeset recording the repairs (None in dry-run or if nothing changed)
+    pub changeset_id: Option<i64>,
+}
+
+impl PackageReconcile {
+    /// Whether any file in this trove could not be restored
+    pub fn has_irrecoverable(&self) -> bool {
+        self.repairs
+            .iter()
+            .any(|r| matches!(r.resolution, Resolution::Irrecoverable(_)))
+    }
+}
+
+/// Summary of a reconcile run
+#[derive(Debug, Clone)]
+pub struct ReconcileReport {
+    /// Batch id shared by all changesets created in this run
+    pub batch_id: String,
+    pub dry_run: bool,
+    /// Per-trove results, in the order they were processed
+    pub packages: Vec<PackageReconcile>,
+}
+
+impl ReconcileReport {
+    /// Total number of files checked
+    pub fn files_checked(&self) -> usize {
+        self.packages.iter().map(|p| p.files_checked).sum()
+    }
+
+    /// Number of files that were (or would be) restored
+    pub fn files_repaired(&self) -> usize {
+        self.all_repairs()
+            .filter(|r| !matches!(r.resolution, Resolution::Irrecoverable(_)))
+            .count()
+    }
+
+    /// Number of files that could not be restored
+    pub fn files_irrecoverable(&self) -> usize {
+        self.all_repairs()
+            .filter(|r| matches!(r.resolution, Resolution::Irrecoverable(_)))
+            .count()
+    }
+
+    /// Whether the system was already consistent
+    pub fn is_clean(&self) -> bool {
+        self.all_repairs().next().is_none()
+    }
+
+    fn all_repairs(&self) -> impl Iterator<Item = &FileRepair> {
+        self.packages.iter().flat_map(|p| p.repairs.iter())
+    }
+}
+
+/// Order installed troves so that dependencies come before their dependents
+///
+/// Cycles are broken arbitrarily; dependencies on packages that are not
+/// installed are ignored.
+fn dependency_order(conn: &Connection) -> Result<Vec<Trove>> {
+    let troves = Trove::list_all(conn)?;
+
+    let mut by_name: HashMap<String, Vec<usize>> = HashMap::new();
+    for (idx, trove) in troves.iter().enumerate() {
+        by_name.entry(trove.name.clone()).or_default().push(idx);
+    }
+
+    let mut deps: Vec<Vec<usize>> = Vec::with_capacity(troves.len());
+    for trove in &troves {
+        let entries = DependencyEntry::find_by_trove(conn, trove.id.unwrap())?;
+        let targets = entries
+            .iter()
+            .filter_map(|d| by_name.get(&d.depends_on_name))
+            .flatten()
+            .copied()
+            .collect();
+        deps.push(targets);
+    }
+
+    // Iterative post-order DFS
+    let mut visited = HashSet::new();
+    let mut order = Vec::with_capacity(troves.len());
+    for start in 0..troves.len() {
+        if !visited.insert(start) {
+            continue;
+        }
+        let mut stack = vec![(start, 0usize)];
+        while let Some((node, next)) = stack.pop() {
+            if let Some(&dep) = deps[node].get(next) {
+                stack.push((node, next + 1));
+                if visited.insert(dep) {
+                    stack.push((dep, 0));
+                }
+            } else {
+                order.push(node);
+            }
+        }
+    }
+
+    let mut troves: Vec<Option<Trove>> = troves.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .filter_map(|idx| troves[idx].take())
+        .collect())
+}
+
+/// Find a repository package matching an installed trove exactly
+///
+/// A trove installed from a repository is only matched in that repository,
+/// preferring the package at the URL it was downloaded from: another
+/// repository carrying the same name and version may not carry the same
+/// content. Any repository will do for a trove with no recorded origin.
+fn find_origin_package(conn: &Connection, trove: &Trove) -> Result<Option<RepositoryPackage>> {
+    let mut candidates: Vec<_> = RepositoryPackage::find_by_name(conn, &trove.name)?
+        .into_iter()
+        .filter(|pkg| {
+            pkg.version == trove.version
+                && (pkg.architecture == trove.architecture || pkg.architecture.is_none())
+        })
+        .collect();
+    if let Some(origin) = trove.origin_repository_id {
+        candidates.retain(|pkg| pkg.repository_id == origin);
+        candidates
+            .sort_by_key(|pkg| trove.origin_url.as_deref() != Some(pkg.download_url.as_str()));
+    }
+    Ok(candidates.into_iter().next())
+}
+
+/// Re-download a trove's package and load its file contents into the CAS
+fn refill_cas_from_repository(
+    conn: &Connection,
+    deployer: &FileDeployer,
+    trove: &Trove,
+) -> Result<()> {
+    let repo_pkg =
+        find_origin_package(conn, trove)?.ok_or_else(|| match trove.origin_repository_id {
+            Some(_) => Error::NotFoundError(format!(
+                "The repository {} {} was installed from no longer carries it",
+                trove.name, trove.version
+            )),
+            None => Error::NotFoundError(format!(
+                "No repository carries {} {}",
+                trove.name, trove.version
+            )),
+        })?;
+
+    let repo = Repository::find_by_id(conn, repo_pkg.repository_id)?.ok_or_else(|| {
+        Error::NotFoundError(format!(
+            "Repository of {} {} is gone",
+            trove.name, trove.version
+        ))
+    })?;
+    let temp_dir = TempDir::new()?;
+    let pkg_path = repository::download_package(&repo, &repo_pkg, temp_dir.path(), None)?;
+    stats::record_download(
+        conn,
+        repo_pkg.repository_id,
+        std::fs::metadata(&pkg_path)?.len() as i64,
+    )?;
+
+    // Hardlinks share the content of the file they point to
+    let package = packages::open_package(&pkg_path)?;
+    let mut staged = Vec::new();
+    package.extract_files(&mut |packages::traits::FileStream { file, content }| {
+        let (_, _, object) = match (file.file_type, &file.link_target) {
+            (FileType::Hardlink, _) => return Ok(()),
+            (FileType::Symlink, Some(target)) => {
+                deployer.cas().stage_reader(&mut target.as_bytes())?
+            }
+            _ => deployer.cas().stage_reader(content)?,
+        };
+        staged.extend(object);
+        Ok(())
+    })?;
+    deployer.cas().commit_staged(staged)
+}
+
+/// Check and repair every installed trove
+///
+/// `objects_dir` is the CAS objects directory and `root` the install root.
+pub fn reconcile(
+    conn: &mut Connection,
+    objects_dir: &Path,
+    root: &Path,
+    options: &ReconcileOptions,
+) -> Result<ReconcileReport> {
+    let deployer = FileDeployer::new(objects_dir, root)?;
+    let batch_id = format!(
+        "reconcile-{}",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+    );
+    info!("Reconciling installed troves (batch {})", batch_id);
+
+    let mut report = ReconcileReport {
+        batch_id: batch_id.clone(),
+        dry_run: options.dry_run,
+        packages: Vec::new(),
+    };
+
+    for trove in dependency_order(conn)? {
+        let files = FileEntry::find_by_trove(conn, trove.id.unwrap())?;
+        let mut result = PackageReconcile {
+            name: trove.name.clone(),
+            version: trove.version.clone(),
+            files_checked: files.len(),
+            repairs: Vec::new(),
+            changeset_id: None,
+        };
+
+        // Verify
+        let mut broken: Vec<(&FileEntry, FileProblem, bool)> = Vec::new();
+        for file in &files {
+            let problem = if !deployer.file_exists(&file.path) {
+                // Never deployed by design
+                if !options.include_excluded && trove.install_modifiers.excludes(&file.path) {
+                    continue;
+                }
+                FileProblem::Missing
+            } else if !deployer.verify_entry(file)? {
+                // Local edits to config files are intentional, not drift
+                if file.is_config {
+                    continue;
+                }
+                FileProblem::Modified
+            } else {
+                continue;
+            };
+            broken.push((file, problem, deployer.can_redeploy(file)));
+        }
+
+        if broken.is_empty() {
+            report.packages.push(result);
+            continue;
+        }
+
+        // Reinstall: refill the CAS once per trove if any object is gone
+        let mut redownloaded = false;
+        let mut download_error = "content missing from CAS (use --download-missing)".to_string();
+        if broken.iter().any(|(_, _, had_object)| !had_object) && options.download_missing {
+            if options.dry_run {
+                match find_origin_package(conn, &trove)? {
+                    Some(_) => redownloaded = true,
+                    None => download_error = "no repository carries this version".to_string(),
+                }
+            } else {
+                match refill_cas_from_repository(conn, &deployer, &trove) {
+                    Ok(()) => redownloaded = true,
+                    Err(e) => {
+                        warn!(
+                            "Failed to re-download {} {}: {}",
+                            trove.name, trove.version, e
+                        );
+                        download_error = e.to_string();
+                    }
+                }
+            }
+        }
+
+        // Repair (hardlinks last, so the files they point to are back first)
+        broken.sort_by_key(|(file, _, _)| file.file_type == FileType::Hardlink);
+        for (file, problem, had_object) in broken {
+            let resolution = if had_object {
+                Resolution::Redeployed
+            } else if redownloaded && (options.dry_run || deployer.can_redeploy(file)) {
+                Resolution::Redownloaded
+            } else if redownloaded {
+                Resolution::Irrecoverable(
+                    "re-downloaded package does not contain this content".to_string(),
+                )
+            } else {
+                Resolution::Irrecoverable(download_error.clone())
+            };
+
+            if !options.dry_run && !matches!(resolution, Resolution::Irrecoverable(_)) {
+                deployer.deploy_entry(file)?;
+            }
+
+            result.repairs.push(FileRepair {
+                path: file.path.clone(),
+                problem,
+                resolution,
+            });
+        }
+
+        // Record a changeset for the files that were restored
+        let restored: Vec<&FileRepair> = result
+            .repairs
+            .iter()
+            .filter(|r| !matches!(r.resolution, Resolution::Irrecoverable(_)))
+            .collect();
+        if !options.dry_run && !restored.is_empty() {
+            let by_path: HashMap<&str, &FileEntry> =
+                files.iter().map(|f| (f.path.as_str(), f)).collect();
+
+            let changeset_id = crate::db::transaction(conn, |tx| {
+                let mut changeset = Changeset::new(format!(
+                    "Reconcile {}-{} ({} files restored)",
+                    trove.name,
+                    trove.version,
+                    restored.len()
+                ));
+                changeset.batch_id = Some(batch_id.clone());
+                let changeset_id = changeset.insert(tx)?;
+
+                for repair in &restored {
+                    let action = match repair.problem {
+                        FileProblem::Missing => "add",
+                        FileProblem::Modified => "modify",
+                    };
+                    let file = by_path[repair.path.as_str()];
+                    let hash = &file.sha256_hash;
+                    if let Some(hash) = hash {
+                        FileContents::new(hash.clone(), file.size).insert(tx)?;
+                    }
+                    tx.execute(
+                        "INSERT INTO file_history (changeset_id, path, sha256_hash, action) VALUES (?1, ?2, ?3, ?4)",
+                        rusqlite::params![changeset_id, repair.path, hash, action],
+                    )?;
+                }
+
+                changeset.update_status(tx, ChangesetStatus::Applied)?;
+                Ok(changeset_id)
+            })?;
+            result.changeset_id = Some(changeset_id);
+        }
+
+        report.packages.push(result);
+    }
+
+    info!(
+        "Reconcile complete: {} files checked, {} repaired, {} irrecoverable",
+        report.files_checked(),
+        report.files_repaired(),
+        report.files_irrecoverable()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::TroveType;
+    use tempfile::NamedTempFile;
+
+    fn create_test_db() -> (NamedTempFile, Connection) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
+        crate::db::schema::migrate(&conn).unwrap();
+        (temp_file, conn)
+    }
+
+    fn add_trove(conn: &Connection, name: &str, deps: &[&str]) -> i64 {
+        let mut trove = Trove::new(name.to_string(), "1.0".to_string(), TroveType::Package);
+        let id = trove.insert(conn).unwrap();
+        for dep in deps {
+            let mut entry =
+                DependencyEntry::new(id, dep.to_string(), None, "runtime".to_string(), None);
+            entry.insert(conn).unwrap();
+        }
+        id
+    }
+
+    #[test]
+    fn test_dependency_order_puts_dependencies_first() {
+        let (_temp, conn) = create_test_db();
+        add_trove(&conn, "app", &["libfoo", "missing-dep"]);
+        add_trove(&conn, "libfoo", &["glibc"]);
+        add_trove(&conn, "glibc", &[]);
+
+        let names: Vec<String> = dependency_order(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        let pos = |n: &str| names.iter().position(|x| x == n).unwrap();
+
+        assert_eq!(names.len(), 3);
+        assert!(pos("glibc") < pos("libfoo"));
+        assert!(pos("libfoo") < pos("app"));
+    }
+
+    #[test]
+    fn test_find_origin_package_prefers_recorded_origin() {
+        use crate::db::models::Repository;
+
+        let (_temp, conn) = create_test_db();
+        let mut repo_ids = Vec::new();
+        for name in ["mirror", "vendor"] {
+            let mut repo =
+                Repository::new(name.to_string(), format!("https://{}.example.com", name));
+            repo_ids.push(repo.insert(&conn).unwrap());
+        }
+        for &repo_id in &repo_ids {
+            let url = format!("https://example.com/{}/tool-1.0.pkg", repo_id);
+            RepositoryPackage::new(
+                repo_id,
+                "tool".to_string(),
+                "1.0".to_string(),
+                "abc".to_string(),
+                1,
+                url,
+            )
+            .insert(&conn)
+            .unwrap();
+        }
+
+        // Both repositories carry tool 1.0; the one it came from is used
+        let mut trove = Trove::new("tool".to_string(), "1.0".to_string(), TroveType::Package);
+        trove.origin_repository_id = Some(repo_ids[1]);
+        trove.origin_url = Some(format!("https://example.com/{}/tool-1.0.pkg", repo_ids[1]));
+        let found = find_origin_package(&conn, &trove).unwrap().unwrap();
+        assert_eq!(found.repository_id, repo_ids[1]);
+
+        // A recorded origin that no longer carries it is not swapped for another
+        conn.execute(
+            "DELETE FROM repository_packages WHERE repository_id = ?1",
+            [repo_ids[1]],
+        )
+        .unwrap();
+        assert!(find_origin_package(&conn, &trove).unwrap().is_none());
+
+        // Without a recorded origin, any repository carrying it will do
+        trove.origin_repository_id = None;
+        trove.origin_url = None;
+        let found = find_origin_package(&conn, &trove).unwrap().unwrap();
+        assert_eq!(found.repository_id, repo_ids[0]);
+    }
+
+    #[test]
+    fn test_dependency_order_tolerates_cycles() {
+        let (_temp, conn) = create_test_db();
+        add_trove(&conn, "a", &["b"]);
+        add_trove(&conn, "b", &["a"]);
+
+        assert_eq!(dependency_order(&conn).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,259 @@
+// src/repository/defaults.rs
+
+//! Repositories `conary init --with-defaults` can add
+//!
+//! Each supported distribution has a set of well-known repositories with
+//! priorities that keep its core ahead of its add-ons. `init` adds none of
+//! them unless asked; `--with-defaults` without a distribution picks the
+//! host's from os-release. `conary bootstrap` presets use the same table.
+//! A distribution is supported by adding it to [`Distro`] and its
+//! repositories to [`DEFAULT_REPOSITORIES`].
+
+use crate::error::{Error, Result};
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+/// Where os-release is looked for, in order
+const OS_RELEASE_PATHS: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
+
+/// A distribution with default repositories
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distro {
+    Arch,
+    Fedora,
+    Ubuntu,
+}
+
+impl Distro {
+    /// Every distribution with default repositories
+    pub const ALL: [Distro; 3] = [Distro::Arch, Distro::Fedora, Distro::Ubuntu];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Distro::Arch => "arch",
+            Distro::Fedora => "fedora",
+            Distro::Ubuntu => "ubuntu",
+        }
+    }
+
+    /// Identify the distribution described by os-release contents
+    ///
+    /// `ID` is tried first, then each entry of `ID_LIKE`, so derivatives
+    /// such as Manjaro (`ID_LIKE=arch`) or Linux Mint (`ID_LIKE="ubuntu
+    /// debian"`) get their parent's repositories.
+    pub fn from_os_release(contents: &str) -> Option<Self> {
+        let field = |key: &str| {
+            contents.lines().find_map(|line| {
+                let value = line.trim().strip_prefix(key)?.strip_prefix('=')?;
+                Some(
+                    value
+                        .trim()
+                        .trim_matches(|c| c == '"' || c == '\'')
+                        .to_string(),
+                )
+            })
+        };
+        let id = field("ID").unwrap_or_default();
+        let like = field("ID_LIKE").unwrap_or_default();
+        std::iter::once(id.as_str())
+            .chain(like.split_whitespace())
+            .find_map(|id| id.parse().ok())
+    }
+
+    /// Identify the host distribution from its os-release file
+    pub fn detect() -> Result<Self> {
+        let contents = OS_RELEASE_PATHS
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .ok_or_else(|| {
+                Error::NotFoundError(format!(
+                    "No os-release file found (looked for {})",
+                    OS_RELEASE_PATHS.join(", ")
+                ))
+            })?;
+        Self::from_os_release(&contents).ok_or_else(|| {
+            Error::NotFoundError(
+                "The host distribution has no default repositories; name one of arch, fedora, ubuntu or all"
+                    .to_string(),
+            )
+        })
+    }
+}
+
+impl FromStr for Distro {
+    type Err = String;
+
+    /// Parse a distribution by its os-release ID
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|distro| distro.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid distribution: {} (expected arch, fedora or ubuntu)",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for Distro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A well-known repository of a distribution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultRepository {
+    pub name: &'static str,
+    pub url: &'static str,
+    pub priority: i32,
+    /// Human-readable origin shown when the repository is added
+    pub label: &'static str,
+    pub distro: Distro,
+    /// Serves only 32-bit builds for multilib systems; bootstrapped roots
+    /// leave it out
+    pub multilib: bool,
+}
+
+/// Every default repository, highest priority first
+pub const DEFAULT_REPOSITORIES: &[DefaultRepository] = &[
+    DefaultRepository {
+        name: "arch-core",
+        url: "https://geo.mirror.pkgbuild.com/core/os/x86_64",
+        priority: 100,
+        label: "Arch Linux",
+        distro: Distro::Arch,
+        multilib: false,
+    },
+    DefaultRepository {
+        name: "arch-extra",
+        url: "https://geo.mirror.pkgbuild.com/extra/os/x86_64",
+        priority: 95,
+        label: "Arch Linux",
+        distro: Distro::Arch,
+        multilib: false,
+    },
+    DefaultRepository {
+        name: "fedora-43",
+        url: "https://dl.fedoraproject.org/pub/fedora/linux/releases/43/Everything/x86_64/os",
+        priority: 90,
+        label: "Fedora 43",
+        distro: Distro::Fedora,
+        multilib: false,
+    },
+    DefaultRepository {
+        name: "arch-multilib",
+        url: "https://geo.mirror.pkgbuild.com/multilib/os/x86_64",
+        priority: 85,
+        label: "Arch Linux",
+        distro: Distro::Arch,
+        multilib: true,
+    },
+    DefaultRepository {
+        name: "ubuntu-noble",
+        url: "http://archive.ubuntu.com/ubuntu",
+        priority: 80,
+        label: "Ubuntu 24.04 LTS",
+        distro: Distro::Ubuntu,
+        multilib: false,
+    },
+];
+
+/// Which default repositories to add
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultSet {
+    /// Those of one distribution
+    Distro(Distro),
+    /// Those of every supported distribution
+    All,
+}
+
+impl DefaultSet {
+    /// The repositories in this set, highest priority first
+    pub fn repositories(&self) -> Vec<&'static DefaultRepository> {
+        DEFAULT_REPOSITORIES
+            .iter()
+            .filter(|repo| match self {
+                DefaultSet::Distro(distro) => repo.distro == *distro,
+                DefaultSet::All => true,
+            })
+            .collect()
+    }
+}
+
+impl FromStr for DefaultSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(DefaultSet::All),
+            _ => s.parse().map(DefaultSet::Distro).map_err(|_| {
+                format!(
+                    "Invalid distribution: {} (expected arch, fedora, ubuntu or all)",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_sets() {
+        let names = |set: DefaultSet| {
+            set.repositories()
+                .iter()
+                .map(|repo| repo.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names("arch".parse().unwrap()),
+            vec!["arch-core", "arch-extra", "arch-multilib"]
+        );
+        assert_eq!(names("fedora".parse().unwrap()), vec!["fedora-43"]);
+        assert_eq!(names("ubuntu".parse().unwrap()), vec!["ubuntu-noble"]);
+        assert_eq!(names(DefaultSet::All).len(), DEFAULT_REPOSITORIES.len());
+        assert!("debian".parse::<DefaultSet>().is_err());
+        assert!("all".parse::<Distro>().is_err());
+
+        // Names are unique and priorities descend
+        let all = DefaultSet::All.repositories();
+        assert!(
+            all.windows(2)
+                .all(|pair| pair[0].priority > pair[1].priority)
+        );
+        let mut unique: Vec<&str> = all.iter().map(|repo| repo.name).collect();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), all.len());
+    }
+
+    #[test]
+    fn test_distro_from_os_release() {
+        assert_eq!(
+            Distro::from_os_release("NAME=\"Arch Linux\"\nID=arch\n"),
+            Some(Distro::Arch)
+        );
+        assert_eq!(
+            Distro::from_os_release("ID=fedora\nVERSION_ID=43\n"),
+            Some(Distro::Fedora)
+        );
+        assert_eq!(
+            Distro::from_os_release("ID=manjaro\nID_LIKE=arch\n"),
+            Some(Distro::Arch)
+        );
+        assert_eq!(
+            Distro::from_os_release("ID=linuxmint\nID_LIKE=\"ubuntu debian\"\n"),
+            Some(Distro::Ubuntu)
+        );
+        // VERSION_ID must not be mistaken for ID
+        assert_eq!(Distro::from_os_release("VERSION_ID=43\nID=debian\n"), None);
+        assert_eq!(Distro::from_os_release(""), None);
+    }
+}
@@ -0,0 +1,102 @@
+// src/repository/filter.rs
+
+//! Per-repository sync filters
+//!
+//! A repository can keep classes of packages out of its package index, such
+//! as `*-debuginfo` or `*-devel`, so they never take up space in the
+//! database or show up in searches. Each pattern is a glob (`*` and `?`)
+//! matched against the package name and against `name.arch`, so `*.i686`
+//! filters by architecture.
+//!
+//! With include patterns, only packages matching one of them are kept;
+//! exclude patterns then drop packages from what is left. The filter is
+//! applied while a sync stores the parsed index.
+
+use crate::db::models::Repository;
+use crate::query::glob_match;
+
+/// The include and exclude patterns of a repository
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl SyncFilter {
+    /// The filter configured for `repo`
+    pub fn for_repository(repo: &Repository) -> Self {
+        Self {
+            include: repo.sync_include.clone(),
+            exclude: repo.sync_exclude.clone(),
+        }
+    }
+
+    /// Whether the filter keeps every package
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Why a package is filtered out, or `None` when it is kept
+    pub fn rejects(&self, name: &str, arch: Option<&str>) -> Option<String> {
+        let qualified = arch.map(|arch| format!("{}.{}", name, arch));
+        let matches = |pattern: &String| {
+            glob_match(pattern, name)
+                || qualified
+                    .as_deref()
+                    .is_some_and(|qualified| glob_match(pattern, qualified))
+        };
+
+        if !self.include.is_empty() && !self.include.iter().any(matches) {
+            return Some(format!(
+                "matches no include pattern ({})",
+                self.include.join(", ")
+            ));
+        }
+        self.exclude
+            .iter()
+            .find(|pattern| matches(pattern))
+            .map(|pattern| format!("matches exclude pattern '{}'", pattern))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_filter() {
+        let filter = SyncFilter {
+            include: Vec::new(),
+            exclude: vec!["*-debuginfo".to_string(), "*.i686".to_string()],
+        };
+        assert_eq!(filter.rejects("bash", Some("x86_64")), None);
+        assert_eq!(
+            filter.rejects("bash-debuginfo", Some("x86_64")).unwrap(),
+            "matches exclude pattern '*-debuginfo'"
+        );
+        assert_eq!(
+            filter.rejects("glibc", Some("i686")).unwrap(),
+            "matches exclude pattern '*.i686'"
+        );
+        assert_eq!(filter.rejects("glibc", None), None);
+
+        let filter = SyncFilter {
+            include: vec!["kernel*".to_string()],
+            exclude: vec!["*-devel".to_string()],
+        };
+        assert_eq!(filter.rejects("kernel-core", None), None);
+        assert!(
+            filter
+                .rejects("kernel-devel", None)
+                .unwrap()
+                .contains("'*-devel'")
+        );
+        assert!(
+            filter
+                .rejects("bash", None)
+                .unwrap()
+                .contains("no include pattern")
+        );
+        assert!(SyncFilter::default().is_empty());
+    }
+}
@@ -6,9 +6,9 @@
 //! using the sequoia-openpgp library (pure Rust implementation).
 
 use crate::error::{Error, Result};
-use sequoia_openpgp as openpgp;
 use openpgp::parse::Parse;
 use openpgp::policy::StandardPolicy;
+use sequoia_openpgp as openpgp;
 use std::fs;
 use std::path::{Path, PathBuf};
 use tracing::{debug, info};
@@ -26,8 +26,9 @@ impl GpgVerifier {
     pub fn new(keyring_dir: PathBuf) -> Result<Self> {
         // Create keyring directory if it doesn't exist
         if !keyring_dir.exists() {
-            fs::create_dir_all(&keyring_dir)
-                .map_err(|e| Error::IoError(format!("Failed to create keyring directory: {}", e)))?;
+            fs::create_dir_all(&keyring_dir).map_err(|e| {
+                Error::IoError(format!("Failed to create keyring directory: {}", e))
+            })?;
         }
 
         Ok(Self {
@@ -53,7 +54,10 @@ impl GpgVerifier {
         fs::write(&key_path, key_data)
             .map_err(|e| Error::IoError(format!("Failed to write GPG key: {}", e)))?;
 
-        info!("Imported GPG key for repository '{}' (fingerprint: {})", repository_name, fingerprint);
+        info!(
+            "Imported GPG key for repository '{}' (fingerprint: {})",
+            repository_name, fingerprint
+        );
         Ok(fingerprint)
     }
 
@@ -83,13 +87,17 @@ impl GpgVerifier {
     ///
     /// # Returns
     /// Ok(()) if the signature is valid, Err otherwise
+    #[allow(clippy::collapsible_if)]
     pub fn verify_signature(
         &self,
         file_path: &Path,
         signature_path: &Path,
         repository_name: &str,
     ) -> Result<()> {
-        debug!("Verifying signature for {:?} using repository '{}'", file_path, repository_name);
+        debug!(
+            "Verifying signature for {:?} using repository '{}'",
+            file_path, repository_name
+        );
 
         // Load the repository's GPG key
         let key_path = self.get_key_path(repository_name);
@@ -140,7 +148,7 @@ impl GpgVerifier {
 
         if !found_valid_signature {
             return Err(Error::GpgVerificationFailed(
-                "No valid signatures found or verification failed".to_string()
+                "No valid signatures found or verification failed".to_string(),
             ));
         }
 
@@ -160,6 +168,7 @@ impl GpgVerifier {
     }
 
     /// List all imported GPG keys
+    #[allow(clippy::collapsible_if)]
     pub fn list_keys(&self) -> Result<Vec<(String, String)>> {
         let mut keys = Vec::new();
 
@@ -168,7 +177,8 @@ impl GpgVerifier {
         }
 
         for entry in fs::read_dir(&self.keyring_dir)
-            .map_err(|e| Error::IoError(format!("Failed to read keyring directory: {}", e)))? {
+            .map_err(|e| Error::IoError(format!("Failed to read keyring directory: {}", e)))?
+        {
             let entry = entry
                 .map_err(|e| Error::IoError(format!("Failed to read directory entry: {}", e)))?;
             let path = entry.path();
@@ -198,7 +208,7 @@ mod tests {
     #[test]
     fn test_verifier_creation() {
         let temp_dir = TempDir::new().unwrap();
-        let verifier = GpgVerifier::new(temp_dir.path().to_path_buf()).unwrap();
+        let _verifier = GpgVerifier::new(temp_dir.path().to_path_buf()).unwrap();
         assert!(temp_dir.path().exists());
     }
 
@@ -0,0 +1,269 @@
+// src/repository/helper.rs
+
+//! External download helpers
+//!
+//! A repository can name a command that downloads its files in place of the
+//! built-in HTTP client, for transports conary does not speak itself (S3
+//! with SigV4, internal artifact stores, torrent mirrors). The contract:
+//!
+//! - The command line is run by `sh -c` with three arguments appended: the
+//!   URL, the destination path and the expected checksum, which is empty for
+//!   metadata files. It is a SHA-256 unless the repository metadata gave a
+//!   package a SHA-512 or MD5 checksum instead.
+//! - The same values are set as `CONARY_URL`, `CONARY_DEST` and
+//!   `CONARY_CHECKSUM`, along with `CONARY_REPOSITORY` (the repository
+//!   name) and `CONARY_KIND` (`package` or `metadata`).
+//! - The helper writes the whole file to the destination and exits 0. Any
+//!   other exit status, or no file at the destination, is a failure.
+//! - Conary checks the checksum itself; a mismatch is a failure too.
+//! - A helper still running after `timeout` is killed along with its
+//!   children. Its output is logged at debug level, and the last line of
+//!   its stderr goes into the error when it fails.
+
+use crate::db::models::Repository;
+use crate::error::{Error, Result};
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Time a helper may take for one file (10 minutes)
+pub const HELPER_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often a running helper is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What a helper is asked to fetch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchKind {
+    Package,
+    Metadata,
+}
+
+impl FetchKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FetchKind::Package => "package",
+            FetchKind::Metadata => "metadata",
+        }
+    }
+}
+
+/// A repository's download helper and the policy around it
+#[derive(Debug, Clone)]
+pub struct DownloadHelper {
+    /// Command line, run with `sh -c`
+    pub command: String,
+    /// Name of the repository, passed to the helper
+    pub repository: String,
+    /// Fetch metadata through the helper as well as packages
+    pub metadata: bool,
+    /// Fall back to the built-in client when the helper fails
+    pub fallback: bool,
+    /// Time after which the helper is killed
+    pub timeout: Duration,
+}
+
+impl DownloadHelper {
+    /// The helper configured for `repo`, if any
+    pub fn for_repository(repo: &Repository) -> Option<Self> {
+        let command = repo.download_helper.as_ref()?;
+        Some(Self {
+            command: command.clone(),
+            repository: repo.name.clone(),
+            metadata: repo.helper_metadata,
+            fallback: repo.helper_fallback,
+            timeout: HELPER_TIMEOUT,
+        })
+    }
+
+    /// Run the helper to fetch `url` into `dest`
+    ///
+    /// Only checks that the helper succeeded and wrote `dest`; verifying
+    /// `checksum` is up to the caller.
+    pub fn fetch(
+        &self,
+        kind: FetchKind,
+        url: &str,
+        dest: &Path,
+        checksum: Option<&str>,
+    ) -> Result<()> {
+        let fail = |reason: String| {
+            Error::DownloadError(format!(
+                "Download helper of {} failed for {}: {}",
+                self.repository, url, reason
+            ))
+        };
+        let checksum = checksum.unwrap_or("");
+        debug!("Running download helper of {} for {}", self.repository, url);
+
+        // A process group of its own, so a timeout also kills what the helper started
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", self.command))
+            .arg("conary-download-helper")
+            .arg(url)
+            .arg(dest)
+            .arg(checksum)
+            .env("CONARY_URL", url)
+            .env("CONARY_DEST", dest)
+            .env("CONARY_CHECKSUM", checksum)
+            .env("CONARY_REPOSITORY", &self.repository)
+            .env("CONARY_KIND", kind.as_str())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()
+            .map_err(|e| fail(format!("cannot run '{}': {}", self.command, e)))?;
+
+        let capture = |pipe: Option<Box<dyn Read + Send>>| {
+            std::thread::spawn(move || {
+                let mut output = Vec::new();
+                if let Some(mut pipe) = pipe {
+                    let _ = pipe.read_to_end(&mut output);
+                }
+                String::from_utf8_lossy(&output).into_owned()
+            })
+        };
+        let stdout = capture(
+            child
+                .stdout
+                .take()
+                .map(|pipe| Box::new(pipe) as Box<dyn Read + Send>),
+        );
+        let stderr = capture(
+            child
+                .stderr
+                .take()
+                .map(|pipe| Box::new(pipe) as Box<dyn Read + Send>),
+        );
+
+        let status = wait_with_timeout(&mut child, self.timeout)?;
+        let (stdout, stderr) = (
+            stdout.join().unwrap_or_default(),
+            stderr.join().unwrap_or_default(),
+        );
+        for line in stdout.lines().chain(stderr.lines()) {
+            debug!("download helper: {}", line);
+        }
+
+        let Some(status) = status else {
+            return Err(fail(format!("timed out after {}s", self.timeout.as_secs())));
+        };
+        if !status.success() {
+            let last_line = stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .unwrap_or("no output");
+            return Err(fail(format!("{}: {}", status, last_line.trim())));
+        }
+        if !dest.is_file() {
+            return Err(fail(format!(
+                "exited successfully but wrote no file to {}",
+                dest.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Wait for `child`, killing its process group after `timeout`
+///
+/// Returns `None` when the helper was killed.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout: Duration,
+) -> Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            // SAFETY: kill(2) with the id of the group spawned above
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper(command: &str) -> DownloadHelper {
+        DownloadHelper {
+            command: command.to_string(),
+            repository: "site".to_string(),
+            metadata: false,
+            fallback: false,
+            timeout: HELPER_TIMEOUT,
+        }
+    }
+
+    #[test]
+    fn test_helper_gets_arguments_and_environment() {
+        let temp = tempfile::tempdir().unwrap();
+        let dest = temp.path().join("out");
+        let script = temp.path().join("fetch.sh");
+        std::fs::write(&script, r#"printf '%s|%s|%s|%s|%s' "$1" "$3" "$CONARY_REPOSITORY" "$CONARY_KIND" "$CONARY_DEST" > "$2""#)
+            .unwrap();
+        let command = format!("sh {}", script.display());
+        helper(&command)
+            .fetch(FetchKind::Package, "s3://bucket/a.rpm", &dest, Some("abc"))
+            .unwrap();
+
+        let written = std::fs::read_to_string(&dest).unwrap();
+        assert_eq!(
+            written,
+            format!("s3://bucket/a.rpm|abc|site|package|{}", dest.display())
+        );
+    }
+
+    #[test]
+    fn test_helper_failure_reports_stderr() {
+        let temp = tempfile::tempdir().unwrap();
+        let dest = temp.path().join("out");
+
+        let err = helper("echo 'access denied' >&2; exit 3").fetch(
+            FetchKind::Metadata,
+            "s3://x",
+            &dest,
+            None,
+        );
+        let err = err.unwrap_err().to_string();
+        assert!(
+            err.contains("access denied") && err.contains("exit status: 3"),
+            "{}",
+            err
+        );
+
+        let err = helper("true")
+            .fetch(FetchKind::Metadata, "s3://x", &dest, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("wrote no file"), "{}", err);
+    }
+
+    #[test]
+    fn test_helper_is_killed_after_timeout() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut slow = helper("sleep 30; true");
+        slow.timeout = Duration::from_millis(200);
+
+        let started = Instant::now();
+        let err = slow
+            .fetch(FetchKind::Package, "s3://x", &temp.path().join("out"), None)
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -4,35 +4,69 @@
 //!
 //! This module provides functionality for:
 //! - Managing remote package repositories
-//! - Synchronizing repository metadata
-//! - Downloading packages with retry and resume support
+//! - Synchronizing repository metadata, skipping unchanged indexes
+//! - Downloading packages with retry and resume support, or through a
+//!   repository's external download helper
+//! - Reading `file://` repositories straight from disk
 //! - Verifying package checksums
 //! - GPG signature verification
 //! - Native metadata format parsing (Arch, Debian, Fedora)
-
-pub mod gpg;
-pub mod parsers;
-pub mod selector;
-
+//! - Well-known default repositories of each supported distribution
+
+mod defaults;
+mod filter;
+mod gpg;
+mod helper;
+mod overlap;
+pub(crate) mod parsers;
+mod resolution;
+mod selector;
+mod urls;
+
+pub use defaults::{DEFAULT_REPOSITORIES, DefaultRepository, DefaultSet, Distro};
+pub use filter::SyncFilter;
 pub use gpg::GpgVerifier;
+pub use helper::{DownloadHelper, FetchKind, HELPER_TIMEOUT};
+pub use overlap::{OverlapReport, PackageOverlap, RepositoryOffer};
 pub use parsers::{ChecksumType, Dependency, DependencyType, RepositoryParser};
-pub use selector::{PackageSelector, PackageWithRepo, SelectionOptions};
-
-use crate::db::models::{PackageDelta, Repository, RepositoryPackage};
+pub use resolution::{Rejection, RepositoryVerdict, ResolutionError, needed_by};
+pub use selector::{Companion, PackageSelector, PackageWithRepo, SelectionOptions};
+pub use urls::{UrlPolicy, resolve_location};
+
+use crate::db::models::{
+    PackageDelta, Repository, RepositoryAdvisory, RepositoryPackage, RepositoryScope, SearchHit,
+};
+use crate::delta::DeltaApplier;
 use crate::error::{Error, Result};
-use rayon::prelude::*;
+use crate::filesystem::CasStore;
+use crate::resources::ResourceLimits;
+use crate::stats;
 use reqwest::blocking::Client;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{self, File};
-use std::io;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{debug, info, warn};
 
-/// Default timeout for HTTP requests (30 seconds)
+/// Timeout for connecting and for each read of a response (30 seconds)
+///
+/// Bodies are read in chunks, so a large download only fails when the
+/// server stalls this long, not when the whole transfer takes longer.
 const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// Size of the chunks response bodies are read in
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Download progress callback
+///
+/// Called with the bytes received so far and the expected total from
+/// Content-Length, when the server sent one.
+pub type ProgressFn<'a> = dyn Fn(u64, Option<u64>) + Sync + 'a;
+
 /// Maximum retry attempts for failed downloads
 const MAX_RETRIES: u32 = 3;
 
@@ -66,62 +100,358 @@ pub struct PackageMetadata {
     pub architecture: Option<String>,
     pub description: Option<String>,
     pub checksum: String,
+    /// Algorithm of `checksum`; SHA-256 when not given
+    pub checksum_type: Option<ChecksumType>,
     pub size: i64,
     pub download_url: String,
     pub dependencies: Option<Vec<String>>,
+    /// Capabilities provided besides the package name ("name" or "name = version")
+    pub provides: Option<Vec<String>>,
+    /// Files the package ships that file dependencies may name
+    pub files: Option<Vec<String>>,
     /// Available delta updates from previous versions
     pub delta_from: Option<Vec<DeltaInfo>>,
 }
 
+/// A repository metadata index file and the validators it was served with
+pub struct MetadataIndex {
+    pub body: Vec<u8>,
+    /// SHA-256 of the body
+    pub checksum: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// What a repository sync did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// New metadata was stored, with this many packages; `skipped` more
+    /// were left out by the repository's sync filter
+    Updated {
+        packages: usize,
+        skipped: usize,
+        changes: IndexChanges,
+    },
+    /// The metadata had not changed since the last sync
+    Unchanged,
+}
+
+/// How a sync changed a repository's package index
+///
+/// Packages are counted by name and architecture: one that only changed
+/// version counts as updated rather than as removed and added.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexChanges {
+    pub added: usize,
+    pub removed: usize,
+    pub updated: usize,
+}
+
+/// The versions of each name and architecture a repository's index holds
+type IndexVersions = HashMap<(String, Option<String>), BTreeSet<String>>;
+
+impl IndexChanges {
+    /// Compare a repository's index before and after a sync
+    fn between(before: &IndexVersions, after: &IndexVersions) -> Self {
+        let mut changes = Self::default();
+        for (key, versions) in after {
+            match before.get(key) {
+                None => changes.added += 1,
+                Some(old) if old != versions => changes.updated += 1,
+                Some(_) => {}
+            }
+        }
+        changes.removed = before
+            .keys()
+            .filter(|key| !after.contains_key(*key))
+            .count();
+        changes
+    }
+}
+
+/// Read the versions a repository's index holds, for [`IndexChanges`]
+fn index_versions(conn: &Connection, repository_id: i64) -> Result<IndexVersions> {
+    let mut stmt = conn.prepare(
+        "SELECT name, architecture, version FROM repository_packages WHERE repository_id = ?1",
+    )?;
+    let rows = stmt.query_map([repository_id], |row| {
+        Ok((
+            (row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?),
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    let mut versions = IndexVersions::new();
+    for row in rows {
+        let (key, version) = row?;
+        versions.entry(key).or_default().insert(version);
+    }
+    Ok(versions)
+}
+
+/// Retry and resume behavior of a `RepositoryClient`
+#[derive(Debug, Clone, Copy)]
+pub struct ClientOptions {
+    /// Attempts made before a request is given up on
+    pub max_retries: u32,
+    /// Continue a partial `.tmp` download with a Range request instead of
+    /// starting over
+    pub resume: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: MAX_RETRIES,
+            resume: true,
+        }
+    }
+}
+
 /// HTTP client wrapper with retry support
 pub struct RepositoryClient {
     client: Client,
     max_retries: u32,
+    resume: bool,
+    /// Bytes received by this client (for bandwidth statistics)
+    downloaded: AtomicU64,
+    /// External command that fetches files in place of HTTP
+    helper: Option<DownloadHelper>,
 }
 
 impl RepositoryClient {
     /// Create a new repository client
     pub fn new() -> Result<Self> {
+        Self::new_with_options(ClientOptions::default())
+    }
+
+    /// Create a repository client with custom retry and resume behavior
+    pub fn new_with_options(options: ClientOptions) -> Result<Self> {
         let client = Client::builder()
+            .connect_timeout(HTTP_TIMEOUT)
             .timeout(HTTP_TIMEOUT)
             .build()
-            .map_err(|e| Error::InitError(format!("Failed to create HTTP client: {}", e)))?;
+            .map_err(|e| Error::DownloadError(format!("Failed to create HTTP client: {}", e)))?;
 
         Ok(Self {
             client,
-            max_retries: MAX_RETRIES,
+            max_retries: options.max_retries,
+            resume: options.resume,
+            downloaded: AtomicU64::new(0),
+            helper: None,
+        })
+    }
+
+    /// Create a client for `repo`, using its download helper if it has one
+    pub fn for_repository(repo: &Repository) -> Result<Self> {
+        let client = Self::new()?;
+        Ok(match DownloadHelper::for_repository(repo) {
+            Some(helper) => client.with_helper(helper),
+            None => client,
+        })
+    }
+
+    /// Fetch packages, and metadata when the helper asks for it, through `helper`
+    pub fn with_helper(mut self, helper: DownloadHelper) -> Self {
+        self.helper = Some(helper);
+        self
+    }
+
+    /// Fetch `url` through the download helper into `dest_path`
+    ///
+    /// The helper writes to a temporary file that is renamed into place
+    /// once its checksum, when known, has been verified.
+    fn fetch_with_helper(
+        &self,
+        helper: &DownloadHelper,
+        kind: FetchKind,
+        url: &str,
+        dest_path: &Path,
+        expected: Option<(ChecksumType, &str)>,
+        progress: Option<&ProgressFn>,
+    ) -> Result<()> {
+        let mut temp_name = dest_path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".helper");
+        let temp_path = dest_path.with_file_name(temp_name);
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                Error::IoError(format!(
+                    "Failed to create directory {}: {}",
+                    parent.display(),
+                    e
+                ))
+            })?;
+        }
+        let _ = fs::remove_file(&temp_path);
+
+        let checksum = expected.map(|(_, checksum)| checksum);
+        let fetched = helper
+            .fetch(kind, url, &temp_path, checksum)
+            .and_then(|()| match expected {
+                Some((checksum_type, expected)) => {
+                    verify_checksum(&temp_path, expected, checksum_type)
+                }
+                None => Ok(()),
+            });
+        if let Err(e) = fetched {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+
+        let size = fs::metadata(&temp_path).map_or(0, |meta| meta.len());
+        self.downloaded.fetch_add(size, Ordering::Relaxed);
+        if let Some(progress) = progress {
+            progress(size, Some(size));
+        }
+        fs::rename(&temp_path, dest_path).map_err(|e| {
+            Error::IoError(format!(
+                "Failed to move {} to {}: {}",
+                temp_path.display(),
+                dest_path.display(),
+                e
+            ))
         })
     }
 
+    /// Run `fetch` through the download helper when one applies to `kind`
+    ///
+    /// Returns `None` when there is no helper for `kind`, or when it failed
+    /// and falling back to the built-in client is allowed.
+    fn try_helper<T>(
+        &self,
+        kind: FetchKind,
+        url: &str,
+        fetch: impl FnOnce(&DownloadHelper) -> Result<T>,
+    ) -> Option<Result<T>> {
+        let helper = self
+            .helper
+            .as_ref()
+            .filter(|helper| kind == FetchKind::Package || helper.metadata)?;
+        match fetch(helper) {
+            Err(e) if helper.fallback => {
+                warn!("{}; falling back to the built-in client for {}", e, url);
+                None
+            }
+            result => Some(result),
+        }
+    }
+
+    /// Total bytes received by this client so far
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
     /// Fetch repository metadata from URL with retry support
     pub fn fetch_metadata(&self, url: &str) -> Result<RepositoryMetadata> {
-        let metadata_url = if url.ends_with('/') {
-            format!("{}metadata.json", url)
-        } else {
-            format!("{}/metadata.json", url)
-        };
+        let index = self
+            .fetch_index(&metadata_json_url(url), None, None, None)?
+            .ok_or_else(|| {
+                Error::DownloadError(format!(
+                    "Unexpected 304 Not Modified from {}",
+                    metadata_json_url(url)
+                ))
+            })?;
+        parse_metadata_json(&index.body)
+    }
+
+    /// Fetch a metadata index file with retry support
+    ///
+    /// `etag` and `last_modified` from an earlier fetch are sent as
+    /// If-None-Match and If-Modified-Since; `None` is returned when the
+    /// server answers 304 Not Modified.
+    pub fn fetch_index(
+        &self,
+        url: &str,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+        progress: Option<&ProgressFn>,
+    ) -> Result<Option<MetadataIndex>> {
+        info!("Fetching repository metadata from {}", url);
+
+        // A helper has no conditional requests; an unchanged body is still
+        // caught by its checksum
+        let fetched = self.try_helper(FetchKind::Metadata, url, |helper| {
+            let dir = tempfile::tempdir()?;
+            let dest_path = dir.path().join("index");
+            self.fetch_with_helper(helper, FetchKind::Metadata, url, &dest_path, None, progress)?;
+            let body = fs::read(&dest_path)?;
+            Ok(Some(MetadataIndex {
+                checksum: sha256_hex(&body),
+                body,
+                etag: None,
+                last_modified: None,
+            }))
+        });
+        if let Some(result) = fetched {
+            return result;
+        }
 
-        info!("Fetching repository metadata from {}", metadata_url);
+        // A local index has no validators either
+        if let Some(path) = local_path(url) {
+            let mut body = Vec::new();
+            self.read_local(&path, &mut body, progress).map_err(|e| {
+                Error::DownloadError(format!("Failed to read metadata {}: {}", path.display(), e))
+            })?;
+            return Ok(Some(MetadataIndex {
+                checksum: sha256_hex(&body),
+                body,
+                etag: None,
+                last_modified: None,
+            }));
+        }
 
         let mut attempt = 0;
         loop {
             attempt += 1;
-            match self.client.get(&metadata_url).send() {
-                Ok(response) => {
+            let mut request = self.client.get(url);
+            if let Some(etag) = etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+
+            match request.send() {
+                Ok(mut response) => {
+                    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+                        debug!("{} not modified", url);
+                        return Ok(None);
+                    }
                     if !response.status().is_success() {
                         return Err(Error::DownloadError(format!(
                             "HTTP {} from {}",
                             response.status(),
-                            metadata_url
+                            url
                         )));
                     }
 
-                    let metadata: RepositoryMetadata = response.json().map_err(|e| {
-                        Error::DownloadError(format!("Failed to parse metadata JSON: {}", e))
-                    })?;
+                    // Mirrors often redirect to the real file
+                    if response.url().as_str() != url {
+                        info!("{} resolved to {}", url, response.url());
+                    }
 
-                    info!("Successfully fetched metadata for {} packages", metadata.packages.len());
-                    return Ok(metadata);
+                    let header = |name| {
+                        response
+                            .headers()
+                            .get(name)
+                            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                            .map(str::to_string)
+                    };
+                    let etag = header(reqwest::header::ETAG);
+                    let last_modified = header(reqwest::header::LAST_MODIFIED);
+
+                    let mut body = Vec::new();
+                    let total = response.content_length();
+                    self.read_body(&mut response, total, &mut body, progress)
+                        .map_err(|e| {
+                            Error::DownloadError(format!("Failed to read metadata: {}", e))
+                        })?;
+
+                    return Ok(Some(MetadataIndex {
+                        checksum: sha256_hex(&body),
+                        body,
+                        etag,
+                        last_modified,
+                    }));
                 }
                 Err(e) => {
                     if attempt >= self.max_retries {
@@ -130,73 +460,279 @@ impl RepositoryClient {
                             attempt, e
                         )));
                     }
-                    warn!("Metadata fetch attempt {} failed: {}, retrying...", attempt, e);
+                    warn!(
+                        "Metadata fetch attempt {} failed: {}, retrying...",
+                        attempt, e
+                    );
                     std::thread::sleep(Duration::from_millis(RETRY_DELAY_MS * attempt as u64));
                 }
             }
         }
     }
 
+    /// Copy a response body of `total` bytes, when known, into `dest`,
+    /// counting and reporting the bytes
+    fn read_body(
+        &self,
+        body: &mut dyn Read,
+        total: Option<u64>,
+        dest: &mut dyn io::Write,
+        progress: Option<&ProgressFn>,
+    ) -> io::Result<u64> {
+        let mut buf = vec![0u8; READ_CHUNK_SIZE];
+        let mut received = 0;
+        if let Some(progress) = progress {
+            progress(0, total);
+        }
+
+        loop {
+            let n = match body.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            dest.write_all(&buf[..n])?;
+            received += n as u64;
+            self.downloaded.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(progress) = progress {
+                progress(received, total);
+            }
+            fail_point!("download::body", |message| io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                message
+            ));
+        }
+
+        Ok(received)
+    }
+
+    /// Copy the local file at `path` into `dest`, as `read_body` does a response
+    fn read_local(
+        &self,
+        path: &Path,
+        dest: &mut dyn io::Write,
+        progress: Option<&ProgressFn>,
+    ) -> io::Result<u64> {
+        let mut file = File::open(path)?;
+        let total = file.metadata()?.len();
+        self.read_body(&mut file, Some(total), dest, progress)
+    }
+
     /// Download a file to the specified path with retry support
-    pub fn download_file(&self, url: &str, dest_path: &Path) -> Result<()> {
+    ///
+    /// The body is written to a `.tmp` file next to `dest_path` and renamed
+    /// into place once complete. With resume enabled, a partial `.tmp` file
+    /// left by an earlier attempt or run is continued with a Range request;
+    /// a server that answers 200 instead of 206 gets the download restarted.
+    /// `progress` is called as the body arrives.
+    pub fn download_file(
+        &self,
+        url: &str,
+        dest_path: &Path,
+        progress: Option<&ProgressFn>,
+    ) -> Result<()> {
+        self.download_resumable(url, dest_path, self.resume, progress)
+            .map(|_| ())
+    }
+
+    /// Download a file and check its checksum, made with `checksum_type`
+    ///
+    /// Goes through the download helper when the client has one. When a
+    /// resumed download fails the check, the partial data it built on may
+    /// have been stale, so the file is downloaded again from scratch.
+    pub fn download_verified(
+        &self,
+        url: &str,
+        dest_path: &Path,
+        expected: &str,
+        checksum_type: ChecksumType,
+        progress: Option<&ProgressFn>,
+    ) -> Result<()> {
+        let fetched = self.try_helper(FetchKind::Package, url, |helper| {
+            self.fetch_with_helper(
+                helper,
+                FetchKind::Package,
+                url,
+                dest_path,
+                Some((checksum_type, expected)),
+                progress,
+            )
+        });
+        if let Some(result) = fetched {
+            return result;
+        }
+
+        let resumed = self.download_resumable(url, dest_path, self.resume, progress)?;
+        fail_point!("download::verify", |actual| Error::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual
+        });
+        match verify_checksum(dest_path, expected, checksum_type) {
+            Err(Error::ChecksumMismatch { .. }) if resumed => {
+                warn!(
+                    "Resumed download of {} failed its checksum, downloading again",
+                    url
+                );
+                fs::remove_file(dest_path).map_err(|e| {
+                    Error::IoError(format!("Failed to remove {}: {}", dest_path.display(), e))
+                })?;
+                self.download_resumable(url, dest_path, false, progress)?;
+                verify_checksum(dest_path, expected, checksum_type)
+            }
+            result => result,
+        }
+    }
+
+    /// Download `url` to `dest_path`; returns whether a partial file was continued
+    fn download_resumable(
+        &self,
+        url: &str,
+        dest_path: &Path,
+        resume: bool,
+        progress: Option<&ProgressFn>,
+    ) -> Result<bool> {
         info!("Downloading {} to {}", url, dest_path.display());
 
         // Create parent directory if it doesn't exist
         if let Some(parent) = dest_path.parent() {
             fs::create_dir_all(parent).map_err(|e| {
-                Error::IoError(format!("Failed to create directory {}: {}", parent.display(), e))
+                Error::IoError(format!(
+                    "Failed to create directory {}: {}",
+                    parent.display(),
+                    e
+                ))
+            })?;
+        }
+
+        // Write to temporary file first
+        let temp_path = dest_path.with_extension("tmp");
+        if !resume && temp_path.exists() {
+            fs::remove_file(&temp_path).map_err(|e| {
+                Error::IoError(format!("Failed to remove {}: {}", temp_path.display(), e))
+            })?;
+        }
+
+        // A local file is copied whole; there is nothing to retry or resume
+        if let Some(path) = local_path(url) {
+            let mut file = File::create(&temp_path).map_err(|e| {
+                Error::IoError(format!(
+                    "Failed to create file {}: {}",
+                    temp_path.display(),
+                    e
+                ))
+            })?;
+            self.read_local(&path, &mut file, progress).map_err(|e| {
+                Error::DownloadError(format!("Failed to copy {}: {}", path.display(), e))
+            })?;
+            fs::rename(&temp_path, dest_path).map_err(|e| {
+                Error::IoError(format!(
+                    "Failed to move {} to {}: {}",
+                    temp_path.display(),
+                    dest_path.display(),
+                    e
+                ))
             })?;
+            return Ok(false);
         }
 
+        let mut resumed = false;
         let mut attempt = 0;
         loop {
             attempt += 1;
-            match self.client.get(url).send() {
+            let offset = fs::metadata(&temp_path).map(|meta| meta.len()).unwrap_or(0);
+            let mut request = self.client.get(url);
+            if offset > 0 {
+                debug!("Resuming {} at byte {}", url, offset);
+                request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+            }
+
+            let error = match request.send() {
                 Ok(mut response) => {
-                    if !response.status().is_success() {
+                    let status = response.status();
+                    if offset > 0 && status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+                        // The partial file is no prefix of this one; start over
+                        warn!("Server refused to resume {}, restarting download", url);
+                        fs::remove_file(&temp_path).map_err(|e| {
+                            Error::IoError(format!(
+                                "Failed to remove {}: {}",
+                                temp_path.display(),
+                                e
+                            ))
+                        })?;
+                        continue;
+                    }
+                    if !status.is_success() {
                         return Err(Error::DownloadError(format!(
                             "HTTP {} from {}",
-                            response.status(),
-                            url
+                            status, url
                         )));
                     }
 
-                    // Write to temporary file first
-                    let temp_path = dest_path.with_extension("tmp");
-                    let mut file = File::create(&temp_path).map_err(|e| {
-                        Error::IoError(format!("Failed to create file {}: {}", temp_path.display(), e))
-                    })?;
-
-                    // Copy response body to file
-                    io::copy(&mut response, &mut file).map_err(|e| {
-                        Error::IoError(format!("Failed to write downloaded data: {}", e))
-                    })?;
-
-                    // Atomic rename from temp to final destination
-                    fs::rename(&temp_path, dest_path).map_err(|e| {
-                        Error::IoError(format!(
-                            "Failed to move {} to {}: {}",
-                            temp_path.display(),
-                            dest_path.display(),
-                            e
-                        ))
-                    })?;
-
-                    info!("Successfully downloaded to {}", dest_path.display());
-                    return Ok(());
-                }
-                Err(e) => {
-                    if attempt >= self.max_retries {
-                        return Err(Error::DownloadError(format!(
-                            "Failed to download after {} attempts: {}",
-                            attempt, e
-                        )));
+                    // Anything but 206 is the whole file again
+                    let append = offset > 0 && status == reqwest::StatusCode::PARTIAL_CONTENT;
+                    if offset > 0 && !append {
+                        info!(
+                            "Server ignored the range request for {}, downloading in full",
+                            url
+                        );
+                    }
+                    let mut file = fs::OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .append(append)
+                        .truncate(!append)
+                        .open(&temp_path)
+                        .map_err(|e| {
+                            Error::IoError(format!(
+                                "Failed to create file {}: {}",
+                                temp_path.display(),
+                                e
+                            ))
+                        })?;
+                    let base = if append { offset } else { 0 };
+                    resumed |= append;
+
+                    // Copy response body to file, reporting progress over the whole file
+                    let report = |received: u64, total: Option<u64>| {
+                        if let Some(progress) = progress {
+                            progress(base + received, total.map(|total| base + total));
+                        }
+                    };
+                    let total = response.content_length();
+                    match self.read_body(&mut response, total, &mut file, Some(&report)) {
+                        Ok(_) => break,
+                        Err(e) => Error::IoError(format!("Failed to write downloaded data: {}", e)),
                     }
-                    warn!("Download attempt {} failed: {}, retrying...", attempt, e);
-                    std::thread::sleep(Duration::from_millis(RETRY_DELAY_MS * attempt as u64));
                 }
+                Err(e) => Error::DownloadError(e.to_string()),
+            };
+
+            if attempt >= self.max_retries {
+                return Err(Error::DownloadError(format!(
+                    "Failed to download after {} attempts: {}",
+                    attempt, error
+                )));
             }
+            warn!(
+                "Download attempt {} failed: {}, retrying...",
+                attempt, error
+            );
+            std::thread::sleep(Duration::from_millis(RETRY_DELAY_MS * attempt as u64));
         }
+
+        // Atomic rename from temp to final destination
+        fs::rename(&temp_path, dest_path).map_err(|e| {
+            Error::IoError(format!(
+                "Failed to move {} to {}: {}",
+                temp_path.display(),
+                dest_path.display(),
+                e
+            ))
+        })?;
+
+        info!("Successfully downloaded to {}", dest_path.display());
+        Ok(resumed)
     }
 }
 
@@ -224,14 +760,16 @@ fn detect_repository_format(name: &str, url: &str) -> RepositoryFormat {
     if name_lower.contains("arch")
         || url_lower.contains("archlinux")
         || url_lower.contains("pkgbuild")
-        || url_lower.contains(".db.tar") {
+        || url_lower.contains(".db.tar")
+    {
         return RepositoryFormat::Arch;
     }
 
     // Check for Fedora indicators
     if name_lower.contains("fedora")
         || url_lower.contains("fedora")
-        || url_lower.contains("/repodata/") {
+        || url_lower.contains("/repodata/")
+    {
         return RepositoryFormat::Fedora;
     }
 
@@ -240,7 +778,8 @@ fn detect_repository_format(name: &str, url: &str) -> RepositoryFormat {
         || name_lower.contains("ubuntu")
         || url_lower.contains("debian")
         || url_lower.contains("ubuntu")
-        || url_lower.contains("/dists/") {
+        || url_lower.contains("/dists/")
+    {
         return RepositoryFormat::Debian;
     }
 
@@ -248,16 +787,23 @@ fn detect_repository_format(name: &str, url: &str) -> RepositoryFormat {
     RepositoryFormat::Json
 }
 
-/// Synchronize repository using native metadata format parsers
-fn sync_repository_native(
-    conn: &Connection,
+/// Fetch and parse a repository's index with its native metadata format parser
+///
+/// Returns `None` when the index is unchanged since the last sync.
+fn fetch_native(
+    client: &RepositoryClient,
     repo: &mut Repository,
     format: RepositoryFormat,
-) -> Result<usize> {
-    info!("Syncing repository {} using native {:?} format", repo.name, format);
+    force: bool,
+    progress: Option<&ProgressFn>,
+) -> Result<Option<(MetadataIndex, Vec<parsers::PackageMetadata>)>> {
+    info!(
+        "Syncing repository {} using native {:?} format",
+        repo.name, format
+    );
 
-    // Parse metadata using appropriate parser
-    let packages = match format {
+    // Select the appropriate parser
+    let parser: Box<dyn RepositoryParser> = match format {
         RepositoryFormat::Arch => {
             // Extract repository name from repo.name (e.g., "arch-core" -> "core")
             let repo_name = if let Some(suffix) = repo.name.strip_prefix("arch-") {
@@ -266,11 +812,11 @@ fn sync_repository_native(
                 "core".to_string()
             };
 
-            let parser = parsers::arch::ArchParser::new(repo_name);
-            parser.sync_metadata(&repo.url)?
+            Box::new(parsers::arch::ArchParser::new(repo_name))
         }
         RepositoryFormat::Debian => {
-            // For Ubuntu/Debian, we need distribution, component, and architecture
+            // For Ubuntu/Debian, we need the distribution, then the components
+            // and architectures configured on the repository
             // Extract from repository name: "ubuntu-noble" -> noble
             let distribution = if let Some(suffix) = repo.name.strip_prefix("ubuntu-") {
                 suffix.to_string()
@@ -280,48 +826,73 @@ fn sync_repository_native(
                 "noble".to_string()
             };
 
-            let parser = parsers::debian::DebianParser::new(
+            Box::new(parsers::debian::DebianParser::new(
                 distribution,
-                "main".to_string(),
-                "amd64".to_string(),
-            );
-            parser.sync_metadata(&repo.url)?
+                repo.components.clone(),
+                repo.architectures.clone(),
+            ))
         }
         RepositoryFormat::Fedora => {
-            let parser = parsers::fedora::FedoraParser::new("x86_64".to_string());
-            parser.sync_metadata(&repo.url)?
+            Box::new(parsers::fedora::FedoraParser::new("x86_64".to_string()))
         }
         RepositoryFormat::Json => {
-            return Err(Error::ParseError("JSON format should use sync_repository".to_string()));
+            return Err(Error::ParseError(
+                "JSON format should use sync_repository".to_string(),
+            ));
         }
     };
 
-    // Delete old package entries for this repository
-    RepositoryPackage::delete_by_repository(conn, repo.id.unwrap())?;
+    let policy = UrlPolicy::for_repository(repo);
+    let Some(index) = fetch_changed_index(
+        client,
+        repo,
+        &parser.index_url(policy.base()),
+        force,
+        progress,
+    )?
+    else {
+        return Ok(None);
+    };
+    let packages = parser.parse_index(client, policy.base(), &index.body, progress)?;
+    policy.report(packages.iter().map(|pkg| pkg.download_url.as_str()));
+    Ok(Some((index, packages)))
+}
+
+/// Replace a repository's package index with packages from a native parser
+///
+/// Returns the number of packages stored.
+fn store_native_packages(
+    conn: &Connection,
+    repository_id: i64,
+    packages: Vec<parsers::PackageMetadata>,
+) -> Result<usize> {
+    // Delete old package entries and advisories for this repository
+    RepositoryPackage::delete_by_repository(conn, repository_id)?;
+    RepositoryAdvisory::delete_by_repository(conn, repository_id)?;
 
     // Convert and insert package metadata
     let mut count = 0;
     for pkg_meta in packages {
         // Convert parsers::Dependency to Vec<String>
-        let deps_json = if !pkg_meta.dependencies.is_empty() {
-            let dep_strings: Vec<String> = pkg_meta
-                .dependencies
-                .iter()
-                .map(|dep| {
-                    if let Some(constraint) = &dep.constraint {
-                        format!("{} {}", dep.name, constraint)
-                    } else {
-                        dep.name.clone()
-                    }
-                })
-                .collect();
-            Some(serde_json::to_string(&dep_strings).unwrap_or_default())
-        } else {
-            None
-        };
+        let dep_strings: Vec<String> = pkg_meta
+            .dependencies
+            .iter()
+            .map(|dep| {
+                if let Some(constraint) = &dep.constraint {
+                    format!("{} {}", dep.name, constraint)
+                } else {
+                    dep.name.clone()
+                }
+            })
+            .collect();
+        let provides: Vec<(String, Option<String>)> = pkg_meta
+            .provides
+            .into_iter()
+            .map(|provide| (provide.name, provide.version))
+            .collect();
 
         let mut repo_pkg = RepositoryPackage::new(
-            repo.id.unwrap(),
+            repository_id,
             pkg_meta.name,
             pkg_meta.version,
             pkg_meta.checksum,
@@ -329,57 +900,54 @@ fn sync_repository_native(
             pkg_meta.download_url,
         );
 
+        repo_pkg.checksum_type = pkg_meta.checksum_type;
         repo_pkg.architecture = pkg_meta.architecture;
         repo_pkg.description = pkg_meta.description;
-        repo_pkg.dependencies = deps_json;
+        if !pkg_meta.extra_metadata.is_null() {
+            repo_pkg.metadata = Some(pkg_meta.extra_metadata.to_string());
+        }
 
         repo_pkg.insert(conn)?;
+        repo_pkg.insert_dependencies(conn, &dep_strings)?;
+        repo_pkg.insert_provides(conn, &provides)?;
+        repo_pkg.insert_files(conn, &pkg_meta.files)?;
+        for advisory in pkg_meta.advisories {
+            let mut entry = RepositoryAdvisory::new(
+                repository_id,
+                advisory.id,
+                advisory.kind,
+                repo_pkg.name.clone(),
+                repo_pkg.version.clone(),
+            );
+            entry.severity = advisory.severity;
+            entry.cves = advisory.cves;
+            entry.insert(conn)?;
+        }
         count += 1;
     }
 
-    // Update last_sync timestamp
-    repo.last_sync = Some(current_timestamp());
-    repo.update(conn)?;
-
-    info!("Synchronized {} packages from repository {}", count, repo.name);
     Ok(count)
 }
 
-/// Synchronize repository metadata with the database
-pub fn sync_repository(conn: &Connection, repo: &mut Repository) -> Result<usize> {
-    info!("Synchronizing repository: {}", repo.name);
-
-    // Detect repository format
-    let format = detect_repository_format(&repo.name, &repo.url);
-
-    // Try native format first if detected
-    if format != RepositoryFormat::Json {
-        match sync_repository_native(conn, repo, format) {
-            Ok(count) => return Ok(count),
-            Err(e) => {
-                warn!("Native format sync failed: {}, falling back to JSON", e);
-            }
-        }
-    }
-
-    // Fall back to JSON metadata format
-    let client = RepositoryClient::new()?;
-    let metadata = client.fetch_metadata(&repo.url)?;
-
-    // Delete old package entries for this repository
-    RepositoryPackage::delete_by_repository(conn, repo.id.unwrap())?;
+/// Replace a repository's package index with parsed JSON metadata
+///
+/// Returns the number of packages and deltas stored.
+fn store_repository_metadata(
+    conn: &Connection,
+    repository_id: i64,
+    metadata: RepositoryMetadata,
+) -> Result<(usize, usize)> {
+    // Delete old package entries and advisories for this repository
+    RepositoryPackage::delete_by_repository(conn, repository_id)?;
+    RepositoryAdvisory::delete_by_repository(conn, repository_id)?;
 
     // Insert new package metadata
     let mut count = 0;
     let mut delta_count = 0;
 
     for pkg_meta in metadata.packages {
-        let deps_json = pkg_meta.dependencies.as_ref().map(|deps| {
-            serde_json::to_string(deps).unwrap_or_default()
-        });
-
         let mut repo_pkg = RepositoryPackage::new(
-            repo.id.unwrap(),
+            repository_id,
             pkg_meta.name.clone(),
             pkg_meta.version.clone(),
             pkg_meta.checksum.clone(),
@@ -387,11 +955,29 @@ pub fn sync_repository(conn: &Connection, repo: &mut Repository) -> Result<usize
             pkg_meta.download_url,
         );
 
+        repo_pkg.checksum_type = pkg_meta.checksum_type.unwrap_or(ChecksumType::Sha256);
         repo_pkg.architecture = pkg_meta.architecture;
         repo_pkg.description = pkg_meta.description;
-        repo_pkg.dependencies = deps_json;
 
         repo_pkg.insert(conn)?;
+        if let Some(deps) = &pkg_meta.dependencies {
+            repo_pkg.insert_dependencies(conn, deps)?;
+        }
+        if let Some(provides) = &pkg_meta.provides {
+            let provides: Vec<(String, Option<String>)> = provides
+                .iter()
+                .map(|provide| match provide.split_once('=') {
+                    Some((name, version)) => {
+                        (name.trim().to_string(), Some(version.trim().to_string()))
+                    }
+                    None => (provide.trim().to_string(), None),
+                })
+                .collect();
+            repo_pkg.insert_provides(conn, &provides)?;
+        }
+        if let Some(files) = &pkg_meta.files {
+            repo_pkg.insert_files(conn, files)?;
+        }
         count += 1;
 
         // Store delta metadata if available
@@ -415,82 +1001,420 @@ pub fn sync_repository(conn: &Connection, repo: &mut Repository) -> Result<usize
         }
     }
 
-    // Update last_sync timestamp
-    repo.last_sync = Some(current_timestamp());
-    repo.update(conn)?;
-
-    info!(
-        "Synchronized {} packages and {} deltas from repository {}",
-        count, delta_count, repo.name
-    );
-    Ok(count)
+    Ok((count, delta_count))
 }
 
-/// Check if repository metadata needs refresh
-pub fn needs_sync(repo: &Repository) -> bool {
-    match &repo.last_sync {
-        None => true, // Never synced
-        Some(last_sync) => {
-            // Parse timestamp and check if expired
-            match parse_timestamp(last_sync) {
-                Ok(last_sync_time) => {
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
+/// Fetch a repository's metadata index unless it is unchanged since the last sync
+///
+/// The stored ETag and Last-Modified go out as conditional headers, and a
+/// body whose SHA-256 matches the stored checksum counts as unchanged too;
+/// its new validators are kept on `repo`. With `force` the index is always
+/// fetched and used. Returns `None` when the index is unchanged.
+fn fetch_changed_index(
+    client: &RepositoryClient,
+    repo: &mut Repository,
+    url: &str,
+    force: bool,
+    progress: Option<&ProgressFn>,
+) -> Result<Option<MetadataIndex>> {
+    if force {
+        return client.fetch_index(url, None, None, progress);
+    }
 
-                    let age_seconds = now.saturating_sub(last_sync_time);
-                    age_seconds > repo.metadata_expire as u64
-                }
-                Err(_) => true, // If we can't parse timestamp, force sync
-            }
-        }
+    let etag = repo.metadata_etag.as_deref();
+    let Some(index) =
+        client.fetch_index(url, etag, repo.metadata_last_modified.as_deref(), progress)?
+    else {
+        info!("Metadata of {} not modified on the server", repo.name);
+        return Ok(None);
+    };
+    if repo.metadata_checksum.as_deref() == Some(index.checksum.as_str()) {
+        info!("Metadata of {} is unchanged", repo.name);
+        repo.metadata_etag = index.etag;
+        repo.metadata_last_modified = index.last_modified;
+        return Ok(None);
     }
+    Ok(Some(index))
 }
 
-/// Download a package from a repository
-pub fn download_package(
-    repo_pkg: &RepositoryPackage,
-    dest_dir: &Path,
-) -> Result<PathBuf> {
-    let client = RepositoryClient::new()?;
+/// Record a sync of `index` on the repository, without saving it
+fn set_synced(repo: &mut Repository, index: &MetadataIndex) {
+    repo.metadata_etag = index.etag.clone();
+    repo.metadata_last_modified = index.last_modified.clone();
+    repo.metadata_checksum = Some(index.checksum.clone());
+    repo.last_sync = Some(current_timestamp());
+}
 
-    // Construct destination path
-    let default_filename = format!("{}-{}.rpm", repo_pkg.name, repo_pkg.version);
-    let filename = repo_pkg
-        .download_url
-        .split('/')
-        .next_back()
-        .unwrap_or(&default_filename);
+/// Save a sync that found the metadata unchanged
+fn mark_unchanged(conn: &Connection, repo: &mut Repository) -> Result<SyncOutcome> {
+    repo.last_sync = Some(current_timestamp());
+    repo.update(conn)?;
+    Ok(SyncOutcome::Unchanged)
+}
 
-    let dest_path = dest_dir.join(filename);
+/// The local file a `file://` URL names, if it is one
+fn local_path(url: &str) -> Option<PathBuf> {
+    let url = reqwest::Url::parse(url).ok()?;
+    if url.scheme() != "file" {
+        return None;
+    }
+    url.to_file_path().ok()
+}
 
-    // Download the file
-    client.download_file(&repo_pkg.download_url, &dest_path)?;
+/// URL of the JSON metadata index under a repository URL
+fn metadata_json_url(url: &str) -> String {
+    format!("{}/metadata.json", url.trim_end_matches('/'))
+}
 
-    // Verify checksum
-    verify_checksum(&dest_path, &repo_pkg.checksum)?;
+/// Parse a JSON metadata index
+fn parse_metadata_json(body: &[u8]) -> Result<RepositoryMetadata> {
+    let metadata: RepositoryMetadata = serde_json::from_slice(body)
+        .map_err(|e| Error::DownloadError(format!("Failed to parse metadata JSON: {}", e)))?;
+    info!(
+        "Successfully fetched metadata for {} packages",
+        metadata.packages.len()
+    );
+    Ok(metadata)
+}
 
-    Ok(dest_path)
+/// Hex SHA-256 of a byte slice
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    format!("{:x}", Sha256::digest(data))
 }
 
-/// Download a delta update file
+/// A repository's package list, as parsed from its metadata
+enum ParsedIndex {
+    Native(Vec<parsers::PackageMetadata>),
+    Json(RepositoryMetadata),
+}
+
+/// Repository metadata downloaded and parsed by `fetch_repository`
 ///
-/// # Arguments
-/// * `delta_info` - Delta metadata from repository
-/// * `package_name` - Name of the package (for filename construction)
-/// * `to_version` - Target version (for filename construction)
-/// * `dest_dir` - Destination directory for the delta file
+/// Holds no database state, so several repositories can be fetched in
+/// parallel while `store_fetched` writes the results one at a time.
+pub struct FetchedMetadata {
+    /// Bytes downloaded, counting failed attempts
+    pub bytes_downloaded: u64,
+    /// The new index and its packages, or `None` when unchanged
+    result: Result<Option<(MetadataIndex, ParsedIndex)>>,
+}
+
+/// Download and parse a repository's metadata without touching the database
 ///
-/// # Returns
-/// Path to the downloaded and verified delta file
-pub fn download_delta(
-    delta_info: &DeltaInfo,
-    package_name: &str,
+/// Native formats are tried first, falling back to the JSON index. New
+/// HTTP validators of an unchanged index are kept on `repo` for
+/// `store_fetched` to save.
+pub fn fetch_repository(
+    repo: &mut Repository,
+    force: bool,
+    progress: Option<&ProgressFn>,
+) -> FetchedMetadata {
+    let client = match RepositoryClient::for_repository(repo) {
+        Ok(client) => client,
+        Err(e) => {
+            return FetchedMetadata {
+                bytes_downloaded: 0,
+                result: Err(e),
+            };
+        }
+    };
+
+    // Try native format first if detected
+    let format = detect_repository_format(&repo.name, &repo.url);
+    if format != RepositoryFormat::Json {
+        match fetch_native(&client, repo, format, force, progress) {
+            Ok(fetched) => {
+                return FetchedMetadata {
+                    bytes_downloaded: client.bytes_downloaded(),
+                    result: Ok(
+                        fetched.map(|(index, packages)| (index, ParsedIndex::Native(packages)))
+                    ),
+                };
+            }
+            Err(e) => {
+                warn!("Native format sync failed: {}, falling back to JSON", e);
+            }
+        }
+    }
+
+    // Fall back to JSON metadata format
+    let result = (|| -> Result<_> {
+        let policy = UrlPolicy::for_repository(repo);
+        let Some(index) = fetch_changed_index(
+            &client,
+            repo,
+            &metadata_json_url(policy.base()),
+            force,
+            progress,
+        )?
+        else {
+            return Ok(None);
+        };
+        let mut metadata = parse_metadata_json(&index.body)?;
+        for pkg in &mut metadata.packages {
+            pkg.download_url = resolve_location(policy.base(), &pkg.download_url);
+            for delta in pkg.delta_from.iter_mut().flatten() {
+                delta.delta_url = resolve_location(policy.base(), &delta.delta_url);
+            }
+        }
+        policy.report(
+            metadata
+                .packages
+                .iter()
+                .map(|pkg| pkg.download_url.as_str()),
+        );
+        Ok(Some((index, ParsedIndex::Json(metadata))))
+    })();
+    FetchedMetadata {
+        bytes_downloaded: client.bytes_downloaded(),
+        result,
+    }
+}
+
+/// Store fetched metadata for a repository
+///
+/// The package index is replaced, and the sync and its bandwidth recorded,
+/// in one transaction, so a failure leaves the previous index in place.
+/// The outcome counts what the new index added, removed and updated.
+pub fn store_fetched(
+    conn: &mut Connection,
+    repo: &mut Repository,
+    fetched: FetchedMetadata,
+) -> Result<SyncOutcome> {
+    let bytes = fetched.bytes_downloaded as i64;
+    let fetched = match fetched.result {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            stats::record(conn, stats::Metric::SyncBytes, Some(&repo.name), bytes)?;
+            return Err(e);
+        }
+    };
+
+    crate::db::transaction(conn, |tx| {
+        stats::record(tx, stats::Metric::SyncBytes, Some(&repo.name), bytes)?;
+        let Some((index, parsed)) = fetched else {
+            return mark_unchanged(tx, repo);
+        };
+
+        let mut parsed = parsed;
+        let skipped = apply_sync_filter(&SyncFilter::for_repository(repo), &mut parsed);
+        let repository_id = repo.id.unwrap();
+        let before = index_versions(tx, repository_id)?;
+        let count = match parsed {
+            ParsedIndex::Native(packages) => store_native_packages(tx, repository_id, packages)?,
+            ParsedIndex::Json(metadata) => {
+                let (count, delta_count) = store_repository_metadata(tx, repository_id, metadata)?;
+                info!(
+                    "Stored {} deltas from repository {}",
+                    delta_count, repo.name
+                );
+                count
+            }
+        };
+        fail_point!("sync::store", |message| {
+            Error::Database(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                Some(message),
+            ))
+        });
+        let changes = IndexChanges::between(&before, &index_versions(tx, repository_id)?);
+        set_synced(repo, &index);
+        repo.update(tx)?;
+
+        info!(
+            "Synchronized {} packages from repository {}: {} added, {} removed, {} updated",
+            count, repo.name, changes.added, changes.removed, changes.updated
+        );
+        if skipped > 0 {
+            info!(
+                "{} packages of repository {} skipped by its sync filter",
+                skipped, repo.name
+            );
+        }
+        Ok(SyncOutcome::Updated {
+            packages: count,
+            skipped,
+            changes,
+        })
+    })
+}
+
+/// Drop the packages a sync filter rejects from a parsed index
+///
+/// Returns the number of packages dropped.
+fn apply_sync_filter(filter: &SyncFilter, parsed: &mut ParsedIndex) -> usize {
+    if filter.is_empty() {
+        return 0;
+    }
+    let rejected = |name: &str, arch: Option<&str>| match filter.rejects(name, arch) {
+        Some(reason) => {
+            debug!("Skipping {}: {}", name, reason);
+            true
+        }
+        None => false,
+    };
+    match parsed {
+        ParsedIndex::Native(packages) => {
+            let before = packages.len();
+            packages.retain(|pkg| !rejected(&pkg.name, pkg.architecture.as_deref()));
+            before - packages.len()
+        }
+        ParsedIndex::Json(metadata) => {
+            let before = metadata.packages.len();
+            metadata
+                .packages
+                .retain(|pkg| !rejected(&pkg.name, pkg.architecture.as_deref()));
+            before - metadata.packages.len()
+        }
+    }
+}
+
+/// Synchronize repository metadata with the database
+///
+/// Repositories whose metadata index is unchanged since the last sync are
+/// skipped, judged by HTTP validators and the index checksum; `force`
+/// always downloads and replaces the package index. `progress` is called as
+/// each metadata file downloads.
+pub fn sync_repository(
+    conn: &mut Connection,
+    repo: &mut Repository,
+    force: bool,
+    progress: Option<&ProgressFn>,
+) -> Result<SyncOutcome> {
+    info!("Synchronizing repository: {}", repo.name);
+
+    let fetched = fetch_repository(repo, force, progress);
+    store_fetched(conn, repo, fetched)
+}
+
+/// Synchronize several repositories, fetching in parallel
+///
+/// Downloads and parsing run on up to `max_parallel_downloads` threads;
+/// every database write goes through `conn` on the calling thread, one
+/// repository transaction at a time, so the fetches never contend for
+/// SQLite's write lock. `progress` gets the index of the repository along
+/// with its download progress. Returns each repository's name and outcome,
+/// in the order given.
+pub fn sync_repositories(
+    conn: &mut Connection,
+    repos: &[Repository],
+    force: bool,
+    limits: &ResourceLimits,
+    progress: Option<&(dyn Fn(usize, u64, Option<u64>) + Sync)>,
+) -> Vec<(String, Result<SyncOutcome>)> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let indexed: Vec<_> = repos.iter().enumerate().collect();
+            limits.par_map_downloads(&indexed, |&(index, repo)| {
+                let report = |received, total| {
+                    if let Some(progress) = progress {
+                        progress(index, received, total);
+                    }
+                };
+                let mut repo = repo.clone();
+                let fetched = fetch_repository(&mut repo, force, Some(&report));
+                // The receiver outlives every sender, so this cannot fail
+                let _ = sender.send((index, repo, fetched));
+            });
+        });
+
+        // The single writer, storing each repository as its fetch completes
+        let mut results: Vec<Option<(String, Result<SyncOutcome>)>> =
+            repos.iter().map(|_| None).collect();
+        for (index, mut repo, fetched) in receiver {
+            let outcome = store_fetched(conn, &mut repo, fetched);
+            results[index] = Some((repo.name.clone(), outcome));
+        }
+        results.into_iter().flatten().collect()
+    })
+}
+
+/// Check if repository metadata needs refresh
+pub fn needs_sync(repo: &Repository) -> bool {
+    match &repo.last_sync {
+        None => true, // Never synced
+        Some(last_sync) => {
+            // Parse timestamp and check if expired
+            match parse_timestamp(last_sync) {
+                Ok(last_sync_time) => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs();
+
+                    let age_seconds = now.saturating_sub(last_sync_time);
+                    age_seconds > repo.metadata_expire as u64
+                }
+                Err(_) => true, // If we can't parse timestamp, force sync
+            }
+        }
+    }
+}
+
+/// Download a package from a repository
+///
+/// `repo` is the repository carrying `repo_pkg`; its download helper is
+/// used if it has one, and its URL policy decides the final URL (see
+/// [`UrlPolicy`]). `progress` is called as the package downloads.
+pub fn download_package(
+    repo: &Repository,
+    repo_pkg: &RepositoryPackage,
+    dest_dir: &Path,
+    progress: Option<&ProgressFn>,
+) -> Result<PathBuf> {
+    let client = RepositoryClient::for_repository(repo)?;
+    let url = UrlPolicy::for_repository(repo).package_url(&repo_pkg.download_url)?;
+    let dest_path = package_path(repo_pkg, dest_dir);
+
+    // Download the file and verify its checksum
+    client.download_verified(
+        &url,
+        &dest_path,
+        &repo_pkg.checksum,
+        repo_pkg.checksum_type,
+        progress,
+    )?;
+
+    Ok(dest_path)
+}
+
+/// Where `download_package` puts a package in `dest_dir`
+fn package_path(repo_pkg: &RepositoryPackage, dest_dir: &Path) -> PathBuf {
+    let default_filename = format!("{}-{}.rpm", repo_pkg.name, repo_pkg.version);
+    let filename = repo_pkg
+        .download_url
+        .split('/')
+        .next_back()
+        .unwrap_or(&default_filename);
+    dest_dir.join(filename)
+}
+
+/// Download a delta update file
+///
+/// # Arguments
+/// * `repo` - Repository carrying the delta, whose download helper and URL policy apply
+/// * `delta_info` - Delta metadata from repository
+/// * `package_name` - Name of the package (for filename construction)
+/// * `to_version` - Target version (for filename construction)
+/// * `dest_dir` - Destination directory for the delta file
+/// * `progress` - Called as the delta downloads
+///
+/// # Returns
+/// Path to the downloaded and verified delta file
+pub fn download_delta(
+    repo: &Repository,
+    delta_info: &DeltaInfo,
+    package_name: &str,
     to_version: &str,
     dest_dir: &Path,
+    progress: Option<&ProgressFn>,
 ) -> Result<PathBuf> {
-    let client = RepositoryClient::new()?;
+    let client = RepositoryClient::for_repository(repo)?;
+    let url = UrlPolicy::for_repository(repo).package_url(&delta_info.delta_url)?;
 
     // Construct destination path
     let default_filename = format!(
@@ -510,11 +1434,14 @@ pub fn download_delta(
         package_name, delta_info.from_version, to_version
     );
 
-    // Download the delta file
-    client.download_file(&delta_info.delta_url, &dest_path)?;
-
-    // Verify checksum
-    verify_checksum(&dest_path, &delta_info.delta_checksum)?;
+    // Download the delta file and verify its checksum
+    client.download_verified(
+        &url,
+        &dest_path,
+        &delta_info.delta_checksum,
+        ChecksumType::Sha256,
+        progress,
+    )?;
 
     info!(
         "Delta downloaded successfully: {} bytes (compression ratio: {:.1}%)",
@@ -525,24 +1452,168 @@ pub fn download_delta(
     Ok(dest_path)
 }
 
-/// Verify file checksum matches expected value
-fn verify_checksum(path: &Path, expected: &str) -> Result<()> {
-    use sha2::{Digest, Sha256};
+/// A repository package fetched for installation
+#[derive(Debug, Clone)]
+pub struct FetchedPackage {
+    pub path: PathBuf,
+    /// Bytes transferred: the delta's size, or the whole package's
+    pub downloaded_bytes: i64,
+    /// The delta the package was rebuilt from, if one applied
+    pub delta: Option<PackageDelta>,
+    /// Deltas tried and given up on before the package was fetched
+    pub delta_failures: i32,
+}
 
-    debug!("Verifying checksum for {}", path.display());
+impl FetchedPackage {
+    /// Bytes the delta saved over downloading the whole package
+    pub fn bytes_saved(&self) -> i64 {
+        self.delta
+            .as_ref()
+            .map_or(0, |delta| (delta.full_size - delta.delta_size).max(0))
+    }
+}
 
-    let mut file = File::open(path).map_err(|e| {
-        Error::IoError(format!("Failed to open file for checksum: {}", e))
-    })?;
+/// Deltas that rebuild `repo_pkg` from a package `cas` still holds, smallest first
+///
+/// The base can be any version whose package is in the CAS, whether or not
+/// it is installed now: an older version removed since, or one kept for
+/// rollback, serves as well as the installed one.
+pub fn usable_deltas(
+    conn: &Connection,
+    cas: &CasStore,
+    repo_pkg: &RepositoryPackage,
+) -> Result<Vec<PackageDelta>> {
+    Ok(
+        PackageDelta::find_to_version(conn, &repo_pkg.name, &repo_pkg.version)?
+            .into_iter()
+            .filter(|delta| delta.to_hash == repo_pkg.checksum)
+            // A malformed base hash could name a directory of the CAS instead of an object
+            .filter(|delta| {
+                delta.from_hash.len() == 64
+                    && delta.from_hash.bytes().all(|b| b.is_ascii_hexdigit())
+            })
+            .filter(|delta| cas.exists(&delta.from_hash))
+            .collect(),
+    )
+}
 
-    let mut hasher = Sha256::new();
-    io::copy(&mut file, &mut hasher).map_err(|e| {
-        Error::IoError(format!("Failed to read file for checksum: {}", e))
-    })?;
+/// Fetch a repository package, rebuilding it from a delta when one applies
+///
+/// Each of `deltas` (see [`usable_deltas`]) is tried in turn: it is
+/// downloaded to `dest_dir` and applied to its base in `cas`, which then
+/// holds the rebuilt package, and the package is written to where
+/// [`download_package`] would have put it. A delta that fails to download
+/// or apply is logged and skipped; when none works the whole package is
+/// downloaded. `progress` follows whichever download is running.
+pub fn fetch_package(
+    repo: &Repository,
+    repo_pkg: &RepositoryPackage,
+    deltas: &[PackageDelta],
+    cas: &CasStore,
+    dest_dir: &Path,
+    progress: Option<&ProgressFn>,
+) -> Result<FetchedPackage> {
+    let mut delta_failures = 0;
+    for delta in deltas {
+        match apply_package_delta(repo, repo_pkg, delta, cas, dest_dir, progress) {
+            Ok(path) => {
+                info!(
+                    "Rebuilt {} {} from the delta of {}",
+                    repo_pkg.name, repo_pkg.version, delta.from_version
+                );
+                return Ok(FetchedPackage {
+                    path,
+                    downloaded_bytes: delta.delta_size,
+                    delta: Some(delta.clone()),
+                    delta_failures,
+                });
+            }
+            Err(e) => {
+                warn!(
+                    "Delta of {} from {} failed, trying the next route: {}",
+                    repo_pkg.name, delta.from_version, e
+                );
+                delta_failures += 1;
+            }
+        }
+    }
+
+    let path = download_package(repo, repo_pkg, dest_dir, progress)?;
+    let downloaded_bytes = fs::metadata(&path)?.len() as i64;
+    Ok(FetchedPackage {
+        path,
+        downloaded_bytes,
+        delta: None,
+        delta_failures,
+    })
+}
+
+/// Download one delta, apply it in `cas` and write the rebuilt package to `dest_dir`
+fn apply_package_delta(
+    repo: &Repository,
+    repo_pkg: &RepositoryPackage,
+    delta: &PackageDelta,
+    cas: &CasStore,
+    dest_dir: &Path,
+    progress: Option<&ProgressFn>,
+) -> Result<PathBuf> {
+    let info = DeltaInfo {
+        from_version: delta.from_version.clone(),
+        from_hash: delta.from_hash.clone(),
+        delta_url: delta.delta_url.clone(),
+        delta_size: delta.delta_size,
+        delta_checksum: delta.delta_checksum.clone(),
+        compression_ratio: delta.compression_ratio,
+    };
+    let delta_path = download_delta(
+        repo,
+        &info,
+        &repo_pkg.name,
+        &repo_pkg.version,
+        dest_dir,
+        progress,
+    )?;
+    let applied = DeltaApplier::new(cas.objects_dir())
+        .and_then(|applier| applier.apply_delta(&delta.from_hash, &delta_path, &delta.to_hash));
+    let _ = fs::remove_file(&delta_path);
+    let hash = applied?;
+
+    let dest_path = package_path(repo_pkg, dest_dir);
+    let mut file = File::create(&dest_path)?;
+    if let Err(e) = cas.retrieve_into(&hash, &mut file) {
+        drop(file);
+        let _ = fs::remove_file(&dest_path);
+        return Err(e);
+    }
+    Ok(dest_path)
+}
+
+/// Verify file checksum matches expected value
+///
+/// The digest is computed with `checksum_type`, the algorithm the
+/// repository published. MD5 is still checked, with a warning that it only
+/// guards against corruption.
+fn verify_checksum(path: &Path, expected: &str, checksum_type: ChecksumType) -> Result<()> {
+    debug!(
+        "Verifying {} checksum for {}",
+        checksum_type.as_str(),
+        path.display()
+    );
+    if checksum_type == ChecksumType::Md5 {
+        warn!(
+            "{} is only protected by an MD5 checksum, which does not guard against tampering",
+            path.display()
+        );
+    }
 
-    let actual = format!("{:x}", hasher.finalize());
+    let mut file = File::open(path)
+        .map_err(|e| Error::IoError(format!("Failed to open file for checksum: {}", e)))?;
 
-    if actual != expected {
+    let actual = checksum_type
+        .digest(&mut file)
+        .map_err(|e| Error::IoError(format!("Failed to read file for checksum: {}", e)))?;
+
+    if !actual.eq_ignore_ascii_case(expected) {
         return Err(Error::ChecksumMismatch {
             expected: expected.to_string(),
             actual,
@@ -620,168 +1691,626 @@ pub fn set_repository_enabled(conn: &Connection, name: &str, enabled: bool) -> R
     Ok(())
 }
 
-/// Search for packages across all enabled repositories
-pub fn search_packages(conn: &Connection, pattern: &str) -> Result<Vec<RepositoryPackage>> {
-    let packages = RepositoryPackage::search(conn, pattern)?;
-    Ok(packages)
+/// Set which operations may use a repository
+pub fn set_repository_scope(conn: &Connection, name: &str, scope: RepositoryScope) -> Result<()> {
+    let mut repo = Repository::find_by_name(conn, name)?
+        .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))?;
+
+    repo.scope = scope;
+    repo.update(conn)?;
+
+    info!("Repository '{}' scope set to {}", name, scope.as_str());
+    Ok(())
 }
 
-/// Resolve dependencies and return list of packages to download
+/// Set or remove (`None`) the download helper of a repository
 ///
-/// This function takes a list of dependency names and searches repositories
-/// for matching packages. It checks which dependencies are already installed
-/// and returns only the ones that need to be downloaded.
-///
-/// Returns: Vec<(dependency_name, PackageWithRepo)>
-pub fn resolve_dependencies(
+/// `metadata` sends metadata fetches through the helper too, and
+/// `fallback` lets a failed helper download be retried with the built-in
+/// client instead of failing.
+pub fn set_download_helper(
     conn: &Connection,
-    dependencies: &[String],
-) -> Result<Vec<(String, PackageWithRepo)>> {
-    use crate::db::models::Trove;
+    name: &str,
+    command: Option<&str>,
+    metadata: bool,
+    fallback: bool,
+) -> Result<Repository> {
+    let mut repo = Repository::find_by_name(conn, name)?
+        .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))?;
+    if command.is_some_and(|command| command.trim().is_empty()) {
+        return Err(Error::ParseError(
+            "Download helper command is empty".to_string(),
+        ));
+    }
 
-    let mut to_download = Vec::new();
+    repo.download_helper = command.map(str::to_string);
+    repo.helper_metadata = command.is_some() && metadata;
+    repo.helper_fallback = command.is_some() && fallback;
+    repo.update(conn)?;
 
-    for dep_name in dependencies {
-        // Skip rpmlib dependencies and file paths
-        if dep_name.starts_with("rpmlib(") || dep_name.starts_with('/') {
-            continue;
-        }
+    match command {
+        Some(command) => info!("Repository '{}' downloads through '{}'", name, command),
+        None => info!("Repository '{}' no longer has a download helper", name),
+    }
+    Ok(repo)
+}
 
-        // Check if already installed
-        let installed = Trove::find_by_name(conn, dep_name)?;
-        if !installed.is_empty() {
-            debug!("Dependency {} already installed, skipping", dep_name);
-            continue;
-        }
+/// Change how a repository treats the URLs it fetches
+///
+/// `None` leaves a setting as it is.
+pub fn set_url_policy(
+    conn: &Connection,
+    name: &str,
+    prefer_https: Option<bool>,
+    allow_cross_host: Option<bool>,
+) -> Result<Repository> {
+    let mut repo = Repository::find_by_name(conn, name)?
+        .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))?;
 
-        // Search repositories for this dependency
-        let options = SelectionOptions::default();
-        match PackageSelector::find_best_package(conn, dep_name, &options) {
-            Ok(pkg_with_repo) => {
-                info!(
-                    "Found dependency {} version {} in repository {}",
-                    dep_name, pkg_with_repo.package.version, pkg_with_repo.repository.name
-                );
-                to_download.push((dep_name.clone(), pkg_with_repo));
-            }
-            Err(e) => {
-                // Dependency not found - this is a critical error
-                return Err(Error::NotFoundError(format!(
-                    "Required dependency '{}' not found in any repository: {}",
-                    dep_name, e
-                )));
-            }
-        }
+    if let Some(prefer_https) = prefer_https {
+        repo.prefer_https = prefer_https;
+    }
+    if let Some(allow_cross_host) = allow_cross_host {
+        repo.allow_cross_host = allow_cross_host;
     }
+    repo.update(conn)?;
 
-    Ok(to_download)
+    info!(
+        "Repository '{}' URL policy: prefer_https={}, allow_cross_host={}",
+        name, repo.prefer_https, repo.allow_cross_host
+    );
+    Ok(repo)
 }
 
-/// Resolve dependencies transitively (recursively resolve all dependencies)
-///
-/// This function performs a breadth-first search through the dependency tree,
-/// resolving all transitive dependencies. It tracks visited packages to avoid
-/// cycles and respects a maximum depth to prevent infinite loops.
+/// Replace a repository's sync filter
 ///
-/// Returns: Vec<(dependency_name, PackageWithRepo)> in topological order (dependencies before dependents)
-pub fn resolve_dependencies_transitive(
+/// `None` leaves a list as it is. The stored metadata validators are
+/// cleared, so the next sync stores the index again under the new filter
+/// even when the metadata has not changed.
+pub fn set_sync_filter(
     conn: &Connection,
-    initial_dependencies: &[String],
-    max_depth: usize,
-) -> Result<Vec<(String, PackageWithRepo)>> {
-    use crate::db::models::Trove;
-    use std::collections::{HashMap, HashSet, VecDeque};
-
-    let mut to_download: HashMap<String, PackageWithRepo> = HashMap::new();
-    let mut visited: HashSet<String> = HashSet::new();
-    let mut queue: VecDeque<(String, usize)> = VecDeque::new();
+    name: &str,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+) -> Result<Repository> {
+    let mut repo = Repository::find_by_name(conn, name)?
+        .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))?;
 
-    // Seed queue with initial dependencies
-    for dep in initial_dependencies {
-        // Skip rpmlib dependencies and file paths
-        if dep.starts_with("rpmlib(") || dep.starts_with('/') {
-            continue;
-        }
-        queue.push_back((dep.clone(), 0));
+    if let Some(include) = include {
+        repo.sync_include = include;
+    }
+    if let Some(exclude) = exclude {
+        repo.sync_exclude = exclude;
     }
+    repo.metadata_etag = None;
+    repo.metadata_last_modified = None;
+    repo.metadata_checksum = None;
+    repo.update(conn)?;
 
-    while let Some((dep_name, depth)) = queue.pop_front() {
-        // Check depth limit
-        if depth > max_depth {
-            warn!(
-                "Maximum dependency depth {} reached for package {}",
-                max_depth, dep_name
-            );
-            continue;
-        }
+    info!(
+        "Repository '{}' sync filter: include [{}], exclude [{}]",
+        name,
+        repo.sync_include.join(", "),
+        repo.sync_exclude.join(", ")
+    );
+    Ok(repo)
+}
 
-        // Skip if already visited
-        if visited.contains(&dep_name) {
-            continue;
-        }
-        visited.insert(dep_name.clone());
+/// Set the Debian components and architectures a repository is synced for
+///
+/// Empty lists go back to every component of the Release file and the
+/// host architecture. The stored metadata validators are cleared like
+/// `set_sync_filter` does, so the next sync reads the new lists.
+pub fn set_debian_sources(
+    conn: &Connection,
+    name: &str,
+    components: Vec<String>,
+    architectures: Vec<String>,
+) -> Result<Repository> {
+    let mut repo = Repository::find_by_name(conn, name)?
+        .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))?;
 
-        // Check if already installed
-        let installed = Trove::find_by_name(conn, &dep_name)?;
-        if !installed.is_empty() {
-            debug!("Dependency {} already installed, skipping", dep_name);
-            continue;
+    repo.components = components;
+    repo.architectures = architectures;
+    repo.metadata_etag = None;
+    repo.metadata_last_modified = None;
+    repo.metadata_checksum = None;
+    repo.update(conn)?;
+
+    info!(
+        "Repository '{}' Debian sources: components [{}], architectures [{}]",
+        name,
+        repo.components.join(", "),
+        repo.architectures.join(", ")
+    );
+    Ok(repo)
+}
+
+/// Set how a repository's metadata is checked and how long it stays fresh
+///
+/// `gpg_check` and `gpg_key_url` are recorded with the repository; sync
+/// does not verify signatures yet. `metadata_expire` is the age in
+/// seconds after which `repo-sync` fetches the metadata again.
+pub fn set_metadata_policy(
+    conn: &Connection,
+    name: &str,
+    gpg_check: bool,
+    gpg_key_url: Option<String>,
+    metadata_expire: i32,
+) -> Result<Repository> {
+    let mut repo = Repository::find_by_name(conn, name)?
+        .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))?;
+
+    repo.gpg_check = gpg_check;
+    repo.gpg_key_url = gpg_key_url;
+    repo.metadata_expire = metadata_expire;
+    repo.update(conn)?;
+
+    info!(
+        "Repository '{}' metadata policy: gpg_check={}, metadata_expire={}s",
+        name, repo.gpg_check, repo.metadata_expire
+    );
+    Ok(repo)
+}
+
+/// One change applied by `edit_priorities`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriorityEdit {
+    /// Give a repository this priority
+    Set { name: String, priority: i32 },
+    /// Place a repository directly before or after another in the order
+    Move {
+        name: String,
+        before: bool,
+        other: String,
+    },
+}
+
+/// Where `repo` sorts relative to `other`: priority descending, then name
+/// ascending, the order `Repository::list_all` and package selection use
+fn repository_order(repo: &Repository, other: &Repository) -> std::cmp::Ordering {
+    other
+        .priority
+        .cmp(&repo.priority)
+        .then_with(|| repo.name.cmp(&other.name))
+}
+
+/// Apply priority edits in one transaction and return the new order
+///
+/// A move changes as few priorities as it can: the moved repository takes
+/// a priority just above its new successor, and repositories above it are
+/// raised only where the order would otherwise break. Disabled repositories
+/// take part, so enabling one later keeps its place.
+pub fn edit_priorities(conn: &mut Connection, edits: &[PriorityEdit]) -> Result<Vec<Repository>> {
+    crate::db::transaction(conn, |tx| {
+        for edit in edits {
+            let mut order = Repository::list_all(tx)?;
+            let position = |name: &str, order: &[Repository]| {
+                order
+                    .iter()
+                    .position(|repo| repo.name == name)
+                    .ok_or_else(|| Error::NotFoundError(format!("Repository '{}' not found", name)))
+            };
+
+            match edit {
+                PriorityEdit::Set { name, priority } => {
+                    let mut repo = order.remove(position(name, &order)?);
+                    repo.priority = *priority;
+                    repo.update(tx)?;
+                }
+                PriorityEdit::Move {
+                    name,
+                    before,
+                    other,
+                } => {
+                    if name == other {
+                        return Err(Error::ConflictError(format!(
+                            "Cannot move repository '{}' relative to itself",
+                            name
+                        )));
+                    }
+                    let moved = order.remove(position(name, &order)?);
+                    let anchor = position(other, &order)?;
+                    let index = if *before { anchor } else { anchor + 1 };
+                    order.insert(index, moved);
+
+                    // Already in place with its current priority
+                    let fits_above =
+                        index == 0 || repository_order(&order[index - 1], &order[index]).is_lt();
+                    let fits_below = index + 1 == order.len()
+                        || repository_order(&order[index], &order[index + 1]).is_lt();
+                    if fits_above && fits_below {
+                        continue;
+                    }
+
+                    let mut changed = vec![index];
+                    if let Some(next) = order.get(index + 1) {
+                        order[index].priority = next.priority + 1;
+                    } else {
+                        order[index].priority = order[index - 1].priority - 1;
+                    }
+                    for i in (0..index).rev() {
+                        if repository_order(&order[i], &order[i + 1]).is_lt() {
+                            break;
+                        }
+                        order[i].priority = order[i + 1].priority + 1;
+                        changed.push(i);
+                    }
+                    for i in changed {
+                        order[i].update(tx)?;
+                        debug!(
+                            "Repository '{}' priority set to {}",
+                            order[i].name, order[i].priority
+                        );
+                    }
+                }
+            }
+        }
+        Repository::list_all(tx)
+    })
+}
+
+/// How `search_packages` matches
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Only search the repository of this name
+    pub repository: Option<String>,
+    /// Read the pattern as a regular expression instead of a substring
+    pub regex: bool,
+}
+
+/// Search for packages across repositories, by name then repository priority
+///
+/// The pattern matches names and descriptions. As a regular expression it
+/// is matched here, over every package of the searched repositories.
+pub fn search_packages(
+    conn: &Connection,
+    pattern: &str,
+    options: &SearchOptions,
+) -> Result<Vec<SearchHit>> {
+    let repository = options.repository.as_deref();
+    if let Some(name) = repository
+        && Repository::find_by_name(conn, name)?.is_none()
+    {
+        return Err(Error::NotFoundError(format!(
+            "Repository '{}' not found",
+            name
+        )));
+    }
+    if !options.regex {
+        return RepositoryPackage::search_with_repo(conn, Some(pattern), repository);
+    }
+
+    let regex = regex::Regex::new(pattern).map_err(|e| {
+        Error::ParseError(format!("Invalid regular expression '{}': {}", pattern, e))
+    })?;
+    let mut hits = RepositoryPackage::search_with_repo(conn, None, repository)?;
+    hits.retain(|hit| {
+        regex.is_match(&hit.package.name)
+            || hit
+                .package
+                .description
+                .as_deref()
+                .is_some_and(|desc| regex.is_match(desc))
+    });
+    Ok(hits)
+}
+
+/// Resolve dependencies and return list of packages to download
+///
+/// This function takes a list of dependency names and searches repositories
+/// for matching packages. It checks which dependencies are already installed
+/// and returns only the ones that need to be downloaded.
+///
+/// Returns: Vec<(dependency_name, PackageWithRepo)>
+pub fn resolve_dependencies(
+    conn: &Connection,
+    dependencies: &[String],
+) -> Result<Vec<(String, PackageWithRepo)>> {
+    let mut to_download = Vec::new();
+
+    for dep in dependencies {
+        // Skip rpmlib dependencies
+        if dep.starts_with("rpmlib(") {
+            continue;
         }
+        let dep_name = dependency_name(dep);
 
-        // Check if already in to_download list
-        if to_download.contains_key(&dep_name) {
+        // Check if already installed, by name or by a provided capability
+        if is_satisfied(conn, dep_name, None)? {
+            debug!("Dependency {} already installed, skipping", dep_name);
             continue;
         }
 
         // Search repositories for this dependency
         let options = SelectionOptions::default();
-        let pkg_with_repo = PackageSelector::find_best_package(conn, &dep_name, &options)
+        match select_dependency(conn, dep, &options) {
+            Ok(pkg_with_repo) => {
+                info!(
+                    "Found dependency {} version {} in repository {}",
+                    dep_name, pkg_with_repo.package.version, pkg_with_repo.repository.name
+                );
+                to_download.push((dep_name.to_string(), pkg_with_repo));
+            }
+            Err(e) => return Err(with_constraint(e, dep)),
+        }
+    }
+
+    Ok(to_download)
+}
+
+/// Name part of a stored dependency string
+///
+/// Native syncs store dependencies as "name constraint" (e.g.
+/// "glibc >= 2.34"); only the name is matched against packages and provides.
+fn dependency_name(dep: &str) -> &str {
+    dep.split_whitespace().next().unwrap_or(dep)
+}
+
+/// Select the package satisfying a stored dependency string
+///
+/// Sonames are satisfied only by a package providing that version; other
+/// dependencies go through [`PackageSelector::find_best_package`].
+fn select_dependency(
+    conn: &Connection,
+    dep: &str,
+    options: &SelectionOptions,
+) -> Result<PackageWithRepo> {
+    match soname_requirement(dep) {
+        Some((name, version)) => PackageSelector::find_best_provider(conn, name, version, options),
+        None => PackageSelector::find_best_package(conn, dependency_name(dep), options),
+    }
+}
+
+/// The soname and version an Arch soname dependency needs
+///
+/// "libfoo.so =3-64" gives ("libfoo.so", "3-64"); other dependencies give
+/// `None`.
+fn soname_requirement(dep: &str) -> Option<(&str, &str)> {
+    let (name, constraint) = crate::version::split_constraint(dep);
+    let version = constraint?.strip_prefix('=')?.trim();
+    parsers::arch::is_soname(name).then_some((name, version))
+}
+
+/// Record the constraint of a stored dependency string in a resolution failure
+fn with_constraint(err: Error, dep: &str) -> Error {
+    match err {
+        Error::ResolutionError(mut failure) => {
+            failure.constraint = crate::version::split_constraint(dep).1.map(str::to_string);
+            Error::ResolutionError(failure)
+        }
+        err => err,
+    }
+}
+
+/// Whether an installed trove satisfies a dependency, by name or by provide
+///
+/// File dependencies ("/bin/sh") are satisfied by an installed trove that
+/// owns the file. With an `architecture`, only troves of that architecture
+/// or architecture-independent ones count, so an x86_64 glibc does not
+/// satisfy an i686 package.
+fn is_satisfied(conn: &Connection, dep_name: &str, architecture: Option<&str>) -> Result<bool> {
+    use crate::db::models::{DependencyEntry, FileEntry};
+
+    if dep_name.starts_with('/') && FileEntry::find_by_path(conn, dep_name)?.is_some() {
+        return Ok(true);
+    }
+    Ok(DependencyEntry::find_providers(conn, dep_name)?
+        .iter()
+        .any(|trove| match architecture {
+            Some(arch) => {
+                PackageSelector::is_architecture_compatible(trove.architecture.as_deref(), arch)
+            }
+            None => true,
+        }))
+}
+
+/// Resolve dependencies transitively (recursively resolve all dependencies)
+///
+/// This function performs a breadth-first search through the dependency tree,
+/// resolving all transitive dependencies. It tracks visited packages to avoid
+/// cycles and respects a maximum depth to prevent infinite loops.
+///
+/// Dependencies may name a capability rather than a package; the package
+/// providing it is selected, and a capability provided by an installed trove
+/// counts as installed. File dependencies select the package whose file
+/// list contains the path, unless an installed trove already owns it.
+///
+/// Explicit-only repositories are never searched.
+///
+/// Returns: Vec<(package_name, PackageWithRepo)> in topological order (dependencies before dependents)
+pub fn resolve_dependencies_transitive(
+    conn: &Connection,
+    initial_dependencies: &[String],
+    max_depth: usize,
+) -> Result<Vec<(String, PackageWithRepo)>> {
+    resolve_dependencies_transitive_in(conn, initial_dependencies, max_depth, None, None)
+}
+
+/// Resolve dependencies for an install that named `explicit_repo` with --repo
+///
+/// Full-scope repositories are preferred; a dependency none of them
+/// provides may still come from `explicit_repo`, even when that repository
+/// is explicit-only.
+///
+/// `architecture` is that of the package the dependencies belong to
+/// (`None` for the system architecture). Each dependency is resolved for
+/// the architecture of the package needing it, so on a multilib system the
+/// dependencies of an i686 package are i686 builds or
+/// architecture-independent ones, and a library needed by both an i686
+/// and an x86_64 package is selected once for each. The architecture is
+/// recorded in [`PackageWithRepo::resolved_for`].
+pub fn resolve_dependencies_transitive_in(
+    conn: &Connection,
+    initial_dependencies: &[String],
+    max_depth: usize,
+    explicit_repo: Option<&str>,
+    architecture: Option<&str>,
+) -> Result<Vec<(String, PackageWithRepo)>> {
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    // Packages and dependencies are told apart by name and architecture
+    type Key = (String, Option<String>);
+
+    // Selected packages, and the package each capability resolved to
+    let mut to_download: HashMap<Key, PackageWithRepo> = HashMap::new();
+    let mut resolved: HashMap<Key, Key> = HashMap::new();
+    let mut visited: HashSet<Key> = HashSet::new();
+    // A queued dependency, with the stored dependency string and the
+    // packages that led to it, for explaining a failure
+    struct Pending {
+        name: String,
+        arch: Option<String>,
+        depth: usize,
+        dep: String,
+        chain: Vec<String>,
+    }
+    let mut queue: VecDeque<Pending> = VecDeque::new();
+
+    // Seed queue with initial dependencies
+    let root_arch = PackageSelector::dependency_architecture(architecture, None);
+    for dep in initial_dependencies {
+        // Skip rpmlib dependencies
+        if dep.starts_with("rpmlib(") {
+            continue;
+        }
+        queue.push_back(Pending {
+            name: dependency_name(dep).to_string(),
+            arch: root_arch.clone(),
+            depth: 0,
+            dep: dep.clone(),
+            chain: Vec::new(),
+        });
+    }
+
+    while let Some(Pending {
+        name: dep_name,
+        arch,
+        depth,
+        dep,
+        chain,
+    }) = queue.pop_front()
+    {
+        // Check depth limit
+        if depth > max_depth {
+            warn!(
+                "Maximum dependency depth {} reached for package {}",
+                max_depth, dep_name
+            );
+            continue;
+        }
+
+        // Skip if already visited
+        let dep_key = (dep_name.clone(), arch.clone());
+        if !visited.insert(dep_key.clone()) {
+            continue;
+        }
+
+        // Check if already installed, by name or by a provided capability
+        if is_satisfied(conn, &dep_name, arch.as_deref())? {
+            debug!("Dependency {} already installed, skipping", dep_name);
+            continue;
+        }
+
+        // Search repositories for this dependency, preferring its architecture
+        let options = SelectionOptions {
+            architecture: arch.clone(),
+            ..SelectionOptions::default()
+        };
+        let fallback = explicit_repo.map(|repo| SelectionOptions {
+            repository: Some(repo.to_string()),
+            ..options.clone()
+        });
+        let mut pkg_with_repo = select_dependency(conn, &dep, &options)
+            .or_else(|e| match &fallback {
+                Some(fallback) => select_dependency(conn, &dep, fallback).map_err(|_| e),
+                None => Err(e),
+            })
             .map_err(|e| {
-                Error::NotFoundError(format!(
-                    "Required dependency '{}' not found in any repository: {}",
-                    dep_name, e
-                ))
+                chain
+                    .iter()
+                    .rev()
+                    .fold(with_constraint(e, &dep), |e, package| needed_by(e, package))
             })?;
 
+        let pkg_name = pkg_with_repo.package.name.clone();
+        let pkg_key = (pkg_name.clone(), pkg_with_repo.package.architecture.clone());
+        resolved.insert(dep_key, pkg_key.clone());
+
+        // Several capabilities can resolve to the same package
+        if to_download.contains_key(&pkg_key) {
+            continue;
+        }
+
         info!(
-            "Found dependency {} version {} in repository {} (depth: {})",
-            dep_name, pkg_with_repo.package.version, pkg_with_repo.repository.name, depth
+            "Found dependency {} as {} version {} ({}) in repository {} (depth: {})",
+            dep_name,
+            pkg_name,
+            pkg_with_repo.package.version,
+            pkg_with_repo
+                .package
+                .architecture
+                .as_deref()
+                .unwrap_or("noarch"),
+            pkg_with_repo.repository.name,
+            depth
         );
 
         // Parse this package's dependencies and add to queue
-        if let Ok(sub_deps) = pkg_with_repo.package.parse_dependencies() {
+        let package_arch = pkg_with_repo.package.architecture.as_deref();
+        let sub_arch = PackageSelector::dependency_architecture(package_arch, arch.as_deref());
+        if let Ok(sub_deps) = pkg_with_repo.dependencies(conn) {
             for sub_dep in sub_deps {
-                if !visited.contains(&sub_dep) {
-                    queue.push_back((sub_dep, depth + 1));
+                let sub_name = dependency_name(sub_dep).to_string();
+                if !visited.contains(&(sub_name.clone(), sub_arch.clone())) {
+                    let mut sub_chain = chain.clone();
+                    sub_chain.push(pkg_name.clone());
+                    queue.push_back(Pending {
+                        name: sub_name,
+                        arch: sub_arch.clone(),
+                        depth: depth + 1,
+                        dep: sub_dep.clone(),
+                        chain: sub_chain,
+                    });
                 }
             }
         }
 
-        to_download.insert(dep_name, pkg_with_repo);
+        pkg_with_repo.resolved_for = arch;
+        to_download.insert(pkg_key, pkg_with_repo);
     }
 
-    // Convert HashMap to Vec and perform topological sort for install order
-    let mut result: Vec<(String, PackageWithRepo)> =
-        to_download.into_iter().collect();
-
     // Build dependency graph for topological sorting
-    let mut dep_graph: HashMap<String, Vec<String>> = HashMap::new();
-    let mut in_degree: HashMap<String, usize> = HashMap::new();
+    let mut dep_graph: HashMap<Key, Vec<Key>> = HashMap::new();
+    let mut in_degree: HashMap<Key, usize> = HashMap::new();
 
     // Initialize in_degree for all packages
-    for (name, _) in &result {
-        in_degree.insert(name.clone(), 0);
-        dep_graph.insert(name.clone(), Vec::new());
+    for key in to_download.keys() {
+        in_degree.insert(key.clone(), 0);
+        dep_graph.insert(key.clone(), Vec::new());
     }
 
     // Build edges: package -> dependencies
-    for (name, pkg_with_repo) in &result {
-        if let Ok(deps) = pkg_with_repo.package.parse_dependencies() {
+    for (key, pkg_with_repo) in &to_download {
+        let sub_arch = PackageSelector::dependency_architecture(
+            pkg_with_repo.package.architecture.as_deref(),
+            pkg_with_repo.resolved_for.as_deref(),
+        );
+        // Cached from the traversal above, so not reloaded
+        if let Ok(deps) = pkg_with_repo.dependencies(conn) {
             for dep in deps {
+                let dep_key = (dependency_name(dep).to_string(), sub_arch.clone());
+                let target = resolved.get(&dep_key).unwrap_or(&dep_key);
+
                 // Only count edges to packages we're actually installing
-                if in_degree.contains_key(&dep) {
-                    dep_graph.entry(name.clone()).or_default().push(dep.clone());
-                    *in_degree.entry(dep).or_default() += 1;
+                if target != key
+                    && let Some(degree) = in_degree.get_mut(target)
+                {
+                    dep_graph
+                        .entry(key.clone())
+                        .or_default()
+                        .push(target.clone());
+                    *degree += 1;
                 }
             }
         }
@@ -789,10 +2318,10 @@ pub fn resolve_dependencies_transitive(
 
     // Topological sort using Kahn's algorithm
     let mut sorted = Vec::new();
-    let mut zero_in_degree: VecDeque<String> = in_degree
+    let mut zero_in_degree: VecDeque<Key> = in_degree
         .iter()
         .filter(|&(_, &degree)| degree == 0)
-        .map(|(name, _)| name.clone())
+        .map(|(key, _)| key.clone())
         .collect();
 
     while let Some(node) = zero_in_degree.pop_front() {
@@ -811,47 +2340,70 @@ pub fn resolve_dependencies_transitive(
     }
 
     // If sorted doesn't contain all nodes, there's a cycle
-    if sorted.len() != result.len() {
+    if sorted.len() != to_download.len() {
         warn!("Circular dependency detected in transitive resolution, using partial order");
         // Fall back to original order if there's a cycle
-    } else {
-        // Reorder result based on topological sort (dependencies before dependents)
-        let pkg_map: HashMap<String, PackageWithRepo> = result.into_iter().collect();
-        result = sorted
-            .into_iter()
-            .filter_map(|name| pkg_map.get(&name).map(|pkg| (name, pkg.clone())))
-            .collect();
+        sorted = to_download.keys().cloned().collect();
     }
 
+    // Reorder result based on topological sort (dependencies before dependents)
+    let result = sorted
+        .into_iter()
+        .filter_map(|key| to_download.remove(&key).map(|pkg| (key.0, pkg)))
+        .collect();
+
     Ok(result)
 }
 
 /// Download all dependencies to a directory in parallel
 ///
-/// Downloads are performed concurrently using rayon's parallel iterators.
-/// This significantly speeds up the download of multiple dependencies.
+/// Downloads are performed concurrently using rayon's parallel iterators,
+/// with at most `max_parallel_downloads` in flight when that limit is set.
+/// A dependency with a usable delta into it (see [`usable_deltas`]) is
+/// rebuilt from the CAS instead, as [`fetch_package`] does. `progress` is
+/// called with the index of the dependency in `dependencies` as each one
+/// downloads.
 ///
-/// Returns: Vec<(dependency_name, downloaded_path)>
+/// Returns: Vec<(dependency_name, fetched_package)>
 pub fn download_dependencies(
+    conn: &Connection,
     dependencies: &[(String, PackageWithRepo)],
+    cas: &CasStore,
     dest_dir: &Path,
-) -> Result<Vec<(String, PathBuf)>> {
-    // Use parallel iterator for concurrent downloads
-    let results: Result<Vec<_>> = dependencies
-        .par_iter()
-        .map(|(dep_name, pkg_with_repo)| {
+    limits: &ResourceLimits,
+    progress: Option<&(dyn Fn(usize, u64, Option<u64>) + Sync)>,
+) -> Result<Vec<(String, FetchedPackage)>> {
+    // The database is looked at here, before the downloads leave this thread
+    let deltas = dependencies
+        .iter()
+        .map(|(_, pkg_with_repo)| usable_deltas(conn, cas, &pkg_with_repo.package))
+        .collect::<Result<Vec<_>>>()?;
+    let indexed: Vec<(usize, &(String, PackageWithRepo))> =
+        dependencies.iter().enumerate().collect();
+    limits
+        .par_map_downloads(&indexed, |&(index, (dep_name, pkg_with_repo))| {
             info!("Downloading dependency: {}", dep_name);
-            let path = download_package(&pkg_with_repo.package, dest_dir)?;
-            Ok((dep_name.clone(), path))
+            let report =
+                progress.map(|progress| move |received, total| progress(index, received, total));
+            let report = report.as_ref().map(|f| f as &ProgressFn);
+            let fetched = fetch_package(
+                &pkg_with_repo.repository,
+                &pkg_with_repo.package,
+                &deltas[index],
+                cas,
+                dest_dir,
+                report,
+            )?;
+            Ok((dep_name.clone(), fetched))
         })
-        .collect();
-
-    results
+        .into_iter()
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::models::{Trove, TroveType};
     use crate::db::schema;
     use tempfile::NamedTempFile;
 
@@ -941,12 +2493,16 @@ mod tests {
 
         // Disable
         set_repository_enabled(&conn, "test-repo", false).unwrap();
-        let repo = Repository::find_by_name(&conn, "test-repo").unwrap().unwrap();
+        let repo = Repository::find_by_name(&conn, "test-repo")
+            .unwrap()
+            .unwrap();
         assert!(!repo.enabled);
 
         // Enable
         set_repository_enabled(&conn, "test-repo", true).unwrap();
-        let repo = Repository::find_by_name(&conn, "test-repo").unwrap().unwrap();
+        let repo = Repository::find_by_name(&conn, "test-repo")
+            .unwrap()
+            .unwrap();
         assert!(repo.enabled);
     }
 
@@ -973,4 +2529,2020 @@ mod tests {
         // Should be within a few seconds
         assert!((now as i64 - parsed as i64).abs() < 5);
     }
+
+    fn package_metadata(name: &str, dependencies: Option<Vec<String>>) -> PackageMetadata {
+        PackageMetadata {
+            name: name.to_string(),
+            version: "1.0".to_string(),
+            architecture: None,
+            description: None,
+            checksum: format!("sha256-{}", name),
+            size: 1,
+            download_url: format!("https://example.com/{}.rpm", name),
+            dependencies,
+            provides: None,
+            files: None,
+            checksum_type: None,
+            delta_from: None,
+        }
+    }
+
+    #[test]
+    fn test_metapackage_with_many_dependencies() {
+        let (_temp, mut conn) = create_test_db();
+        let repo = add_repository(
+            &conn,
+            "texlive".to_string(),
+            "https://example.com/texlive".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+
+        let dep_names: Vec<String> = (0..10_000).map(|i| format!("texlive-part-{}", i)).collect();
+        let mut packages = vec![package_metadata("texlive-full", Some(dep_names.clone()))];
+        packages.extend(dep_names.iter().map(|name| package_metadata(name, None)));
+        let metadata = RepositoryMetadata {
+            name: "texlive".to_string(),
+            version: "1".to_string(),
+            packages,
+        };
+
+        let tx = conn.transaction().unwrap();
+        let (count, _) = store_repository_metadata(&tx, repo.id.unwrap(), metadata).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(count, 10_001);
+
+        // The package row itself stays small
+        let dependencies_column: Option<String> = conn
+            .query_row(
+                "SELECT dependencies FROM repository_packages WHERE name = 'texlive-full'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(dependencies_column.is_none());
+
+        let start = std::time::Instant::now();
+        let found = RepositoryPackage::search(&conn, "texlive-full").unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "search took {:?}",
+            start.elapsed()
+        );
+
+        let resolved =
+            resolve_dependencies_transitive(&conn, &["texlive-full".to_string()], 5).unwrap();
+        assert_eq!(resolved.len(), 10_001);
+        let (_, meta) = resolved
+            .iter()
+            .find(|(name, _)| name == "texlive-full")
+            .unwrap();
+        assert_eq!(meta.dependencies(&conn).unwrap(), dep_names.as_slice());
+    }
+
+    #[test]
+    fn test_resolve_dependencies_through_provides() {
+        use crate::db::models::{ProvideEntry, Trove, TroveType};
+
+        let (_temp, conn) = create_test_db();
+        let repo = add_repository(
+            &conn,
+            "distro".to_string(),
+            "https://example.com/distro".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+
+        let mutt = package_metadata(
+            "mutt",
+            Some(vec![
+                "mail-transport-agent".to_string(),
+                "libssl.so.3()(64bit)".to_string(),
+                "openssl-libs >= 3.0".to_string(),
+            ]),
+        );
+        let mut postfix = package_metadata("postfix", None);
+        postfix.provides = Some(vec!["mail-transport-agent".to_string()]);
+        let mut openssl = package_metadata("openssl-libs", None);
+        openssl.provides = Some(vec![
+            "libssl.so.3()(64bit)".to_string(),
+            "openssl-libs = 3.2".to_string(),
+        ]);
+        let metadata = RepositoryMetadata {
+            name: "distro".to_string(),
+            version: "1".to_string(),
+            packages: vec![mutt, postfix, openssl],
+        };
+        store_repository_metadata(&conn, repo.id.unwrap(), metadata).unwrap();
+
+        // A virtual name selects the package providing it
+        let options = SelectionOptions::default();
+        let mta =
+            PackageSelector::find_best_package(&conn, "mail-transport-agent", &options).unwrap();
+        assert_eq!(mta.package.name, "postfix");
+
+        // Both openssl dependencies resolve to one package
+        let resolved = resolve_dependencies_transitive(&conn, &["mutt".to_string()], 5).unwrap();
+        let mut names: Vec<&str> = resolved.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["mutt", "openssl-libs", "postfix"]);
+
+        // An installed trove providing the capability satisfies it
+        let mut exim = Trove::new("exim".to_string(), "4.97".to_string(), TroveType::Package);
+        let exim_id = exim.insert(&conn).unwrap();
+        ProvideEntry::new(exim_id, "mail-transport-agent".to_string(), None)
+            .insert(&conn)
+            .unwrap();
+
+        let resolved = resolve_dependencies_transitive(&conn, &["mutt".to_string()], 5).unwrap();
+        let mut names: Vec<&str> = resolved.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["mutt", "openssl-libs"]);
+
+        let direct = resolve_dependencies(&conn, &["mail-transport-agent".to_string()]).unwrap();
+        assert!(direct.is_empty());
+    }
+
+    /// primary.xml with a script package depending on two interpreters by path
+    const FILE_DEPS_PRIMARY_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" xmlns:rpm="http://linux.duke.edu/metadata/rpm" packages="3">
+<package type="rpm">
+  <name>bash</name>
+  <arch>noarch</arch>
+  <version epoch="0" ver="5.2.26" rel="3.fc41"/>
+  <checksum type="sha256" pkgid="YES">aaa</checksum>
+  <size package="1024" installed="4096" archive="4200"/>
+  <location href="Packages/b/bash-5.2.26-3.fc41.noarch.rpm"/>
+  <format>
+    <file>/usr/bin/bash</file>
+    <file>/usr/bin/sh</file>
+    <file>/etc/skel/.bashrc</file>
+  </format>
+</package>
+<package type="rpm">
+  <name>python3</name>
+  <arch>noarch</arch>
+  <version epoch="0" ver="3.13.0" rel="1.fc41"/>
+  <checksum type="sha256" pkgid="YES">bbb</checksum>
+  <size package="2048" installed="8192" archive="8300"/>
+  <location href="Packages/p/python3-3.13.0-1.fc41.noarch.rpm"/>
+  <format>
+    <rpm:requires>
+      <rpm:entry name="/usr/bin/sh"/>
+    </rpm:requires>
+    <file>/usr/bin/python3</file>
+    <file>/usr/lib64/python3.13/os.py</file>
+  </format>
+</package>
+<package type="rpm">
+  <name>tool</name>
+  <arch>noarch</arch>
+  <version epoch="0" ver="1.0" rel="1.fc41"/>
+  <checksum type="sha256" pkgid="YES">ccc</checksum>
+  <size package="512" installed="1024" archive="1100"/>
+  <location href="Packages/t/tool-1.0-1.fc41.noarch.rpm"/>
+  <format>
+    <rpm:requires>
+      <rpm:entry name="/usr/bin/python3"/>
+      <rpm:entry name="rpmlib(PayloadIsZstd)"/>
+    </rpm:requires>
+  </format>
+</package>
+</metadata>"#;
+
+    #[test]
+    fn test_resolve_file_dependencies() {
+        use crate::db::models::{FileEntry, Trove, TroveType};
+
+        let (_temp, conn) = create_test_db();
+        let repo = add_repository(
+            &conn,
+            "fedora".to_string(),
+            "https://example.com/fedora".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+
+        let parser = parsers::fedora::FedoraParser::new("x86_64".to_string());
+        let packages = parser
+            .parse_primary_xml(FILE_DEPS_PRIMARY_XML, "https://example.com/fedora")
+            .unwrap();
+        assert_eq!(
+            store_native_packages(&conn, repo.id.unwrap(), packages).unwrap(),
+            3
+        );
+
+        // Only primary files are stored
+        assert!(
+            RepositoryPackage::find_by_file(&conn, "/usr/lib64/python3.13/os.py")
+                .unwrap()
+                .is_empty()
+        );
+
+        // File dependencies pull in the packages that ship the interpreters
+        let resolved = resolve_dependencies_transitive(&conn, &["tool".to_string()], 5).unwrap();
+        let names: Vec<&str> = resolved.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&"bash") && names.contains(&"python3") && names.contains(&"tool"));
+
+        let direct = resolve_dependencies(&conn, &["/usr/bin/python3".to_string()]).unwrap();
+        assert_eq!(direct.len(), 1);
+        assert_eq!(direct[0].1.package.name, "python3");
+
+        // A file owned by an installed trove is already satisfied
+        let mut installed = Trove::new(
+            "bash".to_string(),
+            "5.2.26-3.fc41".to_string(),
+            TroveType::Package,
+        );
+        let trove_id = installed.insert(&conn).unwrap();
+        FileEntry::new(
+            "/usr/bin/sh".to_string(),
+            "abc".to_string(),
+            10,
+            0o755,
+            trove_id,
+        )
+        .insert(&conn)
+        .unwrap();
+
+        let resolved = resolve_dependencies_transitive(&conn, &["tool".to_string()], 5).unwrap();
+        let mut names: Vec<&str> = resolved.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["python3", "tool"]);
+
+        // A file nobody ships is an error rather than silently skipped
+        assert!(resolve_dependencies(&conn, &["/usr/bin/perl".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_resolve_soname_dependencies_through_provides() {
+        use parsers::{Dependency, PackageMetadata, Provide};
+
+        let (_temp, conn) = create_test_db();
+        let repo = add_repository(
+            &conn,
+            "extra".to_string(),
+            "https://example.com/extra".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+
+        let package = |name: &str, version: &str| {
+            let url = format!(
+                "https://example.com/extra/{}-{}-x86_64.pkg.tar.zst",
+                name, version
+            );
+            PackageMetadata::new(
+                name.to_string(),
+                version.to_string(),
+                "0".repeat(64),
+                10,
+                url,
+            )
+        };
+        let mut app = package("app", "1.0-1");
+        app.dependencies = vec![Dependency::capability(
+            "libfoo.so".to_string(),
+            Some("=3-64".to_string()),
+        )];
+        let mut libfoo = package("libfoo", "3.1-1");
+        libfoo.provides = vec![Provide::new(
+            "libfoo.so".to_string(),
+            Some("3-64".to_string()),
+        )];
+        let mut compat = package("libfoo-compat", "2.4-1");
+        compat.provides = vec![Provide::new(
+            "libfoo.so".to_string(),
+            Some("2-64".to_string()),
+        )];
+        // A package merely named like the soname does not satisfy it
+        let named = package("libfoo.so", "9.0-1");
+        store_native_packages(&conn, repo.id.unwrap(), vec![app, libfoo, compat, named]).unwrap();
+
+        let resolved = resolve_dependencies_transitive(&conn, &["app".to_string()], 5).unwrap();
+        let mut names: Vec<&str> = resolved.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["app", "libfoo"]);
+
+        let failure = resolve_dependencies(&conn, &["libfoo.so =4-64".to_string()]).unwrap_err();
+        match failure {
+            Error::ResolutionError(failure) => {
+                assert_eq!(failure.constraint.as_deref(), Some("=4-64"))
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn test_search_packages_across_repositories() {
+        let (_temp, conn) = create_test_db();
+        let core = add_repository(
+            &conn,
+            "arch-core".to_string(),
+            "https://a.example.com".to_string(),
+            true,
+            50,
+        )
+        .unwrap();
+        let fedora = add_repository(
+            &conn,
+            "fedora".to_string(),
+            "https://f.example.com".to_string(),
+            true,
+            90,
+        )
+        .unwrap();
+        let add = |repo: &Repository, name: &str, version: &str, description: &str| {
+            let mut pkg = RepositoryPackage::new(
+                repo.id.unwrap(),
+                name.to_string(),
+                version.to_string(),
+                format!("{}-{}", repo.name, name),
+                1,
+                format!("https://example.com/{}/{}", repo.name, name),
+            );
+            pkg.description = Some(description.to_string());
+            pkg.insert(&conn).unwrap();
+        };
+        add(
+            &core,
+            "openssl",
+            "3.3.2-1",
+            "The Open Source toolkit for SSL/TLS",
+        );
+        add(
+            &fedora,
+            "openssl",
+            "3.2.2-5.fc41",
+            "Utilities from the general purpose cryptography library with TLS",
+        );
+        add(
+            &core,
+            "libssh2",
+            "1.11.0-1",
+            "A library implementing the SSH2 protocol",
+        );
+        add(
+            &fedora,
+            "ssldump",
+            "1.8-1.fc41",
+            "An SSLv3/TLS network protocol analyzer",
+        );
+        let mut trove = Trove::new(
+            "openssl".to_string(),
+            "3.3.2-1".to_string(),
+            TroveType::Package,
+        );
+        trove.insert(&conn).unwrap();
+
+        let hits = search_packages(&conn, "ssl", &SearchOptions::default()).unwrap();
+        let found: Vec<(&str, &str)> = hits
+            .iter()
+            .map(|hit| (hit.package.name.as_str(), hit.repository.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("openssl", "fedora"),
+                ("openssl", "arch-core"),
+                ("ssldump", "fedora")
+            ]
+        );
+        assert_eq!(hits[0].repository_priority, 90);
+        assert_eq!(hits[0].installed_version.as_deref(), Some("3.3.2-1"));
+        assert_eq!(hits[2].installed_version, None);
+
+        let options = SearchOptions {
+            repository: Some("arch-core".to_string()),
+            ..Default::default()
+        };
+        let hits = search_packages(&conn, "ssl", &options).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].package.version, "3.3.2-1");
+
+        // Regexes match names and descriptions
+        let options = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let names = |pattern: &str| -> Vec<String> {
+            search_packages(&conn, pattern, &options)
+                .unwrap()
+                .into_iter()
+                .map(|hit| hit.package.name)
+                .collect()
+        };
+        assert_eq!(names("^lib|dump$"), ["libssh2", "ssldump"]);
+        assert_eq!(names("SSH2 protocol"), ["libssh2"]);
+        assert!(
+            search_packages(&conn, "ssl(", &options)
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid regular expression")
+        );
+
+        let options = SearchOptions {
+            repository: Some("ubuntu".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            search_packages(&conn, "ssl", &options),
+            Err(Error::NotFoundError(_))
+        ));
+    }
+
+    #[test]
+    fn test_explicit_only_repository_scope() {
+        let (_temp, conn) = create_test_db();
+        let base = add_repository(
+            &conn,
+            "base".to_string(),
+            "https://example.com/base".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+        let testing = add_repository(
+            &conn,
+            "testing".to_string(),
+            "https://example.com/testing".to_string(),
+            true,
+            10,
+        )
+        .unwrap();
+        set_repository_scope(&conn, "testing", RepositoryScope::ExplicitOnly).unwrap();
+
+        let packages = vec![package_metadata("libfoo", None)];
+        store_repository_metadata(
+            &conn,
+            base.id.unwrap(),
+            RepositoryMetadata {
+                name: "base".to_string(),
+                version: "1".to_string(),
+                packages,
+            },
+        )
+        .unwrap();
+        let mut newer = package_metadata("libfoo", None);
+        newer.version = "2.0".to_string();
+        let packages = vec![
+            package_metadata(
+                "app",
+                Some(vec!["libfoo".to_string(), "libbar".to_string()]),
+            ),
+            newer,
+            package_metadata("libbar", None),
+        ];
+        store_repository_metadata(
+            &conn,
+            testing.id.unwrap(),
+            RepositoryMetadata {
+                name: "testing".to_string(),
+                version: "1".to_string(),
+                packages,
+            },
+        )
+        .unwrap();
+
+        // Without --repo the package is hidden, with a hint where it lives
+        let err = PackageSelector::find_best_package(&conn, "app", &SelectionOptions::default())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("explicit-only repository testing"),
+            "{}",
+            err
+        );
+        let named = SelectionOptions {
+            repository: Some("testing".to_string()),
+            ..SelectionOptions::default()
+        };
+        assert_eq!(
+            PackageSelector::find_best_package(&conn, "app", &named)
+                .unwrap()
+                .repository
+                .name,
+            "testing"
+        );
+
+        // Plain dependency resolution never looks at the testing repository
+        let resolved = resolve_dependencies_transitive(&conn, &["libfoo".to_string()], 5).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].1.repository.name, "base");
+        assert!(resolve_dependencies_transitive(&conn, &["libbar".to_string()], 5).is_err());
+
+        // Dependencies of an install from testing prefer full-scope repositories
+        let deps = ["libfoo".to_string(), "libbar".to_string()];
+        let resolved =
+            resolve_dependencies_transitive_in(&conn, &deps, 5, Some("testing"), None).unwrap();
+        let mut origins: Vec<(&str, &str)> = resolved
+            .iter()
+            .map(|(name, pkg)| (name.as_str(), pkg.repository.name.as_str()))
+            .collect();
+        origins.sort();
+        assert_eq!(origins, vec![("libbar", "testing"), ("libfoo", "base")]);
+    }
+
+    #[test]
+    fn test_multilib_dependencies_follow_architecture() {
+        use crate::db::models::{Trove, TroveType};
+
+        let (_temp, conn) = create_test_db();
+        let repo = add_repository(
+            &conn,
+            "fedora".to_string(),
+            "https://example.com/fedora".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+        let build = |name: &str, arch: &str, deps: &[&str]| {
+            let mut package =
+                package_metadata(name, Some(deps.iter().map(|dep| dep.to_string()).collect()));
+            package.architecture = Some(arch.to_string());
+            package.checksum = format!("sha256-{}-{}", name, arch);
+            package.download_url = format!("https://example.com/{}.{}.rpm", name, arch);
+            package
+        };
+        let packages = vec![
+            build("glibc", "x86_64", &[]),
+            build("glibc", "i686", &[]),
+            build("libfoo", "x86_64", &["glibc"]),
+            build("libfoo", "i686", &["glibc", "foo-data"]),
+            build("foo-data", "noarch", &["glibc"]),
+        ];
+        let metadata = RepositoryMetadata {
+            name: "fedora".to_string(),
+            version: "1".to_string(),
+            packages,
+        };
+        store_repository_metadata(&conn, repo.id.unwrap(), metadata).unwrap();
+
+        let arches = |resolved: &[(String, PackageWithRepo)]| {
+            let mut arches: Vec<(String, String, Option<String>)> = resolved
+                .iter()
+                .map(|(name, pkg)| {
+                    (
+                        name.clone(),
+                        pkg.package.architecture.clone().unwrap(),
+                        pkg.resolved_for.clone(),
+                    )
+                })
+                .collect();
+            arches.sort();
+            arches
+        };
+        let i686 = Some("i686".to_string());
+
+        // The whole chain of an i686 package is i686, through the noarch package too
+        let resolved = resolve_dependencies_transitive_in(
+            &conn,
+            &["libfoo".to_string()],
+            5,
+            None,
+            Some("i686"),
+        )
+        .unwrap();
+        assert_eq!(
+            arches(&resolved),
+            vec![
+                ("foo-data".to_string(), "noarch".to_string(), i686.clone()),
+                ("glibc".to_string(), "i686".to_string(), i686.clone()),
+                ("libfoo".to_string(), "i686".to_string(), i686.clone()),
+            ]
+        );
+
+        // An installed x86_64 glibc does not satisfy the i686 chain
+        let mut installed = Trove::new("glibc".to_string(), "1.0".to_string(), TroveType::Package);
+        installed.architecture = Some("x86_64".to_string());
+        installed.insert(&conn).unwrap();
+        let resolved = resolve_dependencies_transitive_in(
+            &conn,
+            &["libfoo".to_string()],
+            5,
+            None,
+            Some("i686"),
+        )
+        .unwrap();
+        assert!(
+            resolved
+                .iter()
+                .any(|(name, pkg)| name == "glibc" && pkg.resolved_for == i686)
+        );
+        let resolved = resolve_dependencies_transitive_in(
+            &conn,
+            &["libfoo".to_string()],
+            5,
+            None,
+            Some("x86_64"),
+        )
+        .unwrap();
+        assert_eq!(
+            arches(&resolved),
+            vec![(
+                "libfoo".to_string(),
+                "x86_64".to_string(),
+                Some("x86_64".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn test_selection_prefers_host_architecture() {
+        let (_temp, conn) = create_test_db();
+        let host = PackageSelector::detect_architecture();
+        let other = if host == "i686" { "x86_64" } else { "i686" };
+        let build = |name: &str, version: &str, arch: &str| {
+            let mut package = package_metadata(name, None);
+            package.version = version.to_string();
+            package.architecture = Some(arch.to_string());
+            package.checksum = format!("sha256-{}-{}-{}", name, version, arch);
+            package.download_url = format!("https://example.com/{}-{}.{}.rpm", name, version, arch);
+            package
+        };
+        let store = |name: &str, priority: i32, packages: Vec<PackageMetadata>| {
+            let repo = add_repository(
+                &conn,
+                name.to_string(),
+                format!("https://example.com/{}", name),
+                true,
+                priority,
+            )
+            .unwrap();
+            let metadata = RepositoryMetadata {
+                name: name.to_string(),
+                version: "1".to_string(),
+                packages,
+            };
+            store_repository_metadata(&conn, repo.id.unwrap(), metadata).unwrap();
+        };
+        store(
+            "everything",
+            50,
+            vec![
+                build("hello", "1.0", "x86_64"),
+                build("hello", "1.0", "i686"),
+                build("hello", "1.0", "noarch"),
+                build("docs", "1.0", host.as_str()),
+                build("docs", "1.1", "noarch"),
+                build("lib32", "1.0", other),
+            ],
+        );
+        store("updates", 60, vec![build("tool", "1.0", host.as_str())]);
+        store("testing", 40, vec![build("tool", "2.0", host.as_str())]);
+
+        let select = |name: &str, arch: Option<&str>| {
+            let options = SelectionOptions {
+                architecture: arch.map(str::to_string),
+                ..Default::default()
+            };
+            PackageSelector::find_best_package(&conn, name, &options).map(|pkg| {
+                (
+                    pkg.package.version.clone(),
+                    pkg.package.architecture.clone().unwrap(),
+                )
+            })
+        };
+        let found = |version: &str, arch: &str| (version.to_string(), arch.to_string());
+
+        // The host build beats the other architecture and the noarch build of the same version
+        if host == "x86_64" {
+            assert_eq!(select("hello", None).unwrap(), found("1.0", "x86_64"));
+        }
+        assert_eq!(select("hello", Some("i686")).unwrap(), found("1.0", "i686"));
+        assert_eq!(
+            select("hello", Some("aarch64")).unwrap(),
+            found("1.0", "noarch")
+        );
+
+        // A newer noarch build beats an older one for the host
+        assert_eq!(select("docs", None).unwrap(), found("1.1", "noarch"));
+
+        // Other architectures only when asked for
+        assert!(select("lib32", None).is_err());
+        assert_eq!(select("lib32", Some(other)).unwrap(), found("1.0", other));
+
+        // Repository priority comes before version
+        assert_eq!(select("tool", None).unwrap(), found("1.0", host.as_str()));
+    }
+
+    #[test]
+    fn test_resolution_failure_explains_chain_and_repositories() {
+        let (_temp, conn) = create_test_db();
+        let build = |name: &str, arch: &str, deps: &[&str]| {
+            let mut package =
+                package_metadata(name, Some(deps.iter().map(|dep| dep.to_string()).collect()));
+            package.architecture = Some(arch.to_string());
+            package.checksum = format!("sha256-{}-{}", name, arch);
+            package.download_url = format!("https://example.com/{}.{}.rpm", name, arch);
+            package
+        };
+        let store = |name: &str, packages: Vec<PackageMetadata>| {
+            let repo = add_repository(
+                &conn,
+                name.to_string(),
+                format!("https://example.com/{}", name),
+                true,
+                50,
+            )
+            .unwrap();
+            let metadata = RepositoryMetadata {
+                name: name.to_string(),
+                version: "1".to_string(),
+                packages,
+            };
+            store_repository_metadata(&conn, repo.id.unwrap(), metadata).unwrap();
+        };
+        store(
+            "fedora",
+            vec![
+                build("app", "x86_64", &["libfoo"]),
+                build("libfoo", "x86_64", &["libbar >= 2.0"]),
+                build("libbar", "i686", &[]),
+            ],
+        );
+        store("testing", vec![build("libbar", "x86_64", &[])]);
+        set_repository_scope(&conn, "testing", RepositoryScope::ExplicitOnly).unwrap();
+        store("debug", Vec::new());
+        set_sync_filter(&conn, "debug", None, Some(vec!["libbar*".to_string()])).unwrap();
+        store("empty", Vec::new());
+
+        // app -> libfoo -> libbar, with nothing usable for libbar
+        let err = resolve_dependencies_transitive_in(
+            &conn,
+            &["libfoo".to_string()],
+            5,
+            None,
+            Some("x86_64"),
+        )
+        .map_err(|e| needed_by(e, "app"))
+        .unwrap_err();
+        let Error::ResolutionError(failure) = &err else {
+            panic!("unexpected error: {}", err)
+        };
+        assert_eq!(failure.dependency, "libbar");
+        assert_eq!(failure.constraint.as_deref(), Some(">= 2.0"));
+        assert_eq!(failure.architecture, "x86_64");
+        assert_eq!(failure.chain, vec!["app".to_string(), "libfoo".to_string()]);
+        let verdicts: Vec<(&str, &Rejection)> = failure
+            .repositories
+            .iter()
+            .map(|verdict| (verdict.repository.as_str(), &verdict.rejection))
+            .collect();
+        assert_eq!(
+            verdicts,
+            vec![
+                (
+                    "debug",
+                    &Rejection::Filtered {
+                        detail: "'libbar' matches exclude pattern 'libbar*' in the sync filter of repository debug"
+                            .to_string()
+                    }
+                ),
+                ("empty", &Rejection::NotPresent),
+                ("fedora", &Rejection::WrongArchitecture { available: vec!["i686".to_string()] }),
+                ("testing", &Rejection::ExplicitOnly),
+            ]
+        );
+
+        let rendered = err.to_string();
+        assert!(
+            rendered.starts_with("Required dependency 'libbar' (>= 2.0) not found"),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.contains("needed by: app -> libfoo -> libbar"),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.contains("fedora: only built for i686"),
+            "{}",
+            rendered
+        );
+
+        let json = serde_json::to_value(failure).unwrap();
+        assert_eq!(json["chain"], serde_json::json!(["app", "libfoo"]));
+        assert_eq!(json["repositories"][0]["reason"], "filtered");
+        assert_eq!(json["repositories"][2]["reason"], "wrong_architecture");
+
+        // A requested version the repositories do not have
+        let options = SelectionOptions {
+            version: Some("9.0".to_string()),
+            architecture: Some("x86_64".to_string()),
+            ..Default::default()
+        };
+        let Err(Error::ResolutionError(failure)) =
+            PackageSelector::find_best_package(&conn, "libfoo", &options)
+        else {
+            panic!("libfoo 9.0 should not resolve");
+        };
+        assert!(failure.chain.is_empty());
+        let fedora = failure
+            .repositories
+            .iter()
+            .find(|verdict| verdict.repository == "fedora")
+            .unwrap();
+        assert_eq!(
+            fedora.rejection,
+            Rejection::ConstraintUnsatisfied {
+                constraint: "= 9.0".to_string(),
+                candidates: vec!["1.0".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn test_repository_pins_override_priorities() {
+        use crate::db::models::{PinAction, RepositoryPin};
+
+        let (_temp, conn) = create_test_db();
+        let store = |name: &str, priority: i32, packages: Vec<PackageMetadata>| {
+            let repo = add_repository(
+                &conn,
+                name.to_string(),
+                format!("https://example.com/{}", name),
+                true,
+                priority,
+            )
+            .unwrap();
+            let metadata = RepositoryMetadata {
+                name: name.to_string(),
+                version: "1".to_string(),
+                packages,
+            };
+            store_repository_metadata(&conn, repo.id.unwrap(), metadata).unwrap();
+        };
+        let mut newer_bash = package_metadata("bash", None);
+        newer_bash.version = "5.3".to_string();
+        store("fedora-43", 90, vec![package_metadata("bash", None)]);
+        store("arch-core", 95, vec![newer_bash]);
+        let multilib = vec![
+            package_metadata("lib32-glibc", None),
+            package_metadata("lib32-gcc-libs", None),
+        ];
+        store("arch-multilib", 85, multilib);
+        let pin = |pattern: &str, repository: &str, action: PinAction, priority: i32| {
+            RepositoryPin::new(
+                pattern.to_string(),
+                repository.to_string(),
+                action,
+                priority,
+            )
+            .save(&conn)
+            .unwrap();
+        };
+        let best = |name: &str, options: &SelectionOptions| {
+            PackageSelector::find_best_package(&conn, name, options)
+                .map(|selected| selected.repository.name)
+        };
+        let defaults = SelectionOptions::default();
+
+        // A prefer pin beats the higher-priority repository, and only for what it matches
+        assert_eq!(best("bash", &defaults).unwrap(), "arch-core");
+        pin("ba?h", "fedora-43", PinAction::Prefer, 0);
+        assert_eq!(best("bash", &defaults).unwrap(), "fedora-43");
+        // A pin on a repository without the package changes nothing
+        pin("lib32-*", "fedora-43", PinAction::Prefer, 0);
+        assert_eq!(best("lib32-gcc-libs", &defaults).unwrap(), "arch-multilib");
+
+        // A forbid pin hides the repository from selection and dependency resolution
+        pin("*", "arch-multilib", PinAction::Forbid, 0);
+        let err = best("lib32-gcc-libs", &defaults).unwrap_err();
+        let Error::ResolutionError(failure) = &err else {
+            panic!("unexpected error: {}", err)
+        };
+        let multilib = failure
+            .repositories
+            .iter()
+            .find(|verdict| verdict.repository == "arch-multilib")
+            .unwrap();
+        assert_eq!(
+            multilib.rejection,
+            Rejection::Pinned {
+                pattern: "*".to_string()
+            }
+        );
+        assert!(
+            err.to_string()
+                .contains("arch-multilib: filtered out by pin '*'"),
+            "{}",
+            err
+        );
+        let err =
+            resolve_dependencies_transitive(&conn, &["lib32-glibc".to_string()], 5).unwrap_err();
+        assert!(err.to_string().contains("filtered out by pin"), "{}", err);
+
+        // Naming the repository lifts its forbid pins, and so does a higher-priority pin
+        let named = SelectionOptions {
+            repository: Some("arch-multilib".to_string()),
+            ..SelectionOptions::default()
+        };
+        assert_eq!(best("lib32-gcc-libs", &named).unwrap(), "arch-multilib");
+        pin("lib32-glibc", "arch-multilib", PinAction::Prefer, 10);
+        assert_eq!(best("lib32-glibc", &defaults).unwrap(), "arch-multilib");
+        assert!(best("lib32-gcc-libs", &defaults).is_err());
+    }
+
+    #[test]
+    fn test_equal_priority_selection_is_stable() {
+        let (_temp, conn) = create_test_db();
+        // Inserted out of name order, so insertion order cannot be what decides
+        for name in ["zeta", "alpha", "mid"] {
+            let repo = add_repository(
+                &conn,
+                name.to_string(),
+                format!("https://example.com/{}", name),
+                true,
+                50,
+            )
+            .unwrap();
+            let metadata = RepositoryMetadata {
+                name: name.to_string(),
+                version: "1".to_string(),
+                packages: vec![package_metadata("libfoo", None)],
+            };
+            store_repository_metadata(&conn, repo.id.unwrap(), metadata).unwrap();
+        }
+
+        let names: Vec<String> = Repository::list_enabled(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|repo| repo.name)
+            .collect();
+        assert_eq!(names, vec!["alpha", "mid", "zeta"]);
+        for _ in 0..5 {
+            let selected =
+                PackageSelector::find_best_package(&conn, "libfoo", &SelectionOptions::default())
+                    .unwrap();
+            assert_eq!(selected.repository.name, "alpha");
+        }
+    }
+
+    #[test]
+    fn test_edit_priorities_moves_with_minimal_changes() {
+        let (_temp, mut conn) = create_test_db();
+        for (name, priority) in [("fedora", 90), ("arch", 85), ("ubuntu", 80), ("local", 0)] {
+            add_repository(
+                &conn,
+                name.to_string(),
+                format!("https://example.com/{}", name),
+                true,
+                priority,
+            )
+            .unwrap();
+        }
+        let order = |repos: &[Repository]| -> Vec<(String, i32)> {
+            repos
+                .iter()
+                .map(|repo| (repo.name.clone(), repo.priority))
+                .collect()
+        };
+        let move_edit = |name: &str, before: bool, other: &str| PriorityEdit::Move {
+            name: name.to_string(),
+            before,
+            other: other.to_string(),
+        };
+
+        // Moving into a gap only touches the moved repository
+        let repos = edit_priorities(&mut conn, &[move_edit("local", true, "ubuntu")]).unwrap();
+        assert_eq!(
+            order(&repos),
+            vec![
+                ("fedora".into(), 90),
+                ("arch".into(), 85),
+                ("local".into(), 81),
+                ("ubuntu".into(), 80)
+            ]
+        );
+
+        // Without a gap the repositories above are raised as needed
+        let repos = edit_priorities(
+            &mut conn,
+            &[
+                PriorityEdit::Set {
+                    name: "local".to_string(),
+                    priority: 91,
+                },
+                move_edit("arch", true, "fedora"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            order(&repos),
+            vec![
+                ("local".into(), 92),
+                ("arch".into(), 91),
+                ("fedora".into(), 90),
+                ("ubuntu".into(), 80)
+            ]
+        );
+
+        // Equal priorities are fine where the name order agrees, and a move
+        // already satisfied changes nothing
+        let repos = edit_priorities(&mut conn, &[move_edit("ubuntu", false, "arch")]).unwrap();
+        assert_eq!(
+            order(&repos),
+            vec![
+                ("local".into(), 92),
+                ("arch".into(), 91),
+                ("ubuntu".into(), 91),
+                ("fedora".into(), 90)
+            ]
+        );
+        let repos = edit_priorities(&mut conn, &[move_edit("fedora", false, "ubuntu")]).unwrap();
+        assert_eq!(repos[3].priority, 90);
+
+        // Edits are all or nothing
+        let err = edit_priorities(
+            &mut conn,
+            &[
+                PriorityEdit::Set {
+                    name: "local".to_string(),
+                    priority: 100,
+                },
+                move_edit("local", true, "missing"),
+            ],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("missing"), "{}", err);
+        assert_eq!(
+            Repository::find_by_name(&conn, "local")
+                .unwrap()
+                .unwrap()
+                .priority,
+            92
+        );
+    }
+
+    /// Serve metadata.json on localhost, answering If-None-Match with 304
+    ///
+    /// `etag` is None for a server that sends no validators. Returns the base
+    /// URL and the request headers seen, one string per request.
+    fn serve_metadata(
+        body: &'static str,
+        etag: Option<&'static str>,
+        requests: usize,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut headers = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    headers.push_str(&line.to_lowercase());
+                }
+
+                let response = match etag {
+                    Some(etag) if headers.contains(&format!("if-none-match: {}", etag)) => {
+                        "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+                    }
+                    Some(etag) => format!(
+                        "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        etag,
+                        body.len(),
+                        body
+                    ),
+                    None => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+                seen.push(headers);
+            }
+            seen
+        });
+        (url, handle)
+    }
+
+    const METADATA_JSON: &str = r#"{"name": "local", "version": "1", "packages": [
+        {"name": "hello", "version": "1.0", "architecture": null, "description": null, "checksum": "abc",
+         "size": 10, "download_url": "http://127.0.0.1/hello.pkg", "dependencies": null, "provides": null,
+         "files": null, "delta_from": null}
+    ]}"#;
+
+    #[test]
+    fn test_sync_skips_unchanged_metadata() {
+        let (_temp, mut conn) = create_test_db();
+
+        // The server honours If-None-Match
+        let (url, server) = serve_metadata(METADATA_JSON, Some("\"v1\""), 3);
+        let mut repo = add_repository(&conn, "local".to_string(), url, true, 0).unwrap();
+
+        let added = IndexChanges {
+            added: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 1,
+                skipped: 0,
+                changes: added
+            }
+        );
+        let stored = Repository::find_by_name(&conn, "local").unwrap().unwrap();
+        assert_eq!(stored.metadata_etag.as_deref(), Some("\"v1\""));
+        assert_eq!(
+            stored.metadata_checksum,
+            Some(sha256_hex(METADATA_JSON.as_bytes()))
+        );
+
+        let mut repo = stored;
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Unchanged
+        );
+        assert_eq!(
+            RepositoryPackage::find_by_name(&conn, "hello")
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // --force ignores the validators
+        let unchanged = IndexChanges::default();
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, true, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 1,
+                skipped: 0,
+                changes: unchanged
+            }
+        );
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        assert!(!requests[2].contains("if-none-match"));
+    }
+
+    #[test]
+    fn test_sync_skips_metadata_with_same_checksum() {
+        let (_temp, mut conn) = create_test_db();
+
+        // No validators, so only the content hash can tell
+        let (url, server) = serve_metadata(METADATA_JSON, None, 2);
+        let mut repo = add_repository(&conn, "local".to_string(), url, true, 0).unwrap();
+
+        let added = IndexChanges {
+            added: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 1,
+                skipped: 0,
+                changes: added
+            }
+        );
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Unchanged
+        );
+        assert_eq!(
+            RepositoryPackage::find_by_name(&conn, "hello")
+                .unwrap()
+                .len(),
+            1
+        );
+        server.join().unwrap();
+    }
+
+    /// A package entry of a JSON metadata index
+    fn json_package(name: &str, version: &str) -> String {
+        format!(
+            r#"{{"name": "{name}", "version": "{version}", "architecture": "x86_64", "description": null,
+            "checksum": "abc", "size": 10, "download_url": "http://127.0.0.1/{name}-{version}.pkg",
+            "dependencies": null, "provides": null, "files": null, "delta_from": null}}"#
+        )
+    }
+
+    fn json_metadata(packages: &[String]) -> &'static str {
+        let body = format!(
+            r#"{{"name": "local", "version": "1", "packages": [{}]}}"#,
+            packages.join(",")
+        );
+        Box::leak(body.into_boxed_str())
+    }
+
+    fn stored_versions(conn: &Connection, name: &str) -> Vec<String> {
+        RepositoryPackage::find_by_name(conn, name)
+            .unwrap()
+            .into_iter()
+            .map(|pkg| pkg.version)
+            .collect()
+    }
+
+    #[test]
+    fn test_failed_json_sync_keeps_previous_index() {
+        let (_temp, mut conn) = create_test_db();
+        let first = json_metadata(&[json_package("hello", "1.0"), json_package("old", "1.0")]);
+        let (url, server) = serve_metadata(first, None, 1);
+        let mut repo = add_repository(&conn, "local".to_string(), url, true, 0).unwrap();
+        let added = IndexChanges {
+            added: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 2,
+                skipped: 0,
+                changes: added
+            }
+        );
+        server.join().unwrap();
+
+        // The download fails: the server is gone
+        let err = sync_repository(&mut conn, &mut repo, true, None).unwrap_err();
+        assert!(matches!(err, Error::DownloadError(_)), "{}", err);
+        assert_eq!(stored_versions(&conn, "hello"), ["1.0"]);
+
+        // The database is busy once the new packages are stored
+        let _failpoint = crate::failpoints::configure("sync::store", "1*return");
+        let updated = json_metadata(&[json_package("hello", "2.0"), json_package("tool", "1.0")]);
+        let (url, server) = serve_metadata(updated, None, 1);
+        repo.url = url;
+        repo.update(&conn).unwrap();
+        let err = sync_repository(&mut conn, &mut repo, true, None).unwrap_err();
+        assert!(err.is_retryable(), "{}", err);
+        server.join().unwrap();
+        assert_eq!(stored_versions(&conn, "hello"), ["1.0"]);
+        assert_eq!(stored_versions(&conn, "old"), ["1.0"]);
+        assert!(stored_versions(&conn, "tool").is_empty());
+
+        // Trying again stores the same index
+        let (url, server) = serve_metadata(updated, None, 1);
+        repo.url = url;
+        repo.update(&conn).unwrap();
+        let changes = IndexChanges {
+            added: 1,
+            removed: 1,
+            updated: 1,
+        };
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 2,
+                skipped: 0,
+                changes
+            }
+        );
+        server.join().unwrap();
+        assert_eq!(stored_versions(&conn, "hello"), ["2.0"]);
+    }
+
+    #[test]
+    fn test_failed_native_sync_keeps_previous_index() {
+        let (_temp, mut conn) = create_test_db();
+        let mut repo = add_repository(
+            &conn,
+            "ubuntu-noble".to_string(),
+            "http://127.0.0.1".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+        let parser = parsers::debian::DebianParser::new(
+            "noble".to_string(),
+            Vec::new(),
+            vec!["amd64".to_string()],
+        );
+        let stanza = |name: &str, version: &str| {
+            format!(
+                "Package: {name}\nArchitecture: amd64\nVersion: {version}\nFilename: pool/main/{name}_{version}_amd64.deb\n\
+                 Size: 10\nSHA256: {}\nDescription: {name}\n",
+                "0".repeat(64)
+            )
+        };
+        let fetched = |stanzas: &[String]| {
+            let packages = parser
+                .parse_packages(&stanzas.join("\n"), "http://127.0.0.1", "main")
+                .unwrap();
+            let index = MetadataIndex {
+                body: stanzas.concat().into_bytes(),
+                checksum: sha256_hex(stanzas.concat().as_bytes()),
+                etag: None,
+                last_modified: None,
+            };
+            FetchedMetadata {
+                bytes_downloaded: 0,
+                result: Ok(Some((index, ParsedIndex::Native(packages)))),
+            }
+        };
+
+        let outcome = store_fetched(
+            &mut conn,
+            &mut repo,
+            fetched(&[stanza("bash", "5.2-1"), stanza("zsh", "5.9-1")]),
+        )
+        .unwrap();
+        let added = IndexChanges {
+            added: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            outcome,
+            SyncOutcome::Updated {
+                packages: 2,
+                skipped: 0,
+                changes: added
+            }
+        );
+
+        // A later metadata file failed to download
+        let failed = FetchedMetadata {
+            bytes_downloaded: 512,
+            result: Err(Error::DownloadError(
+                "HTTP 404 for universe/binary-amd64/Packages.gz".to_string(),
+            )),
+        };
+        assert!(store_fetched(&mut conn, &mut repo, failed).is_err());
+        assert_eq!(stored_versions(&conn, "bash"), ["5.2-1"]);
+
+        // Storing fails after bash 5.3 went in
+        let _failpoint = crate::failpoints::configure("sync::store", "return");
+        let updated = fetched(&[stanza("bash", "5.3-1"), stanza("dash", "0.5-1")]);
+        assert!(store_fetched(&mut conn, &mut repo, updated).is_err());
+        assert_eq!(stored_versions(&conn, "bash"), ["5.2-1"]);
+        assert_eq!(stored_versions(&conn, "zsh"), ["5.9-1"]);
+        assert!(stored_versions(&conn, "dash").is_empty());
+        assert_eq!(
+            Repository::find_by_name(&conn, "ubuntu-noble")
+                .unwrap()
+                .unwrap()
+                .last_sync,
+            repo.last_sync
+        );
+    }
+
+    const FILTERED_METADATA_JSON: &str = r#"{"name": "local", "version": "1", "packages": [
+        {"name": "hello", "version": "1.0", "architecture": "x86_64", "description": null, "checksum": "abc",
+         "size": 10, "download_url": "http://127.0.0.1/hello.pkg", "dependencies": null, "provides": null,
+         "files": null, "delta_from": null},
+        {"name": "hello-debuginfo", "version": "1.0", "architecture": "x86_64", "description": null, "checksum": "abd",
+         "size": 10, "download_url": "http://127.0.0.1/hello-debuginfo.pkg", "dependencies": null, "provides": null,
+         "files": null, "delta_from": null},
+        {"name": "libhello", "version": "1.0", "architecture": "i686", "description": null, "checksum": "abe",
+         "size": 10, "download_url": "http://127.0.0.1/libhello.pkg", "dependencies": null, "provides": null,
+         "files": null, "delta_from": null}
+    ]}"#;
+
+    #[test]
+    fn test_sync_filter_skips_packages_and_explains_resolution() {
+        let (_temp, mut conn) = create_test_db();
+        // The same ETag both times: only the cleared validators force the refetch
+        let (url, server) = serve_metadata(FILTERED_METADATA_JSON, Some("\"v1\""), 2);
+        let mut repo = add_repository(&conn, "local".to_string(), url, true, 0).unwrap();
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 3,
+                skipped: 0,
+                changes: IndexChanges {
+                    added: 3,
+                    ..Default::default()
+                }
+            }
+        );
+
+        let patterns = vec!["*-debuginfo".to_string(), "*.i686".to_string()];
+        let mut repo = set_sync_filter(&conn, "local", None, Some(patterns.clone())).unwrap();
+        assert_eq!(
+            Repository::find_by_name(&conn, "local")
+                .unwrap()
+                .unwrap()
+                .sync_exclude,
+            patterns
+        );
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 1,
+                skipped: 2,
+                changes: IndexChanges {
+                    removed: 2,
+                    ..Default::default()
+                }
+            }
+        );
+        server.join().unwrap();
+
+        assert_eq!(
+            RepositoryPackage::find_by_name(&conn, "hello")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(
+            RepositoryPackage::find_by_name(&conn, "hello-debuginfo")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            RepositoryPackage::find_by_name(&conn, "libhello")
+                .unwrap()
+                .is_empty()
+        );
+
+        let options = SelectionOptions {
+            architecture: Some("x86_64".to_string()),
+            ..Default::default()
+        };
+        let err = PackageSelector::find_best_package(&conn, "hello-debuginfo", &options)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "matches exclude pattern '*-debuginfo' in the sync filter of repository local"
+            ),
+            "{}",
+            err
+        );
+        let options = SelectionOptions {
+            architecture: Some("i686".to_string()),
+            ..Default::default()
+        };
+        let err = PackageSelector::find_best_package(&conn, "libhello", &options)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'*.i686'"), "{}", err);
+        let err =
+            PackageSelector::find_best_package(&conn, "missing", &SelectionOptions::default())
+                .unwrap_err()
+                .to_string();
+        assert!(!err.contains("sync filter"), "{}", err);
+    }
+
+    #[test]
+    fn test_download_reports_progress() {
+        let (url, server) = serve_metadata(METADATA_JSON, None, 1);
+        let dest = tempfile::tempdir().unwrap();
+        let dest_path = dest.path().join("metadata.json");
+
+        let reports = std::sync::Mutex::new(Vec::new());
+        let progress = |received, total| reports.lock().unwrap().push((received, total));
+        let client = RepositoryClient::new().unwrap();
+        client
+            .download_file(&metadata_json_url(&url), &dest_path, Some(&progress))
+            .unwrap();
+        server.join().unwrap();
+
+        let len = METADATA_JSON.len() as u64;
+        let reports = reports.into_inner().unwrap();
+        assert_eq!(reports.first(), Some(&(0, Some(len))));
+        assert_eq!(reports.last(), Some(&(len, Some(len))));
+        assert_eq!(client.bytes_downloaded(), len);
+        assert_eq!(fs::read(&dest_path).unwrap(), METADATA_JSON.as_bytes());
+    }
+
+    #[test]
+    fn test_file_urls_read_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("metadata.json"), METADATA_JSON).unwrap();
+        let url = reqwest::Url::from_directory_path(dir.path())
+            .unwrap()
+            .to_string();
+
+        let client = RepositoryClient::new().unwrap();
+        let metadata = client.fetch_metadata(&url).unwrap();
+        assert_eq!(metadata.packages.len(), 1);
+
+        let dest_path = dir.path().join("copy").join("metadata.json");
+        client
+            .download_file(&metadata_json_url(&url), &dest_path, None)
+            .unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), METADATA_JSON.as_bytes());
+        assert_eq!(client.bytes_downloaded(), 2 * METADATA_JSON.len() as u64);
+
+        let err = client
+            .download_file(&format!("{}missing.pkg", url), &dest_path, None)
+            .unwrap_err();
+        assert!(err.to_string().contains("missing.pkg"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_checksum_algorithms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.pkg");
+        fs::write(&path, b"conary fixture package\n").unwrap();
+
+        let digests = [
+            (
+                ChecksumType::Sha256,
+                "d4a394ea6359409b017f219a0f8c238db6464da63e143638b87cc179e89db1bc",
+            ),
+            (
+                ChecksumType::Sha512,
+                "bd6f29effc7dd146727b9395696f9717788cbdfbc40742bf180a83511de6af04\
+                 553bc8148294aaab000c0498d3f12497b9a404c482b96d2ec3ecb428b0cc4663",
+            ),
+            (ChecksumType::Md5, "21610f67a791083fbb6289b45909e099"),
+        ];
+        for (checksum_type, digest) in digests {
+            verify_checksum(&path, digest, checksum_type).unwrap();
+            verify_checksum(&path, &digest.to_uppercase(), checksum_type).unwrap();
+        }
+
+        // A SHA-512 checksum is not taken for a SHA-256 one
+        let err = verify_checksum(&path, digests[1].1, ChecksumType::Sha256).unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { actual, .. } if actual == digests[0].1));
+    }
+
+    #[test]
+    fn test_sync_stores_checksum_type() {
+        let (_temp, mut conn) = create_test_db();
+        let dir = tempfile::tempdir().unwrap();
+        let metadata = METADATA_JSON.replace(
+            r#""checksum": "abc","#,
+            r#""checksum": "abc", "checksum_type": "sha512","#,
+        );
+        fs::write(dir.path().join("metadata.json"), metadata).unwrap();
+        let url = reqwest::Url::from_directory_path(dir.path())
+            .unwrap()
+            .to_string();
+
+        let mut repo = add_repository(&conn, "local".to_string(), url, true, 0).unwrap();
+        sync_repository(&mut conn, &mut repo, false, None).unwrap();
+        let hello = RepositoryPackage::find_by_name(&conn, "hello").unwrap();
+        assert_eq!(hello[0].checksum_type, ChecksumType::Sha512);
+    }
+
+    /// Serve `body` on localhost, honouring `Range: bytes=N-` when `ranges` is set
+    ///
+    /// Returns the URL and the request headers seen, one string per request.
+    /// Each response body is sent in two halves with a pause between them.
+    fn serve_ranges(
+        body: &'static [u8],
+        ranges: bool,
+        requests: usize,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/pkg.bin", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut headers = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    headers.push_str(&line.to_lowercase());
+                }
+
+                let start = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok())
+                    .filter(|_| ranges);
+                let (status, content) = match start {
+                    Some(start) => ("206 Partial Content", &body[start..]),
+                    None => ("200 OK", body),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    content.len()
+                );
+                // The body goes out in two pieces, so the client reads a
+                // partial body first; it may hang up before the second
+                let (first, rest) = content.split_at(content.len() / 2);
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(first).unwrap();
+                stream.flush().unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                let _ = stream.write_all(rest);
+                seen.push(headers);
+            }
+            seen
+        });
+        (url, handle)
+    }
+
+    const PACKAGE_BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    #[test]
+    fn test_download_resumes_partial_file() {
+        let (url, server) = serve_ranges(PACKAGE_BODY, true, 1);
+        let dest = tempfile::tempdir().unwrap();
+        let dest_path = dest.path().join("pkg.bin");
+        fs::write(dest.path().join("pkg.tmp"), &PACKAGE_BODY[..10]).unwrap();
+
+        let reports = std::sync::Mutex::new(Vec::new());
+        let progress = |received, total| reports.lock().unwrap().push((received, total));
+        let client = RepositoryClient::new().unwrap();
+        client
+            .download_file(&url, &dest_path, Some(&progress))
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("range: bytes=10-"), "{}", requests[0]);
+        assert_eq!(fs::read(&dest_path).unwrap(), PACKAGE_BODY);
+        assert!(!dest.path().join("pkg.tmp").exists());
+        assert_eq!(client.bytes_downloaded(), PACKAGE_BODY.len() as u64 - 10);
+
+        // Progress covers the whole file, not just the resumed part
+        let len = PACKAGE_BODY.len() as u64;
+        let reports = reports.into_inner().unwrap();
+        assert_eq!(reports.first(), Some(&(10, Some(len))));
+        assert_eq!(reports.last(), Some(&(len, Some(len))));
+    }
+
+    #[test]
+    fn test_download_restarts_when_range_is_ignored() {
+        let (url, server) = serve_ranges(PACKAGE_BODY, false, 1);
+        let dest = tempfile::tempdir().unwrap();
+        let dest_path = dest.path().join("pkg.bin");
+        fs::write(dest.path().join("pkg.tmp"), b"stale").unwrap();
+
+        RepositoryClient::new()
+            .unwrap()
+            .download_file(&url, &dest_path, None)
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), PACKAGE_BODY);
+
+        // With resume disabled no range is asked for at all
+        let (url, server) = serve_ranges(PACKAGE_BODY, true, 1);
+        fs::write(dest.path().join("pkg.tmp"), &PACKAGE_BODY[..10]).unwrap();
+        let options = ClientOptions {
+            max_retries: 1,
+            resume: false,
+        };
+        let client = RepositoryClient::new_with_options(options).unwrap();
+        client.download_file(&url, &dest_path, None).unwrap();
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("range:"));
+        assert_eq!(client.bytes_downloaded(), PACKAGE_BODY.len() as u64);
+    }
+
+    #[test]
+    fn test_download_retry_resumes_interrupted_body() {
+        let (url, server) = serve_ranges(PACKAGE_BODY, true, 2);
+        let dest = tempfile::tempdir().unwrap();
+        let dest_path = dest.path().join("pkg.bin");
+
+        // The connection drops after the first chunk is written
+        let _failpoint = crate::failpoints::configure("download::body", "1*return");
+        let reports = std::sync::Mutex::new(Vec::new());
+        let progress = |received, total| reports.lock().unwrap().push((received, total));
+        let client = RepositoryClient::new().unwrap();
+        client
+            .download_file(&url, &dest_path, Some(&progress))
+            .unwrap();
+
+        // The retry asks for the rest after what the partial file held when
+        // the connection dropped, the last progress before the failure
+        let reports = reports.into_inner().unwrap();
+        let partial = reports
+            .iter()
+            .map(|(received, _)| *received)
+            .find(|received| *received > 0)
+            .unwrap();
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("range:"));
+        assert!(partial < PACKAGE_BODY.len() as u64, "{:?}", reports);
+        assert!(
+            requests[1].contains(&format!("range: bytes={}-\r\n", partial)),
+            "{}",
+            requests[1]
+        );
+        assert_eq!(fs::read(&dest_path).unwrap(), PACKAGE_BODY);
+        assert_eq!(client.bytes_downloaded(), PACKAGE_BODY.len() as u64);
+    }
+
+    #[test]
+    fn test_resumed_download_with_bad_checksum_restarts() {
+        let (url, server) = serve_ranges(PACKAGE_BODY, true, 2);
+        let dest = tempfile::tempdir().unwrap();
+        let dest_path = dest.path().join("pkg.bin");
+        // A partial file from some other version of the package
+        fs::write(dest.path().join("pkg.tmp"), b"XXXXXXXXXX").unwrap();
+
+        let client = RepositoryClient::new().unwrap();
+        client
+            .download_verified(
+                &url,
+                &dest_path,
+                &sha256_hex(PACKAGE_BODY),
+                ChecksumType::Sha256,
+                None,
+            )
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("range: bytes=10-"));
+        assert!(!requests[1].contains("range:"));
+        assert_eq!(fs::read(&dest_path).unwrap(), PACKAGE_BODY);
+
+        // A fresh download that fails its checksum is not retried
+        let (url, server) = serve_ranges(PACKAGE_BODY, true, 1);
+        let err = client
+            .download_verified(&url, &dest_path, "0000", ChecksumType::Sha256, None)
+            .unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { .. }));
+        server.join().unwrap();
+    }
+
+    /// A JSON metadata index listing `count` packages named "{prefix}-N"
+    fn metadata_with_packages(prefix: &str, count: usize) -> &'static str {
+        let packages: Vec<String> = (0..count)
+            .map(|i| {
+                format!(
+                    r#"{{"name": "{prefix}-{i}", "version": "1.0", "architecture": null, "description": null,
+                    "checksum": "abc", "size": 10, "download_url": "http://127.0.0.1/{prefix}-{i}.pkg",
+                    "dependencies": ["libc"], "provides": null, "files": null, "delta_from": null}}"#
+                )
+            })
+            .collect();
+        let body = format!(
+            r#"{{"name": "{}", "version": "1", "packages": [{}]}}"#,
+            prefix,
+            packages.join(",")
+        );
+        Box::leak(body.into_boxed_str())
+    }
+
+    #[test]
+    fn test_parallel_sync_funnels_writes_through_one_connection() {
+        let (_temp, mut conn) = create_test_db();
+
+        let mut servers = Vec::new();
+        let mut repos = Vec::new();
+        for i in 0..4 {
+            let name = format!("repo{}", i);
+            let (url, server) = serve_metadata(metadata_with_packages(&name, 200), None, 2);
+            repos.push(add_repository(&conn, name, url, true, 0).unwrap());
+            servers.push(server);
+        }
+        let limits = ResourceLimits::default();
+
+        // The second, forced round replaces every stored index with the same packages
+        for added in [200, 0] {
+            let results = sync_repositories(&mut conn, &repos, true, &limits, None);
+            assert_eq!(results.len(), 4);
+            let changes = IndexChanges {
+                added,
+                ..Default::default()
+            };
+            for (i, (name, outcome)) in results.iter().enumerate() {
+                assert_eq!(name, &format!("repo{}", i));
+                assert_eq!(
+                    outcome.as_ref().unwrap(),
+                    &SyncOutcome::Updated {
+                        packages: 200,
+                        skipped: 0,
+                        changes
+                    }
+                );
+            }
+        }
+        for server in servers {
+            server.join().unwrap();
+        }
+
+        for repo in &repos {
+            let packages = RepositoryPackage::find_by_repository(&conn, repo.id.unwrap()).unwrap();
+            assert_eq!(packages.len(), 200);
+            let stored = Repository::find_by_name(&conn, &repo.name)
+                .unwrap()
+                .unwrap();
+            assert!(stored.last_sync.is_some());
+        }
+    }
+
+    /// A download helper that copies the file named by the last URL segment out of `fixtures`
+    fn fixture_helper(fixtures: &Path) -> String {
+        let script = fixtures.join("fetch.sh");
+        fs::write(&script, "cp \"$(dirname \"$0\")/${1##*/}\" \"$2\"\n").unwrap();
+        format!("sh {}", script.display())
+    }
+
+    #[test]
+    fn test_download_package_through_helper() {
+        let (_temp, conn) = create_test_db();
+        let fixtures = tempfile::tempdir().unwrap();
+        fs::write(fixtures.path().join("pkg.bin"), PACKAGE_BODY).unwrap();
+        let command = fixture_helper(fixtures.path());
+        add_repository(
+            &conn,
+            "site".to_string(),
+            "s3://bucket/repo".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+        let repo = set_download_helper(&conn, "site", Some(&command), false, false).unwrap();
+
+        let mut pkg = RepositoryPackage::new(
+            repo.id.unwrap(),
+            "pkg".to_string(),
+            "1.0".to_string(),
+            sha256_hex(PACKAGE_BODY),
+            PACKAGE_BODY.len() as i64,
+            "s3://bucket/repo/pkg.bin".to_string(),
+        );
+        let dest = tempfile::tempdir().unwrap();
+        let path = download_package(&repo, &pkg, dest.path(), None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), PACKAGE_BODY);
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 1);
+
+        // The checksum is checked whatever the helper says
+        pkg.checksum = sha256_hex(b"other");
+        fs::remove_file(&path).unwrap();
+        let err = download_package(&repo, &pkg, dest.path(), None).unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { .. }), "{}", err);
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 0);
+
+        // A failing helper is an error, unless falling back is allowed
+        let (url, server) = serve_ranges(PACKAGE_BODY, false, 1);
+        pkg.checksum = sha256_hex(PACKAGE_BODY);
+        pkg.download_url = url;
+        let repo = set_download_helper(
+            &conn,
+            "site",
+            Some("echo 'no credentials' >&2; exit 1"),
+            false,
+            false,
+        )
+        .unwrap();
+        let err = download_package(&repo, &pkg, dest.path(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no credentials"), "{}", err);
+
+        let repo = set_download_helper(&conn, "site", Some("exit 1"), false, true).unwrap();
+        let path = download_package(&repo, &pkg, dest.path(), None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), PACKAGE_BODY);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_download_package_refuses_other_hosts() {
+        let (_temp, conn) = create_test_db();
+        add_repository(
+            &conn,
+            "site".to_string(),
+            "http://127.0.0.1:9/repo".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+        let repo = set_url_policy(&conn, "site", None, Some(false)).unwrap();
+        assert!(!repo.prefer_https && !repo.allow_cross_host);
+
+        let pkg = RepositoryPackage::new(
+            repo.id.unwrap(),
+            "pkg".to_string(),
+            "1.0".to_string(),
+            sha256_hex(PACKAGE_BODY),
+            PACKAGE_BODY.len() as i64,
+            "https://mirror.example.org/repo/pkg.bin".to_string(),
+        );
+        let dest = tempfile::tempdir().unwrap();
+        let err = download_package(&repo, &pkg, dest.path(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Refusing")
+                && err.contains("host mirror.example.org instead of 127.0.0.1"),
+            "{}",
+            err
+        );
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 0);
+
+        let stored = Repository::find_by_name(&conn, "site").unwrap().unwrap();
+        assert!(!stored.allow_cross_host);
+    }
+
+    #[test]
+    fn test_fetch_package_falls_back_from_a_failing_delta() {
+        let (_temp, conn) = create_test_db();
+        let served = tempfile::tempdir().unwrap();
+        fs::write(served.path().join("pkg.bin"), PACKAGE_BODY).unwrap();
+        fs::write(served.path().join("pkg.delta"), b"not a delta").unwrap();
+        let url = format!("file://{}", served.path().display());
+        let repo = add_repository(&conn, "local".to_string(), url.clone(), true, 0).unwrap();
+        let pkg = RepositoryPackage::new(
+            repo.id.unwrap(),
+            "pkg".to_string(),
+            "1.1".to_string(),
+            sha256_hex(PACKAGE_BODY),
+            PACKAGE_BODY.len() as i64,
+            format!("{}/pkg.bin", url),
+        );
+
+        let cas_dir = tempfile::tempdir().unwrap();
+        let cas = CasStore::new(cas_dir.path()).unwrap();
+        let base = cas.store(b"pkg 1.0").unwrap();
+        PackageDelta::new(
+            "pkg".to_string(),
+            "1.0".to_string(),
+            "1.1".to_string(),
+            base,
+            pkg.checksum.clone(),
+            format!("{}/pkg.delta", url),
+            11,
+            sha256_hex(b"not a delta"),
+            PACKAGE_BODY.len() as i64,
+        )
+        .insert(&conn)
+        .unwrap();
+
+        // Only a CAS holding the base can use the delta
+        let empty_dir = tempfile::tempdir().unwrap();
+        assert!(
+            usable_deltas(&conn, &CasStore::new(empty_dir.path()).unwrap(), &pkg)
+                .unwrap()
+                .is_empty()
+        );
+        let deltas = usable_deltas(&conn, &cas, &pkg).unwrap();
+        assert_eq!(deltas.len(), 1);
+
+        // The delta does not decompress, so the whole package is downloaded
+        let dest = tempfile::tempdir().unwrap();
+        let fetched = fetch_package(&repo, &pkg, &deltas, &cas, dest.path(), None).unwrap();
+        assert!(fetched.delta.is_none());
+        assert_eq!((fetched.delta_failures, fetched.bytes_saved()), (1, 0));
+        assert_eq!(fetched.downloaded_bytes, PACKAGE_BODY.len() as i64);
+        assert_eq!(fs::read(&fetched.path).unwrap(), PACKAGE_BODY);
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_sync_metadata_through_helper() {
+        let (_temp, mut conn) = create_test_db();
+        let fixtures = tempfile::tempdir().unwrap();
+        fs::write(fixtures.path().join("metadata.json"), METADATA_JSON).unwrap();
+        let command = fixture_helper(fixtures.path());
+        add_repository(
+            &conn,
+            "site".to_string(),
+            "s3://bucket/repo".to_string(),
+            true,
+            0,
+        )
+        .unwrap();
+        let mut repo = set_download_helper(&conn, "site", Some(&command), true, false).unwrap();
+
+        let added = IndexChanges {
+            added: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Updated {
+                packages: 1,
+                skipped: 0,
+                changes: added
+            }
+        );
+        assert_eq!(
+            RepositoryPackage::find_by_name(&conn, "hello")
+                .unwrap()
+                .len(),
+            1
+        );
+        // No validators come back from a helper; the checksum still spots an unchanged index
+        assert_eq!(
+            sync_repository(&mut conn, &mut repo, false, None).unwrap(),
+            SyncOutcome::Unchanged
+        );
+
+        let stored = Repository::find_by_name(&conn, "site").unwrap().unwrap();
+        assert_eq!(stored.download_helper.as_deref(), Some(command.as_str()));
+        assert!(stored.helper_metadata && !stored.helper_fallback);
+        let cleared = set_download_helper(&conn, "site", None, true, true).unwrap();
+        assert!(
+            cleared.download_helper.is_none()
+                && !cleared.helper_metadata
+                && !cleared.helper_fallback
+        );
+    }
 }
@@ -0,0 +1,312 @@
+// src/repository/overlap.rs
+
+//! Package names offered by repositories of different formats
+//!
+//! With Fedora, Arch and Debian repositories enabled side by side, many
+//! names exist in several of them, with version schemes that cannot be
+//! compared meaningfully. Selection still picks one, by priority first and
+//! then by comparing the versions anyway. An [`OverlapReport`] lists those
+//! names, what each repository offers, which repository selection picks and
+//! why, for `conary doctor repos`.
+
+use crate::db::models::{Repository, RepositoryPackage};
+use crate::error::Result;
+use crate::repository::{PackageSelector, PackageWithRepo, SelectionOptions};
+use crate::version::{self, VersionScheme};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::cmp::Ordering;
+
+/// Names in two or more enabled repositories whose packages come in more
+/// than one format, by name
+///
+/// The format follows the package URL the way `VersionScheme::for_location`
+/// reads it.
+const OVERLAP_QUERY: &str = "
+    SELECT rp.name
+    FROM repository_packages rp
+    JOIN repositories r ON r.id = rp.repository_id
+    WHERE r.enabled = 1
+    GROUP BY rp.name
+    HAVING COUNT(DISTINCT rp.repository_id) > 1
+       AND COUNT(DISTINCT CASE
+               WHEN rp.download_url LIKE '%.deb' THEN 'deb'
+               WHEN rp.download_url LIKE '%.pkg.tar%' THEN 'arch'
+               ELSE 'rpm'
+           END) > 1
+    ORDER BY rp.name";
+
+/// One page of the names offered in more than one package format
+#[derive(Debug, Clone, Serialize)]
+pub struct OverlapReport {
+    /// Names overlapping in all, not just on this page
+    pub total: usize,
+    /// Names skipped before this page
+    pub offset: usize,
+    pub overlaps: Vec<PackageOverlap>,
+}
+
+/// A name several repositories offer in different formats
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageOverlap {
+    pub name: String,
+    /// Every package of the name in an enabled repository
+    pub offers: Vec<RepositoryOffer>,
+    /// Repository selection picks for this system, if any
+    pub selected: Option<String>,
+    /// Why that repository wins, or why none does
+    pub reason: String,
+}
+
+/// A package of the overlapping name in one repository
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RepositoryOffer {
+    pub repository: String,
+    pub priority: i32,
+    /// "rpm", "deb" or "arch"
+    pub format: String,
+    pub version: String,
+    pub architecture: Option<String>,
+}
+
+impl OverlapReport {
+    /// Build the page of `limit` overlapping names starting at `offset`
+    pub fn build(conn: &Connection, offset: usize, limit: usize) -> Result<Self> {
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM ({})", OVERLAP_QUERY),
+            [],
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(&format!("{} LIMIT ?1 OFFSET ?2", OVERLAP_QUERY))?;
+        let names = stmt
+            .query_map([limit as i64, offset as i64], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut overlaps = Vec::with_capacity(names.len());
+        for name in names {
+            overlaps.push(PackageOverlap::build(conn, name)?);
+        }
+        Ok(Self {
+            total: total as usize,
+            offset,
+            overlaps,
+        })
+    }
+}
+
+impl PackageOverlap {
+    fn build(conn: &Connection, name: String) -> Result<Self> {
+        let mut offers = Vec::new();
+        for pkg in RepositoryPackage::find_by_name(conn, &name)? {
+            let Some(repo) = Repository::find_by_id(conn, pkg.repository_id)? else {
+                continue;
+            };
+            if !repo.enabled {
+                continue;
+            }
+            offers.push(RepositoryOffer {
+                repository: repo.name,
+                priority: repo.priority,
+                format: format_name(pkg.version_scheme()).to_string(),
+                version: pkg.version,
+                architecture: pkg.architecture,
+            });
+        }
+        offers.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then_with(|| a.repository.cmp(&b.repository))
+        });
+
+        let candidates =
+            PackageSelector::search_packages(conn, &name, &SelectionOptions::default())?;
+        let (selected, reason) = if candidates.is_empty() {
+            (
+                None,
+                "no repository offers a package for this architecture outside explicit-only repositories".to_string(),
+            )
+        } else {
+            let best = PackageSelector::select_best(candidates.clone())?;
+            let reason = selection_reason(&best, &candidates);
+            (Some(best.repository.name), reason)
+        };
+        Ok(Self {
+            name,
+            offers,
+            selected,
+            reason,
+        })
+    }
+}
+
+/// Why selection picked `best` among `candidates`, in the order
+/// `PackageSelector::select_best` decides
+fn selection_reason(best: &PackageWithRepo, candidates: &[PackageWithRepo]) -> String {
+    let rivals: Vec<&PackageWithRepo> = candidates
+        .iter()
+        .filter(|other| other.repository.id != best.repository.id)
+        .collect();
+
+    let Some(runner_up) = rivals.iter().max_by_key(|other| other.repository.priority) else {
+        return format!(
+            "only {} has a candidate for this system; the others are explicit-only or built for other architectures",
+            best.repository.name
+        );
+    };
+    if best.repository.priority > runner_up.repository.priority {
+        return format!(
+            "priority {} is higher than {} of {}",
+            best.repository.priority, runner_up.repository.priority, runner_up.repository.name
+        );
+    }
+
+    let tied: Vec<&PackageWithRepo> = rivals
+        .into_iter()
+        .filter(|other| other.repository.priority == best.repository.priority)
+        .collect();
+    let tied_names: Vec<&str> = tied
+        .iter()
+        .map(|other| other.repository.name.as_str())
+        .collect();
+    let scheme = best.package.version_scheme();
+    let newer = tied.iter().all(|other| {
+        version::compare_versions(scheme, &best.package.version, &other.package.version)
+            == Ordering::Greater
+    });
+    if newer {
+        format!(
+            "same priority as {}; {} sorts newest when compared as {} versions",
+            tied_names.join(", "),
+            best.package.version,
+            format_name(scheme)
+        )
+    } else {
+        format!(
+            "same priority and version as {}; the architecture-specific build, then the repository name, decides",
+            tied_names.join(", ")
+        )
+    }
+}
+
+/// Short name of the package format a version scheme belongs to
+fn format_name(scheme: VersionScheme) -> &'static str {
+    match scheme {
+        VersionScheme::Rpm => "rpm",
+        VersionScheme::Debian => "deb",
+        VersionScheme::Arch => "arch",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn add_package(conn: &Connection, repository_id: i64, name: &str, version: &str, file: &str) {
+        let mut pkg = RepositoryPackage::new(
+            repository_id,
+            name.to_string(),
+            version.to_string(),
+            format!("sha256-{}", file),
+            1,
+            format!("https://example.com/{}", file),
+        );
+        pkg.insert(conn).unwrap();
+    }
+
+    #[test]
+    fn test_overlap_report() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("conary.db");
+        db::init(db_path.to_str().unwrap()).unwrap();
+        let conn = db::open(db_path.to_str().unwrap()).unwrap();
+
+        let repo = |name: &str, priority: i32| {
+            let mut repo =
+                Repository::new(name.to_string(), format!("https://example.com/{}", name));
+            repo.priority = priority;
+            repo.insert(&conn).unwrap()
+        };
+        let fedora = repo("fedora-41", 90);
+        let arch = repo("arch-core", 90);
+        let ubuntu = repo("ubuntu-noble", 80);
+        let updates = repo("fedora-updates", 95);
+
+        // curl and zlib overlap across formats; bash only within rpm
+        add_package(
+            &conn,
+            fedora,
+            "curl",
+            "8.9.1-2.fc41",
+            "curl-8.9.1-2.fc41.x86_64.rpm",
+        );
+        add_package(
+            &conn,
+            arch,
+            "curl",
+            "8.10.1-1",
+            "curl-8.10.1-1-x86_64.pkg.tar.zst",
+        );
+        add_package(
+            &conn,
+            ubuntu,
+            "zlib",
+            "1:1.3.dfsg-3.1ubuntu2",
+            "zlib1g_1.3.dfsg-3.1ubuntu2_amd64.deb",
+        );
+        add_package(
+            &conn,
+            arch,
+            "zlib",
+            "1:1.3.1-2",
+            "zlib-1:1.3.1-2-x86_64.pkg.tar.zst",
+        );
+        add_package(
+            &conn,
+            fedora,
+            "bash",
+            "5.2.26-3.fc41",
+            "bash-5.2.26-3.fc41.x86_64.rpm",
+        );
+        add_package(
+            &conn,
+            updates,
+            "bash",
+            "5.2.32-1.fc41",
+            "bash-5.2.32-1.fc41.x86_64.rpm",
+        );
+
+        let report = OverlapReport::build(&conn, 0, 10).unwrap();
+        assert_eq!(report.total, 2);
+        let names: Vec<&str> = report
+            .overlaps
+            .iter()
+            .map(|overlap| overlap.name.as_str())
+            .collect();
+        assert_eq!(names, ["curl", "zlib"]);
+
+        let curl = &report.overlaps[0];
+        let formats: Vec<(&str, &str)> = curl
+            .offers
+            .iter()
+            .map(|offer| (offer.repository.as_str(), offer.format.as_str()))
+            .collect();
+        assert_eq!(formats, [("arch-core", "arch"), ("fedora-41", "rpm")]);
+        assert_eq!(curl.selected.as_deref(), Some("arch-core"));
+        assert!(
+            curl.reason
+                .starts_with("same priority as fedora-41; 8.10.1-1 sorts newest"),
+            "{}",
+            curl.reason
+        );
+
+        let zlib = &report.overlaps[1];
+        assert_eq!(zlib.selected.as_deref(), Some("arch-core"));
+        assert_eq!(zlib.reason, "priority 90 is higher than 80 of ubuntu-noble");
+
+        // Pages count from the full list
+        let page = OverlapReport::build(&conn, 1, 1).unwrap();
+        assert_eq!((page.total, page.offset, page.overlaps.len()), (2, 1, 1));
+        assert_eq!(page.overlaps[0].name, "zlib");
+    }
+}
@@ -2,18 +2,49 @@
 
 //! Arch Linux repository metadata parser
 //!
-//! Parses Arch Linux .db.tar.gz files which contain package metadata
-//! in a custom text format with %FIELD% markers.
+//! Parses Arch Linux .db files which contain package metadata in a custom
+//! text format with %FIELD% markers. The database is a tarball that may be
+//! gzip, xz or zstd compressed, or not compressed at all.
 
-use super::{ChecksumType, Dependency, PackageMetadata, RepositoryParser};
+use super::{ChecksumType, Dependency, DependencyType, PackageMetadata, Provide, RepositoryParser};
 use crate::error::{Error, Result};
+use crate::repository::{ProgressFn, RepositoryClient, resolve_location};
 use flate2::read::GzDecoder;
 use std::collections::HashMap;
 use std::io::Read;
 use tar::Archive;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use xz2::read::XzDecoder;
 
+/// Offset of the "ustar" magic in a tar header
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// Encoding of a downloaded repository database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DbEncoding {
+    Gzip,
+    Xz,
+    Zstd,
+    Tar,
+}
+
+impl DbEncoding {
+    /// Identify the encoding from the leading magic bytes
+    fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::Gzip)
+        } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Self::Xz)
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::Zstd)
+        } else if data.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5) == Some(b"ustar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
 /// Arch Linux repository parser
 pub struct ArchParser {
     /// Repository name (e.g., "core", "extra", "community")
@@ -26,58 +57,36 @@ impl ArchParser {
         Self { repo_name }
     }
 
-    /// Download and decompress the repository database
-    fn download_database(&self, repo_url: &str) -> Result<Vec<u8>> {
-        let db_url = format!("{}/{}.db", repo_url.trim_end_matches('/'), self.repo_name);
-        debug!("Downloading Arch database from: {}", db_url);
-
-        let response = reqwest::blocking::get(&db_url)
-            .map_err(|e| Error::DownloadError(format!("Failed to download {}: {}", db_url, e)))?;
-
-        if !response.status().is_success() {
-            return Err(Error::DownloadError(format!(
-                "Failed to download {}: HTTP {}",
-                db_url,
-                response.status()
-            )));
-        }
-
-        let bytes = response
-            .bytes()
-            .map_err(|e| Error::DownloadError(format!("Failed to read response: {}", e)))?;
-
-        Ok(bytes.to_vec())
-    }
-
-    /// Decompress the database (handles .gz, .xz, or .zst)
+    /// Decompress the database according to its magic bytes
+    ///
+    /// The `.db` name says nothing about the encoding: repositories serve
+    /// gzip, xz or zstd compressed tarballs, or a plain tar.
     fn decompress_database(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Try gzip first
-        let mut gz = GzDecoder::new(data);
-        let mut decompressed = Vec::new();
-        if gz.read_to_end(&mut decompressed).is_ok() && !decompressed.is_empty() {
-            debug!("Decompressed gzip database");
-            return Ok(decompressed);
-        }
+        let encoding = DbEncoding::sniff(data).ok_or_else(|| {
+            let magic: Vec<String> = data.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+            Error::ParseError(format!(
+                "Unrecognized Arch database format (expected gzip, xz, zstd or tar; found magic bytes: {})",
+                if magic.is_empty() { "none".to_string() } else { magic.join(" ") }
+            ))
+        })?;
+        debug!("Arch database is {:?} encoded", encoding);
 
-        // Try xz
-        let mut xz = XzDecoder::new(data);
         let mut decompressed = Vec::new();
-        if xz.read_to_end(&mut decompressed).is_ok() && !decompressed.is_empty() {
-            debug!("Decompressed xz database");
-            return Ok(decompressed);
-        }
-
-        // If neither worked, try zstd
-        match zstd::decode_all(data) {
-            Ok(decompressed) => {
-                debug!("Decompressed zstd database");
-                Ok(decompressed)
-            }
-            Err(e) => Err(Error::ParseError(format!(
-                "Failed to decompress database (tried gz, xz, zstd): {}",
-                e
-            ))),
-        }
+        let result = match encoding {
+            DbEncoding::Gzip => GzDecoder::new(data).read_to_end(&mut decompressed),
+            DbEncoding::Xz => XzDecoder::new(data).read_to_end(&mut decompressed),
+            DbEncoding::Zstd => zstd::stream::read::Decoder::new(data)
+                .and_then(|mut decoder| decoder.read_to_end(&mut decompressed)),
+            DbEncoding::Tar => return Ok(data.to_vec()),
+        };
+        result.map_err(|e| {
+            Error::ParseError(format!(
+                "Failed to decompress {:?} database: {}",
+                encoding, e
+            ))
+        })?;
+
+        Ok(decompressed)
     }
 
     /// Parse a desc file from the tarball
@@ -112,16 +121,22 @@ impl ArchParser {
         fields
     }
 
-    /// Parse dependencies from depends file
-    fn parse_depends_file(&self, content: &str) -> Vec<Dependency> {
-        let fields = self.parse_desc_file(content);
+    /// Parse the %DEPENDS% and %OPTDEPENDS% entries of a desc or depends file
+    fn parse_dependencies(&self, fields: &HashMap<String, Vec<String>>) -> Vec<Dependency> {
         let mut dependencies = Vec::new();
 
-        // Runtime dependencies
+        // Runtime dependencies; sonames are needed as capabilities
         if let Some(deps) = fields.get("DEPENDS") {
             for dep in deps {
                 let (name, constraint) = self.parse_dependency_string(dep);
-                dependencies.push(Dependency::runtime_versioned(name, constraint));
+                if is_soname(&name) {
+                    dependencies.push(Dependency::capability(
+                        name,
+                        Some(constraint).filter(|c| !c.is_empty()),
+                    ));
+                } else {
+                    dependencies.push(Dependency::runtime_versioned(name, constraint));
+                }
             }
         }
 
@@ -142,6 +157,47 @@ impl ArchParser {
         dependencies
     }
 
+    /// Parse the %PROVIDES% entries of a desc or depends file
+    ///
+    /// Entries look like "sh" or "libreadline.so=8-64".
+    fn parse_provides(&self, fields: &HashMap<String, Vec<String>>) -> Vec<Provide> {
+        fields
+            .get("PROVIDES")
+            .map(|provides| {
+                provides
+                    .iter()
+                    .map(|provide| {
+                        let (name, constraint) = self.parse_dependency_string(provide);
+                        let version = constraint.strip_prefix('=').map(str::to_string);
+                        Provide::new(name, version)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Record the %CONFLICTS% and %REPLACES% entries of a desc or depends file
+    ///
+    /// They are kept as raw entries ("name" or "name<2.0") in the package's
+    /// extra metadata under "conflicts" and "replaces".
+    fn add_relations(&self, extra: &mut serde_json::Value, fields: &HashMap<String, Vec<String>>) {
+        let Some(extra) = extra.as_object_mut() else {
+            return;
+        };
+
+        for (field, key) in [("CONFLICTS", "conflicts"), ("REPLACES", "replaces")] {
+            let Some(entries) = fields.get(field) else {
+                continue;
+            };
+            let list = extra
+                .entry(key)
+                .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+            if let Some(list) = list.as_array_mut() {
+                list.extend(entries.iter().cloned().map(serde_json::Value::String));
+            }
+        }
+    }
+
     /// Parse dependency string into name and constraint
     /// Format: "package>=1.0" or "package=1.0" or "package<2.0" or just "package"
     fn parse_dependency_string(&self, dep: &str) -> (String, String) {
@@ -156,40 +212,36 @@ impl ArchParser {
         // No version constraint
         (dep.to_string(), String::new())
     }
-}
-
-impl RepositoryParser for ArchParser {
-    fn sync_metadata(&self, repo_url: &str) -> Result<Vec<PackageMetadata>> {
-        info!("Syncing Arch Linux repository: {}", self.repo_name);
-
-        // Download database
-        let db_data = self.download_database(repo_url)?;
 
-        // Decompress
-        let decompressed = self.decompress_database(&db_data)?;
+    /// Parse a downloaded database into package metadata
+    ///
+    /// Download URLs are built relative to `repo_url`.
+    fn parse_database(&self, db_data: &[u8], repo_url: &str) -> Result<Vec<PackageMetadata>> {
+        let decompressed = self.decompress_database(db_data)?;
 
         // Extract tarball
         let mut archive = Archive::new(decompressed.as_slice());
         let mut packages = Vec::new();
+        // Package directory ("bash-5.2.037-1") to index in `packages`
+        let mut by_dir: HashMap<String, usize> = HashMap::new();
 
         // Iterate through tarball entries
         for entry in archive.entries()? {
-            let mut entry = entry.map_err(|e| {
-                Error::ParseError(format!("Failed to read tarball entry: {}", e))
-            })?;
+            let mut entry = entry
+                .map_err(|e| Error::ParseError(format!("Failed to read tarball entry: {}", e)))?;
 
             let path = entry
                 .path()
                 .map_err(|e| Error::ParseError(format!("Invalid path in tarball: {}", e)))?;
 
-            let path_str = path.to_string_lossy();
+            let path_str = path.to_string_lossy().to_string();
 
             // Each package has a directory with desc and depends files
             if path_str.ends_with("/desc") {
                 let mut content = String::new();
-                entry.read_to_string(&mut content).map_err(|e| {
-                    Error::ParseError(format!("Failed to read desc file: {}", e))
-                })?;
+                entry
+                    .read_to_string(&mut content)
+                    .map_err(|e| Error::ParseError(format!("Failed to read desc file: {}", e)))?;
 
                 let desc_fields = self.parse_desc_file(&content);
 
@@ -212,40 +264,66 @@ impl RepositoryParser for ArchParser {
                     .ok_or_else(|| Error::ParseError("Missing %FILENAME% field".to_string()))?
                     .clone();
 
-                let checksum = desc_fields
-                    .get("SHA256SUM")
-                    .and_then(|v| v.first())
-                    .ok_or_else(|| Error::ParseError("Missing %SHA256SUM% field".to_string()))?
-                    .clone();
+                // Older databases carry only %MD5SUM%
+                let digest = |field: &str| {
+                    desc_fields
+                        .get(field)
+                        .and_then(|v| v.first())
+                        .map(String::as_str)
+                };
+                let (checksum_type, checksum) =
+                    ChecksumType::prefer(digest("SHA256SUM"), None, digest("MD5SUM")).ok_or_else(
+                        || Error::ParseError("Missing %SHA256SUM% field".to_string()),
+                    )?;
 
                 let size: u64 = desc_fields
                     .get("CSIZE")
                     .and_then(|v| v.first())
                     .and_then(|s| s.parse().ok())
-                    .ok_or_else(|| Error::ParseError("Missing or invalid %CSIZE% field".to_string()))?;
+                    .ok_or_else(|| {
+                        Error::ParseError("Missing or invalid %CSIZE% field".to_string())
+                    })?;
 
                 let architecture = desc_fields.get("ARCH").and_then(|v| v.first()).cloned();
 
                 let description = desc_fields.get("DESC").and_then(|v| v.first()).cloned();
 
                 // Build download URL
-                let download_url = format!("{}/{}", repo_url.trim_end_matches('/'), filename);
+                let download_url = resolve_location(repo_url, &filename);
 
                 // Build extra metadata
                 let mut extra = serde_json::Map::new();
                 if let Some(url) = desc_fields.get("URL").and_then(|v| v.first()) {
-                    extra.insert("homepage".to_string(), serde_json::Value::String(url.clone()));
+                    extra.insert(
+                        "homepage".to_string(),
+                        serde_json::Value::String(url.clone()),
+                    );
                 }
                 if let Some(license) = desc_fields.get("LICENSE").and_then(|v| v.first()) {
-                    extra.insert("license".to_string(), serde_json::Value::String(license.clone()));
+                    extra.insert(
+                        "license".to_string(),
+                        serde_json::Value::String(license.clone()),
+                    );
                 }
                 if let Some(builddate) = desc_fields.get("BUILDDATE").and_then(|v| v.first()) {
-                    extra.insert("builddate".to_string(), serde_json::Value::String(builddate.clone()));
+                    extra.insert(
+                        "builddate".to_string(),
+                        serde_json::Value::String(builddate.clone()),
+                    );
                 }
                 if let Some(isize) = desc_fields.get("ISIZE").and_then(|v| v.first()) {
-                    extra.insert("installed_size".to_string(), serde_json::Value::String(isize.clone()));
+                    extra.insert(
+                        "installed_size".to_string(),
+                        serde_json::Value::String(isize.clone()),
+                    );
                 }
-                extra.insert("format".to_string(), serde_json::Value::String("arch".to_string()));
+                extra.insert(
+                    "format".to_string(),
+                    serde_json::Value::String("arch".to_string()),
+                );
+
+                let mut extra_metadata = serde_json::Value::Object(extra);
+                self.add_relations(&mut extra_metadata, &desc_fields);
 
                 let package = PackageMetadata {
                     name,
@@ -253,56 +331,56 @@ impl RepositoryParser for ArchParser {
                     architecture,
                     description,
                     checksum,
-                    checksum_type: ChecksumType::Sha256,
+                    checksum_type,
                     size,
                     download_url,
-                    dependencies: Vec::new(), // Will be populated if depends file exists
-                    extra_metadata: serde_json::Value::Object(extra),
+                    dependencies: self.parse_dependencies(&desc_fields),
+                    provides: self.parse_provides(&desc_fields),
+                    files: Vec::new(),
+                    advisories: Vec::new(),
+                    extra_metadata,
                 };
 
+                by_dir.insert(package_dir(&path_str).to_string(), packages.len());
                 packages.push(package);
             }
         }
 
         // Second pass: parse depends files and update dependencies
         let mut archive = Archive::new(decompressed.as_slice());
-        let mut package_deps: HashMap<String, Vec<Dependency>> = HashMap::new();
 
         for entry in archive.entries()? {
-            let mut entry = entry.map_err(|e| {
-                Error::ParseError(format!("Failed to read tarball entry: {}", e))
-            })?;
+            let mut entry = entry
+                .map_err(|e| Error::ParseError(format!("Failed to read tarball entry: {}", e)))?;
 
             let path = entry
                 .path()
                 .map_err(|e| Error::ParseError(format!("Invalid path in tarball: {}", e)))?;
 
-            let path_str = path.to_string_lossy();
+            let path_str = path.to_string_lossy().to_string();
 
             if path_str.ends_with("/depends") {
-                // Extract package name from path (e.g., "bash-5.2.037-1/depends" -> "bash")
-                let pkg_name = path_str
-                    .split('/')
-                    .next()
-                    .and_then(|s| s.split('-').next())
-                    .unwrap_or("")
-                    .to_string();
+                // Matched by directory, since split package names contain dashes
+                let Some(&index) = by_dir.get(package_dir(&path_str)) else {
+                    continue;
+                };
 
                 let mut content = String::new();
                 entry.read_to_string(&mut content).map_err(|e| {
                     Error::ParseError(format!("Failed to read depends file: {}", e))
                 })?;
 
-                let deps = self.parse_depends_file(&content);
-                package_deps.insert(pkg_name, deps);
+                // Older databases keep dependencies, %PROVIDES%, %CONFLICTS% and %REPLACES% here rather than in desc
+                let fields = self.parse_desc_file(&content);
+                let pkg = &mut packages[index];
+                pkg.dependencies.extend(self.parse_dependencies(&fields));
+                pkg.provides.extend(self.parse_provides(&fields));
+                self.add_relations(&mut pkg.extra_metadata, &fields);
             }
         }
 
-        // Update packages with their dependencies
-        for pkg in &mut packages {
-            if let Some(deps) = package_deps.remove(&pkg.name) {
-                pkg.dependencies = deps;
-            }
+        if packages.iter().all(|pkg| pkg.provides.is_empty()) {
+            unversion_sonames(&self.repo_name, &mut packages);
         }
 
         info!("Parsed {} packages from Arch repository", packages.len());
@@ -310,6 +388,60 @@ impl RepositoryParser for ArchParser {
     }
 }
 
+/// Whether a dependency or provide name is a soname ("libfoo.so")
+///
+/// Arch packages need and provide shared libraries as "libfoo.so=3-64":
+/// the soname, its major version and the word size.
+pub fn is_soname(name: &str) -> bool {
+    name.len() > ".so".len() && name.ends_with(".so") && !name.contains(['/', '('])
+}
+
+/// Turn soname dependencies of a repository without provides into plain ones
+///
+/// Without %PROVIDES% data nothing in the repository can satisfy a soname
+/// capability, so the constraint is dropped and the name left to the
+/// resolver's usual lookup.
+fn unversion_sonames(repo_name: &str, packages: &mut [PackageMetadata]) {
+    let mut count = 0;
+    for dep in packages
+        .iter_mut()
+        .flat_map(|pkg| pkg.dependencies.iter_mut())
+    {
+        if dep.dep_type == DependencyType::Capability {
+            *dep = Dependency::runtime(std::mem::take(&mut dep.name));
+            count += 1;
+        }
+    }
+    if count > 0 {
+        warn!(
+            "Arch repository {} declares no provides; resolving {} soname dependencies by name",
+            repo_name, count
+        );
+    }
+}
+
+/// Package directory of a database entry path ("bash-5.2.037-1/desc" -> "bash-5.2.037-1")
+fn package_dir(path: &str) -> &str {
+    path.split('/').next().unwrap_or(path)
+}
+
+impl RepositoryParser for ArchParser {
+    fn index_url(&self, repo_url: &str) -> String {
+        format!("{}/{}.db", repo_url.trim_end_matches('/'), self.repo_name)
+    }
+
+    fn parse_index(
+        &self,
+        _client: &RepositoryClient,
+        repo_url: &str,
+        index: &[u8],
+        _progress: Option<&ProgressFn>,
+    ) -> Result<Vec<PackageMetadata>> {
+        info!("Parsing Arch Linux repository: {}", self.repo_name);
+        self.parse_database(index, repo_url)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -317,7 +449,8 @@ mod tests {
     #[test]
     fn test_parse_desc_file() {
         let parser = ArchParser::new("core".to_string());
-        let content = "%NAME%\nbash\n\n%VERSION%\n5.2.037-1\n\n%DESC%\nThe GNU Bourne Again shell\n";
+        let content =
+            "%NAME%\nbash\n\n%VERSION%\n5.2.037-1\n\n%DESC%\nThe GNU Bourne Again shell\n";
 
         let fields = parser.parse_desc_file(content);
 
@@ -341,4 +474,325 @@ mod tests {
         assert_eq!(name2, "readline");
         assert_eq!(constraint2, "");
     }
+
+    /// A tiny database with one package, as a plain tar
+    fn tiny_db_tar() -> Vec<u8> {
+        let desc = "%FILENAME%\nhello-1.0-1-x86_64.pkg.tar.zst\n\n%NAME%\nhello\n\n%VERSION%\n1.0-1\n\n\
+                    %CSIZE%\n1234\n\n%SHA256SUM%\nabc123\n\n%ARCH%\nx86_64\n";
+        let depends = "%DEPENDS%\nglibc\n\n%PROVIDES%\nhello-world=1.0\n";
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in [("hello-1.0-1/desc", desc), ("hello-1.0-1/depends", depends)] {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_parse_database_encodings() {
+        use std::io::Write;
+
+        let tar = tiny_db_tar();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        let gzip = gz.finish().unwrap();
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&tar).unwrap();
+        let xz = xz.finish().unwrap();
+
+        let zst = zstd::encode_all(tar.as_slice(), 3).unwrap();
+
+        let parser = ArchParser::new("core".to_string());
+        for (encoding, data) in [
+            (DbEncoding::Tar, &tar),
+            (DbEncoding::Gzip, &gzip),
+            (DbEncoding::Xz, &xz),
+            (DbEncoding::Zstd, &zst),
+        ] {
+            assert_eq!(DbEncoding::sniff(data), Some(encoding));
+
+            let packages = parser
+                .parse_database(data, "https://mirror.example/core/os/x86_64/")
+                .unwrap();
+            assert_eq!(packages.len(), 1, "{:?}", encoding);
+            assert_eq!(packages[0].name, "hello");
+            assert_eq!(packages[0].version, "1.0-1");
+            assert_eq!(
+                packages[0].download_url,
+                "https://mirror.example/core/os/x86_64/hello-1.0-1-x86_64.pkg.tar.zst"
+            );
+            assert_eq!(packages[0].dependencies.len(), 1);
+            assert_eq!(
+                packages[0].provides,
+                vec![Provide::new(
+                    "hello-world".to_string(),
+                    Some("1.0".to_string())
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn test_unrecognized_database_format() {
+        let parser = ArchParser::new("core".to_string());
+        let err = parser
+            .parse_database(b"<!DOCTYPE html>", "https://mirror.example")
+            .unwrap_err();
+
+        let message = err.to_string();
+        assert!(
+            message.contains("Unrecognized Arch database format"),
+            "{}",
+            message
+        );
+        assert!(message.contains("3c 21 44 4f 43 54 59 50"), "{}", message);
+    }
+
+    #[test]
+    fn test_parse_database_relations_and_split_packages() {
+        let entries = [
+            (
+                "openbsd-netcat-1.226_1-1/desc",
+                "%FILENAME%\nopenbsd-netcat-1.226_1-1-x86_64.pkg.tar.zst\n\n%NAME%\nopenbsd-netcat\n\n\
+                 %VERSION%\n1.226_1-1\n\n%CSIZE%\n100\n\n%SHA256SUM%\nabc\n\n\
+                 %PROVIDES%\nnetcat\n\n%CONFLICTS%\ngnu-netcat\n\n%REPLACES%\nnetcat-openbsd<1.200\n",
+            ),
+            (
+                "openbsd-netcat-1.226_1-1/depends",
+                "%DEPENDS%\nglibc\nlibbsd\n",
+            ),
+            (
+                "openbsd-netcat-doc-1.226_1-1/desc",
+                "%FILENAME%\nopenbsd-netcat-doc-1.226_1-1-any.pkg.tar.zst\n\n%NAME%\nopenbsd-netcat-doc\n\n\
+                 %VERSION%\n1.226_1-1\n\n%CSIZE%\n10\n\n%SHA256SUM%\ndef\n",
+            ),
+            (
+                "openbsd-netcat-doc-1.226_1-1/depends",
+                "%DEPENDS%\nman-db\n\n%CONFLICTS%\nnetcat-doc\n",
+            ),
+        ];
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in entries {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let parser = ArchParser::new("extra".to_string());
+        let packages = parser
+            .parse_database(&tar, "https://mirror.example/extra/os/x86_64")
+            .unwrap();
+        assert_eq!(packages.len(), 2);
+
+        // Split packages sharing a name prefix keep their own depends
+        let netcat = &packages[0];
+        let deps: Vec<&str> = netcat
+            .dependencies
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(deps, vec!["glibc", "libbsd"]);
+        assert_eq!(
+            netcat.provides,
+            vec![Provide::new("netcat".to_string(), None)]
+        );
+        assert_eq!(
+            netcat.extra_metadata["conflicts"],
+            serde_json::json!(["gnu-netcat"])
+        );
+        assert_eq!(
+            netcat.extra_metadata["replaces"],
+            serde_json::json!(["netcat-openbsd<1.200"])
+        );
+
+        let doc = &packages[1];
+        assert_eq!(doc.dependencies[0].name, "man-db");
+        assert_eq!(
+            doc.extra_metadata["conflicts"],
+            serde_json::json!(["netcat-doc"])
+        );
+        assert!(doc.extra_metadata.get("replaces").is_none());
+    }
+
+    #[test]
+    fn test_parse_database_soname_dependencies() {
+        let build = |entries: &[(&str, &str)]| {
+            let mut builder = tar::Builder::new(Vec::new());
+            for (path, content) in entries {
+                let mut header = tar::Header::new_ustar();
+                header.set_size(content.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder
+                    .append_data(&mut header, path, content.as_bytes())
+                    .unwrap();
+            }
+            builder.into_inner().unwrap()
+        };
+        let app_desc = "%FILENAME%\napp-1.0-1-x86_64.pkg.tar.zst\n\n%NAME%\napp\n\n%VERSION%\n1.0-1\n\n\
+                        %CSIZE%\n10\n\n%SHA256SUM%\nabc\n\n%DEPENDS%\nglibc\nlibfoo.so=3-64\n";
+        let libfoo_desc = "%FILENAME%\nlibfoo-3.1-1-x86_64.pkg.tar.zst\n\n%NAME%\nlibfoo\n\n%VERSION%\n3.1-1\n\n\
+                           %CSIZE%\n10\n\n%SHA256SUM%\ndef\n\n%PROVIDES%\nlibfoo.so=3-64\n";
+        let parser = ArchParser::new("extra".to_string());
+
+        let tar = build(&[
+            ("app-1.0-1/desc", app_desc),
+            ("libfoo-3.1-1/desc", libfoo_desc),
+        ]);
+        let packages = parser
+            .parse_database(&tar, "https://mirror.example/extra/os/x86_64")
+            .unwrap();
+        let app = packages.iter().find(|pkg| pkg.name == "app").unwrap();
+        assert_eq!(app.dependencies[0].dep_type, DependencyType::Runtime);
+        let soname = &app.dependencies[1];
+        assert_eq!(soname.name, "libfoo.so");
+        assert_eq!(soname.constraint.as_deref(), Some("=3-64"));
+        assert_eq!(soname.dep_type, DependencyType::Capability);
+        let libfoo = packages.iter().find(|pkg| pkg.name == "libfoo").unwrap();
+        assert_eq!(
+            libfoo.provides,
+            vec![Provide::new(
+                "libfoo.so".to_string(),
+                Some("3-64".to_string())
+            )]
+        );
+
+        // Without provides in the repository, sonames fall back to plain names
+        let packages = parser
+            .parse_database(
+                &build(&[("app-1.0-1/desc", app_desc)]),
+                "https://mirror.example",
+            )
+            .unwrap();
+        let soname = &packages[0].dependencies[1];
+        assert_eq!(soname.name, "libfoo.so");
+        assert_eq!(soname.constraint, None);
+        assert_eq!(soname.dep_type, DependencyType::Runtime);
+
+        assert!(is_soname("libfoo.so"));
+        assert!(!is_soname("libssl.so.3()(64bit)"));
+        assert!(!is_soname(".so"));
+    }
+
+    #[test]
+    fn test_package_locations() {
+        let desc = |name: &str, filename: &str| {
+            format!(
+                "%FILENAME%\n{filename}\n\n%NAME%\n{name}\n\n%VERSION%\n1.0-1\n\n%CSIZE%\n10\n\n%SHA256SUM%\nabc\n"
+            )
+        };
+        let entries = [
+            (
+                "relative-1.0-1/desc",
+                desc("relative", "relative-1:1.0-1-x86_64.pkg.tar.zst"),
+            ),
+            (
+                "same-host-1.0-1/desc",
+                desc(
+                    "same-host",
+                    "https://geo.mirror.pkgbuild.com/core/os/x86_64/same-host-1.0-1-any.pkg.tar.zst",
+                ),
+            ),
+            (
+                "cross-host-1.0-1/desc",
+                desc(
+                    "cross-host",
+                    "https://mirror.example.org/core/cross-host-1.0-1-any.pkg.tar.zst",
+                ),
+            ),
+        ];
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in &entries {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let parser = ArchParser::new("core".to_string());
+        let packages = parser
+            .parse_database(&tar, "https://geo.mirror.pkgbuild.com/core/os/x86_64")
+            .unwrap();
+        let url = |name: &str| {
+            packages
+                .iter()
+                .find(|pkg| pkg.name == name)
+                .unwrap()
+                .download_url
+                .clone()
+        };
+        assert_eq!(
+            url("relative"),
+            "https://geo.mirror.pkgbuild.com/core/os/x86_64/relative-1:1.0-1-x86_64.pkg.tar.zst"
+        );
+        assert_eq!(
+            url("same-host"),
+            "https://geo.mirror.pkgbuild.com/core/os/x86_64/same-host-1.0-1-any.pkg.tar.zst"
+        );
+        assert_eq!(
+            url("cross-host"),
+            "https://mirror.example.org/core/cross-host-1.0-1-any.pkg.tar.zst"
+        );
+    }
+
+    #[test]
+    fn test_checksum_prefers_sha256() {
+        let entries = [
+            (
+                "both-1.0-1/desc",
+                "%FILENAME%\nboth-1.0-1-any.pkg.tar.zst\n\n%NAME%\nboth\n\n%VERSION%\n1.0-1\n\n%CSIZE%\n10\n\n\
+                                 %MD5SUM%\nmd5\n\n%SHA256SUM%\nsha\n",
+            ),
+            (
+                "old-1.0-1/desc",
+                "%FILENAME%\nold-1.0-1-any.pkg.tar.zst\n\n%NAME%\nold\n\n%VERSION%\n1.0-1\n\n%CSIZE%\n10\n\n\
+                                %MD5SUM%\nmd5\n",
+            ),
+        ];
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in &entries {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let packages = ArchParser::new("core".to_string())
+            .parse_database(&tar, "https://mirror.example")
+            .unwrap();
+        let checksums: Vec<(&str, ChecksumType, &str)> = packages
+            .iter()
+            .map(|pkg| (pkg.name.as_str(), pkg.checksum_type, pkg.checksum.as_str()))
+            .collect();
+        assert_eq!(
+            checksums,
+            [
+                ("both", ChecksumType::Sha256, "sha"),
+                ("old", ChecksumType::Md5, "md5")
+            ]
+        );
+    }
 }
@@ -2,13 +2,17 @@
 
 //! Debian/Ubuntu repository metadata parser
 //!
-//! Parses Debian-style Packages.gz files which use RFC 822-like format
-//! (similar to email headers with key: value pairs).
+//! The distribution's Release file lists its components and architectures
+//! with the checksums of their package lists. Each Packages.gz uses an
+//! RFC 822-like format (similar to email headers with key: value pairs);
+//! the lists of every component and architecture synced are merged.
 
-use super::{ChecksumType, Dependency, PackageMetadata, RepositoryParser};
+use super::{ChecksumType, Dependency, PackageMetadata, Provide, RepositoryParser};
 use crate::error::{Error, Result};
+use crate::repository::{PackageSelector, ProgressFn, RepositoryClient, resolve_location};
 use flate2::read::GzDecoder;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::io::Read;
 use tracing::{debug, info};
 
@@ -16,52 +20,110 @@ use tracing::{debug, info};
 pub struct DebianParser {
     /// Distribution name (e.g., "noble", "jammy")
     distribution: String,
-    /// Component (e.g., "main", "universe")
-    component: String,
-    /// Architecture (e.g., "amd64", "arm64")
-    architecture: String,
+    /// Components (e.g., "main", "universe"); empty reads every one the
+    /// Release file lists
+    components: Vec<String>,
+    /// Architectures (e.g., "amd64", "arm64")
+    architectures: Vec<String>,
+}
+
+/// What a distribution's Release file says about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReleaseFile {
+    pub components: Vec<String>,
+    pub architectures: Vec<String>,
+    /// SHA-256 of each file under the distribution, by path relative to it
+    pub sha256: HashMap<String, String>,
+}
+
+impl ReleaseFile {
+    /// Parse the fields of a Release file this parser uses
+    pub fn parse(content: &str) -> Self {
+        let mut release = Self::default();
+        let mut in_sha256 = false;
+        for line in content.lines() {
+            if let Some(entry) = line.strip_prefix(' ') {
+                // Continuation lines of the checksum lists: "<hash> <size> <path>"
+                if in_sha256
+                    && let [hash, _size, path] = entry.split_whitespace().collect::<Vec<_>>()[..]
+                {
+                    release.sha256.insert(path.to_string(), hash.to_string());
+                }
+                continue;
+            }
+            in_sha256 = false;
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let words = || value.split_whitespace().map(str::to_string).collect();
+            match key {
+                "Components" => release.components = words(),
+                "Architectures" => release.architectures = words(),
+                "SHA256" => in_sha256 = true,
+                _ => {}
+            }
+        }
+        release
+    }
+}
+
+/// Debian's name for an architecture as uname or RPM report it
+pub fn debian_architecture(arch: &str) -> String {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "i386" | "i486" | "i586" | "i686" => "i386",
+        "armv7l" | "armv7hl" => "armhf",
+        "ppc64le" => "ppc64el",
+        other => other,
+    }
+    .to_string()
 }
 
 impl DebianParser {
     /// Create a new Debian/Ubuntu parser
-    pub fn new(distribution: String, component: String, architecture: String) -> Self {
+    ///
+    /// No `architectures` means the host's.
+    pub fn new(distribution: String, components: Vec<String>, architectures: Vec<String>) -> Self {
+        let architectures = if architectures.is_empty() {
+            vec![debian_architecture(&PackageSelector::detect_architecture())]
+        } else {
+            architectures
+        };
         Self {
             distribution,
-            component,
-            architecture,
+            components,
+            architectures,
         }
     }
 
-    /// Download and decompress the Packages file
-    fn download_packages_file(&self, repo_url: &str) -> Result<String> {
-        let packages_url = format!(
-            "{}/dists/{}/{}/binary-{}/Packages.gz",
-            repo_url.trim_end_matches('/'),
-            self.distribution,
-            self.component,
-            self.architecture
-        );
-
-        debug!("Downloading Debian Packages file from: {}", packages_url);
-
-        let response = reqwest::blocking::get(&packages_url).map_err(|e| {
-            Error::DownloadError(format!("Failed to download {}: {}", packages_url, e))
-        })?;
-
-        if !response.status().is_success() {
-            return Err(Error::DownloadError(format!(
-                "Failed to download {}: HTTP {}",
-                packages_url,
-                response.status()
-            )));
+    /// The components to read, checked against those the Release file lists
+    fn components(&self, release: &ReleaseFile) -> Result<Vec<String>> {
+        if self.components.is_empty() {
+            if release.components.is_empty() {
+                return Err(Error::ParseError(format!(
+                    "The Release file of {} lists no components; configure them with --component",
+                    self.distribution
+                )));
+            }
+            return Ok(release.components.clone());
         }
+        for component in &self.components {
+            if !release.components.is_empty() && !release.components.contains(component) {
+                return Err(Error::NotFoundError(format!(
+                    "Component '{}' is not in {} (it has {})",
+                    component,
+                    self.distribution,
+                    release.components.join(", ")
+                )));
+            }
+        }
+        Ok(self.components.clone())
+    }
 
-        let bytes = response
-            .bytes()
-            .map_err(|e| Error::DownloadError(format!("Failed to read response: {}", e)))?;
-
-        // Decompress gzip
-        let mut gz = GzDecoder::new(bytes.as_ref());
+    /// Decompress a downloaded Packages.gz
+    fn decompress_packages_file(&self, data: &[u8]) -> Result<String> {
+        let mut gz = GzDecoder::new(data);
         let mut decompressed = String::new();
         gz.read_to_string(&mut decompressed)
             .map_err(|e| Error::ParseError(format!("Failed to decompress Packages.gz: {}", e)))?;
@@ -94,6 +156,20 @@ impl DebianParser {
         dependencies
     }
 
+    /// Parse virtual packages from Provides field
+    /// Format: "mail-transport-agent, libfoo-abi (= 2)"
+    fn parse_provides(&self, provides_str: &str) -> Vec<Provide> {
+        provides_str
+            .split(',')
+            .filter_map(|provide| self.parse_dependency(provide.trim()))
+            .filter(|(name, _)| !name.is_empty())
+            .map(|(name, constraint)| {
+                let version = constraint.trim_start_matches('=').trim();
+                Provide::new(name, (!version.is_empty()).then(|| version.to_string()))
+            })
+            .collect()
+    }
+
     /// Parse a single dependency string
     /// Format: "package (>= 1.0)" or "package (= 1.0-1)" or "package"
     fn parse_dependency(&self, dep: &str) -> Option<(String, String)> {
@@ -109,43 +185,16 @@ impl DebianParser {
             Some((dep.trim().to_string(), String::new()))
         }
     }
-}
-
-/// Debian package entry structure for rfc822-like parsing
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct DebianPackageEntry {
-    package: String,
-    version: String,
-    architecture: String,
-    #[serde(default)]
-    description: Option<String>,
-    #[serde(rename = "SHA256")]
-    sha256: String,
-    size: String,
-    filename: String,
-    #[serde(default)]
-    depends: Option<String>,
-    #[serde(default)]
-    homepage: Option<String>,
-    #[serde(default)]
-    section: Option<String>,
-    #[serde(rename = "Installed-Size", default)]
-    installed_size: Option<String>,
-}
-
-impl RepositoryParser for DebianParser {
-    fn sync_metadata(&self, repo_url: &str) -> Result<Vec<PackageMetadata>> {
-        info!(
-            "Syncing Debian repository: {}/{}/{}",
-            self.distribution, self.component, self.architecture
-        );
-
-        // Download and decompress Packages file
-        let packages_content = self.download_packages_file(repo_url)?;
 
+    /// Parse one component's Packages file, tagging its packages with the component
+    pub(crate) fn parse_packages(
+        &self,
+        packages_content: &str,
+        repo_url: &str,
+        component: &str,
+    ) -> Result<Vec<PackageMetadata>> {
         // Parse RFC 822-like format
-        let entries: Vec<DebianPackageEntry> = rfc822_like::from_str(&packages_content)
+        let entries: Vec<DebianPackageEntry> = rfc822_like::from_str(packages_content)
             .map_err(|e| Error::ParseError(format!("Failed to parse Packages file: {}", e)))?;
 
         debug!("Parsed {} package entries", entries.len());
@@ -157,6 +206,17 @@ impl RepositoryParser for DebianParser {
                 .size
                 .parse()
                 .map_err(|e| Error::ParseError(format!("Invalid size '{}': {}", entry.size, e)))?;
+            let (checksum_type, checksum) = ChecksumType::prefer(
+                entry.sha256.as_deref(),
+                entry.sha512.as_deref(),
+                entry.md5sum.as_deref(),
+            )
+            .ok_or_else(|| {
+                Error::ParseError(format!(
+                    "Package {} has no SHA256, SHA512 or MD5sum field",
+                    entry.package
+                ))
+            })?;
 
             // Parse dependencies
             let dependencies = if let Some(deps) = &entry.depends {
@@ -164,9 +224,14 @@ impl RepositoryParser for DebianParser {
             } else {
                 Vec::new()
             };
+            let provides = entry
+                .provides
+                .as_deref()
+                .map(|provides| self.parse_provides(provides))
+                .unwrap_or_default();
 
             // Build download URL
-            let download_url = format!("{}/{}", repo_url.trim_end_matches('/'), entry.filename);
+            let download_url = resolve_location(repo_url, &entry.filename);
 
             // Build extra metadata
             let mut extra = serde_json::Map::new();
@@ -182,14 +247,17 @@ impl RepositoryParser for DebianParser {
                     serde_json::Value::String(installed_size),
                 );
             }
-            extra.insert("format".to_string(), serde_json::Value::String("deb".to_string()));
+            extra.insert(
+                "format".to_string(),
+                serde_json::Value::String("deb".to_string()),
+            );
             extra.insert(
                 "distribution".to_string(),
                 serde_json::Value::String(self.distribution.clone()),
             );
             extra.insert(
                 "component".to_string(),
-                serde_json::Value::String(self.component.clone()),
+                serde_json::Value::String(component.to_string()),
             );
 
             let package = PackageMetadata {
@@ -197,17 +265,128 @@ impl RepositoryParser for DebianParser {
                 version: entry.version,
                 architecture: Some(entry.architecture),
                 description: entry.description,
-                checksum: entry.sha256,
-                checksum_type: ChecksumType::Sha256,
+                checksum,
+                checksum_type,
                 size,
                 download_url,
                 dependencies,
+                provides,
+                files: Vec::new(),
+                advisories: Vec::new(),
                 extra_metadata: serde_json::Value::Object(extra),
             };
 
             packages.push(package);
         }
 
+        Ok(packages)
+    }
+}
+
+/// Debian package entry structure for rfc822-like parsing
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DebianPackageEntry {
+    package: String,
+    version: String,
+    architecture: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(rename = "SHA256", default)]
+    sha256: Option<String>,
+    #[serde(rename = "SHA512", default)]
+    sha512: Option<String>,
+    #[serde(rename = "MD5sum", default)]
+    md5sum: Option<String>,
+    size: String,
+    filename: String,
+    #[serde(default)]
+    depends: Option<String>,
+    #[serde(default)]
+    provides: Option<String>,
+    #[serde(default)]
+    homepage: Option<String>,
+    #[serde(default)]
+    section: Option<String>,
+    #[serde(rename = "Installed-Size", default)]
+    installed_size: Option<String>,
+}
+
+impl RepositoryParser for DebianParser {
+    fn index_url(&self, repo_url: &str) -> String {
+        format!(
+            "{}/dists/{}/Release",
+            repo_url.trim_end_matches('/'),
+            self.distribution
+        )
+    }
+
+    fn parse_index(
+        &self,
+        client: &RepositoryClient,
+        repo_url: &str,
+        index: &[u8],
+        progress: Option<&ProgressFn>,
+    ) -> Result<Vec<PackageMetadata>> {
+        let release = ReleaseFile::parse(&String::from_utf8_lossy(index));
+        let components = self.components(&release)?;
+        for architecture in &self.architectures {
+            if !release.architectures.is_empty() && !release.architectures.contains(architecture) {
+                return Err(Error::NotFoundError(format!(
+                    "Architecture '{}' is not in {} (it has {})",
+                    architecture,
+                    self.distribution,
+                    release.architectures.join(", ")
+                )));
+            }
+        }
+        info!(
+            "Parsing Debian repository: {} [{}] for {}",
+            self.distribution,
+            components.join(", "),
+            self.architectures.join(", ")
+        );
+
+        let mut packages = Vec::new();
+        // Architecture "all" packages are listed for every architecture
+        let mut seen = HashSet::new();
+        for component in &components {
+            for architecture in &self.architectures {
+                let path = format!("{}/binary-{}/Packages.gz", component, architecture);
+                let url = format!(
+                    "{}/dists/{}/{}",
+                    repo_url.trim_end_matches('/'),
+                    self.distribution,
+                    path
+                );
+                debug!("Downloading Packages from: {}", url);
+                let listing = client
+                    .fetch_index(&url, None, None, progress)?
+                    .ok_or_else(|| {
+                        Error::DownloadError(format!("Unexpected 304 Not Modified from {}", url))
+                    })?;
+                if let Some(expected) = release.sha256.get(&path)
+                    && !expected.eq_ignore_ascii_case(&listing.checksum)
+                {
+                    return Err(Error::ChecksumMismatch {
+                        expected: expected.clone(),
+                        actual: listing.checksum,
+                    });
+                }
+
+                let content = self.decompress_packages_file(&listing.body)?;
+                for package in self.parse_packages(&content, repo_url, component)? {
+                    if seen.insert((
+                        package.name.clone(),
+                        package.version.clone(),
+                        package.architecture.clone(),
+                    )) {
+                        packages.push(package);
+                    }
+                }
+            }
+        }
+
         info!("Parsed {} packages from Debian repository", packages.len());
         Ok(packages)
     }
@@ -219,7 +398,11 @@ mod tests {
 
     #[test]
     fn test_parse_dependency() {
-        let parser = DebianParser::new("noble".to_string(), "main".to_string(), "amd64".to_string());
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["main".to_string()],
+            vec!["amd64".to_string()],
+        );
 
         let (name, constraint) = parser.parse_dependency("libc6 (>= 2.34)").unwrap();
         assert_eq!(name, "libc6");
@@ -232,7 +415,11 @@ mod tests {
 
     #[test]
     fn test_parse_dependencies() {
-        let parser = DebianParser::new("noble".to_string(), "main".to_string(), "amd64".to_string());
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["main".to_string()],
+            vec!["amd64".to_string()],
+        );
 
         let deps = parser.parse_dependencies("libc6 (>= 2.34), bash (= 5.2-1), coreutils");
         assert_eq!(deps.len(), 3);
@@ -243,7 +430,11 @@ mod tests {
 
     #[test]
     fn test_parse_alternatives() {
-        let parser = DebianParser::new("noble".to_string(), "main".to_string(), "amd64".to_string());
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["main".to_string()],
+            vec!["amd64".to_string()],
+        );
 
         // Should take first alternative
         let deps = parser.parse_dependencies("package-a | package-b, other-package");
@@ -251,4 +442,321 @@ mod tests {
         assert_eq!(deps[0].name, "package-a");
         assert_eq!(deps[1].name, "other-package");
     }
+
+    #[test]
+    fn test_parse_provides() {
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["main".to_string()],
+            vec!["amd64".to_string()],
+        );
+
+        let provides = parser.parse_provides("mail-transport-agent, libfoo-abi (= 2.1-3)");
+        assert_eq!(
+            provides,
+            vec![
+                Provide::new("mail-transport-agent".to_string(), None),
+                Provide::new("libfoo-abi".to_string(), Some("2.1-3".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_package_locations() {
+        use std::io::Write;
+
+        let stanza = |name: &str, filename: &str| {
+            format!(
+                "Package: {name}\nVersion: 1.0-1\nArchitecture: amd64\nSHA256: abc\nSize: 10\nFilename: {filename}\n\n"
+            )
+        };
+        let packages = [
+            stanza("relative", "pool/main/r/relative/relative_1.0-1_amd64.deb"),
+            stanza(
+                "same-host",
+                "https://archive.ubuntu.com/ubuntu/pool/main/s/same-host_1.0-1_amd64.deb",
+            ),
+            stanza(
+                "cross-host",
+                "https://mirror.example.org/ubuntu/pool/main/c/cross-host_1.0-1_amd64.deb",
+            ),
+        ]
+        .concat();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(packages.as_bytes()).unwrap();
+        let index = gz.finish().unwrap();
+
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["main".to_string()],
+            vec!["amd64".to_string()],
+        );
+        let content = parser.decompress_packages_file(&index).unwrap();
+        let packages = parser
+            .parse_packages(&content, "http://archive.ubuntu.com/ubuntu", "main")
+            .unwrap();
+        let urls: Vec<&str> = packages
+            .iter()
+            .map(|pkg| pkg.download_url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://archive.ubuntu.com/ubuntu/pool/main/r/relative/relative_1.0-1_amd64.deb",
+                "https://archive.ubuntu.com/ubuntu/pool/main/s/same-host_1.0-1_amd64.deb",
+                "https://mirror.example.org/ubuntu/pool/main/c/cross-host_1.0-1_amd64.deb",
+            ]
+        );
+    }
+
+    /// Packages files of two components of Ubuntu noble, trimmed to two stanzas each
+    const MAIN_PACKAGES: &str = "Package: bash
+Architecture: amd64
+Version: 5.2.21-2ubuntu4
+Priority: required
+Essential: yes
+Section: shells
+Installed-Size: 1864
+Depends: base-files (>= 2.1.12), debianutils (>= 5.6-0.1)
+Filename: pool/main/b/bash/bash_5.2.21-2ubuntu4_amd64.deb
+Size: 794660
+SHA256: 1a9f1d7b3a2e2c1f4e9c3a27e7c2b1f0a0ad7e4b0e3d9c1a8f6b2e4d3c2b1a09
+Description: GNU Bourne Again SHell
+
+Package: tzdata
+Architecture: all
+Version: 2024a-2ubuntu1
+Priority: important
+Section: localization
+Installed-Size: 1373
+Provides: tzdata-bookworm
+Filename: pool/main/t/tzdata/tzdata_2024a-2ubuntu1_all.deb
+Size: 273244
+SHA256: 5c0a4e6f0e2d7b1a9c3e8f2d4b6a1c0e9f7d5b3a1c8e6f4d2b0a9c7e5f3d1b2a
+Description: time zone and daylight-saving time data
+
+";
+
+    const UNIVERSE_PACKAGES: &str = "Package: htop
+Architecture: amd64
+Version: 3.3.0-4build1
+Priority: optional
+Section: utils
+Installed-Size: 434
+Depends: libc6 (>= 2.38), libncursesw6 (>= 6), libnl-3-200 (>= 3.2.7)
+Filename: pool/main/h/htop/htop_3.3.0-4build1_amd64.deb
+Size: 171114
+SHA256: 9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b1a0f9e8d
+Description: interactive processes viewer
+
+Package: neofetch
+Architecture: all
+Version: 7.1.0-4
+Priority: optional
+Section: universe/utils
+Installed-Size: 358
+Filename: pool/universe/n/neofetch/neofetch_7.1.0-4_all.deb
+Size: 82460
+SHA256: 3f2e1d0c9b8a7f6e5d4c3b2a1f0e9d8c7b6a5f4e3d2c1b0a9f8e7d6c5b4a3f2e
+Description: Shows Linux System Information with Distribution Logo
+
+";
+
+    fn gzip(content: &str) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(content.as_bytes()).unwrap();
+        gz.finish().unwrap()
+    }
+
+    /// Serve `files` by path on localhost for `requests` requests
+    fn serve_files(
+        files: Vec<(String, Vec<u8>)>,
+        requests: usize,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ubuntu", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut paths = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                }
+
+                let path = request
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string();
+                match files.iter().find(|(served, _)| *served == path) {
+                    Some((_, body)) => {
+                        let head = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        );
+                        stream.write_all(head.as_bytes()).unwrap();
+                        stream.write_all(body).unwrap();
+                    }
+                    None => {
+                        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+                    }
+                }
+                paths.push(path);
+            }
+            paths
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_release_components() {
+        let main = gzip(MAIN_PACKAGES);
+        let universe = gzip(UNIVERSE_PACKAGES);
+        let release = format!(
+            "Origin: Ubuntu
+Suite: noble
+Codename: noble
+Architectures: amd64 i386
+Components: main universe
+Description: Ubuntu Noble 24.04
+MD5Sum:
+ 0f1e2d3c4b5a69788796a5b4c3d2e1f0 {} main/binary-amd64/Packages.gz
+SHA256:
+ {} {} main/binary-amd64/Packages.gz
+ {} {} universe/binary-amd64/Packages.gz
+",
+            main.len(),
+            crate::repository::sha256_hex(&main),
+            main.len(),
+            crate::repository::sha256_hex(&universe),
+            universe.len()
+        );
+        let parsed = ReleaseFile::parse(&release);
+        assert_eq!(parsed.components, ["main", "universe"]);
+        assert_eq!(parsed.architectures, ["amd64", "i386"]);
+        assert_eq!(parsed.sha256.len(), 2);
+
+        let (url, server) = serve_files(
+            vec![
+                (
+                    "/ubuntu/dists/noble/main/binary-amd64/Packages.gz".to_string(),
+                    main,
+                ),
+                (
+                    "/ubuntu/dists/noble/universe/binary-amd64/Packages.gz".to_string(),
+                    universe,
+                ),
+            ],
+            2,
+        );
+        let client = RepositoryClient::new().unwrap();
+
+        // No configured components reads every one the Release file lists
+        let parser = DebianParser::new("noble".to_string(), Vec::new(), vec!["amd64".to_string()]);
+        assert_eq!(
+            parser.index_url(&url),
+            format!("{}/dists/noble/Release", url)
+        );
+        let packages = parser
+            .parse_index(&client, &url, release.as_bytes(), None)
+            .unwrap();
+        let tagged: Vec<(&str, &str)> = packages
+            .iter()
+            .map(|pkg| {
+                (
+                    pkg.name.as_str(),
+                    pkg.extra_metadata["component"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tagged,
+            [
+                ("bash", "main"),
+                ("tzdata", "main"),
+                ("htop", "universe"),
+                ("neofetch", "universe")
+            ]
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "/ubuntu/dists/noble/main/binary-amd64/Packages.gz",
+                "/ubuntu/dists/noble/universe/binary-amd64/Packages.gz",
+            ]
+        );
+
+        // Components and architectures the Release file does not list are refused before fetching
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["multiverse".to_string()],
+            vec!["amd64".to_string()],
+        );
+        let err = parser
+            .parse_index(&client, &url, release.as_bytes(), None)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Component 'multiverse' is not in noble (it has main, universe)"),
+            "{}",
+            err
+        );
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["main".to_string()],
+            vec!["arm64".to_string()],
+        );
+        assert!(
+            parser
+                .parse_index(&client, &url, release.as_bytes(), None)
+                .is_err()
+        );
+        assert_eq!(debian_architecture("aarch64"), "arm64");
+    }
+
+    #[test]
+    fn test_checksum_prefers_sha256() {
+        let packages = "Package: all\nVersion: 1.0-1\nArchitecture: amd64\nMD5sum: md5\nSHA512: sha512\nSHA256: sha256\n\
+                        Size: 10\nFilename: pool/all.deb\n\n\
+                        Package: strong\nVersion: 1.0-1\nArchitecture: amd64\nMD5sum: md5\nSHA512: sha512\n\
+                        Size: 10\nFilename: pool/strong.deb\n\n\
+                        Package: legacy\nVersion: 1.0-1\nArchitecture: amd64\nMD5sum: md5\nSize: 10\nFilename: pool/legacy.deb\n\n";
+        let parser = DebianParser::new(
+            "noble".to_string(),
+            vec!["main".to_string()],
+            vec!["amd64".to_string()],
+        );
+        let parsed = parser
+            .parse_packages(packages, "http://archive.ubuntu.com/ubuntu", "main")
+            .unwrap();
+        let checksums: Vec<(&str, ChecksumType, &str)> = parsed
+            .iter()
+            .map(|pkg| (pkg.name.as_str(), pkg.checksum_type, pkg.checksum.as_str()))
+            .collect();
+        assert_eq!(
+            checksums,
+            [
+                ("all", ChecksumType::Sha256, "sha256"),
+                ("strong", ChecksumType::Sha512, "sha512"),
+                ("legacy", ChecksumType::Md5, "md5"),
+            ]
+        );
+
+        let unverifiable = "Package: none\nVersion: 1.0-1\nArchitecture: amd64\nSize: 10\nFilename: pool/none.deb\n\n";
+        let err = parser
+            .parse_packages(unverifiable, "http://archive.ubuntu.com/ubuntu", "main")
+            .unwrap_err();
+        assert!(err.to_string().contains("none has no SHA256"), "{}", err);
+    }
 }
@@ -2,16 +2,20 @@
 
 //! Fedora/RPM repository metadata parser
 //!
-//! Parses Fedora-style repomd.xml and primary.xml files which contain
-//! RPM package metadata in XML format.
+//! Parses Fedora-style repomd.xml and the files it points at: primary.xml
+//! with the RPM package metadata, filelists.xml with the full file list of
+//! every package, and updateinfo.xml with the advisories of updates.
 
-use super::{ChecksumType, Dependency, PackageMetadata, RepositoryParser};
+use super::{Advisory, ChecksumType, Dependency, PackageMetadata, Provide, RepositoryParser};
 use crate::error::{Error, Result};
+use crate::repository::{ProgressFn, RepositoryClient, resolve_location};
 use flate2::read::GzDecoder;
-use quick_xml::events::Event;
 use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashMap;
 use std::io::Read;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+use xz2::read::XzDecoder;
 
 /// Fedora/RPM repository parser
 pub struct FedoraParser {
@@ -25,129 +29,150 @@ impl FedoraParser {
         Self { architecture }
     }
 
-    /// Download repomd.xml and find primary.xml location
-    fn get_primary_xml_location(&self, repo_url: &str) -> Result<String> {
-        let repomd_url = format!("{}/repodata/repomd.xml", repo_url.trim_end_matches('/'));
-        debug!("Downloading repomd.xml from: {}", repomd_url);
+    /// Every `<data>` entry of repomd.xml, with the checksum of its file
+    pub fn data_locations(&self, repomd: &[u8]) -> Result<Vec<DataLocation>> {
+        let xml_content = std::str::from_utf8(repomd)
+            .map_err(|e| Error::ParseError(format!("Invalid UTF-8 in repomd.xml: {}", e)))?;
 
-        let response = reqwest::blocking::get(&repomd_url)
-            .map_err(|e| Error::DownloadError(format!("Failed to download {}: {}", repomd_url, e)))?;
-
-        if !response.status().is_success() {
-            return Err(Error::DownloadError(format!(
-                "Failed to download {}: HTTP {}",
-                repomd_url,
-                response.status()
-            )));
-        }
-
-        let xml_content = response
-            .text()
-            .map_err(|e| Error::DownloadError(format!("Failed to read repomd.xml: {}", e)))?;
-
-        // Parse repomd.xml to find primary location
-        let mut reader = Reader::from_str(&xml_content);
+        let mut reader = Reader::from_str(xml_content);
         reader.trim_text(true);
 
         let mut buf = Vec::new();
-        let mut in_primary = false;
-        let mut location = None;
+        let mut locations = Vec::new();
+        let mut current: Option<DataLocation> = None;
+        // Inside <checksum>, as opposed to <open-checksum> of the uncompressed file
+        let mut in_checksum = false;
 
         loop {
             match reader.read_event_into(&mut buf) {
                 Ok(Event::Start(e)) if e.name().as_ref() == b"data" => {
-                    // Check if this is the primary data type
-                    if let Some(attr) = e.attributes().find(|a| {
-                        a.as_ref()
-                            .map(|attr| attr.key.as_ref() == b"type")
-                            .unwrap_or(false)
-                    }) {
-                        if let Ok(attr) = attr {
-                            if attr.value.as_ref() == b"primary" {
-                                in_primary = true;
-                            }
-                        }
+                    current = attribute(&e, b"type").map(|data_type| DataLocation {
+                        data_type,
+                        href: String::new(),
+                        checksum: None,
+                        checksum_type: None,
+                    });
+                }
+                Ok(Event::Start(e)) if e.name().as_ref() == b"checksum" => {
+                    if let Some(ref mut data) = current {
+                        data.checksum_type = attribute(&e, b"type");
+                        in_checksum = true;
                     }
                 }
-                Ok(Event::Start(e) | Event::Empty(e)) if e.name().as_ref() == b"location" && in_primary => {
-                    // Extract href attribute
-                    if let Some(attr) = e.attributes().find(|a| {
-                        a.as_ref()
-                            .map(|attr| attr.key.as_ref() == b"href")
-                            .unwrap_or(false)
-                    }) {
-                        if let Ok(attr) = attr {
-                            location = Some(
-                                String::from_utf8_lossy(attr.value.as_ref()).to_string(),
-                            );
-                        }
+                Ok(Event::Start(e) | Event::Empty(e)) if e.name().as_ref() == b"location" => {
+                    if let Some(ref mut data) = current
+                        && let Some(href) = attribute(&e, b"href")
+                    {
+                        data.href = href;
+                    }
+                }
+                Ok(Event::Text(e)) if in_checksum => {
+                    if let Some(ref mut data) = current {
+                        data.checksum = Some(e.unescape().unwrap_or_default().trim().to_string());
                     }
                 }
+                Ok(Event::End(e)) if e.name().as_ref() == b"checksum" => {
+                    in_checksum = false;
+                }
                 Ok(Event::End(e)) if e.name().as_ref() == b"data" => {
-                    in_primary = false;
+                    if let Some(data) = current.take()
+                        && !data.href.is_empty()
+                    {
+                        locations.push(data);
+                    }
                 }
                 Ok(Event::Eof) => break,
                 Err(e) => {
                     return Err(Error::ParseError(format!(
                         "Failed to parse repomd.xml: {}",
                         e
-                    )))
+                    )));
                 }
                 _ => {}
             }
             buf.clear();
         }
 
-        location.ok_or_else(|| {
-            Error::ParseError("Could not find primary data location in repomd.xml".to_string())
-        })
+        Ok(locations)
     }
 
-    /// Download and decompress primary.xml
-    fn download_primary_xml(&self, repo_url: &str, location: &str) -> Result<String> {
-        let primary_url = format!("{}/{}", repo_url.trim_end_matches('/'), location);
-        debug!("Downloading primary.xml from: {}", primary_url);
+    /// Download, verify and decompress one of the files repomd.xml lists
+    ///
+    /// A SHA-256 checksum from repomd.xml is checked against the downloaded
+    /// file; other checksum types are not verified.
+    fn download_data(
+        &self,
+        client: &RepositoryClient,
+        repo_url: &str,
+        data: &DataLocation,
+        progress: Option<&ProgressFn>,
+    ) -> Result<String> {
+        let url = resolve_location(repo_url, &data.href);
+        debug!("Downloading {} metadata from: {}", data.data_type, url);
 
-        let response = reqwest::blocking::get(&primary_url).map_err(|e| {
-            Error::DownloadError(format!("Failed to download {}: {}", primary_url, e))
-        })?;
+        let index = client
+            .fetch_index(&url, None, None, progress)?
+            .ok_or_else(|| {
+                Error::DownloadError(format!("Unexpected 304 Not Modified from {}", url))
+            })?;
 
-        if !response.status().is_success() {
-            return Err(Error::DownloadError(format!(
-                "Failed to download {}: HTTP {}",
-                primary_url,
-                response.status()
-            )));
+        match (data.checksum_type.as_deref(), &data.checksum) {
+            (Some("sha256"), Some(expected)) if !expected.eq_ignore_ascii_case(&index.checksum) => {
+                return Err(Error::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual: index.checksum,
+                });
+            }
+            (Some("sha256"), Some(_)) => {}
+            (checksum_type, _) => {
+                debug!(
+                    "Not verifying {} metadata with checksum type {:?}",
+                    data.data_type, checksum_type
+                );
+            }
         }
 
-        let bytes = response
-            .bytes()
-            .map_err(|e| Error::DownloadError(format!("Failed to read response: {}", e)))?;
-
-        // Detect compression format from location extension
-        let decompressed = if location.ends_with(".zst") {
-            // Decompress zstd
-            debug!("Decompressing zstd-compressed primary.xml");
-            let decompressed_bytes = zstd::decode_all(bytes.as_ref())
-                .map_err(|e| Error::ParseError(format!("Failed to decompress primary.xml.zst: {}", e)))?;
-            String::from_utf8(decompressed_bytes)
-                .map_err(|e| Error::ParseError(format!("Invalid UTF-8 in primary.xml: {}", e)))?
-        } else {
-            // Try gzip decompression (default)
-            debug!("Decompressing gzip-compressed primary.xml");
-            let mut gz = GzDecoder::new(bytes.as_ref());
-            let mut decompressed = String::new();
-            gz.read_to_string(&mut decompressed)
-                .map_err(|e| Error::ParseError(format!("Failed to decompress primary.xml.gz: {}", e)))?;
+        let bytes = index.body;
+        let decompressed = if data.href.ends_with(".zst") {
+            zstd::decode_all(bytes.as_slice()).map_err(|e| {
+                Error::ParseError(format!("Failed to decompress {}: {}", data.href, e))
+            })?
+        } else if data.href.ends_with(".gz") {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(bytes.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(|e| {
+                    Error::ParseError(format!("Failed to decompress {}: {}", data.href, e))
+                })?;
+            decompressed
+        } else if data.href.ends_with(".xz") {
+            let mut decompressed = Vec::new();
+            XzDecoder::new(bytes.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(|e| {
+                    Error::ParseError(format!("Failed to decompress {}: {}", data.href, e))
+                })?;
             decompressed
+        } else {
+            bytes
         };
 
-        debug!("Decompressed primary.xml: {} bytes", decompressed.len());
-        Ok(decompressed)
+        let xml = String::from_utf8(decompressed)
+            .map_err(|e| Error::ParseError(format!("Invalid UTF-8 in {}: {}", data.href, e)))?;
+        debug!(
+            "Decompressed {} metadata: {} bytes",
+            data.data_type,
+            xml.len()
+        );
+        Ok(xml)
     }
 
     /// Parse primary.xml and extract package metadata
-    fn parse_primary_xml(&self, xml_content: &str, base_url: &str) -> Result<Vec<PackageMetadata>> {
+    pub(crate) fn parse_primary_xml(
+        &self,
+        xml_content: &str,
+        base_url: &str,
+    ) -> Result<Vec<PackageMetadata>> {
         let mut reader = Reader::from_str(xml_content);
         reader.trim_text(true);
 
@@ -158,6 +183,9 @@ impl FedoraParser {
         let mut current_package: Option<PackageBuilder> = None;
         let mut current_tag = String::new();
         let mut in_format = false;
+        // Only entries of <rpm:requires> and <rpm:provides> are recorded;
+        // conflicts, obsoletes and weak dependencies are skipped
+        let mut section: Option<EntrySection> = None;
 
         loop {
             match reader.read_event_into(&mut buf) {
@@ -172,6 +200,8 @@ impl FedoraParser {
                         "format" => {
                             in_format = true;
                         }
+                        "rpm:requires" => section = Some(EntrySection::Requires),
+                        "rpm:provides" => section = Some(EntrySection::Provides),
                         _ => {}
                     }
                 }
@@ -220,9 +250,12 @@ impl FedoraParser {
                             if let Some(ref mut pkg) = current_package {
                                 for attr in e.attributes().filter_map(|a| a.ok()) {
                                     let key = String::from_utf8_lossy(attr.key.as_ref());
-                                    if key.as_ref() == "href" {
-                                        let value = String::from_utf8_lossy(&attr.value);
-                                        pkg.location = Some(value.to_string());
+                                    let value = String::from_utf8_lossy(&attr.value);
+                                    match key.as_ref() {
+                                        "href" => pkg.location = Some(value.to_string()),
+                                        // A mirror the href is relative to instead of the repository
+                                        "xml:base" => pkg.location_base = Some(value.to_string()),
+                                        _ => {}
                                     }
                                 }
                             }
@@ -230,12 +263,13 @@ impl FedoraParser {
                         "format" => {
                             in_format = true;
                         }
-                        "entry" if in_format => {
-                            // This is a dependency entry within <rpm:requires>
+                        "entry" | "rpm:entry" if in_format && section.is_some() => {
+                            // A dependency entry within <rpm:requires> or a capability within <rpm:provides>
                             if let Some(ref mut pkg) = current_package {
                                 let mut dep_name = None;
                                 let mut dep_flags = None;
                                 let mut dep_ver = None;
+                                let mut dep_rel = None;
 
                                 for attr in e.attributes().filter_map(|a| a.ok()) {
                                     let key = String::from_utf8_lossy(attr.key.as_ref());
@@ -244,13 +278,23 @@ impl FedoraParser {
                                         "name" => dep_name = Some(value.to_string()),
                                         "flags" => dep_flags = Some(value.to_string()),
                                         "ver" => dep_ver = Some(value.to_string()),
+                                        "rel" => dep_rel = Some(value.to_string()),
                                         _ => {}
                                     }
                                 }
 
-                                if let Some(name) = dep_name {
-                                    // Skip rpmlib and file dependencies
-                                    if !name.starts_with("rpmlib(") && !name.starts_with('/') {
+                                if section == Some(EntrySection::Provides) {
+                                    if let Some(name) = dep_name {
+                                        let version = dep_ver.map(|ver| match dep_rel {
+                                            Some(rel) => format!("{}-{}", ver, rel),
+                                            None => ver,
+                                        });
+                                        pkg.provides.push(Provide::new(name, version));
+                                    }
+                                } else if let Some(name) = dep_name {
+                                    // Skip rpmlib dependencies; file dependencies are
+                                    // resolved against package file lists
+                                    if !name.starts_with("rpmlib(") {
                                         let constraint = match (dep_flags, dep_ver) {
                                             (Some(flags), Some(ver)) => {
                                                 let op = match flags.as_str() {
@@ -287,6 +331,7 @@ impl FedoraParser {
                             "description" => pkg.description = Some(text),
                             "checksum" => pkg.checksum = Some(text),
                             "url" => pkg.url = Some(text),
+                            "file" if in_format && is_primary_file(&text) => pkg.files.push(text),
                             _ => {}
                         }
                     }
@@ -295,17 +340,23 @@ impl FedoraParser {
                     let tag_name = String::from_utf8_lossy(e.name().as_ref()).to_string();
                     if tag_name == "package" {
                         if let Some(builder) = current_package.take() {
-                            if let Ok(pkg) = builder.build(base_url) {
-                                packages.push(pkg);
+                            match builder.build(base_url) {
+                                Ok(pkg) => packages.push(pkg),
+                                Err(e) => warn!("Skipping package in primary.xml: {}", e),
                             }
                         }
                     } else if tag_name == "format" {
                         in_format = false;
+                    } else if tag_name == "rpm:requires" || tag_name == "rpm:provides" {
+                        section = None;
                     }
                 }
                 Ok(Event::Eof) => break,
                 Err(e) => {
-                    return Err(Error::ParseError(format!("Failed to parse primary.xml: {}", e)))
+                    return Err(Error::ParseError(format!(
+                        "Failed to parse primary.xml: {}",
+                        e
+                    )));
                 }
                 _ => {}
             }
@@ -314,10 +365,278 @@ impl FedoraParser {
 
         Ok(packages)
     }
+
+    /// Replace the file lists of `packages` with the full lists from filelists.xml
+    ///
+    /// Entries are matched to packages by name, architecture and version;
+    /// directories are left out. Returns the number of packages matched.
+    pub(crate) fn apply_filelists_xml(
+        &self,
+        xml_content: &str,
+        packages: &mut [PackageMetadata],
+    ) -> Result<usize> {
+        let index = package_index(packages);
+        let mut reader = Reader::from_str(xml_content);
+        reader.trim_text(true);
+
+        let mut buf = Vec::new();
+        let mut matched = 0;
+        // (name, arch) of the current <package>, then the package it matched
+        let mut current: Option<(String, String)> = None;
+        let mut target: Option<usize> = None;
+        let mut files = Vec::new();
+        let mut in_file = false;
+
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(e)) if e.name().as_ref() == b"package" => {
+                    current = Some((
+                        attribute(&e, b"name").unwrap_or_default(),
+                        attribute(&e, b"arch").unwrap_or_default(),
+                    ));
+                    target = None;
+                    files.clear();
+                }
+                Ok(Event::Start(e) | Event::Empty(e)) if e.name().as_ref() == b"version" => {
+                    if let Some((name, arch)) = &current {
+                        let version = evr(
+                            attribute(&e, b"epoch").as_deref(),
+                            &attribute(&e, b"ver").unwrap_or_default(),
+                            &attribute(&e, b"rel").unwrap_or_default(),
+                        );
+                        target = index.get(&(name.clone(), arch.clone(), version)).copied();
+                    }
+                }
+                Ok(Event::Start(e)) if e.name().as_ref() == b"file" => {
+                    in_file = attribute(&e, b"type").as_deref() != Some("dir");
+                }
+                Ok(Event::Text(e)) if in_file && target.is_some() => {
+                    files.push(e.unescape().unwrap_or_default().to_string());
+                }
+                Ok(Event::End(e)) if e.name().as_ref() == b"file" => {
+                    in_file = false;
+                }
+                Ok(Event::End(e)) if e.name().as_ref() == b"package" => {
+                    if let Some(i) = target.take() {
+                        packages[i].files = std::mem::take(&mut files);
+                        matched += 1;
+                    }
+                    current = None;
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    return Err(Error::ParseError(format!(
+                        "Failed to parse filelists.xml: {}",
+                        e
+                    )));
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(matched)
+    }
+
+    /// Attach the advisories of updateinfo.xml to the package versions they cover
+    ///
+    /// CVE identifiers come from `cve` references and from the titles of
+    /// other references, as Fedora only links the bugzilla entries.
+    /// Returns the number of advisories that covered a package in `packages`.
+    pub(crate) fn apply_updateinfo_xml(
+        &self,
+        xml_content: &str,
+        packages: &mut [PackageMetadata],
+    ) -> Result<usize> {
+        let index = package_index(packages);
+        let mut reader = Reader::from_str(xml_content);
+        reader.trim_text(true);
+
+        let mut buf = Vec::new();
+        let mut matched = 0;
+        let mut current: Option<Advisory> = None;
+        let mut covered: Vec<usize> = Vec::new();
+        let mut current_tag = String::new();
+
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(e)) => {
+                    current_tag = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                    match current_tag.as_str() {
+                        "update" => {
+                            current = Some(Advisory {
+                                id: String::new(),
+                                kind: attribute(&e, b"type")
+                                    .unwrap_or_else(|| "bugfix".to_string()),
+                                severity: None,
+                                cves: Vec::new(),
+                            });
+                            covered.clear();
+                        }
+                        "reference" => reference_cves(&e, current.as_mut()),
+                        "package" => covered.extend(covered_package(&e, &index)),
+                        _ => {}
+                    }
+                }
+                Ok(Event::Empty(e)) => match e.name().as_ref() {
+                    b"reference" => reference_cves(&e, current.as_mut()),
+                    b"package" => covered.extend(covered_package(&e, &index)),
+                    _ => {}
+                },
+                Ok(Event::Text(e)) => {
+                    if let Some(ref mut advisory) = current {
+                        let text = e.unescape().unwrap_or_default().trim().to_string();
+                        match current_tag.as_str() {
+                            "id" => advisory.id = text,
+                            "severity" if !text.is_empty() && text != "None" => {
+                                advisory.severity = Some(text)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Ok(Event::End(e)) => {
+                    current_tag.clear();
+                    if e.name().as_ref() == b"update"
+                        && let Some(advisory) = current.take()
+                        && !advisory.id.is_empty()
+                        && !covered.is_empty()
+                    {
+                        for &i in &covered {
+                            if !packages[i]
+                                .advisories
+                                .iter()
+                                .any(|known| known.id == advisory.id)
+                            {
+                                packages[i].advisories.push(advisory.clone());
+                            }
+                        }
+                        matched += 1;
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    return Err(Error::ParseError(format!(
+                        "Failed to parse updateinfo.xml: {}",
+                        e
+                    )));
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(matched)
+    }
+}
+
+/// One `<data>` entry of repomd.xml
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataLocation {
+    /// Kind of data, e.g. "primary", "filelists" or "updateinfo"
+    pub data_type: String,
+    /// Location of the file, relative to the repository
+    pub href: String,
+    /// Checksum of the file as published, usually compressed
+    pub checksum: Option<String>,
+    /// Algorithm of `checksum`, e.g. "sha256"
+    pub checksum_type: Option<String>,
+}
+
+/// Value of the attribute `key`, if present
+fn attribute(element: &BytesStart, key: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .filter_map(|a| a.ok())
+        .find(|attr| attr.key.as_ref() == key)
+        .map(|attr| String::from_utf8_lossy(&attr.value).to_string())
+}
+
+/// Version string of an epoch, version and release, as packages are stored
+fn evr(epoch: Option<&str>, ver: &str, rel: &str) -> String {
+    match epoch {
+        Some(epoch) if epoch != "0" && !epoch.is_empty() => format!("{}:{}-{}", epoch, ver, rel),
+        _ => format!("{}-{}", ver, rel),
+    }
+}
+
+/// Position of each package by (name, architecture, version)
+fn package_index(packages: &[PackageMetadata]) -> HashMap<(String, String, String), usize> {
+    packages
+        .iter()
+        .enumerate()
+        .map(|(i, pkg)| {
+            (
+                (
+                    pkg.name.clone(),
+                    pkg.architecture.clone().unwrap_or_default(),
+                    pkg.version.clone(),
+                ),
+                i,
+            )
+        })
+        .collect()
+}
+
+/// The package an updateinfo.xml `<package>` element names, if it is in the index
+fn covered_package(
+    element: &BytesStart,
+    index: &HashMap<(String, String, String), usize>,
+) -> Option<usize> {
+    let version = evr(
+        attribute(element, b"epoch").as_deref(),
+        &attribute(element, b"version")?,
+        &attribute(element, b"release")?,
+    );
+    index
+        .get(&(
+            attribute(element, b"name")?,
+            attribute(element, b"arch")?,
+            version,
+        ))
+        .copied()
+}
+
+/// Add the CVE identifiers an updateinfo.xml `<reference>` names to `advisory`
+fn reference_cves(element: &BytesStart, advisory: Option<&mut Advisory>) {
+    let Some(advisory) = advisory else {
+        return;
+    };
+    let mut found = Vec::new();
+    if attribute(element, b"type").as_deref() == Some("cve")
+        && let Some(id) = attribute(element, b"id")
+    {
+        found.push(id);
+    }
+    if let Some(title) = attribute(element, b"title") {
+        found.extend(
+            title
+                .split(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+                .filter(|word| word.starts_with("CVE-") && word.len() > 4)
+                .map(str::to_string),
+        );
+    }
+    for cve in found {
+        if !advisory.cves.contains(&cve) {
+            advisory.cves.push(cve);
+        }
+    }
+}
+
+/// Whether primary.xml would list `path` among a package's files
+///
+/// createrepo only puts files under these directories (plus anything
+/// matching a file dependency) in primary.xml; the full list lives in
+/// filelists.xml, which replaces it when the repository publishes one.
+pub fn is_primary_file(path: &str) -> bool {
+    ["/bin/", "/sbin/", "/usr/bin/", "/usr/sbin/", "/etc/"]
+        .iter()
+        .any(|dir| path.starts_with(dir))
+        || path == "/usr/lib/sendmail"
 }
 
 /// Builder for constructing PackageMetadata from XML parsing
-#[derive(Default)]
+#[derive(Clone, Default)]
 struct PackageBuilder {
     name: Option<String>,
     epoch: Option<String>,
@@ -330,8 +649,18 @@ struct PackageBuilder {
     checksum_type: Option<String>,
     size: Option<String>,
     location: Option<String>,
+    location_base: Option<String>,
     url: Option<String>,
     dependencies: Vec<(String, String)>,
+    provides: Vec<Provide>,
+    files: Vec<String>,
+}
+
+/// Dependency section of a package's <format> block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntrySection {
+    Requires,
+    Provides,
 }
 
 impl PackageBuilder {
@@ -352,11 +681,7 @@ impl PackageBuilder {
         let rel = self
             .rel
             .ok_or_else(|| Error::ParseError("Missing release".to_string()))?;
-        let version = if epoch == "0" {
-            format!("{}-{}", ver, rel)
-        } else {
-            format!("{}:{}-{}", epoch, ver, rel)
-        };
+        let version = evr(Some(&epoch), &ver, &rel);
 
         let checksum = self
             .checksum
@@ -372,12 +697,15 @@ impl PackageBuilder {
             .location
             .ok_or_else(|| Error::ParseError("Missing location".to_string()))?;
 
-        let download_url = format!("{}/{}", base_url.trim_end_matches('/'), location);
+        let download_url =
+            resolve_location(self.location_base.as_deref().unwrap_or(base_url), &location);
 
+        // A checksum in an algorithm that cannot be verified would fail every download
         let checksum_type = match self.checksum_type.as_deref() {
-            Some("sha256") => ChecksumType::Sha256,
-            Some("sha512") => ChecksumType::Sha512,
-            _ => ChecksumType::Sha256, // Default
+            None => ChecksumType::Sha256,
+            Some(kind) => kind
+                .parse()
+                .map_err(|e| Error::ParseError(format!("{} of package {}", e, name)))?,
         };
 
         // Convert dependencies
@@ -401,7 +729,10 @@ impl PackageBuilder {
         if let Some(summary) = self.summary {
             extra.insert("summary".to_string(), serde_json::Value::String(summary));
         }
-        extra.insert("format".to_string(), serde_json::Value::String("rpm".to_string()));
+        extra.insert(
+            "format".to_string(),
+            serde_json::Value::String("rpm".to_string()),
+        );
         extra.insert("epoch".to_string(), serde_json::Value::String(epoch));
 
         Ok(PackageMetadata {
@@ -414,23 +745,63 @@ impl PackageBuilder {
             size,
             download_url,
             dependencies,
+            provides: self.provides,
+            files: self.files,
+            advisories: Vec::new(),
             extra_metadata: serde_json::Value::Object(extra),
         })
     }
 }
 
 impl RepositoryParser for FedoraParser {
-    fn sync_metadata(&self, repo_url: &str) -> Result<Vec<PackageMetadata>> {
-        info!("Syncing Fedora repository for {}", self.architecture);
+    fn index_url(&self, repo_url: &str) -> String {
+        format!("{}/repodata/repomd.xml", repo_url.trim_end_matches('/'))
+    }
 
-        // Get primary.xml location from repomd.xml
-        let primary_location = self.get_primary_xml_location(repo_url)?;
+    fn parse_index(
+        &self,
+        client: &RepositoryClient,
+        repo_url: &str,
+        index: &[u8],
+        progress: Option<&ProgressFn>,
+    ) -> Result<Vec<PackageMetadata>> {
+        info!("Parsing Fedora repository for {}", self.architecture);
 
-        // Download and decompress primary.xml
-        let primary_xml = self.download_primary_xml(repo_url, &primary_location)?;
+        let locations = self.data_locations(index)?;
+        let location = |data_type: &str| locations.iter().find(|data| data.data_type == data_type);
 
-        // Parse primary.xml
-        let packages = self.parse_primary_xml(&primary_xml, repo_url)?;
+        let primary = location("primary").ok_or_else(|| {
+            Error::ParseError("Could not find primary data location in repomd.xml".to_string())
+        })?;
+        let primary_xml = self.download_data(client, repo_url, primary, progress)?;
+        let mut packages = self.parse_primary_xml(&primary_xml, repo_url)?;
+
+        // The package list stands without these, so a broken one only costs
+        // file dependencies or advisories
+        if let Some(filelists) = location("filelists") {
+            match self
+                .download_data(client, repo_url, filelists, progress)
+                .and_then(|xml| self.apply_filelists_xml(&xml, &mut packages))
+            {
+                Ok(matched) => debug!("Read file lists of {} packages", matched),
+                Err(e) => warn!(
+                    "Could not read filelists.xml, only primary files are indexed: {}",
+                    e
+                ),
+            }
+        }
+        if let Some(updateinfo) = location("updateinfo") {
+            match self
+                .download_data(client, repo_url, updateinfo, progress)
+                .and_then(|xml| self.apply_updateinfo_xml(&xml, &mut packages))
+            {
+                Ok(matched) => debug!("Read {} advisories covering repository packages", matched),
+                Err(e) => warn!(
+                    "Could not read updateinfo.xml, advisories are not indexed: {}",
+                    e
+                ),
+            }
+        }
 
         info!("Parsed {} packages from Fedora repository", packages.len());
         Ok(packages)
@@ -454,9 +825,380 @@ mod tests {
         builder.size = Some("1024".to_string());
         builder.location = Some("Packages/t/test-package-2.3.4-5.fc43.x86_64.rpm".to_string());
 
-        let pkg = builder.build("https://example.com").unwrap();
+        let pkg = builder.clone().build("https://example.com").unwrap();
         assert_eq!(pkg.name, "test-package");
         assert_eq!(pkg.version, "1:2.3.4-5.fc43");
         assert_eq!(pkg.size, 1024);
+        assert_eq!(pkg.checksum_type, ChecksumType::Sha256);
+
+        builder.checksum_type = Some("sha512".to_string());
+        assert_eq!(
+            builder
+                .clone()
+                .build("https://example.com")
+                .unwrap()
+                .checksum_type,
+            ChecksumType::Sha512
+        );
+        builder.checksum_type = Some("md5".to_string());
+        assert_eq!(
+            builder
+                .clone()
+                .build("https://example.com")
+                .unwrap()
+                .checksum_type,
+            ChecksumType::Md5
+        );
+        // Not something a download could be checked against
+        builder.checksum_type = Some("sha1".to_string());
+        let err = builder
+            .build("https://example.com")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("sha1") && err.contains("test-package"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_parse_primary_xml_sections() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" xmlns:rpm="http://linux.duke.edu/metadata/rpm" packages="1">
+<package type="rpm">
+  <name>openssl-libs</name>
+  <arch>x86_64</arch>
+  <version epoch="1" ver="3.2.2" rel="3.fc41"/>
+  <checksum type="sha256" pkgid="YES">abc123</checksum>
+  <size package="2048" installed="8192" archive="8300"/>
+  <location href="Packages/o/openssl-libs-3.2.2-3.fc41.x86_64.rpm"/>
+  <format>
+    <rpm:provides>
+      <rpm:entry name="libssl.so.3()(64bit)"/>
+      <rpm:entry name="openssl-libs" flags="EQ" epoch="1" ver="3.2.2" rel="3.fc41"/>
+    </rpm:provides>
+    <rpm:requires>
+      <rpm:entry name="glibc" flags="GE" ver="2.34"/>
+      <rpm:entry name="rpmlib(CompressedFileNames)"/>
+    </rpm:requires>
+    <rpm:conflicts>
+      <rpm:entry name="openssl-old"/>
+    </rpm:conflicts>
+  </format>
+</package>
+</metadata>"#;
+
+        let parser = FedoraParser::new("x86_64".to_string());
+        let packages = parser
+            .parse_primary_xml(xml, "https://example.com")
+            .unwrap();
+        assert_eq!(packages.len(), 1);
+
+        let pkg = &packages[0];
+        let dep_names: Vec<&str> = pkg.dependencies.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(dep_names, vec!["glibc"]);
+        assert_eq!(pkg.dependencies[0].constraint.as_deref(), Some(">= 2.34"));
+        assert_eq!(
+            pkg.provides,
+            vec![
+                Provide::new("libssl.so.3()(64bit)".to_string(), None),
+                Provide::new("openssl-libs".to_string(), Some("3.2.2-3.fc41".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_primary_xml_files() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" xmlns:rpm="http://linux.duke.edu/metadata/rpm" packages="1">
+<package type="rpm">
+  <name>dnf-utils</name>
+  <arch>noarch</arch>
+  <version epoch="0" ver="4.9" rel="1.fc41"/>
+  <checksum type="sha256" pkgid="YES">abc123</checksum>
+  <size package="2048" installed="8192" archive="8300"/>
+  <location href="Packages/d/dnf-utils-4.9-1.fc41.noarch.rpm"/>
+  <format>
+    <rpm:requires>
+      <rpm:entry name="/usr/bin/python3"/>
+      <rpm:entry name="/bin/sh" pre="1"/>
+    </rpm:requires>
+    <file>/usr/bin/needs-restarting</file>
+    <file>/etc/dnf/plugins/debuginfo-install.conf</file>
+    <file>/usr/share/man/man1/needs-restarting.1.gz</file>
+  </format>
+</package>
+</metadata>"#;
+
+        let parser = FedoraParser::new("x86_64".to_string());
+        let packages = parser
+            .parse_primary_xml(xml, "https://example.com")
+            .unwrap();
+        let pkg = &packages[0];
+
+        let dep_names: Vec<&str> = pkg.dependencies.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(dep_names, vec!["/usr/bin/python3", "/bin/sh"]);
+        assert_eq!(
+            pkg.files,
+            vec![
+                "/usr/bin/needs-restarting",
+                "/etc/dnf/plugins/debuginfo-install.conf"
+            ]
+        );
+        assert!(is_primary_file("/sbin/ldconfig"));
+        assert!(!is_primary_file("/usr/share/doc/README"));
+    }
+
+    #[test]
+    fn test_package_locations() {
+        let package = |name: &str, location: &str| {
+            format!(
+                r#"<package type="rpm"><name>{name}</name><arch>noarch</arch><version epoch="0" ver="1.0" rel="1"/>
+  <checksum type="sha256" pkgid="YES">abc</checksum><size package="10"/>{location}</package>"#
+            )
+        };
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" packages="4">
+{}
+{}
+{}
+{}
+</metadata>"#,
+            package(
+                "relative",
+                r#"<location href="Packages/r/relative-1.0-1.noarch.rpm"/>"#
+            ),
+            package(
+                "same-host",
+                r#"<location href="http://dl.fedoraproject.org/pub/fedora/Packages/s/same-host-1.0-1.noarch.rpm"/>"#
+            ),
+            package(
+                "cross-host",
+                r#"<location href="https://mirror.example.org/fedora/cross-host-1.0-1.noarch.rpm"/>"#
+            ),
+            package(
+                "based",
+                r#"<location xml:base="https://mirror.example.org/fedora/" href="Packages/b/based-1.0-1.noarch.rpm"/>"#
+            ),
+        );
+
+        let parser = FedoraParser::new("x86_64".to_string());
+        let packages = parser
+            .parse_primary_xml(&xml, "http://dl.fedoraproject.org/pub/fedora/")
+            .unwrap();
+        let urls: Vec<&str> = packages
+            .iter()
+            .map(|pkg| pkg.download_url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://dl.fedoraproject.org/pub/fedora/Packages/r/relative-1.0-1.noarch.rpm",
+                "http://dl.fedoraproject.org/pub/fedora/Packages/s/same-host-1.0-1.noarch.rpm",
+                "https://mirror.example.org/fedora/cross-host-1.0-1.noarch.rpm",
+                "https://mirror.example.org/fedora/Packages/b/based-1.0-1.noarch.rpm",
+            ]
+        );
+    }
+
+    /// repomd.xml of a Fedora updates repository, trimmed to four entries
+    const REPOMD_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo" xmlns:rpm="http://linux.duke.edu/metadata/rpm">
+  <revision>1728950164</revision>
+  <data type="primary">
+    <checksum type="sha256">7b5e1c9f3a0dd2f4c5776e8a2d9e2c3b6a8f1e0d4c3b2a1908f7e6d5c4b3a291</checksum>
+    <open-checksum type="sha256">1f0e2d3c4b5a69788796a5b4c3d2e1f00f1e2d3c4b5a69788796a5b4c3d2e1f0</open-checksum>
+    <location href="repodata/7b5e1c9f-primary.xml.zst"/>
+    <timestamp>1728950067</timestamp>
+    <size>10854437</size>
+    <open-size>79795942</open-size>
+  </data>
+  <data type="filelists">
+    <checksum type="sha256">a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90</checksum>
+    <open-checksum type="sha256">0918273645a5b4c3d2e1f00918273645a5b4c3d2e1f00918273645a5b4c3d2</open-checksum>
+    <location href="repodata/a1b2c3d4-filelists.xml.zst"/>
+    <timestamp>1728950067</timestamp>
+    <size>33573161</size>
+    <open-size>421547808</open-size>
+  </data>
+  <data type="primary_db">
+    <checksum type="sha256">ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100</checksum>
+    <location href="repodata/ffeeddcc-primary.sqlite.xz"/>
+    <database_version>10</database_version>
+  </data>
+  <data type="updateinfo">
+    <checksum type="sha1">3b6a8f1e0d4c3b2a1908f7e6d5c4b3a2917b5e1c</checksum>
+    <location href="repodata/3b6a8f1e-updateinfo.xml.xz"/>
+    <timestamp>1728950164</timestamp>
+  </data>
+</repomd>"#;
+
+    /// primary.xml matching [`FILELISTS_XML`] and [`UPDATEINFO_XML`]
+    const PRIMARY_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metadata xmlns="http://linux.duke.edu/metadata/common" xmlns:rpm="http://linux.duke.edu/metadata/rpm" packages="2">
+<package type="rpm">
+  <name>openssl-libs</name>
+  <arch>x86_64</arch>
+  <version epoch="1" ver="3.2.2" rel="5.fc41"/>
+  <checksum type="sha256" pkgid="YES">5d1e0c0b5b32d3f0</checksum>
+  <size package="2361826" installed="7437458" archive="7450212"/>
+  <location href="Packages/o/openssl-libs-3.2.2-5.fc41.x86_64.rpm"/>
+  <format>
+    <rpm:provides>
+      <rpm:entry name="libssl.so.3()(64bit)"/>
+    </rpm:provides>
+  </format>
+</package>
+<package type="rpm">
+  <name>curl</name>
+  <arch>x86_64</arch>
+  <version epoch="0" ver="8.9.1" rel="2.fc41"/>
+  <checksum type="sha256" pkgid="YES">9a0f2c41b2e8e7d1</checksum>
+  <size package="318034" installed="745504" archive="746844"/>
+  <location href="Packages/c/curl-8.9.1-2.fc41.x86_64.rpm"/>
+  <format>
+    <file>/usr/bin/curl</file>
+  </format>
+</package>
+</metadata>"#;
+
+    /// filelists.xml with an older build of curl that is no longer in primary.xml
+    const FILELISTS_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<filelists xmlns="http://linux.duke.edu/metadata/filelists" packages="3">
+<package pkgid="5d1e0c0b5b32d3f0" name="openssl-libs" arch="x86_64">
+  <version epoch="1" ver="3.2.2" rel="5.fc41"/>
+  <file type="dir">/usr/lib64/engines-3</file>
+  <file>/etc/pki/tls/openssl.cnf</file>
+  <file>/usr/lib64/libcrypto.so.3</file>
+  <file>/usr/lib64/libssl.so.3</file>
+</package>
+<package pkgid="9a0f2c41b2e8e7d1" name="curl" arch="x86_64">
+  <version epoch="0" ver="8.9.1" rel="2.fc41"/>
+  <file>/usr/bin/curl</file>
+  <file>/usr/share/man/man1/curl.1.gz</file>
+</package>
+<package pkgid="0c7d3e14f9a2b6c8" name="curl" arch="x86_64">
+  <version epoch="0" ver="8.9.1" rel="1.fc41"/>
+  <file>/usr/bin/curl</file>
+</package>
+</filelists>"#;
+
+    /// updateinfo.xml with one security and one bugfix advisory
+    const UPDATEINFO_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<updates>
+  <update from="updates@fedoraproject.org" status="stable" type="security" version="2.0">
+    <id>FEDORA-2024-2d7ea6d2c6</id>
+    <title>openssl-3.2.2-5.fc41</title>
+    <issued date="2024-10-12 01:23:45"/>
+    <severity>Important</severity>
+    <references>
+      <reference href="https://bugzilla.redhat.com/show_bug.cgi?id=2305641" id="2305641" type="bugzilla" title="CVE-2024-6119 openssl: Possible denial of service in X.509 name checks [fedora-all]"/>
+      <reference href="https://www.cve.org/CVERecord?id=CVE-2024-5535" id="CVE-2024-5535" type="cve" title="CVE-2024-5535"/>
+    </references>
+    <pkglist>
+      <collection short="F41">
+        <name>Fedora 41</name>
+        <package name="openssl-libs" version="3.2.2" release="5.fc41" epoch="1" arch="x86_64" src="https://download.fedoraproject.org/pub/fedora/linux/updates/41/x86_64/o/openssl-libs-3.2.2-5.fc41.x86_64.rpm">
+          <filename>openssl-libs-3.2.2-5.fc41.x86_64.rpm</filename>
+        </package>
+        <package name="openssl-libs" version="3.2.2" release="5.fc41" epoch="1" arch="i686" src="https://download.fedoraproject.org/pub/fedora/linux/updates/41/i386/o/openssl-libs-3.2.2-5.fc41.i686.rpm">
+          <filename>openssl-libs-3.2.2-5.fc41.i686.rpm</filename>
+        </package>
+      </collection>
+    </pkglist>
+  </update>
+  <update from="updates@fedoraproject.org" status="stable" type="bugfix" version="2.0">
+    <id>FEDORA-2024-8c1f0d9a3e</id>
+    <title>curl-8.9.1-2.fc41</title>
+    <severity>None</severity>
+    <references/>
+    <pkglist>
+      <collection short="F41">
+        <name>Fedora 41</name>
+        <package name="curl" version="8.9.1" release="2.fc41" epoch="0" arch="x86_64">
+          <filename>curl-8.9.1-2.fc41.x86_64.rpm</filename>
+        </package>
+      </collection>
+    </pkglist>
+  </update>
+</updates>"#;
+
+    #[test]
+    fn test_data_locations() {
+        let parser = FedoraParser::new("x86_64".to_string());
+        let locations = parser.data_locations(REPOMD_XML.as_bytes()).unwrap();
+
+        let types: Vec<&str> = locations
+            .iter()
+            .map(|data| data.data_type.as_str())
+            .collect();
+        assert_eq!(
+            types,
+            vec!["primary", "filelists", "primary_db", "updateinfo"]
+        );
+        assert_eq!(locations[0].href, "repodata/7b5e1c9f-primary.xml.zst");
+        // The checksum of the published file, not the open-checksum
+        assert_eq!(
+            locations[0].checksum.as_deref(),
+            Some("7b5e1c9f3a0dd2f4c5776e8a2d9e2c3b6a8f1e0d4c3b2a1908f7e6d5c4b3a291")
+        );
+        assert_eq!(locations[3].checksum_type.as_deref(), Some("sha1"));
+    }
+
+    #[test]
+    fn test_apply_filelists_xml() {
+        let parser = FedoraParser::new("x86_64".to_string());
+        let mut packages = parser
+            .parse_primary_xml(PRIMARY_XML, "https://example.com")
+            .unwrap();
+        assert!(packages[0].files.is_empty());
+
+        let matched = parser
+            .apply_filelists_xml(FILELISTS_XML, &mut packages)
+            .unwrap();
+        assert_eq!(matched, 2);
+        assert_eq!(
+            packages[0].files,
+            vec![
+                "/etc/pki/tls/openssl.cnf",
+                "/usr/lib64/libcrypto.so.3",
+                "/usr/lib64/libssl.so.3"
+            ]
+        );
+        // The older curl build does not touch the one in primary.xml
+        assert_eq!(
+            packages[1].files,
+            vec!["/usr/bin/curl", "/usr/share/man/man1/curl.1.gz"]
+        );
+    }
+
+    #[test]
+    fn test_apply_updateinfo_xml() {
+        let parser = FedoraParser::new("x86_64".to_string());
+        let mut packages = parser
+            .parse_primary_xml(PRIMARY_XML, "https://example.com")
+            .unwrap();
+
+        let matched = parser
+            .apply_updateinfo_xml(UPDATEINFO_XML, &mut packages)
+            .unwrap();
+        assert_eq!(matched, 2);
+        assert_eq!(
+            packages[0].advisories,
+            vec![Advisory {
+                id: "FEDORA-2024-2d7ea6d2c6".to_string(),
+                kind: "security".to_string(),
+                severity: Some("Important".to_string()),
+                cves: vec!["CVE-2024-6119".to_string(), "CVE-2024-5535".to_string()],
+            }]
+        );
+        let curl = &packages[1].advisories[0];
+        assert_eq!(
+            (curl.kind.as_str(), curl.severity.as_deref()),
+            ("bugfix", None)
+        );
+        assert!(curl.cves.is_empty());
     }
 }
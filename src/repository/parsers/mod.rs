@@ -5,22 +5,54 @@
 //! This module provides parsers for native repository metadata formats:
 //! - Arch Linux: .db.tar.gz files
 //! - Debian/Ubuntu: Packages.gz files
-//! - Fedora/RPM: repomd.xml and primary.xml files
+//! - Fedora/RPM: repomd.xml, primary.xml, filelists.xml and updateinfo.xml files
 
 pub mod arch;
 pub mod debian;
 pub mod fedora;
 
-use crate::error::Result;
+use super::{ProgressFn, RepositoryClient};
+use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+use std::str::FromStr;
 
 /// Repository metadata parser trait
 pub trait RepositoryParser {
+    /// URL of the index file that changes whenever the repository does
+    ///
+    /// Arch's `.db`, Debian's `Packages.gz` or Fedora's `repomd.xml`. Syncs
+    /// fetch it conditionally and skip parsing when it has not changed.
+    fn index_url(&self, repo_url: &str) -> String;
+
+    /// Parse packages from a downloaded index file
+    ///
+    /// Formats whose index only points at the package list (Fedora) fetch
+    /// the rest through `client`, reporting to `progress`.
+    fn parse_index(
+        &self,
+        client: &RepositoryClient,
+        repo_url: &str,
+        index: &[u8],
+        progress: Option<&ProgressFn>,
+    ) -> Result<Vec<PackageMetadata>>;
+
     /// Parse repository metadata from a base URL
     ///
     /// Downloads and parses the repository's metadata files, returning
     /// a list of all packages available in the repository.
-    fn sync_metadata(&self, repo_url: &str) -> Result<Vec<PackageMetadata>>;
+    fn sync_metadata(&self, repo_url: &str) -> Result<Vec<PackageMetadata>> {
+        let client = RepositoryClient::new()?;
+        let index = client
+            .fetch_index(&self.index_url(repo_url), None, None, None)?
+            .ok_or_else(|| {
+                Error::DownloadError(format!(
+                    "Unexpected 304 Not Modified from {}",
+                    self.index_url(repo_url)
+                ))
+            })?;
+        self.parse_index(&client, repo_url, &index.body, None)
+    }
 }
 
 /// Package metadata extracted from repository
@@ -53,6 +85,24 @@ pub struct PackageMetadata {
     /// Package dependencies
     pub dependencies: Vec<Dependency>,
 
+    /// Capabilities the package provides besides its own name
+    #[serde(default)]
+    pub provides: Vec<Provide>,
+
+    /// Files the package ships, where the metadata lists them
+    ///
+    /// Fedora lists every file in filelists.xml, and only the "primary"
+    /// ones (see [`fedora::is_primary_file`]) when a repository does not
+    /// publish it; other formats leave this empty.
+    #[serde(default)]
+    pub files: Vec<String>,
+
+    /// Advisories the repository published for this version of the package
+    ///
+    /// Read from Fedora's updateinfo.xml; other formats leave this empty.
+    #[serde(default)]
+    pub advisories: Vec<Advisory>,
+
     /// Additional format-specific metadata (stored as JSON)
     pub extra_metadata: serde_json::Value,
 }
@@ -73,6 +123,36 @@ pub struct Dependency {
     pub description: Option<String>,
 }
 
+/// A capability provided by a package
+///
+/// Virtual package names (`mail-transport-agent`), sonames
+/// (`libssl.so.3()(64bit)`) and similar names that dependencies may refer
+/// to instead of a package name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provide {
+    /// Provided name
+    pub name: String,
+
+    /// Provided version, if the package declares one
+    pub version: Option<String>,
+}
+
+/// An update advisory covering a package version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advisory {
+    /// Advisory identifier, e.g. "FEDORA-2024-1a2b3c4d5e"
+    pub id: String,
+
+    /// Kind of update: "security", "bugfix", "enhancement" or "newpackage"
+    pub kind: String,
+
+    /// Severity as the repository rates it ("Critical", "Important", ...)
+    pub severity: Option<String>,
+
+    /// CVE identifiers the advisory fixes
+    pub cves: Vec<String>,
+}
+
 /// Type of package dependency
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -85,6 +165,10 @@ pub enum DependencyType {
 
     /// Build-time only dependency
     Build,
+
+    /// Required capability, such as an Arch soname ("libfoo.so=3-64"),
+    /// satisfied only by a package providing it
+    Capability,
 }
 
 /// Checksum algorithm type
@@ -102,6 +186,72 @@ pub enum ChecksumType {
     Md5,
 }
 
+impl ChecksumType {
+    /// Name used in metadata and in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChecksumType::Sha256 => "sha256",
+            ChecksumType::Sha512 => "sha512",
+            ChecksumType::Md5 => "md5",
+        }
+    }
+
+    /// Pick the checksum to verify against from those a metadata entry offers
+    ///
+    /// SHA-256 wins, then SHA-512, then MD5, whatever order the metadata
+    /// lists them in. Empty values count as not offered.
+    pub fn prefer(
+        sha256: Option<&str>,
+        sha512: Option<&str>,
+        md5: Option<&str>,
+    ) -> Option<(Self, String)> {
+        [
+            (ChecksumType::Sha256, sha256),
+            (ChecksumType::Sha512, sha512),
+            (ChecksumType::Md5, md5),
+        ]
+        .into_iter()
+        .find_map(|(kind, value)| {
+            value
+                .filter(|value| !value.is_empty())
+                .map(|value| (kind, value.to_string()))
+        })
+    }
+
+    /// Hex digest of everything `reader` yields
+    pub fn digest(&self, reader: &mut dyn Read) -> io::Result<String> {
+        fn hex<D: sha2::Digest + io::Write>(
+            mut hasher: D,
+            reader: &mut dyn Read,
+        ) -> io::Result<String> {
+            io::copy(reader, &mut hasher)?;
+            Ok(hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect())
+        }
+        match self {
+            ChecksumType::Sha256 => hex(sha2::Sha256::default(), reader),
+            ChecksumType::Sha512 => hex(sha2::Sha512::default(), reader),
+            ChecksumType::Md5 => hex(md5::Md5::default(), reader),
+        }
+    }
+}
+
+impl FromStr for ChecksumType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(ChecksumType::Sha256),
+            "sha512" => Ok(ChecksumType::Sha512),
+            "md5" => Ok(ChecksumType::Md5),
+            _ => Err(format!("Unsupported checksum type: {}", s)),
+        }
+    }
+}
+
 impl PackageMetadata {
     /// Create minimal package metadata for testing
     pub fn new(
@@ -121,6 +271,9 @@ impl PackageMetadata {
             size,
             download_url,
             dependencies: Vec::new(),
+            provides: Vec::new(),
+            files: Vec::new(),
+            advisories: Vec::new(),
             extra_metadata: serde_json::Value::Null,
         }
     }
@@ -147,6 +300,16 @@ impl Dependency {
         }
     }
 
+    /// Create a capability dependency, resolved through provides
+    pub fn capability(name: String, constraint: Option<String>) -> Self {
+        Self {
+            name,
+            constraint,
+            dep_type: DependencyType::Capability,
+            description: None,
+        }
+    }
+
     /// Create an optional dependency
     pub fn optional(name: String, description: Option<String>) -> Self {
         Self {
@@ -158,6 +321,13 @@ impl Dependency {
     }
 }
 
+impl Provide {
+    /// Create a provide with an optional version
+    pub fn new(name: String, version: Option<String>) -> Self {
+        Self { name, version }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -188,4 +358,27 @@ mod tests {
         let versioned = Dependency::runtime_versioned("libc".to_string(), ">= 2.34".to_string());
         assert_eq!(versioned.constraint, Some(">= 2.34".to_string()));
     }
+
+    #[test]
+    fn test_checksum_type_preference_and_names() {
+        let picked = ChecksumType::prefer(Some("aa"), Some("bb"), Some("cc"));
+        assert_eq!(picked, Some((ChecksumType::Sha256, "aa".to_string())));
+        let picked = ChecksumType::prefer(Some(""), Some("bb"), Some("cc"));
+        assert_eq!(picked, Some((ChecksumType::Sha512, "bb".to_string())));
+        assert_eq!(
+            ChecksumType::prefer(None, None, Some("cc")).unwrap().0,
+            ChecksumType::Md5
+        );
+        assert_eq!(ChecksumType::prefer(None, None, None), None);
+
+        for kind in [
+            ChecksumType::Sha256,
+            ChecksumType::Sha512,
+            ChecksumType::Md5,
+        ] {
+            assert_eq!(kind.as_str().parse::<ChecksumType>(), Ok(kind));
+        }
+        assert_eq!("SHA512".parse::<ChecksumType>(), Ok(ChecksumType::Sha512));
+        assert!("sha1".parse::<ChecksumType>().is_err());
+    }
 }
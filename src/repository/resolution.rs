@@ -0,0 +1,243 @@
+// src/repository/resolution.rs
+
+//! Explaining why a package or dependency could not be resolved
+//!
+//! When the selector finds no candidate, every enabled repository that was
+//! consulted is examined again to say why it had nothing to offer: the
+//! package is not there, is only built for other architectures, was kept
+//! out by the repository's sync filter, sits in an explicit-only
+//! repository, is forbidden there by a pin, or has no version matching
+//! the request. The resolver adds
+//! the chain of packages that led to the dependency and the constraint it
+//! was required with.
+//!
+//! A [`ResolutionError`] renders as a multi-line explanation and serializes
+//! to JSON for `install --json`.
+
+use crate::db::models::{Repository, RepositoryPackage, RepositoryPin, RepositoryScope};
+use crate::error::{Error, Result};
+use crate::repository::{PackageSelector, SelectionOptions, SyncFilter};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::fmt;
+
+/// Why a package or dependency could not be resolved
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResolutionError {
+    /// The package name, capability or file path that was looked up
+    pub dependency: String,
+    /// Version constraint it was required with, if any
+    pub constraint: Option<String>,
+    /// Architecture it was looked up for
+    pub architecture: String,
+    /// The requested package and the packages leading from it to the
+    /// dependency, outermost first; empty for a package requested directly
+    pub chain: Vec<String>,
+    /// What each repository consulted had to say
+    pub repositories: Vec<RepositoryVerdict>,
+}
+
+/// Why one repository could not provide a package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RepositoryVerdict {
+    pub repository: String,
+    #[serde(flatten)]
+    pub rejection: Rejection,
+}
+
+/// The reason a repository had no candidate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Rejection {
+    /// No package of that name, provide or file
+    NotPresent,
+    /// Only built for other architectures
+    WrongArchitecture { available: Vec<String> },
+    /// Kept out of the index by the repository's sync filter
+    Filtered { detail: String },
+    /// Present, but the repository only serves installs naming it with --repo
+    ExplicitOnly,
+    /// Present, but a forbid pin keeps it out of this repository
+    Pinned { pattern: String },
+    /// Present, but no version satisfies the constraint
+    ConstraintUnsatisfied {
+        constraint: String,
+        candidates: Vec<String>,
+    },
+}
+
+impl ResolutionError {
+    /// Examine the repositories `options` searches for `name`
+    pub fn explain(conn: &Connection, name: &str, options: &SelectionOptions) -> Result<Self> {
+        let architecture = options
+            .architecture
+            .clone()
+            .unwrap_or_else(PackageSelector::detect_architecture);
+        let mut packages = RepositoryPackage::find_by_name(conn, name)?;
+        if packages.is_empty() {
+            packages = RepositoryPackage::find_by_provide(conn, name)?;
+        }
+        if packages.is_empty() && name.starts_with('/') {
+            packages = RepositoryPackage::find_by_file(conn, name)?;
+        }
+
+        let pins = RepositoryPin::list_all(conn)?;
+        let mut repositories = Vec::new();
+        for repo in Repository::list_enabled(conn)? {
+            if options
+                .repository
+                .as_ref()
+                .is_some_and(|wanted| wanted != &repo.name)
+            {
+                continue;
+            }
+            let present: Vec<&RepositoryPackage> = packages
+                .iter()
+                .filter(|pkg| Some(pkg.repository_id) == repo.id)
+                .collect();
+            let compatible: Vec<&RepositoryPackage> = present
+                .iter()
+                .copied()
+                .filter(|pkg| {
+                    PackageSelector::is_architecture_compatible(
+                        pkg.architecture.as_deref(),
+                        &architecture,
+                    )
+                })
+                .collect();
+
+            let rejection = if present.is_empty() {
+                match SyncFilter::for_repository(&repo).rejects(name, Some(&architecture)) {
+                    Some(reason) => Rejection::Filtered {
+                        detail: format!(
+                            "'{}' {} in the sync filter of repository {}",
+                            name, reason, repo.name
+                        ),
+                    },
+                    None => Rejection::NotPresent,
+                }
+            } else if compatible.is_empty() {
+                let mut available: Vec<String> = present
+                    .iter()
+                    .filter_map(|pkg| pkg.architecture.clone())
+                    .collect();
+                available.sort();
+                available.dedup();
+                Rejection::WrongArchitecture { available }
+            } else if repo.scope == RepositoryScope::ExplicitOnly && options.repository.is_none() {
+                Rejection::ExplicitOnly
+            } else if let Some(pin) = compatible
+                .iter()
+                .map(|pkg| PackageSelector::forbidden_by(&pins, &pkg.name, &repo.name, options))
+                .collect::<Option<Vec<_>>>()
+                .and_then(|forbidding| forbidding.first().copied())
+            {
+                Rejection::Pinned {
+                    pattern: pin.pattern.clone(),
+                }
+            } else if let Some(version) = &options.version
+                && !compatible.iter().any(|pkg| &pkg.version == version)
+            {
+                let mut candidates: Vec<String> =
+                    compatible.iter().map(|pkg| pkg.version.clone()).collect();
+                candidates.sort();
+                candidates.dedup();
+                Rejection::ConstraintUnsatisfied {
+                    constraint: format!("= {}", version),
+                    candidates,
+                }
+            } else {
+                continue;
+            };
+            repositories.push(RepositoryVerdict {
+                repository: repo.name,
+                rejection,
+            });
+        }
+
+        Ok(Self {
+            dependency: name.to_string(),
+            constraint: options
+                .version
+                .as_ref()
+                .map(|version| format!("= {}", version)),
+            architecture,
+            chain: Vec::new(),
+            repositories,
+        })
+    }
+}
+
+/// Record that the package or dependency behind `err` was needed by `package`
+///
+/// Errors other than resolution failures are returned unchanged.
+pub fn needed_by(err: Error, package: &str) -> Error {
+    match err {
+        Error::ResolutionError(mut failure) => {
+            failure.chain.insert(0, package.to_string());
+            Error::ResolutionError(failure)
+        }
+        err => err,
+    }
+}
+
+impl fmt::Display for ResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.chain.is_empty() {
+            "Package"
+        } else {
+            "Required dependency"
+        };
+        write!(f, "{} '{}'", kind, self.dependency)?;
+        if let Some(constraint) = &self.constraint {
+            write!(f, " ({})", constraint)?;
+        }
+        write!(f, " not found in any repository for {}", self.architecture)?;
+        if !self.chain.is_empty() {
+            write!(
+                f,
+                "\n  needed by: {} -> {}",
+                self.chain.join(" -> "),
+                self.dependency
+            )?;
+        }
+        if self.repositories.is_empty() {
+            return write!(f, "\n  no enabled repository was searched");
+        }
+        write!(f, "\n  repositories searched:")?;
+        for verdict in &self.repositories {
+            write!(f, "\n    {}: ", verdict.repository)?;
+            match &verdict.rejection {
+                Rejection::NotPresent => write!(f, "not present")?,
+                Rejection::WrongArchitecture { available } => {
+                    write!(f, "only built for {}", available.join(", "))?
+                }
+                Rejection::Filtered { detail } => write!(
+                    f,
+                    "{}, so it was not stored; see 'conary repo-modify {} --no-sync-filter'",
+                    detail, verdict.repository
+                )?,
+                Rejection::ExplicitOnly => write!(
+                    f,
+                    "available from explicit-only repository {}; use --repo {}",
+                    verdict.repository, verdict.repository
+                )?,
+                Rejection::Pinned { pattern } => write!(
+                    f,
+                    "filtered out by pin '{}'; see 'conary pin list' or use --repo {}",
+                    pattern, verdict.repository
+                )?,
+                Rejection::ConstraintUnsatisfied {
+                    constraint,
+                    candidates,
+                } => write!(
+                    f,
+                    "no version {}, has {}",
+                    constraint,
+                    candidates.join(", ")
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -3,13 +3,25 @@
 //! Package selection logic for repository-based installation
 //!
 //! This module handles selecting the best package when multiple matches exist
-//! across different repositories, versions, or architectures.
+//! across different repositories, versions, or architectures, and finding
+//! the companion packages (debug symbols, documentation) distributions
+//! split off from a package.
+//!
+//! Repository pins are consulted before priorities: a `forbid` pin keeps
+//! the packages it matches out of every search, dependency resolution
+//! included, and a `prefer` pin narrows a selection to its repository when
+//! that repository has a candidate.
 
-use crate::db::models::{Repository, RepositoryPackage};
+use crate::db::models::{PinAction, Repository, RepositoryPackage, RepositoryPin, RepositoryScope};
 use crate::error::{Error, Result};
-use crate::version::RpmVersion;
+use crate::query::glob_match;
+use crate::repository::ResolutionError;
+use crate::version::{self, VersionScheme};
 use rusqlite::Connection;
+use serde::Serialize;
 use std::env;
+use std::fmt;
+use std::sync::OnceLock;
 use tracing::{debug, info};
 
 /// Options for package selection
@@ -28,6 +40,98 @@ pub struct SelectionOptions {
 pub struct PackageWithRepo {
     pub package: RepositoryPackage,
     pub repository: Repository,
+    /// Architecture the package was selected for when it was resolved as a
+    /// dependency; `None` for the system architecture
+    pub resolved_for: Option<String>,
+    /// Dependencies, loaded on first use
+    dependencies: OnceLock<Vec<String>>,
+}
+
+impl PackageWithRepo {
+    /// Pair a package with the repository it comes from
+    pub fn new(package: RepositoryPackage, repository: Repository) -> Self {
+        Self {
+            package,
+            repository,
+            resolved_for: None,
+            dependencies: OnceLock::new(),
+        }
+    }
+
+    /// The package's dependencies (see `RepositoryPackage::load_dependencies`)
+    ///
+    /// Loaded from the database once and cached, since the resolver visits
+    /// each candidate more than once.
+    pub fn dependencies(&self, conn: &Connection) -> Result<&[String]> {
+        if let Some(deps) = self.dependencies.get() {
+            return Ok(deps);
+        }
+
+        let deps = self.package.load_dependencies(conn)?;
+        Ok(self.dependencies.get_or_init(|| deps))
+    }
+}
+
+/// Part of a package that distributions publish as a separate package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Companion {
+    /// Debug symbols, such as `foo-debuginfo` or `foo-dbgsym`
+    Debuginfo,
+    /// Documentation, such as `foo-doc`
+    Docs,
+}
+
+/// Name suffixes of companion packages, by the packaging format's conventions
+const COMPANION_SUFFIXES: &[(VersionScheme, Companion, &[&str])] = &[
+    (VersionScheme::Rpm, Companion::Debuginfo, &["-debuginfo"]),
+    (VersionScheme::Rpm, Companion::Docs, &["-doc"]),
+    (
+        VersionScheme::Debian,
+        Companion::Debuginfo,
+        &["-dbgsym", "-dbg"],
+    ),
+    (VersionScheme::Debian, Companion::Docs, &["-doc"]),
+    (VersionScheme::Arch, Companion::Debuginfo, &["-debug"]),
+    (VersionScheme::Arch, Companion::Docs, &["-docs", "-doc"]),
+];
+
+impl Companion {
+    /// Names the companion of `name` goes by in `scheme`'s format, most usual first
+    pub fn names(self, scheme: VersionScheme, name: &str) -> Vec<String> {
+        COMPANION_SUFFIXES
+            .iter()
+            .filter(|(suffix_scheme, companion, _)| *suffix_scheme == scheme && *companion == self)
+            .flat_map(|(_, _, suffixes)| {
+                suffixes
+                    .iter()
+                    .map(move |suffix| format!("{}{}", name, suffix))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Companion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Companion::Debuginfo => "debuginfo",
+            Companion::Docs => "documentation",
+        })
+    }
+}
+
+/// The machine field of uname(2), such as `x86_64` or `armv7l`
+fn uname_machine() -> Option<String> {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return None;
+    }
+    let machine = unsafe { std::ffi::CStr::from_ptr(name.machine.as_ptr()) };
+    machine
+        .to_str()
+        .ok()
+        .filter(|machine| !machine.is_empty())
+        .map(str::to_string)
 }
 
 /// Package selector for choosing the best package from multiple matches
@@ -35,18 +139,67 @@ pub struct PackageSelector;
 
 impl PackageSelector {
     /// Detect the current system architecture
+    ///
+    /// The machine reported by uname(2), so a 32-bit build of conary on a
+    /// 64-bit kernel still selects 64-bit packages, normalized by
+    /// [`canonical_architecture`](Self::canonical_architecture). Falls back
+    /// to the architecture conary was built for.
     pub fn detect_architecture() -> String {
-        // env::consts::ARCH returns the target architecture
-        // Common values: "x86_64", "aarch64", "x86", "arm", etc.
-        env::consts::ARCH.to_string()
+        static DETECTED: OnceLock<String> = OnceLock::new();
+        DETECTED
+            .get_or_init(|| {
+                let machine = uname_machine().unwrap_or_else(|| env::consts::ARCH.to_string());
+                Self::canonical_architecture(&machine).to_string()
+            })
+            .clone()
+    }
+
+    /// The RPM-style name of an architecture
+    ///
+    /// Debian calls x86_64 `amd64` and aarch64 `arm64`, uname reports
+    /// `armv7l` and Rust calls 32-bit x86 `x86`; each spelling maps to one
+    /// name so packages of every format compare equal. Unknown names are
+    /// returned unchanged.
+    pub fn canonical_architecture(arch: &str) -> &str {
+        match arch {
+            "amd64" | "x86-64" => "x86_64",
+            "arm64" | "armv8" => "aarch64",
+            "x86" | "i386" | "i486" | "i586" => "i686",
+            "armhf" | "armv7l" | "armv7h" | "arm" => "armv7hl",
+            "ppc64el" => "ppc64le",
+            other => other,
+        }
     }
 
     /// Check if a package architecture is compatible with the system
     pub fn is_architecture_compatible(pkg_arch: Option<&str>, system_arch: &str) -> bool {
         match pkg_arch {
-            None => true, // Unknown architecture - assume compatible
-            Some("noarch") => true, // noarch is compatible with everything
-            Some(arch) => arch == system_arch,
+            Some(arch) if !Self::is_architecture_independent(Some(arch)) => {
+                Self::canonical_architecture(arch) == Self::canonical_architecture(system_arch)
+            }
+            _ => true, // Unknown or architecture-independent - compatible with everything
+        }
+    }
+
+    /// Whether a package runs on any architecture
+    ///
+    /// Covers RPM's `noarch`, Debian's `all` and Arch's `any`, and packages
+    /// whose architecture is unknown.
+    pub fn is_architecture_independent(pkg_arch: Option<&str>) -> bool {
+        matches!(pkg_arch, None | Some("noarch" | "all" | "any"))
+    }
+
+    /// The architecture the dependencies of a package are resolved for
+    ///
+    /// A package built for an architecture needs dependencies of the same
+    /// architecture (an i686 package on x86_64 needs i686 libraries); an
+    /// architecture-independent one inherits `parent`, the architecture it
+    /// was itself resolved for.
+    pub fn dependency_architecture(pkg_arch: Option<&str>, parent: Option<&str>) -> Option<String> {
+        if Self::is_architecture_independent(pkg_arch) {
+            parent.map(str::to_string)
+        } else {
+            pkg_arch.map(str::to_string)
         }
     }
 
@@ -60,10 +213,7 @@ impl PackageSelector {
         options: &SelectionOptions,
     ) -> Result<Vec<PackageWithRepo>> {
         let detected_arch = Self::detect_architecture();
-        let system_arch = options
-            .architecture
-            .as_deref()
-            .unwrap_or(&detected_arch);
+        let system_arch = options.architecture.as_deref().unwrap_or(&detected_arch);
 
         debug!(
             "Searching for package '{}' (arch: {})",
@@ -73,10 +223,150 @@ impl PackageSelector {
         // Find all matching packages
         let packages = RepositoryPackage::find_by_name(conn, package_name)?;
 
+        Self::filter_candidates(conn, packages, options, system_arch)
+    }
+
+    /// Search for packages that provide a capability
+    ///
+    /// Used when no package carries the requested name, such as a
+    /// virtual package (`mail-transport-agent`) or a soname
+    /// (`libssl.so.3()(64bit)`). Filtered like `search_packages`.
+    pub fn search_providers(
+        conn: &Connection,
+        capability: &str,
+        options: &SelectionOptions,
+    ) -> Result<Vec<PackageWithRepo>> {
+        let detected_arch = Self::detect_architecture();
+        let system_arch = options.architecture.as_deref().unwrap_or(&detected_arch);
+
+        debug!(
+            "Searching for providers of '{}' (arch: {})",
+            capability, system_arch
+        );
+
+        let packages = RepositoryPackage::find_by_provide(conn, capability)?;
+
+        Self::filter_candidates(conn, packages, options, system_arch)
+    }
+
+    /// Search for packages that provide a capability at exactly `version`
+    ///
+    /// Used for capabilities only a provide satisfies, such as Arch sonames
+    /// ("libfoo.so" at "3-64"); a package merely named like the capability
+    /// does not match. Filtered like `search_packages`.
+    pub fn search_versioned_providers(
+        conn: &Connection,
+        capability: &str,
+        version: &str,
+        options: &SelectionOptions,
+    ) -> Result<Vec<PackageWithRepo>> {
+        let detected_arch = Self::detect_architecture();
+        let system_arch = options.architecture.as_deref().unwrap_or(&detected_arch);
+
+        debug!(
+            "Searching for providers of '{}' = {} (arch: {})",
+            capability, version, system_arch
+        );
+
+        let packages = RepositoryPackage::find_by_provide_version(conn, capability, version)?;
+
+        Self::filter_candidates(conn, packages, options, system_arch)
+    }
+
+    /// Search for packages whose file lists contain `path`
+    pub fn search_file_owners(
+        conn: &Connection,
+        path: &str,
+        options: &SelectionOptions,
+    ) -> Result<Vec<PackageWithRepo>> {
+        let detected_arch = Self::detect_architecture();
+        let system_arch = options.architecture.as_deref().unwrap_or(&detected_arch);
+
+        debug!(
+            "Searching for packages shipping '{}' (arch: {})",
+            path, system_arch
+        );
+
+        let packages = RepositoryPackage::find_by_file(conn, path)?;
+
+        Self::filter_candidates(conn, packages, options, system_arch)
+    }
+
+    /// The pin deciding whether `name` may come from `repository`, if any
+    ///
+    /// `pins` are in the order [`RepositoryPin::list_all`] gives, so the
+    /// first match has the highest priority.
+    pub fn deciding_pin<'a>(
+        pins: &'a [RepositoryPin],
+        name: &str,
+        repository: &str,
+    ) -> Option<&'a RepositoryPin> {
+        pins.iter()
+            .find(|pin| pin.repository == repository && glob_match(&pin.pattern, name))
+    }
+
+    /// Whether a pin keeps `name` out of `repository` for a selection
+    ///
+    /// Naming the repository with --repo overrides its forbid pins.
+    pub fn forbidden_by<'a>(
+        pins: &'a [RepositoryPin],
+        name: &str,
+        repository: &str,
+        options: &SelectionOptions,
+    ) -> Option<&'a RepositoryPin> {
+        if options.repository.as_deref() == Some(repository) {
+            return None;
+        }
+        Self::deciding_pin(pins, name, repository).filter(|pin| pin.action == PinAction::Forbid)
+    }
+
+    /// Narrow candidates to the repository a `prefer` pin picks
+    ///
+    /// The highest-priority prefer pin matching a candidate from its own
+    /// repository wins, and candidates from other repositories are dropped.
+    /// Without such a pin the candidates are returned as they are.
+    pub fn apply_preferences(
+        conn: &Connection,
+        candidates: Vec<PackageWithRepo>,
+    ) -> Result<Vec<PackageWithRepo>> {
+        let pins = RepositoryPin::list_all(conn)?;
+        let preferred = pins
+            .iter()
+            .filter(|pin| pin.action == PinAction::Prefer)
+            .find(|pin| {
+                candidates.iter().any(|candidate| {
+                    candidate.repository.name == pin.repository
+                        && glob_match(&pin.pattern, &candidate.package.name)
+                })
+            });
+        let Some(pin) = preferred else {
+            return Ok(candidates);
+        };
+
+        debug!(
+            "Pin '{}' prefers repository {}",
+            pin.pattern, pin.repository
+        );
+        Ok(candidates
+            .into_iter()
+            .filter(|candidate| candidate.repository.name == pin.repository)
+            .collect())
+    }
+
+    /// Apply the version, architecture, repository and pin filters to matches
+    #[allow(clippy::collapsible_if)]
+    fn filter_candidates(
+        conn: &Connection,
+        packages: Vec<RepositoryPackage>,
+        options: &SelectionOptions,
+        system_arch: &str,
+    ) -> Result<Vec<PackageWithRepo>> {
         if packages.is_empty() {
             return Ok(Vec::new());
         }
 
+        let pins = RepositoryPin::list_all(conn)?;
+
         // Get repository information for each package
         let mut results = Vec::new();
         for pkg in packages {
@@ -97,13 +387,12 @@ impl PackageSelector {
             }
 
             // Get repository information
-            let repo = Repository::find_by_id(conn, pkg.repository_id)?
-                .ok_or_else(|| {
-                    Error::NotFoundError(format!(
-                        "Repository {} not found for package {}",
-                        pkg.repository_id, pkg.name
-                    ))
-                })?;
+            let repo = Repository::find_by_id(conn, pkg.repository_id)?.ok_or_else(|| {
+                Error::NotFoundError(format!(
+                    "Repository {} not found for package {}",
+                    pkg.repository_id, pkg.name
+                ))
+            })?;
 
             // Filter by repository if specified
             if let Some(ref repo_name) = options.repository {
@@ -121,10 +410,26 @@ impl PackageSelector {
                 continue;
             }
 
-            results.push(PackageWithRepo {
-                package: pkg,
-                repository: repo,
-            });
+            // Explicit-only repositories serve only installs that name them
+            if repo.scope == RepositoryScope::ExplicitOnly
+                && options.repository.as_ref() != Some(&repo.name)
+            {
+                debug!(
+                    "Skipping package {} from explicit-only repository {}",
+                    pkg.name, repo.name
+                );
+                continue;
+            }
+
+            if let Some(pin) = Self::forbidden_by(&pins, &pkg.name, &repo.name, options) {
+                debug!(
+                    "Skipping package {} from repository {}, forbidden by pin '{}'",
+                    pkg.name, repo.name, pin.pattern
+                );
+                continue;
+            }
+
+            results.push(PackageWithRepo::new(pkg, repo));
         }
 
         Ok(results)
@@ -134,8 +439,12 @@ impl PackageSelector {
     ///
     /// Selection criteria (in order of priority):
     /// 1. Repository priority (higher is better)
-    /// 2. Version (latest version)
-    /// 3. First match (stable tie-breaker)
+    /// 2. Version (latest version, compared the package format's way)
+    /// 3. A build for a specific architecture over an
+    ///    architecture-independent one of the same version, since
+    ///    candidates were already filtered to the requested architecture
+    /// 4. Repository name (alphabetically first), so equal-priority
+    ///    repositories give the same answer on every run
     pub fn select_best(candidates: Vec<PackageWithRepo>) -> Result<PackageWithRepo> {
         if candidates.is_empty() {
             return Err(Error::NotFoundError(
@@ -147,27 +456,26 @@ impl PackageSelector {
             return Ok(candidates.into_iter().next().unwrap());
         }
 
-        // Sort by priority (descending) and version (descending)
-        let mut sorted = candidates;
-        sorted.sort_by(|a, b| {
+        // Best by priority (descending) and version (descending); not a sort,
+        // since pacman's ordering is not total
+        let selected = candidates.into_iter().min_by(|a, b| {
             // First compare repository priority (higher is better)
             match b.repository.priority.cmp(&a.repository.priority) {
                 std::cmp::Ordering::Equal => {
-                    // Then compare versions (newer is better)
-                    match (
-                        RpmVersion::parse(&a.package.version),
-                        RpmVersion::parse(&b.package.version),
-                    ) {
-                        (Ok(v_a), Ok(v_b)) => v_b.cmp(&v_a),
-                        // If version parsing fails, fall back to string comparison
-                        _ => b.package.version.cmp(&a.package.version),
-                    }
+                    // Then newer versions, then a specific architecture
+                    let independent = |pkg: &PackageWithRepo| {
+                        Self::is_architecture_independent(pkg.package.architecture.as_deref())
+                    };
+                    let scheme = b.package.version_scheme();
+                    version::compare_versions(scheme, &b.package.version, &a.package.version)
+                        .then_with(|| independent(a).cmp(&independent(b)))
+                        .then_with(|| a.repository.name.cmp(&b.repository.name))
                 }
                 ord => ord,
             }
         });
 
-        let selected = sorted.into_iter().next().unwrap();
+        let selected = selected.unwrap();
         info!(
             "Selected package {} {} from repository {} (priority {})",
             selected.package.name,
@@ -179,31 +487,85 @@ impl PackageSelector {
         Ok(selected)
     }
 
+    /// Find the `companion` package of `base`
+    ///
+    /// Companions are only taken from the repository `base` comes from and
+    /// at exactly its version, since debug symbols of another build are of
+    /// no use. They have the architecture of `base` or none at all.
+    pub fn find_companion(
+        conn: &Connection,
+        base: &RepositoryPackage,
+        companion: Companion,
+    ) -> Result<Option<PackageWithRepo>> {
+        let Some(repo) = Repository::find_by_id(conn, base.repository_id)? else {
+            return Ok(None);
+        };
+        for name in companion.names(base.version_scheme(), &base.name) {
+            let found = RepositoryPackage::find_by_name(conn, &name)?
+                .into_iter()
+                .find(|pkg| {
+                    pkg.repository_id == base.repository_id
+                        && pkg.version == base.version
+                        && (Self::is_architecture_independent(pkg.architecture.as_deref())
+                            || pkg.architecture == base.architecture)
+                });
+            if let Some(pkg) = found {
+                debug!(
+                    "Found {} package {} {} for {}",
+                    companion, pkg.name, pkg.version, base.name
+                );
+                return Ok(Some(PackageWithRepo::new(pkg, repo)));
+            }
+        }
+        Ok(None)
+    }
+
     /// Find and select the best package matching the given name and options
     ///
     /// This is a convenience function that combines search and selection.
+    /// When no package has the name, packages providing it are considered,
+    /// and for a file path, packages whose file lists contain it. Finding
+    /// nothing is an [`Error::ResolutionError`] explaining what each
+    /// repository had instead. Pins are applied before priorities (see
+    /// [`apply_preferences`](Self::apply_preferences)).
     pub fn find_best_package(
         conn: &Connection,
         package_name: &str,
         options: &SelectionOptions,
     ) -> Result<PackageWithRepo> {
-        let candidates = Self::search_packages(conn, package_name, options)?;
-
+        let mut candidates = Self::search_packages(conn, package_name, options)?;
         if candidates.is_empty() {
-            let mut msg = format!("Package '{}' not found in any repository", package_name);
+            candidates = Self::search_providers(conn, package_name, options)?;
+        }
+        if candidates.is_empty() && package_name.starts_with('/') {
+            candidates = Self::search_file_owners(conn, package_name, options)?;
+        }
 
-            if let Some(ref repo) = options.repository {
-                msg.push_str(&format!(" (searched repository: {})", repo));
-            }
+        if candidates.is_empty() {
+            let failure = ResolutionError::explain(conn, package_name, options)?;
+            return Err(Error::ResolutionError(Box::new(failure)));
+        }
 
-            if let Some(ref version) = options.version {
-                msg.push_str(&format!(" (version: {})", version));
-            }
+        Self::select_best(Self::apply_preferences(conn, candidates)?)
+    }
 
-            return Err(Error::NotFoundError(msg));
+    /// Find the best package providing `capability` at exactly `version`
+    ///
+    /// See `search_versioned_providers`; fails with an explanation like
+    /// `find_best_package` when nothing provides it.
+    pub fn find_best_provider(
+        conn: &Connection,
+        capability: &str,
+        version: &str,
+        options: &SelectionOptions,
+    ) -> Result<PackageWithRepo> {
+        let candidates = Self::search_versioned_providers(conn, capability, version, options)?;
+        if candidates.is_empty() {
+            let failure = ResolutionError::explain(conn, capability, options)?;
+            return Err(Error::ResolutionError(Box::new(failure)));
         }
 
-        Self::select_best(candidates)
+        Self::select_best(Self::apply_preferences(conn, candidates)?)
     }
 }
 
@@ -249,6 +611,49 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_canonical_architecture() {
+        assert_eq!(PackageSelector::canonical_architecture("amd64"), "x86_64");
+        assert_eq!(PackageSelector::canonical_architecture("arm64"), "aarch64");
+        assert_eq!(PackageSelector::canonical_architecture("i386"), "i686");
+        assert_eq!(PackageSelector::canonical_architecture("s390x"), "s390x");
+
+        // Debian names match the RPM-style host name
+        assert!(PackageSelector::is_architecture_compatible(
+            Some("amd64"),
+            "x86_64"
+        ));
+        assert!(!PackageSelector::is_architecture_compatible(
+            Some("i386"),
+            "x86_64"
+        ));
+        assert!(PackageSelector::is_architecture_compatible(
+            Some("all"),
+            "aarch64"
+        ));
+    }
+
+    #[test]
+    fn test_companion_names_follow_format_conventions() {
+        assert_eq!(
+            Companion::Debuginfo.names(VersionScheme::Rpm, "foo"),
+            ["foo-debuginfo"]
+        );
+        assert_eq!(
+            Companion::Debuginfo.names(VersionScheme::Debian, "foo"),
+            ["foo-dbgsym", "foo-dbg"]
+        );
+        assert_eq!(
+            Companion::Debuginfo.names(VersionScheme::Arch, "foo"),
+            ["foo-debug"]
+        );
+        assert_eq!(
+            Companion::Docs.names(VersionScheme::Debian, "foo"),
+            ["foo-doc"]
+        );
+        assert_eq!(Companion::Docs.to_string(), "documentation");
+    }
+
     #[test]
     fn test_selection_options_default() {
         let opts = SelectionOptions::default();
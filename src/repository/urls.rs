@@ -0,0 +1,252 @@
+// src/repository/urls.rs
+
+//! Resolving and checking repository URLs
+//!
+//! Metadata names package files by location: usually a path relative to the
+//! repository (Arch's `%FILENAME%`, Debian's `Filename`, Fedora's
+//! `<location href>`), sometimes an absolute URL on a mirror. Every parser
+//! resolves locations with [`resolve_location`], so relative, `..` and
+//! absolute locations mean the same thing in every format.
+//!
+//! A [`UrlPolicy`] applies a repository's settings to the URLs it fetches:
+//! - `prefer_https` upgrades `http://` metadata and package URLs to `https://`
+//! - a package URL on a different host, or with a different scheme, than the
+//!   repository is logged with both sides, since authentication and proxy
+//!   settings chosen by URL will not cover it
+//! - with `allow_cross_host` off, downloads from another host are refused
+
+use crate::db::models::Repository;
+use crate::error::{Error, Result};
+use reqwest::Url;
+use tracing::info;
+
+/// Resolve a location from repository metadata against the repository URL
+///
+/// `base` is treated as a directory whether or not it ends in a slash.
+/// Absolute URLs are returned unchanged.
+pub fn resolve_location(base: &str, location: &str) -> String {
+    if is_absolute(location) {
+        return location.to_string();
+    }
+
+    let base_dir = format!("{}/", base.trim_end_matches('/'));
+    // "./" keeps a colon in a file name (an epoch, say) from reading as a scheme
+    let relative = if location.starts_with('/') {
+        location.to_string()
+    } else {
+        format!("./{}", location)
+    };
+    match Url::parse(&base_dir).and_then(|base| base.join(&relative)) {
+        Ok(url) => url.to_string(),
+        Err(_) => format!("{}{}", base_dir, location.trim_start_matches('/')),
+    }
+}
+
+/// Whether a location is a URL with its own scheme
+fn is_absolute(location: &str) -> bool {
+    location.split_once("://").is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+/// A repository's rules for the URLs it fetches
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    repository: String,
+    base: String,
+    prefer_https: bool,
+    allow_cross_host: bool,
+}
+
+impl UrlPolicy {
+    /// The policy configured for `repo`
+    pub fn for_repository(repo: &Repository) -> Self {
+        let mut policy = Self {
+            repository: repo.name.clone(),
+            base: String::new(),
+            prefer_https: repo.prefer_https,
+            allow_cross_host: repo.allow_cross_host,
+        };
+        policy.base = policy.apply_scheme(&repo.url);
+        policy
+    }
+
+    /// The repository URL that metadata is fetched from and resolved against
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// `url` with `prefer_https` applied
+    pub fn apply_scheme(&self, url: &str) -> String {
+        match url.strip_prefix("http://") {
+            Some(rest) if self.prefer_https => format!("https://{}", rest),
+            _ => url.to_string(),
+        }
+    }
+
+    /// How `url` departs from the repository URL, if it does
+    ///
+    /// Describes a different host first, then a different scheme. URLs that
+    /// do not parse are not judged.
+    pub fn mismatch(&self, url: &str) -> Option<String> {
+        let (base, url) = (Url::parse(&self.base).ok()?, Url::parse(url).ok()?);
+        if base.host_str() != url.host_str() {
+            return Some(format!(
+                "host {} instead of {}",
+                url.host_str().unwrap_or("(none)"),
+                base.host_str().unwrap_or("(none)")
+            ));
+        }
+        if base.scheme() != url.scheme() {
+            return Some(format!("{} instead of {}", url.scheme(), base.scheme()));
+        }
+        None
+    }
+
+    /// Whether `url` points at a different host than the repository
+    pub fn leaves_host(&self, url: &str) -> bool {
+        match (Url::parse(&self.base), Url::parse(url)) {
+            (Ok(base), Ok(url)) => base.host_str() != url.host_str(),
+            _ => false,
+        }
+    }
+
+    /// The URL to download a package from, or an error if the policy forbids it
+    pub fn package_url(&self, url: &str) -> Result<String> {
+        let url = self.apply_scheme(url);
+        if let Some(mismatch) = self.mismatch(&url) {
+            if self.leaves_host(&url) && !self.allow_cross_host {
+                return Err(Error::DownloadError(format!(
+                    "Refusing to download {} for repository {} ({}, {}); \
+                     allow it with 'conary repo-modify {} --allow-cross-host true'",
+                    url, self.repository, mismatch, self.base, self.repository
+                )));
+            }
+            info!(
+                "Downloading {} for repository {}: {}",
+                url, self.repository, mismatch
+            );
+        }
+        Ok(url)
+    }
+
+    /// Log how many of a repository's package URLs depart from it, once per sync
+    pub fn report<'a>(&self, urls: impl IntoIterator<Item = &'a str>) {
+        let mut total = 0;
+        let mut departing = 0;
+        let mut first = None;
+        for url in urls {
+            total += 1;
+            if let Some(mismatch) = self.mismatch(&self.apply_scheme(url)) {
+                departing += 1;
+                first.get_or_insert_with(|| format!("{} ({})", url, mismatch));
+            }
+        }
+        if let Some(first) = first {
+            let blocked = if self.allow_cross_host {
+                ""
+            } else {
+                "; other hosts will be refused"
+            };
+            info!(
+                "{} of {} package URLs of {} depart from {}, e.g. {}{}",
+                departing, total, self.repository, self.base, first, blocked
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(url: &str, prefer_https: bool, allow_cross_host: bool) -> UrlPolicy {
+        let mut repo = Repository::new("ubuntu-noble".to_string(), url.to_string());
+        repo.prefer_https = prefer_https;
+        repo.allow_cross_host = allow_cross_host;
+        UrlPolicy::for_repository(&repo)
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let base = "http://archive.ubuntu.com/ubuntu";
+        assert_eq!(
+            resolve_location(base, "pool/main/a/a.deb"),
+            "http://archive.ubuntu.com/ubuntu/pool/main/a/a.deb"
+        );
+        assert_eq!(
+            resolve_location("http://archive.ubuntu.com/ubuntu/", "pool/a.deb"),
+            "http://archive.ubuntu.com/ubuntu/pool/a.deb"
+        );
+        assert_eq!(
+            resolve_location(base, "../other/a.deb"),
+            "http://archive.ubuntu.com/other/a.deb"
+        );
+        assert_eq!(
+            resolve_location(base, "/pool/a.deb"),
+            "http://archive.ubuntu.com/pool/a.deb"
+        );
+        assert_eq!(
+            resolve_location(base, "tzdata-1:2024a-1.deb"),
+            "http://archive.ubuntu.com/ubuntu/tzdata-1:2024a-1.deb"
+        );
+        assert_eq!(
+            resolve_location(base, "https://mirror.example.org/a.deb"),
+            "https://mirror.example.org/a.deb"
+        );
+    }
+
+    #[test]
+    fn test_prefer_https_upgrades_base_and_packages() {
+        let secure = policy("http://archive.ubuntu.com/ubuntu", true, true);
+        assert_eq!(secure.base(), "https://archive.ubuntu.com/ubuntu");
+        assert_eq!(
+            secure
+                .package_url("http://archive.ubuntu.com/ubuntu/a.deb")
+                .unwrap(),
+            "https://archive.ubuntu.com/ubuntu/a.deb"
+        );
+
+        let plain = policy("http://archive.ubuntu.com/ubuntu", false, true);
+        assert_eq!(
+            plain
+                .mismatch("https://archive.ubuntu.com/ubuntu/a.deb")
+                .unwrap(),
+            "https instead of http"
+        );
+        assert!(
+            plain
+                .mismatch("http://archive.ubuntu.com/ubuntu/a.deb")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_cross_host_downloads() {
+        let url = "https://mirror.example.org/a.deb";
+        let open = policy("http://archive.ubuntu.com/ubuntu", false, true);
+        assert!(open.leaves_host(url));
+        assert_eq!(
+            open.mismatch(url).unwrap(),
+            "host mirror.example.org instead of archive.ubuntu.com"
+        );
+        assert_eq!(open.package_url(url).unwrap(), url);
+
+        let closed = policy("http://archive.ubuntu.com/ubuntu", false, false);
+        let err = closed.package_url(url).unwrap_err().to_string();
+        assert!(
+            err.contains("mirror.example.org") && err.contains("--allow-cross-host"),
+            "{}",
+            err
+        );
+        // A scheme change on the same host is not a cross-host download
+        assert!(
+            closed
+                .package_url("https://archive.ubuntu.com/ubuntu/a.deb")
+                .is_ok()
+        );
+    }
+}
@@ -4,30 +4,45 @@
 //!
 //! This module provides dependency graph construction, topological sorting,
 //! cycle detection, and conflict resolution for package dependencies.
+//!
+//! Before a transaction installs, upgrades or removes several packages,
+//! [`Resolver::check_transaction`] looks at the installed set as it will be
+//! afterwards, so a new version that breaks what another package requires
+//! is refused before anything is deployed.
 
-use crate::db::models::{DependencyEntry, Trove};
+use crate::db::models::{DependencyEntry, InstallReason, Trove};
 use crate::error::{Error, Result};
-use crate::version::{RpmVersion, VersionConstraint};
+use crate::version::{VersionConstraint, VersionScheme};
 use rusqlite::Connection;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet, VecDeque};
 
 /// A node in the dependency graph representing a package
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PackageNode {
     pub name: String,
-    pub version: RpmVersion,
+    pub version: String,
+    /// How versions of the package are ordered
+    pub scheme: VersionScheme,
     pub trove_id: Option<i64>,
 }
 
 impl PackageNode {
-    pub fn new(name: String, version: RpmVersion) -> Self {
+    /// A package whose versions are ordered like RPM's
+    pub fn new(name: String, version: String) -> Self {
         Self {
             name,
             version,
+            scheme: VersionScheme::Rpm,
             trove_id: None,
         }
     }
 
+    pub fn with_scheme(mut self, scheme: VersionScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
     pub fn with_trove_id(mut self, trove_id: i64) -> Self {
         self.trove_id = Some(trove_id);
         self
@@ -72,9 +87,8 @@ impl DependencyGraph {
         let troves = Trove::list_all(conn)?;
 
         for trove in troves {
-            // Parse the version
-            let version = RpmVersion::parse(&trove.version)?;
-            let node = PackageNode::new(trove.name.clone(), version)
+            let node = PackageNode::new(trove.name.clone(), trove.version.clone())
+                .with_scheme(trove.version_scheme())
                 .with_trove_id(trove.id.unwrap());
 
             graph.add_node(node);
@@ -83,11 +97,12 @@ impl DependencyGraph {
             let deps = DependencyEntry::find_by_trove(conn, trove.id.unwrap())?;
 
             for dep in deps {
-                let constraint = if let Some(ref constraint_str) = dep.version_constraint {
-                    VersionConstraint::parse(constraint_str)?
-                } else {
-                    VersionConstraint::Any
-                };
+                // Installs record the package's own constraint text as the version
+                let constraint = requirement(
+                    dep.version_constraint
+                        .as_deref()
+                        .or(dep.depends_on_version.as_deref()),
+                );
 
                 let edge = DependencyEdge {
                     from: trove.name.clone(),
@@ -130,15 +145,15 @@ impl DependencyGraph {
 
     /// Get all dependencies of a package
     pub fn get_dependencies(&self, name: &str) -> Vec<&DependencyEdge> {
-        self.edges.get(name).map(|v| v.iter().collect()).unwrap_or_default()
+        self.edges
+            .get(name)
+            .map(|v| v.iter().collect())
+            .unwrap_or_default()
     }
 
     /// Get all packages that depend on this package (reverse dependencies)
     pub fn get_dependents(&self, name: &str) -> Vec<String> {
-        self.reverse_edges
-            .get(name)
-            .cloned()
-            .unwrap_or_default()
+        self.reverse_edges.get(name).cloned().unwrap_or_default()
     }
 
     /// Perform topological sort using Kahn's algorithm
@@ -186,7 +201,7 @@ impl DependencyGraph {
 
         // If we haven't processed all nodes, there's a cycle
         if result.len() != self.nodes.len() {
-            return Err(Error::InitError(
+            return Err(Error::ConflictError(
                 "Circular dependency detected in package graph".to_string(),
             ));
         }
@@ -247,19 +262,24 @@ impl DependencyGraph {
     }
 
     /// Check if a version satisfies all constraints for a dependency
-    pub fn check_constraints(&self, package_name: &str, version: &RpmVersion) -> Result<()> {
+    ///
+    /// The version is ordered the way the package in the graph is.
+    pub fn check_constraints(&self, package_name: &str, version: &str) -> Result<()> {
+        let scheme = self
+            .nodes
+            .get(package_name)
+            .map_or(VersionScheme::Rpm, |node| node.scheme);
         // Find all packages that depend on this package
         if let Some(dependents) = self.reverse_edges.get(package_name) {
             for dependent in dependents {
                 if let Some(edges) = self.edges.get(dependent) {
                     for edge in edges {
-                        if edge.to == package_name
-                            && !edge.constraint.satisfies(version)
-                        {
-                            return Err(Error::InitError(format!(
-                                "Version {} of {} does not satisfy constraint {} required by {}",
-                                version, package_name, edge.constraint, dependent
-                            )));
+                        if edge.to == package_name && !edge.constraint.satisfies(scheme, version) {
+                            return Err(Error::DependencyUnresolved {
+                                name: package_name.to_string(),
+                                constraint: edge.constraint.to_string(),
+                                required_by: dependent.clone(),
+                            });
                         }
                     }
                 }
@@ -352,7 +372,8 @@ pub struct MissingDependency {
 }
 
 /// A conflict between package requirements
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Conflict {
     /// Version constraint cannot be satisfied
     UnsatisfiableConstraint {
@@ -392,7 +413,11 @@ impl std::fmt::Display for Conflict {
                 package,
                 constraints,
             } => {
-                writeln!(f, "Conflicting version requirements for package {}:", package)?;
+                writeln!(
+                    f,
+                    "Conflicting version requirements for package {}:",
+                    package
+                )?;
                 for (requirer, constraint) in constraints {
                     writeln!(f, "  - {} requires {}", requirer, constraint)?;
                 }
@@ -416,6 +441,59 @@ impl std::fmt::Display for Conflict {
     }
 }
 
+/// One change a transaction makes to the installed set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProposedChange {
+    /// Install a package, taking the place of any installed version of it
+    Install {
+        name: String,
+        version: String,
+        /// How versions of the package are ordered
+        scheme: VersionScheme,
+        /// Names it requires, with their version constraints
+        requires: Vec<(String, Option<String>)>,
+    },
+    /// Take an installed package off
+    Remove { name: String },
+}
+
+impl ProposedChange {
+    /// An install whose requirements are entries like "libfoo >= 2.0"
+    pub fn install(name: &str, version: &str, scheme: VersionScheme, entries: &[String]) -> Self {
+        let requires = entries
+            .iter()
+            .map(|entry| {
+                let (name, constraint) = crate::version::split_constraint(entry);
+                (name.to_string(), constraint.map(str::to_string))
+            })
+            .collect();
+        ProposedChange::Install {
+            name: name.to_string(),
+            version: version.to_string(),
+            scheme,
+            requires,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            ProposedChange::Install { name, .. } | ProposedChange::Remove { name } => name,
+        }
+    }
+}
+
+/// The constraint of a requirement, if it has one this module can check
+///
+/// Only text starting with an operator counts: RPM requirements keep their
+/// version without the comparison, which would otherwise read as an exact
+/// match. Constraints that do not parse accept any version.
+fn requirement(constraint: Option<&str>) -> VersionConstraint {
+    constraint
+        .filter(|constraint| constraint.trim_start().starts_with(['<', '>', '=', '!']))
+        .and_then(|constraint| VersionConstraint::parse(constraint).ok())
+        .unwrap_or(VersionConstraint::Any)
+}
+
 /// Dependency resolver for determining installation order and conflicts
 pub struct Resolver {
     graph: DependencyGraph,
@@ -442,7 +520,7 @@ impl Resolver {
     pub fn resolve_install(
         &mut self,
         package_name: String,
-        version: RpmVersion,
+        version: String,
         dependencies: Vec<DependencyEdge>,
     ) -> Result<ResolutionPlan> {
         // Add the new package to the graph
@@ -508,9 +586,7 @@ impl Resolver {
     }
 
     /// Find all missing dependencies in the graph
-    fn find_missing_dependencies(
-        &self,
-    ) -> HashMap<String, (VersionConstraint, Vec<String>)> {
+    fn find_missing_dependencies(&self) -> HashMap<String, (VersionConstraint, Vec<String>)> {
         let mut missing: HashMap<String, (VersionConstraint, Vec<String>)> = HashMap::new();
 
         for (package_name, edges) in &self.graph.edges {
@@ -532,8 +608,7 @@ impl Resolver {
     /// Check all version constraints and return conflicts
     fn check_all_constraints(&self) -> Vec<Conflict> {
         let mut conflicts = Vec::new();
-        let mut constraint_map: HashMap<String, Vec<(String, VersionConstraint)>> =
-            HashMap::new();
+        let mut constraint_map: HashMap<String, Vec<(String, VersionConstraint)>> = HashMap::new();
 
         // Collect all constraints for each package
         for (requirer, edges) in &self.graph.edges {
@@ -550,7 +625,7 @@ impl Resolver {
             if let Some(node) = self.graph.get_node(&package_name) {
                 // Check if installed version satisfies all constraints
                 for (requirer, constraint) in &constraints {
-                    if !constraint.satisfies(&node.version) {
+                    if !constraint.satisfies(node.scheme, &node.version) {
                         conflicts.push(Conflict::UnsatisfiableConstraint {
                             package: package_name.clone(),
                             installed_version: node.version.to_string(),
@@ -591,6 +666,106 @@ impl Resolver {
         conflicts
     }
 
+    /// Check the installed set as it would be after `proposed_changes`
+    ///
+    /// Every requirement of an installed or proposed package is checked
+    /// against the versions after the transaction. Only requirements of a
+    /// changed package, or on one, are reported, and not those the installed
+    /// versions already failed, so breakage that predates the transaction
+    /// does not block it. Requirements on names no package
+    /// in the graph carries (sonames, virtual provides) are not checked, and
+    /// constraints that do not parse are skipped. Versions are ordered the
+    /// way the format of their package does.
+    pub fn check_transaction(&self, proposed_changes: &[ProposedChange]) -> Vec<Conflict> {
+        let mut versions: HashMap<&str, (VersionScheme, &str)> = self
+            .graph
+            .nodes
+            .iter()
+            .map(|(name, node)| (name.as_str(), (node.scheme, node.version.as_str())))
+            .collect();
+        let mut requires: HashMap<&str, Vec<(&str, VersionConstraint)>> = self
+            .graph
+            .edges
+            .iter()
+            .map(|(name, edges)| {
+                (
+                    name.as_str(),
+                    edges
+                        .iter()
+                        .map(|edge| (edge.to.as_str(), edge.constraint.clone()))
+                        .collect(),
+                )
+            })
+            .collect();
+        let mut removed: HashSet<&str> = HashSet::new();
+
+        for change in proposed_changes {
+            match change {
+                ProposedChange::Install {
+                    name,
+                    version,
+                    scheme,
+                    requires: entries,
+                } => {
+                    versions.insert(name, (*scheme, version));
+                    let entries = entries
+                        .iter()
+                        .map(|(dep, constraint)| (dep.as_str(), requirement(constraint.as_deref())))
+                        .collect();
+                    requires.insert(name, entries);
+                    removed.remove(name.as_str());
+                }
+                ProposedChange::Remove { name } => {
+                    versions.remove(name.as_str());
+                    requires.remove(name.as_str());
+                    removed.insert(name);
+                }
+            }
+        }
+
+        let changed: HashSet<&str> = proposed_changes.iter().map(ProposedChange::name).collect();
+        let mut requirers: Vec<&&str> = requires.keys().collect();
+        requirers.sort();
+
+        let mut conflicts = Vec::new();
+        let mut missing: Vec<(&str, Vec<String>)> = Vec::new();
+        for requirer in requirers {
+            for (dep, constraint) in &requires[*requirer] {
+                if !changed.contains(*requirer) && !changed.contains(dep) {
+                    continue;
+                }
+                // A requirement the installed version already failed is not new breakage
+                let was_broken = !changed.contains(*requirer)
+                    && self
+                        .graph
+                        .get_node(dep)
+                        .is_some_and(|node| !constraint.satisfies(node.scheme, &node.version));
+                if let Some(&(scheme, version)) = versions.get(dep) {
+                    if !constraint.satisfies(scheme, version) && !was_broken {
+                        conflicts.push(Conflict::UnsatisfiableConstraint {
+                            package: dep.to_string(),
+                            installed_version: version.to_string(),
+                            required_constraint: constraint.to_string(),
+                            required_by: requirer.to_string(),
+                        });
+                    }
+                } else if removed.contains(dep) {
+                    match missing.iter_mut().find(|(name, _)| name == dep) {
+                        Some((_, required_by)) => required_by.push(requirer.to_string()),
+                        None => missing.push((dep, vec![requirer.to_string()])),
+                    }
+                }
+            }
+        }
+        conflicts.extend(missing.into_iter().map(|(package, required_by)| {
+            Conflict::MissingPackage {
+                package: package.to_string(),
+                required_by,
+            }
+        }));
+        conflicts
+    }
+
     /// Check if removing a package would break dependencies
     pub fn check_removal(&self, package_name: &str) -> Result<Vec<String>> {
         let breaking = self.graph.find_breaking_packages(package_name);
@@ -603,12 +778,46 @@ impl Resolver {
     }
 }
 
+/// Find troves installed as dependencies that nothing needs anymore
+///
+/// Walks the dependency graph from every explicitly installed trove; troves
+/// installed as dependencies that cannot be reached are returned. Dependency
+/// cycles with nothing explicit depending on them are found as a whole.
+pub fn find_unneeded_dependencies(conn: &Connection) -> Result<Vec<Trove>> {
+    let troves = Trove::list_all(conn)?;
+
+    let mut needed: HashSet<String> = HashSet::new();
+    let mut queue: VecDeque<&Trove> = VecDeque::new();
+    for trove in &troves {
+        if trove.install_reason == InstallReason::Explicit && needed.insert(trove.name.clone()) {
+            queue.push_back(trove);
+        }
+    }
+
+    while let Some(trove) = queue.pop_front() {
+        let Some(trove_id) = trove.id else {
+            continue;
+        };
+        for dep in DependencyEntry::find_by_trove(conn, trove_id)? {
+            if needed.insert(dep.depends_on_name.clone()) {
+                queue.extend(troves.iter().filter(|t| t.name == dep.depends_on_name));
+            }
+        }
+    }
+
+    Ok(troves
+        .into_iter()
+        .filter(|t| t.install_reason == InstallReason::Dependency && !needed.contains(&t.name))
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::packages::PackageFormatType;
 
-    fn make_version(s: &str) -> RpmVersion {
-        RpmVersion::parse(s).unwrap()
+    fn make_version(s: &str) -> String {
+        s.to_string()
     }
 
     #[test]
@@ -846,7 +1055,11 @@ mod tests {
         });
 
         let lib_version = make_version("0.5.0");
-        assert!(graph.check_constraints("lib", &lib_version).is_err());
+        let err = graph.check_constraints("lib", &lib_version).unwrap_err();
+        assert!(
+            matches!(&err, Error::DependencyUnresolved { name, required_by, .. } if name == "lib" && required_by == "app")
+        );
+        assert_eq!(err.exit_code(), 5);
     }
 
     #[test]
@@ -1110,4 +1323,239 @@ mod tests {
         assert!(pos_lib3 < pos_app);
         assert!(pos_lib1 < pos_app);
     }
+
+    #[test]
+    fn test_check_transaction_conflicts() {
+        use crate::db::models::TroveType;
+
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        crate::db::schema::migrate(&conn).unwrap();
+
+        let add = |name: &str, version: &str, deps: &[(&str, Option<&str>)]| {
+            let id = Trove::new(name.to_string(), version.to_string(), TroveType::Package)
+                .insert(&conn)
+                .unwrap();
+            for (dep, constraint) in deps {
+                DependencyEntry::new(
+                    id,
+                    dep.to_string(),
+                    None,
+                    "runtime".to_string(),
+                    constraint.map(str::to_string),
+                )
+                .insert(&conn)
+                .unwrap();
+            }
+        };
+        add("libfoo", "1.5", &[]);
+        add("a", "1.0", &[("libfoo", Some(">= 1.0"))]);
+        add("b", "1.0", &[("libfoo", Some("< 2"))]);
+        add("c", "1.0", &[("libbar", None)]);
+        // Broken before the transaction, and not touched by it
+        add("d", "1.0", &[("libfoo", Some(">= 3"))]);
+
+        let resolver = Resolver::new(&conn).unwrap();
+        let update = [
+            ProposedChange::install("a", "2.0", VersionScheme::Rpm, &["libfoo >= 2".to_string()]),
+            ProposedChange::install("libfoo", "2.1", VersionScheme::Rpm, &[]),
+        ];
+        let conflicts = resolver.check_transaction(&update);
+        assert_eq!(
+            conflicts,
+            vec![Conflict::UnsatisfiableConstraint {
+                package: "libfoo".to_string(),
+                installed_version: "2.1".to_string(),
+                required_constraint: "< 2".to_string(),
+                required_by: "b".to_string(),
+            }]
+        );
+        assert!(
+            conflicts[0].to_string().contains("required by b"),
+            "{}",
+            conflicts[0]
+        );
+
+        // The new a alone needs a libfoo the transaction does not bring
+        let conflicts = resolver.check_transaction(&update[..1]);
+        assert_eq!(conflicts.len(), 1);
+        assert!(
+            matches!(&conflicts[0], Conflict::UnsatisfiableConstraint { required_by, .. } if required_by == "a")
+        );
+
+        let conflicts = resolver.check_transaction(&[ProposedChange::Remove {
+            name: "libfoo".to_string(),
+        }]);
+        assert_eq!(
+            conflicts,
+            vec![Conflict::MissingPackage {
+                package: "libfoo".to_string(),
+                required_by: vec!["a".to_string(), "b".to_string(), "d".to_string()],
+            }]
+        );
+        assert!(
+            resolver
+                .check_transaction(&[ProposedChange::install(
+                    "libfoo",
+                    "1.9",
+                    VersionScheme::Rpm,
+                    &[]
+                )])
+                .is_empty()
+        );
+    }
+
+    /// Record an installed trove of `format` requiring `deps` in a fresh database
+    fn add_trove(
+        conn: &Connection,
+        format: PackageFormatType,
+        name: &str,
+        version: &str,
+        deps: &[(&str, &str)],
+    ) {
+        let mut trove = Trove::new(
+            name.to_string(),
+            version.to_string(),
+            crate::db::models::TroveType::Package,
+        );
+        trove.format = Some(format);
+        let id = trove.insert(conn).unwrap();
+        for (dep, constraint) in deps {
+            DependencyEntry::new(
+                id,
+                dep.to_string(),
+                None,
+                "runtime".to_string(),
+                Some(constraint.to_string()),
+            )
+            .insert(conn)
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_check_transaction_orders_debian_versions() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        crate::db::schema::migrate(&conn).unwrap();
+        // dpkg splits the revision at the last hyphen, rpm the release at the first
+        add_trove(&conn, PackageFormatType::Deb, "libfoo", "1.2-3-1", &[]);
+        add_trove(&conn, PackageFormatType::Deb, "libbar", "2.0-1", &[]);
+        add_trove(
+            &conn,
+            PackageFormatType::Deb,
+            "app",
+            "1.0-1",
+            &[("libbar", ">= 2.0")],
+        );
+
+        let resolver = Resolver::new(&conn).unwrap();
+        let tool = |constraint: &str| {
+            ProposedChange::install(
+                "tool",
+                "1.0-1",
+                VersionScheme::Debian,
+                &[format!("libfoo {}", constraint)],
+            )
+        };
+        assert!(resolver.check_transaction(&[tool(">= 1.2-10")]).is_empty());
+        assert_eq!(resolver.check_transaction(&[tool(">> 1.2-3-1")]).len(), 1);
+        assert!(resolver.check_transaction(&[tool("<< 1.2-3-2")]).is_empty());
+
+        // A release candidate sorts before the release it leads up to
+        let update = [ProposedChange::install(
+            "libbar",
+            "2.0~rc1-1",
+            VersionScheme::Debian,
+            &[],
+        )];
+        let conflicts = resolver.check_transaction(&update);
+        assert!(
+            matches!(&conflicts[..], [Conflict::UnsatisfiableConstraint { required_by, .. }] if required_by == "app"),
+            "{:?}",
+            conflicts
+        );
+    }
+
+    #[test]
+    fn test_check_transaction_orders_arch_versions() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        crate::db::schema::migrate(&conn).unwrap();
+        add_trove(&conn, PackageFormatType::Arch, "libfoo", "1.0-1", &[]);
+        add_trove(
+            &conn,
+            PackageFormatType::Arch,
+            "app",
+            "1.0-1",
+            &[("libfoo", ">=1.0")],
+        );
+
+        // pacman takes 1.0a for a pre-release of 1.0, where rpm would take it as newer
+        let resolver = Resolver::new(&conn).unwrap();
+        let conflicts = resolver.check_transaction(&[ProposedChange::install(
+            "libfoo",
+            "1.0a-1",
+            VersionScheme::Arch,
+            &[],
+        )]);
+        assert_eq!(
+            conflicts,
+            vec![Conflict::UnsatisfiableConstraint {
+                package: "libfoo".to_string(),
+                installed_version: "1.0a-1".to_string(),
+                required_constraint: ">= 1.0".to_string(),
+                required_by: "app".to_string(),
+            }]
+        );
+        assert!(
+            resolver
+                .check_transaction(&[ProposedChange::install(
+                    "libfoo",
+                    "1.0.1-1",
+                    VersionScheme::Arch,
+                    &[]
+                )])
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_find_unneeded_dependencies() {
+        use crate::db::models::TroveType;
+
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        crate::db::schema::migrate(&conn).unwrap();
+
+        let add = |name: &str, reason: InstallReason, deps: &[&str]| {
+            let mut trove = Trove::new(name.to_string(), "1.0".to_string(), TroveType::Package);
+            trove.install_reason = reason;
+            let id = trove.insert(&conn).unwrap();
+            for dep in deps {
+                DependencyEntry::new(id, dep.to_string(), None, "runtime".to_string(), None)
+                    .insert(&conn)
+                    .unwrap();
+            }
+        };
+
+        // app -> libfoo -> libbar stays; the orphaned pair cycles on itself
+        add("app", InstallReason::Explicit, &["libfoo"]);
+        add("libfoo", InstallReason::Dependency, &["libbar"]);
+        add("libbar", InstallReason::Dependency, &[]);
+        add("orphan-a", InstallReason::Dependency, &["orphan-b"]);
+        add(
+            "orphan-b",
+            InstallReason::Dependency,
+            &["orphan-a", "libbar"],
+        );
+        add("tool", InstallReason::Explicit, &[]);
+
+        let unneeded: Vec<String> = find_unneeded_dependencies(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(unneeded, vec!["orphan-a", "orphan-b"]);
+    }
 }
@@ -0,0 +1,371 @@
+// src/resources/mod.rs
+
+//! Limits on the memory, parallelism and scratch disk Conary uses
+//!
+//! Small devices (a few hundred MB of RAM, a tmpfs scratch area) can run out
+//! of memory when parallel downloads, extraction buffers and a metadata
+//! parse happen at once. These settings cap each of them:
+//! - `max_parallel_downloads` - downloads and repository syncs running at once
+//! - `max_extraction_buffer_bytes` - file content held in memory while it is
+//!   hashed; larger files spill to a temporary file in the CAS directory
+//! - `tmp_dir_quota_bytes` - space downloads may take in the scratch directory
+//! - `max_threads` - size of the rayon thread pool used for parallel work
+//!
+//! Unset settings keep the defaults, which match what a normal machine
+//! needs. `RAYON_NUM_THREADS` takes precedence over `max_threads`, since rayon
+//! reads it itself. Sizes accept a `K`, `M` or `G` suffix (powers of 1024).
+
+use crate::db::models::Setting;
+use crate::error::{Error, Result};
+use crate::filesystem::DEFAULT_BUFFER_LIMIT;
+use rayon::prelude::*;
+use rusqlite::Connection;
+use std::fs;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use tracing::{debug, warn};
+
+/// Setting capping concurrent downloads and repository syncs
+pub const MAX_PARALLEL_DOWNLOADS_SETTING: &str = "max_parallel_downloads";
+
+/// Setting capping the file content held in memory during extraction
+pub const MAX_EXTRACTION_BUFFER_SETTING: &str = "max_extraction_buffer_bytes";
+
+/// Setting capping the space downloads may use in the scratch directory
+pub const TMP_QUOTA_SETTING: &str = "tmp_dir_quota_bytes";
+
+/// Setting sizing the rayon thread pool
+pub const MAX_THREADS_SETTING: &str = "max_threads";
+
+/// Every resource setting, in the order `config-get` lists them
+pub const SETTINGS: [&str; 4] = [
+    MAX_PARALLEL_DOWNLOADS_SETTING,
+    MAX_EXTRACTION_BUFFER_SETTING,
+    TMP_QUOTA_SETTING,
+    MAX_THREADS_SETTING,
+];
+
+/// Resource limits of an installation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Concurrent downloads and syncs (None keeps rayon's pool size)
+    pub max_parallel_downloads: Option<usize>,
+    /// Bytes of a file's content buffered in memory before spilling to disk
+    pub max_extraction_buffer_bytes: usize,
+    /// Bytes downloads may occupy in the scratch directory (None is unlimited)
+    pub tmp_dir_quota_bytes: Option<u64>,
+    /// Rayon thread pool size (None keeps rayon's default)
+    pub max_threads: Option<usize>,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            max_parallel_downloads: None,
+            max_extraction_buffer_bytes: DEFAULT_BUFFER_LIMIT,
+            tmp_dir_quota_bytes: None,
+            max_threads: None,
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// Load the limits from the settings table, using defaults for unset keys
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut limits = Self::default();
+        for key in SETTINGS {
+            if let Some(value) = Setting::get(conn, key)? {
+                limits.apply(key, &value)?;
+            }
+        }
+        Ok(limits)
+    }
+
+    /// Check that `value` is valid for the resource setting `key`
+    ///
+    /// Returns false when `key` is not a resource setting.
+    pub fn validate(key: &str, value: &str) -> Result<bool> {
+        if !SETTINGS.contains(&key) {
+            return Ok(false);
+        }
+        Self::default().apply(key, value)?;
+        Ok(true)
+    }
+
+    /// Default value of a resource setting, as `config-get` shows it
+    pub fn default_value(key: &str) -> Option<String> {
+        let defaults = Self::default();
+        match key {
+            MAX_PARALLEL_DOWNLOADS_SETTING => Some("unlimited".to_string()),
+            MAX_EXTRACTION_BUFFER_SETTING => Some(defaults.max_extraction_buffer_bytes.to_string()),
+            TMP_QUOTA_SETTING => Some("unlimited".to_string()),
+            MAX_THREADS_SETTING => Some("one per CPU".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Set the field for `key` from its stored value
+    fn apply(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            MAX_PARALLEL_DOWNLOADS_SETTING => {
+                self.max_parallel_downloads = Some(parse_count(key, value)?);
+            }
+            MAX_EXTRACTION_BUFFER_SETTING => {
+                self.max_extraction_buffer_bytes = parse_size(key, value)? as usize;
+            }
+            TMP_QUOTA_SETTING => self.tmp_dir_quota_bytes = Some(parse_size(key, value)?),
+            MAX_THREADS_SETTING => self.max_threads = Some(parse_count(key, value)?),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Size the global rayon pool from `max_threads`
+    ///
+    /// Does nothing when `RAYON_NUM_THREADS` is set or the setting is not,
+    /// and only takes effect before the pool is first used.
+    pub fn configure_thread_pool(&self) {
+        if std::env::var_os("RAYON_NUM_THREADS").is_some() {
+            return;
+        }
+        let Some(threads) = self.max_threads else {
+            return;
+        };
+
+        match rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+        {
+            Ok(()) => debug!("Using {} worker threads", threads),
+            Err(e) => debug!("Thread pool already configured: {}", e),
+        }
+    }
+
+    /// Fail if `incoming` more bytes would push `dir` over the scratch quota
+    pub fn check_tmp_quota(&self, dir: &Path, incoming: u64) -> Result<()> {
+        let Some(quota) = self.tmp_dir_quota_bytes else {
+            return Ok(());
+        };
+
+        let used = dir_size(dir)?;
+        if used + incoming > quota {
+            return Err(Error::IoError(format!(
+                "Downloading {} bytes into {} would exceed the {} quota of {} bytes ({} bytes in use)",
+                incoming,
+                dir.display(),
+                TMP_QUOTA_SETTING,
+                quota,
+                used
+            )));
+        }
+        Ok(())
+    }
+
+    /// Map `f` over `items` in parallel, with at most `max_parallel_downloads` running at once
+    pub fn par_map_downloads<T, R, F>(&self, items: &[T], f: F) -> Vec<R>
+    where
+        T: Sync,
+        R: Send,
+        F: Fn(&T) -> R + Sync,
+    {
+        let Some(limit) = self.max_parallel_downloads else {
+            return items.par_iter().map(&f).collect();
+        };
+
+        let slots = Semaphore::new(limit);
+        items
+            .par_iter()
+            .map(|item| {
+                let _permit = slots.acquire();
+                f(item)
+            })
+            .collect()
+    }
+}
+
+/// Counting semaphore bounding how many callers run a section at once
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A taken semaphore slot, given back when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// Create a semaphore with `permits` slots (at least one)
+    pub fn new(permits: usize) -> Self {
+        Self {
+            available: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free slot and take it
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available == 0 {
+            available = self
+                .released
+                .wait(available)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= 1;
+        SemaphorePermit { semaphore: self }
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        let mut available = self
+            .semaphore
+            .available
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *available += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+/// Parse a positive count such as `max_parallel_downloads`
+fn parse_count(key: &str, value: &str) -> Result<usize> {
+    match value.trim().parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(Error::ParseError(format!(
+            "{} must be a positive number, got '{}'",
+            key, value
+        ))),
+    }
+}
+
+/// Parse a byte size with an optional K, M or G suffix
+fn parse_size(key: &str, value: &str) -> Result<u64> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1024),
+        Some((i, 'M' | 'm')) => (&value[..i], 1024 * 1024),
+        Some((i, 'G' | 'g')) => (&value[..i], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| {
+            Error::ParseError(format!(
+                "{} must be a size in bytes (optionally with K, M or G), got '{}'",
+                key, value
+            ))
+        })
+}
+
+/// Total size of the regular files under `dir` (0 if it does not exist)
+fn dir_size(dir: &Path) -> Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut total = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            match entry.metadata() {
+                Ok(metadata) => total += metadata.len(),
+                // Downloads finishing concurrently may vanish mid-walk
+                Err(e) => warn!("Skipping {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_limits_from_settings() {
+        let conn = Connection::open_in_memory().unwrap();
+        schema::migrate(&conn).unwrap();
+        assert_eq!(
+            ResourceLimits::load(&conn).unwrap(),
+            ResourceLimits::default()
+        );
+
+        Setting::set(&conn, MAX_PARALLEL_DOWNLOADS_SETTING, "2").unwrap();
+        Setting::set(&conn, MAX_EXTRACTION_BUFFER_SETTING, "1M").unwrap();
+        Setting::set(&conn, TMP_QUOTA_SETTING, "512M").unwrap();
+        let limits = ResourceLimits::load(&conn).unwrap();
+        assert_eq!(limits.max_parallel_downloads, Some(2));
+        assert_eq!(limits.max_extraction_buffer_bytes, 1024 * 1024);
+        assert_eq!(limits.tmp_dir_quota_bytes, Some(512 * 1024 * 1024));
+        assert_eq!(limits.max_threads, None);
+
+        assert!(ResourceLimits::validate(MAX_THREADS_SETTING, "4").unwrap());
+        assert!(ResourceLimits::validate(MAX_THREADS_SETTING, "0").is_err());
+        assert!(ResourceLimits::validate(TMP_QUOTA_SETTING, "lots").is_err());
+        assert!(!ResourceLimits::validate("objects_path", "/srv").unwrap());
+    }
+
+    #[test]
+    fn test_download_semaphore_caps_concurrency() {
+        let limits = ResourceLimits {
+            max_parallel_downloads: Some(2),
+            ..ResourceLimits::default()
+        };
+        let active = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+
+        let items: Vec<usize> = (0..16).collect();
+        let results = limits.par_map_downloads(&items, |&i| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            active.fetch_sub(1, Ordering::SeqCst);
+            i * 2
+        });
+
+        assert_eq!(results, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+        assert!(
+            peak.load(Ordering::SeqCst) <= 2,
+            "peak {}",
+            peak.load(Ordering::SeqCst)
+        );
+    }
+
+    #[test]
+    fn test_tmp_quota() {
+        let temp = TempDir::new().unwrap();
+        fs::create_dir(temp.path().join("sub")).unwrap();
+        fs::write(temp.path().join("sub/partial"), vec![0u8; 600]).unwrap();
+
+        let limits = ResourceLimits {
+            tmp_dir_quota_bytes: Some(1000),
+            ..ResourceLimits::default()
+        };
+        limits.check_tmp_quota(temp.path(), 400).unwrap();
+        let err = limits.check_tmp_quota(temp.path(), 401).unwrap_err();
+        assert!(err.to_string().contains(TMP_QUOTA_SETTING), "{}", err);
+
+        // A scratch directory that does not exist yet holds nothing
+        limits
+            .check_tmp_quota(&temp.path().join("missing"), 1000)
+            .unwrap();
+        ResourceLimits::default()
+            .check_tmp_quota(temp.path(), u64::MAX / 2)
+            .unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+// src/rollback/mod.rs
+
+//! Planning and applying the rollback of a changeset
+//!
+//! A [`RollbackPlan`] combines what a changeset recorded in its file history
+//! with the state of the install root right now:
+//! - packages the changeset installed, which the rollback removes
+//! - files it added or replaced, which are removed, or put back to the
+//!   content they had before when that content is still in the CAS
+//! - configuration files whose local version was kept, where only the unused
+//!   new version is removed
+//! - directories the changeset replaced with a file, which are created
+//!   again or moved back from where they were saved, and files it replaced
+//!   with a directory, which are put back once the directory is empty
+//! - directories the removed packages owned, which go once their files
+//!   are gone if they are empty and no other package owns them
+//! - files changed on disk since the changeset, whose local changes the
+//!   rollback will clobber
+//! - installed packages that depend on a package the rollback removes
+//!
+//! A staged changeset never reached the root, so its rollback only changes
+//! the database; the caller discards its pending tree (see
+//! [`crate::staged::discard`]).
+//!
+//! `rollback --dry-run` prints the plan and the real rollback executes the
+//! same plan, so the preview and what happens cannot drift apart.
+
+use crate::db::models::{Changeset, ChangesetStatus, RemovalReason, RemovedTrove, Trove};
+use crate::error::{Error, Result};
+use crate::filesystem::{CONFIG_NEW_SUFFIX, FileDeployer, SAVED_DIR_SUFFIX};
+use crate::query::changeset_report;
+use crate::resolver::Resolver;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+/// What the rollback does to one path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum FileAction {
+    /// The changeset put the file there; it goes
+    Remove,
+    /// The changeset replaced the file; its earlier content is deployed again
+    Restore { hash: String },
+    /// The local config was kept; the unused new version next to it goes
+    RemoveConfigNew,
+    /// The changeset replaced a directory with a file; the directory comes
+    /// back, with the contents moved aside to `saved` if there were any
+    RestoreDirectory { saved: Option<String> },
+    /// The changeset replaced a file with a directory; the directory goes
+    /// once empty and the file is deployed again if its content is in the CAS
+    RestoreFile { hash: Option<String> },
+}
+
+/// One path the rollback touches
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedFile {
+    pub path: String,
+    #[serde(flatten)]
+    pub action: FileAction,
+    /// Hash the changeset deployed, if it is still recorded
+    pub installed_hash: Option<String>,
+    /// The file on disk no longer has the content the changeset deployed,
+    /// so the rollback clobbers local changes; false when there is nothing
+    /// on disk or no hash to compare with
+    pub modified: bool,
+}
+
+/// A package the rollback removes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedTrove {
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+}
+
+/// An installed package left depending on a package the rollback removes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StrandedDependent {
+    pub package: String,
+    pub needs: String,
+}
+
+/// Everything a rollback of one changeset will do
+#[derive(Debug, Clone, Serialize)]
+pub struct RollbackPlan {
+    pub changeset_id: i64,
+    pub description: String,
+    pub troves: Vec<PlannedTrove>,
+    pub files: Vec<PlannedFile>,
+    pub stranded: Vec<StrandedDependent>,
+    /// Directories of the removed packages, deepest first
+    pub dirs: Vec<String>,
+    /// The changeset is staged, and its files are only in its pending tree
+    pub staged: bool,
+}
+
+impl RollbackPlan {
+    /// Plan the rollback of `changeset_id` against the root `deployer` writes to
+    ///
+    /// Fails for changesets that were never applied or are already rolled
+    /// back, for protected changesets unless `force` is set, and for
+    /// changesets that installed no packages.
+    pub fn build(
+        conn: &Connection,
+        deployer: &FileDeployer,
+        changeset_id: i64,
+        force: bool,
+    ) -> Result<Self> {
+        let report = changeset_report(conn, changeset_id)?;
+        let changeset = report.changeset;
+        match changeset.status {
+            ChangesetStatus::RolledBack => {
+                return Err(Error::ConflictError(format!(
+                    "Changeset {} is already rolled back",
+                    changeset_id
+                )));
+            }
+            ChangesetStatus::Pending => {
+                return Err(Error::ConflictError(format!(
+                    "Cannot rollback pending changeset {}",
+                    changeset_id
+                )));
+            }
+            ChangesetStatus::Applied | ChangesetStatus::Staged => {}
+        }
+        let staged = changeset.status == ChangesetStatus::Staged;
+        if changeset.protected && !force {
+            return Err(Error::ConflictError(format!(
+                "Changeset {} is protected; use --force to roll it back",
+                changeset_id
+            )));
+        }
+
+        let installed = Trove::find_by_changeset(conn, changeset_id)?;
+        let mut dirs = Vec::new();
+        if !staged {
+            for trove in &installed {
+                dirs.extend(crate::installer::trove_dirs(conn, trove)?);
+            }
+            dirs.sort_by(|a, b| b.cmp(a));
+            dirs.dedup();
+        }
+        let troves: Vec<PlannedTrove> = installed
+            .into_iter()
+            .map(|trove| PlannedTrove {
+                name: trove.name,
+                version: trove.version,
+                architecture: trove.architecture,
+            })
+            .collect();
+        if troves.is_empty() {
+            return Err(Error::NotFoundError(
+                "No troves found for this changeset. Cannot rollback Remove operations yet."
+                    .to_string(),
+            ));
+        }
+
+        // The files of a staged changeset never reached the root
+        let changes = if staged { Vec::new() } else { report.files };
+        let mut files = Vec::new();
+        for change in changes {
+            let (action, on_disk) = match change.action.as_str() {
+                "add" => (FileAction::Remove, change.path.clone()),
+                "modify" => match change.previous_hash {
+                    Some(hash) if deployer.cas().exists(&hash) => {
+                        (FileAction::Restore { hash }, change.path.clone())
+                    }
+                    _ => (FileAction::Remove, change.path.clone()),
+                },
+                "config-kept" => (
+                    FileAction::RemoveConfigNew,
+                    format!("{}{}", change.path, CONFIG_NEW_SUFFIX),
+                ),
+                "dir-to-file" => (
+                    FileAction::RestoreDirectory { saved: None },
+                    change.path.clone(),
+                ),
+                "dir-saved" => {
+                    let saved = format!("{}{}", change.path, SAVED_DIR_SUFFIX);
+                    (
+                        FileAction::RestoreDirectory { saved: Some(saved) },
+                        change.path.clone(),
+                    )
+                }
+                "file-to-dir" => {
+                    let hash = change
+                        .previous_hash
+                        .filter(|hash| deployer.cas().exists(hash));
+                    (FileAction::RestoreFile { hash }, change.path.clone())
+                }
+                _ => continue,
+            };
+            let modified = match &change.sha256_hash {
+                Some(hash) => {
+                    deployer.file_exists(&on_disk) && !deployer.verify_file(&on_disk, hash)?
+                }
+                None => false,
+            };
+            files.push(PlannedFile {
+                path: change.path,
+                action,
+                installed_hash: change.sha256_hash,
+                modified,
+            });
+        }
+
+        let removed: HashSet<&str> = troves.iter().map(|trove| trove.name.as_str()).collect();
+        let resolver = Resolver::new(conn)?;
+        let mut stranded = Vec::new();
+        for trove in &troves {
+            for package in resolver.check_removal(&trove.name)? {
+                if !removed.contains(package.as_str()) {
+                    stranded.push(StrandedDependent {
+                        package,
+                        needs: trove.name.clone(),
+                    });
+                }
+            }
+        }
+        stranded.sort_by(|a, b| (&a.package, &a.needs).cmp(&(&b.package, &b.needs)));
+        stranded.dedup();
+
+        Ok(Self {
+            changeset_id,
+            description: changeset.description,
+            troves,
+            files,
+            stranded,
+            dirs,
+            staged,
+        })
+    }
+
+    /// Files whose local changes the rollback overwrites or deletes
+    pub fn clobbered(&self) -> impl Iterator<Item = &PlannedFile> {
+        self.files.iter().filter(|file| file.modified)
+    }
+
+    /// Roll the changeset back as planned, returning the rollback changeset
+    ///
+    /// The database side runs in one transaction; files are removed and
+    /// restored once it has committed. Directories and files the changeset
+    /// swapped for one another are put back last, when the paths the
+    /// changeset added in their place are gone.
+    pub fn execute(&self, conn: &mut Connection, deployer: &FileDeployer) -> Result<i64> {
+        let rollback_changeset_id = crate::db::transaction(conn, |tx| {
+            let mut rollback_changeset = Changeset::new(format!(
+                "Rollback of changeset {} ({})",
+                self.changeset_id, self.description
+            ));
+            let rollback_changeset_id = rollback_changeset.insert(tx)?;
+
+            for trove in Trove::find_by_changeset(tx, self.changeset_id)? {
+                let removed_size = crate::installer::trove_file_size(tx, Some(&trove))?;
+                Changeset::add_sizes(tx, rollback_changeset_id, 0, -removed_size)?;
+                RemovedTrove::new(rollback_changeset_id, &trove, RemovalReason::RolledBack)
+                    .insert(tx)?;
+                crate::db::models::FileEntry::hand_back_shared(tx, trove.id.unwrap())?;
+                Trove::delete(tx, trove.id.unwrap())?;
+                info!("Removed {} version {}", trove.name, trove.version);
+            }
+
+            rollback_changeset.update_status(tx, ChangesetStatus::Applied)?;
+            tx.execute(
+                "UPDATE changesets
+                 SET status = 'rolled_back',
+                     rolled_back_at = CURRENT_TIMESTAMP,
+                     reversed_by_changeset_id = ?1
+                 WHERE id = ?2",
+                [rollback_changeset_id, self.changeset_id],
+            )?;
+            Ok(rollback_changeset_id)
+        })?;
+
+        for dependent in &self.stranded {
+            warn!(
+                "{} still depends on {}, which was rolled back",
+                dependent.package, dependent.needs
+            );
+        }
+        for file in &self.files {
+            if file.modified {
+                warn!("Discarding local changes to {}", file.path);
+            }
+            match &file.action {
+                FileAction::Remove => deployer.remove_file(&file.path)?,
+                FileAction::Restore { hash } => {
+                    let permissions = file_mode(deployer, &file.path);
+                    deployer.deploy_file(&file.path, hash, permissions)?;
+                }
+                FileAction::RemoveConfigNew => {
+                    deployer.remove_file(&format!("{}{}", file.path, CONFIG_NEW_SUFFIX))?;
+                }
+                FileAction::RestoreDirectory { .. } | FileAction::RestoreFile { .. } => {}
+            }
+        }
+        for file in &self.files {
+            match &file.action {
+                FileAction::RestoreDirectory { saved: Some(saved) } => {
+                    deployer.rename(saved, &file.path)?
+                }
+                FileAction::RestoreDirectory { saved: None } => deployer.create_dir(&file.path)?,
+                FileAction::RestoreFile { hash } => {
+                    if let Err(e) = deployer.remove_empty_tree(&file.path) {
+                        warn!("Leaving {} in place, it is not empty: {}", file.path, e);
+                        continue;
+                    }
+                    match hash {
+                        Some(hash) => deployer.deploy_file(&file.path, hash, 0o644)?,
+                        None => warn!(
+                            "Content of {} is gone from the CAS; it cannot be restored",
+                            file.path
+                        ),
+                    }
+                }
+                _ => {}
+            }
+        }
+        crate::installer::remove_unowned_dirs(conn, deployer, &self.dirs)?;
+        Ok(rollback_changeset_id)
+    }
+}
+
+/// Mode of the file being replaced, so a restored file keeps it
+fn file_mode(deployer: &FileDeployer, path: &str) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    let target = crate::paths::under_root(deployer.install_root(), path);
+    std::fs::symlink_metadata(target)
+        .map(|metadata| metadata.permissions().mode() & 0o7777)
+        .unwrap_or(0o644)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::db::models::{FileContents, TroveType};
+
+    #[test]
+    fn test_rollback_plan_flags_local_changes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("conary.db");
+        db::init(db_path.to_str().unwrap()).unwrap();
+        let mut conn = db::open(db_path.to_str().unwrap()).unwrap();
+        let deployer = FileDeployer::new(
+            temp_dir.path().join("objects"),
+            temp_dir.path().join("root"),
+        )
+        .unwrap();
+
+        let tool = deployer.cas().store(b"tool v1").unwrap();
+        let conf = deployer.cas().store(b"setting = 1").unwrap();
+        let changeset_id = db::transaction(&mut conn, |tx| {
+            let mut changeset = Changeset::new("Install tool-1.0".to_string());
+            let changeset_id = changeset.insert(tx)?;
+            let mut trove = Trove::new("tool".to_string(), "1.0".to_string(), TroveType::Package);
+            trove.installed_by_changeset_id = Some(changeset_id);
+            trove.insert(tx)?;
+            for hash in [&tool, &conf] {
+                FileContents::new(hash.clone(), 0).insert(tx)?;
+            }
+            tx.execute(
+                "INSERT INTO file_history (changeset_id, path, sha256_hash, action) VALUES (?1, '/usr/bin/tool', ?2, 'add'),
+                     (?1, '/etc/tool.conf', ?3, 'add')",
+                rusqlite::params![changeset_id, &tool, &conf],
+            )?;
+            changeset.update_status(tx, ChangesetStatus::Applied)?;
+            Ok(changeset_id)
+        })
+        .unwrap();
+        deployer.deploy_file("/usr/bin/tool", &tool, 0o755).unwrap();
+        deployer
+            .deploy_file("/etc/tool.conf", &conf, 0o644)
+            .unwrap();
+        std::fs::write(temp_dir.path().join("root/etc/tool.conf"), "setting = 2").unwrap();
+
+        let plan = RollbackPlan::build(&conn, &deployer, changeset_id, false).unwrap();
+        assert_eq!(plan.troves[0].name, "tool");
+        let clobbered: Vec<&str> = plan.clobbered().map(|file| file.path.as_str()).collect();
+        assert_eq!(clobbered, ["/etc/tool.conf"]);
+        assert!(
+            plan.files
+                .iter()
+                .all(|file| file.action == FileAction::Remove)
+        );
+
+        plan.execute(&mut conn, &deployer).unwrap();
+        assert!(!deployer.file_exists("/usr/bin/tool") && !deployer.file_exists("/etc/tool.conf"));
+        let err = RollbackPlan::build(&conn, &deployer, changeset_id, false).unwrap_err();
+        assert!(err.to_string().contains("already rolled back"), "{}", err);
+    }
+}
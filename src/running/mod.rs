@@ -0,0 +1,394 @@
+// src/running/mod.rs
+
+//! Detection of running processes that use files an update will replace
+//!
+//! Replacing an executable or shared library while a process has it mapped
+//! can crash long-running services (or leave them running stale code). Before
+//! an update deploys anything, the process table is walked once and every
+//! process whose executable or memory mappings include a file in the plan is
+//! reported.
+//!
+//! The walk reads /proc and is only implemented on Linux; on other platforms
+//! it finds no processes. The walk sits behind `ProcessSource` so tests can
+//! substitute a synthetic process table.
+
+use crate::error::Result;
+use std::cell::OnceCell;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tracing::debug;
+
+/// What to do when files being replaced are in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunningCheck {
+    /// List the affected processes and continue
+    Warn,
+    /// List the affected processes and ask before continuing
+    Confirm,
+    /// List the affected processes and refuse to continue
+    Abort,
+}
+
+impl RunningCheck {
+    pub fn as_str(&self) -> &str {
+        match self {
+            RunningCheck::Warn => "warn",
+            RunningCheck::Confirm => "confirm",
+            RunningCheck::Abort => "abort",
+        }
+    }
+}
+
+impl FromStr for RunningCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(RunningCheck::Warn),
+            "confirm" => Ok(RunningCheck::Confirm),
+            "abort" => Ok(RunningCheck::Abort),
+            _ => Err(format!(
+                "Invalid running check mode: {} (expected warn, confirm or abort)",
+                s
+            )),
+        }
+    }
+}
+
+/// A running process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    /// Command name from /proc/<pid>/comm
+    pub comm: String,
+    /// systemd unit the process belongs to, if it could be determined
+    pub unit: Option<String>,
+}
+
+/// A process and the files it uses (its executable and mapped files)
+#[derive(Debug, Clone)]
+pub struct ProcessFiles {
+    pub process: ProcessInfo,
+    pub files: Vec<String>,
+}
+
+/// Source of the process table
+pub trait ProcessSource {
+    /// List running processes with the files each one uses
+    fn processes(&self) -> Result<Vec<ProcessFiles>>;
+}
+
+/// Process table read from procfs
+pub struct ProcFs {
+    proc_root: PathBuf,
+}
+
+impl Default for ProcFs {
+    fn default() -> Self {
+        Self {
+            proc_root: PathBuf::from("/proc"),
+        }
+    }
+}
+
+impl ProcFs {
+    /// Read processes from a procfs mounted somewhere other than /proc
+    pub fn new(proc_root: impl Into<PathBuf>) -> Self {
+        Self {
+            proc_root: proc_root.into(),
+        }
+    }
+}
+
+impl ProcessSource for ProcFs {
+    #[cfg(target_os = "linux")]
+    fn processes(&self) -> Result<Vec<ProcessFiles>> {
+        let mut processes = Vec::new();
+
+        for entry in std::fs::read_dir(&self.proc_root)? {
+            let entry = entry?;
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let dir = entry.path();
+
+            // Processes can exit mid-walk and other users' maps are unreadable;
+            // both are skipped rather than failing the scan
+            let Ok(maps) = std::fs::read_to_string(dir.join("maps")) else {
+                debug!("Cannot read maps of pid {}, skipping", pid);
+                continue;
+            };
+            let mut files = parse_maps(&maps);
+            if let Ok(exe) = std::fs::read_link(dir.join("exe")) {
+                files.push(strip_deleted(&exe.to_string_lossy()).to_string());
+            }
+            files.sort();
+            files.dedup();
+
+            let comm = std::fs::read_to_string(dir.join("comm"))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_default();
+            let unit = std::fs::read_to_string(dir.join("cgroup"))
+                .ok()
+                .and_then(|cgroup| unit_from_cgroup(&cgroup));
+
+            processes.push(ProcessFiles {
+                process: ProcessInfo { pid, comm, unit },
+                files,
+            });
+        }
+
+        Ok(processes)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn processes(&self) -> Result<Vec<ProcessFiles>> {
+        debug!(
+            "Running process detection is only supported on Linux ({:?})",
+            self.proc_root
+        );
+        Ok(Vec::new())
+    }
+}
+
+/// A process using files that are about to be replaced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InUse {
+    pub process: ProcessInfo,
+    /// Files from the plan this process uses
+    pub files: Vec<String>,
+}
+
+/// One walk of the process table, shared by every check in an operation
+pub struct ProcessScan<'a> {
+    source: &'a dyn ProcessSource,
+    snapshot: OnceCell<Vec<ProcessFiles>>,
+}
+
+impl<'a> ProcessScan<'a> {
+    /// Create a scan; the process table is not read until first needed
+    pub fn new(source: &'a dyn ProcessSource) -> Self {
+        Self {
+            source,
+            snapshot: OnceCell::new(),
+        }
+    }
+
+    /// Find processes using any of the given absolute paths
+    pub fn users_of(&self, paths: &[String]) -> Result<Vec<InUse>> {
+        if paths.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let snapshot = match self.snapshot.get() {
+            Some(snapshot) => snapshot,
+            None => {
+                let processes = self.source.processes()?;
+                debug!("Scanned {} running processes", processes.len());
+                self.snapshot.get_or_init(|| processes)
+            }
+        };
+
+        let wanted: HashSet<&str> = paths.iter().map(String::as_str).collect();
+        let in_use = snapshot
+            .iter()
+            .filter_map(|proc_files| {
+                let files: Vec<String> = proc_files
+                    .files
+                    .iter()
+                    .filter(|file| wanted.contains(file.as_str()))
+                    .cloned()
+                    .collect();
+                (!files.is_empty()).then(|| InUse {
+                    process: proc_files.process.clone(),
+                    files,
+                })
+            })
+            .collect();
+
+        Ok(in_use)
+    }
+}
+
+/// Drop the marker the kernel appends to paths of unlinked files
+fn strip_deleted(path: &str) -> &str {
+    path.strip_suffix(" (deleted)").unwrap_or(path)
+}
+
+/// Extract the file paths from /proc/<pid>/maps content
+///
+/// Anonymous and pseudo mappings such as `[heap]` are skipped.
+pub(crate) fn parse_maps(content: &str) -> Vec<String> {
+    let mut files: Vec<String> = content
+        .lines()
+        .filter_map(|line| {
+            // address perms offset dev inode pathname (pathname may contain spaces)
+            let path = line.splitn(6, char::is_whitespace).nth(5)?.trim_start();
+            path.starts_with('/')
+                .then(|| strip_deleted(path).to_string())
+        })
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Find the systemd unit in /proc/<pid>/cgroup content
+pub(crate) fn unit_from_cgroup(content: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let path = line.rsplit(':').next()?;
+        path.split('/')
+            .rev()
+            .find(|part| part.ends_with(".service") || part.ends_with(".scope"))
+            .map(str::to_string)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct FakeProcs {
+        processes: Vec<ProcessFiles>,
+        scans: Cell<usize>,
+    }
+
+    impl ProcessSource for FakeProcs {
+        fn processes(&self) -> Result<Vec<ProcessFiles>> {
+            self.scans.set(self.scans.get() + 1);
+            Ok(self.processes.clone())
+        }
+    }
+
+    fn process(pid: u32, comm: &str, maps: &str) -> ProcessFiles {
+        ProcessFiles {
+            process: ProcessInfo {
+                pid,
+                comm: comm.to_string(),
+                unit: None,
+            },
+            files: parse_maps(maps),
+        }
+    }
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = "\
+55d0c0a00000-55d0c0a28000 r--p 00000000 fd:01 1048601                    /usr/sbin/sshd
+7f2b1c000000-7f2b1c021000 rw-p 00000000 00:00 0
+7f2b1d200000-7f2b1d228000 r--p 00000000 fd:01 1050121                    /usr/lib/libc.so.6
+7f2b1d228000-7f2b1d3bd000 r-xp 00028000 fd:01 1050121                    /usr/lib/libc.so.6
+7f2b1d400000-7f2b1d401000 r--p 00000000 fd:01 1050200                    /usr/lib/libold.so (deleted)
+7f2b1d500000-7f2b1d501000 r--p 00000000 fd:01 1050300                    /opt/my app/lib.so
+7ffd5e9f0000-7ffd5ea11000 rw-p 00000000 00:00 0                          [stack]
+";
+        assert_eq!(
+            parse_maps(maps),
+            vec![
+                "/opt/my app/lib.so",
+                "/usr/lib/libc.so.6",
+                "/usr/lib/libold.so",
+                "/usr/sbin/sshd"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unit_from_cgroup() {
+        assert_eq!(
+            unit_from_cgroup("0::/system.slice/sshd.service\n"),
+            Some("sshd.service".to_string())
+        );
+        assert_eq!(
+            unit_from_cgroup("0::/user.slice/user-1000.slice/session-2.scope\n"),
+            Some("session-2.scope".to_string())
+        );
+        assert_eq!(unit_from_cgroup("0::/\n"), None);
+    }
+
+    #[test]
+    fn test_users_of_scans_once() {
+        let source = FakeProcs {
+            processes: vec![
+                process(
+                    1,
+                    "systemd",
+                    "0-1 r-xp 0 fd:01 1 /usr/lib/systemd/systemd\n0-1 r-xp 0 fd:01 2 /usr/lib/libc.so.6\n",
+                ),
+                process(
+                    42,
+                    "sshd",
+                    "0-1 r-xp 0 fd:01 3 /usr/sbin/sshd\n0-1 r-xp 0 fd:01 2 /usr/lib/libc.so.6\n",
+                ),
+                process(99, "bash", "0-1 r-xp 0 fd:01 4 /usr/bin/bash\n"),
+            ],
+            scans: Cell::new(0),
+        };
+        let scan = ProcessScan::new(&source);
+
+        // Nothing in the plan means no scan at all
+        assert!(scan.users_of(&[]).unwrap().is_empty());
+        assert_eq!(source.scans.get(), 0);
+
+        let glibc = scan.users_of(&["/usr/lib/libc.so.6".to_string()]).unwrap();
+        let pids: Vec<u32> = glibc.iter().map(|u| u.process.pid).collect();
+        assert_eq!(pids, vec![1, 42]);
+
+        let sshd = scan
+            .users_of(&["/usr/sbin/sshd".to_string(), "/usr/bin/unused".to_string()])
+            .unwrap();
+        assert_eq!(sshd.len(), 1);
+        assert_eq!(sshd[0].process.comm, "sshd");
+        assert_eq!(sshd[0].files, vec!["/usr/sbin/sshd".to_string()]);
+
+        // Both checks share one walk of the process table
+        assert_eq!(source.scans.get(), 1);
+    }
+
+    #[test]
+    fn test_running_check_from_str() {
+        assert_eq!("warn".parse::<RunningCheck>().unwrap(), RunningCheck::Warn);
+        assert_eq!("abort".parse::<RunningCheck>().unwrap().as_str(), "abort");
+        assert!("maybe".parse::<RunningCheck>().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_procfs_walk() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let pid_dir = proc_root.path().join("1234");
+        std::fs::create_dir_all(&pid_dir).unwrap();
+        std::fs::write(
+            pid_dir.join("maps"),
+            "0-1 r-xp 0 fd:01 1 /usr/lib/libz.so.1\n",
+        )
+        .unwrap();
+        std::fs::write(pid_dir.join("comm"), "daemon\n").unwrap();
+        std::fs::write(pid_dir.join("cgroup"), "0::/system.slice/daemon.service\n").unwrap();
+        std::os::unix::fs::symlink("/usr/bin/daemon", pid_dir.join("exe")).unwrap();
+        // Non-pid entries and unreadable processes are ignored
+        std::fs::create_dir_all(proc_root.path().join("self-test")).unwrap();
+        std::fs::create_dir_all(proc_root.path().join("77")).unwrap();
+
+        let processes = ProcFs::new(proc_root.path()).processes().unwrap();
+        assert_eq!(processes.len(), 1);
+        assert_eq!(
+            processes[0].process,
+            ProcessInfo {
+                pid: 1234,
+                comm: "daemon".to_string(),
+                unit: Some("daemon.service".to_string()),
+            }
+        );
+        assert_eq!(
+            processes[0].files,
+            vec!["/usr/bin/daemon", "/usr/lib/libz.so.1"]
+        );
+    }
+}
@@ -0,0 +1,764 @@
+// src/scriptlet/mod.rs
+
+//! Package scriptlet execution
+//!
+//! Runs the maintainer scripts shipped with RPM, DEB and Arch packages around
+//! file deployment and removal. Each format has its own calling convention
+//! (RPM passes an instance count, Debian an action word, Arch calls hook
+//! functions with versions), so the arguments are derived from the package
+//! format the scriptlet came from.
+//!
+//! How a script is launched is up to a [`ScriptletRunner`]. The default
+//! [`DirectRunner`] chroots into the install root when it is not "/". The
+//! [`SandboxRunner`] uses bubblewrap to run the script with the root bind
+//! mounted, no network and a private /tmp. It is enabled with
+//! `--sandbox-scripts` or the `scriptlet_sandbox` setting.
+
+use crate::db::models::{Changeset, ChangesetStatus, ScriptletEntry, ScriptletRun, Setting};
+use crate::error::{Error, Result};
+use crate::packages::PackageFormatType;
+use crate::packages::traits::{Scriptlet, ScriptletPhase};
+use rusqlite::Connection;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, info, warn};
+
+/// The package operation a scriptlet runs for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Install,
+    Upgrade,
+    Remove,
+}
+
+/// Setting that turns on the sandbox runner when set to "true"
+pub const SANDBOX_SETTING: &str = "scriptlet_sandbox";
+
+/// A scriptlet ready to launch, with paths as seen on the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation<'a> {
+    /// Install root the script operates on
+    pub root: &'a Path,
+    /// Interpreter, e.g. "/bin/sh"
+    pub program: &'a str,
+    /// Interpreter arguments from the scriptlet header
+    pub interpreter_args: Vec<&'a str>,
+    /// Script file under `<root>/tmp`, None for interpreter-only scriptlets
+    pub script: Option<&'a Path>,
+    /// Arguments after the script, per the package format's convention
+    pub args: &'a [String],
+}
+
+impl Invocation<'_> {
+    /// The script path as seen from inside the install root
+    fn script_in_root(&self) -> Option<PathBuf> {
+        self.script
+            .map(|script| PathBuf::from("/tmp").join(script.file_name().unwrap_or_default()))
+    }
+}
+
+/// Launches scriptlets
+pub trait ScriptletRunner: std::fmt::Debug {
+    /// Name recorded with each scriptlet run
+    fn name(&self) -> &'static str;
+
+    /// Run the scriptlet and return its exit code, -1 if killed by a signal
+    fn run(&self, invocation: &Invocation) -> Result<i32>;
+}
+
+/// Run a command with stdin closed and map its status to an exit code
+fn exit_code_of(mut command: Command, program: &str) -> Result<i32> {
+    let status = command
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| Error::ScriptletError(format!("Failed to run {}: {}", program, e)))?;
+    Ok(status.code().unwrap_or(-1))
+}
+
+/// Runs scriptlets directly, chrooted into the install root unless it is "/"
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectRunner;
+
+impl ScriptletRunner for DirectRunner {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    fn run(&self, invocation: &Invocation) -> Result<i32> {
+        let chroot = invocation.root != Path::new("/");
+        let mut command = if chroot {
+            let mut command = Command::new("chroot");
+            command.arg(invocation.root).arg(invocation.program);
+            command
+        } else {
+            Command::new(invocation.program)
+        };
+        command.args(&invocation.interpreter_args);
+        if chroot {
+            command.args(invocation.script_in_root());
+        } else {
+            command.args(invocation.script);
+        }
+        command.args(invocation.args);
+        exit_code_of(command, invocation.program)
+    }
+}
+
+/// Runs scriptlets under bubblewrap
+///
+/// The install root is bind mounted as `/` with fresh /dev and /proc, a
+/// tmpfs on /tmp holding only the script, and no network, IPC or PID
+/// namespace sharing with the host.
+#[derive(Debug, Clone)]
+pub struct SandboxRunner {
+    bwrap: PathBuf,
+}
+
+impl SandboxRunner {
+    /// Use the bwrap binary at the given path
+    pub fn new(bwrap: impl Into<PathBuf>) -> Self {
+        Self {
+            bwrap: bwrap.into(),
+        }
+    }
+
+    /// Find bwrap on PATH
+    pub fn detect() -> Option<Self> {
+        let path = std::env::var_os("PATH")?;
+        std::env::split_paths(&path)
+            .map(|dir| dir.join("bwrap"))
+            .find(|candidate| candidate.is_file())
+            .map(Self::new)
+    }
+
+    /// Arguments passed to bwrap
+    fn arguments(&self, invocation: &Invocation) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec!["--bind".into(), invocation.root.into(), "/".into()];
+        args.extend(
+            [
+                "--dev",
+                "/dev",
+                "--proc",
+                "/proc",
+                "--tmpfs",
+                "/tmp",
+                "--unshare-net",
+                "--unshare-ipc",
+                "--unshare-pid",
+                "--die-with-parent",
+            ]
+            .map(OsString::from),
+        );
+
+        let inside = invocation.script_in_root();
+        if let (Some(script), Some(inside)) = (invocation.script, &inside) {
+            args.extend(["--ro-bind".into(), script.into(), inside.into()]);
+        }
+        args.push(invocation.program.into());
+        args.extend(invocation.interpreter_args.iter().map(OsString::from));
+        args.extend(inside.map(OsString::from));
+        args.extend(invocation.args.iter().map(OsString::from));
+        args
+    }
+}
+
+impl ScriptletRunner for SandboxRunner {
+    fn name(&self) -> &'static str {
+        "sandbox"
+    }
+
+    fn run(&self, invocation: &Invocation) -> Result<i32> {
+        let mut command = Command::new(&self.bwrap);
+        command.args(self.arguments(invocation));
+        exit_code_of(command, &self.bwrap.to_string_lossy())
+    }
+}
+
+/// Pick the scriptlet runner for an operation
+///
+/// The sandbox is used when `sandbox` is set or the [`SANDBOX_SETTING`] is
+/// "true". Without bubblewrap on PATH this warns and falls back to running
+/// scripts directly.
+pub fn select_runner(conn: &Connection, sandbox: bool) -> Result<Box<dyn ScriptletRunner>> {
+    let sandbox = sandbox || Setting::get(conn, SANDBOX_SETTING)?.as_deref() == Some("true");
+    if !sandbox {
+        return Ok(Box::new(DirectRunner));
+    }
+
+    match SandboxRunner::detect() {
+        Some(runner) => Ok(Box::new(runner)),
+        None => {
+            warn!(
+                "Scriptlet sandbox requested but bwrap was not found on PATH; running scriptlets directly"
+            );
+            Ok(Box::new(DirectRunner))
+        }
+    }
+}
+
+/// Everything needed to invoke a scriptlet
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptletContext<'a> {
+    /// Install root the scripts operate on
+    pub root: &'a Path,
+    /// Launches the scripts
+    pub runner: &'a dyn ScriptletRunner,
+    /// Format of the package the scriptlet came from
+    pub format: PackageFormatType,
+    pub operation: Operation,
+    /// Version of the package that owns the scriptlet
+    pub version: &'a str,
+    /// During an upgrade, the version on the other side of the upgrade
+    pub other_version: Option<&'a str>,
+}
+
+/// Check whether a shell script defines a function with the given name
+pub(crate) fn shell_function_defined(script: &str, name: &str) -> bool {
+    script.lines().any(|line| {
+        let line = line.trim_start();
+        let line = line
+            .strip_prefix("function ")
+            .map(str::trim_start)
+            .unwrap_or(line);
+        line.strip_prefix(name)
+            .map(|rest| {
+                let rest = rest.trim_start();
+                rest.starts_with('(') || rest.starts_with('{')
+            })
+            .unwrap_or(false)
+    })
+}
+
+/// Work out the script text and arguments for a scriptlet
+///
+/// Returns None when the package format does not run this phase for the
+/// given operation (e.g. Arch never runs remove hooks during an upgrade).
+fn invocation(scriptlet: &Scriptlet, ctx: &ScriptletContext) -> Option<(String, Vec<String>)> {
+    let install_phase = matches!(
+        scriptlet.phase,
+        ScriptletPhase::PreInstall | ScriptletPhase::PostInstall
+    );
+    let other = ctx.other_version.unwrap_or_default().to_string();
+
+    match ctx.format {
+        PackageFormatType::Rpm => {
+            // $1 is the number of instances installed once the operation completes
+            let count = match (ctx.operation, install_phase) {
+                (Operation::Install, _) => "1",
+                (Operation::Upgrade, true) => "2",
+                (Operation::Upgrade, false) => "1",
+                (Operation::Remove, _) => "0",
+            };
+            Some((scriptlet.body.clone(), vec![count.to_string()]))
+        }
+        PackageFormatType::Deb => {
+            let args = match (ctx.operation, scriptlet.phase) {
+                (Operation::Upgrade, ScriptletPhase::PostInstall) => {
+                    vec!["configure".to_string(), other]
+                }
+                (Operation::Upgrade, _) => vec!["upgrade".to_string(), other],
+                (_, ScriptletPhase::PreInstall) => vec!["install".to_string()],
+                (_, ScriptletPhase::PostInstall) => vec!["configure".to_string()],
+                (_, ScriptletPhase::PreRemove | ScriptletPhase::PostRemove) => {
+                    vec!["remove".to_string()]
+                }
+            };
+            Some((scriptlet.body.clone(), args))
+        }
+        PackageFormatType::Arch => {
+            let (function, args) = match (ctx.operation, scriptlet.phase) {
+                (Operation::Upgrade, ScriptletPhase::PreInstall) => {
+                    ("pre_upgrade", vec![ctx.version.to_string(), other])
+                }
+                (Operation::Upgrade, ScriptletPhase::PostInstall) => {
+                    ("post_upgrade", vec![ctx.version.to_string(), other])
+                }
+                (Operation::Upgrade, _) => return None,
+                (_, ScriptletPhase::PreInstall) => ("pre_install", vec![ctx.version.to_string()]),
+                (_, ScriptletPhase::PostInstall) => ("post_install", vec![ctx.version.to_string()]),
+                (_, ScriptletPhase::PreRemove) => ("pre_remove", vec![ctx.version.to_string()]),
+                (_, ScriptletPhase::PostRemove) => ("post_remove", vec![ctx.version.to_string()]),
+            };
+
+            if !shell_function_defined(&scriptlet.body, function) {
+                return None;
+            }
+            Some((format!("{}\n{} \"$@\"\n", scriptlet.body, function), args))
+        }
+    }
+}
+
+/// Run a single scriptlet
+///
+/// Returns the exit code, or None if the scriptlet does not apply to this
+/// operation or uses an unsupported interpreter. A script killed by a signal
+/// reports -1.
+pub fn run(scriptlet: &Scriptlet, ctx: &ScriptletContext) -> Result<Option<i32>> {
+    let Some((script, args)) = invocation(scriptlet, ctx) else {
+        debug!(
+            "Skipping {} scriptlet (not used for {:?})",
+            scriptlet.phase.as_str(),
+            ctx.operation
+        );
+        return Ok(None);
+    };
+
+    let mut interpreter = scriptlet.interpreter.split_whitespace();
+    let Some(program) = interpreter.next() else {
+        return Err(Error::ScriptletError(format!(
+            "{} scriptlet has no interpreter",
+            scriptlet.phase.as_str()
+        )));
+    };
+    if program == "<lua>" {
+        warn!(
+            "Skipping {} scriptlet: Lua scriptlets are not supported",
+            scriptlet.phase.as_str()
+        );
+        return Ok(None);
+    }
+
+    // Interpreter-only scriptlets (e.g. "-p /sbin/ldconfig") have no body to write out
+    let script_file = if scriptlet.body.trim().is_empty() {
+        None
+    } else {
+        let tmp_dir = ctx.root.join("tmp");
+        std::fs::create_dir_all(&tmp_dir)?;
+        let mut file = tempfile::Builder::new()
+            .prefix("conary-scriptlet-")
+            .tempfile_in(&tmp_dir)?;
+        file.write_all(script.as_bytes())?;
+        file.flush()?;
+        Some(file)
+    };
+
+    let invocation = Invocation {
+        root: ctx.root,
+        program,
+        interpreter_args: interpreter.collect(),
+        script: script_file.as_ref().map(|file| file.path()),
+        args: &args,
+    };
+
+    info!(
+        "Running {} scriptlet ({}, {}) with args {:?}",
+        scriptlet.phase.as_str(),
+        scriptlet.interpreter,
+        ctx.runner.name(),
+        args
+    );
+    let exit_code = ctx.runner.run(&invocation).map_err(|e| {
+        Error::ScriptletError(format!(
+            "Failed to run {} scriptlet: {}",
+            scriptlet.phase.as_str(),
+            e
+        ))
+    })?;
+
+    debug!(
+        "{} scriptlet exited with {}",
+        scriptlet.phase.as_str(),
+        exit_code
+    );
+    Ok(Some(exit_code))
+}
+
+/// Run the scriptlet registered for a phase, if the package has one
+pub fn run_phase(
+    scriptlets: &[Scriptlet],
+    phase: ScriptletPhase,
+    ctx: &ScriptletContext,
+) -> Result<Option<i32>> {
+    match scriptlets.iter().find(|s| s.phase == phase) {
+        Some(scriptlet) => run(scriptlet, ctx),
+        None => Ok(None),
+    }
+}
+
+/// Exit code of a scriptlet run, waiting to be recorded against a changeset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptletOutcome {
+    pub package: String,
+    pub phase: ScriptletPhase,
+    pub exit_code: i32,
+    /// Name of the runner that launched the scriptlet
+    pub backend: &'static str,
+}
+
+/// Run a phase and collect its outcome
+///
+/// A failing pre-install or pre-remove scriptlet returns an error so the
+/// caller can abort before touching the filesystem; post-phase failures are
+/// only logged, since the files are already in place by then.
+pub fn run_checked(
+    scriptlets: &[Scriptlet],
+    phase: ScriptletPhase,
+    ctx: &ScriptletContext,
+    package: &str,
+    outcomes: &mut Vec<ScriptletOutcome>,
+) -> Result<()> {
+    let Some(exit_code) = run_phase(scriptlets, phase, ctx)? else {
+        return Ok(());
+    };
+
+    outcomes.push(ScriptletOutcome {
+        package: package.to_string(),
+        phase,
+        exit_code,
+        backend: ctx.runner.name(),
+    });
+
+    if exit_code != 0 {
+        if matches!(
+            phase,
+            ScriptletPhase::PreInstall | ScriptletPhase::PreRemove
+        ) {
+            return Err(Error::ScriptletError(format!(
+                "{} scriptlet of {} exited with status {}",
+                phase.as_str(),
+                package,
+                exit_code
+            )));
+        }
+        warn!(
+            "{} scriptlet of {} exited with status {}",
+            phase.as_str(),
+            package,
+            exit_code
+        );
+    }
+
+    Ok(())
+}
+
+/// Store a package's scriptlets for an installed trove
+pub fn store(
+    conn: &Connection,
+    trove_id: i64,
+    format: PackageFormatType,
+    scriptlets: &[Scriptlet],
+) -> Result<()> {
+    for scriptlet in scriptlets {
+        let mut entry = ScriptletEntry::new(
+            trove_id,
+            scriptlet.phase.as_str().to_string(),
+            scriptlet.interpreter.clone(),
+            scriptlet.body.clone(),
+            format.as_str().to_string(),
+        );
+        entry.insert(conn)?;
+    }
+    Ok(())
+}
+
+/// Load the scriptlets stored for an installed trove
+///
+/// Returns None if the trove has no scriptlets.
+pub fn load(
+    conn: &Connection,
+    trove_id: i64,
+) -> Result<Option<(PackageFormatType, Vec<Scriptlet>)>> {
+    let entries = ScriptletEntry::find_by_trove(conn, trove_id)?;
+    let Some(first) = entries.first() else {
+        return Ok(None);
+    };
+
+    let format = first.format.parse().map_err(Error::ParseError)?;
+    let scriptlets = entries
+        .iter()
+        .map(ScriptletEntry::to_scriptlet)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some((format, scriptlets)))
+}
+
+/// Record scriptlet exit codes against a changeset
+pub fn record_outcomes(
+    conn: &Connection,
+    changeset_id: i64,
+    outcomes: &[ScriptletOutcome],
+) -> Result<()> {
+    for outcome in outcomes {
+        let mut run = ScriptletRun::new(
+            changeset_id,
+            outcome.package.clone(),
+            outcome.phase.as_str().to_string(),
+            outcome.exit_code,
+        );
+        run.backend = Some(outcome.backend.to_string());
+        run.insert(conn)?;
+    }
+    Ok(())
+}
+
+/// Record a changeset that was aborted by a failing scriptlet
+///
+/// The changeset is marked rolled back, since nothing was applied, and keeps
+/// the exit codes of the scriptlets that ran. Returns the changeset id.
+pub fn record_aborted(
+    conn: &mut Connection,
+    description: &str,
+    outcomes: &[ScriptletOutcome],
+) -> Result<i64> {
+    crate::db::transaction(conn, |tx| {
+        let mut changeset = Changeset::new(format!("{} (aborted by scriptlet)", description));
+        let changeset_id = changeset.insert(tx)?;
+        record_outcomes(tx, changeset_id, outcomes)?;
+        changeset.update_status(tx, ChangesetStatus::RolledBack)?;
+        Ok(changeset_id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(format: PackageFormatType, operation: Operation) -> ScriptletContext<'static> {
+        ScriptletContext {
+            root: Path::new("/"),
+            runner: &DirectRunner,
+            format,
+            operation,
+            version: "2.0",
+            other_version: Some("1.0"),
+        }
+    }
+
+    fn scriptlet(phase: ScriptletPhase, body: &str) -> Scriptlet {
+        Scriptlet {
+            phase,
+            interpreter: "/bin/sh".to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_shell_function_defined() {
+        let script = "pre_install() {\n  true\n}\nfunction post_upgrade {\n  true\n}\npost_install_helper() { :; }\n";
+        assert!(shell_function_defined(script, "pre_install"));
+        assert!(shell_function_defined(script, "post_upgrade"));
+        assert!(!shell_function_defined(script, "post_install"));
+        assert!(!shell_function_defined(script, "pre_remove"));
+    }
+
+    #[test]
+    fn test_format_arguments() {
+        let post = scriptlet(ScriptletPhase::PostInstall, "true");
+        let prerm = scriptlet(ScriptletPhase::PreRemove, "true");
+
+        let rpm = |s, op| {
+            invocation(s, &context(PackageFormatType::Rpm, op))
+                .unwrap()
+                .1
+        };
+        assert_eq!(rpm(&post, Operation::Install), vec!["1"]);
+        assert_eq!(rpm(&post, Operation::Upgrade), vec!["2"]);
+        assert_eq!(rpm(&prerm, Operation::Upgrade), vec!["1"]);
+        assert_eq!(rpm(&prerm, Operation::Remove), vec!["0"]);
+
+        let deb = |s, op| {
+            invocation(s, &context(PackageFormatType::Deb, op))
+                .unwrap()
+                .1
+        };
+        assert_eq!(deb(&post, Operation::Install), vec!["configure"]);
+        assert_eq!(deb(&post, Operation::Upgrade), vec!["configure", "1.0"]);
+        assert_eq!(deb(&prerm, Operation::Upgrade), vec!["upgrade", "1.0"]);
+        assert_eq!(deb(&prerm, Operation::Remove), vec!["remove"]);
+    }
+
+    #[test]
+    fn test_arch_hook_selection() {
+        let install =
+            "post_install() {\n  echo installed\n}\npost_upgrade() {\n  echo upgraded\n}\n";
+        let post = scriptlet(ScriptletPhase::PostInstall, install);
+
+        let (script, args) =
+            invocation(&post, &context(PackageFormatType::Arch, Operation::Install)).unwrap();
+        assert!(script.ends_with("post_install \"$@\"\n"));
+        assert_eq!(args, vec!["2.0"]);
+
+        let (script, args) =
+            invocation(&post, &context(PackageFormatType::Arch, Operation::Upgrade)).unwrap();
+        assert!(script.ends_with("post_upgrade \"$@\"\n"));
+        assert_eq!(args, vec!["2.0", "1.0"]);
+
+        // Remove hooks are not run during an upgrade
+        let prerm = scriptlet(ScriptletPhase::PreRemove, "pre_remove() { :; }\n");
+        assert!(
+            invocation(
+                &prerm,
+                &context(PackageFormatType::Arch, Operation::Upgrade)
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_run_reports_exit_code() {
+        let ctx = context(PackageFormatType::Rpm, Operation::Install);
+
+        let ok = scriptlet(ScriptletPhase::PreInstall, "test \"$1\" = 1");
+        assert_eq!(run(&ok, &ctx).unwrap(), Some(0));
+
+        let failing = scriptlet(ScriptletPhase::PreInstall, "exit 3");
+        assert_eq!(run(&failing, &ctx).unwrap(), Some(3));
+
+        let scriptlets = vec![ok];
+        assert_eq!(
+            run_phase(&scriptlets, ScriptletPhase::PostInstall, &ctx).unwrap(),
+            None
+        );
+    }
+
+    /// Root, program, arguments and script body of one recorded invocation
+    type Call = (PathBuf, String, Vec<String>, Option<String>);
+
+    /// Records invocations instead of running anything
+    #[derive(Debug, Default)]
+    struct FakeRunner {
+        calls: std::cell::RefCell<Vec<Call>>,
+    }
+
+    impl ScriptletRunner for FakeRunner {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn run(&self, invocation: &Invocation) -> Result<i32> {
+            let script = invocation
+                .script
+                .map(|path| std::fs::read_to_string(path).unwrap());
+            self.calls.borrow_mut().push((
+                invocation.root.to_path_buf(),
+                invocation.program.to_string(),
+                invocation.args.to_vec(),
+                script,
+            ));
+            Ok(7)
+        }
+    }
+
+    #[test]
+    fn test_runner_receives_invocation() {
+        let root = tempfile::TempDir::new().unwrap();
+        let runner = FakeRunner::default();
+        let ctx = ScriptletContext {
+            root: root.path(),
+            runner: &runner,
+            ..context(PackageFormatType::Rpm, Operation::Install)
+        };
+
+        let scriptlets = vec![scriptlet(ScriptletPhase::PostInstall, "echo hi")];
+        let mut outcomes = Vec::new();
+        run_checked(
+            &scriptlets,
+            ScriptletPhase::PostInstall,
+            &ctx,
+            "pkg",
+            &mut outcomes,
+        )
+        .unwrap();
+
+        let calls = runner.calls.borrow();
+        assert_eq!(calls.len(), 1);
+        let (call_root, program, args, script) = &calls[0];
+        assert_eq!(call_root, root.path());
+        assert_eq!(program, "/bin/sh");
+        assert_eq!(args, &["1"]);
+        assert_eq!(script.as_deref(), Some("echo hi"));
+        assert_eq!(outcomes[0].exit_code, 7);
+        assert_eq!(outcomes[0].backend, "fake");
+    }
+
+    #[test]
+    fn test_sandbox_arguments() {
+        let runner = SandboxRunner::new("/usr/bin/bwrap");
+        let args = vec!["configure".to_string()];
+        let invocation = Invocation {
+            root: Path::new("/var/roots/v42"),
+            program: "/bin/sh",
+            interpreter_args: vec!["-e"],
+            script: Some(Path::new("/var/roots/v42/tmp/conary-scriptlet-x")),
+            args: &args,
+        };
+
+        let argv: Vec<String> = runner
+            .arguments(&invocation)
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+        assert_eq!(&argv[..3], ["--bind", "/var/roots/v42", "/"]);
+        assert!(argv.contains(&"--unshare-net"));
+        assert!(argv.windows(2).any(|w| w == ["--tmpfs", "/tmp"]));
+        assert!(argv.windows(3).any(|w| {
+            w == [
+                "--ro-bind",
+                "/var/roots/v42/tmp/conary-scriptlet-x",
+                "/tmp/conary-scriptlet-x",
+            ]
+        }));
+        assert!(argv.ends_with(&["/bin/sh", "-e", "/tmp/conary-scriptlet-x", "configure"]));
+    }
+
+    #[test]
+    fn test_direct_runner_runs_script() {
+        let out = tempfile::TempDir::new().unwrap();
+        let marker = out.path().join("marker");
+        let ctx = context(PackageFormatType::Rpm, Operation::Install);
+
+        let body = format!("echo \"installed $1\" > '{}'", marker.display());
+        let post = scriptlet(ScriptletPhase::PostInstall, &body);
+        assert_eq!(run(&post, &ctx).unwrap(), Some(0));
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "installed 1\n");
+    }
+
+    #[test]
+    fn test_failed_pre_phase_is_recorded() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap().to_string();
+        drop(temp_file);
+        crate::db::init(&db_path).unwrap();
+        let mut conn = crate::db::open(&db_path).unwrap();
+
+        let ctx = context(PackageFormatType::Rpm, Operation::Install);
+        let scriptlets = vec![
+            scriptlet(ScriptletPhase::PreInstall, "exit 3"),
+            scriptlet(ScriptletPhase::PostInstall, "exit 4"),
+        ];
+
+        // Post-phase failures only warn
+        let mut outcomes = Vec::new();
+        run_checked(
+            &scriptlets,
+            ScriptletPhase::PostInstall,
+            &ctx,
+            "pkg",
+            &mut outcomes,
+        )
+        .unwrap();
+        assert_eq!(outcomes[0].exit_code, 4);
+
+        let mut outcomes = Vec::new();
+        let result = run_checked(
+            &scriptlets,
+            ScriptletPhase::PreInstall,
+            &ctx,
+            "pkg",
+            &mut outcomes,
+        );
+        assert!(matches!(result, Err(Error::ScriptletError(_))));
+
+        let changeset_id = record_aborted(&mut conn, "Install pkg-2.0", &outcomes).unwrap();
+        let changeset = Changeset::find_by_id(&conn, changeset_id).unwrap().unwrap();
+        assert_eq!(changeset.status, ChangesetStatus::RolledBack);
+
+        let runs = ScriptletRun::find_by_changeset(&conn, changeset_id).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].phase, "pre-install");
+        assert_eq!(runs[0].exit_code, 3);
+        assert_eq!(runs[0].backend.as_deref(), Some("direct"));
+    }
+}
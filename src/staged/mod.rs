@@ -0,0 +1,268 @@
+// src/staged/mod.rs
+
+//! Staged deployment for read-only roots
+//!
+//! Image-based systems mount the root read-only and keep the database and
+//! CAS somewhere writable. With the `staged_deploy` setting on, an install
+//! or update records its changeset as [`ChangesetStatus::Staged`] and
+//! writes the files to a pending tree laid out like the root, at
+//! `<db dir>/pending/<changeset id>`, leaving the root as it is.
+//! [`apply_pending`], run from early boot or by hand once the root is
+//! writable, moves each tree into place, oldest changeset first, and marks
+//! its changeset applied.
+//!
+//! Files leave a pending tree as they are moved, so an interrupted
+//! `apply-pending` picks up where it stopped. Verify checks the files of a
+//! staged changeset in its pending tree, and rolling one back only reverts
+//! the database and [`discard`]s the tree.
+
+use crate::db::models::{Changeset, ChangesetFilter, ChangesetStatus, Trove};
+use crate::error::{Error, Result};
+use crate::paths::{Layout, encode_path, resolve_under_root};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// A staged changeset moved into place by [`apply_pending`]
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedChangeset {
+    pub changeset_id: i64,
+    pub description: String,
+    /// Files and links moved into the root
+    pub files: usize,
+}
+
+/// Where the files of `changeset_id` wait, under the pending directory `pending_dir`
+pub fn pending_tree(pending_dir: &Path, changeset_id: i64) -> PathBuf {
+    pending_dir.join(changeset_id.to_string())
+}
+
+/// Changesets staged and not applied yet, oldest first
+pub fn staged_changesets(conn: &Connection) -> Result<Vec<Changeset>> {
+    let filter = ChangesetFilter {
+        status: Some(ChangesetStatus::Staged),
+        ..Default::default()
+    };
+    let mut changesets = Changeset::list(conn, &filter)?;
+    changesets.reverse();
+    Ok(changesets)
+}
+
+/// The pending tree holding the files of `trove`, while the changeset that installed it is staged
+pub fn pending_tree_of(
+    conn: &Connection,
+    layout: &Layout,
+    trove: &Trove,
+) -> Result<Option<PathBuf>> {
+    let Some(changeset_id) = trove.installed_by_changeset_id else {
+        return Ok(None);
+    };
+    let staged = Changeset::find_by_id(conn, changeset_id)?
+        .is_some_and(|cs| cs.status == ChangesetStatus::Staged);
+    Ok(staged.then(|| pending_tree(&layout.pending_dir(), changeset_id)))
+}
+
+/// Move the pending tree of every staged changeset into the install root of `layout`
+///
+/// Changesets are applied oldest first, so a file staged twice ends up
+/// with its newest version. Each one is marked applied once its tree is
+/// empty, and the tree is removed. Callers should hold the operation lock.
+pub fn apply_pending(conn: &Connection, layout: &Layout) -> Result<Vec<AppliedChangeset>> {
+    let mut applied = Vec::new();
+    for mut changeset in staged_changesets(conn)? {
+        let changeset_id = changeset
+            .id
+            .ok_or_else(|| Error::NotFoundError("Staged changeset has no ID".to_string()))?;
+        let tree = pending_tree(&layout.pending_dir(), changeset_id);
+        let files = if tree.exists() {
+            move_tree(&tree, Path::new(""), layout.install_root())?
+        } else {
+            warn!(
+                "Staged changeset {} has no pending tree at {}",
+                changeset_id,
+                tree.display()
+            );
+            0
+        };
+
+        changeset.update_status(conn, ChangesetStatus::Applied)?;
+        if tree.exists() {
+            fs::remove_dir_all(&tree)?;
+        }
+        info!(
+            "Applied staged changeset {} ({} files)",
+            changeset_id, files
+        );
+        applied.push(AppliedChangeset {
+            changeset_id,
+            description: changeset.description,
+            files,
+        });
+    }
+    Ok(applied)
+}
+
+/// Remove the pending tree of a staged changeset, once it has been rolled back
+pub fn discard(layout: &Layout, changeset_id: i64) -> Result<()> {
+    let tree = pending_tree(&layout.pending_dir(), changeset_id);
+    if tree.exists() {
+        fs::remove_dir_all(&tree)?;
+        info!("Discarded pending tree {}", tree.display());
+    }
+    Ok(())
+}
+
+/// Move everything below `dir`, which is `relative` in the pending tree, to the same place under `root`
+///
+/// Targets are resolved like the deployer resolves them, so symlinks already
+/// in the root, such as merged-usr's `/lib -> usr/lib`, are followed and
+/// nothing lands outside it. Directories the root lacks are created with
+/// the mode, owner and group they were staged with; existing ones are left
+/// as they are. Returns the number of files moved.
+fn move_tree(dir: &Path, relative: &Path, root: &Path) -> Result<usize> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut moved = 0;
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        let target = resolve_under_root(root, &encode_path(&relative))?;
+        if entry.file_type()?.is_dir() {
+            if fs::symlink_metadata(&target).is_err() {
+                let metadata = entry.metadata()?;
+                fs::create_dir_all(&target)?;
+                let created = fs::metadata(&target)?;
+                if (created.uid(), created.gid()) != (metadata.uid(), metadata.gid()) {
+                    std::os::unix::fs::lchown(&target, Some(metadata.uid()), Some(metadata.gid()))?;
+                }
+                fs::set_permissions(&target, metadata.permissions())?;
+            }
+            moved += move_tree(&entry.path(), &relative, root)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        move_file(&entry.path(), &target)?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// Rename `from` over `to`, copying it instead when they are on different filesystems
+///
+/// A copy is written next to `to` with the mode, owner and group of `from`
+/// and renamed over it, so the target is replaced in one step either way.
+/// Hardlinks between copied files are not kept.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        result => return Ok(result?),
+    }
+
+    let metadata = fs::symlink_metadata(from)?;
+    let temp = to.with_extension("conary-tmp");
+    if fs::symlink_metadata(&temp).is_ok() {
+        fs::remove_file(&temp)?;
+    }
+    if metadata.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, &temp)?;
+    } else {
+        fs::copy(from, &temp)?;
+        fs::File::open(&temp)?.sync_all()?;
+    }
+
+    let copied = fs::symlink_metadata(&temp)?;
+    if (copied.uid(), copied.gid()) != (metadata.uid(), metadata.gid()) {
+        std::os::unix::fs::lchown(&temp, Some(metadata.uid()), Some(metadata.gid()))?;
+        // chown clears setuid and setgid bits
+        if !metadata.is_symlink() {
+            fs::set_permissions(&temp, metadata.permissions())?;
+        }
+    }
+    fs::rename(&temp, to)?;
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn test_apply_pending_moves_trees_oldest_first() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("conary.db");
+        let db_path = db_path.to_str().unwrap();
+        db::init(db_path).unwrap();
+        let conn = db::open(db_path).unwrap();
+        let layout = Layout::load(&conn, db_path)
+            .unwrap()
+            .with_root(temp_dir.path().join("root"))
+            .unwrap();
+        std::os::unix::fs::symlink("usr/lib", temp_dir.path().join("root/lib")).unwrap();
+
+        let mut staged = Vec::new();
+        for (description, content) in [
+            ("Install tool-1.0", "v1"),
+            ("Upgrade tool from 1.0 to 2.0", "v2"),
+        ] {
+            let mut changeset = Changeset::new(description.to_string());
+            let changeset_id = changeset.insert(&conn).unwrap();
+            changeset
+                .update_status(&conn, ChangesetStatus::Staged)
+                .unwrap();
+            let tree = pending_tree(&layout.pending_dir(), changeset_id);
+            fs::create_dir_all(tree.join("usr/bin")).unwrap();
+            fs::write(tree.join("usr/bin/tool"), content).unwrap();
+            fs::create_dir_all(tree.join("lib")).unwrap();
+            fs::write(tree.join("lib/libtool.so"), content).unwrap();
+            staged.push(changeset_id);
+        }
+        assert_eq!(
+            staged_changesets(&conn)
+                .unwrap()
+                .iter()
+                .map(|cs| cs.id.unwrap())
+                .collect::<Vec<_>>(),
+            staged
+        );
+
+        let applied = apply_pending(&conn, &layout).unwrap();
+        assert_eq!(
+            applied
+                .iter()
+                .map(|cs| (cs.changeset_id, cs.files))
+                .collect::<Vec<_>>(),
+            [(staged[0], 2), (staged[1], 2)]
+        );
+        let root = layout.install_root();
+        assert_eq!(fs::read_to_string(root.join("usr/bin/tool")).unwrap(), "v2");
+        // Staged below a symlink the root already has, and moved through it
+        assert_eq!(
+            fs::read_to_string(root.join("usr/lib/libtool.so")).unwrap(),
+            "v2"
+        );
+        assert!(fs::symlink_metadata(root.join("lib")).unwrap().is_symlink());
+
+        assert!(staged_changesets(&conn).unwrap().is_empty());
+        for changeset_id in staged {
+            assert_eq!(
+                Changeset::find_by_id(&conn, changeset_id)
+                    .unwrap()
+                    .unwrap()
+                    .status,
+                ChangesetStatus::Applied
+            );
+            assert!(!pending_tree(&layout.pending_dir(), changeset_id).exists());
+        }
+    }
+}
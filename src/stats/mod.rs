@@ -0,0 +1,384 @@
+// src/stats/mod.rs
+
+//! Cumulative bandwidth and operation statistics
+//!
+//! Delta savings are tracked per changeset in `delta_stats`; this module keeps
+//! broader counters that are useful for capacity planning:
+//! - Bytes downloaded per repository (packages and deltas)
+//! - Bytes fetched while syncing repository metadata
+//! - Bytes that did not need to be stored because they were already in the CAS
+//! - Packages installed, removed and updated
+//!
+//! Every increment is appended as a row in `operation_stats`, so totals can be
+//! computed over any time window. Recording is a no-op on databases that have
+//! not yet been migrated to a schema containing the table.
+
+use crate::error::{Error, Result};
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use tracing::debug;
+
+/// A counter tracked in the operation statistics table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Metric {
+    /// Bytes downloaded for packages and deltas
+    BytesDownloaded,
+    /// Bytes downloaded while synchronizing repository metadata
+    SyncBytes,
+    /// Bytes of file content already present in the CAS
+    CacheHitBytes,
+    /// Packages freshly installed
+    PackagesInstalled,
+    /// Packages removed
+    PackagesRemoved,
+    /// Packages replaced by a different version
+    PackagesUpdated,
+}
+
+impl Metric {
+    /// All metrics, in display order
+    pub const ALL: [Metric; 6] = [
+        Metric::BytesDownloaded,
+        Metric::SyncBytes,
+        Metric::CacheHitBytes,
+        Metric::PackagesInstalled,
+        Metric::PackagesRemoved,
+        Metric::PackagesUpdated,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::BytesDownloaded => "bytes_downloaded",
+            Metric::SyncBytes => "sync_bytes",
+            Metric::CacheHitBytes => "cache_hit_bytes",
+            Metric::PackagesInstalled => "packages_installed",
+            Metric::PackagesRemoved => "packages_removed",
+            Metric::PackagesUpdated => "packages_updated",
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "bytes_downloaded" => Ok(Metric::BytesDownloaded),
+            "sync_bytes" => Ok(Metric::SyncBytes),
+            "cache_hit_bytes" => Ok(Metric::CacheHitBytes),
+            "packages_installed" => Ok(Metric::PackagesInstalled),
+            "packages_removed" => Ok(Metric::PackagesRemoved),
+            "packages_updated" => Ok(Metric::PackagesUpdated),
+            _ => Err(format!("Invalid metric: {}", s)),
+        }
+    }
+}
+
+/// Aggregated counter values, over all time or a window
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsTotals {
+    pub bytes_downloaded: i64,
+    pub sync_bytes: i64,
+    pub cache_hit_bytes: i64,
+    pub packages_installed: i64,
+    pub packages_removed: i64,
+    pub packages_updated: i64,
+}
+
+impl StatsTotals {
+    /// Get the value of a single metric
+    pub fn get(&self, metric: Metric) -> i64 {
+        match metric {
+            Metric::BytesDownloaded => self.bytes_downloaded,
+            Metric::SyncBytes => self.sync_bytes,
+            Metric::CacheHitBytes => self.cache_hit_bytes,
+            Metric::PackagesInstalled => self.packages_installed,
+            Metric::PackagesRemoved => self.packages_removed,
+            Metric::PackagesUpdated => self.packages_updated,
+        }
+    }
+
+    fn add(&mut self, metric: Metric, amount: i64) {
+        match metric {
+            Metric::BytesDownloaded => self.bytes_downloaded += amount,
+            Metric::SyncBytes => self.sync_bytes += amount,
+            Metric::CacheHitBytes => self.cache_hit_bytes += amount,
+            Metric::PackagesInstalled => self.packages_installed += amount,
+            Metric::PackagesRemoved => self.packages_removed += amount,
+            Metric::PackagesUpdated => self.packages_updated += amount,
+        }
+    }
+}
+
+/// Check whether the stats table exists (older databases may predate it)
+fn stats_table_exists(conn: &Connection) -> Result<bool> {
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'operation_stats'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    Ok(exists)
+}
+
+/// Record an increment of a metric, optionally attributed to a repository
+///
+/// Zero amounts are not recorded. Silently does nothing if the database has
+/// not been migrated to include the `operation_stats` table.
+pub fn record(
+    conn: &Connection,
+    metric: Metric,
+    repository: Option<&str>,
+    amount: i64,
+) -> Result<()> {
+    if amount == 0 || !stats_table_exists(conn)? {
+        return Ok(());
+    }
+
+    debug!(
+        "Recording stat {} += {} ({:?})",
+        metric.as_str(),
+        amount,
+        repository
+    );
+    conn.execute(
+        "INSERT INTO operation_stats (metric, repository, amount) VALUES (?1, ?2, ?3)",
+        rusqlite::params![metric.as_str(), repository, amount],
+    )?;
+    Ok(())
+}
+
+/// Record bytes downloaded for a repository package
+///
+/// Looks up the repository name from the package's repository id so the
+/// bytes can be attributed in `--by-repo` reports.
+pub fn record_download(conn: &Connection, repository_id: i64, bytes: i64) -> Result<()> {
+    let repo_name: Option<String> = conn
+        .query_row(
+            "SELECT name FROM repositories WHERE id = ?1",
+            [repository_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    record(conn, Metric::BytesDownloaded, repo_name.as_deref(), bytes)
+}
+
+/// Compute totals for all metrics, optionally restricted to rows recorded at
+/// or after `since` (a `YYYY-MM-DD HH:MM:SS` UTC timestamp)
+pub fn totals(conn: &Connection, since: Option<&str>) -> Result<StatsTotals> {
+    let mut totals = StatsTotals::default();
+    for (metric, _, amount) in grouped(conn, since)? {
+        totals.add(metric, amount);
+    }
+    Ok(totals)
+}
+
+/// Compute totals per repository
+///
+/// Rows not attributed to any repository (e.g. installs from local files) are
+/// grouped under `"(local)"`.
+pub fn totals_by_repo(
+    conn: &Connection,
+    since: Option<&str>,
+) -> Result<BTreeMap<String, StatsTotals>> {
+    let mut by_repo: BTreeMap<String, StatsTotals> = BTreeMap::new();
+    for (metric, repository, amount) in grouped(conn, since)? {
+        let key = repository.unwrap_or_else(|| "(local)".to_string());
+        by_repo.entry(key).or_default().add(metric, amount);
+    }
+    Ok(by_repo)
+}
+
+/// Sum amounts grouped by metric and repository
+fn grouped(conn: &Connection, since: Option<&str>) -> Result<Vec<(Metric, Option<String>, i64)>> {
+    if !stats_table_exists(conn)? {
+        return Ok(Vec::new());
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT metric, repository, SUM(amount) FROM operation_stats
+         WHERE ?1 IS NULL OR recorded_at >= ?1
+         GROUP BY metric, repository",
+    )?;
+    let rows = stmt
+        .query_map([since], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    // Unknown metric names (from a newer Conary) are skipped rather than failing
+    Ok(rows
+        .into_iter()
+        .filter_map(|(metric, repo, amount)| metric.parse().ok().map(|m| (m, repo, amount)))
+        .collect())
+}
+
+/// Delete all recorded statistics, returning the number of rows removed
+pub fn reset(conn: &Connection) -> Result<usize> {
+    if !stats_table_exists(conn)? {
+        return Ok(0);
+    }
+    let deleted = conn.execute("DELETE FROM operation_stats", [])?;
+    Ok(deleted)
+}
+
+/// Convert a relative window like "30d", "12h", "2w" or "45m" into the
+/// absolute UTC timestamp it starts at, in SQLite `CURRENT_TIMESTAMP` format
+pub fn parse_since(window: &str) -> Result<String> {
+    let window = window.trim();
+    let split = window
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| Error::ParseError(format!("Missing unit in time window '{}'", window)))?;
+    let (number, unit) = window.split_at(split);
+
+    let count: i64 = number
+        .parse()
+        .map_err(|_| Error::ParseError(format!("Invalid number in time window '{}'", window)))?;
+
+    let duration = match unit {
+        "m" => chrono::Duration::minutes(count),
+        "h" => chrono::Duration::hours(count),
+        "d" => chrono::Duration::days(count),
+        "w" => chrono::Duration::weeks(count),
+        _ => {
+            return Err(Error::ParseError(format!(
+                "Invalid unit '{}' in time window (expected m, h, d or w)",
+                unit
+            )));
+        }
+    };
+
+    let start = chrono::Utc::now() - duration;
+    Ok(start.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema;
+    use tempfile::NamedTempFile;
+
+    fn create_test_db() -> (NamedTempFile, Connection) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+        schema::migrate(&conn).unwrap();
+        (temp_file, conn)
+    }
+
+    #[test]
+    fn test_record_and_totals() {
+        let (_temp, conn) = create_test_db();
+
+        record(&conn, Metric::BytesDownloaded, Some("arch-core"), 1000).unwrap();
+        record(&conn, Metric::BytesDownloaded, Some("fedora-43"), 500).unwrap();
+        record(&conn, Metric::PackagesInstalled, None, 1).unwrap();
+        record(&conn, Metric::PackagesInstalled, None, 1).unwrap();
+        record(&conn, Metric::CacheHitBytes, None, 0).unwrap();
+
+        let totals = totals(&conn, None).unwrap();
+        assert_eq!(totals.bytes_downloaded, 1500);
+        assert_eq!(totals.packages_installed, 2);
+        assert_eq!(totals.cache_hit_bytes, 0);
+
+        // Zero increments are not stored
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM operation_stats", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 4);
+    }
+
+    #[test]
+    fn test_totals_by_repo() {
+        let (_temp, conn) = create_test_db();
+
+        record(&conn, Metric::BytesDownloaded, Some("arch-core"), 1000).unwrap();
+        record(&conn, Metric::SyncBytes, Some("arch-core"), 200).unwrap();
+        record(&conn, Metric::BytesDownloaded, Some("fedora-43"), 500).unwrap();
+        record(&conn, Metric::PackagesRemoved, None, 1).unwrap();
+
+        let by_repo = totals_by_repo(&conn, None).unwrap();
+        assert_eq!(by_repo.len(), 3);
+        assert_eq!(by_repo["arch-core"].bytes_downloaded, 1000);
+        assert_eq!(by_repo["arch-core"].sync_bytes, 200);
+        assert_eq!(by_repo["fedora-43"].bytes_downloaded, 500);
+        assert_eq!(by_repo["(local)"].packages_removed, 1);
+    }
+
+    #[test]
+    fn test_record_download_attributes_repository() {
+        let (_temp, conn) = create_test_db();
+        conn.execute(
+            "INSERT INTO repositories (name, url) VALUES ('arch-core', 'https://example.com')",
+            [],
+        )
+        .unwrap();
+        let repo_id = conn.last_insert_rowid();
+
+        record_download(&conn, repo_id, 4096).unwrap();
+
+        let by_repo = totals_by_repo(&conn, None).unwrap();
+        assert_eq!(by_repo["arch-core"].bytes_downloaded, 4096);
+    }
+
+    #[test]
+    fn test_totals_since_window() {
+        let (_temp, conn) = create_test_db();
+
+        conn.execute(
+            "INSERT INTO operation_stats (metric, amount, recorded_at)
+             VALUES ('bytes_downloaded', 100, '2000-01-01 00:00:00')",
+            [],
+        )
+        .unwrap();
+        record(&conn, Metric::BytesDownloaded, None, 50).unwrap();
+
+        let since = parse_since("30d").unwrap();
+        assert_eq!(totals(&conn, Some(&since)).unwrap().bytes_downloaded, 50);
+        assert_eq!(totals(&conn, None).unwrap().bytes_downloaded, 150);
+    }
+
+    #[test]
+    fn test_reset() {
+        let (_temp, conn) = create_test_db();
+
+        record(&conn, Metric::PackagesUpdated, None, 3).unwrap();
+        assert_eq!(reset(&conn).unwrap(), 1);
+        assert_eq!(totals(&conn, None).unwrap(), StatsTotals::default());
+    }
+
+    #[test]
+    fn test_noop_without_table() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let conn = Connection::open(temp_file.path()).unwrap();
+
+        record(&conn, Metric::BytesDownloaded, None, 100).unwrap();
+        assert_eq!(totals(&conn, None).unwrap(), StatsTotals::default());
+        assert_eq!(reset(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert!(parse_since("30d").is_ok());
+        assert!(parse_since("12h").is_ok());
+        assert!(parse_since("2w").is_ok());
+        assert!(parse_since("45m").is_ok());
+        assert!(parse_since("30").is_err());
+        assert!(parse_since("d").is_err());
+        assert!(parse_since("5y").is_err());
+    }
+
+    #[test]
+    fn test_metric_round_trip() {
+        for metric in Metric::ALL {
+            assert_eq!(metric.as_str().parse::<Metric>().unwrap(), metric);
+        }
+        assert!("bogus".parse::<Metric>().is_err());
+    }
+}
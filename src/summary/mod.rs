@@ -0,0 +1,282 @@
+// src/summary/mod.rs
+
+//! End-of-run summaries for long operations
+//!
+//! A large update prints progress for every package, and the warnings
+//! logged along the way (a delta that failed, a download that was skipped,
+//! a scriptlet that complained) scroll away with it. A [`WarningCollector`]
+//! is a tracing layer that keeps every warning of the run; an
+//! [`OperationSummary`] adds up what the run did and prints both in one
+//! block at the end, for `update --summary`.
+//!
+//! Everything stays local: the summary is printed and nothing is sent
+//! anywhere.
+
+use crate::timing::{Phase, TimingReport};
+use std::fmt::{self, Write as _};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// A warning logged during the run, with how often it was logged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectedWarning {
+    pub message: String,
+    pub count: usize,
+}
+
+/// A tracing layer buffering the messages of warning events
+///
+/// Clones share the buffer, so one clone can be handed to the subscriber
+/// and another kept to read the warnings back.
+#[derive(Debug, Clone, Default)]
+pub struct WarningCollector {
+    warnings: Arc<Mutex<Vec<CollectedWarning>>>,
+}
+
+impl WarningCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffer a warning; a repeated message only bumps its count
+    pub fn record(&self, message: &str) {
+        let message = message.trim();
+        let mut warnings = self.warnings.lock().unwrap();
+        match warnings
+            .iter_mut()
+            .find(|warning| warning.message == message)
+        {
+            Some(warning) => warning.count += 1,
+            None => warnings.push(CollectedWarning {
+                message: message.to_string(),
+                count: 1,
+            }),
+        }
+    }
+
+    /// The warnings so far, in the order they were first logged, emptying
+    /// the buffer
+    pub fn take(&self) -> Vec<CollectedWarning> {
+        std::mem::take(&mut *self.warnings.lock().unwrap())
+    }
+}
+
+/// Picks the message out of an event's fields
+struct MessageVisitor(Option<String>);
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for WarningCollector {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::WARN {
+            return;
+        }
+        let mut visitor = MessageVisitor(None);
+        event.record(&mut visitor);
+        if let Some(message) = visitor.0 {
+            self.record(&message);
+        }
+    }
+}
+
+/// What a long operation did, for the block printed at its end
+#[derive(Debug, Clone)]
+pub struct OperationSummary {
+    started: Instant,
+    pub installed: usize,
+    pub upgraded: usize,
+    pub removed: usize,
+    pub failed: usize,
+    /// Bytes downloaded, packages and deltas together
+    pub downloaded_bytes: u64,
+    /// Bytes deltas saved over downloading the full packages
+    pub delta_bytes_saved: u64,
+    /// Changesets the operation created
+    pub changesets: Vec<i64>,
+    /// Phase timings of the installs, added up
+    pub phases: Vec<(Phase, Duration)>,
+}
+
+impl OperationSummary {
+    /// Start timing an operation
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            installed: 0,
+            upgraded: 0,
+            removed: 0,
+            failed: 0,
+            downloaded_bytes: 0,
+            delta_bytes_saved: 0,
+            changesets: Vec::new(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Add the phase timings of one install
+    pub fn add_timings(&mut self, report: &TimingReport) {
+        for timing in &report.phases {
+            let elapsed = Duration::from_secs_f64(timing.duration_ms / 1000.0);
+            match self
+                .phases
+                .iter_mut()
+                .find(|(phase, _)| *phase == timing.phase)
+            {
+                Some((_, total)) => *total += elapsed,
+                None => {
+                    self.phases.push((timing.phase, elapsed));
+                    self.phases.sort_by_key(|(phase, _)| *phase);
+                }
+            }
+        }
+    }
+
+    /// The summary block, ending with `warnings` grouped by message
+    pub fn render(&self, warnings: &[CollectedWarning]) -> String {
+        let mut out = String::from("=== Summary ===\n");
+        let _ = writeln!(
+            out,
+            "Packages: {} upgraded, {} installed, {} removed, {} failed",
+            self.upgraded, self.installed, self.removed, self.failed
+        );
+        let _ = write!(out, "Downloaded: {}", format_bytes(self.downloaded_bytes));
+        if self.delta_bytes_saved > 0 {
+            let _ = write!(
+                out,
+                " ({} saved by deltas)",
+                format_bytes(self.delta_bytes_saved)
+            );
+        }
+        out.push('\n');
+        let _ = writeln!(out, "Elapsed: {:.1}s", self.started.elapsed().as_secs_f64());
+        for (phase, elapsed) in &self.phases {
+            let _ = writeln!(out, "  {:<8} {:.1}s", phase.as_str(), elapsed.as_secs_f64());
+        }
+        let changesets: Vec<String> = self.changesets.iter().map(|id| id.to_string()).collect();
+        let _ = writeln!(
+            out,
+            "Changesets: {}",
+            if changesets.is_empty() {
+                "none".to_string()
+            } else {
+                changesets.join(", ")
+            }
+        );
+
+        if warnings.is_empty() {
+            out.push_str("Warnings: none\n");
+        } else {
+            let _ = writeln!(out, "Warnings ({}):", warnings.len());
+            for warning in warnings {
+                match warning.count {
+                    1 => {
+                        let _ = writeln!(out, "  - {}", warning.message);
+                    }
+                    count => {
+                        let _ = writeln!(out, "  - {} (x{})", warning.message, count);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A byte count in the largest unit that keeps it above one
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::PhaseTiming;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_collector_groups_warnings() {
+        let collector = WarningCollector::new();
+        let subscriber = tracing_subscriber::registry().with(collector.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("  Delta download failed: {}", "HTTP 404");
+            tracing::info!("not a warning");
+            tracing::warn!("chown of {} failed", "/etc/motd");
+            tracing::warn!("  Delta download failed: {}", "HTTP 404");
+        });
+
+        let warnings = collector.take();
+        assert_eq!(
+            warnings,
+            vec![
+                CollectedWarning {
+                    message: "Delta download failed: HTTP 404".to_string(),
+                    count: 2
+                },
+                CollectedWarning {
+                    message: "chown of /etc/motd failed".to_string(),
+                    count: 1
+                },
+            ]
+        );
+        assert!(collector.take().is_empty());
+
+        let mut summary = OperationSummary::start();
+        summary.upgraded = 3;
+        summary.downloaded_bytes = 3 * 1024 * 1024;
+        summary.changesets = vec![7, 8];
+        let report = |ms: f64| TimingReport {
+            total_ms: ms,
+            phases: vec![PhaseTiming {
+                phase: Phase::Deploy,
+                duration_ms: ms,
+                files: 1,
+                bytes: 1,
+            }],
+        };
+        summary.add_timings(&report(1500.0));
+        summary.add_timings(&report(500.0));
+
+        let rendered = summary.render(&warnings);
+        assert!(
+            rendered.contains("Packages: 3 upgraded, 0 installed, 0 removed, 0 failed"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("Downloaded: 3.0 MB\n"), "{}", rendered);
+        assert!(rendered.contains("  deploy   2.0s"), "{}", rendered);
+        assert!(rendered.contains("Changesets: 7, 8"), "{}", rendered);
+        assert!(
+            rendered.contains(
+                "  - Delta download failed: HTTP 404 (x2)\n  - chown of /etc/motd failed"
+            ),
+            "{}",
+            rendered
+        );
+    }
+}
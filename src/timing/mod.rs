@@ -0,0 +1,188 @@
+// src/timing/mod.rs
+
+//! Phase timing for installs
+//!
+//! An `Instrument` is threaded through the phases of an install and adds
+//! up, per phase, the time spent and the files and bytes processed. Its
+//! `TimingReport` is stored with the changeset that installed the packages,
+//! shows up in `history --json`, and is printed by `install --timings` and
+//! `update --timings`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A phase of installing a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Reading package headers and file lists
+    Parse,
+    /// Reading and decompressing the payload
+    Extract,
+    /// Hashing file content and staging it in the CAS; the files and bytes
+    /// also count content already in the CAS that deploying verified
+    Hash,
+    /// Writing trove, file and history rows
+    Record,
+    /// Putting files in place under the install root
+    Deploy,
+}
+
+impl Phase {
+    /// All phases, in the order an install runs them
+    pub const ALL: [Phase; 5] = [
+        Phase::Parse,
+        Phase::Extract,
+        Phase::Hash,
+        Phase::Record,
+        Phase::Deploy,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Extract => "extract",
+            Phase::Hash => "hash",
+            Phase::Record => "record",
+            Phase::Deploy => "deploy",
+        }
+    }
+}
+
+/// Time spent and work done in one phase
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    pub duration_ms: f64,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Phase timings of one operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimingReport {
+    /// Wall time from the start of the instrument to the report
+    pub total_ms: f64,
+    /// Phases that ran, in `Phase::ALL` order
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl TimingReport {
+    /// The timing of `phase`, if it ran
+    pub fn phase(&self, phase: Phase) -> Option<&PhaseTiming> {
+        self.phases.iter().find(|timing| timing.phase == phase)
+    }
+
+    /// Time spent in the phases together; the rest of `total_ms` went to
+    /// unmeasured work such as conflict checks and scriptlets
+    pub fn phases_ms(&self) -> f64 {
+        self.phases.iter().map(|timing| timing.duration_ms).sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    duration: Duration,
+    files: u64,
+    bytes: u64,
+}
+
+/// Collects phase timings; shared by reference, so phases can record from
+/// wherever they run
+#[derive(Debug)]
+pub struct Instrument {
+    started: Instant,
+    phases: Mutex<BTreeMap<Phase, Totals>>,
+}
+
+impl Instrument {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            phases: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Run `f` and add its duration to `phase`
+    pub fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add_duration(phase, start.elapsed());
+        result
+    }
+
+    /// Add time spent in `phase`
+    pub fn add_duration(&self, phase: Phase, elapsed: Duration) {
+        self.phases
+            .lock()
+            .unwrap()
+            .entry(phase)
+            .or_default()
+            .duration += elapsed;
+    }
+
+    /// Add files and bytes processed by `phase`
+    pub fn count(&self, phase: Phase, files: u64, bytes: u64) {
+        let mut phases = self.phases.lock().unwrap();
+        let totals = phases.entry(phase).or_default();
+        totals.files += files;
+        totals.bytes += bytes;
+    }
+
+    /// The timings so far
+    pub fn report(&self) -> TimingReport {
+        let phases = self.phases.lock().unwrap();
+        TimingReport {
+            total_ms: millis(self.started.elapsed()),
+            phases: phases
+                .iter()
+                .map(|(&phase, totals)| PhaseTiming {
+                    phase,
+                    duration_ms: millis(totals.duration),
+                    files: totals.files,
+                    bytes: totals.bytes,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_sums_phases_in_order() {
+        let instrument = Instrument::new();
+        instrument.add_duration(Phase::Deploy, Duration::from_millis(3));
+        instrument.time(Phase::Parse, || {
+            std::thread::sleep(Duration::from_millis(2))
+        });
+        instrument.add_duration(Phase::Deploy, Duration::from_millis(1));
+        instrument.count(Phase::Deploy, 2, 100);
+        instrument.count(Phase::Deploy, 1, 50);
+
+        let report = instrument.report();
+        let phases: Vec<Phase> = report.phases.iter().map(|timing| timing.phase).collect();
+        assert_eq!(phases, vec![Phase::Parse, Phase::Deploy]);
+        assert!(report.phase(Phase::Parse).unwrap().duration_ms >= 2.0);
+        assert!(report.phase(Phase::Hash).is_none());
+
+        let deploy = report.phase(Phase::Deploy).unwrap();
+        assert_eq!(deploy.duration_ms, 4.0);
+        assert_eq!((deploy.files, deploy.bytes), (3, 150));
+        assert!(report.total_ms >= report.phase(Phase::Parse).unwrap().duration_ms);
+    }
+}
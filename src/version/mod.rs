@@ -4,12 +4,85 @@
 //!
 //! This module provides version parsing and comparison for RPM-style versions,
 //! including support for epoch:version-release format and version constraints.
+//!
+//! Each package format orders versions its own way, so comparisons between
+//! version strings from repositories go through [`compare_versions`] with the
+//! [`VersionScheme`] of the package:
+//! - RPM: `rpmvercmp`, where `~` sorts before anything and `^` after the end
+//! - Debian: dpkg's ordering of epoch, upstream version and revision, where
+//!   `~` sorts before anything, even the end of the string
+//! - Arch: pacman's `vercmp`, where a letter suffix (`1.0a`) is older than
+//!   the bare version and the pkgrel is only compared when both have one
+//!
+//! Code that keeps parsed versions around uses [`Version::parse_for_format`],
+//! which gives an [`RpmVersion`], a [`DebVersion`] or an [`ArchVersion`].
 
 use crate::error::{Error, Result};
-use semver::Version;
+use crate::packages::PackageFormatType;
 use std::cmp::Ordering;
 use std::fmt;
 
+/// The version ordering of a package format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VersionScheme {
+    Rpm,
+    Debian,
+    Arch,
+}
+
+impl VersionScheme {
+    /// The scheme of a package format
+    pub fn for_format(format: PackageFormatType) -> Self {
+        match format {
+            PackageFormatType::Rpm => VersionScheme::Rpm,
+            PackageFormatType::Deb => VersionScheme::Debian,
+            PackageFormatType::Arch => VersionScheme::Arch,
+        }
+    }
+
+    /// The scheme for a package file name or URL, by its extension
+    ///
+    /// Anything that is not a `.deb` or an Arch package is taken as RPM.
+    pub fn for_location(location: &str) -> Self {
+        if location.ends_with(".deb") {
+            VersionScheme::Debian
+        } else if location.contains(".pkg.tar") {
+            VersionScheme::Arch
+        } else {
+            VersionScheme::Rpm
+        }
+    }
+}
+
+/// Compare two version strings the way their package format does
+pub fn compare_versions(scheme: VersionScheme, a: &str, b: &str) -> Ordering {
+    match scheme {
+        VersionScheme::Rpm => match (RpmVersion::parse(a), RpmVersion::parse(b)) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => rpmvercmp(a, b),
+        },
+        VersionScheme::Debian => match (DebVersion::parse(a), DebVersion::parse(b)) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => dpkg_verrevcmp(a, b),
+        },
+        VersionScheme::Arch => match (ArchVersion::parse(a), ArchVersion::parse(b)) {
+            (Ok(a), Ok(b)) => a.compare(&b),
+            _ => alpm_segment_cmp(a, b),
+        },
+    }
+}
+
+/// The epoch of a version string, 0 when it has no `epoch:` prefix
+///
+/// RPM, Debian and Arch all write a non-zero epoch as a leading number
+/// followed by a colon, so this holds for any of their version strings.
+pub fn epoch_of(version: &str) -> u64 {
+    version
+        .split_once(':')
+        .and_then(|(epoch, _)| epoch.parse().ok())
+        .unwrap_or(0)
+}
+
 /// A parsed RPM version with epoch, version, and release components
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RpmVersion {
@@ -35,9 +108,9 @@ impl RpmVersion {
             ("0", s)
         };
 
-        let epoch = epoch_str.parse::<u64>().map_err(|e| {
-            Error::InitError(format!("Invalid epoch in version '{}': {}", s, e))
-        })?;
+        let epoch = epoch_str
+            .parse::<u64>()
+            .map_err(|e| Error::ParseError(format!("Invalid epoch in version '{}': {}", s, e)))?;
 
         let (version, release) = if let Some(dash_pos) = rest.find('-') {
             let (v, r) = rest.split_at(dash_pos);
@@ -47,7 +120,7 @@ impl RpmVersion {
         };
 
         if version.is_empty() {
-            return Err(Error::InitError(format!(
+            return Err(Error::ParseError(format!(
                 "Empty version component in '{}'",
                 s
             )));
@@ -60,51 +133,434 @@ impl RpmVersion {
         })
     }
 
-    /// Convert to a semver::Version for comparison
+    /// Compare two RPM versions
     ///
-    /// RPM versions may not be semver-compliant, so we normalize them:
-    /// - If version parses as semver, use it directly
-    /// - Otherwise, try to extract major.minor.patch from version string
-    fn to_semver(&self) -> Result<Version> {
-        // First try to parse version directly
-        if let Ok(v) = Version::parse(&self.version) {
-            return Ok(v);
-        }
+    /// Epochs compare as numbers, versions and releases with `rpmvercmp`.
+    /// A missing release sorts before any release.
+    pub fn compare(&self, other: &RpmVersion) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| rpmvercmp(&self.version, &other.version))
+            .then_with(|| match (&self.release, &other.release) {
+                (Some(a), Some(b)) => rpmvercmp(a, b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            })
+    }
+}
 
-        // Try to extract numbers and create a semver-compliant version
-        let parts: Vec<&str> = self.version.split('.').collect();
-        let major = parts.first().and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
-        let minor = parts.get(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
-        let patch = parts.get(2).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+/// Take the leading run of bytes matching `class` off `s`
+fn take_while(s: &mut &[u8], class: impl Fn(u8) -> bool) -> Vec<u8> {
+    let len = s.iter().position(|&c| !class(c)).unwrap_or(s.len());
+    let (run, rest) = s.split_at(len);
+    *s = rest;
+    run.to_vec()
+}
 
-        Ok(Version::new(major, minor, patch))
+/// Compare two numeric or alphabetic segments of the same kind
+fn compare_segments(a: &[u8], b: &[u8], numeric: bool) -> Ordering {
+    if numeric {
+        let a = &a[a.iter().position(|&c| c != b'0').unwrap_or(a.len())..];
+        let b = &b[b.iter().position(|&c| c != b'0').unwrap_or(b.len())..];
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    } else {
+        a.cmp(b)
     }
+}
 
-    /// Compare two RPM versions
-    pub fn compare(&self, other: &RpmVersion) -> Ordering {
-        // First compare epochs
-        match self.epoch.cmp(&other.epoch) {
+/// RPM's segment-wise comparison of a version or release
+///
+/// Runs of digits and runs of letters are compared in turn, numbers
+/// numerically; everything else only separates segments. A number is newer
+/// than letters, `~` sorts before anything including the end, and `^` sorts
+/// after the end but before anything else.
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let separator = |c: u8| !c.is_ascii_alphanumeric() && c != b'~' && c != b'^';
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        take_while(&mut a, separator);
+        take_while(&mut b, separator);
+
+        if a.first() == Some(&b'~') || b.first() == Some(&b'~') {
+            if a.first() != Some(&b'~') {
+                return Ordering::Greater;
+            }
+            if b.first() != Some(&b'~') {
+                return Ordering::Less;
+            }
+            (a, b) = (&a[1..], &b[1..]);
+            continue;
+        }
+        if a.first() == Some(&b'^') || b.first() == Some(&b'^') {
+            if a.is_empty() {
+                return Ordering::Less;
+            }
+            if b.is_empty() {
+                return Ordering::Greater;
+            }
+            if a[0] != b'^' {
+                return Ordering::Greater;
+            }
+            if b[0] != b'^' {
+                return Ordering::Less;
+            }
+            (a, b) = (&a[1..], &b[1..]);
+            continue;
+        }
+
+        if a.is_empty() || b.is_empty() {
+            return a.len().cmp(&b.len());
+        }
+
+        let numeric = a[0].is_ascii_digit();
+        let class = |c: u8| {
+            if numeric {
+                c.is_ascii_digit()
+            } else {
+                c.is_ascii_alphabetic()
+            }
+        };
+        let (one, two) = (take_while(&mut a, class), take_while(&mut b, class));
+        if two.is_empty() {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+        match compare_segments(&one, &two, numeric) {
             Ordering::Equal => {}
             ord => return ord,
         }
+    }
+}
+
+/// A parsed Debian version: `[epoch:]upstream[-revision]`
+///
+/// Ordered like dpkg: epochs as numbers, then the upstream version, then the
+/// revision, both with [`dpkg_verrevcmp`]. A missing revision compares like
+/// `0`, so equality follows the ordering rather than the text.
+#[derive(Debug, Clone)]
+pub struct DebVersion {
+    pub epoch: u64,
+    pub upstream: String,
+    pub revision: Option<String>,
+}
+
+impl DebVersion {
+    /// Parse a Debian version string
+    ///
+    /// The epoch ends at the first `:`, the revision starts after the last
+    /// `-`. Examples:
+    /// - "2.38-3ubuntu1" → epoch=0, upstream="2.38", revision=Some("3ubuntu1")
+    /// - "1:2.0~rc1" → epoch=1, upstream="2.0~rc1", revision=None
+    /// - "1.2-3-4" → epoch=0, upstream="1.2-3", revision=Some("4")
+    pub fn parse(s: &str) -> Result<Self> {
+        let (epoch, rest) = match s.split_once(':') {
+            Some((epoch, rest)) => {
+                let epoch = epoch.parse::<u64>().map_err(|e| {
+                    Error::ParseError(format!("Invalid epoch in Debian version '{}': {}", s, e))
+                })?;
+                (epoch, rest)
+            }
+            None => (0, s),
+        };
 
-        // Then compare versions using semver if possible
-        match (self.to_semver(), other.to_semver()) {
-            (Ok(v1), Ok(v2)) => match v1.cmp(&v2) {
+        let (upstream, revision) = match rest.rsplit_once('-') {
+            Some((upstream, revision)) => (upstream, Some(revision.to_string())),
+            None => (rest, None),
+        };
+        if upstream.is_empty() {
+            return Err(Error::ParseError(format!(
+                "Empty upstream version in '{}'",
+                s
+            )));
+        }
+
+        Ok(Self {
+            epoch,
+            upstream: upstream.to_string(),
+            revision,
+        })
+    }
+
+    /// Compare two Debian versions
+    pub fn compare(&self, other: &DebVersion) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| dpkg_verrevcmp(&self.upstream, &other.upstream))
+            .then_with(|| {
+                dpkg_verrevcmp(
+                    self.revision.as_deref().unwrap_or(""),
+                    other.revision.as_deref().unwrap_or(""),
+                )
+            })
+    }
+}
+
+impl fmt::Display for DebVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.epoch > 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        write!(f, "{}", self.upstream)?;
+        if let Some(ref revision) = self.revision {
+            write!(f, "-{}", revision)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for DebVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.compare(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DebVersion {}
+
+impl Ord for DebVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.compare(other)
+    }
+}
+
+impl PartialOrd for DebVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A version parsed by the rules of its package format
+///
+/// Versions of different formats have no order between them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Version {
+    Rpm(RpmVersion),
+    Deb(DebVersion),
+    Arch(ArchVersion),
+}
+
+impl Version {
+    /// Parse a version the way `scheme` does
+    pub fn parse(scheme: VersionScheme, s: &str) -> Result<Self> {
+        match scheme {
+            VersionScheme::Rpm => RpmVersion::parse(s).map(Version::Rpm),
+            VersionScheme::Debian => DebVersion::parse(s).map(Version::Deb),
+            VersionScheme::Arch => ArchVersion::parse(s).map(Version::Arch),
+        }
+    }
+
+    /// Parse a version of a package of the given format
+    pub fn parse_for_format(format: PackageFormatType, s: &str) -> Result<Self> {
+        Self::parse(VersionScheme::for_format(format), s)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Version::Rpm(version) => version.fmt(f),
+            Version::Deb(version) => version.fmt(f),
+            Version::Arch(version) => version.fmt(f),
+        }
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Version::Rpm(a), Version::Rpm(b)) => Some(a.cmp(b)),
+            (Version::Deb(a), Version::Deb(b)) => Some(a.cmp(b)),
+            (Version::Arch(a), Version::Arch(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+/// dpkg's `verrevcmp` on one upstream version or revision
+///
+/// Non-digit runs compare character by character with letters before other
+/// characters and `~` before everything, the end of the string included;
+/// digit runs compare numerically.
+fn dpkg_verrevcmp(a: &str, b: &str) -> Ordering {
+    fn order(c: Option<&u8>) -> i32 {
+        match c {
+            None => 0,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => *c as i32,
+            Some(b'~') => -1,
+            Some(c) => *c as i32 + 256,
+        }
+    }
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() || !b.is_empty() {
+        while a.first().is_some_and(|c| !c.is_ascii_digit())
+            || b.first().is_some_and(|c| !c.is_ascii_digit())
+        {
+            match order(a.first()).cmp(&order(b.first())) {
                 Ordering::Equal => {}
                 ord => return ord,
-            },
-            _ => {
-                // Fall back to string comparison if semver parsing fails
-                match self.version.cmp(&other.version) {
-                    Ordering::Equal => {}
-                    ord => return ord,
-                }
             }
+            a = a.get(1..).unwrap_or_default();
+            b = b.get(1..).unwrap_or_default();
+        }
+        let (one, two) = (
+            take_while(&mut a, |c| c.is_ascii_digit()),
+            take_while(&mut b, |c| c.is_ascii_digit()),
+        );
+        match compare_segments(&one, &two, true) {
+            Ordering::Equal => {}
+            ord => return ord,
+        }
+    }
+    Ordering::Equal
+}
+
+/// A parsed Arch version: `[epoch:]pkgver[-pkgrel]`
+///
+/// Ordered like pacman's `vercmp`: epochs, then pkgvers with
+/// [`alpm_segment_cmp`], then pkgrels, but only when both versions have
+/// one. Because of that last rule `1.5` equals both `1.5-1` and `1.5-2`
+/// while those two differ, so the type is only `PartialOrd`: sorting by it
+/// is not well defined.
+#[derive(Debug, Clone)]
+pub struct ArchVersion {
+    pub epoch: u64,
+    pub pkgver: String,
+    pub pkgrel: Option<String>,
+}
+
+impl ArchVersion {
+    /// Parse an Arch version string
+    ///
+    /// Only a run of digits before the first `:` is an epoch; the pkgrel
+    /// starts after the last `-`. Examples:
+    /// - "1.2.3-2" → epoch=0, pkgver="1.2.3", pkgrel=Some("2")
+    /// - "1:2.0-1" → epoch=1, pkgver="2.0", pkgrel=Some("1")
+    /// - "2.0rc1" → epoch=0, pkgver="2.0rc1", pkgrel=None
+    pub fn parse(s: &str) -> Result<Self> {
+        let (epoch, rest) = match s.split_once(':') {
+            Some((epoch, rest)) if epoch.bytes().all(|c| c.is_ascii_digit()) => {
+                let epoch = if epoch.is_empty() {
+                    0
+                } else {
+                    epoch.parse::<u64>().map_err(|e| {
+                        Error::ParseError(format!("Invalid epoch in Arch version '{}': {}", s, e))
+                    })?
+                };
+                (epoch, rest)
+            }
+            _ => (0, s),
+        };
+
+        let (pkgver, pkgrel) = match rest.rsplit_once('-') {
+            Some((pkgver, pkgrel)) => (pkgver, Some(pkgrel.to_string())),
+            None => (rest, None),
+        };
+        if pkgver.is_empty() {
+            return Err(Error::ParseError(format!("Empty pkgver in '{}'", s)));
         }
 
-        // Finally compare releases (lexicographically)
-        self.release.cmp(&other.release)
+        Ok(Self {
+            epoch,
+            pkgver: pkgver.to_string(),
+            pkgrel,
+        })
+    }
+
+    /// Compare two Arch versions
+    pub fn compare(&self, other: &ArchVersion) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| alpm_segment_cmp(&self.pkgver, &other.pkgver))
+            .then_with(|| match (&self.pkgrel, &other.pkgrel) {
+                (Some(a), Some(b)) => alpm_segment_cmp(a, b),
+                _ => Ordering::Equal,
+            })
+    }
+}
+
+impl fmt::Display for ArchVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.epoch > 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        write!(f, "{}", self.pkgver)?;
+        if let Some(ref pkgrel) = self.pkgrel {
+            write!(f, "-{}", pkgrel)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for ArchVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.compare(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for ArchVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.compare(other))
+    }
+}
+
+/// pacman's variant of `rpmvercmp`
+///
+/// Every non-alphanumeric character is a separator, and more separators
+/// make a newer version. When one side runs out, leftover letters are older
+/// than nothing (`1.0a` < `1.0`) and leftover numbers are newer.
+fn alpm_segment_cmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let separator = |c: u8| !c.is_ascii_alphanumeric();
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() && !b.is_empty() {
+        let (gap_a, gap_b) = (
+            take_while(&mut a, separator).len(),
+            take_while(&mut b, separator).len(),
+        );
+        if a.is_empty() || b.is_empty() {
+            break;
+        }
+        if gap_a != gap_b {
+            return gap_a.cmp(&gap_b);
+        }
+
+        let numeric = a[0].is_ascii_digit();
+        let class = |c: u8| {
+            if numeric {
+                c.is_ascii_digit()
+            } else {
+                c.is_ascii_alphabetic()
+            }
+        };
+        let (one, two) = (take_while(&mut a, class), take_while(&mut b, class));
+        if two.is_empty() {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+        match compare_segments(&one, &two, numeric) {
+            Ordering::Equal => {}
+            ord => return ord,
+        }
+    }
+
+    if a.is_empty() && b.is_empty() {
+        Ordering::Equal
+    } else if (a.is_empty() && !b[0].is_ascii_alphabetic())
+        || a.first().is_some_and(|c| c.is_ascii_alphabetic())
+    {
+        Ordering::Less
+    } else {
+        Ordering::Greater
     }
 }
 
@@ -134,22 +590,27 @@ impl PartialOrd for RpmVersion {
 }
 
 /// Version constraint operators
+///
+/// Constraints keep the version text as written; [`VersionConstraint::satisfies`]
+/// compares it in the [`VersionScheme`] of the package being checked, so the
+/// same constraint orders `1.0~rc1` the dpkg way for a Debian package and
+/// `1.0a` the pacman way for an Arch one.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VersionConstraint {
     /// Any version is acceptable
     Any,
     /// Exact version match
-    Exact(RpmVersion),
+    Exact(String),
     /// Greater than
-    GreaterThan(RpmVersion),
+    GreaterThan(String),
     /// Greater than or equal
-    GreaterOrEqual(RpmVersion),
+    GreaterOrEqual(String),
     /// Less than
-    LessThan(RpmVersion),
+    LessThan(String),
     /// Less than or equal
-    LessOrEqual(RpmVersion),
+    LessOrEqual(String),
     /// Not equal
-    NotEqual(RpmVersion),
+    NotEqual(String),
     /// Both constraints must be satisfied (for ranges like ">= 1.0, < 2.0")
     And(Box<VersionConstraint>, Box<VersionConstraint>),
 }
@@ -162,6 +623,7 @@ impl VersionConstraint {
     /// - "< 2.0.0" → LessThan(2.0.0)
     /// - "= 1.5.0" → Exact(1.5.0)
     /// - "> 1.0" → GreaterThan(1.0)
+    /// - ">> 1.0" → GreaterThan(1.0), and "<< 2.0" → LessThan(2.0), as Debian writes them
     pub fn parse(s: &str) -> Result<Self> {
         let s = s.trim();
 
@@ -181,42 +643,42 @@ impl VersionConstraint {
 
         // Parse single constraint
         if let Some(rest) = s.strip_prefix(">=") {
-            let version = RpmVersion::parse(rest.trim())?;
-            Ok(VersionConstraint::GreaterOrEqual(version))
+            Ok(VersionConstraint::GreaterOrEqual(constraint_version(
+                s, rest,
+            )?))
         } else if let Some(rest) = s.strip_prefix("<=") {
-            let version = RpmVersion::parse(rest.trim())?;
-            Ok(VersionConstraint::LessOrEqual(version))
+            Ok(VersionConstraint::LessOrEqual(constraint_version(s, rest)?))
         } else if let Some(rest) = s.strip_prefix("!=") {
-            let version = RpmVersion::parse(rest.trim())?;
-            Ok(VersionConstraint::NotEqual(version))
+            Ok(VersionConstraint::NotEqual(constraint_version(s, rest)?))
+        } else if let Some(rest) = s.strip_prefix(">>") {
+            Ok(VersionConstraint::GreaterThan(constraint_version(s, rest)?))
+        } else if let Some(rest) = s.strip_prefix("<<") {
+            Ok(VersionConstraint::LessThan(constraint_version(s, rest)?))
         } else if let Some(rest) = s.strip_prefix('>') {
-            let version = RpmVersion::parse(rest.trim())?;
-            Ok(VersionConstraint::GreaterThan(version))
+            Ok(VersionConstraint::GreaterThan(constraint_version(s, rest)?))
         } else if let Some(rest) = s.strip_prefix('<') {
-            let version = RpmVersion::parse(rest.trim())?;
-            Ok(VersionConstraint::LessThan(version))
+            Ok(VersionConstraint::LessThan(constraint_version(s, rest)?))
         } else if let Some(rest) = s.strip_prefix('=') {
-            let version = RpmVersion::parse(rest.trim())?;
-            Ok(VersionConstraint::Exact(version))
+            Ok(VersionConstraint::Exact(constraint_version(s, rest)?))
         } else {
             // No operator means exact match
-            let version = RpmVersion::parse(s)?;
-            Ok(VersionConstraint::Exact(version))
+            Ok(VersionConstraint::Exact(constraint_version(s, s)?))
         }
     }
 
-    /// Check if a version satisfies this constraint
-    pub fn satisfies(&self, version: &RpmVersion) -> bool {
+    /// Check if a version satisfies this constraint, ordered by `scheme`
+    pub fn satisfies(&self, scheme: VersionScheme, version: &str) -> bool {
+        let cmp = |v: &str| compare_versions(scheme, version, v);
         match self {
             VersionConstraint::Any => true,
-            VersionConstraint::Exact(v) => version == v,
-            VersionConstraint::GreaterThan(v) => version > v,
-            VersionConstraint::GreaterOrEqual(v) => version >= v,
-            VersionConstraint::LessThan(v) => version < v,
-            VersionConstraint::LessOrEqual(v) => version <= v,
-            VersionConstraint::NotEqual(v) => version != v,
+            VersionConstraint::Exact(v) => cmp(v).is_eq(),
+            VersionConstraint::GreaterThan(v) => cmp(v).is_gt(),
+            VersionConstraint::GreaterOrEqual(v) => cmp(v).is_ge(),
+            VersionConstraint::LessThan(v) => cmp(v).is_lt(),
+            VersionConstraint::LessOrEqual(v) => cmp(v).is_le(),
+            VersionConstraint::NotEqual(v) => cmp(v).is_ne(),
             VersionConstraint::And(left, right) => {
-                left.satisfies(version) && right.satisfies(version)
+                left.satisfies(scheme, version) && right.satisfies(scheme, version)
             }
         }
     }
@@ -236,6 +698,18 @@ impl VersionConstraint {
     }
 }
 
+/// The version after a constraint's operator, which must not be empty
+fn constraint_version(constraint: &str, rest: &str) -> Result<String> {
+    let version = rest.trim();
+    if version.is_empty() {
+        return Err(Error::ParseError(format!(
+            "No version in constraint '{}'",
+            constraint
+        )));
+    }
+    Ok(version.to_string())
+}
+
 impl fmt::Display for VersionConstraint {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -251,6 +725,17 @@ impl fmt::Display for VersionConstraint {
     }
 }
 
+/// Split an entry like "glibc>=2.34" or "foo < 2.0" into name and constraint
+///
+/// The constraint keeps its operator (">=2.34") so it can be handed to
+/// [`VersionConstraint::parse`]; it is `None` for a bare name.
+pub fn split_constraint(entry: &str) -> (&str, Option<&str>) {
+    match entry.find(['<', '>', '=']) {
+        Some(pos) => (entry[..pos].trim(), Some(entry[pos..].trim())),
+        None => (entry.trim(), None),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -292,6 +777,23 @@ mod tests {
         let v1 = RpmVersion::parse("1:1.0.0").unwrap();
         let v2 = RpmVersion::parse("0:2.0.0").unwrap();
         assert!(v1 > v2); // Higher epoch wins even with lower version
+
+        assert_eq!(
+            compare_versions(VersionScheme::Rpm, "1:1.0-1", "2.0-1"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_versions(VersionScheme::Rpm, "0:2.0-1", "2.0-1"),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn test_epoch_of() {
+        assert_eq!(epoch_of("1:2.3.4-5.fc43"), 1);
+        assert_eq!(epoch_of("2.3.4-5.fc43"), 0);
+        assert_eq!(epoch_of("0:1.0"), 0);
+        assert_eq!(epoch_of("1.0+dfsg:2"), 0);
     }
 
     #[test]
@@ -308,52 +810,343 @@ mod tests {
         assert!(v1 < v2);
     }
 
+    #[test]
+    fn test_rpm_version_compare_ugly_cases() {
+        let less = |a: &str, b: &str| RpmVersion::parse(a).unwrap() < RpmVersion::parse(b).unwrap();
+        assert!(less("1.2.3-9", "1.2.3-10"));
+        assert!(less("1.0-1.el8", "1.0-1.el9"));
+        assert!(less("1.0-1.fc40", "1.0-2.fc39"));
+        assert!(less("1.0-1.el8", "1.0-1.1"));
+        assert!(less("1.0-1", "1.0-1a"));
+        assert!(less("1.0~rc1-1", "1.0-1"));
+        assert!(less("1.0-1", "1.0^git1-1"));
+        assert!(less("2.0.1-1", "1:1.9-1"));
+        assert!(less("1.9", "2.0.1"));
+        assert!(less("5.2", "5.10"));
+        assert_eq!(
+            RpmVersion::parse("1.01")
+                .unwrap()
+                .cmp(&RpmVersion::parse("1.1").unwrap()),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn test_rpmvercmp() {
+        assert_eq!(rpmvercmp("1.0", "1.0"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0~rc2"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0^", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0^", "1.0.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0a", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0_1", "1.0.1"), Ordering::Equal);
+        assert_eq!(rpmvercmp("2.0a", "2.0.1"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_deb_version_parse() {
+        let v = DebVersion::parse("1:2.38-3ubuntu1").unwrap();
+        assert_eq!(
+            (v.epoch, v.upstream.as_str(), v.revision.as_deref()),
+            (1, "2.38", Some("3ubuntu1"))
+        );
+        let v = DebVersion::parse("1.2-3-4").unwrap();
+        assert_eq!(
+            (v.upstream.as_str(), v.revision.as_deref()),
+            ("1.2-3", Some("4"))
+        );
+        let v = DebVersion::parse("2.0~rc1").unwrap();
+        assert_eq!((v.epoch, v.revision.as_deref()), (0, None));
+        assert_eq!(v.to_string(), "2.0~rc1");
+        assert_eq!(
+            DebVersion::parse("1:2.38-3").unwrap().to_string(),
+            "1:2.38-3"
+        );
+
+        assert!(DebVersion::parse("a:1.0").is_err());
+        assert!(DebVersion::parse("1:").is_err());
+        assert!(DebVersion::parse("-1").is_err());
+    }
+
+    #[test]
+    fn test_deb_version_compare() {
+        use Ordering::{Equal, Greater, Less};
+
+        // Cases from dpkg's version tests and Debian policy
+        let cases = [
+            ("1.0", "1.0", Equal),
+            ("0:1.0", "1.0", Equal),
+            ("1.0", "1.0-0", Equal),
+            ("1.0-00", "1.0-0", Equal),
+            ("010", "10", Equal),
+            ("0:0-0", "0:0-0", Equal),
+            ("0:1-1", "0:2-1", Less),
+            ("0:1-1", "1:1-1", Less),
+            ("0:1-1", "0:1-2", Less),
+            ("1:1.0", "2.0", Greater),
+            ("1.0-10", "1.0-9", Greater),
+            ("1.2", "1.10", Less),
+            ("1.0~rc1", "1.0", Less),
+            ("1.0~rc1", "1.0~rc2", Less),
+            ("1.0~~", "1.0~~a", Less),
+            ("1.0~~a", "1.0~", Less),
+            ("1.0~", "1.0", Less),
+            ("1.0", "1.0a", Less),
+            ("1.0a", "1.0+", Less),
+            ("1.0+b1", "1.0", Greater),
+            ("1.0.1", "1.0+1", Greater),
+            ("7.6p2-4", "7.6-0", Greater),
+            ("2.30-1ubuntu2", "2.30-1ubuntu10", Less),
+            ("1.2.3-1-2", "1.2.3-1-10", Less),
+            ("1.0-1~bpo1", "1.0-1", Less),
+            ("2:1.0-1", "1:9.9-9", Greater),
+        ];
+        for (a, b, expected) in cases {
+            let (va, vb) = (DebVersion::parse(a).unwrap(), DebVersion::parse(b).unwrap());
+            assert_eq!(va.cmp(&vb), expected, "{} vs {}", a, b);
+            assert_eq!(vb.cmp(&va), expected.reverse(), "{} vs {}", b, a);
+            assert_eq!(
+                compare_versions(VersionScheme::Debian, a, b),
+                expected,
+                "{} vs {}",
+                a,
+                b
+            );
+        }
+        assert_eq!(
+            DebVersion::parse("1.0").unwrap(),
+            DebVersion::parse("1.0-0").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_version_parse_for_format() {
+        let deb = |s| Version::parse_for_format(PackageFormatType::Deb, s).unwrap();
+        let rpm = |s| Version::parse_for_format(PackageFormatType::Rpm, s).unwrap();
+        let arch = |s| Version::parse_for_format(PackageFormatType::Arch, s).unwrap();
+        assert!(matches!(deb("1.0-1"), Version::Deb(_)));
+        assert!(deb("1.0~rc1-1") < deb("1.0-1"));
+        assert!(rpm("1.0-10") > rpm("1.0-9"));
+        assert!(arch("1.0rc1-1") < arch("1.0-1"));
+        assert!(matches!(arch("1:1.0-1"), Version::Arch(_)));
+        assert_eq!(deb("1.0-1").partial_cmp(&rpm("1.0-1")), None);
+    }
+
+    #[test]
+    fn test_arch_compare() {
+        let cmp = |a, b| compare_versions(VersionScheme::Arch, a, b);
+        assert_eq!(cmp("1.2.3-1", "1.2.3-2"), Ordering::Less);
+        assert_eq!(cmp("1.2.3-10", "1.2.3-9"), Ordering::Greater);
+        assert_eq!(cmp("1.0a", "1.0"), Ordering::Less);
+        assert_eq!(cmp("1.0alpha-1", "1.0-1"), Ordering::Less);
+        assert_eq!(cmp("1:1.0-1", "2.0-1"), Ordering::Greater);
+        // pkgrel only counts when both have one
+        assert_eq!(cmp("1.0", "1.0-5"), Ordering::Equal);
+        assert_eq!(cmp("1.0.1", "1.0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_arch_version_parse() {
+        let v = ArchVersion::parse("1:2.0-1").unwrap();
+        assert_eq!(
+            (v.epoch, v.pkgver.as_str(), v.pkgrel.as_deref()),
+            (1, "2.0", Some("1"))
+        );
+        let v = ArchVersion::parse("1.226_1-1").unwrap();
+        assert_eq!(
+            (v.epoch, v.pkgver.as_str(), v.pkgrel.as_deref()),
+            (0, "1.226_1", Some("1"))
+        );
+        assert_eq!(ArchVersion::parse("2.0rc1").unwrap().pkgrel, None);
+        assert_eq!(
+            ArchVersion::parse("1:2.0-1").unwrap().to_string(),
+            "1:2.0-1"
+        );
+        assert!(ArchVersion::parse("-1").is_err());
+    }
+
+    #[test]
+    fn test_arch_version_compare() {
+        use Ordering::{Equal, Greater, Less};
+
+        // pacman's vercmp test cases
+        let cases = [
+            // all similar length, no pkgrel
+            ("1.5.0", "1.5.0", Equal),
+            ("1.5.1", "1.5.0", Greater),
+            // mixed length
+            ("1.5.1", "1.5", Greater),
+            // with pkgrel, simple
+            ("1.5.0-1", "1.5.0-1", Equal),
+            ("1.5.0-1", "1.5.0-2", Less),
+            ("1.5.0-1", "1.5.1-1", Less),
+            ("1.5.0-2", "1.5.1-1", Less),
+            // with pkgrel, mixed lengths
+            ("1.5-1", "1.5.1-1", Less),
+            ("1.5-2", "1.5.1-1", Less),
+            ("1.5-2", "1.5.1-2", Less),
+            // mixed pkgrel inclusion
+            ("1.5", "1.5-1", Equal),
+            ("1.5.1", "1.5-1", Greater),
+            ("1.1-1", "1.1", Equal),
+            ("1.0-1", "1.1", Less),
+            ("1.1-1", "1.0", Greater),
+            // alphanumeric versions
+            ("1.5b-1", "1.5-1", Less),
+            ("1.5b", "1.5", Less),
+            ("1.5b-1", "1.5", Less),
+            ("1.5b", "1.5.1", Less),
+            // from the manpage
+            ("1.0a", "1.0alpha", Less),
+            ("1.0alpha", "1.0b", Less),
+            ("1.0b", "1.0beta", Less),
+            ("1.0beta", "1.0rc", Less),
+            ("1.0rc", "1.0", Less),
+            // alpha-dotted versions
+            ("1.5.a", "1.5", Greater),
+            ("1.5.b", "1.5.a", Greater),
+            ("1.5.1", "1.5.b", Greater),
+            // alpha dots and dashes
+            ("1.5.b-1", "1.5.b", Equal),
+            ("1.5-1", "1.5.b", Less),
+            // same/similar content, differing separators
+            ("2.0", "2_0", Equal),
+            ("2.0_a", "2_0.a", Equal),
+            ("2.0a", "2.0.a", Less),
+            ("2___a", "2_a", Greater),
+            // epoch included version comparisons
+            ("0:1.0", "0:1.0", Equal),
+            ("0:1.0", "0:1.1", Less),
+            ("1:1.0", "0:1.0", Greater),
+            ("1:1.0", "0:1.1", Greater),
+            ("1:1.0", "2:1.1", Less),
+            // epoch + sometimes present pkgrel
+            ("1:1.0", "0:1.0-1", Greater),
+            ("1:1.0-1", "0:1.1-1", Greater),
+            // epoch included on one version
+            ("0:1.0", "1.0", Equal),
+            ("0:1.0", "1.1", Less),
+            ("0:1.1", "1.0", Greater),
+            ("1:1.0", "1.0", Greater),
+            ("1:1.0", "1.1", Greater),
+            ("1:1.1", "1.1", Greater),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(
+                compare_versions(VersionScheme::Arch, a, b),
+                expected,
+                "{} vs {}",
+                a,
+                b
+            );
+            assert_eq!(
+                compare_versions(VersionScheme::Arch, b, a),
+                expected.reverse(),
+                "{} vs {}",
+                b,
+                a
+            );
+        }
+
+        // Where RPM ordering disagrees
+        assert_eq!(compare_versions(VersionScheme::Rpm, "1.0a", "1.0"), Greater);
+        assert_eq!(compare_versions(VersionScheme::Arch, "1.0a", "1.0"), Less);
+    }
+
+    #[test]
+    fn test_version_scheme_for_location() {
+        assert_eq!(
+            VersionScheme::for_location("pool/main/z/zlib_1.3.deb"),
+            VersionScheme::Debian
+        );
+        assert_eq!(
+            VersionScheme::for_location("zlib-1.3-1-x86_64.pkg.tar.zst"),
+            VersionScheme::Arch
+        );
+        assert_eq!(
+            VersionScheme::for_location("zlib-1.3-1.fc40.x86_64.rpm"),
+            VersionScheme::Rpm
+        );
+    }
+
     #[test]
     fn test_version_constraint_parse_exact() {
         let c = VersionConstraint::parse("1.2.3").unwrap();
-        let v = RpmVersion::parse("1.2.3").unwrap();
-        assert!(c.satisfies(&v));
+        assert!(c.satisfies(VersionScheme::Rpm, "1.2.3"));
     }
 
     #[test]
     fn test_version_constraint_parse_greater_or_equal() {
         let c = VersionConstraint::parse(">= 1.2.0").unwrap();
-        let v1 = RpmVersion::parse("1.2.0").unwrap();
-        let v2 = RpmVersion::parse("1.3.0").unwrap();
-        let v3 = RpmVersion::parse("1.1.0").unwrap();
 
-        assert!(c.satisfies(&v1));
-        assert!(c.satisfies(&v2));
-        assert!(!c.satisfies(&v3));
+        assert!(c.satisfies(VersionScheme::Rpm, "1.2.0"));
+        assert!(c.satisfies(VersionScheme::Rpm, "1.3.0"));
+        assert!(!c.satisfies(VersionScheme::Rpm, "1.1.0"));
     }
 
     #[test]
     fn test_version_constraint_parse_less_than() {
         let c = VersionConstraint::parse("< 2.0.0").unwrap();
-        let v1 = RpmVersion::parse("1.9.9").unwrap();
-        let v2 = RpmVersion::parse("2.0.0").unwrap();
 
-        assert!(c.satisfies(&v1));
-        assert!(!c.satisfies(&v2));
+        assert!(c.satisfies(VersionScheme::Rpm, "1.9.9"));
+        assert!(!c.satisfies(VersionScheme::Rpm, "2.0.0"));
     }
 
     #[test]
     fn test_version_constraint_and() {
         let c = VersionConstraint::parse(">= 1.0.0, < 2.0.0").unwrap();
-        let v1 = RpmVersion::parse("1.5.0").unwrap();
-        let v2 = RpmVersion::parse("2.0.0").unwrap();
-        let v3 = RpmVersion::parse("0.9.0").unwrap();
 
-        assert!(c.satisfies(&v1));
-        assert!(!c.satisfies(&v2));
-        assert!(!c.satisfies(&v3));
+        assert!(c.satisfies(VersionScheme::Rpm, "1.5.0"));
+        assert!(!c.satisfies(VersionScheme::Rpm, "2.0.0"));
+        assert!(!c.satisfies(VersionScheme::Rpm, "0.9.0"));
     }
 
     #[test]
     fn test_version_constraint_any() {
         let c = VersionConstraint::parse("*").unwrap();
-        let v = RpmVersion::parse("99.99.99").unwrap();
-        assert!(c.satisfies(&v));
+        assert!(c.satisfies(VersionScheme::Rpm, "99.99.99"));
+    }
+
+    #[test]
+    fn test_version_constraint_parse_rejects_missing_version() {
+        assert!(VersionConstraint::parse(">=").is_err());
+        assert!(VersionConstraint::parse(">= 1.0, <").is_err());
+    }
+
+    #[test]
+    fn test_version_constraint_parse_debian_operators() {
+        assert_eq!(
+            VersionConstraint::parse(">> 1.0").unwrap(),
+            VersionConstraint::GreaterThan("1.0".to_string())
+        );
+        assert_eq!(
+            VersionConstraint::parse("<<2.0-1").unwrap(),
+            VersionConstraint::LessThan("2.0-1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_version_constraint_uses_debian_ordering() {
+        // dpkg keeps the revision apart and sorts ~ before the end of the string
+        let c = VersionConstraint::parse(">= 2.0-1").unwrap();
+        assert!(!c.satisfies(VersionScheme::Debian, "2.0~rc1-1"));
+        assert!(c.satisfies(VersionScheme::Debian, "2.0-1"));
+        assert!(c.satisfies(VersionScheme::Debian, "1:1.0-1"));
+
+        let c = VersionConstraint::parse("< 1.0").unwrap();
+        assert!(c.satisfies(VersionScheme::Debian, "1.0~beta"));
+    }
+
+    #[test]
+    fn test_version_constraint_uses_arch_ordering() {
+        // pacman takes 1.0a as older than 1.0, rpm as newer
+        let c = VersionConstraint::parse(">= 1.0").unwrap();
+        assert!(!c.satisfies(VersionScheme::Arch, "1.0a"));
+        assert!(c.satisfies(VersionScheme::Rpm, "1.0a"));
+
+        // A constraint without a pkgrel matches every pkgrel of the version
+        let c = VersionConstraint::parse("= 1.5").unwrap();
+        assert!(c.satisfies(VersionScheme::Arch, "1.5-2"));
     }
 
     #[test]
@@ -363,6 +1156,10 @@ mod tests {
 
         let v2 = RpmVersion::parse("2:1.2.3-4.el8").unwrap();
         assert_eq!(v2.to_string(), "2:1.2.3-4.el8");
+
+        // A zero epoch is not shown
+        let v3 = RpmVersion::parse("0:1.2.3-4.el8").unwrap();
+        assert_eq!(v3.to_string(), "1.2.3-4.el8");
     }
 
     #[test]
@@ -373,4 +1170,11 @@ mod tests {
         let c2 = VersionConstraint::parse(">= 1.0.0, < 2.0.0").unwrap();
         assert_eq!(c2.to_string(), ">= 1.0.0, < 2.0.0");
     }
+
+    #[test]
+    fn test_split_constraint() {
+        assert_eq!(split_constraint("glibc>=2.34"), ("glibc", Some(">=2.34")));
+        assert_eq!(split_constraint("foo < 2.0"), ("foo", Some("< 2.0")));
+        assert_eq!(split_constraint("bash"), ("bash", None));
+    }
 }
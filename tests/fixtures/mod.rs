@@ -0,0 +1,440 @@
+// tests/fixtures/mod.rs
+
+//! Synthetic packages for integration tests
+//!
+//! `PackageFixture` describes a small package — name, version, relations and
+//! file payload — and writes it out as an Arch, Debian or RPM package that
+//! the real parsers accept. This lets install, upgrade, removal and conflict
+//! handling run end to end without real distribution packages.
+//!
+//! Relations are written as "name", "name>=1.0" or "name >= 1.0" and
+//! translated into each format's own syntax.
+
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// One of the `build_*` writers, for tests that loop over formats
+pub type BuildFn = fn(&PackageFixture, &Path) -> PathBuf;
+
+/// A file shipped by a fixture package
+pub struct FixtureFile {
+    /// Absolute install path, e.g. "/usr/bin/hello"
+    pub path: String,
+    pub content: Vec<u8>,
+    /// Permission bits, without the file type
+    pub mode: u32,
+    /// What the file points to if it is a symlink; symlinks have no content
+    pub link_target: Option<String>,
+    /// Written to Arch archives under `path` exactly, leading `/` included
+    pub verbatim: bool,
+    /// A directory the package owns; directories have no content
+    pub is_dir: bool,
+    /// Extended attributes, written as PAX records to Arch and Debian archives
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// Capabilities in `setcap` text form, written to RPM headers only
+    pub caps: Option<String>,
+    /// Owning user and group names of a directory; archives otherwise
+    /// record uid and gid 0
+    pub owner: Option<(String, String)>,
+}
+
+/// Description of a synthetic package
+pub struct PackageFixture {
+    pub name: String,
+    pub version: String,
+    pub release: String,
+    /// Written to RPM headers only
+    pub epoch: u32,
+    pub depends: Vec<String>,
+    pub provides: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub files: Vec<FixtureFile>,
+}
+
+impl PackageFixture {
+    /// A package with no relations and no files, release "1"
+    pub fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            release: "1".to_string(),
+            epoch: 0,
+            depends: Vec::new(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn release(mut self, release: &str) -> Self {
+        self.release = release.to_string();
+        self
+    }
+
+    pub fn epoch(mut self, epoch: u32) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    pub fn depends(mut self, relation: &str) -> Self {
+        self.depends.push(relation.to_string());
+        self
+    }
+
+    pub fn provides(mut self, relation: &str) -> Self {
+        self.provides.push(relation.to_string());
+        self
+    }
+
+    pub fn conflicts(mut self, relation: &str) -> Self {
+        self.conflicts.push(relation.to_string());
+        self
+    }
+
+    /// Ship a regular file with mode 0644
+    pub fn file(self, path: &str, content: &[u8]) -> Self {
+        self.file_with_mode(path, content, 0o644)
+    }
+
+    /// Ship a regular file with the given permission bits
+    pub fn file_with_mode(mut self, path: &str, content: &[u8], mode: u32) -> Self {
+        self.files.push(FixtureFile {
+            path: format!("/{}", path.trim_start_matches('/')),
+            content: content.to_vec(),
+            mode,
+            link_target: None,
+            verbatim: false,
+            is_dir: false,
+            xattrs: Vec::new(),
+            caps: None,
+            owner: None,
+        });
+        self
+    }
+
+    /// Ship a symlink pointing to `target`
+    pub fn symlink(mut self, path: &str, target: &str) -> Self {
+        self.files.push(FixtureFile {
+            path: format!("/{}", path.trim_start_matches('/')),
+            content: Vec::new(),
+            mode: 0o777,
+            link_target: Some(target.to_string()),
+            verbatim: false,
+            is_dir: false,
+            xattrs: Vec::new(),
+            caps: None,
+            owner: None,
+        });
+        self
+    }
+
+    /// Ship a directory with the given permission bits
+    pub fn dir(mut self, path: &str, mode: u32) -> Self {
+        self.files.push(FixtureFile {
+            path: format!("/{}", path.trim_start_matches('/')),
+            content: Vec::new(),
+            mode,
+            link_target: None,
+            verbatim: false,
+            is_dir: true,
+            xattrs: Vec::new(),
+            caps: None,
+            owner: None,
+        });
+        self
+    }
+
+    /// Ship a regular file whose archive entry is named `name` as given,
+    /// such as `/etc/passwd` or `../escape`, for the archives a broken or
+    /// malicious build produces; only Arch packages keep the name
+    pub fn raw_entry(mut self, name: &str, content: &[u8]) -> Self {
+        self.files.push(FixtureFile {
+            path: name.to_string(),
+            content: content.to_vec(),
+            mode: 0o644,
+            link_target: None,
+            verbatim: true,
+            is_dir: false,
+            xattrs: Vec::new(),
+            caps: None,
+            owner: None,
+        });
+        self
+    }
+
+    /// Give the file added last an extended attribute
+    pub fn xattr(mut self, name: &str, value: &[u8]) -> Self {
+        let file = self
+            .files
+            .last_mut()
+            .expect("xattr() follows the file it applies to");
+        file.xattrs.push((name.to_string(), value.to_vec()));
+        self
+    }
+
+    /// Give the file added last capabilities, such as "cap_net_raw=ep"
+    pub fn caps(mut self, caps: &str) -> Self {
+        self.files
+            .last_mut()
+            .expect("caps() follows the file it applies to")
+            .caps = Some(caps.to_string());
+        self
+    }
+
+    /// Give the directory added last an owning user and group
+    pub fn owned_by(mut self, user: &str, group: &str) -> Self {
+        let dir = self
+            .files
+            .last_mut()
+            .filter(|file| file.is_dir)
+            .expect("owned_by() follows the directory it applies to");
+        dir.owner = Some((user.to_string(), group.to_string()));
+        self
+    }
+
+    /// "version-release", the full version Arch and Debian report
+    pub fn full_version(&self) -> String {
+        format!("{}-{}", self.version, self.release)
+    }
+
+    /// Write an Arch package (.pkg.tar.zst with .PKGINFO) into `dir`
+    pub fn build_arch(&self, dir: &Path) -> PathBuf {
+        let path = dir.join(format!(
+            "{}-{}-x86_64.pkg.tar.zst",
+            self.name,
+            self.full_version()
+        ));
+
+        let mut pkginfo = format!(
+            "pkgname = {}\npkgver = {}\narch = x86_64\nsize = {}\n",
+            self.name,
+            self.full_version(),
+            self.installed_size()
+        );
+        let keys = [
+            ("depend", &self.depends),
+            ("provides", &self.provides),
+            ("conflict", &self.conflicts),
+        ];
+        for (key, relations) in keys {
+            for relation in relations {
+                let (name, op, version) = split_relation(relation);
+                pkginfo.push_str(&format!("{} = {}{}{}\n", key, name, op, version));
+            }
+        }
+
+        let encoder = zstd::Encoder::new(File::create(&path).unwrap(), 3).unwrap();
+        let mut tar = tar::Builder::new(encoder);
+        append_tar(&mut tar, ".PKGINFO", pkginfo.as_bytes(), 0o644);
+        for file in &self.files {
+            let name = if file.verbatim {
+                file.path.as_str()
+            } else {
+                file.path.trim_start_matches('/')
+            };
+            append_file(&mut tar, name, file);
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    /// Write a Debian package (ar with control.tar.gz and data.tar.gz) into `dir`
+    pub fn build_deb(&self, dir: &Path) -> PathBuf {
+        let path = dir.join(format!("{}_{}_amd64.deb", self.name, self.full_version()));
+
+        let mut control = format!(
+            "Package: {}\nVersion: {}\nArchitecture: amd64\nMaintainer: Conary Tests <tests@example.com>\nInstalled-Size: {}\n",
+            self.name,
+            self.full_version(),
+            self.installed_size().div_ceil(1024)
+        );
+        let fields = [
+            ("Depends", &self.depends),
+            ("Provides", &self.provides),
+            ("Conflicts", &self.conflicts),
+        ];
+        for (field, relations) in fields {
+            if relations.is_empty() {
+                continue;
+            }
+            let entries: Vec<String> = relations
+                .iter()
+                .map(|relation| match split_relation(relation) {
+                    (name, "", _) => name.to_string(),
+                    (name, op, version) => format!("{} ({} {})", name, deb_operator(op), version),
+                })
+                .collect();
+            control.push_str(&format!("{}: {}\n", field, entries.join(", ")));
+        }
+        control.push_str(&format!("Description: {} test fixture\n", self.name));
+
+        let control_tar = gzip_tar(|tar| append_tar(tar, "./control", control.as_bytes(), 0o644));
+        let data_tar = gzip_tar(|tar| {
+            for file in &self.files {
+                append_file(tar, &format!(".{}", file.path), file);
+            }
+        });
+
+        let mut ar = ar::Builder::new(File::create(&path).unwrap());
+        let members: [(&str, &[u8]); 3] = [
+            ("debian-binary", b"2.0\n"),
+            ("control.tar.gz", &control_tar),
+            ("data.tar.gz", &data_tar),
+        ];
+        for (name, content) in members {
+            let header = ar::Header::new(name.as_bytes().to_vec(), content.len() as u64);
+            ar.append(&header, content).unwrap();
+        }
+        path
+    }
+
+    /// Write an RPM package (lead, signature, header and cpio payload) into `dir`
+    pub fn build_rpm(&self, dir: &Path) -> PathBuf {
+        let path = dir.join(format!("{}-{}.x86_64.rpm", self.name, self.full_version()));
+
+        let summary = format!("{} test fixture", self.name);
+        let mut builder =
+            rpm::PackageBuilder::new(&self.name, &self.version, "MIT", "x86_64", &summary)
+                .release(&self.release)
+                .epoch(self.epoch)
+                .compression(rpm::CompressionType::Gzip);
+        for relation in &self.depends {
+            builder = builder.requires(rpm_dependency(relation));
+        }
+        for relation in &self.provides {
+            builder = builder.provides(rpm_dependency(relation));
+        }
+        for relation in &self.conflicts {
+            builder = builder.conflicts(rpm_dependency(relation));
+        }
+
+        // The builder reads payload files from disk
+        let staging = tempfile::tempdir().unwrap();
+        for (i, file) in self.files.iter().enumerate() {
+            let source = staging.path().join(i.to_string());
+            File::create(&source)
+                .unwrap()
+                .write_all(&file.content)
+                .unwrap();
+            let options = match &file.link_target {
+                None if file.is_dir => rpm::FileOptions::new(file.path.as_str())
+                    .mode(rpm::FileMode::dir(file.mode as u16)),
+                Some(target) => rpm::FileOptions::new(file.path.as_str())
+                    .symlink(target.as_str())
+                    .mode(rpm::FileMode::symbolic_link(file.mode as u16)),
+                None => rpm::FileOptions::new(file.path.as_str())
+                    .mode(rpm::FileMode::regular(file.mode as u16)),
+            };
+            let options = match &file.caps {
+                Some(caps) => options.caps(caps.as_str()).unwrap(),
+                None => options,
+            };
+            let options = match &file.owner {
+                Some((user, group)) => options.user(user.as_str()).group(group.as_str()),
+                None => options,
+            };
+            builder = builder.with_file(&source, options).unwrap();
+        }
+
+        builder.build().unwrap().write_file(&path).unwrap();
+        path
+    }
+
+    fn installed_size(&self) -> usize {
+        self.files.iter().map(|file| file.content.len()).sum()
+    }
+}
+
+/// Split "name >= 1.0" or "name>=1.0" into name, operator and version
+fn split_relation(relation: &str) -> (&str, &str, &str) {
+    let Some(pos) = relation.find(['<', '>', '=']) else {
+        return (relation.trim(), "", "");
+    };
+    let rest = &relation[pos..];
+    let op_len = rest
+        .find(|c: char| !matches!(c, '<' | '>' | '='))
+        .unwrap_or(rest.len());
+    (
+        relation[..pos].trim(),
+        &rest[..op_len],
+        rest[op_len..].trim(),
+    )
+}
+
+/// Debian spells strict comparisons "<<" and ">>"
+fn deb_operator(op: &str) -> &str {
+    match op {
+        "<" => "<<",
+        ">" => ">>",
+        op => op,
+    }
+}
+
+fn rpm_dependency(relation: &str) -> rpm::Dependency {
+    match split_relation(relation) {
+        (name, "", _) => rpm::Dependency::any(name),
+        (name, ">=", version) => rpm::Dependency::greater_eq(name, version),
+        (name, "<=", version) => rpm::Dependency::less_eq(name, version),
+        (name, ">", version) => rpm::Dependency::greater(name, version),
+        (name, "<", version) => rpm::Dependency::less(name, version),
+        (name, _, version) => rpm::Dependency::eq(name, version),
+    }
+}
+
+fn append_tar<W: Write>(tar: &mut tar::Builder<W>, path: &str, content: &[u8], mode: u32) {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(mode);
+    // Set by hand, since `set_path` refuses absolute paths and `..`
+    header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+    header.set_cksum();
+    tar.append(&header, content).unwrap();
+}
+
+/// Add a fixture file to a payload tarball as a regular file, symlink or directory
+fn append_file<W: Write>(tar: &mut tar::Builder<W>, path: &str, file: &FixtureFile) {
+    if !file.xattrs.is_empty() {
+        let keys: Vec<String> = file
+            .xattrs
+            .iter()
+            .map(|(name, _)| format!("SCHILY.xattr.{}", name))
+            .collect();
+        let records = keys
+            .iter()
+            .map(String::as_str)
+            .zip(file.xattrs.iter().map(|(_, value)| value.as_slice()));
+        tar.append_pax_extensions(records).unwrap();
+    }
+    if file.is_dir {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(file.mode);
+        if let Some((user, group)) = &file.owner {
+            header.set_username(user).unwrap();
+            header.set_groupname(group).unwrap();
+        }
+        return tar
+            .append_data(&mut header, format!("{}/", path), std::io::empty())
+            .unwrap();
+    }
+    let Some(target) = &file.link_target else {
+        return append_tar(tar, path, &file.content, file.mode);
+    };
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    header.set_mode(file.mode);
+    tar.append_link(&mut header, path, target).unwrap();
+}
+
+/// Build a gzip-compressed tarball in memory
+fn gzip_tar(fill: impl FnOnce(&mut tar::Builder<flate2::write::GzEncoder<Vec<u8>>>)) -> Vec<u8> {
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut tar = tar::Builder::new(encoder);
+    fill(&mut tar);
+    tar.into_inner().unwrap().finish().unwrap()
+}
@@ -4,7 +4,10 @@
 //!
 //! These tests verify end-to-end functionality across modules.
 
+mod fixtures;
+
 use conary::db;
+use fixtures::PackageFixture;
 use tempfile::NamedTempFile;
 
 #[test]
@@ -276,22 +279,21 @@ fn test_trove_with_flavors_and_provenance() {
 }
 
 #[test]
-#[ignore] // Ignored by default since it requires a real RPM file
 fn test_rpm_install_workflow() {
     use conary::db;
     use conary::db::models::{Changeset, ChangesetStatus, FileEntry, Trove};
-    use conary::packages::rpm::RpmPackage;
     use conary::packages::PackageFormat;
+    use conary::packages::rpm::RpmPackage;
 
-    // This test requires a real RPM file to be present
-    // To run: place an RPM file at /tmp/test.rpm and run:
-    // cargo test test_rpm_install_workflow -- --ignored
-
-    let rpm_path = "/tmp/test.rpm";
-    if !std::path::Path::new(rpm_path).exists() {
-        eprintln!("Skipping RPM install test: no RPM file at {}", rpm_path);
-        return;
-    }
+    let temp_dir = tempfile::tempdir().unwrap();
+    let rpm_path = PackageFixture::new("hello", "2.12")
+        .release("3")
+        .epoch(1)
+        .depends("glibc >= 2.34")
+        .provides("hello-bin")
+        .file_with_mode("/usr/bin/hello", b"#!/bin/sh\necho hello\n", 0o755)
+        .file("/usr/share/doc/hello/README", b"Say hello\n")
+        .build_rpm(temp_dir.path());
 
     let temp_file = NamedTempFile::new().unwrap();
     let db_path = temp_file.path().to_str().unwrap().to_string();
@@ -302,14 +304,13 @@ fn test_rpm_install_workflow() {
     let mut conn = db::open(&db_path).unwrap();
 
     // Parse the RPM
-    let rpm = RpmPackage::parse(rpm_path).expect("Failed to parse RPM");
+    let rpm = RpmPackage::parse(rpm_path.to_str().unwrap()).expect("Failed to parse RPM");
 
     // Verify basic metadata was extracted
-    assert!(!rpm.name().is_empty(), "Package name should not be empty");
-    assert!(
-        !rpm.version().is_empty(),
-        "Package version should not be empty"
-    );
+    assert_eq!(rpm.name(), "hello");
+    assert_eq!(rpm.version(), "1:2.12-3");
+    assert_eq!(rpm.epoch(), 1);
+    assert!(rpm.dependencies().iter().any(|dep| dep.name == "glibc"));
 
     // Perform installation within changeset (simulating the install command)
     db::transaction(&mut conn, |tx| {
@@ -341,6 +342,8 @@ fn test_rpm_install_workflow() {
     let troves = Trove::find_by_name(&conn, rpm.name()).unwrap();
     assert_eq!(troves.len(), 1);
     assert_eq!(troves[0].version, rpm.version());
+    assert_eq!(troves[0].epoch, 1);
+    assert_eq!(troves[0].format, Some(conary::PackageFormatType::Rpm));
 
     // Verify changeset was created
     let changesets = Changeset::list_all(&conn).unwrap();
@@ -349,13 +352,8 @@ fn test_rpm_install_workflow() {
 
     // Verify files were stored
     let files = FileEntry::find_by_trove(&conn, troves[0].id.unwrap()).unwrap();
-    assert_eq!(files.len(), rpm.files().len());
-
-    println!("Successfully installed RPM package:");
-    println!("  Name: {}", rpm.name());
-    println!("  Version: {}", rpm.version());
-    println!("  Files: {}", rpm.files().len());
-    println!("  Dependencies: {}", rpm.dependencies().len());
+    assert_eq!(files.len(), 2);
+    assert!(files.iter().any(|file| file.path == "/usr/bin/hello"));
 }
 
 #[test]
@@ -426,7 +424,11 @@ fn test_install_and_rollback() {
         let mut changeset = Changeset::new("Install nginx-1.21.0".to_string());
         let changeset_id = changeset.insert(tx)?;
 
-        let mut trove = Trove::new("nginx".to_string(), "1.21.0".to_string(), TroveType::Package);
+        let mut trove = Trove::new(
+            "nginx".to_string(),
+            "1.21.0".to_string(),
+            TroveType::Package,
+        );
         trove.installed_by_changeset_id = Some(changeset_id);
         trove.insert(tx)?;
 
@@ -482,7 +484,11 @@ fn test_query_packages() {
     let mut conn = db::open(&db_path).unwrap();
 
     // Install multiple packages
-    for (name, version) in [("nginx", "1.21.0"), ("redis", "6.2.0"), ("postgres", "14.0")] {
+    for (name, version) in [
+        ("nginx", "1.21.0"),
+        ("redis", "6.2.0"),
+        ("postgres", "14.0"),
+    ] {
         db::transaction(&mut conn, |tx| {
             let mut changeset = Changeset::new(format!("Install {}-{}", name, version));
             let changeset_id = changeset.insert(tx)?;
@@ -500,7 +506,7 @@ fn test_query_packages() {
     // Query all packages
     let all_troves: Vec<Trove> = {
         let mut stmt = conn
-            .prepare("SELECT id, name, version, type, architecture, description, installed_at, installed_by_changeset_id FROM troves ORDER BY name")
+            .prepare("SELECT id, name, version, type, architecture, description, installed_at, installed_by_changeset_id, install_reason, origin_repository_id, origin_url FROM troves ORDER BY name")
             .unwrap();
         stmt.query_map([], |row| {
             Ok(Trove {
@@ -512,6 +518,13 @@ fn test_query_packages() {
                 description: row.get(5)?,
                 installed_at: row.get(6)?,
                 installed_by_changeset_id: row.get(7)?,
+                install_reason: row.get::<_, String>(8)?.parse().unwrap(),
+                origin_repository_id: row.get(9)?,
+                origin_url: row.get(10)?,
+                install_modifiers: Default::default(),
+                installed_size: 0,
+                epoch: 0,
+                format: None,
             })
         })
         .unwrap()
@@ -560,3 +573,4220 @@ fn test_history_shows_operations() {
         assert_eq!(changeset.status, ChangesetStatus::Applied);
     }
 }
+
+#[test]
+fn test_history_orders_changesets_from_the_same_second() {
+    use conary::db::models::{Changeset, ChangesetStatus};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    db::init(db_arg).unwrap();
+    let conn = db::open(db_arg).unwrap();
+
+    // A dependency chain records several changesets within one second
+    for n in 0..5 {
+        let mut changeset = Changeset::new(format!("Install dep-{}", n));
+        changeset.insert(&conn).unwrap();
+        changeset
+            .update_status(&conn, ChangesetStatus::Applied)
+            .unwrap();
+    }
+    conn.execute(
+        "UPDATE changesets SET created_at = '2026-10-01 10:00:00'",
+        [],
+    )
+    .unwrap();
+
+    let expected = [
+        "Install dep-4",
+        "Install dep-3",
+        "Install dep-2",
+        "Install dep-1",
+        "Install dep-0",
+    ];
+    let listed: Vec<String> = Changeset::list_all(&conn)
+        .unwrap()
+        .into_iter()
+        .map(|c| c.description)
+        .collect();
+    assert_eq!(listed, expected);
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    let described: Vec<&str> = history
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|changeset| changeset["description"].as_str().unwrap())
+        .collect();
+    assert_eq!(described, expected);
+}
+
+#[test]
+fn test_reconcile_repairs_broken_root() {
+    use conary::db;
+    use conary::db::models::{Changeset, FileEntry, Trove, TroveType};
+    use conary::filesystem::FileDeployer;
+    use conary::reconcile::{self, FileProblem, ReconcileOptions, Resolution};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let objects_dir = temp_dir.path().join("objects");
+    let root = temp_dir.path().join("root");
+
+    db::init(db_path.to_str().unwrap()).unwrap();
+    let mut conn = db::open(db_path.to_str().unwrap()).unwrap();
+    let deployer = FileDeployer::new(&objects_dir, &root).unwrap();
+
+    // Install one package with four files, deployed from the CAS
+    let files: [(&str, &[u8]); 4] = [
+        ("/usr/bin/tool", b"tool binary"),
+        ("/usr/lib/libtool.so", b"tool library"),
+        ("/etc/tool.conf", b"setting = 1\n"),
+        ("/usr/share/tool/data", b"static data"),
+    ];
+    let mut trove = Trove::new("tool".to_string(), "1.0".to_string(), TroveType::Package);
+    let trove_id = trove.insert(&conn).unwrap();
+    for (path, content) in files {
+        let hash = deployer.cas().store(content).unwrap();
+        deployer.deploy_file(path, &hash, 0o644).unwrap();
+        let mut entry = FileEntry::new(
+            path.to_string(),
+            hash,
+            content.len() as i64,
+            0o644,
+            trove_id,
+        );
+        entry.insert(&conn).unwrap();
+    }
+
+    // Break the root three ways: delete a file, modify a file, and delete a
+    // file whose CAS object is also gone
+    std::fs::remove_file(root.join("usr/bin/tool")).unwrap();
+    std::fs::write(root.join("etc/tool.conf"), b"tampered\n").unwrap();
+    std::fs::remove_file(root.join("usr/share/tool/data")).unwrap();
+    let lost_hash = conary::filesystem::CasStore::compute_hash(b"static data");
+    std::fs::remove_file(objects_dir.join(&lost_hash[..2]).join(&lost_hash[2..])).unwrap();
+
+    // Dry run changes nothing
+    let options = ReconcileOptions {
+        download_missing: false,
+        dry_run: true,
+        include_excluded: false,
+    };
+    let report = reconcile::reconcile(&mut conn, &objects_dir, &root, &options).unwrap();
+    assert_eq!(report.files_repaired(), 2);
+    assert_eq!(report.files_irrecoverable(), 1);
+    assert!(!root.join("usr/bin/tool").exists());
+    assert!(
+        Changeset::find_by_batch(&conn, &report.batch_id)
+            .unwrap()
+            .is_empty()
+    );
+
+    // Real run repairs what the CAS still has
+    let options = ReconcileOptions::default();
+    let report = reconcile::reconcile(&mut conn, &objects_dir, &root, &options).unwrap();
+    assert_eq!(report.files_checked(), 4);
+    assert_eq!(report.files_repaired(), 2);
+    assert_eq!(report.files_irrecoverable(), 1);
+
+    let pkg = &report.packages[0];
+    let repair_for = |path: &str| pkg.repairs.iter().find(|r| r.path == path).unwrap();
+    assert_eq!(repair_for("/usr/bin/tool").problem, FileProblem::Missing);
+    assert_eq!(
+        repair_for("/usr/bin/tool").resolution,
+        Resolution::Redeployed
+    );
+    assert_eq!(repair_for("/etc/tool.conf").problem, FileProblem::Modified);
+    assert!(matches!(
+        repair_for("/usr/share/tool/data").resolution,
+        Resolution::Irrecoverable(_)
+    ));
+
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/tool")).unwrap(),
+        b"tool binary"
+    );
+    assert_eq!(
+        std::fs::read(root.join("etc/tool.conf")).unwrap(),
+        b"setting = 1\n"
+    );
+
+    // The repairs are recorded in a changeset tagged with the batch id
+    let batch = Changeset::find_by_batch(&conn, &report.batch_id).unwrap();
+    assert_eq!(batch.len(), 1);
+    assert_eq!(pkg.changeset_id, batch[0].id);
+
+    // A second run only reports the irrecoverable file
+    let report = reconcile::reconcile(&mut conn, &objects_dir, &root, &options).unwrap();
+    assert_eq!(report.files_repaired(), 0);
+    assert_eq!(report.files_irrecoverable(), 1);
+}
+
+/// Run the conary binary and parse its stdout as JSON
+fn conary_json(args: &[&str]) -> (bool, serde_json::Value) {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(args)
+        .env("RUST_LOG", "info")
+        .output()
+        .unwrap();
+    let value = serde_json::from_slice(&output.stdout).unwrap_or_else(|e| {
+        panic!(
+            "conary {:?} did not print JSON ({}): {}",
+            args,
+            e,
+            String::from_utf8_lossy(&output.stdout)
+        )
+    });
+    (output.status.success(), value)
+}
+
+#[test]
+fn test_json_output_round_trips() {
+    use conary::db;
+    use conary::db::models::{Changeset, ChangesetStatus, FileEntry, Trove, TroveType};
+    use conary::filesystem::FileDeployer;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+
+    db::init(db_arg).unwrap();
+    let mut conn = db::open(db_arg).unwrap();
+    let deployer = FileDeployer::new(&temp_dir.path().join("objects"), &root).unwrap();
+
+    db::transaction(&mut conn, |tx| {
+        let mut changeset = Changeset::new("Install tool-1.0".to_string());
+        let changeset_id = changeset.insert(tx)?;
+        let mut trove = Trove::new("tool".to_string(), "1.0".to_string(), TroveType::Package);
+        trove.installed_by_changeset_id = Some(changeset_id);
+        let trove_id = trove.insert(tx)?;
+        for (path, content) in [("/usr/bin/tool", b"tool"), ("/usr/bin/aux1", b"aux1")] {
+            let hash = deployer.cas().store(content)?;
+            deployer.deploy_file(path, &hash, 0o755)?;
+            FileEntry::new(
+                path.to_string(),
+                hash,
+                content.len() as i64,
+                0o755,
+                trove_id,
+            )
+            .insert(tx)?;
+        }
+        changeset.update_status(tx, ChangesetStatus::Applied)?;
+        Ok(())
+    })
+    .unwrap();
+
+    let (ok, troves) = conary_json(&["query", "--json", "-d", db_arg]);
+    assert!(ok);
+    assert_eq!(troves[0]["name"], "tool");
+    assert_eq!(troves[0]["trove_type"], "package");
+    assert_eq!(troves[0]["install_reason"], "explicit");
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    assert_eq!(history[0]["description"], "Install tool-1.0");
+    assert_eq!(history[0]["status"], "applied");
+
+    let (ok, report) = conary_json(&["verify", "--json", "-d", db_arg, "-r", root_arg]);
+    assert!(ok);
+    assert_eq!(report["summary"]["ok"], 2);
+    assert_eq!(report["files"].as_array().unwrap().len(), 2);
+
+    // A modified file fails verification but the report is still JSON
+    std::fs::write(root.join("usr/bin/tool"), b"tampered").unwrap();
+    let (ok, report) = conary_json(&["verify", "--json", "-d", db_arg, "-r", root_arg]);
+    assert!(!ok);
+    assert_eq!(report["summary"]["modified"], 1);
+    let modified = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|f| f["path"] == "/usr/bin/tool")
+        .unwrap();
+    assert_eq!(modified["status"], "modified");
+    assert_eq!(modified["package"], "tool");
+}
+
+#[test]
+fn test_query_changesets_after_remove_and_rollback() {
+    use conary::db;
+    use conary::db::models::{Changeset, ChangesetStatus, Trove, TroveType};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+
+    db::init(db_arg).unwrap();
+    let mut conn = db::open(db_arg).unwrap();
+    let mut install = |name: &str| {
+        db::transaction(&mut conn, |tx| {
+            let mut changeset = Changeset::new(format!("Install {}-1.0", name));
+            let changeset_id = changeset.insert(tx)?;
+            let mut trove = Trove::new(name.to_string(), "1.0".to_string(), TroveType::Package);
+            trove.installed_by_changeset_id = Some(changeset_id);
+            trove.insert(tx)?;
+            changeset.update_status(tx, ChangesetStatus::Applied)?;
+            Ok(changeset_id)
+        })
+        .unwrap()
+    };
+    let tool_install = install("tool");
+    let lib_install = install("lib");
+    drop(conn);
+
+    let conary = |args: &[&str]| {
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "conary {:?} failed", args);
+    };
+    conary(&[
+        "remove",
+        "tool",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    conary(&[
+        "rollback",
+        &lib_install.to_string(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ]);
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    let history = history.as_array().unwrap();
+    let remove_id = history
+        .iter()
+        .find(|c| c["description"].as_str().unwrap().starts_with("Remove"))
+        .unwrap()["id"]
+        .to_string();
+
+    let (ok, report) = conary_json(&["query", "--changeset", &remove_id, "--json", "-d", db_arg]);
+    assert!(ok);
+    assert_eq!(report["troves"][0]["name"], "tool");
+    assert_eq!(report["troves"][0]["action"], "removed");
+
+    let (ok, report) = conary_json(&[
+        "query",
+        "--changeset",
+        &tool_install.to_string(),
+        "--json",
+        "-d",
+        db_arg,
+    ]);
+    assert!(ok);
+    assert_eq!(report["troves"][0]["action"], "installed");
+
+    let (ok, events) = conary_json(&["query", "lib", "--show-changesets", "--json", "-d", db_arg]);
+    assert!(ok);
+    let actions: Vec<_> = events
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].clone())
+        .collect();
+    assert_eq!(actions, ["installed", "rolled_back"]);
+    assert_eq!(events[0]["changeset"]["status"], "rolled_back");
+}
+
+/// Run the conary binary, returning whether it succeeded and its stderr
+fn conary_run(args: &[&str]) -> (bool, String) {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+/// Run the conary binary, returning its exit code and stderr
+fn conary_status(args: &[&str]) -> (Option<i32>, String) {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.code(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+/// Whether rpm2cpio, needed to install RPM payloads, is on the PATH
+fn have_rpm2cpio() -> bool {
+    std::process::Command::new("rpm2cpio")
+        .stdin(std::process::Stdio::null())
+        .output()
+        .is_ok()
+}
+
+#[test]
+fn test_fixture_install_upgrade_remove() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let builds: [(&str, fixtures::BuildFn); 3] = [
+        ("arch", PackageFixture::build_arch),
+        ("deb", PackageFixture::build_deb),
+        ("rpm", PackageFixture::build_rpm),
+    ];
+    for (format, build) in builds {
+        // RPM payloads are unpacked with rpm2cpio
+        if format == "rpm" && !have_rpm2cpio() {
+            eprintln!("Skipping RPM install: rpm2cpio is not installed");
+            continue;
+        }
+
+        let name = format!("{}-tool", format);
+        let binary = format!("/usr/bin/{}", name);
+        let v1 = build(
+            &PackageFixture::new(&name, "1.0").file_with_mode(&binary, b"v1", 0o755),
+            temp_dir.path(),
+        );
+        let v2 = build(
+            &PackageFixture::new(&name, "1.1").file_with_mode(&binary, b"v2", 0o755),
+            temp_dir.path(),
+        );
+
+        let (ok, stderr) = conary_run(&[
+            "install",
+            v1.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ]);
+        assert!(ok, "{} install failed: {}", format, stderr);
+        let deployed = root.join(binary.trim_start_matches('/'));
+        assert_eq!(std::fs::read(&deployed).unwrap(), b"v1");
+
+        let (ok, stderr) = conary_run(&[
+            "install",
+            v2.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ]);
+        assert!(ok, "{} upgrade failed: {}", format, stderr);
+        assert_eq!(std::fs::read(&deployed).unwrap(), b"v2");
+
+        let (ok, troves) = conary_json(&["query", &name, "--json", "-d", db_arg]);
+        assert!(ok);
+        let troves = troves.as_array().unwrap();
+        assert_eq!(troves.len(), 1, "{}", format);
+        assert!(troves[0]["version"].as_str().unwrap().starts_with("1.1"));
+
+        // The same version again is refused
+        let (ok, _) = conary_run(&[
+            "install",
+            v2.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ]);
+        assert!(!ok);
+
+        let (ok, stderr) =
+            conary_run(&["remove", &name, "-d", db_arg, "-r", root_arg, "--noscripts"]);
+        assert!(ok, "{} remove failed: {}", format, stderr);
+        let (ok, troves) = conary_json(&["query", &name, "--json", "-d", db_arg]);
+        assert!(ok);
+        assert!(troves.as_array().unwrap().is_empty(), "{}", format);
+    }
+}
+
+#[test]
+fn test_fixture_conflicts_are_refused() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let first = PackageFixture::new("first", "1.0")
+        .file("/etc/shared.conf", b"first\n")
+        .build_arch(temp_dir.path());
+    let second = PackageFixture::new("second", "1.0")
+        .file("/etc/shared.conf", b"second\n")
+        .build_deb(temp_dir.path());
+    let declared = PackageFixture::new("rival", "1.0")
+        .conflicts("first")
+        .file("/usr/bin/rival", b"rival")
+        .build_arch(temp_dir.path());
+
+    let (ok, stderr) = conary_run(&[
+        "install",
+        first.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    // A file owned by another package; conflicts exit 4
+    let (code, stderr) = conary_status(&[
+        "install",
+        second.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert_eq!(code, Some(4), "{}", stderr);
+    assert!(
+        stderr.contains("/etc/shared.conf is owned by package first"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        std::fs::read(root.join("etc/shared.conf")).unwrap(),
+        b"first\n"
+    );
+
+    // A declared conflict with an installed package
+    let (code, stderr) = conary_status(&[
+        "install",
+        declared.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert_eq!(code, Some(4), "{}", stderr);
+    assert!(
+        stderr.contains("conflicts with installed package first"),
+        "{}",
+        stderr
+    );
+    assert!(!root.join("usr/bin/rival").exists());
+
+    // A package file that is not one exits 7, a missing package 3
+    let garbage = temp_dir.path().join("garbage.pkg.tar.zst");
+    std::fs::write(&garbage, [0x28, 0xB5, 0x2F, 0xFD, 0, 0, 0, 0]).unwrap();
+    let (code, stderr) = conary_status(&[
+        "install",
+        garbage.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert_eq!(code, Some(7), "{}", stderr);
+    let (code, stderr) = conary_status(&[
+        "remove",
+        "absent",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert_eq!(code, Some(3), "{}", stderr);
+}
+
+#[test]
+fn test_upgrade_breaking_an_installed_requirement_is_refused() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let old_dir = temp_dir.path().join("old");
+    std::fs::create_dir_all(&old_dir).unwrap();
+    let libfoo = PackageFixture::new("libfoo", "1.5")
+        .file("/usr/lib/libfoo.so.1", b"foo 1")
+        .build_arch(&old_dir);
+    let tool = PackageFixture::new("tool", "1.0")
+        .depends("libfoo < 2")
+        .file("/usr/bin/tool", b"tool")
+        .build_arch(temp_dir.path());
+    for package in [&libfoo, &tool] {
+        let (ok, stderr) = conary_run(&[
+            "install",
+            package.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ]);
+        assert!(ok, "{}", stderr);
+    }
+
+    let libfoo2 = PackageFixture::new("libfoo", "2.1")
+        .file("/usr/lib/libfoo.so.1", b"foo 2")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        libfoo2.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(!ok);
+    assert!(
+        stderr.contains("Install would break dependencies"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("libfoo version 2.1-1 does not satisfy constraint < 2 required by tool"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/libfoo.so.1")).unwrap(),
+        b"foo 1"
+    );
+}
+
+#[test]
+fn test_fixture_batch_install_and_rollback() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    // The application's dependency is satisfied by the library in the same batch
+    let app = PackageFixture::new("app", "1.0")
+        .depends("libapp >= 1.0")
+        .file_with_mode("/usr/bin/app", b"app", 0o755)
+        .build_arch(temp_dir.path());
+    let lib = PackageFixture::new("libapp", "1.2")
+        .file("/usr/lib/libapp.so.1", b"lib")
+        .build_arch(temp_dir.path());
+
+    let (ok, stderr) = conary_run(&[
+        "install",
+        app.to_str().unwrap(),
+        lib.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+    assert!(root.join("usr/bin/app").exists() && root.join("usr/lib/libapp.so.1").exists());
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    let history = history.as_array().unwrap();
+    assert_eq!(history.len(), 1);
+    let description = history[0]["description"].as_str().unwrap();
+    assert!(
+        description.contains("app-1.0-1") && description.contains("libapp-1.2-1"),
+        "{}",
+        description
+    );
+
+    let changeset_id = history[0]["id"].to_string();
+    let (ok, stderr) = conary_run(&["rollback", &changeset_id, "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", stderr);
+    assert!(!root.join("usr/bin/app").exists() && !root.join("usr/lib/libapp.so.1").exists());
+    let (ok, troves) = conary_json(&["query", "--json", "-d", db_arg]);
+    assert!(ok);
+    assert!(troves.as_array().unwrap().is_empty());
+}
+
+#[test]
+fn test_rollback_dry_run_flags_local_changes() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let lib = PackageFixture::new("libapp", "1.2")
+        .file("/usr/lib/libapp.so.1", b"lib")
+        .file("/usr/share/libapp/data", b"data")
+        .build_arch(temp_dir.path());
+    let app = PackageFixture::new("app", "1.0")
+        .depends("libapp >= 1.0")
+        .file_with_mode("/usr/bin/app", b"app", 0o755)
+        .build_arch(temp_dir.path());
+    for package in [&lib, &app] {
+        let (ok, stderr) = conary_run(&[
+            "install",
+            package.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ]);
+        assert!(ok, "{}", stderr);
+    }
+    std::fs::write(root.join("usr/lib/libapp.so.1"), b"patched lib").unwrap();
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    let lib_install = history
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|c| c["description"].as_str().unwrap().contains("libapp"))
+        .unwrap()["id"]
+        .to_string();
+
+    let (ok, plan) = conary_json(&[
+        "rollback",
+        &lib_install,
+        "--dry-run",
+        "--json",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ]);
+    assert!(ok);
+    assert_eq!(plan["troves"][0]["name"], "libapp");
+    let files = plan["files"].as_array().unwrap();
+    assert_eq!(files.len(), 2);
+    for file in files {
+        assert_eq!(file["action"], "remove");
+        assert_eq!(
+            file["modified"],
+            file["path"] == "/usr/lib/libapp.so.1",
+            "{}",
+            file
+        );
+    }
+    assert_eq!(plan["stranded"][0]["package"], "app");
+    assert_eq!(plan["stranded"][0]["needs"], "libapp");
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args([
+            "rollback",
+            &lib_install,
+            "--dry-run",
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("/usr/lib/libapp.so.1  WILL CLOBBER local changes"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("app needs libapp"), "{}", stdout);
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/libapp.so.1")).unwrap(),
+        b"patched lib"
+    );
+
+    let (ok, stderr) = conary_run(&["rollback", &lib_install, "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", stderr);
+    assert!(!root.join("usr/lib/libapp.so.1").exists() && root.join("usr/bin/app").exists());
+}
+
+#[test]
+fn test_verify_repair_restores_from_cas() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary", 0o755)
+        .file("/usr/share/tool/data", b"tool data")
+        .file("/usr/share/tool/extra", b"tool extra")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    // Corrupt one file, delete another and loosen the mode of the third
+    std::fs::write(root.join("usr/bin/tool"), b"tampered").unwrap();
+    std::fs::remove_file(root.join("usr/share/tool/data")).unwrap();
+    std::fs::set_permissions(
+        root.join("usr/share/tool/extra"),
+        std::fs::Permissions::from_mode(0o666),
+    )
+    .unwrap();
+
+    let verify = ["verify", "--attrs", "-d", db_arg, "-r", root_arg];
+    let (ok, _) = conary_run(&verify);
+    assert!(!ok);
+
+    let (ok, report) = conary_json(&[&verify[..], &["--repair", "--json"]].concat());
+    assert!(ok);
+    assert_eq!(report["summary"]["repaired"], 3);
+    assert_eq!(report["summary"]["unrepairable"], 0);
+
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/tool")).unwrap(),
+        b"tool binary"
+    );
+    assert_eq!(
+        std::fs::read(root.join("usr/share/tool/data")).unwrap(),
+        b"tool data"
+    );
+    let mode = std::fs::metadata(root.join("usr/share/tool/extra"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o644);
+    let mode = std::fs::metadata(root.join("usr/bin/tool"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o755);
+
+    let (ok, stderr) = conary_run(&verify);
+    assert!(ok, "{}", stderr);
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    let repair = history.as_array().unwrap().iter().find(|changeset| {
+        changeset["description"]
+            .as_str()
+            .unwrap()
+            .starts_with("Repair tool")
+    });
+    assert!(repair.is_some(), "{}", history);
+
+    // Content that has left the CAS cannot be restored
+    let hash = conary::filesystem::CasStore::compute_hash(b"tool data");
+    let object = temp_dir
+        .path()
+        .join("objects")
+        .join(&hash[..2])
+        .join(&hash[2..]);
+    std::fs::remove_file(object).unwrap();
+    std::fs::remove_file(root.join("usr/share/tool/data")).unwrap();
+
+    let (ok, report) = conary_json(&[&verify[..], &["--repair", "--json"]].concat());
+    assert!(!ok);
+    assert_eq!(report["summary"]["unrepairable"], 1);
+    let files = report["files"].as_array().unwrap();
+    let data = files
+        .iter()
+        .find(|file| file["path"] == "/usr/share/tool/data")
+        .unwrap();
+    assert_eq!(data["status"], "unrepairable");
+}
+
+#[test]
+fn test_protected_changeset_refuses_rollback() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("base", "1.0")
+        .file("/etc/base-release", b"base 1.0")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    let changeset_id = history[0]["id"].to_string();
+    assert_eq!(history[0]["protected"], false);
+
+    let (ok, stderr) = conary_run(&["history", "protect", &changeset_id, "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert_eq!(history[0]["protected"], true);
+
+    let (ok, stderr) = conary_run(&["rollback", &changeset_id, "-d", db_arg, "-r", root_arg]);
+    assert!(!ok);
+    assert!(stderr.contains("protected"), "{}", stderr);
+    assert!(root.join("etc/base-release").exists());
+
+    let (ok, stderr) = conary_run(&["history", "unprotect", &changeset_id, "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert_eq!(history[0]["protected"], false);
+
+    // --force overrides the protection
+    let (ok, stderr) = conary_run(&["history", "protect", &changeset_id, "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let (ok, stderr) = conary_run(&[
+        "rollback",
+        &changeset_id,
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--force",
+    ]);
+    assert!(ok, "{}", stderr);
+    assert!(!root.join("etc/base-release").exists());
+
+    let (ok, _) = conary_run(&["history", "protect", "999", "-d", db_arg]);
+    assert!(!ok);
+}
+
+#[test]
+fn test_idempotent_install_is_a_no_op() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let v1 = PackageFixture::new("motd", "1.0")
+        .file("/etc/motd", b"hello 1.0")
+        .build_arch(temp_dir.path());
+    let v1_arg = v1.to_str().unwrap();
+    let install = |package: &str, extra: &[&str]| {
+        let mut args = vec![
+            "install",
+            package,
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ];
+        args.extend_from_slice(extra);
+        conary_run(&args)
+    };
+
+    for _ in 0..2 {
+        let (ok, stderr) = install(v1_arg, &["--idempotent"]);
+        assert!(ok, "{}", stderr);
+    }
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert_eq!(history.as_array().unwrap().len(), 1);
+
+    // Without the flag a reinstall is still an error
+    let (ok, stderr) = install(v1_arg, &[]);
+    assert!(!ok);
+    assert!(stderr.contains("already installed"), "{}", stderr);
+
+    // --verify-existing refuses to skip over a modified file
+    std::fs::write(root.join("etc/motd"), b"tampered").unwrap();
+    let (ok, stderr) = install(v1_arg, &["--idempotent", "--verify-existing"]);
+    assert!(!ok);
+    assert!(stderr.contains("verify --repair"), "{}", stderr);
+
+    // Upgrades proceed normally under the flag
+    let v2 = PackageFixture::new("motd", "2.0")
+        .file("/etc/motd", b"hello 2.0")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = install(v2.to_str().unwrap(), &["--idempotent"]);
+    assert!(ok, "{}", stderr);
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert_eq!(history.as_array().unwrap().len(), 2);
+    assert_eq!(std::fs::read(root.join("etc/motd")).unwrap(), b"hello 2.0");
+}
+
+#[test]
+fn test_install_records_phase_timings() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("timed", "1.0")
+        .file("/usr/bin/timed", &vec![b'x'; 256 * 1024])
+        .file("/usr/share/timed/README", b"timed")
+        .build_arch(temp_dir.path());
+
+    let started = std::time::Instant::now();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args([
+            "install",
+            package.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root.to_str().unwrap(),
+            "--noscripts",
+            "--timings",
+        ])
+        .output()
+        .unwrap();
+    let wall_ms = started.elapsed().as_secs_f64() * 1000.0;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Timings"));
+
+    let (ok, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(ok);
+    let timings = &history[0]["timings"];
+    let phases = timings["phases"].as_array().unwrap();
+    let names: Vec<&str> = phases
+        .iter()
+        .map(|phase| phase["phase"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["parse", "extract", "hash", "record", "deploy"]);
+    for phase in phases {
+        assert!(phase["duration_ms"].as_f64().unwrap() > 0.0, "{}", phase);
+        assert_eq!(phase["files"], 2, "{}", phase);
+    }
+    assert!(phases[2]["bytes"].as_u64().unwrap() >= 256 * 1024);
+
+    // The phases fit inside the instrument's wall time, which fits inside the command's
+    let phases_ms: f64 = phases
+        .iter()
+        .map(|phase| phase["duration_ms"].as_f64().unwrap())
+        .sum();
+    let total_ms = timings["total_ms"].as_f64().unwrap();
+    assert!(phases_ms <= total_ms, "{} > {}", phases_ms, total_ms);
+    assert!(total_ms <= wall_ms, "{} > {}", total_ms, wall_ms);
+}
+
+#[test]
+fn test_install_hashes_new_content_once() {
+    use conary::timing::Phase;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+    let large = vec![b'x'; 4 * 1024 * 1024];
+
+    // Staging hashes every byte of new content; deploying copies it out unhashed
+    let package = PackageFixture::new("large", "1.0")
+        .file("/usr/lib/large.bin", &large)
+        .file("/usr/bin/large", b"large")
+        .build_arch(temp_dir.path());
+    let report = installer.install_file(&package, &NO_SCRIPTS).unwrap();
+    let hash = report.timings.phase(Phase::Hash).unwrap();
+    assert_eq!((hash.files, hash.bytes), (2, large.len() as u64 + 5));
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/large.bin")).unwrap(),
+        large
+    );
+
+    // Content the CAS already had is hashed once more, to verify it on the way out
+    let copy = PackageFixture::new("large-copy", "1.0")
+        .file("/opt/large.bin", &large)
+        .build_arch(temp_dir.path());
+    let report = installer.install_file(&copy, &NO_SCRIPTS).unwrap();
+    let hash = report.timings.phase(Phase::Hash).unwrap();
+    assert_eq!((hash.files, hash.bytes), (2, 2 * large.len() as u64));
+    assert_eq!(std::fs::read(root.join("opt/large.bin")).unwrap(), large);
+}
+
+#[test]
+fn test_info_reports_installed_package_and_verification() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("hello", "1.0")
+        .depends("glibc")
+        .file("/usr/bin/hello", b"#!/bin/sh\necho hello\n")
+        .file("/usr/share/hello/README", b"readme")
+        .build_arch(temp_dir.path());
+    let glibc = PackageFixture::new("glibc", "2.39").build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        glibc.to_str().unwrap(),
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let info_args = [
+        "info", "hello", "--json", "--verify", "-d", db_arg, "-r", root_arg,
+    ];
+    let (ok, info) = conary_json(&info_args);
+    assert!(ok);
+    let entry = &info[0];
+    assert_eq!(entry["trove"]["version"], "1.0-1");
+    assert_eq!(entry["file_count"], 2);
+    assert_eq!(entry["total_size"], 27);
+    assert_eq!(entry["dependencies"][0]["depends_on_name"], "glibc");
+    assert!(
+        entry["changeset"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("hello")
+    );
+    assert_eq!(entry["verification_failures"], serde_json::json!([]));
+
+    std::fs::write(root.join("usr/share/hello/README"), b"changed").unwrap();
+    let (_, info) = conary_json(&info_args);
+    assert_eq!(
+        info[0]["verification_failures"],
+        serde_json::json!(["/usr/share/hello/README"])
+    );
+
+    let (ok, stderr) = conary_run(&["info", "missing", "-d", db_arg]);
+    assert!(!ok);
+    assert!(stderr.contains("not installed"), "{}", stderr);
+}
+
+/// A fresh database and install root for the library installer
+fn installer_setup(
+    temp_dir: &std::path::Path,
+) -> (rusqlite::Connection, std::path::PathBuf, std::path::PathBuf) {
+    let db_path = temp_dir.join("conary.db");
+    db::init(db_path.to_str().unwrap()).unwrap();
+    let conn = db::open(db_path.to_str().unwrap()).unwrap();
+    (conn, temp_dir.join("objects"), temp_dir.join("root"))
+}
+
+const NO_SCRIPTS: conary::InstallOptions = conary::InstallOptions {
+    scripts: None,
+    allow_downgrade: false,
+    exclude: &[],
+    force_unlock: false,
+    allow_dir_replace: false,
+};
+
+#[test]
+fn test_installer_install_upgrade_remove() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let v1 = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"v1", 0o755)
+        .file("/usr/share/tool/data", b"data")
+        .build_arch(temp_dir.path());
+    let v2 = PackageFixture::new("tool", "1.1")
+        .file_with_mode("/usr/bin/tool", b"v2!", 0o755)
+        .build_arch(temp_dir.path());
+
+    let report = installer.install_file(&v1, &NO_SCRIPTS).unwrap();
+    assert_eq!((report.files_deployed, report.bytes_written), (2, 6));
+    assert_eq!(report.packages.len(), 1);
+    assert_eq!(report.packages[0].version, "1.0-1");
+    assert!(report.packages[0].replaced.is_none());
+    assert_eq!(std::fs::read(root.join("usr/bin/tool")).unwrap(), b"v1");
+
+    // A newer file upgrades the installed trove in place
+    let report = installer.install_file(&v2, &NO_SCRIPTS).unwrap();
+    assert_eq!((report.files_deployed, report.bytes_written), (1, 3));
+    assert_eq!(
+        report.packages[0].replaced.as_ref().unwrap().version,
+        "1.0-1"
+    );
+    let changeset = conary::Changeset::find_by_id(installer.conn(), report.changeset_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(changeset.description, "Upgrade tool from 1.0-1 to 1.1-1");
+    assert!(changeset.timings.is_some());
+    assert_eq!(std::fs::read(root.join("usr/bin/tool")).unwrap(), b"v2!");
+
+    // The same version again, or an older one, is refused
+    let err = installer.install_file(&v2, &NO_SCRIPTS).unwrap_err();
+    assert!(
+        matches!(err, conary::Error::ConflictError(_))
+            && err.to_string().contains("already installed"),
+        "{}",
+        err
+    );
+    let err = installer.install_file(&v1, &NO_SCRIPTS).unwrap_err();
+    assert!(err.to_string().contains("Cannot downgrade"), "{}", err);
+
+    // An explicit upgrade replaces whatever version is installed
+    let installed = conary::Trove::find_by_name(installer.conn(), "tool")
+        .unwrap()
+        .remove(0);
+    assert_eq!(installed.format, Some(conary::PackageFormatType::Arch));
+    let report = installer.upgrade(&v1, &installed, &NO_SCRIPTS).unwrap();
+    assert_eq!(
+        report.packages[0].replaced.as_ref().unwrap().version,
+        "1.1-1"
+    );
+    assert_eq!(std::fs::read(root.join("usr/bin/tool")).unwrap(), b"v1");
+
+    let report = installer
+        .remove("tool", None, &conary::RemoveOptions { scripts: None })
+        .unwrap();
+    assert_eq!(report.removed.len(), 1);
+    assert_eq!(
+        (report.removed[0].version.as_str(), report.removed[0].files),
+        ("1.0-1", 2)
+    );
+    assert!(
+        conary::Trove::find_by_name(installer.conn(), "tool")
+            .unwrap()
+            .is_empty()
+    );
+
+    let err = installer
+        .remove("tool", None, &conary::RemoveOptions { scripts: None })
+        .unwrap_err();
+    assert!(matches!(err, conary::Error::NotFoundError(_)), "{}", err);
+}
+
+#[test]
+fn test_installer_remove_respects_dependents() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let app = PackageFixture::new("app", "1.0")
+        .depends("libapp")
+        .file("/usr/bin/app", b"app")
+        .build_arch(temp_dir.path());
+    let lib = PackageFixture::new("libapp", "1.0")
+        .file("/usr/lib/libapp.so", b"lib")
+        .build_arch(temp_dir.path());
+
+    let mut dependency = installer.prepare(&lib).unwrap();
+    dependency.set_reason(conary::InstallReason::Dependency);
+    let batch = vec![dependency, installer.prepare(&app).unwrap()];
+    installer.install_batch(batch, &NO_SCRIPTS).unwrap();
+
+    let remove = conary::RemoveOptions { scripts: None };
+    let err = installer.remove("libapp", None, &remove).unwrap_err();
+    assert!(
+        matches!(err, conary::Error::ConflictError(_)) && err.to_string().contains("app"),
+        "{}",
+        err
+    );
+    assert_eq!(
+        conary::Trove::find_by_name(installer.conn(), "libapp")
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // Nothing is unneeded while app is installed; afterwards the library goes
+    assert!(installer.autoremove(&remove).unwrap().is_none());
+    installer.remove("app", None, &remove).unwrap();
+    let report = installer.autoremove(&remove).unwrap().unwrap();
+    let removed: Vec<&str> = report
+        .removed
+        .iter()
+        .map(|removed| removed.name.as_str())
+        .collect();
+    assert_eq!(removed, vec!["libapp"]);
+    let changeset = conary::Changeset::find_by_id(installer.conn(), report.changeset_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(changeset.description, "Autoremove 1 package(s)");
+}
+
+#[test]
+fn test_installonly_versions_stay_side_by_side() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    conary::db::models::Setting::set(&conn, conary::installer::INSTALLONLY_LIMIT_SETTING, "2")
+        .unwrap();
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let kernel = |version: &str, license: &[u8]| {
+        PackageFixture::new("kernel", version)
+            .file(&format!("/boot/vmlinuz-{}", version), version.as_bytes())
+            .file("/boot/LICENSE", license)
+            .build_arch(temp_dir.path())
+    };
+    let (v1, v2, v3) = (
+        kernel("6.1", b"GPL"),
+        kernel("6.2", b"GPL"),
+        kernel("6.3", b"GPL"),
+    );
+    let remove = conary::RemoveOptions { scripts: None };
+
+    installer.install_file(&v1, &NO_SCRIPTS).unwrap();
+    let report = installer.install_file(&v2, &NO_SCRIPTS).unwrap();
+    assert!(report.packages[0].replaced.is_none());
+    assert!(report.pruned.is_none());
+    let versions = |installer: &conary::Installer| -> Vec<String> {
+        conary::Trove::find_by_name(installer.conn(), "kernel")
+            .unwrap()
+            .into_iter()
+            .map(|trove| trove.version)
+            .collect()
+    };
+    assert_eq!(versions(&installer), vec!["6.1-1", "6.2-1"]);
+    assert!(root.join("boot/vmlinuz-6.1").exists() && root.join("boot/vmlinuz-6.2").exists());
+    // The identical shared file belongs to the version installed last
+    let shared = conary::FileEntry::find_by_path(installer.conn(), "/boot/LICENSE")
+        .unwrap()
+        .unwrap();
+    let owner = conary::Trove::find_by_id(installer.conn(), shared.trove_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(owner.version, "6.2-1");
+
+    // A shared path with other content conflicts, and the same version is refused
+    let err = installer
+        .install_file(&kernel("6.4", b"MIT"), &NO_SCRIPTS)
+        .unwrap_err();
+    assert!(
+        matches!(err, conary::Error::FileConflict { .. })
+            && err.to_string().contains("kernel 6.2-1"),
+        "{}",
+        err
+    );
+    let err = installer.install_file(&v2, &NO_SCRIPTS).unwrap_err();
+    assert!(err.to_string().contains("already installed"), "{}", err);
+
+    // Beyond the limit the oldest version goes
+    let report = installer.install_file(&v3, &NO_SCRIPTS).unwrap();
+    let pruned = report.pruned.unwrap();
+    assert_eq!(pruned.removed[0].version, "6.1-1");
+    assert_eq!(versions(&installer), vec!["6.2-1", "6.3-1"]);
+
+    // Removing needs a version while several are installed
+    let err = installer.remove("kernel", None, &remove).unwrap_err();
+    assert!(err.to_string().contains("--version"), "{}", err);
+    let err = installer
+        .remove("kernel", Some("6.1-1"), &remove)
+        .unwrap_err();
+    assert!(matches!(err, conary::Error::NotFoundError(_)), "{}", err);
+    let report = installer.remove("kernel", Some("6.2-1"), &remove).unwrap();
+    assert_eq!(report.removed[0].version, "6.2-1");
+    assert_eq!(versions(&installer), vec!["6.3-1"]);
+}
+
+#[test]
+fn test_removing_installonly_version_hands_shared_files_back() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let kernel = |version: &str| {
+        PackageFixture::new("kernel", version)
+            .file(&format!("/boot/vmlinuz-{}", version), version.as_bytes())
+            .file("/boot/LICENSE", b"GPL")
+            .build_arch(temp_dir.path())
+    };
+    let remove = conary::RemoveOptions { scripts: None };
+    installer.install_file(&kernel("6.1"), &NO_SCRIPTS).unwrap();
+    installer.install_file(&kernel("6.2"), &NO_SCRIPTS).unwrap();
+    let owner = |installer: &conary::Installer| {
+        let shared = conary::FileEntry::find_by_path(installer.conn(), "/boot/LICENSE").unwrap()?;
+        conary::Trove::find_by_id(installer.conn(), shared.trove_id)
+            .unwrap()
+            .map(|trove| trove.version)
+    };
+    assert_eq!(owner(&installer).as_deref(), Some("6.2-1"));
+
+    // The version that owned the shared file goes; the one left owns it again
+    installer.remove("kernel", Some("6.2-1"), &remove).unwrap();
+    assert_eq!(owner(&installer).as_deref(), Some("6.1-1"));
+    assert_eq!(std::fs::read(root.join("boot/LICENSE")).unwrap(), b"GPL");
+    assert!(!root.join("boot/vmlinuz-6.2").exists());
+    assert!(root.join("boot/vmlinuz-6.1").exists());
+    let (ok, stderr) = conary_run(&[
+        "verify",
+        "kernel",
+        "-d",
+        temp_dir.path().join("conary.db").to_str().unwrap(),
+        "-r",
+        root.to_str().unwrap(),
+    ]);
+    assert!(ok, "{}", stderr);
+
+    // With the last version the file goes too
+    installer.remove("kernel", None, &remove).unwrap();
+    assert_eq!(owner(&installer), None);
+    assert!(!root.join("boot/LICENSE").exists());
+}
+
+#[test]
+fn test_fresh_install_rebuilds_from_a_delta_of_an_earlier_version() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let repo_dir = temp_dir.path().join("repo");
+    std::fs::create_dir_all(&repo_dir).unwrap();
+
+    let v1 = PackageFixture::new("hello", "1.0")
+        .file("/usr/bin/hello", b"hello, version one")
+        .build_arch(temp_dir.path());
+    let v2 = PackageFixture::new("hello", "1.1")
+        .file("/usr/bin/hello", b"hello, version two")
+        .build_arch(temp_dir.path());
+    let (v1_body, v2_body) = (std::fs::read(&v1).unwrap(), std::fs::read(&v2).unwrap());
+
+    // The delta is made in a CAS of its own, so the installer's never holds 1.1
+    let scratch = temp_dir.path().join("scratch");
+    let scratch_cas = conary::CasStore::new(&scratch).unwrap();
+    let v1_hash = scratch_cas.store(&v1_body).unwrap();
+    let v2_hash = scratch_cas.store(&v2_body).unwrap();
+    let delta_path = repo_dir.join("hello-1.0-to-1.1.delta");
+    let metrics = conary::delta::DeltaGenerator::new(&scratch)
+        .unwrap()
+        .generate_delta(&v1_hash, &v2_hash, &delta_path)
+        .unwrap();
+
+    // The repository serves the delta but not the full package
+    let repo_url = format!("file://{}", repo_dir.display());
+    let repo_id = conary::add_repository(&conn, "local".to_string(), repo_url.clone(), true, 50)
+        .unwrap()
+        .id
+        .unwrap();
+    let mut repo_pkg = conary::RepositoryPackage::new(
+        repo_id,
+        "hello".to_string(),
+        "1.1-1".to_string(),
+        v2_hash.clone(),
+        v2_body.len() as i64,
+        format!("{}/hello-1.1-1-x86_64.pkg.tar.zst", repo_url),
+    );
+    repo_pkg.architecture = Some("x86_64".to_string());
+    repo_pkg.insert(&conn).unwrap();
+    conary::db::models::PackageDelta::new(
+        "hello".to_string(),
+        "1.0-1".to_string(),
+        "1.1-1".to_string(),
+        v1_hash.clone(),
+        v2_hash.clone(),
+        format!("{}/hello-1.0-to-1.1.delta", repo_url),
+        metrics.delta_size as i64,
+        conary::CasStore::compute_hash(&std::fs::read(&delta_path).unwrap()),
+        v2_body.len() as i64,
+    )
+    .insert(&conn)
+    .unwrap();
+
+    // Without the 1.0 package in the CAS there is nothing to apply the delta to
+    let selection = conary::repository::SelectionOptions::default();
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+    assert!(
+        installer
+            .install_from_repo("hello", &selection, &NO_SCRIPTS)
+            .is_err()
+    );
+
+    // hello 1.0 is not installed, but its package is still in the CAS
+    conary::CasStore::new(&objects_dir)
+        .unwrap()
+        .store(&v1_body)
+        .unwrap();
+    let report = installer
+        .install_from_repo("hello", &selection, &NO_SCRIPTS)
+        .unwrap();
+    assert_eq!(report.packages[0].version, "1.1-1");
+    assert!(report.packages[0].replaced.is_none());
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/hello")).unwrap(),
+        b"hello, version two"
+    );
+    let changeset = conary::Changeset::find_by_id(installer.conn(), report.changeset_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(changeset.downloaded_bytes, Some(metrics.delta_size as i64));
+}
+
+#[test]
+fn test_queries_run_while_an_install_writes() {
+    const FILES: usize = 40;
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (conn, objects_dir, root) = installer_setup(temp_dir.path());
+    drop(conn);
+    let db_path = temp_dir
+        .path()
+        .join("conary.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let packages: Vec<_> = (0..6)
+        .map(|release| {
+            let version = format!("1.{}", release);
+            (0..FILES)
+                .fold(PackageFixture::new("bulk", &version), |fixture, i| {
+                    fixture.file(
+                        &format!("/usr/share/bulk/{}", i),
+                        format!("{} {}", version, i).as_bytes(),
+                    )
+                })
+                .build_arch(temp_dir.path())
+        })
+        .collect();
+
+    std::thread::scope(|scope| {
+        // Each upgrade replaces the installed version in one changeset
+        let writer = scope.spawn(|| {
+            let mut conn = db::open(&db_path).unwrap();
+            let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+            for package in &packages {
+                installer.install_file(package, &NO_SCRIPTS).unwrap();
+            }
+        });
+
+        // Each query sees the package with all of its files or not at all
+        let mut queries = 0;
+        while !writer.is_finished() || queries == 0 {
+            let conn = db::open_read_only(&db_path).unwrap();
+            for trove in conary::Trove::find_by_name(&conn, "bulk").unwrap() {
+                let files = conary::FileEntry::find_by_trove(&conn, trove.id.unwrap()).unwrap();
+                assert_eq!(files.len(), FILES);
+            }
+            queries += 1;
+        }
+        writer.join().unwrap();
+    });
+}
+
+#[test]
+fn test_installer_batch_is_one_changeset() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+    let prepare = |installer: &conary::Installer, paths: &[&std::path::PathBuf]| -> Vec<_> {
+        paths
+            .iter()
+            .map(|path| installer.prepare(path).unwrap())
+            .collect()
+    };
+
+    let foo = PackageFixture::new("foo", "1.0")
+        .file("/usr/bin/foo", b"foo")
+        .build_arch(temp_dir.path());
+    let bar = PackageFixture::new("bar", "2.0")
+        .file("/usr/bin/bar", b"bar")
+        .build_arch(temp_dir.path());
+    let batch = prepare(&installer, &[&foo, &bar]);
+    let report = installer.install_batch(batch, &NO_SCRIPTS).unwrap();
+
+    let changeset = conary::Changeset::find_by_id(installer.conn(), report.changeset_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        changeset.description,
+        "Install 2 packages: foo-1.0-1, bar-2.0-1"
+    );
+    assert_eq!(
+        conary::Changeset::list_all(installer.conn()).unwrap().len(),
+        1
+    );
+    assert_eq!(
+        conary::Trove::find_by_changeset(installer.conn(), report.changeset_id)
+            .unwrap()
+            .len(),
+        2
+    );
+    assert!(root.join("usr/bin/foo").exists() && root.join("usr/bin/bar").exists());
+
+    // Two packages shipping the same file fail together, before anything is deployed
+    let one = PackageFixture::new("one", "1.0")
+        .file("/usr/bin/one", b"one")
+        .file("/usr/bin/shared", b"1")
+        .build_arch(temp_dir.path());
+    let two = PackageFixture::new("two", "1.0")
+        .file("/usr/bin/shared", b"2")
+        .build_arch(temp_dir.path());
+    let batch = prepare(&installer, &[&one, &two]);
+    let err = installer
+        .install_batch(batch, &NO_SCRIPTS)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("usr/bin/shared"), "{}", err);
+    assert!(
+        conary::Trove::find_by_name(installer.conn(), "one")
+            .unwrap()
+            .is_empty()
+    );
+    assert!(!root.join("usr/bin/one").exists());
+
+    // A conflict between members of the batch is caught as well
+    let gnu = PackageFixture::new("gnu-netcat", "0.7.1")
+        .release("9")
+        .provides("netcat")
+        .build_arch(temp_dir.path());
+    let openbsd = PackageFixture::new("openbsd-netcat", "1.226_1")
+        .conflicts("netcat")
+        .build_arch(temp_dir.path());
+    let batch = prepare(&installer, &[&gnu, &openbsd]);
+    let err = installer
+        .install_batch(batch, &NO_SCRIPTS)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("conflicts with installed package gnu-netcat"),
+        "{}",
+        err
+    );
+    assert!(
+        conary::Trove::find_by_name(installer.conn(), "gnu-netcat")
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(
+        conary::Changeset::list_all(installer.conn()).unwrap().len(),
+        1
+    );
+}
+
+#[test]
+fn test_upgrade_swaps_directories_and_files() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let v1 = PackageFixture::new("tool", "1.0")
+        .file("/usr/lib/tool/plugins/a.so", b"a")
+        .file("/usr/share/tool/doc/README", b"readme")
+        .file("/usr/lib/tool/data", b"data")
+        .file("/etc/tool.d/default", b"default")
+        .build_arch(temp_dir.path());
+    let v2 = PackageFixture::new("tool", "2.0")
+        .file("/usr/lib/tool/plugins", b"built in")
+        .symlink("/usr/share/tool/doc", "../doc/tool")
+        .file("/usr/lib/tool/data/index", b"index")
+        .file("/etc/tool.d", b"single file")
+        .build_arch(temp_dir.path());
+    installer.install_file(&v1, &NO_SCRIPTS).unwrap();
+    std::fs::write(root.join("etc/tool.d/local"), b"mine").unwrap();
+
+    // A directory with files the old version does not own needs the flag
+    let err = installer.install_file(&v2, &NO_SCRIPTS).unwrap_err();
+    assert!(matches!(err, conary::Error::ConflictError(_)), "{}", err);
+    assert!(err.to_string().contains("--allow-dir-replace"), "{}", err);
+    assert!(root.join("usr/lib/tool/plugins/a.so").exists());
+    assert_eq!(
+        conary::Trove::find_by_name(installer.conn(), "tool").unwrap()[0].version,
+        "1.0-1"
+    );
+
+    let options = conary::InstallOptions {
+        allow_dir_replace: true,
+        ..NO_SCRIPTS
+    };
+    let report = installer.install_file(&v2, &options).unwrap();
+    assert_eq!(report.packages[0].saved_dirs, ["/etc/tool.d.conary-saved"]);
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/tool/plugins")).unwrap(),
+        b"built in"
+    );
+    assert!(
+        std::fs::symlink_metadata(root.join("usr/share/tool/doc"))
+            .unwrap()
+            .is_symlink()
+    );
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/tool/data/index")).unwrap(),
+        b"index"
+    );
+    assert_eq!(
+        std::fs::read(root.join("etc/tool.d")).unwrap(),
+        b"single file"
+    );
+    assert_eq!(
+        std::fs::read(root.join("etc/tool.d.conary-saved/local")).unwrap(),
+        b"mine"
+    );
+    assert!(!root.join("etc/tool.d.conary-saved/default").exists());
+
+    let mut stmt = installer
+        .conn()
+        .prepare("SELECT path, action FROM file_history WHERE changeset_id = ?1 AND action NOT IN ('add', 'modify') ORDER BY path")
+        .unwrap();
+    let swaps: Vec<(String, String)> = stmt
+        .query_map([report.changeset_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    drop(stmt);
+    let swaps: Vec<(&str, &str)> = swaps
+        .iter()
+        .map(|(path, action)| (path.as_str(), action.as_str()))
+        .collect();
+    assert_eq!(
+        swaps,
+        [
+            ("/etc/tool.d", "dir-saved"),
+            ("/usr/lib/tool/data", "file-to-dir"),
+            ("/usr/lib/tool/plugins", "dir-to-file"),
+            ("/usr/share/tool/doc", "dir-to-file"),
+        ]
+    );
+
+    // Rolling back puts each directory and file back
+    drop(installer);
+    let deployer = conary::FileDeployer::new(&objects_dir, &root).unwrap();
+    let plan = conary::rollback::RollbackPlan::build(&conn, &deployer, report.changeset_id, false)
+        .unwrap();
+    plan.execute(&mut conn, &deployer).unwrap();
+    assert!(root.join("usr/lib/tool/plugins").is_dir());
+    assert!(
+        std::fs::symlink_metadata(root.join("usr/share/tool/doc"))
+            .unwrap()
+            .is_dir()
+    );
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/tool/data")).unwrap(),
+        b"data"
+    );
+    assert_eq!(
+        std::fs::read(root.join("etc/tool.d/local")).unwrap(),
+        b"mine"
+    );
+    assert!(!root.join("etc/tool.d.conary-saved").exists());
+}
+
+#[test]
+fn test_upgrade_refuses_a_directory_over_a_foreign_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let v1 = PackageFixture::new("tool", "1.0")
+        .file("/usr/bin/tool", b"v1")
+        .build_arch(temp_dir.path());
+    let v2 = PackageFixture::new("tool", "2.0")
+        .file("/usr/bin/tool", b"v2")
+        .file("/opt/tool/lib/core", b"core")
+        .build_arch(temp_dir.path());
+    installer.install_file(&v1, &NO_SCRIPTS).unwrap();
+    std::fs::create_dir_all(root.join("opt")).unwrap();
+    std::fs::write(root.join("opt/tool"), b"someone else's").unwrap();
+
+    // Only files of the version being replaced make way for a directory
+    let options = conary::InstallOptions {
+        allow_dir_replace: true,
+        ..NO_SCRIPTS
+    };
+    let err = installer.install_file(&v2, &options).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("needs /opt/tool to be a directory"),
+        "{}",
+        err
+    );
+    assert_eq!(
+        std::fs::read(root.join("opt/tool")).unwrap(),
+        b"someone else's"
+    );
+    assert_eq!(std::fs::read(root.join("usr/bin/tool")).unwrap(), b"v1");
+}
+
+#[test]
+fn test_du_lists_installed_sizes() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let small = PackageFixture::new("small", "1.0")
+        .file("/usr/share/small/LICENSE", b"MIT License")
+        .build_arch(temp_dir.path());
+    let big_v1 = PackageFixture::new("big", "1.0")
+        .file("/usr/lib/big.so", &[1; 100])
+        .build_arch(temp_dir.path());
+    let big_v2 = PackageFixture::new("big", "2.0")
+        .file("/usr/lib/big.so", &[2; 300])
+        .file("/usr/share/big/LICENSE", b"MIT License")
+        .build_arch(temp_dir.path());
+    installer.install_file(&small, &NO_SCRIPTS).unwrap();
+    installer.install_file(&big_v1, &NO_SCRIPTS).unwrap();
+    assert_eq!(
+        conary::Trove::find_by_name(installer.conn(), "big").unwrap()[0].installed_size,
+        100
+    );
+
+    // The upgrade records the new version's size
+    installer.install_file(&big_v2, &NO_SCRIPTS).unwrap();
+    drop(installer);
+
+    let (ok, report) = conary_json(&["du", "-d", db_arg, "--json"]);
+    assert!(ok);
+    let sizes: Vec<(&str, i64)> = report["packages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|package| {
+            (
+                package["name"].as_str().unwrap(),
+                package["installed_size"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(sizes, [("big", 311), ("small", 11)]);
+    assert_eq!(report["total_size"], 322);
+    // Both packages ship the same license, stored once
+    assert_eq!(report["unique_cas_bytes"], 311);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(["du", "-d", db_arg, "--limit", "1"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("311 B  big 2.0-1"), "{}", stdout);
+    assert!(
+        !stdout.contains("small 1.0-1") && stdout.contains("(1 smaller package(s) not shown)"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Total: 322 B in 2 package(s)"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_bootstrap_builds_a_root_from_a_file_repository() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let repo_dir = temp_dir.path().join("repo");
+    std::fs::create_dir_all(&repo_dir).unwrap();
+    let fixtures = [
+        PackageFixture::new("hello", "1.0")
+            .depends("libgreet")
+            .file("/usr/bin/hello", b"hello binary")
+            .file("/usr/share/doc/hello/README", b"read me")
+            .file("/usr/share/locale/de/hello.mo", b"hallo"),
+        PackageFixture::new("libgreet", "2.0").file("/usr/lib/libgreet.so", b"greetings"),
+    ];
+    let packages: Vec<serde_json::Value> = fixtures
+        .iter()
+        .map(|fixture| {
+            let path = fixture.build_arch(&repo_dir);
+            let body = std::fs::read(&path).unwrap();
+            serde_json::json!({
+                "name": fixture.name,
+                "version": fixture.full_version(),
+                "architecture": "x86_64",
+                "checksum": conary::CasStore::compute_hash(&body),
+                "size": body.len(),
+                "download_url": path.file_name().unwrap().to_str().unwrap(),
+                "dependencies": fixture.depends,
+            })
+        })
+        .collect();
+    let metadata = serde_json::json!({"name": "local", "version": "1", "packages": packages});
+    std::fs::write(repo_dir.join("metadata.json"), metadata.to_string()).unwrap();
+
+    let root = temp_dir.path().join("rootfs");
+    let manifest_path = temp_dir.path().join("manifest.json");
+    let repo_arg = format!("local=file://{}", repo_dir.display());
+    let run = || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+            .args([
+                "bootstrap",
+                "--root",
+                root.to_str().unwrap(),
+                "--repo",
+                &repo_arg,
+                "--packages",
+                "hello",
+            ])
+            .args(["--manifest", manifest_path.to_str().unwrap()])
+            .output()
+            .unwrap()
+    };
+    let output = run();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.contains("Installed 2 package(s)") && stdout.contains("Excluded: 2 files"),
+        "{}",
+        stdout
+    );
+
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/hello")).unwrap(),
+        b"hello binary"
+    );
+    assert!(root.join("usr/lib/libgreet.so").exists());
+    assert!(!root.join("usr/share/doc/hello/README").exists());
+    assert!(!root.join("usr/share/locale/de/hello.mo").exists());
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+    assert_eq!(manifest["repositories"][0]["name"], "local");
+    assert_eq!(manifest["scripts"], false);
+    let installed: Vec<(&str, bool)> = manifest["packages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|package| {
+            (
+                package["name"].as_str().unwrap(),
+                package["dependency"].as_bool().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(installed, [("hello", false), ("libgreet", true)]);
+    assert!(
+        manifest["packages"][0]["url"]
+            .as_str()
+            .unwrap()
+            .starts_with("file://")
+    );
+
+    // The root keeps its own database
+    let (ok, list) = conary_json(&[
+        "query",
+        "-d",
+        root.join("var/lib/conary/conary.db").to_str().unwrap(),
+        "--json",
+    ]);
+    assert!(ok, "{}", list);
+
+    // A root that already has a database is not bootstrapped again
+    let output = run();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already has a Conary database"));
+}
+
+/// Add a repository entry for `hello` and return it
+#[test]
+fn test_install_exclusions_are_kept_by_verify_and_repair() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let db_path = temp_dir.path().join("conary.db");
+    let (db_arg, root_arg) = (db_path.to_str().unwrap(), root.to_str().unwrap());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let v1 = PackageFixture::new("docs", "1.0")
+        .file("/usr/bin/docs", b"bin")
+        .file("/usr/share/doc/docs/README", b"readme")
+        .build_arch(temp_dir.path());
+    let exclude = ["/usr/share/doc/*".to_string(), "/opt/*".to_string()];
+    let options = conary::InstallOptions {
+        exclude: &exclude,
+        ..NO_SCRIPTS
+    };
+    let report = installer.install_file(&v1, &options).unwrap();
+    assert_eq!(
+        (report.packages[0].files, report.packages[0].excluded),
+        (1, 1)
+    );
+    assert!(!root.join("usr/share/doc/docs/README").exists());
+
+    // Only the globs that matched are recorded, and an upgrade keeps them
+    let v2 = PackageFixture::new("docs", "1.1")
+        .file("/usr/bin/docs", b"bin2")
+        .file("/usr/share/doc/docs/README", b"readme2")
+        .build_arch(temp_dir.path());
+    installer.install_file(&v2, &NO_SCRIPTS).unwrap();
+    let trove = conary::Trove::find_by_name(installer.conn(), "docs")
+        .unwrap()
+        .remove(0);
+    assert_eq!(trove.install_modifiers.excluded, vec!["/usr/share/doc/*"]);
+    assert!(!root.join("usr/share/doc/docs/README").exists());
+
+    let options = conary::ReconcileOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    assert!(
+        conary::reconcile(&mut conn, &objects_dir, &root, &options)
+            .unwrap()
+            .is_clean()
+    );
+    let options = conary::ReconcileOptions {
+        include_excluded: true,
+        ..options
+    };
+    assert!(
+        !conary::reconcile(&mut conn, &objects_dir, &root, &options)
+            .unwrap()
+            .is_clean()
+    );
+
+    let (ok, info) = conary_json(&[
+        "info", "docs", "--json", "--verify", "-d", db_arg, "-r", root_arg,
+    ]);
+    assert!(ok);
+    assert_eq!(
+        info[0]["trove"]["install_modifiers"]["excluded"],
+        serde_json::json!(["/usr/share/doc/*"])
+    );
+    assert_eq!(info[0]["verification_failures"], serde_json::json!([]));
+
+    let (ok, report) = conary_json(&["verify", "docs", "--json", "-d", db_arg, "-r", root_arg]);
+    assert!(ok);
+    assert_eq!(
+        (
+            report["summary"]["excluded"].as_u64(),
+            report["summary"]["missing"].as_u64()
+        ),
+        (Some(1), Some(0))
+    );
+
+    // Repair leaves excluded files out unless asked to bring them back
+    let (ok, stderr) = conary_run(&["verify", "docs", "--repair", "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", stderr);
+    assert!(!root.join("usr/share/doc/docs/README").exists());
+
+    let (ok, report) = conary_json(&[
+        "verify",
+        "docs",
+        "--json",
+        "--include-excluded",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ]);
+    assert!(!ok);
+    assert_eq!(report["summary"]["excluded"], 0);
+    let args = [
+        "verify",
+        "docs",
+        "--repair",
+        "--include-excluded",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ];
+    let (ok, stderr) = conary_run(&args);
+    assert!(ok, "{}", stderr);
+    assert_eq!(
+        std::fs::read(root.join("usr/share/doc/docs/README")).unwrap(),
+        b"readme2"
+    );
+}
+
+fn advertise_hello(
+    conn: &rusqlite::Connection,
+    repo_id: i64,
+    version: &str,
+    arch: Option<&str>,
+) -> conary::RepositoryPackage {
+    let mut repo_pkg = conary::RepositoryPackage::new(
+        repo_id,
+        "hello".to_string(),
+        version.to_string(),
+        "sha256:0".to_string(),
+        10,
+        format!(
+            "https://mirror.example.com/{}/hello-{}.pkg.tar.zst",
+            repo_id, version
+        ),
+    );
+    repo_pkg.architecture = arch.map(str::to_string);
+    repo_pkg.insert(conn).unwrap();
+    repo_pkg
+}
+
+#[test]
+fn test_header_version_mismatch_does_not_loop_updates() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let repo_id = conary::add_repository(
+        &conn,
+        "test".to_string(),
+        "https://example.com".to_string(),
+        true,
+        50,
+    )
+    .unwrap()
+    .id
+    .unwrap();
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    // The metadata lags a rebuild: it says 1.2-3, the package says 1.2-3.1
+    let selected = advertise_hello(installer.conn(), repo_id, "1.2-3", Some("x86_64"));
+    let pkg = PackageFixture::new("hello", "1.2")
+        .release("3.1")
+        .file("/usr/bin/hello", b"hello")
+        .build_arch(temp_dir.path());
+    let mut prepared = installer.prepare(&pkg).unwrap();
+    prepared.downloaded_for(&selected);
+    let report = installer
+        .install_batch(vec![prepared], &NO_SCRIPTS)
+        .unwrap();
+
+    let conn = installer.conn();
+    let trove = conary::Trove::find_by_name(conn, "hello")
+        .unwrap()
+        .remove(0);
+    assert_eq!(trove.version, "1.2-3.1");
+    let provenance = conary::db::models::Provenance::find_by_trove(conn, trove.id.unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!(provenance.repository_version.as_deref(), Some("1.2-3"));
+    let changeset = conary::Changeset::find_by_id(conn, report.changeset_id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        changeset.description,
+        "Install hello-1.2-3.1 (repository version 1.2-3)"
+    );
+
+    // Neither the advertised nor the header version is an update
+    let troves = vec![trove];
+    assert!(
+        conary::installer::available_updates(conn, &troves, None)
+            .unwrap()
+            .is_empty()
+    );
+    advertise_hello(conn, repo_id, "1.2-3.1", Some("x86_64"));
+    assert!(
+        conary::installer::available_updates(conn, &troves, None)
+            .unwrap()
+            .is_empty()
+    );
+
+    // A genuinely different version still is
+    advertise_hello(conn, repo_id, "1.3-1", Some("x86_64"));
+    let updates = conary::installer::available_updates(conn, &troves, None).unwrap();
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].1.version, "1.3-1");
+}
+
+/// Serve `count` requests, answering the one for `path` with `body` and the rest with 404
+fn serve_one_package(
+    path: String,
+    body: Vec<u8>,
+    count: usize,
+) -> (String, std::thread::JoinHandle<()>) {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        for stream in listener.incoming().take(count) {
+            let mut stream = stream.unwrap();
+            let mut request = [0u8; 4096];
+            let read = stream.read(&mut request).unwrap();
+            let request = String::from_utf8_lossy(&request[..read]).into_owned();
+            let wanted = request.split_whitespace().nth(1).unwrap_or_default() == path;
+            let (status, body) = if wanted {
+                ("200 OK", body.as_slice())
+            } else {
+                ("404 Not Found", &b""[..])
+            };
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+        }
+    });
+    (url, handle)
+}
+
+#[test]
+fn test_update_summary_lists_each_warning_once() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+    let v2_dir = temp_dir.path().join("v2");
+    std::fs::create_dir_all(&v2_dir).unwrap();
+
+    let mut installs = vec!["install", "--noscripts", "-d", db_arg, "-r", root_arg];
+    let v1: Vec<String> = ["hello", "tool", "extra"]
+        .iter()
+        .map(|name| {
+            let file = format!("/usr/bin/{}", name);
+            let package = PackageFixture::new(name, "1.0")
+                .file(&file, b"v1")
+                .build_arch(temp_dir.path());
+            package.to_str().unwrap().to_string()
+        })
+        .collect();
+    installs.extend(v1.iter().map(String::as_str));
+    let (ok, stderr) = conary_run(&installs);
+    assert!(ok, "{}", stderr);
+
+    // hello 2.0 downloads; tool and extra 404, each logging a warning
+    let hello = PackageFixture::new("hello", "2.0")
+        .file("/usr/bin/hello", b"v2")
+        .build_arch(&v2_dir);
+    let body = std::fs::read(&hello).unwrap();
+    let (url, server) = serve_one_package(
+        "/hello-2.0-1-x86_64.pkg.tar.zst".to_string(),
+        body.clone(),
+        3,
+    );
+    let conn = db::open(db_arg).unwrap();
+    let repo_id = conary::add_repository(&conn, "local".to_string(), url.clone(), true, 50)
+        .unwrap()
+        .id
+        .unwrap();
+    for name in ["hello", "tool", "extra"] {
+        let checksum = if name == "hello" {
+            conary::CasStore::compute_hash(&body)
+        } else {
+            "0".repeat(64)
+        };
+        let mut repo_pkg = conary::RepositoryPackage::new(
+            repo_id,
+            name.to_string(),
+            "2.0-1".to_string(),
+            checksum,
+            body.len() as i64,
+            format!("{}/{}-2.0-1-x86_64.pkg.tar.zst", url, name),
+        );
+        repo_pkg.architecture = Some("x86_64".to_string());
+        repo_pkg.insert(&conn).unwrap();
+    }
+    drop(conn);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args([
+            "update",
+            "--summary",
+            "--noscripts",
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+        ])
+        .output()
+        .unwrap();
+    server.join().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}\n{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let summary = &stdout[stdout.find("=== Summary ===").expect("no summary printed")..];
+    assert!(
+        summary.contains("Packages: 1 upgraded, 0 installed, 0 removed, 2 failed"),
+        "{}",
+        summary
+    );
+    assert!(summary.contains("Warnings (2):"), "{}", summary);
+    for name in ["tool", "extra"] {
+        let warning = format!(
+            "Full download failed: Download failed: HTTP 404 Not Found from {}/{}-2.0-1",
+            url, name
+        );
+        assert_eq!(summary.matches(&warning).count(), 1, "{}", summary);
+    }
+    assert_eq!(std::fs::read(root.join("usr/bin/hello")).unwrap(), b"v2");
+}
+
+#[test]
+fn test_update_picks_newest_by_priority_and_never_downgrades() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let add_repo = |conn: &rusqlite::Connection, name: &str, priority: i32| {
+        conary::add_repository(
+            conn,
+            name.to_string(),
+            format!("https://{}.example.com", name),
+            true,
+            priority,
+        )
+        .unwrap()
+        .id
+        .unwrap()
+    };
+    let low = add_repo(&conn, "low", 10);
+    let high = add_repo(&conn, "high", 90);
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+    let pkg = PackageFixture::new("hello", "2.0.1")
+        .release("1")
+        .file("/usr/bin/hello", b"hello")
+        .build_arch(temp_dir.path());
+    installer.install_file(&pkg, &NO_SCRIPTS).unwrap();
+    let conn = installer.conn();
+    let troves = conary::Trove::find_by_name(conn, "hello").unwrap();
+
+    // Older and equal versions are not updates, whichever repository has them
+    advertise_hello(conn, high, "1.9-1", None);
+    advertise_hello(conn, low, "2.0.1-1", None);
+    assert!(
+        conary::installer::available_updates(conn, &troves, None)
+            .unwrap()
+            .is_empty()
+    );
+    advertise_hello(conn, high, "2.0.1rc1-1", None);
+    assert!(
+        conary::installer::available_updates(conn, &troves, None)
+            .unwrap()
+            .is_empty()
+    );
+
+    // The newest version of the highest-priority repository wins
+    advertise_hello(conn, low, "3.0-1", None);
+    advertise_hello(conn, high, "2.0.1-2", None);
+    advertise_hello(conn, high, "2.0.1-10", None);
+    let updates = conary::installer::available_updates(conn, &troves, None).unwrap();
+    assert_eq!(
+        (updates[0].1.repository_id, updates[0].1.version.as_str()),
+        (high, "2.0.1-10")
+    );
+
+    // A disabled repository is not searched
+    let mut repo = conary::Repository::find_by_id(conn, high).unwrap().unwrap();
+    repo.enabled = false;
+    repo.update(conn).unwrap();
+    let updates = conary::installer::available_updates(conn, &troves, None).unwrap();
+    assert_eq!(
+        (updates[0].1.repository_id, updates[0].1.version.as_str()),
+        (low, "3.0-1")
+    );
+}
+
+#[test]
+fn test_update_stays_with_origin_repository() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let add_repo = |conn: &rusqlite::Connection, name: &str, priority: i32| {
+        conary::add_repository(
+            conn,
+            name.to_string(),
+            format!("https://{}.example.com", name),
+            true,
+            priority,
+        )
+        .unwrap()
+        .id
+        .unwrap()
+    };
+    let base = add_repo(&conn, "base", 10);
+    let other = add_repo(&conn, "other", 90);
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+    let selected = advertise_hello(installer.conn(), base, "1.0-1", None);
+    let pkg = PackageFixture::new("hello", "1.0")
+        .file("/usr/bin/hello", b"hello")
+        .build_arch(temp_dir.path());
+    let mut prepared = installer.prepare(&pkg).unwrap();
+    prepared.downloaded_for(&selected);
+    installer
+        .install_batch(vec![prepared], &NO_SCRIPTS)
+        .unwrap();
+
+    let troves = conary::Trove::find_by_name(installer.conn(), "hello").unwrap();
+    assert_eq!(troves[0].origin_repository_id, Some(base));
+    assert_eq!(
+        troves[0].origin_url.as_deref(),
+        Some(selected.download_url.as_str())
+    );
+
+    // A newer build elsewhere is not picked up while the origin still carries the package
+    let conn = installer.conn();
+    advertise_hello(conn, other, "2.0-1", None);
+    assert!(
+        conary::installer::available_updates(conn, &troves, None)
+            .unwrap()
+            .is_empty()
+    );
+    advertise_hello(conn, base, "1.1-1", None);
+    let updates = conary::installer::available_updates(conn, &troves, None).unwrap();
+    assert_eq!(
+        (updates[0].1.repository_id, updates[0].1.version.as_str()),
+        (base, "1.1-1")
+    );
+
+    // --repo overrides the origin
+    let updates = conary::installer::available_updates(conn, &troves, Some("other")).unwrap();
+    assert_eq!(
+        (updates[0].1.repository_id, updates[0].1.version.as_str()),
+        (other, "2.0-1")
+    );
+
+    // Local files have no origin
+    let local = PackageFixture::new("tool", "1.0")
+        .file("/usr/bin/tool", b"tool")
+        .build_arch(temp_dir.path());
+    installer.install_file(&local, &NO_SCRIPTS).unwrap();
+    let tool = conary::Trove::find_by_name(installer.conn(), "tool")
+        .unwrap()
+        .remove(0);
+    assert_eq!((tool.origin_repository_id, tool.origin_url), (None, None));
+}
+
+#[test]
+fn test_relocated_install_keeps_dependency_content_in_its_cas() {
+    use conary::filesystem::CasStore;
+
+    // A database outside /var/lib/conary and a root that is not /
+    let temp_dir = tempfile::tempdir().unwrap();
+    let state = temp_dir.path().join("state");
+    std::fs::create_dir_all(&state).unwrap();
+    let db_path = state.join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+    let conn = db::open(db_arg).unwrap();
+
+    // The repository is a fixture directory, fetched by a helper script
+    let pool = temp_dir.path().join("pool");
+    std::fs::create_dir_all(&pool).unwrap();
+    let lib = PackageFixture::new("libgreet", "1.0")
+        .file("/usr/lib/libgreet.so", b"libgreet")
+        .build_arch(&pool);
+    let app = PackageFixture::new("greet", "1.0")
+        .depends("libgreet")
+        .file_with_mode("/usr/bin/greet", b"greet", 0o755)
+        .build_arch(&pool);
+    let script = pool.join("fetch.sh");
+    std::fs::write(&script, "cp \"$(dirname \"$0\")/${1##*/}\" \"$2\"\n").unwrap();
+    let repo = conary::add_repository(
+        &conn,
+        "pool".to_string(),
+        "pool://fixtures".to_string(),
+        true,
+        0,
+    )
+    .unwrap();
+    let command = format!("sh {}", script.display());
+    conary::repository::set_download_helper(&conn, "pool", Some(&command), false, false).unwrap();
+    for (name, path, deps) in [
+        ("libgreet", &lib, Vec::new()),
+        ("greet", &app, vec!["libgreet".to_string()]),
+    ] {
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let mut pkg = conary::RepositoryPackage::new(
+            repo.id.unwrap(),
+            name.to_string(),
+            "1.0-1".to_string(),
+            CasStore::compute_hash(&bytes),
+            bytes.len() as i64,
+            format!("pool://fixtures/{}", file_name),
+        );
+        pkg.insert(&conn).unwrap();
+        pkg.insert_dependencies(&conn, &deps).unwrap();
+    }
+
+    let (ok, stderr) = conary_run(&[
+        "install",
+        "greet",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/libgreet.so")).unwrap(),
+        b"libgreet"
+    );
+
+    // Every file's content, the dependency's included, is in the CAS next to the database
+    let cas = CasStore::new(state.join("objects")).unwrap();
+    for name in ["greet", "libgreet"] {
+        let trove = conary::Trove::find_by_name(&conn, name).unwrap().remove(0);
+        let files = conary::FileEntry::find_by_trove(&conn, trove.id.unwrap()).unwrap();
+        assert!(!files.is_empty());
+        for file in files {
+            let hash = file.sha256_hash.as_deref().unwrap();
+            assert!(
+                cas.exists(hash),
+                "{} of {} is not in the relocated CAS",
+                file.path,
+                name
+            );
+        }
+    }
+
+    // Repair finds the dependency's content there too
+    std::fs::write(root.join("usr/lib/libgreet.so"), b"damaged").unwrap();
+    let (ok, stderr) = conary_run(&["verify", "--repair", "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", stderr);
+    assert_eq!(
+        std::fs::read(root.join("usr/lib/libgreet.so")).unwrap(),
+        b"libgreet"
+    );
+}
+
+#[test]
+fn test_cas_gc_removes_superseded_content_and_keeps_rollback() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let versions: [(&str, &[u8]); 3] = [
+        ("1.0", b"tool v1"),
+        ("1.1", b"tool v1.1"),
+        ("1.2", b"tool v1.2"),
+    ];
+    for (version, content) in versions {
+        let package = PackageFixture::new("tool", version)
+            .file_with_mode("/usr/bin/tool", content, 0o755)
+            .build_arch(temp_dir.path());
+        let (ok, stderr) = conary_run(&[
+            "install",
+            package.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ]);
+        assert!(ok, "{}", stderr);
+    }
+
+    // The install and the first upgrade fall out of the retention window
+    let conn = db::open(db_arg).unwrap();
+    let latest: i64 = conn
+        .query_row("SELECT MAX(id) FROM changesets", [], |row| row.get(0))
+        .unwrap();
+    conn.execute(
+        "UPDATE changesets SET created_at = '2000-01-01 00:00:00' WHERE id < ?1",
+        [latest],
+    )
+    .unwrap();
+    drop(conn);
+
+    let cas = conary::CasStore::new(temp_dir.path().join("objects")).unwrap();
+    let hashes: Vec<String> = versions
+        .iter()
+        .map(|(_, content)| conary::CasStore::compute_hash(content))
+        .collect();
+    assert!(hashes.iter().all(|hash| cas.exists(hash)));
+
+    let (ok, stderr) = conary_run(&["cas", "gc", "--dry-run", "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    assert!(hashes.iter().all(|hash| cas.exists(hash)));
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(["cas", "gc", "-d", db_arg])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The latest upgrade keeps the content it replaced
+    assert!(
+        stdout.contains("Removed 1 unreferenced objects, reclaimed 7 B"),
+        "{}",
+        stdout
+    );
+    assert!(!cas.exists(&hashes[0]) && cas.exists(&hashes[1]) && cas.exists(&hashes[2]));
+
+    let (ok, stderr) = conary_run(&[
+        "rollback",
+        &latest.to_string(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ]);
+    assert!(ok, "{}", stderr);
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/tool")).unwrap(),
+        b"tool v1.1"
+    );
+}
+
+#[test]
+fn test_verify_porcelain_output_and_exit_codes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary", 0o755)
+        .file("/usr/lib/tool/gone.so", b"gone")
+        .file("/usr/lib/tool/mod.so", b"mod")
+        .file("/usr/share/doc/tool/README", b"readme")
+        .build_arch(temp_dir.path());
+    let install = [
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ];
+    let (ok, stderr) = conary_run(&[&install[..], &["--exclude", "/usr/share/doc/*"]].concat());
+    assert!(ok, "{}", stderr);
+
+    let porcelain = |extra: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+            .args(
+                [
+                    &["verify", "--porcelain", "-d", db_arg, "-r", root_arg][..],
+                    extra,
+                ]
+                .concat(),
+            )
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+
+    // Excluded files are reported but are not findings
+    assert_eq!(
+        porcelain(&[]),
+        (
+            Some(0),
+            "X\t/usr/share/doc/tool/README\ttool\t\n".to_string()
+        )
+    );
+
+    std::fs::set_permissions(
+        root.join("usr/bin/tool"),
+        std::fs::Permissions::from_mode(0o700),
+    )
+    .unwrap();
+    std::fs::remove_file(root.join("usr/lib/tool/gone.so")).unwrap();
+    std::fs::write(root.join("usr/lib/tool/mod.so"), b"tampered").unwrap();
+
+    let snapshot = "P\t/usr/bin/tool\ttool\tmode 0700 (expected 0755)\n\
+                    D\t/usr/lib/tool/gone.so\ttool\t\n\
+                    M\t/usr/lib/tool/mod.so\ttool\t\n\
+                    X\t/usr/share/doc/tool/README\ttool\t\n";
+    assert_eq!(
+        porcelain(&["--attrs", "--changed-only"]),
+        (Some(1), snapshot.to_string())
+    );
+
+    // Errors are 2 and leave stdout empty
+    assert_eq!(porcelain(&["missing-package"]), (Some(2), String::new()));
+
+    // --changed-only trims the JSON file list to the problems
+    let (ok, report) = conary_json(&[
+        "verify",
+        "--json",
+        "--changed-only",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ]);
+    assert!(!ok);
+    let statuses: Vec<&str> = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, vec!["missing", "modified", "excluded"]);
+}
+
+#[test]
+fn test_install_keeps_extended_attributes() {
+    use conary::packages::PackageFormat;
+    use conary::packages::xattrs::{CAPABILITY_XATTR, capability_xattr};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    // RPM capabilities become the security.capability value setcap would write
+    let ping = PackageFixture::new("ping", "1.0")
+        .file_with_mode("/usr/bin/ping", b"ping binary", 0o755)
+        .caps("cap_net_raw=ep")
+        .build_rpm(temp_dir.path());
+    let ping = conary::packages::rpm::RpmPackage::parse(ping.to_str().unwrap()).unwrap();
+    assert_eq!(
+        ping.files()[0].xattrs.get(CAPABILITY_XATTR),
+        capability_xattr("cap_net_raw=ep").unwrap().as_ref()
+    );
+
+    // Not every filesystem a tempdir lands on supports xattrs
+    let probe = temp_dir.path().join("probe");
+    std::fs::write(&probe, b"").unwrap();
+    if xattr::set(&probe, "user.conary.probe", b"1").is_err() {
+        eprintln!("Skipping: the temporary filesystem does not support extended attributes");
+        return;
+    }
+
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary", 0o755)
+        .xattr("user.conary.origin", b"fixture")
+        .file("/usr/share/tool/data", b"data")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let tool = root.join("usr/bin/tool");
+    assert_eq!(
+        xattr::get(&tool, "user.conary.origin").unwrap().as_deref(),
+        Some(&b"fixture"[..])
+    );
+    let conn = db::open(db_arg).unwrap();
+    let recorded = db::models::FileEntry::find_by_path(&conn, "/usr/bin/tool")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        recorded.xattrs.get("user.conary.origin").map(Vec::as_slice),
+        Some(&b"fixture"[..])
+    );
+
+    let porcelain = || {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+            .args([
+                "verify",
+                "--porcelain",
+                "--attrs",
+                "-d",
+                db_arg,
+                "-r",
+                root_arg,
+            ])
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+    assert_eq!(porcelain(), (Some(0), String::new()));
+
+    // A lost attribute is drift, and repair puts it back
+    xattr::remove(&tool, "user.conary.origin").unwrap();
+    assert_eq!(
+        porcelain(),
+        (
+            Some(1),
+            "A\t/usr/bin/tool\ttool\txattr user.conary.origin missing\n".to_string()
+        )
+    );
+    std::fs::write(&tool, b"tampered").unwrap();
+    let (ok, stderr) = conary_run(&["verify", "--repair", "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", stderr);
+    assert_eq!(
+        xattr::get(&tool, "user.conary.origin").unwrap().as_deref(),
+        Some(&b"fixture"[..])
+    );
+}
+
+#[test]
+fn test_delta_generate_from_installed_and_package_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    db::init(db_arg).unwrap();
+
+    let motd = |version: &str| format!("Welcome to version {}\n", version).repeat(200);
+    let v1 = PackageFixture::new("motd", "1.0")
+        .file("/etc/motd", motd("1.0").as_bytes())
+        .build_arch(temp_dir.path());
+    let v2 = PackageFixture::new("motd", "2.0")
+        .file("/etc/motd", motd("2.0").as_bytes())
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        v1.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root.to_str().unwrap(),
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let output = temp_dir.path().join("deltas");
+    let output_arg = output.to_str().unwrap();
+    let (ok, stderr) = conary_run(&[
+        "delta-generate",
+        "motd",
+        "1.0-1",
+        "2.0-1",
+        "-o",
+        output_arg,
+        "-d",
+        db_arg,
+    ]);
+    assert!(!ok);
+    assert!(
+        stderr.contains("motd 2.0-1 is not installed and no --package-file provides it"),
+        "{}",
+        stderr
+    );
+
+    let (ok, stderr) = conary_run(&[
+        "delta-generate",
+        "motd",
+        "1.0-1",
+        "2.0-1",
+        "--package-file",
+        v2.to_str().unwrap(),
+        "-o",
+        output_arg,
+        "-d",
+        db_arg,
+    ]);
+    assert!(ok, "{}", stderr);
+    let manifest: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(output.join("motd-1.0-1-to-2.0-1.json")).unwrap(),
+    )
+    .unwrap();
+    let entry = &manifest["files"][0];
+    assert_eq!(entry["path"], "/etc/motd");
+    assert_eq!(entry["from_version"], "1.0-1");
+    assert!(output.join(entry["delta_url"].as_str().unwrap()).exists());
+}
+
+#[test]
+fn test_history_filters_and_detail_view() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    db::init(db_arg).unwrap();
+
+    for version in ["1.0", "2.0"] {
+        let package = PackageFixture::new("motd", version)
+            .file("/etc/motd", format!("hello {}", version).as_bytes())
+            .build_arch(temp_dir.path());
+        let (ok, stderr) = conary_run(&[
+            "install",
+            package.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root.to_str().unwrap(),
+            "--noscripts",
+        ]);
+        assert!(ok, "{}", stderr);
+    }
+
+    let (ok, history) = conary_json(&[
+        "history", "--json", "--limit", "1", "--status", "applied", "-d", db_arg,
+    ]);
+    assert!(ok);
+    assert_eq!(history.as_array().unwrap().len(), 1);
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    let upgrade = history
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|c| c["id"].as_i64())
+        .max()
+        .unwrap()
+        .to_string();
+    let (_, history) = conary_json(&["history", "--json", "--status", "rolled_back", "-d", db_arg]);
+    assert!(history.as_array().unwrap().is_empty());
+    let (_, history) = conary_json(&["history", "--json", "--since", "2000-01-01", "-d", db_arg]);
+    assert_eq!(history.as_array().unwrap().len(), 2);
+    let (ok, stderr) = conary_run(&["history", "--since", "last week", "-d", db_arg]);
+    assert!(!ok);
+    assert!(stderr.contains("Invalid --since date"), "{}", stderr);
+
+    let (ok, report) = conary_json(&["history", "--show", &upgrade, "--json", "-d", db_arg]);
+    assert!(ok);
+    let actions: Vec<_> = report["troves"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["action"].clone())
+        .collect();
+    assert_eq!(actions, ["replaced", "installed"]);
+    assert_eq!(report["files"][0]["path"], "/etc/motd");
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(["history", "--show", &upgrade, "-d", db_arg])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Modified (1):\n  /etc/motd"), "{}", stdout);
+}
+
+#[test]
+fn test_install_refuses_paths_the_filesystem_cannot_hold() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    db::init(db_arg).unwrap();
+
+    let (ok, stderr) = conary_run(&["config-set", "max_filename_length", "16", "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let package = PackageFixture::new("docs", "1.0")
+        .file("/usr/share/docs/short", b"fits")
+        .file("/usr/share/docs/a-rather-long-file-name", b"too long")
+        .file("/usr/share/docs/another-long-file-name", b"too long")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root.to_str().unwrap(),
+        "--noscripts",
+    ]);
+    assert!(!ok);
+    assert!(
+        stderr.contains("2 path(s) cannot be deployed"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("docs: /usr/share/docs/a-rather-long-file-name: name of 23 bytes exceeds max_filename_length (16)"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("/usr/share/docs/another-long-file-name"),
+        "{}",
+        stderr
+    );
+
+    // Nothing was deployed or recorded
+    assert!(!root.join("usr/share/docs/short").exists());
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert!(history.as_array().unwrap().is_empty());
+}
+
+#[test]
+fn test_check_update_exit_codes() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    db::init(db_arg).unwrap();
+    let check = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+            .args(["check-update", "-d", db_arg])
+            .args(args)
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        )
+    };
+
+    let conn = db::open(db_arg).unwrap();
+    let repo_id = conary::add_repository(
+        &conn,
+        "local".to_string(),
+        "https://local.example.com".to_string(),
+        true,
+        50,
+    )
+    .unwrap()
+    .id
+    .unwrap();
+    for name in ["hello", "tool"] {
+        conary::Trove::new(
+            name.to_string(),
+            "1.0-1".to_string(),
+            conary::TroveType::Package,
+        )
+        .insert(&conn)
+        .unwrap();
+    }
+    assert_eq!(check(&[]), (Some(0), String::new()));
+
+    for name in ["hello", "tool"] {
+        conary::RepositoryPackage::new(
+            repo_id,
+            name.to_string(),
+            "2.0-1".to_string(),
+            "0".repeat(64),
+            1,
+            format!("https://local.example.com/{}-2.0-1.pkg.tar.zst", name),
+        )
+        .insert(&conn)
+        .unwrap();
+    }
+    assert_eq!(
+        check(&[]),
+        (
+            Some(100),
+            "hello 1.0-1 -> 2.0-1 local\ntool 1.0-1 -> 2.0-1 local\n".to_string()
+        )
+    );
+    assert_eq!(
+        check(&["tool"]),
+        (Some(100), "tool 1.0-1 -> 2.0-1 local\n".to_string())
+    );
+    assert_eq!(check(&["--quiet"]), (Some(100), String::new()));
+    // No advisory covers either update
+    assert_eq!(check(&["--security"]), (Some(0), String::new()));
+
+    // Locked packages are not updates
+    conary::db::models::PackageLock::lock(&conn, "hello").unwrap();
+    assert_eq!(
+        check(&[]),
+        (Some(100), "tool 1.0-1 -> 2.0-1 local\n".to_string())
+    );
+    conary::db::models::PackageLock::lock(&conn, "tool").unwrap();
+    assert_eq!(check(&[]), (Some(0), String::new()));
+    drop(conn);
+
+    // Errors exit 1, whatever code the error carries elsewhere
+    let missing = temp_dir.path().join("missing.db");
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(["check-update", "-d", missing.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_locked_package_is_skipped_by_update_and_install() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    for name in ["hello", "tool"] {
+        let package = PackageFixture::new(name, "1.0")
+            .file(&format!("/usr/bin/{}", name), b"v1")
+            .build_arch(temp_dir.path());
+        let (ok, stderr) = conary_run(&[
+            "install",
+            package.to_str().unwrap(),
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+            "--noscripts",
+        ]);
+        assert!(ok, "{}", stderr);
+    }
+    let (ok, stderr) = conary_run(&["lock", "hello", "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let (ok, _) = conary_run(&["lock", "missing", "-d", db_arg]);
+    assert!(!ok);
+
+    let conn = db::open(db_arg).unwrap();
+    let repo_id = conary::add_repository(
+        &conn,
+        "local".to_string(),
+        "https://local.example.com".to_string(),
+        true,
+        50,
+    )
+    .unwrap()
+    .id
+    .unwrap();
+    for name in ["hello", "tool"] {
+        let mut repo_pkg = conary::RepositoryPackage::new(
+            repo_id,
+            name.to_string(),
+            "2.0-1".to_string(),
+            "0".repeat(64),
+            1,
+            format!(
+                "https://local.example.com/{}-2.0-1-x86_64.pkg.tar.zst",
+                name
+            ),
+        );
+        repo_pkg.architecture = Some("x86_64".to_string());
+        repo_pkg.insert(&conn).unwrap();
+    }
+    drop(conn);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(["update", "--dry-run", "-d", db_arg, "-r", root_arg])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("hello 1.0-1 -> 2.0-1 skipped (locked)"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Found 1 package(s) with updates available:\n  NAME  INSTALLED  CANDIDATE"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains(
+            "\n  tool  1.0-1      2.0-1      local       1 B       no\nTotal download: 1 B\n"
+        ),
+        "{}",
+        stdout
+    );
+
+    // Another version of a locked package needs --force-unlock, which lifts the lock
+    let v2 = PackageFixture::new("hello", "2.0")
+        .file("/usr/bin/hello", b"v2")
+        .build_arch(temp_dir.path());
+    let install = [
+        "install",
+        v2.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ];
+    let (ok, stderr) = conary_run(&install);
+    assert!(!ok);
+    assert!(
+        stderr.contains("hello is locked at version 1.0-1"),
+        "{}",
+        stderr
+    );
+    let (ok, stderr) = conary_run(&[&install[..], &["--force-unlock"]].concat());
+    assert!(ok, "{}", stderr);
+    assert_eq!(std::fs::read(root.join("usr/bin/hello")).unwrap(), b"v2");
+    let (_, locks) = conary_json(&["locks", "--json", "-d", db_arg]);
+    assert!(locks.as_array().unwrap().is_empty());
+}
+
+#[test]
+fn test_pin_add_list_remove() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    db::init(db_arg).unwrap();
+    let conn = db::open(db_arg).unwrap();
+    for name in ["fedora-43", "arch-multilib"] {
+        conary::add_repository(
+            &conn,
+            name.to_string(),
+            format!("https://{}.example.com", name),
+            true,
+            50,
+        )
+        .unwrap();
+    }
+
+    let (ok, stderr) = conary_run(&["pin", "add", "bash", "fedora-43", "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let (ok, stderr) = conary_run(&[
+        "pin",
+        "add",
+        "*",
+        "arch-multilib",
+        "--forbid",
+        "--priority",
+        "-1",
+        "-d",
+        db_arg,
+    ]);
+    assert!(ok, "{}", stderr);
+    let (ok, _) = conary_run(&["pin", "add", "bash", "missing-repo", "-d", db_arg]);
+    assert!(!ok);
+
+    let (ok, pins) = conary_json(&["pin", "list", "--json", "-d", db_arg]);
+    assert!(ok);
+    let listed: Vec<(&str, &str, &str, i64)> = pins
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pin| {
+            (
+                pin["pattern"].as_str().unwrap(),
+                pin["repository"].as_str().unwrap(),
+                pin["action"].as_str().unwrap(),
+                pin["priority"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        listed,
+        vec![
+            ("bash", "fedora-43", "prefer", 0),
+            ("*", "arch-multilib", "forbid", -1)
+        ]
+    );
+
+    let (ok, stderr) = conary_run(&["pin", "remove", "bash", "fedora-43", "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let (ok, _) = conary_run(&["pin", "remove", "bash", "fedora-43", "-d", db_arg]);
+    assert!(!ok);
+    let (_, pins) = conary_json(&["pin", "list", "--json", "-d", db_arg]);
+    assert_eq!(pins.as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_stats_show_and_reset() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("tool", "1.0")
+        .file("/usr/bin/tool", b"tool")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root.to_str().unwrap(),
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let stats = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+            .args([&["stats"][..], args, &["-d", db_arg]].concat())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert!(stats(&[]).contains("Packages installed: 1"));
+    assert!(stats(&["--since", "30d"]).contains("Packages installed: 1"));
+
+    // reset is a subcommand of stats, and takes the database path after it too
+    assert!(stats(&["reset"]).contains("Reset operation statistics"));
+    assert!(stats(&[]).contains("Packages installed: 0"));
+    let (ok, _) = conary_run(&["stats-reset", "-d", db_arg]);
+    assert!(!ok);
+}
+
+/// Split a line the way a POSIX shell splits words, for lines quoting
+/// with single quotes and backslashes only
+fn shell_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            '\\' if !quoted => word.get_or_insert_with(String::new).extend(chars.next()),
+            ' ' if !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+#[test]
+fn test_repo_export_commands_recreate_repositories() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let source = temp_dir.path().join("source.db");
+    let target = temp_dir.path().join("target.db");
+    let (source_arg, target_arg) = (source.to_str().unwrap(), target.to_str().unwrap());
+    db::init(source_arg).unwrap();
+    db::init(target_arg).unwrap();
+
+    let adds: [&[&str]; 3] = [
+        &[
+            "repo-add",
+            "fedora-43",
+            "https://dl.fedoraproject.org/pub/fedora/linux/releases/43/os",
+            "-p",
+            "90",
+        ],
+        &[
+            "repo-add",
+            "ubuntu-noble",
+            "http://archive.ubuntu.com/ubuntu",
+            "--component",
+            "main",
+            "--component",
+            "universe",
+            "--arch",
+            "amd64",
+            "--prefer-https",
+            "--gpg-key",
+            "https://keys.example.com/ubuntu key.asc",
+            "--metadata-expire",
+            "86400",
+        ],
+        &[
+            "repo-add",
+            "it's-local",
+            "https://mirror.example.com/arch",
+            "--disabled",
+            "--scope",
+            "explicit-only",
+            "--download-helper",
+            "aria2c -x4",
+            "--helper-fallback",
+            "--no-cross-host",
+            "--sync-exclude",
+            "*-debug",
+            "--no-gpg-check",
+        ],
+    ];
+    for args in adds {
+        let (ok, stderr) = conary_run(&[args, &["-d", source_arg]].concat());
+        assert!(ok, "{}", stderr);
+    }
+    // Later edits show up in the export
+    let (ok, stderr) = conary_run(&[
+        "repo-modify",
+        "fedora-43",
+        "--sync-include",
+        "kernel*",
+        "-d",
+        source_arg,
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(["repo-export", "--as-commands", "-d", source_arg])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let script = String::from_utf8(output.stdout).unwrap();
+    assert!(script.starts_with("#!/bin/sh\n"), "{}", script);
+    assert!(
+        script.contains("conary repo-add 'it'\\''s-local' "),
+        "{}",
+        script
+    );
+
+    let commands: Vec<&str> = script
+        .lines()
+        .filter(|line| line.starts_with("conary "))
+        .collect();
+    assert_eq!(commands.len(), 3, "{}", script);
+    for line in commands {
+        let words = shell_words(line);
+        let args: Vec<&str> = words[1..].iter().map(String::as_str).collect();
+        let (ok, stderr) = conary_run(&[&args[..], &["-d", target_arg]].concat());
+        assert!(ok, "{}: {}", line, stderr);
+    }
+
+    let rows = |db_arg: &str| {
+        let (ok, mut repos) = conary_json(&["repo-list", "--all", "--json", "-d", db_arg]);
+        assert!(ok);
+        for repo in repos.as_array_mut().unwrap() {
+            let repo = repo.as_object_mut().unwrap();
+            repo.remove("id");
+            repo.remove("created_at");
+        }
+        repos
+    };
+    assert_eq!(rows(source_arg), rows(target_arg));
+}
+
+#[test]
+fn test_update_refuses_to_overwrite_verify_findings() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let v1 = PackageFixture::new("hello", "1.0")
+        .file("/usr/bin/hello", b"v1")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        v1.to_str().unwrap(),
+        "--noscripts",
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ]);
+    assert!(ok, "{}", stderr);
+
+    // A locally patched binary, found by verify
+    std::fs::write(root.join("usr/bin/hello"), b"patched").unwrap();
+    let (ok, _) = conary_run(&["verify", "-d", db_arg, "-r", root_arg]);
+    assert!(!ok);
+
+    let v2_dir = temp_dir.path().join("v2");
+    std::fs::create_dir_all(&v2_dir).unwrap();
+    let v2 = PackageFixture::new("hello", "2.0")
+        .file("/usr/bin/hello", b"v2")
+        .build_arch(&v2_dir);
+    let body = std::fs::read(&v2).unwrap();
+    let (url, server) = serve_one_package(
+        "/hello-2.0-1-x86_64.pkg.tar.zst".to_string(),
+        body.clone(),
+        1,
+    );
+    let conn = db::open(db_arg).unwrap();
+    let repo_id = conary::add_repository(&conn, "local".to_string(), url.clone(), true, 50)
+        .unwrap()
+        .id
+        .unwrap();
+    let mut repo_pkg = conary::RepositoryPackage::new(
+        repo_id,
+        "hello".to_string(),
+        "2.0-1".to_string(),
+        conary::CasStore::compute_hash(&body),
+        body.len() as i64,
+        format!("{}/hello-2.0-1-x86_64.pkg.tar.zst", url),
+    );
+    repo_pkg.architecture = Some("x86_64".to_string());
+    repo_pkg.insert(&conn).unwrap();
+    drop(conn);
+
+    let update = ["update", "--noscripts", "-d", db_arg, "-r", root_arg];
+    let (ok, stderr) = conary_run(&update);
+    assert!(!ok);
+    assert!(
+        stderr.contains("  /usr/bin/hello (hello, modified)"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("--overwrite-modified"), "{}", stderr);
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/hello")).unwrap(),
+        b"patched"
+    );
+
+    let (ok, stderr) = conary_run(&[&update[..], &["--overwrite-modified"]].concat());
+    server.join().unwrap();
+    assert!(ok, "{}", stderr);
+    assert_eq!(std::fs::read(root.join("usr/bin/hello")).unwrap(), b"v2");
+
+    // The finding was about 1.0; it does not hold back 2.0
+    let conn = db::open(db_arg).unwrap();
+    let trove = conary::db::models::Trove::find_by_name(&conn, "hello")
+        .unwrap()
+        .remove(0);
+    assert!(
+        conary::db::models::VerifyFinding::unresolved(&conn, &trove)
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_extract_unpacks_every_format() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let fixture = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"#!/bin/sh\n", 0o755)
+        .file("/usr/share/tool/data", b"data")
+        .symlink("/usr/bin/tool-alias", "tool");
+
+    let builds: [(&str, fixtures::BuildFn); 3] = [
+        ("arch", PackageFixture::build_arch),
+        ("deb", PackageFixture::build_deb),
+        ("rpm", PackageFixture::build_rpm),
+    ];
+    for (format, build) in builds {
+        if format == "rpm" && !have_rpm2cpio() {
+            eprintln!("Skipping RPM extract: rpm2cpio is not installed");
+            continue;
+        }
+        let path = build(&fixture, temp_dir.path());
+        let package = conary::packages::open_package(&path).unwrap();
+        let dest = temp_dir.path().join(format);
+        let summary = conary::packages::extract::extract_to(package.as_ref(), &dest).unwrap();
+        assert_eq!(
+            (summary.files, summary.symlinks, summary.bytes),
+            (2, 1, 14),
+            "{}",
+            format
+        );
+
+        assert_eq!(
+            std::fs::read(dest.join("usr/share/tool/data")).unwrap(),
+            b"data"
+        );
+        let mode = std::fs::metadata(dest.join("usr/bin/tool"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755, "{}", format);
+        assert_eq!(
+            std::fs::read_link(dest.join("usr/bin/tool-alias")).unwrap(),
+            std::path::Path::new("tool")
+        );
+    }
+}
+
+#[test]
+fn test_packages_cannot_write_outside_the_root() {
+    use conary::packages::extract::extract_to;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+    let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+    let outside = temp_dir.path().join("outside");
+    std::fs::create_dir(&outside).unwrap();
+    let outside_arg = outside.to_str().unwrap();
+
+    // A `..` entry refuses the whole package before anything is recorded
+    let climbing = PackageFixture::new("climbing", "1.0")
+        .file("/usr/bin/climbing", b"ok")
+        .raw_entry("../escape", b"escaped")
+        .build_arch(temp_dir.path());
+    let err = installer
+        .install_file(&climbing, &NO_SCRIPTS)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("/../escape leads outside the install root"),
+        "{}",
+        err
+    );
+    assert!(!root.join("usr/bin/climbing").exists());
+    assert!(
+        conary::Changeset::list_all(installer.conn())
+            .unwrap()
+            .is_empty()
+    );
+    let package = conary::packages::open_package(&climbing).unwrap();
+    let err = extract_to(package.as_ref(), &temp_dir.path().join("climbing"))
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("leads outside"), "{}", err);
+    assert!(!temp_dir.path().join("escape").exists());
+
+    // An absolute entry lands under the root
+    let absolute = PackageFixture::new("absolute", "1.0")
+        .raw_entry("/etc/absolute.conf", b"inside")
+        .build_arch(temp_dir.path());
+    installer.install_file(&absolute, &NO_SCRIPTS).unwrap();
+    assert_eq!(
+        std::fs::read(root.join("etc/absolute.conf")).unwrap(),
+        b"inside"
+    );
+
+    // Writing through a symlink pointing out of the root stays inside it
+    let link = PackageFixture::new("link", "1.0")
+        .symlink("/usr/lib/evil", outside_arg)
+        .build_arch(temp_dir.path());
+    let through = PackageFixture::new("through", "1.0")
+        .file("/usr/lib/evil/payload", b"payload")
+        .build_arch(temp_dir.path());
+    installer.install_file(&link, &NO_SCRIPTS).unwrap();
+    installer.install_file(&through, &NO_SCRIPTS).unwrap();
+    let relocated = root.join(outside.strip_prefix("/").unwrap());
+    assert_eq!(
+        std::fs::read(relocated.join("payload")).unwrap(),
+        b"payload"
+    );
+    assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+    // So does extracting a package shipping the link and the file behind it
+    let both = PackageFixture::new("both", "1.0")
+        .symlink("/usr/lib/evil", outside_arg)
+        .file("/usr/lib/evil/payload", b"payload")
+        .build_arch(temp_dir.path());
+    let dest = temp_dir.path().join("both");
+    extract_to(
+        conary::packages::open_package(&both).unwrap().as_ref(),
+        &dest,
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read(
+            dest.join(outside.strip_prefix("/").unwrap())
+                .join("payload")
+        )
+        .unwrap(),
+        b"payload"
+    );
+    assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+}
+
+/// A read-only bind mount, unmounted when dropped
+struct ReadOnlyMount(std::path::PathBuf);
+
+impl ReadOnlyMount {
+    /// Mount `source` read-only at `target`, or `None` where bind mounts are not allowed
+    fn new(source: &std::path::Path, target: &std::path::Path) -> Option<Self> {
+        std::fs::create_dir_all(target).unwrap();
+        if !mount(&["--bind", source.to_str().unwrap(), target.to_str().unwrap()]) {
+            return None;
+        }
+        let mounted = Self(target.to_path_buf());
+        mount(&["-o", "remount,ro,bind", target.to_str().unwrap()]).then_some(mounted)
+    }
+
+    /// Remount read-write, as after `mount -o remount,rw` at boot
+    fn make_writable(&self) {
+        assert!(mount(&["-o", "remount,rw,bind", self.0.to_str().unwrap()]));
+    }
+}
+
+impl Drop for ReadOnlyMount {
+    fn drop(&mut self) {
+        let _ = std::process::Command::new("umount").arg(&self.0).status();
+    }
+}
+
+fn mount(args: &[&str]) -> bool {
+    std::process::Command::new("mount")
+        .args(args)
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[test]
+fn test_staged_deploy_on_read_only_root() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("state/conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    db::init(db_arg).unwrap();
+    let image = temp_dir.path().join("image");
+    std::fs::create_dir(&image).unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    let Some(read_only) = ReadOnlyMount::new(&image, &root) else {
+        eprintln!("Skipping staged deploy: bind mounts are not allowed here");
+        return;
+    };
+    assert!(std::fs::write(root.join("probe"), "").is_err());
+
+    let (ok, stderr) = conary_run(&["config-set", "staged_deploy", "true", "-d", db_arg]);
+    assert!(ok, "{}", stderr);
+    let tool = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"#!/bin/sh\n", 0o755)
+        .file("/usr/share/tool/data", b"data")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        tool.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    // Recorded as staged, with the files waiting next to the database
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert_eq!(history[0]["status"], "staged");
+    let changeset_id = history[0]["id"].as_i64().unwrap();
+    let pending = temp_dir
+        .path()
+        .join(format!("state/pending/{}", changeset_id));
+    assert_eq!(
+        std::fs::read(pending.join("usr/share/tool/data")).unwrap(),
+        b"data"
+    );
+    assert!(std::fs::read_dir(&image).unwrap().next().is_none());
+
+    let (ok, report) = conary_json(&["verify", "--json", "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", report);
+    assert_eq!(report["summary"]["staged"], 2);
+    assert_eq!(report["summary"]["missing"], 0);
+    assert_eq!(report["files"][0]["status"], "staged");
+
+    // Still read-only: nothing moves and the changeset stays staged
+    let (ok, _) = conary_run(&["apply-pending", "-d", db_arg, "-r", root_arg]);
+    assert!(!ok);
+    assert!(pending.join("usr/bin/tool").exists());
+
+    read_only.make_writable();
+    let (ok, stderr) = conary_run(&["apply-pending", "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", stderr);
+    assert!(!pending.exists());
+    let mode = std::fs::metadata(image.join("usr/bin/tool"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o755);
+    let (ok, report) = conary_json(&["verify", "--json", "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", report);
+    assert_eq!(
+        (
+            report["summary"]["ok"].as_i64(),
+            report["summary"]["staged"].as_i64()
+        ),
+        (Some(2), Some(0))
+    );
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    assert_eq!(history[0]["status"], "applied");
+
+    // Rolling back a staged changeset throws its pending tree away
+    let extra = PackageFixture::new("extra", "1.0")
+        .file("/usr/bin/extra", b"extra")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        extra.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+    let (_, history) = conary_json(&["history", "--json", "-d", db_arg]);
+    let extra_id = history[0]["id"].as_i64().unwrap();
+    let (ok, stderr) = conary_run(&[
+        "rollback",
+        &extra_id.to_string(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+    ]);
+    assert!(ok, "{}", stderr);
+    assert!(
+        !temp_dir
+            .path()
+            .join(format!("state/pending/{}", extra_id))
+            .exists()
+    );
+    assert!(!image.join("usr/bin/extra").exists());
+    let (ok, stderr) = conary_run(&["apply-pending", "-d", db_arg, "-r", root_arg]);
+    assert!(ok, "{}", stderr);
+    assert!(!image.join("usr/bin/extra").exists());
+}
+
+#[test]
+fn test_package_owned_directory_is_created_verified_and_removed() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let builds: [(&str, fixtures::BuildFn); 3] = [
+        ("arch", PackageFixture::build_arch),
+        ("deb", PackageFixture::build_deb),
+        ("rpm", PackageFixture::build_rpm),
+    ];
+    for (format, build) in builds {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let keep = PackageFixture::new("keep", "1.0")
+            .file_with_mode("/usr/bin/keep", b"keep", 0o755)
+            .dir("/usr/share/keep", 0o755)
+            .file("/usr/share/keep/data", b"data")
+            .dir("/var/lib/keep", 0o700)
+            .dir("/var/lib/shared", 0o755);
+        let keep_path = build(&keep, temp_dir.path());
+
+        // The file list has the directory with its type bits
+        let package = conary::packages::open_package(&keep_path).unwrap();
+        let listed = package
+            .files()
+            .iter()
+            .find(|file| file.path == "/var/lib/keep")
+            .unwrap();
+        assert!(listed.is_dir, "{}", format);
+        assert_eq!(listed.mode, 0o040700, "{}", format);
+
+        if format == "rpm" && !have_rpm2cpio() {
+            eprintln!("Skipping RPM directories: rpm2cpio is not installed");
+            continue;
+        }
+        let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+        let db_path = temp_dir.path().join("conary.db");
+        let (db_arg, root_arg) = (db_path.to_str().unwrap(), root.to_str().unwrap());
+        let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+        let other = PackageFixture::new("other", "1.0").dir("/var/lib/shared", 0o755);
+        installer.install_file(&keep_path, &NO_SCRIPTS).unwrap();
+        installer
+            .install_file(&build(&other, temp_dir.path()), &NO_SCRIPTS)
+            .unwrap();
+
+        let dir = root.join("var/lib/keep");
+        assert_eq!(
+            std::fs::metadata(&dir).unwrap().permissions().mode() & 0o7777,
+            0o700,
+            "{}",
+            format
+        );
+        let trove = conary::Trove::find_by_name(installer.conn(), "keep")
+            .unwrap()
+            .remove(0);
+        let files = conary::FileEntry::find_by_trove(installer.conn(), trove.id.unwrap()).unwrap();
+        let entry = files
+            .iter()
+            .find(|file| file.path == "/var/lib/keep")
+            .unwrap();
+        assert_eq!(
+            (entry.file_type, entry.sha256_hash.as_deref()),
+            (conary::db::models::FileType::Directory, None)
+        );
+
+        let (ok, report) = conary_json(&["verify", "keep", "--json", "-d", db_arg, "-r", root_arg]);
+        assert!(ok, "{}: {}", format, report);
+
+        // Directories have no content, so a loosened mode is a modification
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let (ok, report) = conary_json(&["verify", "keep", "--json", "-d", db_arg, "-r", root_arg]);
+        assert!(!ok);
+        assert_eq!(report["summary"]["modified"], 1, "{}: {}", format, report);
+        let (ok, stderr) =
+            conary_run(&["verify", "keep", "--repair", "-d", db_arg, "-r", root_arg]);
+        assert!(ok, "{}", stderr);
+        assert_eq!(
+            std::fs::metadata(&dir).unwrap().permissions().mode() & 0o7777,
+            0o700,
+            "{}",
+            format
+        );
+        std::fs::remove_dir(&dir).unwrap();
+        let (ok, report) = conary_json(&["verify", "keep", "--json", "-d", db_arg, "-r", root_arg]);
+        assert!(!ok, "{}: {}", format, report);
+        assert_eq!(report["summary"]["missing"], 1, "{}: {}", format, report);
+        std::fs::create_dir(&dir).unwrap();
+
+        // Removal takes the package's files and the directories they emptied,
+        // but not a directory another package still owns
+        installer
+            .remove("keep", None, &conary::RemoveOptions { scripts: None })
+            .unwrap();
+        assert!(!root.join("usr/bin/keep").exists(), "{}", format);
+        assert!(!root.join("usr/share/keep").exists(), "{}", format);
+        assert!(root.join("usr/share").is_dir(), "{}", format);
+        assert!(!dir.exists(), "{}", format);
+        assert!(root.join("var/lib/shared").is_dir(), "{}", format);
+        installer
+            .remove("other", None, &conary::RemoveOptions { scripts: None })
+            .unwrap();
+        assert!(!root.join("var/lib/shared").exists(), "{}", format);
+        assert!(root.join("var/lib").is_dir(), "{}", format);
+    }
+}
+
+#[test]
+fn test_shared_directory_gets_the_strictest_declared_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let builds: [(&str, fixtures::BuildFn); 3] = [
+        ("arch", PackageFixture::build_arch),
+        ("deb", PackageFixture::build_deb),
+        ("rpm", PackageFixture::build_rpm),
+    ];
+    for (format, build) in builds {
+        if format == "rpm" && !have_rpm2cpio() {
+            eprintln!("Skipping RPM directories: rpm2cpio is not installed");
+            continue;
+        }
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (mut conn, objects_dir, root) = installer_setup(temp_dir.path());
+        let db_path = temp_dir.path().join("conary.db");
+        let (db_arg, root_arg) = (db_path.to_str().unwrap(), root.to_str().unwrap());
+        let mut installer = conary::Installer::new(&mut conn, &objects_dir, &root).unwrap();
+
+        // Created looser by the admin before any package claimed it
+        let dir = root.join("var/lib/foo");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let mode = || std::fs::metadata(&dir).unwrap().permissions().mode() & 0o7777;
+        let tightened = |conn: &rusqlite::Connection| -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM file_history WHERE path = '/var/lib/foo' AND action = 'dir-mode'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        let foo = PackageFixture::new("foo", "1.0")
+            .dir("/var/lib/foo", 0o755)
+            .owned_by("root", "root");
+        installer
+            .install_file(&build(&foo, temp_dir.path()), &NO_SCRIPTS)
+            .unwrap();
+        assert_eq!(mode(), 0o755, "{}", format);
+        assert_eq!(tightened(installer.conn()), 1, "{}", format);
+
+        // A stricter owner tightens it, and a looser one installed later does not loosen it
+        let data = PackageFixture::new("foo-data", "1.0")
+            .dir("/var/lib/foo", 0o700)
+            .owned_by("root", "root");
+        installer
+            .install_file(&build(&data, temp_dir.path()), &NO_SCRIPTS)
+            .unwrap();
+        assert_eq!(mode(), 0o700, "{}", format);
+        assert_eq!(tightened(installer.conn()), 2, "{}", format);
+        let foo = PackageFixture::new("foo", "1.1")
+            .dir("/var/lib/foo", 0o755)
+            .owned_by("root", "root");
+        installer
+            .install_file(&build(&foo, temp_dir.path()), &NO_SCRIPTS)
+            .unwrap();
+        assert_eq!(mode(), 0o700, "{}", format);
+        assert_eq!(tightened(installer.conn()), 2, "{}", format);
+
+        // Verify expects the reconciled mode from every owner
+        let (ok, report) = conary_json(&["verify", "foo", "--json", "-d", db_arg, "-r", root_arg]);
+        assert!(ok, "{}: {}", format, report);
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let (ok, report) = conary_json(&["verify", "foo", "--json", "-d", db_arg, "-r", root_arg]);
+        assert!(!ok, "{}: {}", format, report);
+        assert_eq!(report["summary"]["modified"], 1, "{}: {}", format, report);
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        // Owners that disagree are refused before anything is recorded
+        let other = PackageFixture::new("foo-other", "1.0")
+            .dir("/var/lib/foo", 0o700)
+            .owned_by("foo", "foo");
+        let err = installer
+            .install_file(&build(&other, temp_dir.path()), &NO_SCRIPTS)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("declares directory /var/lib/foo"),
+            "{}: {}",
+            format,
+            err
+        );
+        assert!(
+            conary::Trove::find_by_name(installer.conn(), "foo-other")
+                .unwrap()
+                .is_empty(),
+            "{}",
+            format
+        );
+    }
+}
+
+#[test]
+fn test_verify_orphans_lists_files_no_package_owns() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary", 0o755)
+        .file("/usr/share/tool/data", b"tool data")
+        .build_arch(temp_dir.path());
+    let (ok, stderr) = conary_run(&[
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let orphans = ["verify", "--orphans", "-d", db_arg, "-r", root_arg];
+    let (ok, stderr) = conary_run(&orphans);
+    assert!(ok, "{}", stderr);
+
+    std::fs::write(root.join("usr/bin/stray"), b"left behind").unwrap();
+    std::fs::create_dir_all(root.join("usr/local/bin")).unwrap();
+    std::fs::write(root.join("usr/local/bin/mine"), b"mine").unwrap();
+    let (ok, report) =
+        conary_json(&[&orphans[..], &["--exclude", "/usr/local", "--json"]].concat());
+    assert!(!ok);
+    assert_eq!(report["orphans"].as_array().unwrap().len(), 1, "{}", report);
+    assert_eq!(report["orphans"][0]["path"], "/usr/bin/stray");
+    assert_eq!(report["orphans"][0]["size"], 11);
+    assert_eq!(report["summary"]["tracked"], 2);
+    assert_eq!(report["summary"]["orphaned"], 1);
+    assert_eq!(report["summary"]["excluded"], 1);
+
+    // A narrower prefix, and the porcelain listing
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args([
+            "verify",
+            "--orphans",
+            "/usr/local",
+            "--porcelain",
+            "-d",
+            db_arg,
+            "-r",
+            root_arg,
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "?\t/usr/local/bin/mine\t\t4\n"
+    );
+}
+
+/// Run the conary binary with `CONARY_FAILPOINTS` set, returning its exit code and stderr
+#[cfg(feature = "failpoints")]
+fn conary_failing(failpoints: &str, args: &[&str]) -> (Option<i32>, String) {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_conary"))
+        .args(args)
+        .env("CONARY_FAILPOINTS", failpoints)
+        .output()
+        .unwrap();
+    (
+        output.status.code(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[cfg(feature = "failpoints")]
+#[test]
+fn test_install_crash_windows_are_recoverable() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+
+    let package = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary", 0o755)
+        .file("/usr/share/tool/data", b"tool data")
+        .build_arch(temp_dir.path());
+    let install = [
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ];
+    let verify = ["verify", "-d", db_arg, "-r", root_arg];
+    let (ok, list) = conary_json(&["query", "--json", "-d", db_arg]);
+    assert!(ok);
+    assert_eq!(list.as_array().map(Vec::len), Some(0));
+
+    // A crash before the content is in the CAS leaves nothing recorded
+    let (code, stderr) = conary_failing("cas::commit=abort", &install);
+    assert_eq!(code, None, "{}", stderr);
+    let (ok, list) = conary_json(&["query", "--json", "-d", db_arg]);
+    assert!(ok);
+    assert_eq!(list.as_array().map(Vec::len), Some(0), "{}", list);
+    assert!(!root.join("usr/bin/tool").exists());
+
+    // A crash after the changeset is committed leaves the package recorded
+    // but not deployed; verify notices and repair deploys it from the CAS
+    let (code, stderr) = conary_failing("install::after-record=abort", &install);
+    assert_eq!(code, None, "{}", stderr);
+    assert!(!root.join("usr/bin/tool").exists());
+    let (ok, _) = conary_run(&verify);
+    assert!(!ok);
+    let (ok, report) = conary_json(&[&verify[..], &["--repair", "--json"]].concat());
+    assert!(ok, "{}", report);
+    assert_eq!(report["summary"]["repaired"], 2);
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/tool")).unwrap(),
+        b"tool binary"
+    );
+
+    // A crash halfway through deploying an upgrade is repaired the same way
+    let upgrade = PackageFixture::new("tool", "2.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary 2", 0o755)
+        .file("/usr/share/tool/data", b"tool data 2")
+        .build_arch(temp_dir.path());
+    let install = [
+        "install",
+        upgrade.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ];
+    let (code, stderr) = conary_failing("deploy::rename=1*off->abort", &install);
+    assert_eq!(code, None, "{}", stderr);
+    let (ok, report) = conary_json(&[&verify[..], &["--repair", "--json"]].concat());
+    assert!(ok, "{}", report);
+    assert_eq!(report["summary"]["repaired"], 1);
+    assert_eq!(
+        std::fs::read(root.join("usr/bin/tool")).unwrap(),
+        b"tool binary 2"
+    );
+    assert_eq!(
+        std::fs::read(root.join("usr/share/tool/data")).unwrap(),
+        b"tool data 2"
+    );
+    let (ok, stderr) = conary_run(&verify);
+    assert!(ok, "{}", stderr);
+}
+
+/// Every path under `root` with its mode and its content or link target
+#[cfg(feature = "failpoints")]
+fn tree_snapshot(root: &std::path::Path) -> Vec<(String, u32, Vec<u8>)> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut snapshot = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let metadata = std::fs::symlink_metadata(&path).unwrap();
+            let content = if metadata.is_symlink() {
+                std::fs::read_link(&path)
+                    .unwrap()
+                    .into_os_string()
+                    .into_encoded_bytes()
+            } else if metadata.is_dir() {
+                pending.push(path.clone());
+                Vec::new()
+            } else {
+                std::fs::read(&path).unwrap()
+            };
+            let relative = path
+                .strip_prefix(root)
+                .unwrap()
+                .to_string_lossy()
+                .into_owned();
+            snapshot.push((relative, metadata.permissions().mode(), content));
+        }
+    }
+    snapshot.sort();
+    snapshot
+}
+
+#[cfg(feature = "failpoints")]
+#[test]
+fn test_failed_deployment_is_undone() {
+    use conary::db::models::{Changeset, ChangesetStatus};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir.path().join("conary.db");
+    let db_arg = db_path.to_str().unwrap();
+    let root = temp_dir.path().join("root");
+    let root_arg = root.to_str().unwrap();
+    db::init(db_arg).unwrap();
+    std::fs::create_dir_all(root.join("etc")).unwrap();
+    std::fs::write(root.join("etc/hostname"), b"box\n").unwrap();
+
+    let package = PackageFixture::new("tool", "1.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary", 0o755)
+        .file("/usr/share/tool/data", b"tool data")
+        .build_arch(temp_dir.path());
+    let upgrade = PackageFixture::new("tool", "2.0")
+        .file_with_mode("/usr/bin/tool", b"tool binary 2", 0o755)
+        .file("/usr/share/tool/data", b"tool data 2")
+        .file("/usr/share/tool/extra", b"tool extra")
+        .build_arch(temp_dir.path());
+    let install = |package: &std::path::Path| {
+        conary_failing(
+            "deploy::rename=2*off->return",
+            &[
+                "install",
+                package.to_str().unwrap(),
+                "-d",
+                db_arg,
+                "-r",
+                root_arg,
+                "--noscripts",
+            ],
+        )
+    };
+    let status = |id: i64| {
+        let conn = db::open(db_arg).unwrap();
+        Changeset::find_by_id(&conn, id).unwrap().unwrap().status
+    };
+
+    // A fresh install that fails partway leaves the root as it was
+    let before = tree_snapshot(&root);
+    let (code, stderr) = install(&upgrade);
+    assert_eq!(code, Some(1), "{}", stderr);
+    assert_eq!(tree_snapshot(&root), before);
+    assert_eq!(status(1), ChangesetStatus::RolledBack);
+    let (ok, list) = conary_json(&["query", "--json", "-d", db_arg]);
+    assert!(ok);
+    assert_eq!(list.as_array().map(Vec::len), Some(0), "{}", list);
+
+    // So does an upgrade, whose replaced files come back byte for byte
+    let (ok, stderr) = conary_run(&[
+        "install",
+        package.to_str().unwrap(),
+        "-d",
+        db_arg,
+        "-r",
+        root_arg,
+        "--noscripts",
+    ]);
+    assert!(ok, "{}", stderr);
+    let before = tree_snapshot(&root);
+    let conn = db::open(db_arg).unwrap();
+    let latest: i64 = conn
+        .query_row("SELECT MAX(id) FROM changesets", [], |row| row.get(0))
+        .unwrap();
+    drop(conn);
+    let (code, stderr) = install(&upgrade);
+    assert_eq!(code, Some(1), "{}", stderr);
+    assert_eq!(tree_snapshot(&root), before);
+    assert_eq!(status(latest + 1), ChangesetStatus::RolledBack);
+}
@@ -0,0 +1,160 @@
+// tests/large_packages.rs
+
+//! Installs of packages too large to share a test binary with anything else
+//!
+//! These measure the installer's wall-clock time and the process's peak
+//! resident set size, which other tests running alongside them would
+//! disturb. They live in their own test binary and take turns through
+//! [`SERIAL`], so the peak memory one test reads is its own.
+
+mod fixtures;
+
+use conary::db::models::InstallReason;
+use fixtures::PackageFixture;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Held by each test for its whole run
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Open a fresh database under `dir`
+fn test_db(dir: &Path) -> rusqlite::Connection {
+    let db_path = dir.join("conary.db");
+    conary::db::init(db_path.to_str().unwrap()).unwrap();
+    conary::db::open(db_path.to_str().unwrap()).unwrap()
+}
+
+/// Install a package file as its own changeset through the library installer
+fn install(package: &Path, conn: &mut rusqlite::Connection, objects_dir: &Path, root: &Path) {
+    let mut installer = conary::Installer::new(conn, objects_dir, root).unwrap();
+    let mut prepared = installer.prepare(package).unwrap();
+    prepared.set_reason(InstallReason::Explicit);
+    let options = conary::InstallOptions {
+        scripts: None,
+        allow_downgrade: false,
+        exclude: &[],
+        force_unlock: false,
+        allow_dir_replace: false,
+    };
+    installer.install_batch(vec![prepared], &options).unwrap();
+}
+
+/// Peak resident set size of this process in KiB, reset with clear_refs
+fn peak_rss_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+#[test]
+fn test_large_package_installs_in_seconds() {
+    let _serial = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let temp = tempfile::tempdir().unwrap();
+    let mut conn = test_db(temp.path());
+
+    const FILES: usize = 50_000;
+    let mut package = PackageFixture::new("firmware", "1.0");
+    for i in 0..FILES {
+        let path = format!("/usr/share/firmware/{:03}/blob-{}.bin", i % 500, i);
+        package = package.file(&path, format!("firmware blob {}", i % 40_000).as_bytes());
+    }
+    let package = package.build_arch(temp.path());
+
+    let start = std::time::Instant::now();
+    install(
+        &package,
+        &mut conn,
+        &temp.path().join("objects"),
+        &temp.path().join("root"),
+    );
+    let elapsed = start.elapsed();
+
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, FILES as i64);
+    let history: i64 = conn
+        .query_row("SELECT COUNT(*) FROM file_history", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(history, FILES as i64);
+    let hits = conary::stats::totals(&conn, None).unwrap().cache_hit_bytes;
+    assert!(
+        hits > 0,
+        "duplicate contents within the package count as cache hits"
+    );
+    // A release build takes a few seconds; the bound leaves room for
+    // unoptimized builds while still catching per-file quadratic work
+    assert!(
+        elapsed < std::time::Duration::from_secs(60),
+        "took {:?}",
+        elapsed
+    );
+}
+
+#[test]
+fn test_large_package_streams_with_bounded_memory() {
+    let _serial = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let temp = tempfile::tempdir().unwrap();
+    let mut conn = test_db(temp.path());
+    let root = temp.path().join("root");
+
+    // Four 64 MiB files, written straight into the package without holding them
+    const FILE_SIZE: u64 = 64 * 1024 * 1024;
+    let package = temp.path().join("assets-1.0-1-x86_64.pkg.tar.zst");
+    let encoder = zstd::Encoder::new(File::create(&package).unwrap(), 1).unwrap();
+    let mut builder = tar::Builder::new(encoder);
+    let pkginfo = "pkgname = assets\npkgver = 1.0-1\narch = x86_64\n";
+    let mut header = tar::Header::new_gnu();
+    header.set_size(pkginfo.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, ".PKGINFO", pkginfo.as_bytes())
+        .unwrap();
+    for i in 0..4u8 {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(FILE_SIZE);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let content = io::Read::take(io::repeat(b'a' + i), FILE_SIZE);
+        builder
+            .append_data(
+                &mut header,
+                format!("usr/share/assets/pack{}.dat", i),
+                content,
+            )
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap();
+
+    // Writing 5 to clear_refs resets the peak RSS to the current RSS
+    let measured = std::fs::write("/proc/self/clear_refs", "5").is_ok();
+    let before = peak_rss_kib();
+    install(&package, &mut conn, &temp.path().join("objects"), &root);
+    let after = peak_rss_kib();
+
+    let deployed = std::fs::metadata(root.join("usr/share/assets/pack3.dat")).unwrap();
+    assert_eq!(deployed.len(), FILE_SIZE);
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 4);
+
+    if let (true, Some(before), Some(after)) = (measured, before, after) {
+        // Holding the payload would add 256 MiB; streaming needs a few buffers
+        let growth_kib = after.saturating_sub(before);
+        assert!(
+            growth_kib < 64 * 1024,
+            "peak RSS grew by {} KiB",
+            growth_kib
+        );
+    }
+}
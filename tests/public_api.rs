@@ -0,0 +1,114 @@
+// tests/public_api.rs
+
+//! Snapshot of the crate-root public API
+//!
+//! The supported surface is whatever `src/lib.rs` exposes at the crate root.
+//! This test lists those items and compares them with `tests/public-api.txt`,
+//! so adding, removing or hiding an item has to be done deliberately. After an
+//! intentional change, regenerate the snapshot with
+//! `CONARY_UPDATE_API_SNAPSHOT=1 cargo test --test public_api`.
+
+use std::path::Path;
+
+/// List the crate-root items declared in lib.rs, one per line
+fn root_items(lib_rs: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut hidden = false;
+    let mut pending_use = String::new();
+
+    for line in lib_rs.lines().map(str::trim) {
+        if !pending_use.is_empty() {
+            pending_use.push_str(line);
+            if line.ends_with(';') {
+                items.extend(expand_use(&pending_use));
+                pending_use.clear();
+            }
+            continue;
+        }
+
+        if line == "#[doc(hidden)]" {
+            hidden = true;
+        } else if let Some(name) = line.strip_prefix("pub mod ") {
+            let name = name.trim_end_matches(';');
+            if hidden {
+                items.push(format!("mod {} (hidden)", name));
+            } else {
+                items.push(format!("mod {}", name));
+            }
+            hidden = false;
+        } else if line.starts_with("pub use ") {
+            if line.ends_with(';') {
+                items.extend(expand_use(line));
+            } else {
+                pending_use.push_str(line);
+            }
+            hidden = false;
+        } else if !line.starts_with("//") && !line.is_empty() {
+            hidden = false;
+        }
+    }
+
+    items.sort();
+    items
+}
+
+/// Expand `pub use a::b::{C, D};` into `use a::b::C` and `use a::b::D`
+fn expand_use(statement: &str) -> Vec<String> {
+    let body = statement
+        .trim_start_matches("pub use ")
+        .trim_end_matches(';');
+    match body.split_once("::{") {
+        Some((prefix, names)) => names
+            .trim_end_matches('}')
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| format!("use {}::{}", prefix, name))
+            .collect(),
+        None => vec![format!("use {}", body)],
+    }
+}
+
+#[test]
+fn test_public_api_matches_snapshot() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let lib_rs = std::fs::read_to_string(manifest_dir.join("src/lib.rs")).unwrap();
+    let snapshot_path = manifest_dir.join("tests/public-api.txt");
+
+    let mut actual = root_items(&lib_rs).join("\n");
+    actual.push('\n');
+
+    if std::env::var_os("CONARY_UPDATE_API_SNAPSHOT").is_some() {
+        std::fs::write(&snapshot_path, &actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&snapshot_path).unwrap();
+    assert_eq!(
+        actual, expected,
+        "the crate-root public API changed; if intended, regenerate tests/public-api.txt \
+         with CONARY_UPDATE_API_SNAPSHOT=1"
+    );
+}
+
+#[test]
+fn test_root_reexports_are_usable() {
+    use conary::{
+        Changeset, ChangesetStatus, Error, InstallReason, PackageFormatType, Result, RpmVersion,
+        Trove, TroveType,
+    };
+
+    fn check() -> Result<()> {
+        Err(Error::NotFoundError("x".to_string()))
+    }
+
+    let trove = Trove::new("pkg".to_string(), "1.0".to_string(), TroveType::Package);
+    assert_eq!(trove.install_reason, InstallReason::Explicit);
+    assert_eq!(
+        Changeset::new("test".to_string()).status,
+        ChangesetStatus::Pending
+    );
+    assert!(RpmVersion::parse("1.0-1").is_ok());
+    assert!(check().is_err());
+    let _ = PackageFormatType::Rpm;
+}